@@ -0,0 +1,53 @@
+//! Records build information for `GET /api/version`.
+//!
+//! Sets `GHOSTLINK_GIT_HASH` to the short commit hash, or `unknown` outside
+//! a git checkout, and `GHOSTLINK_BUILD_DATE` to the build time as a Unix
+//! timestamp. `SOURCE_DATE_EPOCH` overrides the build time for reproducible
+//! builds.
+
+use std::{
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+fn main() {
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .filter(|hash| !hash.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let build_date = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs())
+        });
+
+    println!("cargo:rustc-env=GHOSTLINK_GIT_HASH={}", git_hash);
+    println!("cargo:rustc-env=GHOSTLINK_BUILD_DATE={}", build_date);
+
+    // Rebuild when the checked out commit moves; watching a missing file would rerun every build
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let head = Path::new(".git/HEAD");
+    if head.exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        if let Ok(head) = std::fs::read_to_string(head)
+            && let Some(reference) = head.trim().strip_prefix("ref: ")
+        {
+            for path in [format!(".git/{}", reference), ".git/packed-refs".into()] {
+                if Path::new(&path).exists() {
+                    println!("cargo:rerun-if-changed={}", path);
+                }
+            }
+        }
+    }
+}
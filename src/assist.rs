@@ -0,0 +1,173 @@
+//! Assist mode: letting the peer run commands we have explicitly granted.
+//!
+//! The grantor lists each command by name in the config together with the
+//! exact program and arguments, and the saved contacts that may run it. The
+//! peer can only ask for a name; it cannot pass arguments, and nothing goes
+//! through a shell. Every invocation is
+//! logged and shown in both UIs.
+
+use crate::config::AssistGrant;
+use serde::{Deserialize, Serialize};
+use std::process::Stdio;
+use tokio::{process::Command, time::Duration};
+
+/// Longest a granted command may run before it is killed.
+pub const ASSIST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Combined stdout/stderr returned to the peer, in bytes. The response must
+/// fit in a single stream message together with framing, encryption and padding.
+pub const MAX_OUTPUT_BYTES: usize = 2048;
+
+/// Result of one invocation, as sent back to the requesting peer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AssistOutcome {
+    /// Exit code, if the command ran to completion.
+    pub exit_code: Option<i32>,
+    /// Captured stdout followed by stderr, truncated to `MAX_OUTPUT_BYTES`.
+    pub output: String,
+    /// Why the command did not run or did not finish.
+    pub error: Option<String>,
+}
+
+impl AssistOutcome {
+    /// An invocation that never started.
+    pub fn refused(reason: impl Into<String>) -> Self {
+        Self {
+            exit_code: None,
+            output: String::new(),
+            error: Some(reason.into()),
+        }
+    }
+}
+
+/// Returns true if `name` is no longer than the longest granted name.
+///
+/// Anything longer cannot match a grant, and is refused without being
+/// logged or echoed back, as the peer chooses its length.
+pub fn plausible_name(grants: &[AssistGrant], name: &str) -> bool {
+    grants.iter().any(|g| g.name.len() >= name.len())
+}
+
+/// Looks up the grant the peer asked for.
+///
+/// # Arguments
+///
+/// * `grants` - Commands granted in the config.
+/// * `peer_label` - Saved contact label of the peer, `None` for guests and
+///   peers without a saved contact.
+/// * `name` - Name the peer asked for.
+///
+/// # Returns
+///
+/// The grant, if it exists and lists the peer's contact label.
+pub fn find<'a>(
+    grants: &'a [AssistGrant],
+    peer_label: Option<&str>,
+    name: &str,
+) -> Option<&'a AssistGrant> {
+    let peer_label = peer_label?;
+    grants
+        .iter()
+        .find(|g| g.name == name && g.allowed_contacts.iter().any(|c| c == peer_label))
+}
+
+/// Runs a granted command and captures its output.
+pub async fn run(grant: &AssistGrant) -> AssistOutcome {
+    let child = Command::new(&grant.program)
+        .args(&grant.args)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+
+    match tokio::time::timeout(ASSIST_TIMEOUT, child).await {
+        Ok(Ok(output)) => {
+            let mut bytes = output.stdout;
+            bytes.extend_from_slice(&output.stderr);
+            bytes.truncate(MAX_OUTPUT_BYTES);
+            // Replacement characters can make lossy decoding longer than the input
+            let mut text = String::from_utf8_lossy(&bytes).into_owned();
+            let mut end = text.len().min(MAX_OUTPUT_BYTES);
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            text.truncate(end);
+            AssistOutcome {
+                exit_code: output.status.code(),
+                output: text,
+                error: None,
+            }
+        }
+        Ok(Err(e)) => AssistOutcome::refused(format!("Failed to start: {}", e)),
+        Err(_) => AssistOutcome::refused(format!("Timed out after {} s", ASSIST_TIMEOUT.as_secs())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grant(name: &str, program: &str, args: &[&str]) -> AssistGrant {
+        AssistGrant {
+            name: name.into(),
+            program: program.into(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+            allowed_contacts: vec!["Bob".into()],
+        }
+    }
+
+    #[test]
+    fn test_plausible_name() {
+        let grants = vec![grant("uptime", "uptime", &[]), grant("df", "df", &[])];
+        assert!(plausible_name(&grants, "uptime"));
+        assert!(plausible_name(&grants, "reboot"));
+        assert!(!plausible_name(&grants, &"x".repeat(7)));
+        assert!(!plausible_name(&[], "df"));
+    }
+
+    #[tokio::test]
+    async fn test_run_captures_output() {
+        let outcome = run(&grant("hello", "echo", &["hello"])).await;
+
+        assert_eq!(outcome.exit_code, Some(0));
+        assert_eq!(outcome.output, "hello\n");
+        assert_eq!(outcome.error, None);
+    }
+
+    #[tokio::test]
+    async fn test_run_caps_output() {
+        let script = format!(
+            "head -c {} /dev/zero | tr '\\0' '\\377'",
+            MAX_OUTPUT_BYTES * 2
+        );
+        let outcome = run(&grant("noise", "sh", &["-c", &script])).await;
+
+        assert_eq!(outcome.exit_code, Some(0));
+        assert!(outcome.output.len() <= MAX_OUTPUT_BYTES);
+        assert!(!outcome.output.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_run_reports_missing_program() {
+        let outcome = run(&grant("nope", "/nonexistent/ghostlink-assist", &[])).await;
+
+        assert_eq!(outcome.exit_code, None);
+        assert!(outcome.error.unwrap().starts_with("Failed to start"));
+    }
+
+    #[test]
+    fn test_find_matches_exact_name() {
+        let grants = vec![grant("ip", "ip", &["addr"])];
+
+        assert!(find(&grants, Some("Bob"), "ip").is_some());
+        assert!(find(&grants, Some("Bob"), "IP").is_none());
+        assert!(find(&grants, Some("Bob"), "ip addr").is_none());
+    }
+
+    #[test]
+    fn test_find_checks_contact() {
+        let grants = vec![grant("ip", "ip", &["addr"])];
+
+        assert!(find(&grants, Some("Mallory"), "ip").is_none());
+        assert!(find(&grants, None, "ip").is_none());
+    }
+}
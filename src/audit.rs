@@ -0,0 +1,420 @@
+//! Session audit log for GhostLink.
+//!
+//! Keeps one record per connection attempt (peer, timing, traffic, outcome)
+//! and persists finished records so past sessions can be reviewed later.
+//! Records are kept as long as the retention policy allows.
+
+use crate::{
+    retention::{Retention, RetentionPolicy},
+    storage::{append_jsonl, read_jsonl_tail, remove_file, unix_timestamp, write_jsonl},
+    web::shared_state::NatType,
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, net::SocketAddr, path::PathBuf};
+use tracing::warn;
+
+/// Number of finished sessions kept in memory (and loaded at startup).
+const MAX_RECORDS: usize = 200;
+
+/// Why a session ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisconnectReason {
+    /// The local user requested the disconnect.
+    LocalRequest,
+    /// The peer sent a Bye.
+    PeerRequest,
+    /// Hole punching or key exchange did not complete.
+    HandshakeFailed,
+    /// The handshake succeeded but the KCP stream could not be created.
+    UpgradeFailed,
+    /// The peer stopped responding, or a handshake overran its deadline.
+    PeerTimeout,
+}
+
+/// Audit entry describing a single connection attempt.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    /// Short ID of the connection attempt, as seen in logs and events.
+    #[serde(default)]
+    pub connection_id: Option<String>,
+    /// Unix timestamp (seconds) when the connection attempt started.
+    pub started_at: u64,
+    /// Unix timestamp (seconds) when the session ended. `None` while active.
+    pub ended_at: Option<u64>,
+    /// Remote peer address.
+    pub peer: SocketAddr,
+    /// Display label for the peer, if one was given.
+    #[serde(default)]
+    pub peer_label: Option<String>,
+    /// Local NAT classification at the time of the attempt.
+    pub local_nat_type: NatType,
+    /// Transport carrying the session (e.g., "KCP"). `None` if never established.
+    pub transport: Option<String>,
+    /// Negotiated encryption algorithm, if the handshake completed.
+    pub encryption_algo: Option<String>,
+    /// Encrypted payload bytes written to the peer.
+    pub bytes_sent: u64,
+    /// Encrypted payload bytes read from the peer.
+    pub bytes_received: u64,
+    /// Why the session ended.
+    pub disconnect_reason: Option<DisconnectReason>,
+    /// Error detail for failed sessions.
+    pub error: Option<String>,
+    /// Retention chosen for this conversation, overriding the policy.
+    #[serde(default)]
+    pub retention: Option<Retention>,
+}
+
+/// In-memory view of the audit log, backed by a JSON Lines file.
+#[derive(Debug, Clone, Default)]
+pub struct SessionLog {
+    /// File finished records are appended to. `None` keeps the log in memory only.
+    path: Option<PathBuf>,
+    /// Most recent finished sessions, oldest first.
+    records: VecDeque<SessionRecord>,
+    /// Session currently being tracked.
+    current: Option<SessionRecord>,
+    /// How long finished records are kept.
+    policy: RetentionPolicy,
+}
+
+impl SessionLog {
+    /// Opens the audit log stored at `path`, loading the most recent records.
+    pub fn open(path: PathBuf) -> Self {
+        let records = read_jsonl_tail(&path, MAX_RECORDS).unwrap_or_else(|e| {
+            warn!("Failed to load session history: {}", e);
+            Vec::new()
+        });
+
+        Self {
+            path: Some(path),
+            records: records.into(),
+            current: None,
+            policy: RetentionPolicy::default(),
+        }
+    }
+
+    /// Sets how long finished records are kept.
+    ///
+    /// Records already expired under the new policy go on the next `prune`.
+    pub fn set_policy(&mut self, policy: RetentionPolicy) {
+        self.policy = policy;
+    }
+
+    /// Returns the retention policy in force.
+    pub fn policy(&self) -> &RetentionPolicy {
+        &self.policy
+    }
+
+    /// Returns how long `record` is kept, taking its own override into account.
+    pub fn retention_of(&self, record: &SessionRecord) -> Retention {
+        record
+            .retention
+            .unwrap_or_else(|| self.policy.for_label(record.peer_label.as_deref()))
+    }
+
+    /// Overrides the retention of the session in progress.
+    ///
+    /// `None` returns it to the policy.
+    ///
+    /// # Returns
+    ///
+    /// False if no session is being tracked.
+    pub fn set_retention(&mut self, retention: Option<Retention>) -> bool {
+        let Some(record) = &mut self.current else {
+            return false;
+        };
+        record.retention = retention;
+        true
+    }
+
+    /// Starts tracking a new connection attempt.
+    ///
+    /// An unfinished previous record is discarded; it never reached a
+    /// terminal state the controller could observe.
+    pub fn begin(
+        &mut self,
+        connection_id: String,
+        peer: SocketAddr,
+        peer_label: Option<String>,
+        local_nat_type: NatType,
+    ) {
+        self.current = Some(SessionRecord {
+            connection_id: Some(connection_id),
+            started_at: unix_timestamp(),
+            ended_at: None,
+            peer,
+            peer_label,
+            local_nat_type,
+            transport: None,
+            encryption_algo: None,
+            bytes_sent: 0,
+            bytes_received: 0,
+            disconnect_reason: None,
+            error: None,
+            retention: None,
+        });
+    }
+
+    /// Records the transport and cipher once the session is established.
+    pub fn established(&mut self, transport: &str, encryption_algo: Option<String>) {
+        if let Some(record) = &mut self.current {
+            record.transport = Some(transport.to_string());
+            record.encryption_algo = encryption_algo;
+        }
+    }
+
+    /// Closes the active record and persists it.
+    ///
+    /// Does nothing if no session is being tracked. A session-only record is
+    /// dropped instead of kept.
+    pub fn finish(
+        &mut self,
+        reason: DisconnectReason,
+        error: Option<String>,
+        bytes_sent: u64,
+        bytes_received: u64,
+    ) {
+        let Some(mut record) = self.current.take() else {
+            return;
+        };
+
+        record.ended_at = Some(unix_timestamp());
+        record.disconnect_reason = Some(reason);
+        record.error = error;
+        record.bytes_sent = bytes_sent;
+        record.bytes_received = bytes_received;
+
+        if self.retention_of(&record) == Retention::SessionOnly {
+            return;
+        }
+        self.store(record);
+    }
+
+    /// Adds a finished record mirrored from one of our own nodes.
+    ///
+    /// # Returns
+    ///
+    /// False if the record was not finished, is already known, or is
+    /// session-only under our policy.
+    pub fn merge(&mut self, record: SessionRecord) -> bool {
+        if record.ended_at.is_none()
+            || self.records.iter().any(|r| {
+                r.connection_id == record.connection_id && r.started_at == record.started_at
+            })
+            || self.retention_of(&record) == Retention::SessionOnly
+        {
+            return false;
+        }
+        self.store(record);
+        true
+    }
+
+    /// Persists a finished record and keeps it in memory in start order.
+    fn store(&mut self, record: SessionRecord) {
+        if let Some(path) = &self.path
+            && let Err(e) = append_jsonl(path, &record)
+        {
+            warn!("Failed to persist session record: {}", e);
+        }
+
+        let at = self
+            .records
+            .partition_point(|r| r.started_at <= record.started_at);
+        self.records.insert(at, record);
+        if self.records.len() > MAX_RECORDS {
+            self.records.pop_front();
+        }
+    }
+
+    /// Returns true if the session being tracked will be kept once it ends.
+    pub fn keeps_current(&self) -> bool {
+        self.current
+            .as_ref()
+            .is_some_and(|record| self.retention_of(record) != Retention::SessionOnly)
+    }
+
+    /// Returns the session currently being tracked, if any.
+    pub fn current(&self) -> Option<&SessionRecord> {
+        self.current.as_ref()
+    }
+
+    /// Returns finished sessions, oldest first.
+    pub fn records(&self) -> impl DoubleEndedIterator<Item = &SessionRecord> {
+        self.records.iter()
+    }
+
+    /// Deletes finished records the retention policy no longer keeps.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - Current unix timestamp (seconds).
+    ///
+    /// # Returns
+    ///
+    /// The number of records deleted from the file, or from memory when the
+    /// log is not backed by one.
+    pub fn prune(&mut self, now: u64) -> Result<usize> {
+        let keeps = |record: &SessionRecord| {
+            let ended_at = record.ended_at.unwrap_or(record.started_at);
+            self.retention_of(record).keeps(ended_at, now)
+        };
+
+        let before = self.records.len();
+        let records: VecDeque<_> = self.records.iter().filter(|r| keeps(r)).cloned().collect();
+        let mut pruned = before - records.len();
+
+        // The file holds more records than are loaded, so it is filtered as a whole
+        if let Some(path) = &self.path {
+            let stored: Vec<SessionRecord> = read_jsonl_tail(path, usize::MAX)?;
+            let kept: Vec<_> = stored.iter().filter(|r| keeps(r)).collect();
+            pruned = stored.len() - kept.len();
+            if pruned > 0 {
+                write_jsonl(path, &kept)?;
+            }
+        }
+
+        self.records = records;
+        Ok(pruned)
+    }
+
+    /// Forgets every session, including the one in progress, and deletes the file.
+    ///
+    /// # Returns
+    ///
+    /// The number of records forgotten.
+    pub fn clear(&mut self) -> Result<usize> {
+        let cleared = self.records.len() + usize::from(self.current.is_some());
+        self.records.clear();
+        self.current = None;
+        if let Some(path) = &self.path {
+            remove_file(path)?;
+        }
+        Ok(cleared)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::TempDir;
+
+    fn peer() -> SocketAddr {
+        "203.0.113.7:41234".parse().unwrap()
+    }
+
+    #[test]
+    fn test_finish_without_begin_is_noop() {
+        let mut log = SessionLog::default();
+        log.finish(DisconnectReason::LocalRequest, None, 0, 0);
+        assert_eq!(log.records().count(), 0);
+    }
+
+    #[test]
+    fn test_session_lifecycle() {
+        let mut log = SessionLog::default();
+
+        log.begin("c1".into(), peer(), Some("Bob".into()), NatType::Cone);
+        assert!(log.current().is_some());
+
+        log.established("KCP", Some("ChaCha20-Poly1305".into()));
+        log.finish(DisconnectReason::PeerRequest, None, 128, 256);
+
+        assert!(log.current().is_none());
+        let record = log.records().next().unwrap();
+        assert_eq!(record.peer, peer());
+        assert_eq!(record.peer_label.as_deref(), Some("Bob"));
+        assert_eq!(record.local_nat_type, NatType::Cone);
+        assert_eq!(record.transport.as_deref(), Some("KCP"));
+        assert_eq!(record.bytes_sent, 128);
+        assert_eq!(record.bytes_received, 256);
+        assert_eq!(
+            record.disconnect_reason,
+            Some(DisconnectReason::PeerRequest)
+        );
+        assert!(record.ended_at.is_some());
+    }
+
+    #[test]
+    fn test_records_are_capped() {
+        let mut log = SessionLog::default();
+        for _ in 0..MAX_RECORDS + 5 {
+            log.begin("c1".into(), peer(), None, NatType::Unknown);
+            log.finish(
+                DisconnectReason::HandshakeFailed,
+                Some("timeout".into()),
+                0,
+                0,
+            );
+        }
+        assert_eq!(log.records().count(), MAX_RECORDS);
+    }
+
+    #[test]
+    fn test_retention_prunes_and_skips_session_only() {
+        let dir = TempDir::new("retention");
+        let path = dir.join("sessions.jsonl");
+
+        let mut log = SessionLog::open(path.clone());
+        log.set_policy(RetentionPolicy {
+            default: Retention::Days(1),
+            contacts: [("Bob".to_string(), Retention::Forever)].into(),
+        });
+        for label in [None, Some("Bob")] {
+            log.begin("c1".into(), peer(), label.map(Into::into), NatType::Cone);
+            log.finish(DisconnectReason::PeerRequest, None, 0, 0);
+        }
+        // The conversation override wins over the contact's policy
+        log.begin("c2".into(), peer(), Some("Bob".into()), NatType::Cone);
+        assert!(log.set_retention(Some(Retention::SessionOnly)));
+        log.finish(DisconnectReason::PeerRequest, None, 0, 0);
+        assert!(!log.set_retention(None));
+        assert_eq!(log.records().count(), 2);
+
+        assert_eq!(log.prune(unix_timestamp()).unwrap(), 0);
+        assert_eq!(log.prune(unix_timestamp() + 2 * 24 * 60 * 60).unwrap(), 1);
+        let records: Vec<_> = log.records().collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].peer_label.as_deref(), Some("Bob"));
+        assert_eq!(SessionLog::open(path).records().count(), 1);
+    }
+
+    #[test]
+    fn test_merge_adds_new_finished_records_in_order() {
+        let mut log = SessionLog::default();
+        log.begin("c1".into(), peer(), None, NatType::Cone);
+        log.finish(DisconnectReason::PeerRequest, None, 0, 0);
+
+        // Known by ID and start time, whatever its retention
+        let mut mirrored = log.records().next().unwrap().clone();
+        mirrored.retention = Some(Retention::Forever);
+        assert!(!log.merge(mirrored.clone()));
+
+        mirrored.connection_id = Some("c0".into());
+        mirrored.started_at -= 60;
+        assert!(log.merge(mirrored.clone()));
+        let first = log.records().next().unwrap();
+        assert_eq!(first.connection_id.as_deref(), Some("c0"));
+
+        mirrored.ended_at = None;
+        mirrored.connection_id = Some("c2".into());
+        assert!(!log.merge(mirrored));
+        assert_eq!(log.records().count(), 2);
+    }
+
+    #[test]
+    fn test_open_reloads_persisted_records() {
+        let dir = TempDir::new("audit");
+        let path = dir.join("sessions.jsonl");
+
+        let mut log = SessionLog::open(path.clone());
+        log.begin("c1".into(), peer(), None, NatType::Symmetric);
+        log.finish(DisconnectReason::UpgradeFailed, Some("boom".into()), 0, 0);
+
+        let reopened = SessionLog::open(path);
+        let records: Vec<_> = reopened.records().collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].error.as_deref(), Some("boom"));
+    }
+}
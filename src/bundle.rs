@@ -0,0 +1,131 @@
+//! Diagnostics bundles for bug reports.
+//!
+//! Collects JSON documents into one uncompressed tar archive (ustar) that
+//! users can attach to a report. Written by hand like the pcap captures, as
+//! the format needs no more than a header per file.
+
+use anyhow::{Result, bail};
+use serde::Serialize;
+
+/// Size of a tar block; headers and file data are padded to it.
+const BLOCK: usize = 512;
+
+/// Longest file name a plain ustar header holds.
+const MAX_NAME_LEN: usize = 100;
+
+/// A tar archive being assembled in memory.
+#[derive(Debug)]
+pub struct Bundle {
+    bytes: Vec<u8>,
+    /// Modification time written for every file, as a Unix timestamp.
+    mtime: u64,
+}
+
+impl Bundle {
+    /// Starts an empty archive whose files are dated `mtime`.
+    pub fn new(mtime: u64) -> Self {
+        Self {
+            bytes: Vec::new(),
+            mtime,
+        }
+    }
+
+    /// Adds `value` as pretty-printed JSON under `name`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `value` cannot be serialized or `name` is too long.
+    pub fn add_json<T: Serialize + ?Sized>(&mut self, name: &str, value: &T) -> Result<()> {
+        let data = serde_json::to_vec_pretty(value)?;
+        self.add_file(name, &data)
+    }
+
+    /// Adds a regular file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `name` does not fit a ustar header.
+    pub fn add_file(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        if name.is_empty() || name.len() > MAX_NAME_LEN || !name.is_ascii() {
+            bail!("Invalid file name {:?}", name);
+        }
+
+        let mut header = [0u8; BLOCK];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        write_octal(&mut header[100..108], 0o644);
+        write_octal(&mut header[108..116], 0);
+        write_octal(&mut header[116..124], 0);
+        write_octal(&mut header[124..136], data.len() as u64);
+        write_octal(&mut header[136..148], self.mtime);
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+
+        // The checksum is taken with its own field filled with spaces
+        header[148..156].fill(b' ');
+        let checksum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+        write_octal(&mut header[148..155], u64::from(checksum));
+
+        self.bytes.extend_from_slice(&header);
+        self.bytes.extend_from_slice(data);
+        self.bytes
+            .resize(self.bytes.len().next_multiple_of(BLOCK), 0);
+        Ok(())
+    }
+
+    /// Finishes the archive with the two empty blocks that end it.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.bytes.resize(self.bytes.len() + 2 * BLOCK, 0);
+        self.bytes
+    }
+}
+
+/// Writes `value` as zero-padded octal followed by a NUL, filling `field`.
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}\0", value, width = field.len() - 1);
+    field.copy_from_slice(&digits.as_bytes()[digits.len() - field.len()..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Reads an octal header field.
+    fn octal(field: &[u8]) -> u64 {
+        let text = std::str::from_utf8(field).unwrap();
+        u64::from_str_radix(text.trim_matches(['\0', ' ']), 8).unwrap()
+    }
+
+    #[test]
+    fn test_writes_a_readable_tar() {
+        let mut bundle = Bundle::new(1_700_000_000);
+        bundle
+            .add_json("version.json", &json!({ "version": "0.1.0" }))
+            .unwrap();
+        bundle.add_file("empty.txt", b"").unwrap();
+        assert!(bundle.add_file(&"x".repeat(101), b"").is_err());
+        let tar = bundle.finish();
+
+        assert_eq!(tar.len() % BLOCK, 0);
+        let header = &tar[..BLOCK];
+        assert!(header.starts_with(b"version.json\0"));
+        assert_eq!(&header[257..262], b"ustar");
+        assert_eq!(octal(&header[136..148]), 1_700_000_000);
+
+        let mut blank = header.to_vec();
+        blank[148..156].fill(b' ');
+        let sum: u64 = blank.iter().map(|&b| u64::from(b)).sum();
+        assert_eq!(octal(&header[148..156]), sum);
+
+        let size = octal(&header[124..136]) as usize;
+        let data: serde_json::Value = serde_json::from_slice(&tar[BLOCK..BLOCK + size]).unwrap();
+        assert_eq!(data["version"], "0.1.0");
+
+        // Next header, then the two end blocks
+        let next = BLOCK + size.next_multiple_of(BLOCK);
+        assert!(tar[next..].starts_with(b"empty.txt\0"));
+        assert_eq!(tar.len(), next + 3 * BLOCK);
+        assert!(tar[next + BLOCK..].iter().all(|&b| b == 0));
+    }
+}
@@ -0,0 +1,147 @@
+//! Captive portal detection.
+//!
+//! Hotel and airport networks often resolve DNS but drop UDP and redirect
+//! HTTP to a login page until the user signs in, which shows up as a plain
+//! STUN timeout. When a STUN query times out, a small plain-HTTP request to
+//! a page with known content tells the two apart: a redirect or different
+//! content means a portal is in the way.
+
+use crate::net::StunError;
+use anyhow::Result;
+use reqwest::{Client, header::LOCATION, redirect};
+use serde::{Deserialize, Serialize};
+use tokio::time::Duration;
+use tracing::debug;
+
+/// Maximum time spent on the probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Bytes of the probe page compared with the expected content.
+const MAX_BODY_BYTES: usize = 1024;
+
+/// A page whose content is known, fetched over plain HTTP.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortalProbe {
+    pub url: String,
+    /// Text the page starts with when nothing intercepts it.
+    pub expect: String,
+}
+
+impl Default for PortalProbe {
+    fn default() -> Self {
+        Self {
+            url: "http://detectportal.firefox.com/success.txt".to_string(),
+            expect: "success".to_string(),
+        }
+    }
+}
+
+/// Builds the HTTP client used for the probe; it never follows redirects.
+pub fn client() -> Result<Client> {
+    Ok(Client::builder()
+        .timeout(PROBE_TIMEOUT)
+        .redirect(redirect::Policy::none())
+        .user_agent(concat!("GhostLink/", env!("CARGO_PKG_VERSION")))
+        .build()?)
+}
+
+/// Fetches the probe page.
+///
+/// # Returns
+///
+/// * `Some(String)` - A portal intercepted the request; where it points to,
+///   or the probe URL if it served its own page.
+/// * `None` - The page came back intact, or HTTP failed too.
+pub async fn detect(client: &Client, probe: &PortalProbe) -> Option<String> {
+    let response = match client.get(&probe.url).send().await {
+        Ok(response) => response,
+        Err(e) => {
+            debug!("Captive portal probe failed: {}", e);
+            return None;
+        }
+    };
+
+    if response.status().is_redirection() {
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .unwrap_or(&probe.url);
+        return Some(location.to_string());
+    }
+
+    let intact = response.status().is_success()
+        && response.bytes().await.is_ok_and(|body| {
+            body[..body.len().min(MAX_BODY_BYTES)].starts_with(probe.expect.as_bytes())
+        });
+    (!intact).then(|| probe.url.clone())
+}
+
+/// Reclassifies a STUN timeout as a captive portal if the probe finds one.
+///
+/// Other errors, and timeouts without a portal, are returned as they are.
+pub async fn classify(client: &Client, probe: &PortalProbe, error: StunError) -> StunError {
+    if error != StunError::Timeout {
+        return error;
+    }
+    match detect(client, probe).await {
+        Some(portal) => StunError::CaptivePortal(portal),
+        None => error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, http::StatusCode, response::Redirect, routing::get};
+
+    async fn serve(app: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_detects_redirects_and_substituted_pages() {
+        let base = serve(
+            Router::new()
+                .route("/ok", get(|| async { "success\n" }))
+                .route(
+                    "/login",
+                    get(|| async { Redirect::temporary("http://portal.example/login") }),
+                )
+                .route("/page", get(|| async { "<html>Sign in</html>" }))
+                .route("/blocked", get(|| async { StatusCode::FORBIDDEN })),
+        )
+        .await;
+        let client = client().unwrap();
+        let probe = |path: &str| PortalProbe {
+            url: format!("{}{}", base, path),
+            ..PortalProbe::default()
+        };
+
+        assert_eq!(detect(&client, &probe("/ok")).await, None);
+        assert_eq!(
+            detect(&client, &probe("/login")).await.as_deref(),
+            Some("http://portal.example/login")
+        );
+        assert_eq!(
+            detect(&client, &probe("/page")).await,
+            Some(probe("/page").url)
+        );
+        assert!(detect(&client, &probe("/blocked")).await.is_some());
+
+        // Only timeouts are looked into
+        let dns = StunError::DnsFailure("x".into());
+        assert_eq!(classify(&client, &probe("/login"), dns.clone()).await, dns);
+        assert!(matches!(
+            classify(&client, &probe("/page"), StunError::Timeout).await,
+            StunError::CaptivePortal(_)
+        ));
+        assert_eq!(
+            classify(&client, &probe("/ok"), StunError::Timeout).await,
+            StunError::Timeout
+        );
+    }
+}
@@ -0,0 +1,230 @@
+//! PCAP writer for GhostLink's own UDP traffic.
+//!
+//! Packets are written with synthesised IP and UDP headers (link type RAW) so
+//! the file opens directly in Wireshark without capturing the whole interface.
+
+use anyhow::{Context, Result};
+use std::{
+    fs::{self, File},
+    io::Write,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Classic pcap magic (microsecond timestamps); all header fields are little-endian.
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+/// Largest packet stored per record.
+const SNAPLEN: u32 = 65_535;
+/// LINKTYPE_RAW: each record starts with an IPv4 or IPv6 header.
+const LINKTYPE_RAW: u32 = 101;
+/// IP protocol number for UDP.
+const IPPROTO_UDP: u8 = 17;
+/// TTL / hop limit written into synthesised headers.
+const DEFAULT_TTL: u8 = 64;
+
+/// An open capture file.
+#[derive(Debug)]
+pub struct PcapWriter {
+    file: File,
+    path: PathBuf,
+    redact: bool,
+    packets: u64,
+}
+
+impl PcapWriter {
+    /// Creates `path` and writes the pcap global header.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Destination file. Parent directories are created on demand.
+    /// * `redact` - Replace payload bytes with zeroes, keeping only lengths and headers.
+    pub fn create(path: &Path, redact: bool) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+        }
+
+        let mut file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes()); // version major
+        header.extend_from_slice(&4u16.to_le_bytes()); // version minor
+        header.extend_from_slice(&0i32.to_le_bytes()); // thiszone
+        header.extend_from_slice(&0u32.to_le_bytes()); // sigfigs
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        file.write_all(&header)?;
+
+        Ok(Self {
+            file,
+            path: path.to_path_buf(),
+            redact,
+            packets: 0,
+        })
+    }
+
+    /// Appends one UDP datagram.
+    ///
+    /// # Arguments
+    ///
+    /// * `src` - Sender address.
+    /// * `dst` - Receiver address.
+    /// * `payload` - UDP payload as seen on the socket.
+    pub fn write_packet(&mut self, src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Result<()> {
+        let packet = build_ip_udp(src, dst, payload, self.redact);
+        let captured = packet.len().min(SNAPLEN as usize);
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut record = Vec::with_capacity(16 + captured);
+        record.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&now.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(captured as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&packet[..captured]);
+
+        // One write per record so an interrupted capture stays readable.
+        self.file.write_all(&record)?;
+        self.packets += 1;
+        Ok(())
+    }
+
+    /// Path of the capture file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of packets written so far.
+    pub fn packets(&self) -> u64 {
+        self.packets
+    }
+}
+
+/// Builds an IP + UDP packet around `payload`.
+///
+/// The IP family follows `dst`; a `src` of the other family (e.g., a wildcard
+/// bind) is replaced by the unspecified address.
+fn build_ip_udp(src: SocketAddr, dst: SocketAddr, payload: &[u8], redact: bool) -> Vec<u8> {
+    let udp_len = 8 + payload.len();
+
+    let mut packet = match dst.ip() {
+        IpAddr::V4(dst_ip) => {
+            let src_ip = match src.ip() {
+                IpAddr::V4(ip) => ip,
+                IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+            };
+            ipv4_header(src_ip, dst_ip, udp_len)
+        }
+        IpAddr::V6(dst_ip) => {
+            let src_ip = match src.ip() {
+                IpAddr::V6(ip) => ip,
+                IpAddr::V4(_) => Ipv6Addr::UNSPECIFIED,
+            };
+            ipv6_header(src_ip, dst_ip, udp_len)
+        }
+    };
+
+    packet.extend_from_slice(&src.port().to_be_bytes());
+    packet.extend_from_slice(&dst.port().to_be_bytes());
+    packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    packet.extend_from_slice(&0u16.to_be_bytes()); // checksum not computed
+
+    if redact {
+        packet.resize(packet.len() + payload.len(), 0);
+    } else {
+        packet.extend_from_slice(payload);
+    }
+    packet
+}
+
+fn ipv4_header(src: Ipv4Addr, dst: Ipv4Addr, udp_len: usize) -> Vec<u8> {
+    let total_len = (20 + udp_len) as u16;
+
+    let mut header = vec![0x45, 0x00];
+    header.extend_from_slice(&total_len.to_be_bytes());
+    header.extend_from_slice(&[0x00, 0x00, 0x40, 0x00]); // id, DF
+    header.extend_from_slice(&[DEFAULT_TTL, IPPROTO_UDP, 0x00, 0x00]);
+    header.extend_from_slice(&src.octets());
+    header.extend_from_slice(&dst.octets());
+
+    let checksum = ipv4_checksum(&header);
+    header[10..12].copy_from_slice(&checksum.to_be_bytes());
+    header
+}
+
+fn ipv6_header(src: Ipv6Addr, dst: Ipv6Addr, udp_len: usize) -> Vec<u8> {
+    let mut header = vec![0x60, 0x00, 0x00, 0x00];
+    header.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    header.extend_from_slice(&[IPPROTO_UDP, DEFAULT_TTL]);
+    header.extend_from_slice(&src.octets());
+    header.extend_from_slice(&dst.octets());
+    header
+}
+
+/// RFC 791 header checksum.
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|pair| u32::from(u16::from_be_bytes([pair[0], pair[1]])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::TempDir;
+
+    #[test]
+    fn test_ipv4_header_checksum_verifies() {
+        let header = ipv4_header(
+            Ipv4Addr::new(192, 0, 2, 1),
+            Ipv4Addr::new(198, 51, 100, 2),
+            12,
+        );
+        assert_eq!(header.len(), 20);
+        // Summing a header including its checksum yields zero.
+        assert_eq!(ipv4_checksum(&header), 0);
+    }
+
+    #[test]
+    fn test_write_packet_layout() {
+        let dir = TempDir::new("capture");
+        let path = dir.join("test.pcap");
+        let src: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        let dst: SocketAddr = "198.51.100.2:6000".parse().unwrap();
+
+        let mut writer = PcapWriter::create(&path, false).unwrap();
+        writer.write_packet(src, dst, b"ping").unwrap();
+        assert_eq!(writer.packets(), 1);
+        drop(writer);
+
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(&bytes[..4], &PCAP_MAGIC.to_le_bytes());
+        assert_eq!(&bytes[20..24], &LINKTYPE_RAW.to_le_bytes());
+
+        let packet = &bytes[24 + 16..];
+        assert_eq!(packet.len(), 20 + 8 + 4);
+        assert_eq!(&packet[20..22], &5000u16.to_be_bytes());
+        assert_eq!(&packet[22..24], &6000u16.to_be_bytes());
+        assert_eq!(&packet[28..], b"ping");
+    }
+
+    #[test]
+    fn test_redacted_payload_keeps_length() {
+        let src: SocketAddr = "[2001:db8::1]:5000".parse().unwrap();
+        let dst: SocketAddr = "[2001:db8::2]:6000".parse().unwrap();
+
+        let packet = build_ip_udp(src, dst, b"secret", true);
+        assert_eq!(packet.len(), 40 + 8 + 6);
+        assert_eq!(&packet[48..], &[0u8; 6]);
+    }
+}
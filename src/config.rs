@@ -1,4 +1,10 @@
+use crate::{
+    captive_portal::PortalProbe, ddns::DdnsSettings, history_export::ExportSettings,
+    keep_alive::KeepAliveTarget, mirror::MirrorSettings, power_profile::PowerHints,
+    retention::RetentionPolicy, update::UpdateCheck,
+};
 use serde::{Deserialize, Serialize};
+use std::{fmt, net::IpAddr, ops::RangeInclusive, path::PathBuf};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum EncryptionMode {
@@ -6,29 +12,315 @@ pub enum EncryptionMode {
     Aes256Gcm,
 }
 
+/// DiffServ code points for marking outgoing packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Dscp {
+    /// Best effort (CS0).
+    Default,
+    /// Low-priority bulk data (CS1).
+    Scavenger,
+    /// High-throughput data such as file transfer (AF11).
+    Af11,
+    /// Interactive video or chat (AF41).
+    Af41,
+    /// Expedited forwarding for voice (EF).
+    Ef,
+}
+
+impl Dscp {
+    /// Returns the 6-bit code point.
+    pub fn code_point(self) -> u8 {
+        match self {
+            Dscp::Default => 0,
+            Dscp::Scavenger => 8,
+            Dscp::Af11 => 10,
+            Dscp::Af41 => 34,
+            Dscp::Ef => 46,
+        }
+    }
+}
+
+/// How to pick another UDP port when `client_port` is already taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PortRetry {
+    /// Fail startup.
+    Disabled,
+    /// Try the following ports in `port_range`, wrapping around.
+    Sequential,
+    /// Try random ports in `port_range`.
+    Random,
+}
+
+/// A Unix domain socket to serve the web UI and API on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ApiSocket {
+    /// Socket file at this path. Only its owner may connect until its
+    /// permissions are loosened, e.g. for a reverse proxy's group.
+    Path(PathBuf),
+    /// Linux abstract-namespace socket. It has no file permissions: any
+    /// local user can connect.
+    Abstract(String),
+}
+
+impl fmt::Display for ApiSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiSocket::Path(path) => write!(f, "unix:{}", path.display()),
+            ApiSocket::Abstract(name) => write!(f, "unix:@{}", name),
+        }
+    }
+}
+
+/// A command the peer may run on this machine in assist mode.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AssistGrant {
+    /// Name the peer asks for.
+    pub name: String,
+    /// Program to execute, without a shell.
+    pub program: String,
+    /// Fixed arguments. The peer cannot add or change them.
+    pub args: Vec<String>,
+    /// Contact labels allowed to run it. Guests and peers without a saved
+    /// contact never are.
+    pub allowed_contacts: Vec<String>,
+}
+
+/// A local directory the peer may browse read-only.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SharedFolder {
+    /// Name the peer sees.
+    pub name: String,
+    pub path: PathBuf,
+    /// Contact labels allowed to browse it. Peers without a saved contact never are.
+    pub allowed_contacts: Vec<String>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub client_port: u16,
+    /// Fallback when `client_port` is in use. Ignored for port 0.
+    pub port_retry: PortRetry,
+    /// Ports the fallback may choose from.
+    pub port_range: RangeInclusive<u16>,
+    /// Fallback ports tried before giving up.
+    pub port_retry_attempts: u32,
+    /// Extra local interface addresses to bind standby sockets on
+    /// (e.g., Wi-Fi alongside Ethernet). Empty binds only the wildcard socket.
+    pub extra_bind_addrs: Vec<IpAddr>,
     pub stun_server: String,
     pub stun_verifier: String,
+    /// Where NAT keep-alives go, in order of preference; later entries are
+    /// used when earlier ones cannot be reached.
+    pub keep_alive_targets: Vec<KeepAliveTarget>,
+    /// Dynamic DNS provider to keep a hostname pointed at the public IP.
+    /// `None` disables updates.
+    pub ddns: Option<DdnsSettings>,
     pub web_port: u16,
+    /// Ports tried in order when `web_port` is in use. An empty range
+    /// (e.g. `1..=0`) fails startup instead.
+    pub web_port_fallback: RangeInclusive<u16>,
+    /// Open the web UI in the default browser once it is listening.
+    pub open_browser: bool,
+    /// Serve the web UI and API over TCP on `web_port`. Turn off to serve
+    /// them on `api_socket` only.
+    pub web_tcp: bool,
+    /// Unix domain socket to serve the web UI and API on as well.
+    pub api_socket: Option<ApiSocket>,
+    /// Path prefix to serve the web UI and API under, e.g. `/ghostlink`
+    /// behind a reverse proxy. Empty serves them at the root.
+    pub web_base_path: String,
+    /// Reverse proxies whose `X-Forwarded-For` and `X-Forwarded-Proto`
+    /// headers are believed. Requests on `api_socket` are always trusted.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Accept loopback peer addresses in `/api/connect`, e.g. to run two
+    /// nodes on one machine. The echo bot's address is always accepted.
+    pub allow_loopback_peers: bool,
     pub handshake_timeout_secs: u64,
     pub punch_hole_secs: u64,
     pub disconnect_timeout_ms: u64,
+    /// Seconds without hearing from a connected peer before the session is
+    /// dropped. Quiet sessions are probed with heartbeats well before that.
+    pub peer_timeout_secs: u64,
+    /// Seconds an unanswered incoming connection request waits before it is rejected.
+    pub incoming_prompt_secs: u64,
+    /// Incoming requests shown at once; further requesters are ignored.
+    pub max_pending_incoming: usize,
+    /// DSCP marking for outgoing packets. `None` leaves the OS default.
+    pub dscp: Option<Dscp>,
+    /// IP TTL for outgoing packets. `None` leaves the OS default.
+    pub ttl: Option<u32>,
     pub encryption_mode: EncryptionMode,
+    /// Offer to pad frames to random bucket sizes so message lengths are hidden.
+    /// Only takes effect when the peer offers it too. The traffic is still
+    /// recognisable as KCP.
+    pub traffic_padding: bool,
+    /// Record STUN and handshake packets for `/api/debug/handshake-log`.
+    pub debug_transcript: bool,
+    /// Fetch titles for links in received messages. Off by default because
+    /// it contacts the linked sites from this machine.
+    pub link_previews: bool,
+    /// Commands the peer may run here (assist mode). Empty disables assist mode.
+    pub assist_grants: Vec<AssistGrant>,
+    /// Folders the peer may browse and download from.
+    pub shares: Vec<SharedFolder>,
+    /// Secret shared by all of your own nodes. A contact label only names an
+    /// address, so remote wipe, mirroring and wake relaying also require the
+    /// peer to prove it knows this secret inside the session. `None`
+    /// disables all three.
+    pub own_node_secret: Option<String>,
+    /// Contact labels of your own other nodes, which may wipe this node's
+    /// history remotely. Empty disables remote wipe.
+    pub wipe_contacts: Vec<String>,
+    /// Contact labels of your own other nodes, which may have this node
+    /// send Wake-on-LAN packets on its network. Empty disables relaying.
+    pub wake_relay_contacts: Vec<String>,
+    /// Your own other nodes to mirror contacts and session history with.
+    pub mirror: MirrorSettings,
+    /// How long session history is kept, globally and per contact label.
+    pub retention: RetentionPolicy,
+    /// Periodic export of session history to JSON Lines or Markdown files.
+    pub history_export: ExportSettings,
+    /// Page fetched when STUN times out, to tell a captive portal from
+    /// blocked UDP. `None` skips the check.
+    pub captive_portal_probe: Option<PortalProbe>,
+    /// How long cached STUN results are trusted at startup, and at most
+    /// between keep-alive STUN queries. 0 disables the cache.
+    pub nat_cache_ttl_secs: u64,
+    /// Session traffic (both directions) allowed per session. `None` is unlimited.
+    pub session_data_cap_bytes: Option<u64>,
+    /// Session traffic allowed per calendar month (UTC). `None` is unlimited.
+    pub monthly_data_cap_bytes: Option<u64>,
+    /// Percentages of a data cap at which a warning event is raised.
+    pub data_warn_percents: Vec<u8>,
+    /// Refuse shared file reads in both directions once a data cap is used up.
+    pub data_cap_hard_stop: bool,
+    /// Whether the link is metered and the machine on battery, where
+    /// detection gets it wrong. Either stretches NAT keep-alives.
+    pub power_hints: PowerHints,
+    /// Release manifest to check for new versions. `None`, the default,
+    /// never contacts it.
+    pub update_check: Option<UpdateCheck>,
+    /// Directory for persistent data (session history, caches).
+    pub data_dir: PathBuf,
 }
 
 impl Config {
     pub fn load() -> Self {
         Self {
             client_port: 0,
+            port_retry: PortRetry::Sequential,
+            port_range: 49152..=65535,
+            port_retry_attempts: 20,
+            extra_bind_addrs: Vec::new(),
             stun_server: "stun.l.google.com:19302".to_string(),
             stun_verifier: "stun4.l.google.com:19302".to_string(),
+            keep_alive_targets: vec![KeepAliveTarget::StunServer],
+            ddns: None,
             web_port: 8080,
+            web_port_fallback: 8081..=8099,
+            open_browser: false,
+            web_tcp: true,
+            api_socket: None,
+            web_base_path: String::new(),
+            trusted_proxies: Vec::new(),
+            allow_loopback_peers: false,
             handshake_timeout_secs: 30,
             punch_hole_secs: 15,
             disconnect_timeout_ms: 500,
+            peer_timeout_secs: 30,
+            incoming_prompt_secs: 30,
+            max_pending_incoming: 5,
+            dscp: None,
+            ttl: None,
             encryption_mode: EncryptionMode::ChaCha20Poly1305,
+            traffic_padding: false,
+            debug_transcript: false,
+            link_previews: false,
+            assist_grants: Vec::new(),
+            shares: Vec::new(),
+            own_node_secret: None,
+            wipe_contacts: Vec::new(),
+            wake_relay_contacts: Vec::new(),
+            mirror: MirrorSettings::default(),
+            retention: RetentionPolicy::default(),
+            history_export: ExportSettings::default(),
+            captive_portal_probe: Some(PortalProbe::default()),
+            nat_cache_ttl_secs: 600,
+            session_data_cap_bytes: None,
+            monthly_data_cap_bytes: None,
+            data_warn_percents: vec![80, 100],
+            data_cap_hard_stop: false,
+            power_hints: PowerHints::default(),
+            update_check: None,
+            data_dir: default_data_dir(),
         }
     }
+
+    /// Path of the session audit log.
+    pub fn sessions_path(&self) -> PathBuf {
+        self.data_dir.join("sessions.jsonl")
+    }
+
+    /// Path of the link statistics of past sessions.
+    pub fn stats_history_path(&self) -> PathBuf {
+        self.data_dir.join("stats_history.jsonl")
+    }
+
+    /// Path of the cached public IP and NAT type.
+    pub fn nat_cache_path(&self) -> PathBuf {
+        self.data_dir.join("nat_cache.json")
+    }
+
+    /// Path of the address book.
+    pub fn contacts_path(&self) -> PathBuf {
+        self.data_dir.join("contacts.json")
+    }
+
+    /// Path of the read-only observer tokens (hashed).
+    pub fn observers_path(&self) -> PathBuf {
+        self.data_dir.join("observers.json")
+    }
+
+    /// Path of the messages waiting for their send time.
+    pub fn scheduled_path(&self) -> PathBuf {
+        self.data_dir.join("scheduled.json")
+    }
+
+    /// Path of the web UI preferences.
+    pub fn ui_preferences_path(&self) -> PathBuf {
+        self.data_dir.join("ui_preferences.json")
+    }
+
+    /// Path of this month's data usage total.
+    pub fn data_usage_path(&self) -> PathBuf {
+        self.data_dir.join("data_usage.json")
+    }
+
+    /// Path of the report written when GhostLink panics.
+    pub fn crash_report_path(&self) -> PathBuf {
+        self.data_dir.join("crash_report.json")
+    }
+
+    /// Path of the event log of the current run.
+    pub fn event_log_path(&self) -> PathBuf {
+        self.data_dir.join("event_log.jsonl")
+    }
+
+    /// Directory pcap captures are written to.
+    pub fn captures_dir(&self) -> PathBuf {
+        self.data_dir.join("captures")
+    }
+
+    /// Directory history exports are written to unless configured otherwise.
+    pub fn exports_dir(&self) -> PathBuf {
+        self.data_dir.join("exports")
+    }
+}
+
+/// Resolves `~/.ghostlink`, falling back to `./.ghostlink` when `HOME` is unset.
+fn default_data_dir() -> PathBuf {
+    std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join(".ghostlink")
 }
@@ -0,0 +1,569 @@
+//! Address book for GhostLink.
+//!
+//! Maps peer addresses to display labels so the UI and session history can
+//! say "Bob" instead of `203.0.113.7:41234`. Stored as a single JSON file in
+//! the data directory.
+//!
+//! Besides its main address, a contact may list other endpoints the peer is
+//! known by, such as a LAN address or a second uplink. Connecting tries the
+//! address asked for first, then the contact's other UDP endpoints in order,
+//! and whichever answers becomes the main address. A contact saved with a
+//! hostname, such as a DDNS name, has its main address looked up again
+//! before it is dialled.
+
+use crate::{
+    storage::{read_json, unix_timestamp, write_json},
+    wol::WakeTarget,
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{iter, net::SocketAddr, path::PathBuf};
+use tracing::warn;
+
+/// Longest label accepted, in characters.
+pub const MAX_LABEL_LEN: usize = 32;
+
+/// Most extra endpoints kept per contact.
+pub const MAX_ENDPOINTS: usize = 8;
+
+/// Longest onion host or relay hint accepted, in characters.
+const MAX_ENDPOINT_LEN: usize = 128;
+
+/// Another way to reach a saved peer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Endpoint {
+    /// A UDP address, such as the peer's LAN address or another uplink.
+    Udp(SocketAddr),
+    /// A Tor onion service. Kept for reference only: there is no Tor
+    /// transport, so it is never dialled.
+    Onion { host: String, port: u16 },
+    /// How to reach the peer through a relay. Kept for reference only.
+    Relay(String),
+}
+
+/// A saved peer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Contact {
+    pub label: String,
+    pub addr: SocketAddr,
+    /// Unix timestamp (seconds) the contact was saved.
+    pub added_at: u64,
+    /// Other endpoints of the peer, in the order they are tried after `addr`.
+    #[serde(default)]
+    pub endpoints: Vec<Endpoint>,
+    /// `host:port` that `addr` is looked up from when dialling.
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// How to wake the peer's machine with Wake-on-LAN.
+    #[serde(default)]
+    pub wake: Option<WakeTarget>,
+}
+
+impl Contact {
+    /// Returns true if `addr` is the main address or a UDP endpoint.
+    fn reachable_at(&self, addr: SocketAddr) -> bool {
+        self.addr == addr || self.endpoints.contains(&Endpoint::Udp(addr))
+    }
+
+    /// Returns the UDP endpoints, in order.
+    fn udp_endpoints(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.endpoints.iter().filter_map(|endpoint| match endpoint {
+            Endpoint::Udp(addr) => Some(*addr),
+            _ => None,
+        })
+    }
+}
+
+/// In-memory view of the address book, backed by a JSON file.
+#[derive(Debug, Clone, Default)]
+pub struct Contacts {
+    /// File the contacts are saved to. `None` keeps them in memory only.
+    path: Option<PathBuf>,
+    entries: Vec<Contact>,
+}
+
+impl Contacts {
+    /// Opens the address book stored at `path`.
+    pub fn open(path: PathBuf) -> Self {
+        let entries = read_json(&path).unwrap_or_else(|e| {
+            warn!("Failed to load contacts: {:#}", e);
+            None
+        });
+
+        Self {
+            path: Some(path),
+            entries: entries.unwrap_or_default(),
+        }
+    }
+
+    /// Returns all contacts in the order they were added.
+    pub fn all(&self) -> &[Contact] {
+        &self.entries
+    }
+
+    /// Returns the contact `addr` belongs to, preferring a main address
+    /// over an extra endpoint.
+    fn find(&self, addr: SocketAddr) -> Option<&Contact> {
+        self.entries
+            .iter()
+            .find(|c| c.addr == addr)
+            .or_else(|| self.entries.iter().find(|c| c.reachable_at(addr)))
+    }
+
+    /// Returns the label saved for `addr`, if any.
+    pub fn label_for(&self, addr: SocketAddr) -> Option<String> {
+        self.find(addr).map(|c| c.label.clone())
+    }
+
+    /// Returns the addresses to try when connecting to `addr`: `addr`
+    /// itself, then the rest of its contact's main address and UDP endpoints.
+    pub fn dial_order(&self, addr: SocketAddr) -> Vec<SocketAddr> {
+        let mut order = vec![addr];
+        if let Some(contact) = self.find(addr) {
+            for candidate in iter::once(contact.addr).chain(contact.udp_endpoints()) {
+                if !order.contains(&candidate) {
+                    order.push(candidate);
+                }
+            }
+        }
+        order
+    }
+
+    /// Returns the label and hostname of the contact whose main address is
+    /// `addr`, if it was saved with a hostname.
+    pub fn hostname_for(&self, addr: SocketAddr) -> Option<(String, String)> {
+        let contact = self.entries.iter().find(|c| c.addr == addr)?;
+        let hostname = contact.hostname.clone()?;
+        Some((contact.label.clone(), hostname))
+    }
+
+    /// Saves a contact, replacing any existing one with the same label or address.
+    ///
+    /// Extra endpoints, hostname and wake target of a contact with the same
+    /// label are kept.
+    pub fn upsert(&mut self, label: String, addr: SocketAddr) -> Result<Contact> {
+        let mut contact = match self.entries.iter().find(|c| c.label == label) {
+            Some(kept) => Contact {
+                addr,
+                added_at: unix_timestamp(),
+                ..kept.clone()
+            },
+            None => Contact {
+                label,
+                addr,
+                added_at: unix_timestamp(),
+                endpoints: Vec::new(),
+                hostname: None,
+                wake: None,
+            },
+        };
+        contact.endpoints.retain(|e| e != &Endpoint::Udp(addr));
+        self.entries
+            .retain(|c| c.label != contact.label && c.addr != addr);
+        self.entries.push(contact.clone());
+        self.save()?;
+        Ok(contact)
+    }
+
+    /// Replaces the extra endpoints of the contact with `label`.
+    ///
+    /// Duplicates, and the main address listed again, are dropped.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(Contact))` - The updated contact.
+    /// * `Ok(None)` - No contact has that label.
+    pub fn set_endpoints(
+        &mut self,
+        label: &str,
+        endpoints: Vec<Endpoint>,
+    ) -> Result<Option<Contact>> {
+        self.update(label, |contact| {
+            contact.endpoints.clear();
+            for endpoint in endpoints {
+                if endpoint != Endpoint::Udp(contact.addr) && !contact.endpoints.contains(&endpoint)
+                {
+                    contact.endpoints.push(endpoint);
+                }
+            }
+        })
+    }
+
+    /// Sets or clears the hostname of the contact with `label`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(Contact))` - The updated contact.
+    /// * `Ok(None)` - No contact has that label.
+    pub fn set_hostname(
+        &mut self,
+        label: &str,
+        hostname: Option<String>,
+    ) -> Result<Option<Contact>> {
+        self.update(label, |contact| contact.hostname = hostname)
+    }
+
+    /// Sets or clears the Wake-on-LAN target of the contact with `label`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(Contact))` - The updated contact.
+    /// * `Ok(None)` - No contact has that label.
+    pub fn set_wake(&mut self, label: &str, wake: Option<WakeTarget>) -> Result<Option<Contact>> {
+        self.update(label, |contact| contact.wake = wake)
+    }
+
+    /// Applies `change` to the contact with `label` and saves it.
+    fn update(
+        &mut self,
+        label: &str,
+        change: impl FnOnce(&mut Contact),
+    ) -> Result<Option<Contact>> {
+        let Some(contact) = self.entries.iter_mut().find(|c| c.label == label) else {
+            return Ok(None);
+        };
+        change(contact);
+        let contact = contact.clone();
+        self.save()?;
+        Ok(Some(contact))
+    }
+
+    /// Moves the contact with `label` to `addr`, which its hostname now
+    /// resolves to. The old address is dropped, and so is any other
+    /// contact saved with `addr`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(true)` - The contact moved.
+    /// * `Ok(false)` - No contact has that label, or it is already at `addr`.
+    pub fn relocate(&mut self, label: &str, addr: SocketAddr) -> Result<bool> {
+        if !self
+            .entries
+            .iter()
+            .any(|c| c.label == label && c.addr != addr)
+        {
+            return Ok(false);
+        }
+        self.entries.retain(|c| c.label == label || c.addr != addr);
+        if let Some(contact) = self.entries.iter_mut().find(|c| c.label == label) {
+            contact.addr = addr;
+            contact.endpoints.retain(|e| e != &Endpoint::Udp(addr));
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Records that a peer answered at `addr`.
+    ///
+    /// If `addr` is one of a contact's extra endpoints, it becomes the main
+    /// address and the old main address is tried first among the others.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(true)` - A contact's main address changed.
+    /// * `Ok(false)` - `addr` is already a main address, or unknown.
+    pub fn reached(&mut self, addr: SocketAddr) -> Result<bool> {
+        if self.entries.iter().any(|c| c.addr == addr) {
+            return Ok(false);
+        }
+        let Some(contact) = self.entries.iter_mut().find(|c| c.reachable_at(addr)) else {
+            return Ok(false);
+        };
+        contact.endpoints.retain(|e| e != &Endpoint::Udp(addr));
+        contact.endpoints.insert(0, Endpoint::Udp(contact.addr));
+        contact.addr = addr;
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Deletes the contact with `label`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(true)` - The contact existed and was removed.
+    /// * `Ok(false)` - No contact has that label.
+    pub fn remove(&mut self, label: &str) -> Result<bool> {
+        let before = self.entries.len();
+        self.entries.retain(|c| c.label != label);
+        if self.entries.len() == before {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Saves a contact mirrored from one of our own nodes.
+    ///
+    /// A contact with the same label or address is replaced only if it was
+    /// saved earlier than `contact`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(true)` - The contact was saved.
+    /// * `Ok(false)` - It is already known, older than ours, or has an
+    ///   invalid label.
+    pub fn merge(&mut self, contact: Contact) -> Result<bool> {
+        if validate_label(&contact.label).as_deref() != Ok(contact.label.as_str()) {
+            return Ok(false);
+        }
+        let newer = self
+            .entries
+            .iter()
+            .filter(|c| c.label == contact.label || c.addr == contact.addr)
+            .all(|c| c.added_at < contact.added_at);
+        if !newer {
+            return Ok(false);
+        }
+        self.entries
+            .retain(|c| c.label != contact.label && c.addr != contact.addr);
+        self.entries.push(contact);
+        self.save()?;
+        Ok(true)
+    }
+
+    fn save(&self) -> Result<()> {
+        match &self.path {
+            Some(path) => write_json(path, &self.entries),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Normalises a user-supplied label.
+///
+/// # Returns
+///
+/// * `Ok(String)` - The trimmed label.
+/// * `Err(String)` - The label is empty, too long, or contains control characters.
+pub fn validate_label(label: &str) -> Result<String, String> {
+    let label = label.trim();
+    if label.is_empty() {
+        return Err("Label must not be empty".into());
+    }
+    if label.chars().count() > MAX_LABEL_LEN {
+        return Err(format!(
+            "Label must be at most {} characters",
+            MAX_LABEL_LEN
+        ));
+    }
+    if label.chars().any(char::is_control) {
+        return Err("Label must not contain control characters".into());
+    }
+    Ok(label.to_string())
+}
+
+/// Checks a user-supplied contact hostname.
+///
+/// # Returns
+///
+/// * `Ok(String)` - The trimmed `host:port`.
+/// * `Err(String)` - The port is missing or invalid, or the host is empty,
+///   too long, or contains whitespace or control characters.
+pub fn validate_hostname(hostname: &str) -> Result<String, String> {
+    let hostname = hostname.trim();
+    let valid = hostname.rsplit_once(':').is_some_and(|(host, port)| {
+        !host.is_empty()
+            && host.len() <= 253
+            && !host.chars().any(|c| c.is_whitespace() || c.is_control())
+            && port.parse::<u16>().is_ok_and(|port| port != 0)
+    });
+    if !valid {
+        return Err("Hostname must be host:port".into());
+    }
+    Ok(hostname.to_string())
+}
+
+/// Checks user-supplied extra endpoints.
+///
+/// # Returns
+///
+/// * `Ok(())` - The endpoints can be saved.
+/// * `Err(String)` - There are too many, or one is malformed.
+pub fn validate_endpoints(endpoints: &[Endpoint]) -> Result<(), String> {
+    if endpoints.len() > MAX_ENDPOINTS {
+        return Err(format!("At most {} endpoints per contact", MAX_ENDPOINTS));
+    }
+    for endpoint in endpoints {
+        match endpoint {
+            Endpoint::Udp(addr) if addr.port() == 0 || addr.ip().is_unspecified() => {
+                return Err(format!("Invalid endpoint address {}", addr));
+            }
+            Endpoint::Onion { host, .. } if !host.ends_with(".onion") => {
+                return Err(format!("{} is not an onion address", host));
+            }
+            Endpoint::Onion { host: text, .. } | Endpoint::Relay(text)
+                if text.trim().is_empty()
+                    || text.chars().count() > MAX_ENDPOINT_LEN
+                    || text.chars().any(char::is_control) =>
+            {
+                return Err(
+                    "Endpoint must be 1 to 128 characters without control characters".into(),
+                );
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::TempDir;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([203, 0, 113, 7], port))
+    }
+
+    #[test]
+    fn test_upsert_replaces_by_label_or_addr() {
+        let mut contacts = Contacts::default();
+        contacts.upsert("Bob".into(), addr(1)).unwrap();
+        contacts.upsert("Alice".into(), addr(2)).unwrap();
+
+        // Same address, new label
+        contacts.upsert("Robert".into(), addr(1)).unwrap();
+        assert_eq!(contacts.label_for(addr(1)).as_deref(), Some("Robert"));
+
+        // Same label, new address
+        contacts.upsert("Alice".into(), addr(3)).unwrap();
+        assert_eq!(contacts.label_for(addr(2)), None);
+        assert_eq!(contacts.all().len(), 2);
+
+        assert!(contacts.remove("Alice").unwrap());
+        assert!(!contacts.remove("Alice").unwrap());
+    }
+
+    #[test]
+    fn test_merge_keeps_the_newer_contact() {
+        let mut contacts = Contacts::default();
+        let bob = contacts.upsert("Bob".into(), addr(1)).unwrap();
+
+        let older = Contact {
+            addr: addr(2),
+            added_at: bob.added_at - 1,
+            ..bob.clone()
+        };
+        assert!(!contacts.merge(older).unwrap());
+        assert!(!contacts.merge(bob.clone()).unwrap());
+
+        let newer = Contact {
+            addr: addr(2),
+            added_at: bob.added_at + 1,
+            ..bob
+        };
+        assert!(contacts.merge(newer).unwrap());
+        assert_eq!(contacts.label_for(addr(2)).as_deref(), Some("Bob"));
+        assert_eq!(contacts.all().len(), 1);
+    }
+
+    #[test]
+    fn test_extra_endpoints_are_dialled_and_promoted() {
+        let mut contacts = Contacts::default();
+        contacts.upsert("Bob".into(), addr(1)).unwrap();
+        let lan = SocketAddr::from(([192, 168, 1, 20], 41234));
+        let onion = Endpoint::Onion {
+            host: "bob.onion".into(),
+            port: 9000,
+        };
+        let bob = contacts
+            .set_endpoints(
+                "Bob",
+                vec![
+                    Endpoint::Udp(addr(1)),
+                    onion.clone(),
+                    Endpoint::Udp(lan),
+                    onion.clone(),
+                ],
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(bob.endpoints, [onion.clone(), Endpoint::Udp(lan)]);
+        assert_eq!(contacts.label_for(lan).as_deref(), Some("Bob"));
+        assert_eq!(contacts.dial_order(addr(1)), [addr(1), lan]);
+        assert_eq!(contacts.dial_order(lan), [lan, addr(1)]);
+        assert_eq!(contacts.dial_order(addr(9)), [addr(9)]);
+
+        // Whichever answered becomes the main address
+        assert!(contacts.reached(lan).unwrap());
+        assert!(!contacts.reached(lan).unwrap());
+        assert_eq!(contacts.all()[0].addr, lan);
+        assert_eq!(contacts.dial_order(lan), [lan, addr(1)]);
+
+        // Re-saving the label keeps the other endpoints
+        contacts.upsert("Bob".into(), addr(1)).unwrap();
+        assert_eq!(contacts.all()[0].endpoints, [onion]);
+
+        assert!(validate_endpoints(&[Endpoint::Relay("relay.example:7000".into())]).is_ok());
+        assert!(validate_endpoints(&[Endpoint::Relay(" ".into())]).is_err());
+        assert!(
+            validate_endpoints(&[Endpoint::Onion {
+                host: "example.com".into(),
+                port: 80
+            }])
+            .is_err()
+        );
+        assert!(validate_endpoints(&[Endpoint::Udp(addr(0))]).is_err());
+    }
+
+    #[test]
+    fn test_hostname_contacts_follow_their_address() {
+        let mut contacts = Contacts::default();
+        contacts.upsert("Bob".into(), addr(1)).unwrap();
+        contacts.upsert("Old".into(), addr(2)).unwrap();
+        assert_eq!(contacts.hostname_for(addr(1)), None);
+
+        let hostname = validate_hostname(" bob.duckdns.org:41234 ").unwrap();
+        contacts
+            .set_hostname("Bob", Some(hostname.clone()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            contacts.hostname_for(addr(1)),
+            Some(("Bob".to_string(), hostname))
+        );
+
+        // The new address replaces the old one, and whoever had it before
+        assert!(contacts.relocate("Bob", addr(2)).unwrap());
+        assert!(!contacts.relocate("Bob", addr(2)).unwrap());
+        assert_eq!(contacts.label_for(addr(2)).as_deref(), Some("Bob"));
+        assert_eq!(contacts.label_for(addr(1)), None);
+        assert_eq!(contacts.all().len(), 1);
+
+        let wake = WakeTarget {
+            mac: "aa:bb:cc:dd:ee:01".into(),
+            broadcast: "192.168.1.255:9".parse().unwrap(),
+            relay: Some("Laptop".into()),
+        };
+        contacts.set_wake("Bob", Some(wake.clone())).unwrap();
+        contacts.upsert("Bob".into(), addr(3)).unwrap();
+        assert_eq!(contacts.all()[0].wake, Some(wake));
+
+        assert!(validate_hostname("bob.duckdns.org").is_err());
+        assert!(validate_hostname("bob .org:1").is_err());
+        assert!(validate_hostname(":41234").is_err());
+        assert!(validate_hostname("bob.org:0").is_err());
+    }
+
+    #[test]
+    fn test_persists_to_disk() {
+        let dir = TempDir::new("contacts");
+        let path = dir.join("contacts.json");
+
+        Contacts::open(path.clone())
+            .upsert("Bob".into(), addr(1))
+            .unwrap();
+        let reopened = Contacts::open(path.clone());
+        assert_eq!(reopened.label_for(addr(1)).as_deref(), Some("Bob"));
+    }
+
+    #[test]
+    fn test_validate_label() {
+        assert_eq!(validate_label("  Bob "), Ok("Bob".to_string()));
+        assert!(validate_label("   ").is_err());
+        assert!(validate_label(&"x".repeat(MAX_LABEL_LEN + 1)).is_err());
+        assert!(validate_label("Bob\n").is_ok()); // trailing whitespace is trimmed
+        assert!(validate_label("B\u{7}ob").is_err());
+    }
+}
@@ -0,0 +1,1420 @@
+//! The network controller: the loop that owns the UDP socket and the session.
+//!
+//! The caller builds what it runs on, namely the bound socket, the shared
+//! state, the command queue and what NAT detection found at startup, and
+//! hands them over in a `Controller`. Tests can give it a loopback socket
+//! and a paused clock, then drive it with commands and packets, without a
+//! network.
+//!
+//! Timers here and in the handshake, keep-alive and session code read
+//! `tokio::time`, never `std::time`, so a paused clock moves all of them and
+//! a 30 second timeout passes instantly. The exception is KCP itself, which
+//! keeps wall clock time inside `tokio_kcp`.
+
+use crate::{
+    assist::{self, AssistOutcome},
+    audit::DisconnectReason,
+    captive_portal,
+    config::Config,
+    data_budget::BULK_BLOCKED,
+    ddns,
+    keep_alive::{self, KeepAlive},
+    link_preview,
+    messaging::{
+        broadcast::{BroadcastReport, Delivery},
+        chat_command::{self, ChatCommand, ChatInput},
+        connect::{ConnectOutcome, ConnectReply},
+        cookie::CookieIssuer,
+        expiry::{self, MAX_TTL_SECS},
+        handshake::{self, Capabilities},
+        incoming::{self, IncomingQueue},
+        message_manager::{HandshakeResult, MAX_FRAME_LEN, MessageManager, StreamMessage},
+        ping::{HEARTBEAT_SEQ, PingProbe},
+        reactions,
+    },
+    mirror::MirrorItem,
+    nat_cache::NatCache,
+    net,
+    operations::OperationKind,
+    share::{self, ShareReply, ShareRequest, ShareResponse},
+    stats_history::MarkEvent,
+    storage::{unix_timestamp, unix_timestamp_ms},
+    transcript::{Direction, Protocol},
+    web::{
+        shared_state::{Command, NetworkStatus, PunchPhase, PunchProgress, SharedState, Status},
+        status_message::StatusMessage,
+        system_notice::SystemNotice,
+    },
+    wipe::{self, WipeReply},
+    wol::{self, WakeReply},
+};
+use anyhow::{Result, anyhow};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::Arc,
+};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, oneshot, watch},
+    task::JoinHandle,
+    time::{Duration, Instant},
+};
+use tracing::{Instrument, debug, error, info, warn};
+
+/// How often handshakes and sessions are checked for liveness.
+const LIVENESS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time a handshake may overrun its own timeout before it is abandoned.
+const HANDSHAKE_GRACE: Duration = Duration::from_secs(5);
+
+/// How often queued messages are retried while the KCP send window is full.
+const OUTBOX_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What the controller runs on.
+pub struct Controller {
+    pub config: Config,
+    pub state: SharedState,
+    /// Main UDP socket, already bound.
+    pub socket: Arc<UdpSocket>,
+    /// Queue commands are read from; the sender lets the controller queue follow-ups.
+    pub commands: (mpsc::Sender<Command>, mpsc::Receiver<Command>),
+    /// Where public address changes are published for DDNS, if enabled.
+    pub ddns: Option<watch::Sender<Option<SocketAddr>>>,
+    /// Client for captive portal probes, if `captive_portal_probe` is set.
+    pub portal_client: Option<reqwest::Client>,
+    pub startup: Startup,
+}
+
+/// What NAT detection at startup left for the controller to finish.
+#[derive(Debug, Default)]
+pub struct Startup {
+    /// True if STUN failed; detection runs once the network is up.
+    pub needs_detection: bool,
+    /// The NAT cache as loaded, if caching is on.
+    pub nat_cache: Option<NatCache>,
+    /// Cached results in use until a keep-alive confirms them.
+    pub unconfirmed_cache: Option<NatCache>,
+}
+
+impl Controller {
+    /// Runs the controller until the process exits.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the socket's local address cannot be read.
+    pub async fn run(self) -> Result<()> {
+        let Controller {
+            config,
+            state,
+            socket,
+            commands: (cmd_tx, mut cmd_rx),
+            ddns,
+            portal_client,
+            startup:
+                Startup {
+                    mut needs_detection,
+                    mut nat_cache,
+                    mut unconfirmed_cache,
+                },
+        } = self;
+        let local_port = socket.local_addr()?.port();
+        let transcript = state.read().await.transcript.clone();
+        let nat_cache_path = (config.nat_cache_ttl_secs > 0).then(|| config.nat_cache_path());
+
+        // 1. Initialize Message Manager
+        let mut manager = MessageManager::new(socket.clone(), state.clone());
+        let own_node = config.own_node_secret.is_some();
+        manager.set_local_capabilities(Capabilities {
+            padding: config.traffic_padding,
+            shares: !config.shares.is_empty(),
+            assist: !config.assist_grants.is_empty(),
+            remote_wipe: own_node && !config.wipe_contacts.is_empty(),
+            wake_relay: own_node && !config.wake_relay_contacts.is_empty(),
+        });
+        manager.set_own_node_secret(config.own_node_secret.clone());
+
+        // Bind standby paths on additional interfaces
+        let mut standby_addrs = Vec::new();
+        for ip in &config.extra_bind_addrs {
+            match UdpSocket::bind((*ip, 0)).await {
+                Ok(extra) => {
+                    if let Err(e) = net::apply_socket_options(&extra, config.dscp, config.ttl) {
+                        warn!("Failed to apply socket options on {}: {}", ip, e);
+                    }
+                    let addr = extra.local_addr()?;
+                    info!("Standby path bound on {}", addr);
+                    standby_addrs.push(addr);
+                    manager.add_path(Arc::new(extra));
+                }
+                Err(e) => warn!("Failed to bind standby path on {}: {}", ip, e),
+            }
+        }
+        state
+            .write()
+            .await
+            .set_paths(Some(socket.local_addr()?), standby_addrs);
+
+        // 2. Setup NAT Keep-Alive
+        let mut keep_alive = KeepAlive::new(
+            Duration::from_secs(config.punch_hole_secs),
+            Duration::from_secs(config.nat_cache_ttl_secs),
+        );
+        {
+            let mut guard = state.write().await;
+            keep_alive.set_low_traffic(guard.power_profile.low_traffic);
+            guard.set_keep_alive_interval(keep_alive.interval());
+        }
+        let keep_alive_timer = tokio::time::sleep(keep_alive.next_delay());
+        tokio::pin!(keep_alive_timer);
+
+        // 3. Setup liveness checks; quiet sessions get a heartbeat at a third of the timeout
+        let mut liveness_interval = tokio::time::interval(LIVENESS_CHECK_INTERVAL);
+        liveness_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        let peer_timeout = Duration::from_secs(config.peer_timeout_secs);
+        let handshake_timeout = Duration::from_secs(config.handshake_timeout_secs);
+
+        let mut receive_buf = [0u8; MAX_FRAME_LEN];
+
+        // Handshake running in the background, if any, and when it must be done by
+        let mut connecting: Option<(SocketAddr, JoinHandle<HandshakeResult>, Instant)> = None;
+        // Caller waiting for that handshake to finish, if any
+        let mut connect_reply: Option<ConnectReply> = None;
+        // Other endpoints of the contact being dialled, tried in turn when a
+        // handshake fails; set when the next ConnectPeer is such a retry
+        let mut dial_queue: VecDeque<SocketAddr> = VecDeque::new();
+        let mut dialing_next = false;
+        // Lookup of the hostname of the contact being dialled: its label,
+        // the hostname and the address it was saved with. `resolved` is set
+        // when the next ConnectPeer follows such a lookup.
+        let mut resolving: Option<(String, String, SocketAddr, JoinHandle<Result<SocketAddr>>)> =
+            None;
+        let mut resolved = false;
+
+        // Ping run in progress, if any
+        let mut ping: Option<PingProbe> = None;
+
+        // Peers asking to connect while we are idle
+        let mut incoming = IncomingQueue::new(
+            Duration::from_secs(config.incoming_prompt_secs),
+            config.max_pending_incoming,
+        );
+        let mut listen_buf = [0u8; 2048];
+        // Cookies unsolicited SYNs must echo before they are queued
+        let cookies = CookieIssuer::new();
+
+        // Assist mode: our last request ID, and whether a granted command is running
+        let mut assist_seq: u32 = 0;
+        let mut assist_running = false;
+
+        // Requests we sent to the peer's shared folders, waiting for a reply
+        let mut share_seq: u32 = 0;
+        let mut share_pending: HashMap<u32, ShareReply> = HashMap::new();
+
+        // Wipes we asked the peer for, waiting for its result
+        let mut wipe_seq: u32 = 0;
+        let mut wipe_pending: HashMap<u32, WipeReply> = HashMap::new();
+
+        // Wake-on-LAN packets we asked the peer to send, waiting for its result
+        let mut wake_seq: u32 = 0;
+        let mut wake_pending: HashMap<u32, WakeReply> = HashMap::new();
+
+        // Fetches link previews for received messages, if enabled
+        let preview_client = if config.link_previews {
+            link_preview::client()
+                .inspect_err(|e| warn!("Link previews disabled: {}", e))
+                .ok()
+        } else {
+            None
+        };
+
+        info!("System Ready. Press Ctrl+C to exit.");
+
+        // 4. Main Event Loop
+        let listen_addr = socket.local_addr()?;
+        loop {
+            let ping_deadline = ping.as_ref().map(|probe| probe.deadline);
+            let incoming_deadline = incoming.next_deadline();
+
+            // Log lines from handling this event carry the connection ID, if any
+            let span = manager.span();
+            async {
+                tokio::select! {
+                    // A. Handle Commands from Web UI
+                    Some(cmd) = cmd_rx.recv() => {
+                        match cmd {
+                            Command::ConnectPeer { reply } if connecting.is_some() || resolving.is_some() || manager.is_connected() => {
+                                warn!("ConnectPeer ignored: a session is already active or being set up");
+                                if let Some(reply) = reply {
+                                    let _ = reply.send(Err("A session is already active or being set up".into()));
+                                }
+                            }
+                            Command::ConnectPeer { reply } => {
+                                let target_peer = {
+                                    state.read().await.peer_ip
+                                };
+
+                                if let Some(peer_addr) = target_peer {
+                                    // Dialling a peer that is already asking to connect answers its request
+                                    if incoming.take(peer_addr).is_some() {
+                                        state.write().await.set_incoming_requests(incoming.requests().to_vec());
+                                    }
+                                    let retry = std::mem::take(&mut dialing_next);
+                                    // Contacts saved with a hostname are dialled wherever it points now
+                                    let hostname = if retry || std::mem::take(&mut resolved) {
+                                        None
+                                    } else {
+                                        state.read().await.contacts.hostname_for(peer_addr)
+                                    };
+                                    if let Some((label, hostname)) = hostname {
+                                        {
+                                            let mut guard = state.write().await;
+                                            // The attempt gets its ID when the handshake starts
+                                            guard.connection_id = None;
+                                            guard.set_status(
+                                                Status::Punching,
+                                                None,
+                                                Some(PunchProgress::new(PunchPhase::Resolving, 0, Duration::ZERO, handshake_timeout)),
+                                            );
+                                        }
+                                        // Looked up on its own task so commands keep flowing; branch J dials once it is done
+                                        let lookup = {
+                                            let hostname = hostname.clone();
+                                            async move { ddns::lookup(&hostname).await }
+                                        };
+                                        let task = tokio::spawn(lookup.instrument(manager.span()));
+                                        resolving = Some((label, hostname, peer_addr, task));
+                                        connect_reply = reply;
+                                    } else {
+                                        if !retry {
+                                            dial_queue = state.read().await.contacts.dial_order(peer_addr).into_iter().skip(1).collect();
+                                        }
+
+                                        let pending = manager.start_handshake(
+                                            peer_addr,
+                                            config.handshake_timeout_secs,
+                                            config.encryption_mode
+                                        ).await;
+                                        state.write().await.set_status(
+                                            Status::Punching,
+                                            Some(StatusMessage::HandshakeStarted { peer: peer_addr }),
+                                            Some(PunchProgress::new(PunchPhase::Spraying, 0, Duration::ZERO, handshake_timeout)),
+                                        );
+
+                                        // Run the handshake on its own task so commands keep flowing; branch G finishes it
+                                        let deadline = Instant::now() + handshake_timeout + HANDSHAKE_GRACE;
+                                        let task = tokio::spawn(pending.instrument(manager.span()));
+                                        connecting = Some((peer_addr, task, deadline));
+                                        connect_reply = reply;
+                                    }
+                                } else {
+                                    warn!("ConnectPeer command received without peer IP set");
+                                    if let Some(reply) = reply {
+                                        let _ = reply.send(Err("No peer address set".into()));
+                                    }
+                                }
+                            }
+                            Command::SendMessage(text) => {
+                                match chat_command::parse(&text) {
+                                    ChatInput::Text(text) => {
+                                        if manager.is_connected() {
+                                            if let Err(e) = manager.send_text(text.clone()).await {
+                                                error!("Failed to send message: {}", e);
+                                            } else {
+                                                state.write().await.add_message(text, true);
+                                            }
+                                        } else {
+                                            warn!("Cannot send message: not connected");
+                                        }
+                                    }
+                                    ChatInput::Invalid(e) => {
+                                        state.read().await.command_result(text, Err(e));
+                                    }
+                                    ChatInput::Command(ChatCommand::Ping { count }) => {
+                                        // Queued like `POST /api/ping`; the result is posted when the run ends
+                                        let operation = state.write().await.operations.start(OperationKind::Ping);
+                                        let (reply, outcome) = oneshot::channel();
+                                        if let Err(e) = cmd_tx.try_send(Command::Ping { count, operation, reply }) {
+                                            error!("Failed to queue ping: {}", e);
+                                        }
+                                        let state = state.clone();
+                                        tokio::spawn(async move {
+                                            let outcome = outcome
+                                                .await
+                                                .unwrap_or_else(|_| Err("Controller dropped the ping request".into()));
+                                            let mut guard = state.write().await;
+                                            let result = match &outcome {
+                                                Ok(stats) => serde_json::to_value(stats).map_err(|e| e.to_string()),
+                                                Err(e) => Err(e.clone()),
+                                            };
+                                            guard.operations.finish(operation, result);
+                                            guard.command_result(text, outcome.map(|stats| chat_command::describe_ping(&stats)));
+                                        });
+                                    }
+                                    ChatInput::Command(ChatCommand::Stats) => {
+                                        let guard = state.read().await;
+                                        guard.command_result(text, Ok(guard.stats_text()));
+                                    }
+                                    ChatInput::Command(ChatCommand::Disconnect) => {
+                                        let outcome = if manager.is_connected() || connecting.is_some() || resolving.is_some() {
+                                            cmd_tx
+                                                .try_send(Command::Disconnect)
+                                                .map(|()| "Disconnecting".to_string())
+                                                .map_err(|e| format!("Failed to queue disconnect: {}", e))
+                                        } else {
+                                            Err("Not connected to a peer".into())
+                                        };
+                                        state.read().await.command_result(text, outcome);
+                                    }
+                                    ChatInput::Command(ChatCommand::Nick(label)) => {
+                                        let mut guard = state.write().await;
+                                        let saved = match guard.peer_ip {
+                                            None => Err("Not connected to a peer".to_string()),
+                                            // Guest sessions leave no trace, so the label is not saved
+                                            Some(_) if guard.guest => Ok(false),
+                                            Some(addr) => guard
+                                                .contacts
+                                                .upsert(label.clone(), addr)
+                                                .map(|_| true)
+                                                .map_err(|e| format!("Failed to save contact: {}", e)),
+                                        };
+                                        match saved {
+                                            Ok(saved) => {
+                                                guard.peer_label = Some(label.clone());
+                                                guard.notify(SystemNotice::PeerRenamed { label, saved });
+                                            }
+                                            Err(e) => guard.command_result(text, Err(e)),
+                                        }
+                                    }
+                                    ChatInput::Command(ChatCommand::Clear) => {
+                                        let guard = state.read().await;
+                                        guard.clear_chat();
+                                        guard.command_result(text, Ok("Chat cleared".into()));
+                                    }
+                                }
+                            }
+                            Command::SetMessageTtl(ttl_secs) => {
+                                if !manager.is_connected() {
+                                    warn!("Cannot set message TTL: not connected");
+                                } else if let Err(e) = manager.send_message_ttl(ttl_secs).await {
+                                    error!("Failed to send message TTL: {}", e);
+                                } else {
+                                    state.write().await.set_message_ttl(ttl_secs, true);
+                                }
+                            }
+                            Command::PowerProfileChanged => {
+                                let low_traffic = state.read().await.power_profile.low_traffic;
+                                if keep_alive.set_low_traffic(low_traffic) {
+                                    info!("Low-traffic mode {} by hint", if low_traffic { "on" } else { "off" });
+                                    keep_alive_timer.as_mut().reset(Instant::now() + keep_alive.next_delay());
+                                    state.write().await.set_keep_alive_interval(keep_alive.interval());
+                                }
+                            }
+                            Command::Disconnect => {
+                                if let Some(probe) = ping.take() {
+                                    probe.fail("Disconnected");
+                                }
+                                if let Some((_, task, _)) = connecting.take() {
+                                    task.abort();
+                                    dial_queue.clear();
+                                    manager.cancel_handshake(DisconnectReason::LocalRequest, StatusMessage::HandshakeCancelled).await;
+                                    if let Some(reply) = connect_reply.take() {
+                                        let _ = reply.send(Err("Cancelled during handshake".into()));
+                                    }
+                                } else if let Some((_, hostname, _, task)) = resolving.take() {
+                                    task.abort();
+                                    info!("Lookup of {} abandoned", hostname);
+                                    state.write().await.set_status(Status::Disconnected, Some(StatusMessage::HandshakeCancelled), None);
+                                    if let Some(reply) = connect_reply.take() {
+                                        let _ = reply.send(Err("Cancelled during handshake".into()));
+                                    }
+                                } else if let Err(e) = manager.disconnect().await {
+                                    error!("Error during disconnect: {}", e);
+                                }
+                            }
+                            Command::AcceptIncoming(addr) => {
+                                if incoming.take(addr).is_some() {
+                                    info!("Accepted connection request from {}", addr);
+                                    // Only one session at a time; turn everyone else away
+                                    for other in incoming.reject_all(Instant::now()) {
+                                        if let Err(e) = handshake::send_bye(&socket, other, &transcript).await {
+                                            debug!("Failed to reject {}: {}", other, e);
+                                        }
+                                    }
+                                    let mut guard = state.write().await;
+                                    guard.set_incoming_requests(Vec::new());
+                                    guard.guest = false;
+                                    guard.set_peer_ip(addr, None, Some(StatusMessage::IncomingAccepted), None);
+                                    drop(guard);
+
+                                    if let Err(e) = cmd_tx.try_send(Command::ConnectPeer { reply: None }) {
+                                        error!("Failed to queue connection to {}: {}", addr, e);
+                                    }
+                                } else {
+                                    warn!("No pending connection request from {}", addr);
+                                }
+                            }
+                            Command::RejectIncoming(addr) => {
+                                if incoming.reject(addr, Instant::now()) {
+                                    info!("Rejected connection request from {}", addr);
+                                    if let Err(e) = handshake::send_bye(&socket, addr, &transcript).await {
+                                        debug!("Failed to reject {}: {}", addr, e);
+                                    }
+                                    state.write().await.set_incoming_requests(incoming.requests().to_vec());
+                                }
+                            }
+                            Command::Broadcast { text, reply } => {
+                                // One session at a time for now, so the fan-out has at most one target
+                                let mut deliveries = Vec::new();
+                                if manager.is_connected() {
+                                    let (peer, peer_label) = {
+                                        let guard = state.read().await;
+                                        (guard.peer_ip, guard.peer_label.clone())
+                                    };
+                                    if let Some(peer) = peer {
+                                        let result = manager.send_text(text.clone()).await;
+                                        if result.is_ok() {
+                                            state.write().await.add_message(text, true);
+                                        }
+                                        deliveries.push(Delivery {
+                                            peer,
+                                            peer_label,
+                                            delivered: result.is_ok(),
+                                            error: result.err().map(|e| e.to_string()),
+                                        });
+                                    }
+                                }
+                                let _ = reply.send(BroadcastReport::new(deliveries));
+                            }
+                            Command::React { message_id, emoji, add } => {
+                                if !manager.is_connected() {
+                                    warn!("Cannot react: not connected");
+                                } else if let Err(e) = manager.send_reaction(message_id, emoji.clone(), add).await {
+                                    error!("Failed to send reaction: {}", e);
+                                } else {
+                                    state.write().await.apply_reaction(message_id, &emoji, true, add);
+                                }
+                            }
+                            Command::AssistRun { name } => {
+                                if !manager.is_connected() {
+                                    warn!("Cannot request assist command: not connected");
+                                } else {
+                                    assist_seq = assist_seq.wrapping_add(1);
+                                    match manager.send_assist_request(assist_seq, name.clone()).await {
+                                        Ok(()) => {
+                                            info!("Asked peer to run assist command '{}'", name);
+                                            state.read().await.report_assist(assist_seq, name, true, None);
+                                        }
+                                        Err(e) => error!("Failed to send assist request: {}", e),
+                                    }
+                                }
+                            }
+                            Command::AssistFinished { id, name, outcome } => {
+                                assist_running = false;
+                                info!("Assist command '{}' finished: exit {:?}, error {:?}", name, outcome.exit_code, outcome.error);
+                                if manager.is_connected()
+                                    && let Err(e) = manager.send_assist_response(id, name.clone(), outcome.clone()).await
+                                {
+                                    warn!("Failed to return assist result: {}", e);
+                                }
+                                state.read().await.report_assist(id, name, false, Some(outcome));
+                            }
+                            Command::ShareQuery { request, reply } => {
+                                if !manager.is_connected() {
+                                    let _ = reply.send(Err("Not connected to a peer".into()));
+                                } else if matches!(request, ShareRequest::Read { .. })
+                                    && !state.read().await.data_budget.bulk_allowed()
+                                {
+                                    let _ = reply.send(Err(BULK_BLOCKED.into()));
+                                } else {
+                                    share_seq = share_seq.wrapping_add(1);
+                                    // Forget requests whose caller gave up waiting
+                                    share_pending.retain(|_, pending| !pending.is_closed());
+                                    let mark = MarkEvent::transfer_started(&request, true);
+                                    match manager.send_share_query(share_seq, request).await {
+                                        Ok(()) => {
+                                            share_pending.insert(share_seq, reply);
+                                            if let Some(mark) = mark {
+                                                state.write().await.stats_history.mark(unix_timestamp_ms(), mark);
+                                            }
+                                        }
+                                        Err(e) => {
+                                            let _ = reply.send(Err(format!("Failed to send request: {}", e)));
+                                        }
+                                    }
+                                }
+                            }
+                            Command::RemoteWipe { reply } => {
+                                if !manager.is_connected() {
+                                    let _ = reply.send(Err("Not connected to a peer".into()));
+                                } else {
+                                    wipe_seq = wipe_seq.wrapping_add(1);
+                                    wipe_pending.retain(|_, pending| !pending.is_closed());
+                                    match manager.send_wipe_request(wipe_seq).await {
+                                        Ok(()) => {
+                                            wipe_pending.insert(wipe_seq, reply);
+                                        }
+                                        Err(e) => {
+                                            let _ = reply.send(Err(format!("Failed to send request: {}", e)));
+                                        }
+                                    }
+                                }
+                            }
+                            Command::RelayWake { mac, broadcast, reply } => {
+                                if !manager.is_connected() {
+                                    let _ = reply.send(Err("Not connected to a peer".into()));
+                                } else {
+                                    wake_seq = wake_seq.wrapping_add(1);
+                                    wake_pending.retain(|_, pending| !pending.is_closed());
+                                    match manager.send_wake_request(wake_seq, mac, broadcast).await {
+                                        Ok(()) => {
+                                            wake_pending.insert(wake_seq, reply);
+                                        }
+                                        Err(e) => {
+                                            let _ = reply.send(Err(format!("Failed to send request: {}", e)));
+                                        }
+                                    }
+                                }
+                            }
+                            Command::Ping { count, operation, reply } => {
+                                if !manager.is_connected() {
+                                    let _ = reply.send(Err("Not connected to a peer".into()));
+                                } else if ping.is_some() {
+                                    let _ = reply.send(Err("A ping is already running".into()));
+                                } else {
+                                    let probe = PingProbe::new(count, operation, reply);
+                                    match manager.send_ping(probe.seq()).await {
+                                        Ok(()) => ping = Some(probe),
+                                        Err(e) => probe.fail(&format!("Failed to send ping: {}", e)),
+                                    }
+                                }
+                            }
+                        }
+                    }
+
+                    // B. Handle Incoming Messages (KCP)
+                    result = manager.receive_message(&mut receive_buf), if manager.is_connected() => {
+                        match result {
+                            Ok(n) => {
+                                 match StreamMessage::decode(&receive_buf[..n]) {
+                                    Ok(msg) => {
+                                        match msg {
+                                            StreamMessage::Text { text: content, clock } => {
+                                                debug!("Received message: {} bytes", content.len());
+                                                let links = link_preview::find_urls(&content);
+                                                let mut guard = state.write().await;
+                                                let message_id = guard.add_peer_message(content, clock, None);
+                                                if let (Some(client), Some(conversation_id)) =
+                                                    (&preview_client, guard.conversation_id.clone())
+                                                    && !links.is_empty()
+                                                {
+                                                    let (client, state) = (client.clone(), state.clone());
+                                                    tokio::spawn(async move {
+                                                        let previews = link_preview::fetch_all(&client, &links).await;
+                                                        state.read().await.attach_link_previews(&conversation_id, message_id, previews);
+                                                    });
+                                                }
+                                            }
+                                            StreamMessage::ExpiringText { text, clock, ttl_secs } => {
+                                                // No link previews: nothing about a disappearing message is fetched
+                                                debug!("Received expiring message: {} bytes, TTL {} s", text.len(), ttl_secs);
+                                                let ttl_secs = ttl_secs.clamp(1, MAX_TTL_SECS);
+                                                state.write().await.add_peer_message(text, clock, Some(ttl_secs));
+                                            }
+                                            StreamMessage::MessageTtl { ttl_secs } => {
+                                                match ttl_secs.map(expiry::validate_ttl).transpose() {
+                                                    Ok(ttl_secs) => {
+                                                        info!("Peer set the message TTL to {:?} s", ttl_secs);
+                                                        state.write().await.set_message_ttl(ttl_secs, false);
+                                                    }
+                                                    Err(e) => warn!("Ignoring message TTL from peer: {}", e),
+                                                }
+                                            }
+                                            StreamMessage::SyncSummary { fingerprint, received } => {
+                                                let texts = state.write().await.take_backfill(&fingerprint, received);
+                                                if !texts.is_empty() {
+                                                    info!("Resending {} texts lost when the last session dropped", texts.len());
+                                                    let mut resent = 0;
+                                                    for text in texts {
+                                                        if let Err(e) = manager.send_text(text.clone()).await {
+                                                            error!("Failed to resend message: {}", e);
+                                                            break;
+                                                        }
+                                                        state.write().await.add_message(text, true);
+                                                        resent += 1;
+                                                    }
+                                                    state.read().await.announce_backfill(resent);
+                                                }
+                                            }
+                                            StreamMessage::Bye => {
+                                                info!("Peer requested disconnect");
+                                                if let Some(probe) = ping.take() {
+                                                    probe.fail("Peer disconnected");
+                                                }
+                                                let _ = manager.disconnect_on_bye_received().await;
+                                            }
+                                            StreamMessage::ByeAck => {
+                                                debug!("Ignoring ByeAck outside a disconnect");
+                                            }
+                                            StreamMessage::TranscriptCheck(sent) => {
+                                                manager.verify_transcript(sent).await;
+                                            }
+                                            StreamMessage::Reaction { message_id, emoji, add } => {
+                                                match reactions::validate_emoji(&emoji) {
+                                                    Ok(emoji) => {
+                                                        state.write().await.apply_reaction(message_id.flipped(), &emoji, false, add);
+                                                    }
+                                                    Err(e) => debug!("Ignoring reaction from peer: {}", e),
+                                                }
+                                            }
+                                            StreamMessage::AssistRequest { id, name } if !assist::plausible_name(&config.assist_grants, &name) => {
+                                                warn!("Refused an assist command with a {}-byte name", name.len());
+                                                let outcome = AssistOutcome::refused("Command not granted");
+                                                if let Err(e) = manager.send_assist_response(id, String::new(), outcome).await {
+                                                    warn!("Failed to return assist result: {}", e);
+                                                }
+                                            }
+                                            StreamMessage::AssistRequest { id, name } => {
+                                                info!("Peer requested assist command '{}'", name);
+                                                // Grants are by saved contact; guests never hold one
+                                                let peer_label = {
+                                                    let guard = state.read().await;
+                                                    guard.peer_ip.filter(|_| !guard.guest).and_then(|addr| guard.contacts.label_for(addr))
+                                                };
+                                                state.read().await.report_assist(id, name.clone(), false, None);
+
+                                                match assist::find(&config.assist_grants, peer_label.as_deref(), &name) {
+                                                    Some(grant) if !assist_running => {
+                                                        assist_running = true;
+                                                        let (grant, cmd_tx) = (grant.clone(), cmd_tx.clone());
+                                                        tokio::spawn(async move {
+                                                            let outcome = assist::run(&grant).await;
+                                                            let _ = cmd_tx.send(Command::AssistFinished { id, name, outcome }).await;
+                                                        });
+                                                    }
+                                                    grant => {
+                                                        let reason = if grant.is_none() {
+                                                            "Command not granted"
+                                                        } else {
+                                                            "Another command is still running"
+                                                        };
+                                                        warn!("Refused assist command '{}': {}", name, reason);
+                                                        let outcome = AssistOutcome::refused(reason);
+                                                        if let Err(e) = manager.send_assist_response(id, name.clone(), outcome.clone()).await {
+                                                            warn!("Failed to return assist result: {}", e);
+                                                        }
+                                                        state.read().await.report_assist(id, name, false, Some(outcome));
+                                                    }
+                                                }
+                                            }
+                                            StreamMessage::AssistResponse { id, name, outcome } => {
+                                                state.read().await.report_assist(id, name, true, Some(outcome));
+                                            }
+                                            StreamMessage::ShareQuery { id, request } => {
+                                                // Grants are by saved contact, not by the label typed at connect time
+                                                let peer_label = {
+                                                    let guard = state.read().await;
+                                                    guard.peer_ip.and_then(|addr| guard.contacts.label_for(addr))
+                                                };
+                                                let bulk_allowed = state.read().await.data_budget.bulk_allowed();
+                                                let result = if matches!(request, ShareRequest::Read { .. }) && !bulk_allowed {
+                                                    Err(BULK_BLOCKED.to_string())
+                                                } else {
+                                                    share::handle(&config.shares, peer_label.as_deref(), &request).await
+                                                };
+                                                info!(
+                                                    "Share access by {}: {:?} -> {}",
+                                                    peer_label.as_deref().unwrap_or("unknown peer"),
+                                                    request,
+                                                    result.as_ref().err().map_or("ok", String::as_str)
+                                                );
+                                                if result.is_ok()
+                                                    && let Some(mark) = MarkEvent::transfer_started(&request, false)
+                                                {
+                                                    state.write().await.stats_history.mark(unix_timestamp_ms(), mark);
+                                                }
+                                                let guard = state.read().await;
+                                                if let (ShareRequest::Read { share, path, .. }, Ok(ShareResponse::Data { offset, bytes, total_size })) =
+                                                    (&request, &result)
+                                                    && offset + bytes.len() as u64 == *total_size
+                                                {
+                                                    guard.notify(SystemNotice::TransferFinished {
+                                                        share: share.clone(),
+                                                        path: path.clone(),
+                                                        size: *total_size,
+                                                    });
+                                                }
+                                                guard.report_share_access(request, result.as_ref().err().cloned());
+                                                drop(guard);
+                                                if let Err(e) = manager.send_share_reply(id, result).await {
+                                                    warn!("Failed to answer share request: {}", e);
+                                                }
+                                            }
+                                            StreamMessage::ShareReply { id, result } => {
+                                                if let Some(reply) = share_pending.remove(&id) {
+                                                    let _ = reply.send(result);
+                                                }
+                                            }
+                                            StreamMessage::WipeRequest { id } => {
+                                                // Allowed by saved contact, once the peer proved it is our own node
+                                                let peer_label = {
+                                                    let guard = state.read().await;
+                                                    guard.peer_ip.and_then(|addr| guard.contacts.label_for(addr))
+                                                };
+                                                let result = if manager.peer_is_own_node() && wipe::authorized(&config.wipe_contacts, peer_label.as_deref()) {
+                                                    state.write().await.wipe_history().map_err(|e| format!("Wipe failed: {:#}", e))
+                                                } else {
+                                                    Err("Not allowed to wipe this node".to_string())
+                                                };
+                                                warn!(
+                                                    "Remote wipe requested by {}: {}",
+                                                    peer_label.as_deref().unwrap_or("unknown peer"),
+                                                    result.as_ref().err().map_or("done", String::as_str)
+                                                );
+                                                if let Err(e) = manager.send_wipe_result(id, result).await {
+                                                    warn!("Failed to answer wipe request: {}", e);
+                                                }
+                                            }
+                                            StreamMessage::Mirror(item) => {
+                                                let mut guard = state.write().await;
+                                                let peer_label = guard.peer_ip.and_then(|addr| guard.contacts.label_for(addr));
+                                                if !manager.peer_is_own_node() || !config.mirror.paired(peer_label.as_deref()) {
+                                                    debug!("Ignoring mirrored entry from unpaired peer");
+                                                } else {
+                                                    match item {
+                                                        MirrorItem::Contact(contact) => match guard.contacts.merge(contact) {
+                                                            Ok(merged) => debug!("Mirrored contact (new: {})", merged),
+                                                            Err(e) => warn!("Failed to save mirrored contact: {:#}", e),
+                                                        },
+                                                        MirrorItem::Session(record) => {
+                                                            let merged = guard.session_log.merge(record);
+                                                            debug!("Mirrored session record (new: {})", merged);
+                                                        }
+                                                    }
+                                                }
+                                            }
+                                            StreamMessage::WipeResult { id, result } => {
+                                                if let Some(reply) = wipe_pending.remove(&id) {
+                                                    let _ = reply.send(result);
+                                                }
+                                            }
+                                            StreamMessage::WakeRequest { id, mac, broadcast } => {
+                                                // Allowed by saved contact and own-node proof, like remote wipe
+                                                let peer_label = {
+                                                    let guard = state.read().await;
+                                                    guard.peer_ip.and_then(|addr| guard.contacts.label_for(addr))
+                                                };
+                                                let result = if manager.peer_is_own_node() && wol::authorized(&config.wake_relay_contacts, peer_label.as_deref()) {
+                                                    wol::send(&mac, broadcast).await.map_err(|e| format!("Wake failed: {:#}", e))
+                                                } else {
+                                                    Err("Not allowed to send wake packets from this node".to_string())
+                                                };
+                                                info!(
+                                                    "Wake of {} via {} requested by {}: {}",
+                                                    mac,
+                                                    broadcast,
+                                                    peer_label.as_deref().unwrap_or("unknown peer"),
+                                                    result.as_ref().err().map_or("sent", String::as_str)
+                                                );
+                                                if let Err(e) = manager.send_wake_result(id, result).await {
+                                                    warn!("Failed to answer wake request: {}", e);
+                                                }
+                                            }
+                                            StreamMessage::WakeResult { id, result } => {
+                                                if let Some(reply) = wake_pending.remove(&id) {
+                                                    let _ = reply.send(result);
+                                                }
+                                            }
+                                            StreamMessage::OwnNodeProof(proof) => {
+                                                if !manager.accept_own_node_proof(&proof) {
+                                                    warn!("Peer sent an invalid own-node proof");
+                                                } else {
+                                                    // Mirror only to a paired node that proved it is ours
+                                                    let mirror = {
+                                                        let guard = state.read().await;
+                                                        let peer_label = guard.peer_ip.and_then(|addr| guard.contacts.label_for(addr));
+                                                        if config.mirror.paired(peer_label.as_deref()) {
+                                                            config.mirror.outgoing(guard.contacts.all(), guard.session_log.records())
+                                                        } else {
+                                                            Vec::new()
+                                                        }
+                                                    };
+                                                    if !mirror.is_empty() {
+                                                        info!("Mirroring {} entries to paired node", mirror.len());
+                                                        if let Err(e) = manager.send_mirror(mirror).await {
+                                                            warn!("Failed to mirror to paired node: {}", e);
+                                                        }
+                                                    }
+                                                }
+                                            }
+                                            StreamMessage::Ping(seq) => {
+                                                if let Err(e) = manager.send_pong(seq).await {
+                                                    warn!("Failed to answer ping: {}", e);
+                                                }
+                                            }
+                                            StreamMessage::Pong(seq) => {
+                                                if seq == HEARTBEAT_SEQ && let Some(rtt) = manager.on_heartbeat_pong() {
+                                                    state.write().await.set_rtt(rtt);
+                                                }
+                                                let next = ping.as_mut().and_then(|probe| probe.on_pong(seq));
+                                                if let Some(rtt) = ping.as_ref().and_then(PingProbe::last_rtt) {
+                                                    state.write().await.set_rtt(rtt);
+                                                }
+                                                match next {
+                                                    Some(next) => {
+                                                        if let Some(probe) = &ping {
+                                                            let (done, total) = probe.progress();
+                                                            state.write().await.operations.set_progress(probe.operation(), done, total);
+                                                        }
+                                                        if let Err(e) = manager.send_ping(next).await
+                                                            && let Some(probe) = ping.take()
+                                                        {
+                                                            probe.fail(&format!("Failed to send ping: {}", e));
+                                                        }
+                                                    }
+                                                    None => {
+                                                        if let Some(probe) = ping.take_if(|probe| probe.is_complete()) {
+                                                            probe.finish();
+                                                        }
+                                                    }
+                                                }
+                                            }
+                                        }
+                                    }
+                                    Err(e) => warn!("Failed to deserialize packet: {}", e),
+                                 }
+                            }
+                            Err(e) => {
+                                error!("KCP receive error: {}", e);
+                            }
+                        }
+                    }
+
+                    // C. Abandon a ping run whose peer stopped answering
+                    _ = tokio::time::sleep_until(ping_deadline.unwrap_or_else(Instant::now)), if ping_deadline.is_some() => {
+                        if let Some(probe) = ping.take() {
+                            warn!("Ping run timed out");
+                            probe.finish();
+                        }
+                    }
+
+                    // D. Listen for peers trying to connect while idle
+                    // (not while our own handshake is reading the socket)
+                    result = socket.recv_from(&mut listen_buf), if !manager.is_connected() && connecting.is_none() && resolving.is_none() => {
+                        match result {
+                            Ok((len, sender)) => {
+                                if let Some((mode, cookie)) = incoming::parse_syn(&listen_buf[..len]) {
+                                    transcript.record(
+                                        Direction::Received,
+                                        Protocol::Handshake,
+                                        listen_addr,
+                                        sender,
+                                        || format!("Syn ({:?}) while idle", mode),
+                                        &listen_buf[..len],
+                                    );
+                                    let now = unix_timestamp();
+                                    if !cookie.is_some_and(|cookie| cookies.verify(sender, &cookie, now)) {
+                                        // Unvalidated source: answer with a cookie and keep no state
+                                        if let Err(e) = handshake::send_retry(&socket, sender, cookies.issue(sender, now), &transcript).await {
+                                            debug!("Failed to send cookie to {}: {}", sender, e);
+                                        }
+                                    } else if incoming.on_syn(sender, mode, Instant::now()) {
+                                        info!("Incoming connection request from {}", sender);
+                                        state.write().await.set_incoming_requests(incoming.requests().to_vec());
+                                    }
+                                }
+                            }
+                            Err(e) => debug!("Idle socket read failed: {}", e),
+                        }
+                    }
+
+                    // E. Reject connection requests nobody answered
+                    _ = tokio::time::sleep_until(incoming_deadline.unwrap_or_else(Instant::now)), if incoming_deadline.is_some() => {
+                        for addr in incoming.expire(Instant::now()) {
+                            info!("Connection request from {} expired", addr);
+                            if let Err(e) = handshake::send_bye(&socket, addr, &transcript).await {
+                                debug!("Failed to reject {}: {}", addr, e);
+                            }
+                        }
+                        state.write().await.set_incoming_requests(incoming.requests().to_vec());
+                    }
+
+                    // F. Handle NAT Keep-Alive
+                    // (paused during a handshake, whose packets share the sockets)
+                    _ = &mut keep_alive_timer, if connecting.is_none() => {
+                        let (status, targets) = {
+                            let mut guard = state.write().await;
+                            let low_traffic = guard.refresh_power_profile();
+                            if keep_alive.set_low_traffic(low_traffic) {
+                                info!("Low-traffic mode {}; NAT keep-alives at most every {}s", if low_traffic { "on" } else { "off" }, keep_alive.interval().as_secs());
+                            }
+                            let targets: Vec<String> = config
+                                .keep_alive_targets
+                                .iter()
+                                .filter_map(|target| target.resolve(&config.stun_server, &guard.contacts))
+                                .collect();
+                            (guard.status, targets)
+                        };
+
+                        // Keep standby paths' NAT mappings warm so sessions can fail over to them
+                        for standby in manager.standby_paths() {
+                            if let Err(e) = keep_alive::send(standby, &targets, &transcript).await {
+                                debug!("Standby path keep-alive failed: {}", e);
+                            }
+                        }
+
+                        if status == Status::Disconnected && !keep_alive.needs_query(Instant::now()) {
+                            match keep_alive::send(&socket, &targets, &transcript).await {
+                                Ok(target) => {
+                                    debug!("Sent NAT keep-alive to {}", target);
+                                    keep_alive.record_success();
+                                }
+                                Err(e) => {
+                                    debug!("Keep-alive failed: {}", e);
+                                    if keep_alive.record_failure() {
+                                        warn!("NAT keep-alives keep failing; offline, retrying with backoff");
+                                        state.write().await.set_network_status(NetworkStatus::Offline);
+                                    }
+                                }
+                            }
+                        } else if status == Status::Disconnected {
+                            debug!("Refreshing public address from STUN server");
+                            match net::resolve_public_ip(&socket, &config.stun_server, &transcript).await {
+                                Ok(addr) => {
+                                    keep_alive.observe(addr, Instant::now());
+                                    if keep_alive.record_success() {
+                                        info!("STUN server reachable again; back online");
+                                    }
+                                    let mut guard = state.write().await;
+                                    guard.set_network_status(NetworkStatus::Online);
+                                    guard.set_network_error(None);
+                                    if guard.public_ip != Some(addr) {
+                                        info!("Public IP changed from {:?} to {}", guard.public_ip, addr);
+                                        guard.set_public_ip(addr, Some(StatusMessage::PublicIpUpdated), None);
+                                    }
+                                    drop(guard);
+                                    ddns::publish(ddns.as_ref(), addr);
+
+                                    if needs_detection {
+                                        info!("Network is up, finishing startup");
+                                        needs_detection = false;
+                                        if let Ok(local_addr) = net::get_local_ip(local_port).await {
+                                            state.write().await.set_local_ip(local_addr, None, None);
+                                        }
+                                        let detection = net::detect_nat(&socket, &config.stun_server, &config.stun_verifier, &transcript).await;
+                                        let mut guard = state.write().await;
+                                        guard.set_stun_probes(detection.probes);
+                                        guard.set_nat_type(detection.nat_type, Some(StatusMessage::NatTypeDetected), None);
+                                    }
+
+                                    // A cached mapping that moved says nothing about the NAT type; re-detect it
+                                    if let Some(cached) = unconfirmed_cache.take() && cached.public_ip != addr {
+                                        info!("Cached NAT info is stale, re-detecting NAT type");
+                                        let detection = net::detect_nat(&socket, &config.stun_server, &config.stun_verifier, &transcript).await;
+                                        let mut guard = state.write().await;
+                                        guard.set_stun_probes(detection.probes);
+                                        guard.set_nat_type(detection.nat_type, Some(StatusMessage::NatTypeDetected), None);
+                                    }
+
+                                    if let Some(path) = &nat_cache_path {
+                                        let nat_type = state.read().await.nat_type;
+                                        let observed = NatCache::new(addr, nat_type, local_port, config.nat_cache_ttl_secs);
+                                        if nat_cache.as_ref().is_none_or(|c| c.should_replace_with(&observed)) {
+                                            if let Err(e) = observed.save(path) {
+                                                warn!("Failed to save NAT cache: {}", e);
+                                            }
+                                            nat_cache = Some(observed);
+                                        }
+                                    }
+                                }
+                                Err(e) => {
+                                    let e = match (&portal_client, &config.captive_portal_probe) {
+                                        (Some(client), Some(probe)) => captive_portal::classify(client, probe, e).await,
+                                        _ => e,
+                                    };
+                                    debug!("Keep-alive STUN check failed: {}", e);
+                                    let mut guard = state.write().await;
+                                    guard.set_network_error(Some(&e));
+                                    if keep_alive.record_failure() {
+                                        warn!("STUN keeps failing ({}); offline, retrying with backoff", e);
+                                        guard.set_network_status(NetworkStatus::Offline);
+                                    }
+                                }
+                            }
+                        }
+                        // Scheduled from the outcome, which sets the backoff
+                        keep_alive_timer.as_mut().reset(Instant::now() + keep_alive.next_delay());
+                        state.write().await.set_keep_alive_interval(keep_alive.interval());
+                    }
+
+                    // G. Finish a handshake running in the background
+                    result = async {
+                        match connecting.as_mut() {
+                            Some((_, task, _)) => task.await,
+                            None => std::future::pending().await,
+                        }
+                    }, if connecting.is_some() => {
+                        if let Some((peer_addr, _, _)) = connecting.take() {
+                            let result = result.unwrap_or_else(|e| Err(anyhow!("Handshake task failed: {}", e)));
+                            let outcome = if let Err(e) = manager.finish_handshake(peer_addr, result).await {
+                                error!("Handshake failed: {}", e);
+                                if let Some(next) = dial_queue.pop_front() {
+                                    info!("Trying the contact's next endpoint {}", next);
+                                    state.write().await.set_peer_ip(next, None, None, None);
+                                    dialing_next = true;
+                                    if let Err(e) = cmd_tx.try_send(Command::ConnectPeer { reply: connect_reply.take() }) {
+                                        error!("Failed to queue connection to {}: {}", next, e);
+                                    }
+                                }
+                                Err(format!("Handshake failed: {}", e))
+                            } else if let Err(e) = manager.upgrade_to_kcp().await {
+                                error!("Failed to upgrade to KCP: {}", e);
+                                state.write().await.set_status(
+                                    Status::Disconnected,
+                                    Some(StatusMessage::KcpUpgradeFailed { error: e.to_string() }),
+                                    None
+                                );
+                                Err(format!("KCP upgrade failed: {}", e))
+                            } else {
+                                dial_queue.clear();
+                                let mut guard = state.write().await;
+                                if let Err(e) = guard.contacts.reached(peer_addr) {
+                                    warn!("Failed to update contact address: {}", e);
+                                }
+                                guard.set_status(
+                                    Status::Connected,
+                                    Some(StatusMessage::ConnectedViaKcp),
+                                    None
+                                );
+                                let outcome = ConnectOutcome {
+                                    peer: peer_addr,
+                                    peer_label: guard.peer_label.clone(),
+                                    fingerprint: guard.fingerprint.clone(),
+                                    encryption_algo: guard.encryption_algo.clone(),
+                                };
+                                drop(guard);
+                                Ok(outcome)
+                            };
+                            if let Some(reply) = connect_reply.take() {
+                                let _ = reply.send(outcome);
+                            }
+                        }
+                    }
+
+                    // H. Roll back handshakes and sessions whose peer has gone silent
+                    _ = liveness_interval.tick(), if connecting.is_some() || manager.is_connected() => {
+                        if connecting.as_ref().is_some_and(|(_, _, deadline)| Instant::now() >= *deadline) {
+                            if let Some((peer_addr, task, _)) = connecting.take() {
+                                warn!("Handshake with {} overran its deadline", peer_addr);
+                                task.abort();
+                                manager.cancel_handshake(DisconnectReason::PeerTimeout, StatusMessage::HandshakeTimedOut { peer: None }).await;
+                                if let Some(next) = dial_queue.pop_front() {
+                                    info!("Trying the contact's next endpoint {}", next);
+                                    state.write().await.set_peer_ip(next, None, None, None);
+                                    dialing_next = true;
+                                    if let Err(e) = cmd_tx.try_send(Command::ConnectPeer { reply: connect_reply.take() }) {
+                                        error!("Failed to queue connection to {}: {}", next, e);
+                                    }
+                                }
+                                if let Some(reply) = connect_reply.take() {
+                                    let _ = reply.send(Err("Handshake timed out".into()));
+                                }
+                            }
+                        } else if manager.is_connected() {
+                            let mut guard = state.write().await;
+                            guard.record_data_usage(manager.session_bytes());
+                            guard.stats_history.sample(unix_timestamp_ms(), manager.link_counters());
+                            guard.set_outbox_depth(manager.backlog_len());
+                            drop(guard);
+                            let idle = manager.idle_for();
+                            if idle >= peer_timeout {
+                                if let Some(probe) = ping.take() {
+                                    probe.fail("Peer stopped responding");
+                                }
+                                manager.abandon(StatusMessage::PeerUnresponsive { idle_secs: idle.as_secs() }).await;
+                            } else {
+                                if idle >= peer_timeout / 3
+                                    && let Err(e) = manager.send_heartbeat().await
+                                {
+                                    debug!("Failed to send heartbeat: {}", e);
+                                }
+                                if manager.transcript_check_due()
+                                    && let Err(e) = manager.send_transcript_check().await
+                                {
+                                    debug!("Failed to send transcript check: {}", e);
+                                }
+                                let due = {
+                                    let guard = state.read().await;
+                                    guard.peer_ip.map(|peer| guard.scheduled.due(peer, unix_timestamp())).unwrap_or_default()
+                                };
+                                for message in due {
+                                    if let Err(e) = manager.send_text(message.text.clone()).await {
+                                        error!("Failed to send scheduled message: {}", e);
+                                        break;
+                                    }
+                                    let mut guard = state.write().await;
+                                    guard.add_message(message.text, true);
+                                    if let Err(e) = guard.scheduled.remove(message.id) {
+                                        warn!("Failed to save scheduled messages: {:#}", e);
+                                    }
+                                    guard.announce_scheduled();
+                                }
+                            }
+                        }
+                    }
+
+                    // I. Hand queued messages to KCP as the peer acknowledges earlier ones
+                    _ = tokio::time::sleep(OUTBOX_RETRY_INTERVAL), if manager.has_backlog() => {
+                        if let Err(e) = manager.pump_outbox().await {
+                            warn!("Failed to send queued messages: {}", e);
+                        }
+                    }
+
+                    // J. Dial a contact once its hostname is resolved
+                    result = async {
+                        match resolving.as_mut() {
+                            Some((_, _, _, task)) => task.await,
+                            None => std::future::pending().await,
+                        }
+                    }, if resolving.is_some() => {
+                        if let Some((label, hostname, peer_addr, _)) = resolving.take() {
+                            match result.unwrap_or_else(|e| Err(anyhow!("Lookup task failed: {}", e))) {
+                                Ok(addr) if addr != peer_addr => {
+                                    info!("{} now resolves to {}", hostname, addr);
+                                    let mut guard = state.write().await;
+                                    if let Err(e) = guard.contacts.relocate(&label, addr) {
+                                        warn!("Failed to update contact address: {}", e);
+                                    }
+                                    guard.set_peer_ip(addr, None, None, None);
+                                }
+                                Ok(_) => {}
+                                Err(e) => warn!("Failed to look up {}: {:#}", hostname, e),
+                            }
+                            resolved = true;
+                            if let Err(e) = cmd_tx.try_send(Command::ConnectPeer { reply: connect_reply.take() }) {
+                                error!("Failed to queue connection to {}: {}", hostname, e);
+                            }
+                        }
+                    }
+                }
+            }
+            .instrument(span)
+            .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{AssistGrant, EncryptionMode},
+        messaging::handshake::HandshakeMsg,
+        web::shared_state::{AppEvent, AppState, COMMAND_QUEUE_CAPACITY},
+    };
+    use stun::{
+        message::{BINDING_SUCCESS, Message},
+        xoraddr::XorMappedAddress,
+    };
+    use tokio::{
+        sync::{RwLock, broadcast},
+        time::timeout,
+    };
+
+    /// Starts a controller on a loopback socket. Nothing is written to disk
+    /// and no traffic leaves the host.
+    async fn start(config: Config) -> (SharedState, mpsc::Sender<Command>, SocketAddr) {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr = socket.local_addr().unwrap();
+        let (cmd_tx, cmd_rx) = mpsc::channel(COMMAND_QUEUE_CAPACITY);
+        let (event_tx, _) = broadcast::channel(32);
+        let state = Arc::new(RwLock::new(AppState::new(cmd_tx.clone(), event_tx)));
+        let controller = Controller {
+            config,
+            state: state.clone(),
+            socket,
+            commands: (cmd_tx.clone(), cmd_rx),
+            ddns: None,
+            portal_client: None,
+            startup: Startup::default(),
+        };
+        tokio::spawn(controller.run());
+        (state, cmd_tx, addr)
+    }
+
+    fn test_config(stun_server: SocketAddr) -> Config {
+        Config {
+            stun_server: stun_server.to_string(),
+            nat_cache_ttl_secs: 0,
+            ..Config::load()
+        }
+    }
+
+    async fn recv_handshake(socket: &UdpSocket) -> HandshakeMsg {
+        let mut buf = [0u8; 128];
+        let (len, _) = timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        HandshakeMsg::decode(&buf[..len]).unwrap()
+    }
+
+    /// Waits until `check` holds for the state, giving up after `within`.
+    async fn wait_for(state: &SharedState, within: Duration, check: impl Fn(&AppState) -> bool) {
+        timeout(within, async {
+            while !check(&*state.read().await) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("state never reached the expected condition");
+    }
+
+    #[tokio::test]
+    async fn test_incoming_syn_needs_cookie_and_can_be_rejected() {
+        let stun = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (state, cmd_tx, addr) = start(test_config(stun.local_addr().unwrap())).await;
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let syn = |cookie| HandshakeMsg::Syn {
+            public_key: [1; 32],
+            cipher_mode: EncryptionMode::ChaCha20Poly1305,
+            capabilities: Capabilities::default(),
+            cookie,
+        };
+
+        // An unproven source gets a cookie, and no state is kept for it
+        peer.send_to(&syn(None).encode(), addr).await.unwrap();
+        let HandshakeMsg::Retry { cookie } = recv_handshake(&peer).await else {
+            panic!("expected a Retry");
+        };
+        assert!(state.read().await.incoming_requests.is_empty());
+
+        peer.send_to(&syn(Some(cookie)).encode(), addr)
+            .await
+            .unwrap();
+        wait_for(&state, Duration::from_secs(5), |s| {
+            s.incoming_requests.len() == 1
+        })
+        .await;
+
+        cmd_tx
+            .send(Command::RejectIncoming(peer.local_addr().unwrap()))
+            .await
+            .unwrap();
+        assert_eq!(recv_handshake(&peer).await, HandshakeMsg::Bye);
+        wait_for(&state, Duration::from_secs(5), |s| {
+            s.incoming_requests.is_empty()
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_assist_is_refused_to_unknown_peer() {
+        let stun = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let stun_addr = stun.local_addr().unwrap();
+        let (alice, alice_tx, alice_addr) = start(Config {
+            assist_grants: vec![AssistGrant {
+                name: "hello".into(),
+                program: "true".into(),
+                args: Vec::new(),
+                allowed_contacts: vec!["Bob".into()],
+            }],
+            ..test_config(stun_addr)
+        })
+        .await;
+        let (bob, bob_tx, bob_addr) = start(test_config(stun_addr)).await;
+
+        // Both sides dial each other, as two peers punching through NAT do
+        for (state, cmd_tx, peer) in [(&alice, &alice_tx, bob_addr), (&bob, &bob_tx, alice_addr)] {
+            state.write().await.set_peer_ip(peer, None, None, None);
+            cmd_tx
+                .send(Command::ConnectPeer { reply: None })
+                .await
+                .unwrap();
+        }
+        for state in [&alice, &bob] {
+            wait_for(state, Duration::from_secs(10), |s| {
+                s.status == Status::Connected
+            })
+            .await;
+        }
+
+        let mut events = bob.read().await.subscribe_events();
+        let mut run = async |name: &str| {
+            bob_tx
+                .send(Command::AssistRun { name: name.into() })
+                .await
+                .unwrap();
+            timeout(Duration::from_secs(10), async {
+                loop {
+                    if let AppEvent::Assist {
+                        requested_by_me: true,
+                        outcome: Some(outcome),
+                        ..
+                    } = events.recv().await.unwrap()
+                    {
+                        return outcome;
+                    }
+                }
+            })
+            .await
+            .unwrap()
+        };
+
+        // A granted command, but Bob is not a saved contact of Alice
+        let outcome = run("hello").await;
+        assert_eq!(outcome.error.as_deref(), Some("Command not granted"));
+
+        alice
+            .write()
+            .await
+            .contacts
+            .upsert("Bob".into(), bob_addr)
+            .unwrap();
+        assert_eq!(run("hello").await.exit_code, Some(0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keep_alive_goes_offline_and_recovers() {
+        // A STUN server that stays silent until told to answer
+        let stun = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (state, _cmd_tx, _) = start(test_config(stun.local_addr().unwrap())).await;
+
+        // The paused clock skips ahead whenever the controller waits, so the
+        // keep-alive interval, STUN timeouts and backoff take no real time
+        wait_for(&state, Duration::from_secs(3600), |s| {
+            s.network_status == NetworkStatus::Offline
+        })
+        .await;
+        assert!(state.read().await.last_network_error.is_some());
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            loop {
+                let (len, client) = stun.recv_from(&mut buf).await.unwrap();
+                let mut request = Message::new();
+                request.unmarshal_binary(&buf[..len]).unwrap();
+                let mut response = Message::new();
+                response.transaction_id = request.transaction_id;
+                response
+                    .build(&[
+                        Box::new(BINDING_SUCCESS),
+                        Box::new(XorMappedAddress {
+                            ip: "198.51.100.7".parse().unwrap(),
+                            port: 4000,
+                        }),
+                    ])
+                    .unwrap();
+                stun.send_to(&response.raw, client).await.unwrap();
+            }
+        });
+
+        wait_for(&state, Duration::from_secs(3600), |s| {
+            s.network_status == NetworkStatus::Online
+        })
+        .await;
+        let data = state.read().await;
+        assert_eq!(data.public_ip, Some("198.51.100.7:4000".parse().unwrap()));
+        assert_eq!(data.last_network_error, None);
+    }
+}
@@ -0,0 +1,230 @@
+//! Crash reports written when GhostLink panics.
+//!
+//! A panic hook writes the panic message, location, a backtrace, the version,
+//! a summary of the configuration and the last recorded events to
+//! `crash_report.json` in the data directory. The configuration summary leaves
+//! out assist commands, shared folder paths, local addresses and the DDNS
+//! update URL. On the next start the report is announced through the API
+//! until it is dismissed.
+
+use crate::{
+    config::{Config, Dscp, EncryptionMode},
+    event_log::{EventLog, LogEntry},
+    storage::{read_json, unix_timestamp, write_json},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    backtrace::Backtrace,
+    panic::PanicHookInfo,
+    path::{Path, PathBuf},
+};
+
+/// Recorded events included in a report.
+const REPORT_EVENTS: usize = 50;
+
+/// Configuration details safe to include in a report.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConfigSummary {
+    pub client_port: u16,
+    pub web_port: u16,
+    pub extra_bind_addrs: usize,
+    pub encryption_mode: EncryptionMode,
+    pub traffic_padding: bool,
+    pub handshake_timeout_secs: u64,
+    pub peer_timeout_secs: u64,
+    pub dscp: Option<Dscp>,
+    pub ttl: Option<u32>,
+    pub debug_transcript: bool,
+    pub link_previews: bool,
+    pub assist_grants: usize,
+    pub shares: usize,
+    pub wipe_contacts: usize,
+    pub session_data_cap_bytes: Option<u64>,
+    pub monthly_data_cap_bytes: Option<u64>,
+    // Added later; reports from older runs lack them
+    #[serde(default)]
+    pub keep_alive_targets: usize,
+    /// True if DDNS updates are configured. The URL carries a token.
+    #[serde(default)]
+    pub ddns: bool,
+    #[serde(default)]
+    pub captive_portal_probe: bool,
+    #[serde(default)]
+    pub mirror_peers: usize,
+    #[serde(default)]
+    pub wake_relay_contacts: usize,
+    #[serde(default)]
+    pub update_check: bool,
+    /// True if an own-node secret is configured. The secret itself stays out.
+    #[serde(default)]
+    pub own_node_secret: bool,
+}
+
+impl From<&Config> for ConfigSummary {
+    fn from(config: &Config) -> Self {
+        Self {
+            client_port: config.client_port,
+            web_port: config.web_port,
+            extra_bind_addrs: config.extra_bind_addrs.len(),
+            encryption_mode: config.encryption_mode,
+            traffic_padding: config.traffic_padding,
+            handshake_timeout_secs: config.handshake_timeout_secs,
+            peer_timeout_secs: config.peer_timeout_secs,
+            dscp: config.dscp,
+            ttl: config.ttl,
+            debug_transcript: config.debug_transcript,
+            link_previews: config.link_previews,
+            assist_grants: config.assist_grants.len(),
+            shares: config.shares.len(),
+            wipe_contacts: config.wipe_contacts.len(),
+            session_data_cap_bytes: config.session_data_cap_bytes,
+            monthly_data_cap_bytes: config.monthly_data_cap_bytes,
+            keep_alive_targets: config.keep_alive_targets.len(),
+            ddns: config.ddns.is_some(),
+            captive_portal_probe: config.captive_portal_probe.is_some(),
+            mirror_peers: config.mirror.peers.len(),
+            wake_relay_contacts: config.wake_relay_contacts.len(),
+            update_check: config.update_check.is_some(),
+            own_node_secret: config.own_node_secret.is_some(),
+        }
+    }
+}
+
+/// Everything recorded about a panic.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CrashReport {
+    /// Unix timestamp (seconds) of the panic.
+    pub at: u64,
+    pub version: String,
+    pub message: String,
+    /// Source location as `file:line:column`.
+    pub location: Option<String>,
+    pub thread: Option<String>,
+    pub backtrace: String,
+    pub config: ConfigSummary,
+    /// Last events and warnings before the panic, oldest first.
+    pub last_events: Vec<LogEntry>,
+}
+
+/// Announcement of a report left by an earlier run.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CrashNotice {
+    pub at: u64,
+    pub version: String,
+    pub message: String,
+}
+
+impl From<&CrashReport> for CrashNotice {
+    fn from(report: &CrashReport) -> Self {
+        Self {
+            at: report.at,
+            version: report.version.clone(),
+            message: report.message.clone(),
+        }
+    }
+}
+
+/// Installs a panic hook writing a report to `path`.
+///
+/// The previous hook still runs afterwards, so the panic is printed as usual.
+pub fn install(path: PathBuf, config: &Config, event_log: EventLog) {
+    let config = ConfigSummary::from(config);
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let report = build(info, config.clone(), &event_log);
+        match write_json(&path, &report) {
+            Ok(()) => eprintln!("Crash report written to {}", path.display()),
+            Err(e) => eprintln!("Failed to write crash report: {:#}", e),
+        }
+        previous(info);
+    }));
+}
+
+/// Loads the report left at `path` by an earlier run, if any.
+pub fn load(path: &Path) -> Option<CrashReport> {
+    read_json(path).ok().flatten()
+}
+
+fn build(info: &PanicHookInfo<'_>, config: ConfigSummary, event_log: &EventLog) -> CrashReport {
+    let payload = info.payload();
+    let message = payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Box<dyn Any>".to_string());
+
+    let mut last_events = event_log.try_entries();
+    last_events.drain(..last_events.len().saturating_sub(REPORT_EVENTS));
+
+    CrashReport {
+        at: unix_timestamp(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        message,
+        location: info.location().map(|l| l.to_string()),
+        thread: std::thread::current().name().map(str::to_string),
+        backtrace: Backtrace::force_capture().to_string(),
+        config,
+        last_events,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{AssistGrant, SharedFolder},
+        storage::TempDir,
+    };
+
+    #[test]
+    fn test_config_summary_leaves_out_details() {
+        let mut config = Config::load();
+        config.assist_grants.push(AssistGrant {
+            name: "uptime".into(),
+            program: "/usr/bin/uptime".into(),
+            args: vec!["-p".into()],
+            allowed_contacts: vec!["Bob".into()],
+        });
+        config.shares.push(SharedFolder {
+            name: "docs".into(),
+            path: "/home/alice/private".into(),
+            allowed_contacts: vec!["Bob".into()],
+        });
+
+        config.ddns = Some(crate::ddns::DdnsSettings {
+            url: "https://ddns.example/update?token=hunter2&ip={ip}".into(),
+            expect: None,
+        });
+
+        let summary = ConfigSummary::from(&config);
+        assert_eq!(summary.assist_grants, 1);
+        assert_eq!(summary.shares, 1);
+        assert!(summary.ddns);
+        let text = serde_json::to_string(&summary).unwrap();
+        for detail in ["uptime", "/usr/bin", "/home/alice", "Bob", "hunter2"] {
+            assert!(!text.contains(detail), "{} leaked", detail);
+        }
+    }
+
+    #[test]
+    fn test_report_round_trip() {
+        let dir = TempDir::new("crash");
+        let path = dir.join("crash_report.json");
+        assert_eq!(load(&path), None);
+
+        let report = CrashReport {
+            at: 1_700_000_000,
+            version: "0.1.0".into(),
+            message: "index out of bounds".into(),
+            location: Some("src/main.rs:1:1".into()),
+            thread: Some("main".into()),
+            backtrace: String::new(),
+            config: ConfigSummary::from(&Config::load()),
+            last_events: Vec::new(),
+        };
+        write_json(&path, &report).unwrap();
+        let loaded = load(&path).unwrap();
+        assert_eq!(loaded, report);
+        assert_eq!(CrashNotice::from(&loaded).message, "index out of bounds");
+    }
+}
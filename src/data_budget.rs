@@ -0,0 +1,346 @@
+//! Data budgets for metered connections.
+//!
+//! Counts session traffic (both directions, as encrypted bytes on the KCP
+//! stream) against an optional per-session cap and an optional cap per
+//! calendar month (UTC). The month total is kept in a small JSON file in the
+//! data directory so it survives restarts. Crossing a configured percentage
+//! of a cap raises a warning once; with `hard_stop` set, shared file reads
+//! are refused once a cap is used up.
+
+use crate::storage::{read_json, unix_timestamp, utc_date, write_json};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tracing::warn;
+
+/// Error returned for shared file reads while a cap is used up.
+pub const BULK_BLOCKED: &str = "Data cap reached; file transfers are paused";
+
+/// New usage after which the month total is written out even without a warning.
+const SAVE_EVERY_BYTES: u64 = 1 << 20;
+
+/// Caps and warning thresholds, from the configuration.
+#[derive(Debug, Clone, Default)]
+pub struct BudgetLimits {
+    pub session_cap: Option<u64>,
+    pub monthly_cap: Option<u64>,
+    /// Percentages of a cap at which a warning is raised.
+    pub warn_percents: Vec<u8>,
+    /// Refuse shared file reads once a cap is used up.
+    pub hard_stop: bool,
+}
+
+/// Which cap a warning is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetScope {
+    Session,
+    Month,
+}
+
+/// A cap crossed one of its warning thresholds.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BudgetWarning {
+    pub scope: BudgetScope,
+    pub percent: u8,
+    pub used: u64,
+    pub cap: u64,
+}
+
+/// Usage against one cap.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BudgetUsage {
+    pub used: u64,
+    pub cap: Option<u64>,
+}
+
+/// Current usage, as reported by the API.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DataUsageReport {
+    /// Calendar month the monthly total covers, as `YYYY-MM`.
+    pub month: String,
+    pub session: BudgetUsage,
+    pub monthly: BudgetUsage,
+    /// True if shared file reads are being refused.
+    pub bulk_blocked: bool,
+}
+
+/// Month total as stored on disk.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct MonthUsage {
+    month: String,
+    bytes: u64,
+    /// Thresholds already warned about this month.
+    warned: Vec<u8>,
+}
+
+/// Session and month usage, backed by a JSON file.
+#[derive(Debug, Clone, Default)]
+pub struct DataBudget {
+    /// File the month total is saved to. `None` keeps it in memory only.
+    path: Option<PathBuf>,
+    limits: BudgetLimits,
+    month: MonthUsage,
+    session_bytes: u64,
+    /// Thresholds already warned about this session.
+    session_warned: Vec<u8>,
+    /// Usage not yet written to disk.
+    unsaved: u64,
+}
+
+impl DataBudget {
+    /// Opens the month total stored at `path`.
+    pub fn open(path: PathBuf, limits: BudgetLimits) -> Self {
+        let month = read_json(&path).unwrap_or_else(|e| {
+            warn!("Failed to load data usage: {:#}", e);
+            None
+        });
+        let mut budget = Self {
+            path: Some(path),
+            month: month.unwrap_or_default(),
+            ..Self::new(limits)
+        };
+        budget.roll_month(&current_month());
+        budget
+    }
+
+    /// Creates an in-memory budget.
+    pub fn new(limits: BudgetLimits) -> Self {
+        Self {
+            limits,
+            month: MonthUsage {
+                month: current_month(),
+                ..MonthUsage::default()
+            },
+            ..Self::default()
+        }
+    }
+
+    /// Updates usage from the running total of the current session.
+    ///
+    /// # Arguments
+    ///
+    /// * `session_total` - Bytes sent and received this session so far.
+    ///
+    /// # Returns
+    ///
+    /// Thresholds crossed since the last update.
+    pub fn observe_session(&mut self, session_total: u64) -> Vec<BudgetWarning> {
+        self.observe_at(session_total, &current_month())
+    }
+
+    /// Records the final total of a session and starts counting a new one.
+    ///
+    /// # Returns
+    ///
+    /// Thresholds crossed since the last update.
+    pub fn end_session(&mut self, session_total: u64) -> Vec<BudgetWarning> {
+        let warnings = self.observe_session(session_total);
+        self.session_bytes = 0;
+        self.session_warned.clear();
+        self.save();
+        warnings
+    }
+
+    /// Returns false if `hard_stop` is set and a cap is used up.
+    pub fn bulk_allowed(&self) -> bool {
+        let exhausted = |used: u64, cap: Option<u64>| cap.is_some_and(|cap| used >= cap);
+        !self.limits.hard_stop
+            || !(exhausted(self.session_bytes, self.limits.session_cap)
+                || exhausted(self.month.bytes, self.limits.monthly_cap))
+    }
+
+    /// Returns current usage against both caps.
+    pub fn report(&self) -> DataUsageReport {
+        DataUsageReport {
+            month: self.month.month.clone(),
+            session: BudgetUsage {
+                used: self.session_bytes,
+                cap: self.limits.session_cap,
+            },
+            monthly: BudgetUsage {
+                used: self.month.bytes,
+                cap: self.limits.monthly_cap,
+            },
+            bulk_blocked: !self.bulk_allowed(),
+        }
+    }
+
+    fn observe_at(&mut self, session_total: u64, month: &str) -> Vec<BudgetWarning> {
+        self.roll_month(month);
+        let delta = session_total.saturating_sub(self.session_bytes);
+        self.session_bytes = session_total;
+        self.month.bytes += delta;
+        self.unsaved += delta;
+
+        let mut warnings = crossed(
+            BudgetScope::Session,
+            self.session_bytes,
+            self.limits.session_cap,
+            &self.limits.warn_percents,
+            &mut self.session_warned,
+        );
+        warnings.extend(crossed(
+            BudgetScope::Month,
+            self.month.bytes,
+            self.limits.monthly_cap,
+            &self.limits.warn_percents,
+            &mut self.month.warned,
+        ));
+
+        if !warnings.is_empty() || self.unsaved >= SAVE_EVERY_BYTES {
+            self.save();
+        }
+        warnings
+    }
+
+    /// Starts a fresh month total if `month` differs from the stored one.
+    fn roll_month(&mut self, month: &str) {
+        if self.month.month != month {
+            self.month = MonthUsage {
+                month: month.to_string(),
+                ..MonthUsage::default()
+            };
+            self.save();
+        }
+    }
+
+    fn save(&mut self) {
+        self.unsaved = 0;
+        if let Some(path) = &self.path
+            && let Err(e) = write_json(path, &self.month)
+        {
+            warn!("Failed to save data usage: {:#}", e);
+        }
+    }
+}
+
+/// Returns the thresholds `used` has newly reached, marking them as warned.
+fn crossed(
+    scope: BudgetScope,
+    used: u64,
+    cap: Option<u64>,
+    percents: &[u8],
+    warned: &mut Vec<u8>,
+) -> Vec<BudgetWarning> {
+    let Some(cap) = cap.filter(|cap| *cap > 0) else {
+        return Vec::new();
+    };
+    let mut warnings = Vec::new();
+    for &percent in percents {
+        if !warned.contains(&percent)
+            && u128::from(used) * 100 >= u128::from(cap) * u128::from(percent)
+        {
+            warned.push(percent);
+            warnings.push(BudgetWarning {
+                scope,
+                percent,
+                used,
+                cap,
+            });
+        }
+    }
+    warnings
+}
+
+/// Returns the current calendar month (UTC) as `YYYY-MM`.
+fn current_month() -> String {
+    let (year, month) = year_month(unix_timestamp());
+    format!("{:04}-{:02}", year, month)
+}
+
+/// Converts a Unix timestamp to a (year, month) pair in UTC.
+fn year_month(unix_secs: u64) -> (i64, u32) {
+    let (year, month, _) = utc_date(unix_secs);
+    (year, month)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::TempDir;
+
+    fn limits() -> BudgetLimits {
+        BudgetLimits {
+            session_cap: Some(1000),
+            monthly_cap: Some(5000),
+            warn_percents: vec![80, 100],
+            hard_stop: true,
+        }
+    }
+
+    #[test]
+    fn test_year_month() {
+        assert_eq!(year_month(0), (1970, 1));
+        // 2024-02-29T23:59:59Z
+        assert_eq!(year_month(1_709_251_199), (2024, 2));
+        // 2024-03-01T00:00:00Z
+        assert_eq!(year_month(1_709_251_200), (2024, 3));
+        // 2026-12-31T12:00:00Z
+        assert_eq!(year_month(1_798_718_400), (2026, 12));
+    }
+
+    #[test]
+    fn test_warns_once_per_threshold() {
+        let mut budget = DataBudget::new(limits());
+
+        assert!(budget.observe_session(500).is_empty());
+        let warnings = budget.observe_session(850);
+        assert_eq!(
+            warnings,
+            vec![BudgetWarning {
+                scope: BudgetScope::Session,
+                percent: 80,
+                used: 850,
+                cap: 1000,
+            }]
+        );
+        assert!(budget.observe_session(900).is_empty());
+        assert!(budget.bulk_allowed());
+
+        let warnings = budget.end_session(1000);
+        assert_eq!(warnings[0].percent, 100);
+        assert!(!budget.report().bulk_blocked);
+        assert_eq!(budget.report().monthly.used, 1000);
+
+        // A new session counts from zero and may warn again
+        assert_eq!(budget.observe_session(800)[0].percent, 80);
+    }
+
+    #[test]
+    fn test_hard_stop_blocks_bulk() {
+        let mut budget = DataBudget::new(limits());
+        budget.observe_session(1200);
+        assert!(!budget.bulk_allowed());
+        assert!(budget.report().bulk_blocked);
+
+        let mut lenient = DataBudget::new(BudgetLimits {
+            hard_stop: false,
+            ..limits()
+        });
+        lenient.observe_session(1200);
+        assert!(lenient.bulk_allowed());
+    }
+
+    #[test]
+    fn test_month_total_persists_and_rolls_over() {
+        let dir = TempDir::new("budget");
+        let path = dir.join("data_usage.json");
+
+        let limits = BudgetLimits {
+            session_cap: None,
+            ..limits()
+        };
+        let mut budget = DataBudget::open(path.clone(), limits.clone());
+        budget.end_session(3000);
+        let warnings = DataBudget::open(path.clone(), limits.clone()).end_session(1000);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].scope, BudgetScope::Month);
+        assert_eq!(warnings[0].used, 4000);
+
+        // Usage from a past month is dropped
+        let mut reopened = DataBudget::open(path.clone(), limits);
+        reopened.observe_at(100, "1999-01");
+        assert_eq!(reopened.report().monthly.used, 100);
+        assert_eq!(reopened.report().month, "1999-01");
+    }
+}
@@ -0,0 +1,455 @@
+//! `ghostlink dev --pair`: two nodes on one machine, behind simulated NATs.
+//!
+//! Both nodes run in this process with their own UDP socket, web UI and
+//! data directory (`<data_dir>/dev/a` and `<data_dir>/dev/b`). Their
+//! packets cross a `SimulatedInternet` instead of a real network:
+//!
+//! - Each node sits behind a port-restricted cone NAT with one public
+//!   address. Packets from the other node only get in while the node has
+//!   itself sent to it within `MAPPING_LIFETIME`, so a connection needs both
+//!   sides punching, as it would across real NATs.
+//! - A STUN server tells each node its public address, so NAT detection and
+//!   keep-alives run unchanged.
+//! - With the `netem` feature, packets between the nodes also suffer the
+//!   impairments set through `/api/debug/netem` on either UI.
+//!
+//! Once both nodes are up they dial each other, and the two UIs show the
+//! two ends of one session.
+
+use crate::{
+    config::Config,
+    controller::{Controller, Startup},
+    net,
+    web::{
+        self,
+        shared_state::{
+            AppState, COMMAND_QUEUE_CAPACITY, Command, HEARTBEAT_INTERVAL, SharedState,
+        },
+        status_message::StatusMessage,
+    },
+};
+use anyhow::{Result, bail};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use stun::{
+    message::{BINDING_REQUEST, BINDING_SUCCESS, Message},
+    xoraddr::XorMappedAddress,
+};
+use tokio::{
+    net::UdpSocket,
+    sync::{RwLock, broadcast, mpsc},
+    task::JoinHandle,
+    time::{Duration, Instant, sleep},
+};
+use tracing::{debug, error, info, warn};
+
+/// How long a NAT keeps letting a peer in after the node last sent to it.
+/// Session heartbeats come well within it.
+const MAPPING_LIFETIME: Duration = Duration::from_secs(30);
+
+/// Names of the two nodes, also their data subdirectories.
+const NAMES: [&str; 2] = ["a", "b"];
+
+/// Impairments between the nodes. Without the `netem` feature, none.
+#[derive(Debug, Clone, Default)]
+pub struct Conditions {
+    #[cfg(feature = "netem")]
+    netem: crate::netem::NetemHandle,
+}
+
+impl Conditions {
+    /// Decides what happens to one packet: `Some(delay)` to deliver it
+    /// after `delay`, `None` to drop it.
+    async fn impair(&self) -> Option<Duration> {
+        #[cfg(feature = "netem")]
+        return crate::netem::impair(&*self.netem.read().await);
+        #[cfg(not(feature = "netem"))]
+        Some(Duration::ZERO)
+    }
+}
+
+/// Runs `ghostlink dev` until Ctrl+C.
+///
+/// # Arguments
+///
+/// * `config` - Configuration each node's is derived from.
+/// * `args` - Arguments after `dev`.
+///
+/// # Errors
+///
+/// Returns an error for unknown arguments, or if a node cannot bind its
+/// sockets or web UI.
+pub async fn run(config: Config, args: &[String]) -> Result<()> {
+    if args != ["--pair"] {
+        bail!("Usage: ghostlink dev --pair");
+    }
+
+    let conditions = Conditions::default();
+    let nodes = [
+        Node::bind(&config, NAMES[0]).await?,
+        Node::bind(&config, NAMES[1]).await?,
+    ];
+    // Either UI's /api/debug/netem controls the link between them
+    #[cfg(feature = "netem")]
+    for node in &nodes {
+        node.state.write().await.netem = conditions.netem.clone();
+    }
+    let internet = SimulatedInternet::spawn(
+        [nodes[0].socket.local_addr()?, nodes[1].socket.local_addr()?],
+        conditions,
+    )
+    .await?;
+
+    let mut controllers = Vec::new();
+    let mut commands = Vec::new();
+    for (i, node) in nodes.into_iter().enumerate() {
+        let peer = internet.public[1 - i];
+        println!(
+            "Node {}: {} (public address {}, peer {})",
+            NAMES[i].to_uppercase(),
+            node.web_url,
+            internet.public[i],
+            peer
+        );
+        commands.push(node.commands.0.clone());
+        controllers.push(tokio::spawn(node.start(internet.stun, peer)));
+    }
+    println!("Both nodes dial each other now. Press Ctrl+C to stop.");
+
+    let disconnect_timeout = Duration::from_millis(config.disconnect_timeout_ms);
+    tokio::select! {
+        result = futures::future::select_all(controllers) => {
+            let (result, i, _) = result;
+            match result {
+                Ok(Ok(())) => warn!("Node {} stopped", NAMES[i]),
+                Ok(Err(e)) => error!("Node {} failed: {}", NAMES[i], e),
+                Err(e) => error!("Node {} crashed: {}", NAMES[i], e),
+            }
+        }
+        _ = tokio::signal::ctrl_c() => {
+            info!("Received Ctrl+C, disconnecting both nodes");
+            for cmd_tx in &commands {
+                let _ = cmd_tx.send(Command::Disconnect).await;
+            }
+            sleep(disconnect_timeout).await;
+        }
+    }
+    drop(internet);
+    Ok(())
+}
+
+/// One of the two nodes, bound but not yet running.
+struct Node {
+    config: Config,
+    state: SharedState,
+    socket: Arc<UdpSocket>,
+    commands: (mpsc::Sender<Command>, mpsc::Receiver<Command>),
+    web_url: String,
+}
+
+impl Node {
+    /// Binds the node's UDP socket and serves its web UI.
+    async fn bind(base: &Config, name: &str) -> Result<Self> {
+        let mut config = base.clone();
+        config.data_dir = base.data_dir.join("dev").join(name);
+        config.client_port = 0;
+        config.extra_bind_addrs.clear();
+        config.ddns = None;
+        config.captive_portal_probe = None;
+        config.update_check = None;
+        config.nat_cache_ttl_secs = 0;
+
+        let socket = Arc::new(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?);
+        let local_addr = socket.local_addr()?;
+
+        let (cmd_tx, cmd_rx) = mpsc::channel(COMMAND_QUEUE_CAPACITY);
+        let (event_tx, _) = broadcast::channel(32);
+        let state = Arc::new(RwLock::new(AppState::new(cmd_tx.clone(), event_tx)));
+        {
+            let mut guard = state.write().await;
+            crate::open_stores(&mut guard, &config);
+            guard.bound_port = Some(local_addr.port());
+            guard.set_local_ip(local_addr, None, None);
+            // Both nodes and their simulated public addresses are on loopback
+            guard.allow_loopback_peers = true;
+        }
+
+        // The second node finds the first one's port taken and falls back
+        let listener = net::bind_tcp(
+            Ipv4Addr::LOCALHOST.into(),
+            config.web_port,
+            config.web_port_fallback.clone(),
+        )
+        .await?;
+        let base_path = web::forwarded::normalize_base_path(&config.web_base_path)?;
+        let web_url = format!(
+            "http://localhost:{}{}/",
+            listener.local_addr()?.port(),
+            base_path
+        );
+        state.write().await.web_url = Some(web_url.clone());
+        let web_state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = web::start_web_server(web_state, listener, &base_path).await {
+                error!("Web server crashed: {}", e);
+            }
+        });
+
+        let heartbeat_state = state.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+            loop {
+                interval.tick().await;
+                heartbeat_state.write().await.send_heartbeat();
+            }
+        });
+
+        Ok(Self {
+            config,
+            state,
+            socket,
+            commands: (cmd_tx, cmd_rx),
+            web_url,
+        })
+    }
+
+    /// Detects the node's NAT through `stun`, dials `peer` and runs the
+    /// controller.
+    async fn start(mut self, stun: SocketAddr, peer: SocketAddr) -> Result<()> {
+        self.config.stun_server = stun.to_string();
+        self.config.stun_verifier = stun.to_string();
+
+        let transcript = self.state.read().await.transcript.clone();
+        transcript.set_enabled(self.config.debug_transcript);
+        let detection = net::detect_nat(
+            &self.socket,
+            &self.config.stun_server,
+            &self.config.stun_verifier,
+            &transcript,
+        )
+        .await;
+        {
+            let mut guard = self.state.write().await;
+            guard.set_stun_probes(detection.probes);
+            let public_addr = detection.public_addr?;
+            guard.set_public_ip(public_addr, Some(StatusMessage::PublicIpResolved), None);
+            guard.set_nat_type(
+                detection.nat_type,
+                Some(StatusMessage::NatTypeDetected),
+                None,
+            );
+            guard.set_peer_ip(peer, None, Some(StatusMessage::TargetSet), None);
+        }
+        self.commands
+            .0
+            .send(Command::ConnectPeer { reply: None })
+            .await?;
+
+        Controller {
+            config: self.config,
+            state: self.state,
+            socket: self.socket,
+            commands: self.commands,
+            ddns: None,
+            portal_client: None,
+            startup: Startup::default(),
+        }
+        .run()
+        .await
+    }
+}
+
+/// The network between the two nodes: a NAT in front of each, and a STUN
+/// server. The relay stops when this is dropped.
+#[derive(Debug)]
+pub struct SimulatedInternet {
+    /// Public address of each node, as the other node and STUN see it.
+    pub public: [SocketAddr; 2],
+    /// Address of the STUN server.
+    pub stun: SocketAddr,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl SimulatedInternet {
+    /// Binds the public and STUN sockets and starts relaying.
+    ///
+    /// # Arguments
+    ///
+    /// * `nodes` - Local addresses of the two nodes.
+    /// * `conditions` - Impairments applied to packets between them.
+    pub async fn spawn(nodes: [SocketAddr; 2], conditions: Conditions) -> Result<Self> {
+        let public = [
+            Arc::new(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?),
+            Arc::new(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?),
+        ];
+        let stun = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addrs = [public[0].local_addr()?, public[1].local_addr()?];
+        let stun_addr = stun.local_addr()?;
+        debug!(
+            "Simulated internet: {} is {} and {} is {}, STUN on {}",
+            nodes[0], addrs[0], nodes[1], addrs[1], stun_addr
+        );
+
+        let last_sent = Arc::new(Mutex::new([None; 2]));
+        let mut tasks: Vec<_> = (0..2)
+            .map(|to| {
+                tokio::spawn(route(
+                    public.clone(),
+                    nodes,
+                    to,
+                    last_sent.clone(),
+                    conditions.clone(),
+                ))
+            })
+            .collect();
+        tasks.push(tokio::spawn(serve_stun(stun, nodes, addrs)));
+
+        Ok(Self {
+            public: addrs,
+            stun: stun_addr,
+            tasks,
+        })
+    }
+}
+
+impl Drop for SimulatedInternet {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Delivers packets addressed to node `to`'s public address, if its NAT
+/// lets them in.
+///
+/// `last_sent[i]` is when node `i` last sent to the other node, which keeps
+/// its NAT open to it.
+async fn route(
+    public: [Arc<UdpSocket>; 2],
+    nodes: [SocketAddr; 2],
+    to: usize,
+    last_sent: Arc<Mutex<[Option<Instant>; 2]>>,
+    conditions: Conditions,
+) {
+    let from = 1 - to;
+    let mut buf = [0u8; 65_535];
+    loop {
+        let (len, sender) = match public[to].recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                debug!("Simulated internet read failed: {}", e);
+                continue;
+            }
+        };
+        if sender != nodes[from] {
+            continue;
+        }
+
+        let now = Instant::now();
+        let admitted = {
+            let mut last_sent = last_sent.lock().unwrap_or_else(|e| e.into_inner());
+            last_sent[from] = Some(now);
+            last_sent[to].is_some_and(|at| now.duration_since(at) < MAPPING_LIFETIME)
+        };
+        if !admitted {
+            debug!(
+                "NAT of node {} dropped an unsolicited packet",
+                NAMES[to].to_uppercase()
+            );
+            continue;
+        }
+
+        let Some(delay) = conditions.impair().await else {
+            continue;
+        };
+        let datagram = buf[..len].to_vec();
+        let outbound = public[from].clone();
+        let dst = nodes[to];
+        tokio::spawn(async move {
+            if !delay.is_zero() {
+                sleep(delay).await;
+            }
+            let _ = outbound.send_to(&datagram, dst).await;
+        });
+    }
+}
+
+/// Answers binding requests from the nodes with their public address.
+async fn serve_stun(socket: UdpSocket, nodes: [SocketAddr; 2], public: [SocketAddr; 2]) {
+    let mut buf = [0u8; 1024];
+    loop {
+        let Ok((len, sender)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let Some(node) = nodes.iter().position(|&addr| addr == sender) else {
+            continue;
+        };
+        let mut request = Message::new();
+        // Keep-alives arrive as binding indications and need no answer
+        if request.unmarshal_binary(&buf[..len]).is_err() || request.typ != BINDING_REQUEST {
+            continue;
+        }
+
+        let mut response = Message::new();
+        response.transaction_id = request.transaction_id;
+        let built = response.build(&[
+            Box::new(BINDING_SUCCESS),
+            Box::new(XorMappedAddress {
+                ip: public[node].ip(),
+                port: public[node].port(),
+            }),
+        ]);
+        if built.is_ok() {
+            let _ = socket.send_to(&response.raw, sender).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transcript::Transcript;
+    use tokio::time::timeout;
+
+    async fn receive(socket: &UdpSocket) -> Option<(Vec<u8>, SocketAddr)> {
+        let mut buf = [0u8; 64];
+        let (len, from) = timeout(Duration::from_millis(200), socket.recv_from(&mut buf))
+            .await
+            .ok()?
+            .ok()?;
+        Some((buf[..len].to_vec(), from))
+    }
+
+    #[tokio::test]
+    async fn test_nat_needs_both_sides_to_punch() {
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let internet = SimulatedInternet::spawn(
+            [a.local_addr().unwrap(), b.local_addr().unwrap()],
+            Conditions::default(),
+        )
+        .await
+        .unwrap();
+        let [public_a, public_b] = internet.public;
+
+        // STUN reports each node's public address
+        let transcript = Transcript::default();
+        let mapped = net::resolve_public_ip(&a, internet.stun.to_string(), &transcript)
+            .await
+            .unwrap();
+        assert_eq!(mapped, public_a);
+
+        // B has not sent to A yet, so its NAT drops A's packet
+        a.send_to(b"knock", public_b).await.unwrap();
+        assert_eq!(receive(&b).await, None);
+
+        // B punching back gets through, as A's NAT is now open to B
+        b.send_to(b"punch", public_a).await.unwrap();
+        assert_eq!(receive(&a).await, Some((b"punch".to_vec(), public_b)));
+        a.send_to(b"hello", public_b).await.unwrap();
+        assert_eq!(receive(&b).await, Some((b"hello".to_vec(), public_a)));
+    }
+}
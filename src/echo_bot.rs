@@ -0,0 +1,345 @@
+//! Built-in echo peer for demos and onboarding.
+//!
+//! The bot is a second GhostLink endpoint on a loopback port, so a new user
+//! can try connecting, chatting and browsing a share on one machine. It
+//! accepts every connection request, echoes each text back, and offers a
+//! read-only share named `echo` with a single file held in memory. It keeps
+//! its own socket and state; the node dials it like any other peer.
+
+use crate::{
+    messaging::{
+        cookie::CookieIssuer,
+        expiry,
+        handshake::{self, Capabilities},
+        incoming,
+        message_manager::{MessageManager, StreamMessage},
+    },
+    share::{MAX_READ_LEN, ShareEntry, ShareRequest, ShareResponse},
+    storage::unix_timestamp,
+    web::{
+        shared_state::{AppEvent, AppState, Command, SharedState},
+        status_message::StatusMessage,
+    },
+};
+use anyhow::Result;
+use serde::Serialize;
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{
+    net::UdpSocket,
+    sync::{RwLock, broadcast, mpsc},
+    task::AbortHandle,
+    time::{Duration, timeout},
+};
+use tracing::{debug, info, warn};
+
+/// Seconds allowed for the handshake with a dialling node.
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+
+/// A session the node stopped talking to is dropped after this long. Nodes
+/// send heartbeats well within it.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Sent once the session is up.
+const GREETING: &str = "Hi, I'm the GhostLink echo bot. Anything you send comes straight back. \
+Browse the \"echo\" share to try reading a file.";
+
+/// Name of the bot's share.
+const SHARE_NAME: &str = "echo";
+
+/// Name of the only file in the bot's share.
+const FILE_NAME: &str = "welcome.txt";
+
+/// Contents of `FILE_NAME`.
+const FILE_CONTENTS: &str = "\
+Welcome to GhostLink!
+
+This file came from the echo bot over the same encrypted KCP session your
+messages use. A real peer shares folders from its config in the same way,
+and only with the contacts it names.
+
+Once this works, send your public address and port to a friend, connect
+to each other at the same time, and compare the fingerprint out loud.
+";
+
+/// A running echo bot.
+#[derive(Debug, Clone, Serialize)]
+pub struct EchoBot {
+    /// Loopback address to connect to.
+    pub addr: SocketAddr,
+    #[serde(skip)]
+    task: AbortHandle,
+}
+
+impl EchoBot {
+    /// Binds a loopback socket and starts answering connection requests on it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no loopback UDP port can be bound.
+    pub async fn start() -> Result<Self> {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+        let addr = socket.local_addr()?;
+        let task = tokio::spawn(serve(socket)).abort_handle();
+        info!("Echo bot listening on {}", addr);
+        Ok(Self { addr, task })
+    }
+
+    /// Stops the bot. A session it had open ends without a Bye, and the
+    /// node notices once its peer timeout runs out.
+    pub fn stop(&self) {
+        self.task.abort();
+        info!("Echo bot on {} stopped", self.addr);
+    }
+}
+
+/// Answers dialling nodes one at a time, for as long as the task runs.
+async fn serve(socket: Arc<UdpSocket>) {
+    let (cmd_tx, mut cmd_rx) = mpsc::channel::<Command>(8);
+    let (event_tx, _) = broadcast::channel::<AppEvent>(8);
+    tokio::spawn(async move { while cmd_rx.recv().await.is_some() {} });
+    let state = Arc::new(RwLock::new(AppState::new(cmd_tx, event_tx)));
+    let transcript = state.read().await.transcript.clone();
+    let mut manager = MessageManager::new(socket.clone(), state.clone());
+    manager.set_local_capabilities(Capabilities {
+        shares: true,
+        ..Default::default()
+    });
+    let cookies = CookieIssuer::new();
+    let mut buf = [0u8; 2048];
+
+    loop {
+        let (len, sender) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                debug!("Echo bot socket read failed: {}", e);
+                continue;
+            }
+        };
+        let Some((mode, cookie)) = incoming::parse_syn(&buf[..len]) else {
+            continue;
+        };
+        // Same source validation as a real node, so dialling the bot looks the same
+        let now = unix_timestamp();
+        if !cookie.is_some_and(|cookie| cookies.verify(sender, &cookie, now)) {
+            if let Err(e) =
+                handshake::send_retry(&socket, sender, cookies.issue(sender, now), &transcript)
+                    .await
+            {
+                debug!("Echo bot failed to send cookie to {}: {}", sender, e);
+            }
+            continue;
+        }
+
+        info!("Echo bot accepting {}", sender);
+        if let Err(e) = manager
+            .handshake(sender, HANDSHAKE_TIMEOUT_SECS, mode)
+            .await
+        {
+            debug!("Echo bot handshake with {} failed: {}", sender, e);
+            continue;
+        }
+        if let Err(e) = manager.upgrade_to_kcp().await {
+            warn!("Echo bot KCP upgrade failed: {}", e);
+            let _ = manager.disconnect().await;
+            continue;
+        }
+        echo(&mut manager, &state).await;
+        info!("Echo bot session with {} ended", sender);
+    }
+}
+
+/// Echoes a session until the node says goodbye or goes quiet.
+async fn echo(manager: &mut MessageManager, state: &SharedState) {
+    if let Err(e) = manager.send_text(GREETING.into()).await {
+        debug!("Echo bot greeting failed: {}", e);
+    }
+
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = match timeout(IDLE_TIMEOUT, manager.receive_message(&mut buf)).await {
+            Ok(Ok(n)) if n > 0 => n,
+            Ok(Ok(_)) | Ok(Err(_)) | Err(_) => {
+                manager
+                    .abandon(StatusMessage::PeerUnresponsive {
+                        idle_secs: manager.idle_for().as_secs(),
+                    })
+                    .await;
+                return;
+            }
+        };
+        let msg = match StreamMessage::decode(&buf[..n]) {
+            Ok(msg) => msg,
+            Err(e) => {
+                debug!("Echo bot ignoring undecodable message: {}", e);
+                continue;
+            }
+        };
+
+        let result = match msg {
+            StreamMessage::Text { text, clock } => {
+                // Observe the node's clock so the echo is ordered after the original
+                state
+                    .write()
+                    .await
+                    .add_peer_message(text.clone(), clock, None);
+                manager.send_text(text).await
+            }
+            StreamMessage::ExpiringText {
+                text,
+                clock,
+                ttl_secs,
+            } => {
+                state
+                    .write()
+                    .await
+                    .add_peer_message(text.clone(), clock, Some(ttl_secs));
+                manager.send_text(text).await
+            }
+            StreamMessage::MessageTtl { ttl_secs } => {
+                // Echoes disappear like the messages they answer
+                if let Ok(ttl_secs) = ttl_secs.map(expiry::validate_ttl).transpose() {
+                    state.write().await.set_message_ttl(ttl_secs, false);
+                }
+                Ok(())
+            }
+            StreamMessage::Ping(seq) => manager.send_pong(seq).await,
+            StreamMessage::ShareQuery { id, request } => {
+                manager.send_share_reply(id, answer(&request)).await
+            }
+            StreamMessage::TranscriptCheck(sent) => {
+                manager.verify_transcript(sent).await;
+                Ok(())
+            }
+            StreamMessage::Bye => {
+                let _ = manager.disconnect_on_bye_received().await;
+                return;
+            }
+            other => {
+                debug!("Echo bot ignoring {:?}", other);
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            debug!("Echo bot failed to answer: {}", e);
+        }
+    }
+}
+
+/// Answers a request against the bot's share.
+fn answer(request: &ShareRequest) -> Result<ShareResponse, String> {
+    match request {
+        ShareRequest::List { share, .. } if share.is_empty() => Ok(ShareResponse::Listing {
+            entries: vec![ShareEntry {
+                name: SHARE_NAME.into(),
+                is_dir: true,
+                size: 0,
+            }],
+            truncated: false,
+        }),
+        ShareRequest::List { share, path } if share == SHARE_NAME && path.is_empty() => {
+            Ok(ShareResponse::Listing {
+                entries: vec![ShareEntry {
+                    name: FILE_NAME.into(),
+                    is_dir: false,
+                    size: FILE_CONTENTS.len() as u64,
+                }],
+                truncated: false,
+            })
+        }
+        ShareRequest::Read {
+            share,
+            path,
+            offset,
+            len,
+        } if share == SHARE_NAME && path == FILE_NAME => {
+            let bytes = FILE_CONTENTS.as_bytes();
+            let start = (*offset).min(bytes.len() as u64) as usize;
+            let end = (start + (*len).min(MAX_READ_LEN) as usize).min(bytes.len());
+            Ok(ShareResponse::Data {
+                offset: *offset,
+                bytes: bytes[start..end].to_vec(),
+                total_size: bytes.len() as u64,
+            })
+        }
+        ShareRequest::List { share, .. } | ShareRequest::Read { share, .. }
+            if share != SHARE_NAME =>
+        {
+            Err("No such share".into())
+        }
+        _ => Err("No such file or directory".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EncryptionMode;
+
+    async fn receive(manager: &mut MessageManager) -> StreamMessage {
+        let mut buf = [0u8; 4096];
+        let n = timeout(Duration::from_secs(5), manager.receive_message(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        StreamMessage::decode(&buf[..n]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_echoes_texts_and_serves_its_share() {
+        let bot = EchoBot::start().await.unwrap();
+
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let (cmd_tx, _cmd_rx) = mpsc::channel::<Command>(8);
+        let (event_tx, _) = broadcast::channel::<AppEvent>(8);
+        let state = Arc::new(RwLock::new(AppState::new(cmd_tx, event_tx)));
+        let mut node = MessageManager::new(socket, state);
+        node.handshake(bot.addr, 5, EncryptionMode::ChaCha20Poly1305)
+            .await
+            .unwrap();
+        node.upgrade_to_kcp().await.unwrap();
+
+        assert!(matches!(
+            receive(&mut node).await,
+            StreamMessage::Text { text, .. } if text == GREETING
+        ));
+        node.send_text("hello bot".into()).await.unwrap();
+        assert!(matches!(
+            receive(&mut node).await,
+            StreamMessage::Text { text, .. } if text == "hello bot"
+        ));
+
+        node.send_share_query(
+            1,
+            ShareRequest::Read {
+                share: SHARE_NAME.into(),
+                path: FILE_NAME.into(),
+                offset: 0,
+                len: 7,
+            },
+        )
+        .await
+        .unwrap();
+        let StreamMessage::ShareReply {
+            id: 1,
+            result: Ok(ShareResponse::Data {
+                bytes, total_size, ..
+            }),
+        } = receive(&mut node).await
+        else {
+            panic!("expected file data");
+        };
+        assert_eq!(bytes, b"Welcome");
+        assert_eq!(total_size, FILE_CONTENTS.len() as u64);
+        assert_eq!(
+            answer(&ShareRequest::List {
+                share: "photos".into(),
+                path: String::new()
+            }),
+            Err("No such share".into())
+        );
+
+        // The bot acknowledges a Bye and is ready for the next session
+        assert!(node.disconnect().await.unwrap());
+        bot.stop();
+    }
+}
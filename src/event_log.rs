@@ -0,0 +1,367 @@
+//! Persistent log of recent events for after-the-fact diagnosis.
+//!
+//! The last `EVENT_LOG_LIMIT` UI events and warnings/errors from tracing are
+//! appended to a JSON Lines file in the data directory as they happen. On
+//! startup the previous run's file is kept as `event_log.last.jsonl`, so what
+//! the app saw before a crash or unexpected disconnect can be retrieved
+//! without having configured logging beforehand.
+//!
+//! Chat content, link previews, assist output and the full state snapshot are
+//! left out of stored events.
+
+use crate::{
+    storage::{remove_file, unix_timestamp_ms},
+    web::shared_state::AppEvent,
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::VecDeque,
+    fmt::{self, Write as _},
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, TryLockError},
+};
+use tracing::{
+    Level, Subscriber,
+    field::Field,
+    span::{Attributes, Id},
+};
+use tracing_subscriber::{Layer, layer::Context, registry::LookupSpan};
+
+/// Entries kept per run.
+pub const EVENT_LOG_LIMIT: usize = 500;
+
+/// Event fields not stored, as they carry user content or are too large.
+const REDACTED_FIELDS: [&str; 6] = [
+    "state",
+    "content",
+    "links",
+    "previews",
+    "outcome",
+    "scheduled",
+];
+
+/// One recorded event or log line.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogEntry {
+    /// Unix time in milliseconds.
+    pub at_ms: u64,
+    #[serde(flatten)]
+    pub kind: EntryKind,
+}
+
+/// What was recorded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum EntryKind {
+    /// An event sent to the UI, with user content removed.
+    Event { event: Value },
+    /// A warning or error from the application log.
+    Log {
+        level: String,
+        target: String,
+        message: String,
+        /// Spans the line was logged in, outermost first, e.g. `conn{id=1a2b3c4d}`.
+        #[serde(default)]
+        span: Option<String>,
+    },
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// File entries are appended to. `None` until opened.
+    path: Option<PathBuf>,
+    /// Entries of this run, newest last.
+    entries: VecDeque<LogEntry>,
+    /// Lines appended since the file was last rewritten.
+    appended: usize,
+}
+
+/// Shared handle to the event log.
+///
+/// Cloning is cheap; all clones record into the same log. Entries recorded
+/// before `open` are kept in memory and written once the file is known.
+#[derive(Debug, Clone, Default)]
+pub struct EventLog {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl EventLog {
+    /// Starts writing this run's log to `path`.
+    ///
+    /// An existing log at `path` is from the previous run and is moved to
+    /// `last_run_path(path)`, replacing the one before it.
+    pub fn open(&self, path: PathBuf) {
+        // Nothing here may log: tracing records into this log and would deadlock
+        let _ = fs::rename(&path, last_run_path(&path));
+        let mut inner = self.lock();
+        inner.path = Some(path);
+        inner.rewrite();
+    }
+
+    /// Records an event sent to the UI.
+    pub fn record_event(&self, event: &AppEvent) {
+        let mut event = serde_json::to_value(event).unwrap_or(Value::Null);
+        if let Some(fields) = event.as_object_mut() {
+            for field in REDACTED_FIELDS {
+                fields.remove(field);
+            }
+        }
+        self.record(EntryKind::Event { event });
+    }
+
+    /// Returns this run's entries, oldest first.
+    pub fn entries(&self) -> Vec<LogEntry> {
+        self.lock().entries.iter().cloned().collect()
+    }
+
+    /// Like `entries`, but returns nothing instead of waiting for the lock.
+    ///
+    /// For the panic hook, which may run while this thread holds it.
+    pub fn try_entries(&self) -> Vec<LogEntry> {
+        match self.inner.try_lock() {
+            Ok(inner) => inner.entries.iter().cloned().collect(),
+            Err(TryLockError::Poisoned(e)) => e.into_inner().entries.iter().cloned().collect(),
+            Err(TryLockError::WouldBlock) => Vec::new(),
+        }
+    }
+
+    /// Returns the entries of the previous run, oldest first.
+    ///
+    /// Empty if the log was never opened or there was no previous run.
+    pub fn last_run(&self) -> Vec<LogEntry> {
+        let Some(path) = self.lock().path.as_deref().map(last_run_path) else {
+            return Vec::new();
+        };
+        let Ok(text) = fs::read_to_string(path) else {
+            return Vec::new();
+        };
+        // A crash may have cut the last line short; unreadable lines are skipped
+        let entries: Vec<LogEntry> = text
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect();
+        let skip = entries.len().saturating_sub(EVENT_LOG_LIMIT);
+        entries.into_iter().skip(skip).collect()
+    }
+
+    /// Deletes this run's entries and the previous run's log.
+    ///
+    /// # Returns
+    ///
+    /// The number of entries of this run deleted.
+    pub fn clear(&self) -> Result<usize> {
+        let mut inner = self.lock();
+        let cleared = inner.entries.len();
+        inner.entries.clear();
+        if let Some(path) = inner.path.as_deref().map(last_run_path) {
+            remove_file(&path)?;
+        }
+        inner.rewrite();
+        Ok(cleared)
+    }
+
+    fn record(&self, kind: EntryKind) {
+        let entry = LogEntry {
+            at_ms: unix_timestamp_ms(),
+            kind,
+        };
+        self.lock().push(entry);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Inner {
+    fn push(&mut self, entry: LogEntry) {
+        if self.entries.len() == EVENT_LOG_LIMIT {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+
+        // The file is rewritten with the kept entries once it holds twice as many
+        if self.appended >= EVENT_LOG_LIMIT {
+            self.rewrite();
+        } else if let Some(path) = &self.path {
+            let appended = self.entries.back().is_some_and(|entry| {
+                let mut line = serde_json::to_string(entry).unwrap_or_default();
+                line.push('\n');
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .and_then(|mut file| file.write_all(line.as_bytes()))
+                    .is_ok()
+            });
+            if appended {
+                self.appended += 1;
+            }
+        }
+    }
+
+    /// Replaces the file with the entries held in memory.
+    fn rewrite(&mut self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let mut text = String::new();
+        for entry in &self.entries {
+            if let Ok(line) = serde_json::to_string(entry) {
+                text.push_str(&line);
+                text.push('\n');
+            }
+        }
+        if let Some(parent) = path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        let _ = fs::write(path, text);
+        self.appended = 0;
+    }
+}
+
+/// Path the previous run's log is kept at.
+pub fn last_run_path(path: &Path) -> PathBuf {
+    path.with_extension("last.jsonl")
+}
+
+/// Tracing layer recording warnings and errors into an `EventLog`.
+pub struct EventLogLayer(pub EventLog);
+
+/// Formatted fields of a span, kept in its extensions.
+struct SpanFields(String);
+
+impl<S> Layer<S> for EventLogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = MessageVisitor(String::new());
+        attrs.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(visitor.0));
+        }
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        // More verbose levels compare greater
+        if *metadata.level() > Level::WARN {
+            return;
+        }
+        let mut visitor = MessageVisitor(String::new());
+        event.record(&mut visitor);
+        let span = ctx.event_scope(event).map(|scope| {
+            scope
+                .from_root()
+                .map(|span| {
+                    let extensions = span.extensions();
+                    let fields = extensions.get::<SpanFields>().map_or("", |f| &f.0);
+                    format!("{}{{{}}}", span.name(), fields)
+                })
+                .collect::<Vec<_>>()
+                .join(":")
+        });
+        self.0.record(EntryKind::Log {
+            level: metadata.level().to_string(),
+            target: metadata.target().to_string(),
+            message: visitor.0,
+            span,
+        });
+    }
+}
+
+/// Formats an event's message followed by its other fields as `name=value`.
+struct MessageVisitor(String);
+
+impl tracing::field::Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+        if field.name() == "message" {
+            let _ = write!(self.0, "{:?}", value);
+        } else {
+            let _ = write!(self.0, "{}={:?}", field.name(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        messaging::{lamport::MessageOrder, reactions::MessageId},
+        storage::TempDir,
+    };
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_redacts_and_captures_warnings() {
+        let log = EventLog::default();
+        log.record_event(&AppEvent::Message {
+            content: "secret".into(),
+            from_me: true,
+            message_id: MessageId {
+                from_me: true,
+                seq: 0,
+            },
+            order: MessageOrder { clock: 1, side: 0 },
+            expires_at: None,
+            links: Vec::new(),
+            conversation_id: Some("c1".into()),
+            peer: None,
+            peer_label: None,
+        });
+
+        let subscriber = tracing_subscriber::registry().with(EventLogLayer(log.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("not recorded");
+            let _span = tracing::info_span!("conn", id = %"1a2b3c4d").entered();
+            tracing::warn!(peer = 7, "Peer went quiet");
+        });
+
+        let entries = log.entries();
+        assert_eq!(entries.len(), 2);
+        let EntryKind::Event { event } = &entries[0].kind else {
+            panic!("Expected an event");
+        };
+        assert_eq!(event["status"], "MESSAGE");
+        assert_eq!(event["conversation_id"], "c1");
+        assert!(event.get("content").is_none());
+        assert_eq!(
+            entries[1].kind,
+            EntryKind::Log {
+                level: "WARN".into(),
+                target: module_path!().into(),
+                message: "Peer went quiet peer=7".into(),
+                span: Some("conn{id=1a2b3c4d}".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_previous_run_survives_restart() {
+        let dir = TempDir::new("event-log");
+        let path = dir.join("event_log.jsonl");
+
+        let first = EventLog::default();
+        first.record_event(&AppEvent::ClearChat);
+        first.open(path.clone());
+        for _ in 0..EVENT_LOG_LIMIT + 10 {
+            first.record_event(&AppEvent::ClearChat);
+        }
+        // Only the kept entries remain after the file is compacted
+        assert!(fs::read_to_string(&path).unwrap().lines().count() <= EVENT_LOG_LIMIT * 2);
+
+        let second = EventLog::default();
+        second.open(path.clone());
+        assert_eq!(second.last_run().len(), EVENT_LOG_LIMIT);
+        assert!(second.entries().is_empty());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+    }
+}
@@ -0,0 +1,293 @@
+//! Periodic export of session history for other tools.
+//!
+//! For people who pipe their history into a note system, the janitor task
+//! in `main` writes the finished session records to a directory of their
+//! choosing, as JSON Lines or Markdown. A run writes a new file only if a
+//! session ended since the last export, and each file holds every record
+//! still kept; the oldest files beyond `keep_files` are deleted. Chat text is
+//! not kept past a session, so exports hold session metadata only, and a
+//! remote wipe leaves them alone.
+
+use crate::{
+    audit::SessionRecord,
+    storage::{format_utc, write_jsonl},
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{fmt::Write, fs, path::PathBuf};
+
+/// Export files start with this, followed by the unix time they were written.
+const FILE_PREFIX: &str = "ghostlink-history-";
+
+/// File format of an export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    /// One session record per line, as in `sessions.jsonl`.
+    #[default]
+    Jsonl,
+    /// A readable list of sessions.
+    Markdown,
+}
+
+impl ExportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Jsonl => "jsonl",
+            ExportFormat::Markdown => "md",
+        }
+    }
+}
+
+/// Export settings from the configuration.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportSettings {
+    /// Export on every janitor run. Can be toggled at runtime.
+    pub enabled: bool,
+    /// Directory the files are written to. `None` uses `exports` in the
+    /// data directory.
+    pub dir: Option<PathBuf>,
+    pub format: ExportFormat,
+    /// Export files kept in `dir`; older ones are deleted.
+    pub keep_files: usize,
+}
+
+impl Default for ExportSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: None,
+            format: ExportFormat::Jsonl,
+            keep_files: 10,
+        }
+    }
+}
+
+/// The most recent export file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LastExport {
+    pub path: PathBuf,
+    /// Unix timestamp (seconds) it was written at.
+    pub at: u64,
+    /// Session records it holds.
+    pub sessions: usize,
+}
+
+/// Runtime state of the history export.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HistoryExport {
+    pub enabled: bool,
+    pub dir: PathBuf,
+    pub format: ExportFormat,
+    pub keep_files: usize,
+    pub last_export: Option<LastExport>,
+    /// End time of the newest session in the last export.
+    #[serde(skip)]
+    exported_until: Option<u64>,
+}
+
+impl HistoryExport {
+    /// Creates the export from `settings`, writing to `default_dir` unless
+    /// they name a directory.
+    pub fn new(settings: &ExportSettings, default_dir: PathBuf) -> Self {
+        Self {
+            enabled: settings.enabled,
+            dir: settings.dir.clone().unwrap_or(default_dir),
+            format: settings.format,
+            keep_files: settings.keep_files.max(1),
+            last_export: None,
+            exported_until: None,
+        }
+    }
+
+    /// Turns the export on or off, and changes its format if given.
+    ///
+    /// A new format is written on the next run even if no session ended.
+    pub fn set(&mut self, enabled: bool, format: Option<ExportFormat>) {
+        self.enabled = enabled;
+        if let Some(format) = format
+            && format != self.format
+        {
+            self.format = format;
+            self.exported_until = None;
+        }
+    }
+
+    /// Writes `records` to a new export file if enabled and a session ended
+    /// since the last export, then deletes the oldest files beyond `keep_files`.
+    ///
+    /// # Arguments
+    ///
+    /// * `records` - Finished sessions, oldest first.
+    /// * `now` - Current unix timestamp (seconds), used in the file name.
+    ///
+    /// # Returns
+    ///
+    /// The path written, or `None` if there was nothing to do.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file could not be written or old files could
+    /// not be deleted.
+    pub fn run<'a>(
+        &mut self,
+        records: impl Iterator<Item = &'a SessionRecord>,
+        now: u64,
+    ) -> Result<Option<PathBuf>> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        let records: Vec<_> = records.collect();
+        let newest = records.iter().filter_map(|r| r.ended_at).max();
+        if newest.is_none() || newest == self.exported_until {
+            return Ok(None);
+        }
+
+        let path = self.dir.join(format!(
+            "{}{}.{}",
+            FILE_PREFIX,
+            now,
+            self.format.extension()
+        ));
+        match self.format {
+            ExportFormat::Jsonl => write_jsonl(&path, &records)?,
+            ExportFormat::Markdown => {
+                fs::create_dir_all(&self.dir).with_context(|| {
+                    format!("Failed to create directory {}", self.dir.display())
+                })?;
+                fs::write(&path, markdown(&records, now))
+                    .with_context(|| format!("Failed to write {}", path.display()))?;
+            }
+        }
+        self.exported_until = newest;
+        self.last_export = Some(LastExport {
+            path: path.clone(),
+            at: now,
+            sessions: records.len(),
+        });
+        self.rotate()?;
+        Ok(Some(path))
+    }
+
+    /// Deletes the oldest export files beyond `keep_files`.
+    fn rotate(&self) -> Result<()> {
+        let mut files: Vec<_> = fs::read_dir(&self.dir)
+            .with_context(|| format!("Failed to list {}", self.dir.display()))?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| {
+                        name.starts_with(FILE_PREFIX)
+                            && (name.ends_with(".jsonl") || name.ends_with(".md"))
+                    })
+            })
+            .collect();
+        // Names differ only in the timestamp, which has a fixed width
+        files.sort();
+        let excess = files.len().saturating_sub(self.keep_files);
+        for path in &files[..excess] {
+            fs::remove_file(path)
+                .with_context(|| format!("Failed to delete {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Renders session records as a Markdown document.
+fn markdown(records: &[&SessionRecord], now: u64) -> String {
+    let mut out = format!(
+        "# GhostLink session history\n\nExported {}. {} sessions, oldest first.\n",
+        format_utc(now),
+        records.len()
+    );
+    for record in records {
+        let peer = record.peer.to_string();
+        let _ = write!(
+            out,
+            "\n## {} with {}\n\n- Peer: {}\n",
+            format_utc(record.started_at),
+            record.peer_label.as_deref().unwrap_or(&peer),
+            peer
+        );
+        if let Some(ended_at) = record.ended_at {
+            let minutes = ended_at.saturating_sub(record.started_at) / 60;
+            let _ = writeln!(out, "- Duration: {} min", minutes);
+        }
+        if let Some(algo) = &record.encryption_algo {
+            let _ = writeln!(out, "- Encryption: {}", algo);
+        }
+        let _ = writeln!(
+            out,
+            "- Traffic: {} bytes sent, {} bytes received",
+            record.bytes_sent, record.bytes_received
+        );
+        if let Some(reason) = record.disconnect_reason {
+            let _ = writeln!(out, "- Ended: {:?}", reason);
+        }
+        if let Some(error) = &record.error {
+            let _ = writeln!(out, "- Error: {}", error);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{audit::DisconnectReason, storage::TempDir, web::shared_state::NatType};
+
+    fn record(started_at: u64) -> SessionRecord {
+        SessionRecord {
+            connection_id: None,
+            started_at,
+            ended_at: Some(started_at + 600),
+            peer: "203.0.113.7:41234".parse().unwrap(),
+            peer_label: Some("Bob".into()),
+            local_nat_type: NatType::Unknown,
+            transport: Some("KCP".into()),
+            encryption_algo: Some("ChaCha20-Poly1305".into()),
+            bytes_sent: 10,
+            bytes_received: 20,
+            disconnect_reason: Some(DisconnectReason::PeerRequest),
+            error: None,
+            retention: None,
+        }
+    }
+
+    #[test]
+    fn test_exports_new_sessions_and_rotates() {
+        let dir = TempDir::new("export");
+        let settings = ExportSettings {
+            enabled: true,
+            dir: Some(dir.path().to_path_buf()),
+            format: ExportFormat::Jsonl,
+            keep_files: 2,
+        };
+        let mut export = HistoryExport::new(&settings, PathBuf::new());
+        let mut records = vec![record(1_700_000_000)];
+
+        let first = export.run(records.iter(), 1_700_001_000).unwrap().unwrap();
+        assert_eq!(fs::read_to_string(&first).unwrap().lines().count(), 1);
+        // Nothing ended since
+        assert_eq!(export.run(records.iter(), 1_700_002_000).unwrap(), None);
+
+        records.push(record(1_700_003_000));
+        export.run(records.iter(), 1_700_004_000).unwrap().unwrap();
+        export.set(true, Some(ExportFormat::Markdown));
+        let markdown = export.run(records.iter(), 1_700_005_000).unwrap().unwrap();
+        let text = fs::read_to_string(&markdown).unwrap();
+        assert!(text.contains("2 sessions"));
+        assert!(text.contains("with Bob"));
+        assert_eq!(export.last_export.as_ref().unwrap().sessions, 2);
+
+        // Only the two newest files are kept
+        assert!(!first.exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+
+        export.set(false, None);
+        records.push(record(1_700_006_000));
+        assert_eq!(export.run(records.iter(), 1_700_007_000).unwrap(), None);
+    }
+}
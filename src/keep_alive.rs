@@ -0,0 +1,407 @@
+//! Scheduling of NAT keep-alives.
+//!
+//! A fixed interval with a full STUN query on every tick makes nodes started
+//! together hit the STUN server in lockstep. Keep-alives therefore go out at
+//! a jittered interval, and most are Binding Indications, which keep the
+//! mapping open without asking the server anything. A full query refreshes
+//! the reflexive address once the cached one is older than the mapping has
+//! been seen to stay the same, bounded by one keep-alive interval and the NAT
+//! cache TTL.
+//!
+//! Indications go to the first reachable entry of `keep_alive_targets`:
+//! the STUN server, a friend's node or any other host, for networks where
+//! the STUN server is blocked or for privacy. A NAT that maps independently
+//! of the destination keeps one mapping for all of them, so the address
+//! learnt from the STUN server still holds. Queries always go to the STUN
+//! server.
+//!
+//! Once `BREAKER_THRESHOLD` keep-alives in a row fail (offline, captive
+//! portal), the circuit opens: the node counts as offline and keep-alives
+//! become recovery queries, each after twice the wait of the last, up to
+//! `MAX_BACKOFF`. The first one that succeeds closes the circuit again.
+//!
+//! In low-traffic mode (on battery or a metered link, see `power_profile`)
+//! every keep-alive is a query, sent after a gap that grows by half each
+//! time the mapping is found to have survived the previous one, up to
+//! `MAX_STRETCHED_INTERVAL`. When the mapping moved, the gap falls back to
+//! the longest one it has survived.
+
+use crate::{
+    contacts::Contacts,
+    net::{self, StunError},
+    transcript::Transcript,
+};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use tokio::{
+    net::UdpSocket,
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+/// Share of the interval a keep-alive may come early, in percent. Never
+/// late, so the mapping is not left idle longer than configured.
+const JITTER_PERCENT: u64 = 20;
+
+/// Consecutive failed keep-alives that open the circuit.
+const BREAKER_THRESHOLD: u32 = 3;
+
+/// Longest wait between recovery queries while the circuit is open.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Longest gap between keep-alives in low-traffic mode.
+const MAX_STRETCHED_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Where keep-alive indications may go.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "target", rename_all = "snake_case")]
+pub enum KeepAliveTarget {
+    /// The configured `stun_server`.
+    StunServer,
+    /// A friend's node, by saved contact label.
+    Contact(String),
+    /// Any `host:port`, such as a self-hosted STUN server.
+    Host(String),
+}
+
+impl KeepAliveTarget {
+    /// Returns the `host:port` to send to, or `None` for an unknown contact.
+    pub fn resolve(&self, stun_server: &str, contacts: &Contacts) -> Option<String> {
+        match self {
+            KeepAliveTarget::StunServer => Some(stun_server.to_string()),
+            KeepAliveTarget::Contact(label) => contacts
+                .all()
+                .iter()
+                .find(|c| &c.label == label)
+                .map(|c| c.addr.to_string()),
+            KeepAliveTarget::Host(host) => Some(host.clone()),
+        }
+    }
+}
+
+/// Sends a keep-alive indication to the first of `targets` that takes it.
+///
+/// # Returns
+///
+/// * `Ok(&str)` - The target sent to.
+/// * `Err(StunError)` - Every target failed, with the last failure; or
+///   there were no targets.
+pub async fn send<'a>(
+    socket: &UdpSocket,
+    targets: &'a [String],
+    transcript: &Transcript,
+) -> Result<&'a str, StunError> {
+    let mut last_error = StunError::DnsFailure("No keep-alive target configured".into());
+    for target in targets {
+        match net::send_binding_indication(socket, target, transcript).await {
+            Ok(()) => return Ok(target),
+            Err(e) => {
+                debug!("Keep-alive to {} failed: {}", target, e);
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// A reflexive address and how long it has held.
+#[derive(Debug, Clone, Copy)]
+struct Reflexive {
+    addr: SocketAddr,
+    /// When a query first returned `addr`.
+    since: Instant,
+    /// When a query last returned `addr`.
+    confirmed_at: Instant,
+}
+
+/// Decides when the next keep-alive goes out and whether it queries.
+#[derive(Debug, Clone)]
+pub struct KeepAlive {
+    interval: Duration,
+    /// Longest a reflexive address is trusted without a query.
+    max_refresh: Duration,
+    cached: Option<Reflexive>,
+    /// Keep-alives failed in a row.
+    failures: u32,
+    /// Stretch the interval to save traffic.
+    low_traffic: bool,
+    /// Gap between keep-alives in low-traffic mode.
+    stretched: Duration,
+    /// Longest gap between queries the mapping has survived.
+    survived: Duration,
+}
+
+impl KeepAlive {
+    /// Creates a schedule with nothing cached, so the first keep-alive queries.
+    ///
+    /// # Arguments
+    ///
+    /// * `interval` - Longest gap between keep-alives.
+    /// * `max_refresh` - Longest a reflexive address is trusted without a query.
+    pub fn new(interval: Duration, max_refresh: Duration) -> Self {
+        Self {
+            interval,
+            max_refresh,
+            cached: None,
+            failures: 0,
+            low_traffic: false,
+            stretched: interval,
+            survived: interval,
+        }
+    }
+
+    /// Turns low-traffic mode on or off. It resumes at the longest gap the
+    /// mapping has survived.
+    ///
+    /// # Returns
+    ///
+    /// True if the mode changed, so the pending keep-alive should be rescheduled.
+    pub fn set_low_traffic(&mut self, low_traffic: bool) -> bool {
+        if low_traffic == self.low_traffic {
+            return false;
+        }
+        self.low_traffic = low_traffic;
+        self.stretched = self.survived;
+        true
+    }
+
+    /// Returns the longest gap between keep-alives while the circuit is closed.
+    pub fn interval(&self) -> Duration {
+        if self.low_traffic {
+            self.stretched
+        } else {
+            self.interval
+        }
+    }
+
+    /// Returns how long to wait before the next keep-alive.
+    pub fn next_delay(&self) -> Duration {
+        let interval = self.interval();
+        let wait = if self.is_open() {
+            let doublings = (self.failures - BREAKER_THRESHOLD + 1).min(16);
+            (interval * 2u32.pow(doublings)).min(MAX_BACKOFF.max(interval))
+        } else {
+            interval
+        };
+        let early = wait.as_millis() as u64 * (OsRng.next_u64() % (JITTER_PERCENT + 1)) / 100;
+        wait - Duration::from_millis(early)
+    }
+
+    /// Returns true if the keep-alive due at `now` should be a full query.
+    ///
+    /// Always true while the circuit is open, and in low-traffic mode, where
+    /// the query tells whether the mapping survived the gap.
+    pub fn needs_query(&self, now: Instant) -> bool {
+        let Some(cached) = self.cached else {
+            return true;
+        };
+        if self.low_traffic {
+            return true;
+        }
+        let held = cached.confirmed_at.duration_since(cached.since);
+        let refresh = held.clamp(self.interval, self.max_refresh.max(self.interval));
+        now >= cached.confirmed_at + refresh
+    }
+
+    /// Records the reflexive address a query returned at `now`.
+    ///
+    /// # Returns
+    ///
+    /// True if the address is new or changed.
+    pub fn observe(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        match &mut self.cached {
+            Some(cached) if cached.addr == addr => {
+                if self.low_traffic {
+                    let gap = now.duration_since(cached.confirmed_at);
+                    self.survived = self.survived.max(gap);
+                    self.stretched = (self.stretched * 3 / 2).min(MAX_STRETCHED_INTERVAL);
+                }
+                cached.confirmed_at = now;
+                false
+            }
+            _ => {
+                if self.low_traffic && self.cached.is_some() {
+                    self.stretched = self.survived;
+                }
+                self.cached = Some(Reflexive {
+                    addr,
+                    since: now,
+                    confirmed_at: now,
+                });
+                true
+            }
+        }
+    }
+
+    /// Records a successful keep-alive or query.
+    ///
+    /// # Returns
+    ///
+    /// True if this closed the circuit.
+    pub fn record_success(&mut self) -> bool {
+        let was_open = self.is_open();
+        self.failures = 0;
+        was_open
+    }
+
+    /// Records a failed keep-alive or query and forgets the cached address,
+    /// so the next keep-alive queries.
+    ///
+    /// # Returns
+    ///
+    /// True if this opened the circuit.
+    pub fn record_failure(&mut self) -> bool {
+        self.cached = None;
+        self.failures = self.failures.saturating_add(1);
+        self.failures == BREAKER_THRESHOLD
+    }
+
+    /// Returns true while the circuit is open: keep-alives keep failing.
+    pub fn is_open(&self) -> bool {
+        self.failures >= BREAKER_THRESHOLD
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queries_less_often_while_the_mapping_holds() {
+        let interval = Duration::from_secs(15);
+        let mut keep_alive = KeepAlive::new(interval, Duration::from_secs(600));
+        let addr: SocketAddr = "198.51.100.1:40000".parse().unwrap();
+        let start = Instant::now();
+
+        for _ in 0..50 {
+            let delay = keep_alive.next_delay();
+            assert!(delay <= interval && delay >= interval * 4 / 5);
+        }
+
+        assert!(keep_alive.needs_query(start));
+        assert!(keep_alive.observe(addr, start));
+        // A fresh address is re-checked after one interval
+        assert!(!keep_alive.needs_query(start + interval / 2));
+        assert!(keep_alive.needs_query(start + interval));
+
+        // Held for a minute: trusted for another minute
+        let later = start + Duration::from_secs(60);
+        assert!(!keep_alive.observe(addr, later));
+        assert!(!keep_alive.needs_query(later + Duration::from_secs(59)));
+        assert!(keep_alive.needs_query(later + Duration::from_secs(60)));
+
+        // Never past the cap
+        let much_later = start + Duration::from_secs(3600);
+        keep_alive.observe(addr, much_later);
+        assert!(keep_alive.needs_query(much_later + Duration::from_secs(600)));
+
+        let moved: SocketAddr = "198.51.100.1:40001".parse().unwrap();
+        assert!(keep_alive.observe(moved, much_later));
+        keep_alive.record_failure();
+        assert!(keep_alive.needs_query(much_later));
+    }
+
+    #[test]
+    fn test_circuit_backs_off_and_recovers() {
+        let interval = Duration::from_secs(15);
+        let mut keep_alive = KeepAlive::new(interval, Duration::from_secs(600));
+
+        assert!(!keep_alive.record_failure());
+        assert!(!keep_alive.record_failure());
+        assert!(keep_alive.next_delay() <= interval);
+        assert!(keep_alive.record_failure());
+        assert!(keep_alive.is_open());
+        assert!(keep_alive.next_delay() >= interval * 2 * 4 / 5);
+
+        // Waits double up to the cap
+        assert!(!keep_alive.record_failure());
+        assert!(keep_alive.next_delay() >= interval * 4 * 4 / 5);
+        for _ in 0..40 {
+            keep_alive.record_failure();
+        }
+        let delay = keep_alive.next_delay();
+        assert!(delay <= MAX_BACKOFF && delay >= MAX_BACKOFF * 4 / 5);
+
+        assert!(keep_alive.record_success());
+        assert!(!keep_alive.is_open());
+        assert!(!keep_alive.record_success());
+        assert!(keep_alive.next_delay() <= interval);
+    }
+
+    #[test]
+    fn test_low_traffic_stretches_while_the_mapping_survives() {
+        let interval = Duration::from_secs(20);
+        let mut keep_alive = KeepAlive::new(interval, Duration::from_secs(600));
+        let addr: SocketAddr = "198.51.100.1:40000".parse().unwrap();
+        let mut now = Instant::now();
+        keep_alive.observe(addr, now);
+
+        assert!(keep_alive.set_low_traffic(true));
+        assert!(!keep_alive.set_low_traffic(true));
+        assert!(keep_alive.needs_query(now));
+        // 20s, 30s, 45s, 67.5s: each gap survived stretches the next
+        for expected in [20_000, 30_000, 45_000, 67_500] {
+            assert_eq!(keep_alive.interval(), Duration::from_millis(expected));
+            now += keep_alive.interval();
+            assert!(!keep_alive.observe(addr, now));
+        }
+
+        // The mapping expired within 101.25s: back to the 67.5s it survived
+        now += keep_alive.interval();
+        let moved: SocketAddr = "198.51.100.1:40001".parse().unwrap();
+        assert!(keep_alive.observe(moved, now));
+        assert_eq!(keep_alive.interval(), Duration::from_millis(67_500));
+
+        assert!(keep_alive.set_low_traffic(false));
+        assert_eq!(keep_alive.interval(), interval);
+        assert!(!keep_alive.needs_query(now));
+        // Resumes from the longest gap survived
+        keep_alive.set_low_traffic(true);
+        assert_eq!(keep_alive.interval(), Duration::from_millis(67_500));
+    }
+
+    #[test]
+    fn test_targets_resolve_in_priority_order() {
+        let mut contacts = Contacts::default();
+        contacts
+            .upsert("Alice".into(), "203.0.113.7:41234".parse().unwrap())
+            .unwrap();
+        let targets = [
+            KeepAliveTarget::Contact("Bob".into()),
+            KeepAliveTarget::Contact("Alice".into()),
+            KeepAliveTarget::Host("stun.example.org:3478".into()),
+            KeepAliveTarget::StunServer,
+        ];
+        let resolved: Vec<_> = targets
+            .iter()
+            .filter_map(|t| t.resolve("stun.l.google.com:19302", &contacts))
+            .collect();
+        assert_eq!(
+            resolved,
+            [
+                "203.0.113.7:41234",
+                "stun.example.org:3478",
+                "stun.l.google.com:19302"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_falls_back_to_the_next_target() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let targets = [
+            // No IPv4 address, like a blocked or unresolvable host
+            "[::1]:3478".to_string(),
+            target.local_addr().unwrap().to_string(),
+        ];
+
+        let sent = send(&socket, &targets, &Transcript::default()).await;
+        assert_eq!(sent.unwrap(), targets[1]);
+        let mut buf = [0u8; 64];
+        let (len, _) = target.recv_from(&mut buf).await.unwrap();
+        assert_eq!(len, 20); // header only
+        assert!(send(&socket, &[], &Transcript::default()).await.is_err());
+    }
+}
@@ -0,0 +1,393 @@
+//! Link detection and opt-in previews for chat messages.
+//!
+//! URLs are picked out of every message so the UI can render them as links
+//! without interpreting any other markup. Previews are only fetched for
+//! received messages, only when enabled in the config, and by this node
+//! rather than the browser, so opening the UI never contacts third parties
+//! on its own. Hosts that name the local machine or a private network are
+//! refused so a peer cannot use us to probe the LAN. Host names are checked
+//! again once resolved, redirects included, so a public name pointing at
+//! a private address is refused too.
+
+use anyhow::{Result, bail};
+use futures::future::join_all;
+use reqwest::{
+    Client, Url,
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect,
+};
+use serde::Serialize;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use tokio::{net::lookup_host, time::Duration};
+use tracing::debug;
+
+/// URLs looked at per message; the rest are still shown as plain links.
+pub const MAX_URLS_PER_MESSAGE: usize = 3;
+
+/// Maximum time spent fetching one page.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Bytes of a page read before giving up on finding metadata.
+const MAX_BODY_BYTES: usize = 256 * 1024;
+
+/// Redirects followed before a fetch is abandoned.
+const MAX_REDIRECTS: usize = 3;
+
+const MAX_TITLE_CHARS: usize = 200;
+const MAX_DESCRIPTION_CHARS: usize = 300;
+
+/// Metadata shown under a message for one of its links.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LinkPreview {
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+}
+
+/// Returns the http(s) URLs in `text`, in order and without duplicates.
+pub fn find_urls(text: &str) -> Vec<String> {
+    let mut urls: Vec<String> = Vec::new();
+    for word in text.split_whitespace() {
+        let word = word.trim_start_matches(['(', '<', '[', '"', '\'']);
+        if !(word.starts_with("http://") || word.starts_with("https://")) {
+            continue;
+        }
+        // Trailing punctuation usually belongs to the sentence, not the URL
+        let url = word.trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']', '>', '"', '\'']);
+        if Url::parse(url).is_ok() && !urls.iter().any(|u| u == url) {
+            urls.push(url.to_string());
+        }
+    }
+    urls
+}
+
+/// Resolves host names with the system resolver.
+struct SystemResolver;
+
+impl Resolve for SystemResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = lookup_host((name.as_str(), 0)).await?.collect();
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Drops the addresses `is_blocked_ip` refuses from what `R` resolves, and
+/// fails if none are left.
+struct PublicOnly<R>(R);
+
+impl<R: Resolve> Resolve for PublicOnly<R> {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        let resolving = self.0.resolve(name);
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = resolving
+                .await?
+                .filter(|addr| !is_blocked_ip(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} resolves to a local or private address", host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Builds the HTTP client used for previews.
+pub fn client() -> Result<Client> {
+    build_client(SystemResolver)
+}
+
+/// Builds the preview client on top of `resolver`.
+fn build_client<R: Resolve + 'static>(resolver: R) -> Result<Client> {
+    let policy = redirect::Policy::custom(|attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.error("too many redirects")
+        } else if is_blocked(attempt.url()) {
+            attempt.stop()
+        } else {
+            attempt.follow()
+        }
+    });
+
+    // A proxy would resolve names itself, past `PublicOnly`
+    Ok(Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .redirect(policy)
+        .no_proxy()
+        .dns_resolver(Arc::new(PublicOnly(resolver)))
+        .user_agent(concat!("GhostLink/", env!("CARGO_PKG_VERSION")))
+        .build()?)
+}
+
+/// Fetches `url` and extracts its title and description.
+///
+/// # Returns
+///
+/// * `Ok(LinkPreview)` - The page had a title or description.
+/// * `Err` - The host is not allowed, the request failed, or the page is not HTML.
+pub async fn fetch(client: &Client, url: &str) -> Result<LinkPreview> {
+    let parsed = Url::parse(url)?;
+    if is_blocked(&parsed) {
+        bail!("Refusing to fetch a local or private address");
+    }
+
+    let mut response = client.get(parsed).send().await?.error_for_status()?;
+    let is_html = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/html"));
+    if !is_html {
+        bail!("Not an HTML page");
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() >= MAX_BODY_BYTES {
+            body.truncate(MAX_BODY_BYTES);
+            break;
+        }
+    }
+
+    let (title, description) = parse_html(&String::from_utf8_lossy(&body));
+    if title.is_none() && description.is_none() {
+        bail!("No preview metadata");
+    }
+    Ok(LinkPreview {
+        url: url.to_string(),
+        title,
+        description,
+    })
+}
+
+/// Fetches previews for the first few of `urls` concurrently.
+///
+/// # Returns
+///
+/// The previews that could be fetched, in the order of `urls`.
+pub async fn fetch_all(client: &Client, urls: &[String]) -> Vec<LinkPreview> {
+    let fetches = urls
+        .iter()
+        .take(MAX_URLS_PER_MESSAGE)
+        .map(|url| fetch(client, url));
+
+    join_all(fetches)
+        .await
+        .into_iter()
+        .zip(urls)
+        .filter_map(|(result, url)| {
+            result
+                .inspect_err(|e| debug!("No preview for {}: {:#}", url, e))
+                .ok()
+        })
+        .collect()
+}
+
+/// Returns true if `url` points at this machine or a private network.
+fn is_blocked(url: &Url) -> bool {
+    let Some(host) = url.host_str() else {
+        return true;
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.eq_ignore_ascii_case("localhost")
+        || host.ends_with(".localhost")
+        || host.ends_with(".local")
+    {
+        return true;
+    }
+
+    // Names are checked once resolved, by `PublicOnly`
+    host.parse().is_ok_and(is_blocked_ip)
+}
+
+/// Returns true if `ip` is this machine, a private or shared network, or
+/// not a unicast address.
+fn is_blocked_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || a == 0
+                || (a == 100 && (b & 0xc0) == 64) // shared address space, 100.64/10
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || (first & 0xfe00) == 0xfc00 // unique local
+                || (first & 0xffc0) == 0xfe80 // link local
+                || ip.to_ipv4_mapped().is_some()
+        }
+    }
+}
+
+/// Extracts the page title and description from HTML.
+///
+/// Open Graph tags are preferred over `<title>` and `<meta name="description">`.
+fn parse_html(html: &str) -> (Option<String>, Option<String>) {
+    // ASCII lowercasing keeps byte offsets valid in the original
+    let lower = html.to_ascii_lowercase();
+
+    let mut og_title = None;
+    let mut og_description = None;
+    let mut description = None;
+    let mut pos = 0;
+    while let Some(start) = lower[pos..].find("<meta").map(|i| pos + i) {
+        let Some(end) = lower[start..].find('>').map(|i| start + i) else {
+            break;
+        };
+        let tag = &html[start..end];
+        let key = attribute(tag, "property").or_else(|| attribute(tag, "name"));
+        if let (Some(key), Some(content)) = (key, attribute(tag, "content")) {
+            match key.to_ascii_lowercase().as_str() {
+                "og:title" => og_title = og_title.or(Some(content)),
+                "og:description" => og_description = og_description.or(Some(content)),
+                "description" => description = description.or(Some(content)),
+                _ => {}
+            }
+        }
+        pos = end;
+    }
+
+    let title = og_title.or_else(|| {
+        let start = lower.find("<title")?;
+        let start = start + lower[start..].find('>')? + 1;
+        let end = start + lower[start..].find("</title")?;
+        Some(html[start..end].to_string())
+    });
+
+    (
+        title.and_then(|t| clean(&t, MAX_TITLE_CHARS)),
+        og_description
+            .or(description)
+            .and_then(|d| clean(&d, MAX_DESCRIPTION_CHARS)),
+    )
+}
+
+/// Returns the quoted value of `name` in an HTML tag.
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let mut pos = 0;
+    while let Some(i) = lower[pos..].find(name).map(|i| pos + i) {
+        pos = i + name.len();
+        // Must be a whole attribute name followed by =
+        let preceded = lower[..i].ends_with(|c: char| c.is_ascii_whitespace());
+        let rest = lower[pos..].trim_start();
+        if !preceded || !rest.starts_with('=') {
+            continue;
+        }
+        let value_start = tag.len() - rest.len() + 1;
+        let value = tag[value_start..].trim_start();
+        let quote = value.chars().next()?;
+        if quote != '"' && quote != '\'' {
+            return None;
+        }
+        let end = value[1..].find(quote)?;
+        return Some(value[1..1 + end].to_string());
+    }
+    None
+}
+
+/// Decodes common entities, collapses whitespace and truncates to `max_chars`.
+fn clean(text: &str, max_chars: usize) -> Option<String> {
+    let decoded = text
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&");
+    let collapsed = decoded.split_whitespace().collect::<Vec<_>>().join(" ");
+    if collapsed.is_empty() {
+        return None;
+    }
+    Some(collapsed.chars().take(max_chars).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_urls() {
+        let text = "See https://example.com/a?b=1, (http://example.org) and https://example.com/a?b=1. ftp://x.y";
+        assert_eq!(
+            find_urls(text),
+            vec!["https://example.com/a?b=1", "http://example.org"]
+        );
+        assert!(find_urls("no links here").is_empty());
+    }
+
+    #[test]
+    fn test_parse_html_prefers_open_graph() {
+        let html = r#"<html><head>
+            <TITLE>Plain &amp; simple</TITLE>
+            <meta name="description" content="Fallback">
+            <meta property='og:description' content='Rich   description'>
+        </head></html>"#;
+
+        assert_eq!(
+            parse_html(html),
+            (
+                Some("Plain & simple".to_string()),
+                Some("Rich description".to_string())
+            )
+        );
+
+        let html = r#"<meta content="OG title" property="og:title"><title>Ignored</title>"#;
+        assert_eq!(parse_html(html).0.as_deref(), Some("OG title"));
+        assert_eq!(parse_html("<p>nothing</p>"), (None, None));
+    }
+
+    #[test]
+    fn test_blocks_local_and_private_hosts() {
+        let blocked = |url: &str| is_blocked(&Url::parse(url).unwrap());
+
+        assert!(blocked("http://localhost:8080/"));
+        assert!(blocked("http://127.0.0.1/"));
+        assert!(blocked("http://192.168.1.1/admin"));
+        assert!(blocked("http://[::1]/"));
+        assert!(blocked("http://[fe80::1]/"));
+        assert!(blocked("http://printer.local/"));
+        assert!(blocked("http://100.64.0.1/"));
+        assert!(blocked("http://[::ffff:127.0.0.1]/"));
+        assert!(!blocked("https://example.com/"));
+        assert!(!blocked("https://93.184.216.34/"));
+    }
+
+    /// Resolves every name to one address.
+    struct Fixed(IpAddr);
+
+    impl Resolve for Fixed {
+        fn resolve(&self, _name: Name) -> Resolving {
+            let addr = SocketAddr::new(self.0, 0);
+            Box::pin(async move { Ok(Box::new(std::iter::once(addr)) as Addrs) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refuses_names_resolving_to_loopback() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = build_client(Fixed([127, 0, 0, 1].into())).unwrap();
+
+        let url = format!("http://innocent.example:{}/", port);
+        assert!(fetch(&client, &url).await.is_err());
+        // Refused before connecting
+        let accepted = tokio::time::timeout(Duration::from_millis(200), listener.accept()).await;
+        assert!(accepted.is_err());
+    }
+}
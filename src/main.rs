@@ -1,21 +1,76 @@
+mod assist;
+mod audit;
+mod bundle;
+mod captive_portal;
+mod capture;
 mod config;
+mod contacts;
+mod controller;
+mod crash_report;
+mod data_budget;
+mod ddns;
+mod dev;
+mod echo_bot;
+mod event_log;
+mod history_export;
+mod keep_alive;
+mod link_preview;
 mod messaging;
+mod mirror;
+mod nat_cache;
 mod net;
+#[cfg(feature = "netem")]
+mod netem;
+mod observers;
+mod operations;
+mod power_profile;
+mod reachability;
+mod retention;
+mod schedule;
+mod selftest;
+mod share;
+mod stats_history;
+mod storage;
+mod traffic;
+mod transcript;
+mod ui_preferences;
+mod update;
 mod web;
+mod wipe;
+mod wol;
 
 use crate::{
+    audit::SessionLog,
     config::Config,
-    messaging::message_manager::{MessageManager, StreamMessage},
-    web::shared_state::{AppState, Command, Status},
+    contacts::Contacts,
+    controller::{Controller, Startup},
+    crash_report::{ConfigSummary, CrashNotice},
+    data_budget::{BudgetLimits, DataBudget},
+    event_log::{EventLog, EventLogLayer},
+    history_export::HistoryExport,
+    messaging::expiry,
+    nat_cache::NatCache,
+    observers::Observers,
+    power_profile::PowerProfile,
+    schedule::Schedule,
+    stats_history::StatsHistory,
+    storage::unix_timestamp,
+    ui_preferences::UiPreferencesStore,
+    web::{
+        shared_state::{
+            AppState, COMMAND_QUEUE_CAPACITY, Command, HEARTBEAT_INTERVAL, NetworkStatus,
+        },
+        status_message::StatusMessage,
+    },
 };
-use anyhow::Result;
-use std::sync::Arc;
+use anyhow::{Result, anyhow};
+use std::{net::Ipv4Addr, sync::Arc};
 use tokio::{
-    net::UdpSocket,
     sync::{RwLock, broadcast, mpsc},
     time::Duration,
 };
 use tracing::{debug, error, info, warn};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 /// Application entry point.
 ///
@@ -25,27 +80,100 @@ use tracing::{debug, error, info, warn};
 /// 3. Communication channels
 /// 4. Application state
 /// 5. Web server
-/// 6. Network controller (MessageManager)
+/// 6. Network controller (`controller::Controller`)
 #[tokio::main]
 async fn main() -> Result<()> {
-    // 1. Initialize logging
-    tracing_subscriber::fmt::init();
+    // 1. Initialize logging; warnings and errors also go to the event log
+    let event_log = EventLog::default();
+    tracing_subscriber::fmt()
+        .finish()
+        .with(EventLogLayer(event_log.clone()))
+        .init();
     info!("Starting GhostLink v1.1 (Secure)");
 
     // 2. Load configuration
     let config = Config::load();
     debug!("Configuration loaded: {:?}", config);
 
+    // `ghostlink selftest` runs the loopback diagnostic and exits
+    if std::env::args().nth(1).as_deref() == Some("selftest") {
+        let report = selftest::run(config.encryption_mode).await;
+        for stage in &report.stages {
+            println!(
+                "{:<12} {:<6} {:>5} ms  {}",
+                format!("{:?}", stage.stage),
+                if stage.passed { "ok" } else { "FAILED" },
+                stage.elapsed_ms,
+                stage.error.as_deref().unwrap_or("")
+            );
+        }
+        std::process::exit(if report.passed { 0 } else { 1 });
+    }
+    // `ghostlink dev --pair` runs two local nodes behind simulated NATs
+    if std::env::args().nth(1).as_deref() == Some("dev") {
+        let args: Vec<String> = std::env::args().skip(2).collect();
+        return dev::run(config, &args).await;
+    }
+    event_log.open(config.event_log_path());
+    let crash_report = crash_report::load(&config.crash_report_path());
+    if let Some(report) = &crash_report {
+        warn!(
+            "Previous run crashed: {} (report in {})",
+            report.message,
+            config.crash_report_path().display()
+        );
+    }
+    crash_report::install(config.crash_report_path(), &config, event_log.clone());
+
     // 3. Bind UDP socket
-    let socket = UdpSocket::bind(format!("0.0.0.0:{}", config.client_port)).await?;
+    let socket = net::bind_udp(
+        Ipv4Addr::UNSPECIFIED.into(),
+        config.client_port,
+        config.port_retry,
+        config.port_range.clone(),
+        config.port_retry_attempts,
+    )
+    .await?;
+    if let Err(e) = net::apply_socket_options(&socket, config.dscp, config.ttl) {
+        warn!("Failed to apply socket options: {}", e);
+    }
     let socket = Arc::new(socket);
     let local_port = socket.local_addr()?.port();
     info!("Listening on UDP port {}", local_port);
+    let port_warning = (config.client_port != 0 && local_port != config.client_port).then(|| {
+        format!(
+            "Configured UDP port {} was in use; using {} instead",
+            config.client_port, local_port
+        )
+    });
+    if let Some(warning) = &port_warning {
+        warn!("{}", warning);
+    }
 
     // 4. Initialize Shared State
-    let (cmd_tx, mut cmd_rx) = mpsc::channel(32);
+    let (cmd_tx, cmd_rx) = mpsc::channel(COMMAND_QUEUE_CAPACITY);
     let (event_tx, _) = broadcast::channel(32);
     let state = Arc::new(RwLock::new(AppState::new(cmd_tx.clone(), event_tx)));
+    {
+        let mut guard = state.write().await;
+        guard.event_log = event_log;
+        guard.crash_report = crash_report.as_ref().map(CrashNotice::from);
+        guard.crash_report_path = Some(config.crash_report_path());
+        open_stores(&mut guard, &config);
+        guard.bound_port = Some(local_port);
+        guard.port_warning = port_warning;
+    }
+    let transcript = state.read().await.transcript.clone();
+    transcript.set_enabled(config.debug_transcript);
+    transcript.set_capture_dir(config.captures_dir());
+
+    // `--echo-bot` starts the built-in peer for trying GhostLink on one machine
+    if std::env::args().skip(1).any(|arg| arg == "--echo-bot") {
+        match echo_bot::EchoBot::start().await {
+            Ok(bot) => state.write().await.echo_bot = Some(bot),
+            Err(e) => warn!("Failed to start the echo bot: {}", e),
+        }
+    }
 
     // Resolve Initial Local IP
     if let Ok(local_addr) = net::get_local_ip(local_port).await {
@@ -53,40 +181,63 @@ async fn main() -> Result<()> {
         info!("Local IP resolved: {}", local_addr);
     }
 
-    // Resolve Public IP & Detect NAT Type
-    info!("Resolving Public IP and NAT Type...");
-    match net::resolve_public_ip(&socket, &config.stun_server).await {
-        Ok(public_addr) => {
-            info!("Public IP resolved via STUN: {}", public_addr);
-
-            state
-                .write()
-                .await
-                .set_public_ip(public_addr, Some("Public IP resolved".into()), None);
-
-            let nat_type = net::get_nat_type(&socket, &config.stun_verifier, public_addr).await;
-
-            state
-                .write()
-                .await
-                .set_nat_type(nat_type, Some("NAT type detected".into()), None);
-
-            info!("NAT type: {:?}", nat_type);
+    // 5. Start Web Server (Background Task)
+    // Started before STUN so the UI is reachable while NAT detection runs.
+    // Bound here so a taken port fails startup instead of leaving the node headless
+    if !config.web_tcp && config.api_socket.is_none() {
+        return Err(anyhow!("web_tcp is off and no api_socket is configured"));
+    }
+    let base_path = web::forwarded::normalize_base_path(&config.web_base_path)?;
+    {
+        let mut guard = state.write().await;
+        guard.trusted_proxies = config.trusted_proxies.clone();
+        guard.allow_loopback_peers = config.allow_loopback_peers;
+    }
+    if let Some(socket) = &config.api_socket {
+        #[cfg(unix)]
+        {
+            let listener = net::bind_unix(socket)?;
+            info!("Web UI available on {}{}/", socket, base_path);
+            let web_state = state.clone();
+            let base_path = base_path.clone();
+            tokio::spawn(async move {
+                if let Err(e) = web::start_web_server(web_state, listener, &base_path).await {
+                    error!("Web server crashed: {}", e);
+                }
+            });
         }
-        Err(e) => {
-            error!("STUN resolution failed: {:?}", e);
-            warn!("Cannot accept incoming connections without public IP");
+        #[cfg(not(unix))]
+        return Err(anyhow!("{} needs a Unix-like system", socket));
+    }
+    if config.web_tcp {
+        let listener = net::bind_tcp(
+            Ipv4Addr::UNSPECIFIED.into(),
+            config.web_port,
+            config.web_port_fallback.clone(),
+        )
+        .await?;
+        let web_port = listener.local_addr()?.port();
+        let web_url = format!("http://localhost:{}{}/", web_port, base_path);
+        if web_port != config.web_port {
+            warn!(
+                "Configured web port {} was in use; the web UI is at {} instead",
+                config.web_port, web_url
+            );
         }
-    };
-
-    // 5. Start Web Server (Background Task)
-    let web_state = state.clone();
-    let web_port = config.web_port;
-    tokio::spawn(async move {
-        if let Err(e) = web::start_web_server(web_state, web_port).await {
-            error!("Web server crashed: {}", e);
+        info!("Web UI available at {}", web_url);
+        state.write().await.web_url = Some(web_url.clone());
+        let web_state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = web::start_web_server(web_state, listener, &base_path).await {
+                error!("Web server crashed: {}", e);
+            }
+        });
+        if config.open_browser
+            && let Err(e) = web::open_browser(&web_url)
+        {
+            warn!("Failed to open the web UI in a browser: {}", e);
         }
-    });
+    }
 
     // 6. Spawn signal handler for graceful shutdown
     let cmd_tx_clone = cmd_tx.clone();
@@ -107,125 +258,195 @@ async fn main() -> Result<()> {
         }
     });
 
-    // 7. Initialize Message Manager
-    let mut manager = MessageManager::new(socket.clone(), state.clone());
-
-    // 8. Setup NAT Keep-Alive
-    let mut keep_alive_interval =
-        tokio::time::interval(Duration::from_secs(config.punch_hole_secs));
-    keep_alive_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
-
-    let mut receive_buf = [0u8; 4096];
-
-    info!("System Ready. Press Ctrl+C to exit.");
-
-    // 9. Main Event Loop
-    loop {
-        tokio::select! {
-            // A. Handle Commands from Web UI
-            Some(cmd) = cmd_rx.recv() => {
-                match cmd {
-                    Command::ConnectPeer => {
-                        let target_peer = {
-                            state.read().await.peer_ip
-                        };
-
-                        if let Some(peer_addr) = target_peer {
-                            state.write().await.set_status(
-                                Status::Punching,
-                                Some(format!("Initiating handshake with {}...", peer_addr)),
-                                Some(config.handshake_timeout_secs),
-                            );
-
-                            if let Err(e) = manager.handshake(
-                                peer_addr,
-                                config.handshake_timeout_secs,
-                                config.encryption_mode
-                            ).await {
-                                error!("Handshake failed: {}", e);
-                            } else if let Err(e) = manager.upgrade_to_kcp().await {
-                                error!("Failed to upgrade to KCP: {}", e);
-                                state.write().await.set_status(
-                                    Status::Disconnected,
-                                    Some(format!("KCP Upgrade failed: {}", e)),
-                                    None
-                                );
-                            } else {
-                                state.write().await.set_status(
-                                    Status::Connected,
-                                    Some("Connected securely via KCP".into()),
-                                    None
-                                );
-                            }
-                        } else {
-                            warn!("ConnectPeer command received without peer IP set");
-                        }
-                    }
-                    Command::SendMessage(text) => {
-                        if manager.is_connected() {
-                            if let Err(e) = manager.send_text(text.clone()).await {
-                                error!("Failed to send message: {}", e);
-                            } else {
-                                state.read().await.add_message(text, true);
-                            }
-                        } else {
-                            warn!("Cannot send message: not connected");
-                        }
-                    }
-                    Command::Disconnect => {
-                        if let Err(e) = manager.disconnect().await {
-                            error!("Error during disconnect: {}", e);
-                        }
-                    }
+    // Prune session history the retention policy no longer keeps, and export
+    // what is left if enabled, at startup and then hourly. Disappearing
+    // messages are deleted on a much shorter tick, connected or not.
+    let janitor_state = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(retention::JANITOR_INTERVAL);
+        let mut expiry_interval = tokio::time::interval(expiry::EXPIRY_CHECK_INTERVAL);
+        expiry_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            tokio::select! {
+                _ = expiry_interval.tick() => {
+                    janitor_state.write().await.expire_messages(unix_timestamp());
+                    continue;
                 }
+                _ = interval.tick() => {}
             }
-
-            // B. Handle Incoming Messages (KCP)
-            result = manager.receive_message(&mut receive_buf), if manager.is_connected() => {
-                match result {
-                    Ok(n) => {
-                         match bincode::deserialize::<StreamMessage>(&receive_buf[..n]) {
-                            Ok(msg) => {
-                                match msg {
-                                    StreamMessage::Text(content) => {
-                                        debug!("Received message: {} bytes", content.len());
-                                        state.read().await.add_message(content, false);
-                                    }
-                                    StreamMessage::Bye => {
-                                        info!("Peer requested disconnect");
-                                        let _ = manager.disconnect_on_bye_received().await;
-                                    }
-                                }
-                            }
-                            Err(e) => warn!("Failed to deserialize packet: {}", e),
-                         }
-                    }
-                    Err(e) => {
-                        error!("KCP receive error: {}", e);
-                    }
-                }
+            let now = unix_timestamp();
+            let mut guard = janitor_state.write().await;
+            match guard.session_log.prune(now) {
+                Ok(0) => {}
+                Ok(pruned) => info!("Retention: deleted {} expired session records", pruned),
+                Err(e) => warn!("Retention: failed to prune session history: {:#}", e),
             }
+            guard.prune_link_stats();
+            match guard.export_history(now) {
+                Ok(Some(path)) => info!("Exported session history to {}", path.display()),
+                Ok(None) => {}
+                Err(e) => warn!("Failed to export session history: {:#}", e),
+            }
+        }
+    });
+
+    // Lets SSE clients notice missed events and fetch the state again
+    let heartbeat_state = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            interval.tick().await;
+            heartbeat_state.write().await.send_heartbeat();
+        }
+    });
+
+    // Tells a captive portal from blocked UDP when STUN times out
+    let portal_client = config.captive_portal_probe.as_ref().and_then(|_| {
+        captive_portal::client()
+            .inspect_err(|e| warn!("Captive portal detection disabled: {}", e))
+            .ok()
+    });
+
+    // Keeps a DDNS hostname pointed at the public IP STUN reports
+    let ddns = config.ddns.clone().and_then(|settings| {
+        ddns::spawn(settings)
+            .inspect_err(|e| warn!("DDNS updates disabled: {}", e))
+            .ok()
+    });
 
-            // C. Handle NAT Keep-Alive
-            _ = keep_alive_interval.tick() => {
-                let status = state.read().await.status;
-
-                if status == Status::Disconnected {
-                    debug!("Sending NAT keep-alive to STUN server");
-                    match net::resolve_public_ip(&socket, &config.stun_server).await {
-                        Ok(addr) => {
-                            let mut guard = state.write().await;
-                            if guard.public_ip != Some(addr) {
-                                info!("Public IP changed from {:?} to {}", guard.public_ip, addr);
-                                guard.set_public_ip(addr, Some("Public IP updated".into()), None);
+    // Looks for newer releases, if the user opted in
+    if let Some(settings) = &config.update_check {
+        match update::UpdateChecker::new(settings) {
+            Ok(checker) => {
+                let update_state = state.clone();
+                let every = Duration::from_secs(settings.interval_secs.max(60));
+                tokio::spawn(async move {
+                    let mut interval = tokio::time::interval(every);
+                    loop {
+                        interval.tick().await;
+                        match checker.check().await {
+                            Ok(Some(release)) => {
+                                info!("Update check: version {} is available", release.version);
+                                update_state.write().await.set_available_update(release);
                             }
-                        }
-                        Err(e) => {
-                            debug!("Keep-alive STUN check failed: {}", e);
+                            Ok(None) => debug!("Update check: up to date"),
+                            Err(e) => warn!("Update check failed: {:#}", e),
                         }
                     }
-                }
+                });
             }
+            Err(e) => warn!("Update check disabled: {:#}", e),
         }
     }
+
+    // Resolve Public IP & Detect NAT Type
+    // Without a network the node starts offline and finishes this once a
+    // keep-alive gets through; the web UI works meanwhile
+    let mut needs_detection = false;
+    let nat_cache_path = (config.nat_cache_ttl_secs > 0).then(|| config.nat_cache_path());
+    let nat_cache = nat_cache_path.as_deref().and_then(NatCache::load);
+
+    // Cached results from a recent run stand in until the first keep-alive confirms them
+    let unconfirmed_cache = nat_cache
+        .clone()
+        .filter(|cached| cached.is_usable(local_port, unix_timestamp()));
+
+    if let Some(cached) = &unconfirmed_cache {
+        info!(
+            "Using cached public IP {} and NAT type {:?}",
+            cached.public_ip, cached.nat_type
+        );
+        let mut guard = state.write().await;
+        guard.set_public_ip(cached.public_ip, Some(StatusMessage::PublicIpCached), None);
+        guard.set_nat_type(cached.nat_type, Some(StatusMessage::NatTypeCached), None);
+    } else {
+        // Both servers are queried in parallel
+        info!("Resolving Public IP and NAT Type...");
+        let detection = net::detect_nat(
+            &socket,
+            &config.stun_server,
+            &config.stun_verifier,
+            &transcript,
+        )
+        .await;
+        state.write().await.set_stun_probes(detection.probes);
+        match detection.public_addr {
+            Ok(public_addr) => {
+                info!("Public IP resolved via STUN: {}", public_addr);
+
+                let mut guard = state.write().await;
+                guard.set_network_error(None);
+                guard.set_public_ip(public_addr, Some(StatusMessage::PublicIpResolved), None);
+                ddns::publish(ddns.as_ref(), public_addr);
+                guard.set_nat_type(
+                    detection.nat_type,
+                    Some(StatusMessage::NatTypeDetected),
+                    None,
+                );
+                drop(guard);
+
+                info!("NAT type: {:?}", detection.nat_type);
+            }
+            Err(e) => {
+                let e = match (&portal_client, &config.captive_portal_probe) {
+                    (Some(client), Some(probe)) => captive_portal::classify(client, probe, e).await,
+                    _ => e,
+                };
+                error!("STUN resolution failed: {}", e);
+                warn!("Starting offline; cannot accept incoming connections without public IP");
+                needs_detection = true;
+                let mut guard = state.write().await;
+                guard.set_network_error(Some(&e));
+                guard.set_network_status(NetworkStatus::Offline);
+            }
+        };
+    }
+
+    // 7. Run the network controller
+    Controller {
+        config,
+        state,
+        socket,
+        commands: (cmd_tx, cmd_rx),
+        ddns,
+        portal_client,
+        startup: Startup {
+            needs_detection,
+            nat_cache,
+            unconfirmed_cache,
+        },
+    }
+    .run()
+    .await
+}
+
+/// Loads the stores kept in the data directory, and what the config grants
+/// the peer, into `state`.
+fn open_stores(state: &mut AppState, config: &Config) {
+    state.config_summary = Some(ConfigSummary::from(config));
+    state.session_log = SessionLog::open(config.sessions_path());
+    state.session_log.set_policy(config.retention.clone());
+    state.stats_history = StatsHistory::open(config.stats_history_path());
+    state.contacts = Contacts::open(config.contacts_path());
+    state.observers = Observers::open(config.observers_path());
+    state.ui_preferences = UiPreferencesStore::open(config.ui_preferences_path());
+    state.scheduled = Schedule::open(config.scheduled_path());
+    state.data_budget = DataBudget::open(
+        config.data_usage_path(),
+        BudgetLimits {
+            session_cap: config.session_data_cap_bytes,
+            monthly_cap: config.monthly_data_cap_bytes,
+            warn_percents: config.data_warn_percents.clone(),
+            hard_stop: config.data_cap_hard_stop,
+        },
+    );
+    state.assist_grants = config
+        .assist_grants
+        .iter()
+        .map(|g| g.name.clone())
+        .collect();
+    state.shares = config.shares.iter().map(|s| s.name.clone()).collect();
+    state.history_export = HistoryExport::new(&config.history_export, config.exports_dir());
+    state.power_profile =
+        PowerProfile::new(config.power_hints, config.monthly_data_cap_bytes.is_some());
 }
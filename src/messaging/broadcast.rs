@@ -0,0 +1,69 @@
+//! Announcements sent to every active session at once.
+//!
+//! The controller fans the text out to each session and reports, per peer,
+//! whether it was handed to the encrypted stream.
+
+use serde::Serialize;
+use std::net::SocketAddr;
+use tokio::sync::oneshot;
+
+/// Outcome of sending a broadcast to one peer.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Delivery {
+    pub peer: SocketAddr,
+    pub peer_label: Option<String>,
+    pub delivered: bool,
+    /// Why the send failed, if it did.
+    pub error: Option<String>,
+}
+
+/// Aggregated result of a broadcast.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BroadcastReport {
+    pub delivered: usize,
+    pub failed: usize,
+    pub deliveries: Vec<Delivery>,
+}
+
+impl BroadcastReport {
+    /// Summarises per-peer results.
+    pub fn new(deliveries: Vec<Delivery>) -> Self {
+        let delivered = deliveries.iter().filter(|d| d.delivered).count();
+        Self {
+            delivered,
+            failed: deliveries.len() - delivered,
+            deliveries,
+        }
+    }
+}
+
+/// Reply channel for a broadcast.
+pub type BroadcastReply = oneshot::Sender<BroadcastReport>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_counts_failures() {
+        let peer: SocketAddr = "203.0.113.7:41234".parse().unwrap();
+        let report = BroadcastReport::new(vec![
+            Delivery {
+                peer,
+                peer_label: Some("Bob".into()),
+                delivered: true,
+                error: None,
+            },
+            Delivery {
+                peer,
+                peer_label: None,
+                delivered: false,
+                error: Some("stream closed".into()),
+            },
+        ]);
+
+        assert_eq!(report.delivered, 1);
+        assert_eq!(report.failed, 1);
+        assert_eq!(BroadcastReport::new(Vec::new()).failed, 0);
+    }
+}
@@ -0,0 +1,128 @@
+//! Chat commands, run by the controller.
+//!
+//! A message starting with `/` is a command for this node rather than text
+//! for the peer, so every UI gets the same commands by sending messages.
+//! Results come back as `command_result` system notices. Text that should start
+//! with a slash is sent by doubling it: `//shrug` sends `/shrug`.
+
+use super::ping::{DEFAULT_PING_COUNT, MAX_PING_COUNT, PingStats};
+use crate::contacts::validate_label;
+
+/// A command typed into the chat.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatCommand {
+    /// `/ping [count]`: measures the round-trip time to the peer.
+    Ping { count: u32 },
+    /// `/stats`: shows connection and data usage figures.
+    Stats,
+    /// `/disconnect`: ends the session.
+    Disconnect,
+    /// `/nick <label>`: names the peer and saves it as a contact.
+    Nick(String),
+    /// `/clear`: clears the chat window.
+    Clear,
+}
+
+/// What a typed message turned out to be.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatInput {
+    /// Text to send to the peer.
+    Text(String),
+    Command(ChatCommand),
+    /// A command that could not be parsed, with the reason.
+    Invalid(String),
+}
+
+/// Tells commands from text to send.
+pub fn parse(message: &str) -> ChatInput {
+    let Some(command) = message.strip_prefix('/') else {
+        return ChatInput::Text(message.to_string());
+    };
+    if command.starts_with('/') {
+        return ChatInput::Text(command.to_string());
+    }
+
+    let (name, args) = command
+        .trim()
+        .split_once(char::is_whitespace)
+        .map_or((command.trim(), ""), |(name, args)| (name, args.trim()));
+    let command = match (name, args) {
+        ("ping", "") => ChatCommand::Ping {
+            count: DEFAULT_PING_COUNT,
+        },
+        ("ping", count) => match count.parse() {
+            Ok(count) if (1..=MAX_PING_COUNT).contains(&count) => ChatCommand::Ping { count },
+            _ => {
+                return ChatInput::Invalid(format!(
+                    "Usage: /ping [count], count between 1 and {}",
+                    MAX_PING_COUNT
+                ));
+            }
+        },
+        ("stats", "") => ChatCommand::Stats,
+        ("disconnect", "") => ChatCommand::Disconnect,
+        ("clear", "") => ChatCommand::Clear,
+        ("nick", "") => return ChatInput::Invalid("Usage: /nick <label>".into()),
+        ("nick", label) => match validate_label(label) {
+            Ok(label) => ChatCommand::Nick(label),
+            Err(e) => return ChatInput::Invalid(e),
+        },
+        ("stats" | "disconnect" | "clear", _) => {
+            return ChatInput::Invalid(format!("/{} takes no arguments", name));
+        }
+        _ => {
+            return ChatInput::Invalid(format!(
+                "Unknown command /{}; send //{} to send it as text",
+                name, name
+            ));
+        }
+    };
+    ChatInput::Command(command)
+}
+
+/// Describes a ping run's results in one line.
+pub fn describe_ping(stats: &PingStats) -> String {
+    if stats.received == 0 {
+        return format!("Ping: no replies to {} pings", stats.sent);
+    }
+    format!(
+        "Ping: {}/{} replies, RTT min {:.1} / avg {:.1} / max {:.1} ms, jitter {:.1} ms",
+        stats.received, stats.sent, stats.min_ms, stats.avg_ms, stats.max_ms, stats.jitter_ms
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parses_commands_and_escapes() {
+        assert_eq!(parse("hello"), ChatInput::Text("hello".into()));
+        assert_eq!(parse("//shrug"), ChatInput::Text("/shrug".into()));
+        assert_eq!(
+            parse("/ping"),
+            ChatInput::Command(ChatCommand::Ping {
+                count: DEFAULT_PING_COUNT
+            })
+        );
+        assert_eq!(
+            parse("/ping 3"),
+            ChatInput::Command(ChatCommand::Ping { count: 3 })
+        );
+        assert!(matches!(parse("/ping 0"), ChatInput::Invalid(_)));
+        assert_eq!(parse("/stats "), ChatInput::Command(ChatCommand::Stats));
+        assert_eq!(
+            parse("/nick  Bob Smith "),
+            ChatInput::Command(ChatCommand::Nick("Bob Smith".into()))
+        );
+        assert!(matches!(parse("/nick"), ChatInput::Invalid(_)));
+        assert!(matches!(parse("/clear all"), ChatInput::Invalid(_)));
+        match parse("/shrug") {
+            ChatInput::Invalid(e) => assert!(e.contains("//shrug")),
+            other => panic!("expected an error, got {:?}", other),
+        }
+
+        let stats = PingStats::from_samples(2, &[]);
+        assert_eq!(describe_ping(&stats), "Ping: no replies to 2 pings");
+    }
+}
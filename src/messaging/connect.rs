@@ -0,0 +1,22 @@
+//! Outcome of a connection attempt, for callers that wait for one.
+//!
+//! The UI follows connection progress through events; scripts calling the
+//! API can instead ask the controller to answer once the handshake and KCP
+//! upgrade have finished.
+
+use serde::Serialize;
+use std::net::SocketAddr;
+use tokio::sync::oneshot;
+
+/// An established session, as reported to the caller that requested it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConnectOutcome {
+    pub peer: SocketAddr,
+    pub peer_label: Option<String>,
+    /// SAS fingerprint for manual verification.
+    pub fingerprint: Option<String>,
+    pub encryption_algo: Option<String>,
+}
+
+/// Reply channel for a connection attempt; carries the failure reason on error.
+pub type ConnectReply = oneshot::Sender<Result<ConnectOutcome, String>>;
@@ -0,0 +1,94 @@
+//! Source validation for unsolicited handshakes.
+//!
+//! A SYN arriving while we listen is answered with a `Retry` carrying a
+//! cookie before anything else happens. The cookie is a MAC over the
+//! sender's address and the current time window, so checking it needs no
+//! per-sender state. Only a SYN echoing a valid cookie can open a prompt: a
+//! spoofed source never sees the cookie, and the `Retry` is smaller than the
+//! SYN that caused it, so GhostLink can't be used to amplify traffic.
+
+use hkdf::hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use std::net::SocketAddr;
+
+/// Bytes of MAC carried in a cookie.
+pub const COOKIE_LEN: usize = 16;
+
+/// A cookie as sent in `Retry` and echoed in `Syn`.
+pub type Cookie = [u8; COOKIE_LEN];
+
+/// Length of one time window in seconds.
+///
+/// Cookies from the current and the previous window are accepted, so a
+/// cookie stays valid for between one and two windows.
+const WINDOW_SECS: u64 = 30;
+
+/// Issues and checks cookies with a secret generated at startup.
+#[derive(Clone)]
+pub struct CookieIssuer {
+    secret: [u8; 32],
+}
+
+impl CookieIssuer {
+    /// Creates an issuer with a fresh random secret.
+    pub fn new() -> Self {
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        Self { secret }
+    }
+
+    /// Returns the cookie for `addr` at unix time `now`.
+    pub fn issue(&self, addr: SocketAddr, now: u64) -> Cookie {
+        let mac = self.mac(addr, now / WINDOW_SECS).finalize().into_bytes();
+        let mut cookie = [0u8; COOKIE_LEN];
+        cookie.copy_from_slice(&mac[..COOKIE_LEN]);
+        cookie
+    }
+
+    /// Checks a cookie echoed by `addr` at unix time `now`.
+    ///
+    /// # Returns
+    ///
+    /// True if it was issued to `addr` in the current or previous window.
+    pub fn verify(&self, addr: SocketAddr, cookie: &Cookie, now: u64) -> bool {
+        let window = now / WINDOW_SECS;
+        [window, window.saturating_sub(1)]
+            .into_iter()
+            .any(|w| self.mac(addr, w).verify_truncated_left(cookie).is_ok())
+    }
+
+    fn mac(&self, addr: SocketAddr, window: u64) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(addr.to_string().as_bytes());
+        mac.update(&window.to_be_bytes());
+        mac
+    }
+}
+
+impl Default for CookieIssuer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cookie_is_bound_to_address_and_window() {
+        let issuer = CookieIssuer::new();
+        let addr = SocketAddr::from(([203, 0, 113, 7], 4000));
+        let other = SocketAddr::from(([203, 0, 113, 7], 4001));
+        let now = 1_700_000_000;
+        let cookie = issuer.issue(addr, now);
+
+        assert!(issuer.verify(addr, &cookie, now));
+        assert!(issuer.verify(addr, &cookie, now + WINDOW_SECS));
+        assert!(!issuer.verify(addr, &cookie, now + 2 * WINDOW_SECS));
+        assert!(!issuer.verify(other, &cookie, now));
+        assert!(!CookieIssuer::new().verify(addr, &cookie, now));
+    }
+}
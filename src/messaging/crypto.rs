@@ -11,6 +11,7 @@ use aes_gcm::{
 use anyhow::Result;
 use chacha20poly1305::{ChaCha20Poly1305, Nonce as ChaChaNonce};
 use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
 use rand_core::OsRng;
 use sha2::Sha256;
 use std::fmt;
@@ -119,6 +120,13 @@ impl CipherAlgo {
 pub struct SessionData {
     pub cipher: CipherAlgo,
     pub fingerprint: String,
+    /// True if our public key sorts before the peer's. Both sides agree on
+    /// it, so it breaks ties between them.
+    pub lower_key: bool,
+    /// Value both ends derive from the shared secret. A man in the middle
+    /// holds a different one with each side, so a proof bound to it cannot
+    /// be relayed.
+    pub binding: [u8; 32],
 }
 
 /// Derives session keys and authentication data from a secure key exchange.
@@ -151,6 +159,9 @@ pub fn derive_session(
     let mut key_material = [0u8; 32];
     hkdf.expand(b"ghostlink_v1_session", &mut key_material)
         .map_err(|_| anyhow::anyhow!("HKDF expansion failed"))?;
+    let mut binding = [0u8; 32];
+    hkdf.expand(b"ghostlink_v1_binding", &mut binding)
+        .map_err(|_| anyhow::anyhow!("HKDF expansion failed"))?;
 
     let cipher = match mode {
         EncryptionMode::ChaCha20Poly1305 => {
@@ -176,9 +187,58 @@ pub fn derive_session(
     Ok(SessionData {
         cipher,
         fingerprint,
+        lower_key: my_public_bytes < peer_public_bytes,
+        binding,
     })
 }
 
+/// Computes the proof that the sender knows the own-node secret.
+///
+/// # Arguments
+///
+/// * `secret` - The own-node secret from the config.
+/// * `binding` - The session's `SessionData::binding`.
+/// * `lower_key` - The sender's `SessionData::lower_key`, so that a peer
+///   cannot reflect our own proof back at us.
+///
+/// # Returns
+///
+/// An HMAC-SHA256 tag over the binding and the sender's role.
+pub fn own_node_proof(secret: &str, binding: &[u8; 32], lower_key: bool) -> [u8; 32] {
+    own_node_mac(secret, binding, lower_key)
+        .finalize()
+        .into_bytes()
+        .into()
+}
+
+/// Checks a peer's own-node proof in constant time.
+///
+/// # Arguments
+///
+/// * `secret` - The own-node secret from the config.
+/// * `binding` - The session's `SessionData::binding`.
+/// * `peer_lower_key` - The peer's role, i.e. the negation of ours.
+/// * `proof` - The proof the peer sent.
+pub fn verify_own_node_proof(
+    secret: &str,
+    binding: &[u8; 32],
+    peer_lower_key: bool,
+    proof: &[u8; 32],
+) -> bool {
+    own_node_mac(secret, binding, peer_lower_key)
+        .verify_slice(proof)
+        .is_ok()
+}
+
+fn own_node_mac(secret: &str, binding: &[u8; 32], lower_key: bool) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(b"ghostlink_own_node");
+    mac.update(binding);
+    mac.update(&[lower_key as u8]);
+    mac
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -223,6 +283,19 @@ mod tests {
         .unwrap();
 
         assert_eq!(alice_session.fingerprint, bob_session.fingerprint);
+        assert_ne!(alice_session.lower_key, bob_session.lower_key);
+        assert_eq!(alice_session.binding, bob_session.binding);
+    }
+
+    #[test]
+    fn test_own_node_proof() {
+        let binding = [7u8; 32];
+        let proof = own_node_proof("hunter2", &binding, true);
+        assert!(verify_own_node_proof("hunter2", &binding, true, &proof));
+        // Wrong secret, another session, or our own proof reflected back
+        assert!(!verify_own_node_proof("hunter3", &binding, true, &proof));
+        assert!(!verify_own_node_proof("hunter2", &[8u8; 32], true, &proof));
+        assert!(!verify_own_node_proof("hunter2", &binding, false, &proof));
     }
 
     #[test]
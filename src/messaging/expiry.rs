@@ -0,0 +1,102 @@
+//! Disappearing messages.
+//!
+//! Either side of a conversation may set a message TTL; the setting is sent
+//! to the peer, which applies it too, so both agree on it. While it is set,
+//! texts go out as `ExpiringText` carrying the TTL, and each side deletes
+//! them from its history once the TTL has passed since it sent or received
+//! them. Deletion is driven by the node, not the UI: the janitor task in
+//! `main` checks every `EXPIRY_CHECK_INTERVAL`, also while disconnected, then
+//! the message's reactions are dropped and open UIs are told to remove it.
+
+use super::reactions::MessageId;
+use tokio::time::Duration;
+
+/// Longest TTL accepted, in seconds (one week).
+pub const MAX_TTL_SECS: u32 = 7 * 24 * 60 * 60;
+
+/// How often the janitor deletes messages whose TTL has passed.
+pub const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Checks a TTL set locally or received from the peer.
+///
+/// # Returns
+///
+/// * `Ok(u32)` - The TTL in seconds.
+/// * `Err(String)` - Zero or longer than `MAX_TTL_SECS`.
+pub fn validate_ttl(ttl_secs: u32) -> Result<u32, String> {
+    if ttl_secs == 0 || ttl_secs > MAX_TTL_SECS {
+        return Err(format!(
+            "Message TTL must be between 1 and {} seconds",
+            MAX_TTL_SECS
+        ));
+    }
+    Ok(ttl_secs)
+}
+
+/// Messages that will expire, in any conversation since the node started.
+///
+/// A conversation ending does not cancel them, as its messages stay on
+/// screen until they expire.
+#[derive(Debug, Clone, Default)]
+pub struct Expiring {
+    /// Conversation, message and unix time (seconds) it expires at.
+    entries: Vec<(Option<String>, MessageId, u64)>,
+}
+
+impl Expiring {
+    /// Schedules `message_id` of `conversation_id` for deletion at unix
+    /// time `expires_at`.
+    pub fn track(
+        &mut self,
+        conversation_id: Option<String>,
+        message_id: MessageId,
+        expires_at: u64,
+    ) {
+        self.entries.push((conversation_id, message_id, expires_at));
+    }
+
+    /// Removes and returns the messages expired at unix time `now`, with
+    /// their conversations.
+    pub fn take_expired(&mut self, now: u64) -> Vec<(Option<String>, MessageId)> {
+        let mut expired = Vec::new();
+        self.entries
+            .retain(|(conversation_id, message_id, expires_at)| {
+                let keep = *expires_at > now;
+                if !keep {
+                    expired.push((conversation_id.clone(), *message_id));
+                }
+                keep
+            });
+        expired
+    }
+
+    /// Forgets every tracked message.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expires_in_time_order() {
+        let id = |seq| MessageId {
+            from_me: false,
+            seq,
+        };
+        let mut expiring = Expiring::default();
+        expiring.track(Some("a".into()), id(0), 110);
+        expiring.track(None, id(1), 100);
+
+        assert!(expiring.take_expired(99).is_empty());
+        assert_eq!(expiring.take_expired(105), [(None, id(1))]);
+        assert_eq!(expiring.take_expired(200), [(Some("a".into()), id(0))]);
+        assert!(expiring.take_expired(300).is_empty());
+
+        assert!(validate_ttl(0).is_err());
+        assert!(validate_ttl(MAX_TTL_SECS + 1).is_err());
+        assert_eq!(validate_ttl(30), Ok(30));
+    }
+}
@@ -1,8 +1,13 @@
 use super::{
     super::{
         config::EncryptionMode,
-        web::shared_state::{SharedState, Status},
+        transcript::{Direction, Protocol, Transcript},
+        web::{
+            shared_state::{PunchPhase, PunchProgress, SharedState, Status},
+            status_message::StatusMessage,
+        },
     },
+    cookie::{COOKIE_LEN, Cookie},
     crypto::{KeyPair, SessionData, derive_session},
 };
 use anyhow::{Context, Result, bail};
@@ -14,24 +19,291 @@ use tokio::{
 };
 use tracing::{debug, warn};
 
+/// Optional protocol features a peer supports, and services it offers.
+///
+/// Exchanged in SYN and SYN-ACK as one byte of flags. Padding is used only
+/// when both sides advertise it; a service flag tells the other side that
+/// asking for the service can succeed. A node that only knows padding sends
+/// 0 or 1, which reads the same. Flags from newer versions are ignored, so
+/// a newer peer can still connect.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(from = "u8", into = "u8")]
+pub struct Capabilities {
+    /// Pads encrypted frames to randomised bucket sizes (see `padding`).
+    pub padding: bool,
+    /// Shares folders (see `share`).
+    pub shares: bool,
+    /// Grants commands in assist mode (see `assist`).
+    pub assist: bool,
+    /// Accepts remote wipe requests (see `wipe`).
+    pub remote_wipe: bool,
+    /// Relays Wake-on-LAN packets (see `wol`).
+    pub wake_relay: bool,
+}
+
+/// One flag of `Capabilities`.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    Padding,
+    Shares,
+    Assist,
+    RemoteWipe,
+    WakeRelay,
+}
+
+impl Feature {
+    const ALL: [Feature; 5] = [
+        Feature::Padding,
+        Feature::Shares,
+        Feature::Assist,
+        Feature::RemoteWipe,
+        Feature::WakeRelay,
+    ];
+
+    /// Bit of the feature in the encoded flags.
+    fn bit(self) -> u8 {
+        1 << Self::ALL.iter().position(|&f| f == self).unwrap_or(0)
+    }
+}
+
+impl std::fmt::Display for Feature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Feature::Padding => "traffic padding",
+            Feature::Shares => "shared folders",
+            Feature::Assist => "assist mode",
+            Feature::RemoteWipe => "remote wipe",
+            Feature::WakeRelay => "wake relay",
+        })
+    }
+}
+
+impl Capabilities {
+    /// Returns the features supported by both sides.
+    pub fn intersect(self, other: Capabilities) -> Capabilities {
+        Capabilities {
+            padding: self.padding && other.padding,
+            shares: self.shares && other.shares,
+            assist: self.assist && other.assist,
+            remote_wipe: self.remote_wipe && other.remote_wipe,
+            wake_relay: self.wake_relay && other.wake_relay,
+        }
+    }
+
+    /// Returns true if `feature` is advertised.
+    pub fn has(self, feature: Feature) -> bool {
+        u8::from(self) & feature.bit() != 0
+    }
+
+    /// Advertised features, in declaration order.
+    pub fn features(self) -> Vec<Feature> {
+        Feature::ALL.into_iter().filter(|&f| self.has(f)).collect()
+    }
+
+    /// Features a peer advertising `self` lacks, as seen from a node
+    /// offering `local`: padding if we asked for it, and every service.
+    pub fn missing(self, local: Capabilities) -> Vec<Feature> {
+        Feature::ALL
+            .into_iter()
+            .filter(|&f| !self.has(f) && (f != Feature::Padding || local.padding))
+            .collect()
+    }
+}
+
+impl From<u8> for Capabilities {
+    fn from(bits: u8) -> Self {
+        let has = |feature: Feature| bits & feature.bit() != 0;
+        Capabilities {
+            padding: has(Feature::Padding),
+            shares: has(Feature::Shares),
+            assist: has(Feature::Assist),
+            remote_wipe: has(Feature::RemoteWipe),
+            wake_relay: has(Feature::WakeRelay),
+        }
+    }
+}
+
+impl From<Capabilities> for u8 {
+    fn from(caps: Capabilities) -> u8 {
+        [
+            (caps.padding, Feature::Padding),
+            (caps.shares, Feature::Shares),
+            (caps.assist, Feature::Assist),
+            (caps.remote_wipe, Feature::RemoteWipe),
+            (caps.wake_relay, Feature::WakeRelay),
+        ]
+        .into_iter()
+        .filter(|(on, _)| *on)
+        .fold(0, |bits, (_, feature)| bits | feature.bit())
+    }
+}
+
 /// Represents handshake message sent or received.
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum HandshakeMsg {
     Syn {
         public_key: [u8; 32],
         cipher_mode: EncryptionMode,
+        capabilities: Capabilities,
+        /// Cookie from the listener's `Retry`, echoed to prove we own our address.
+        cookie: Option<Cookie>,
     },
     SynAck {
         public_key: [u8; 32],
+        capabilities: Capabilities,
     },
     Bye,
+    /// Answer to an unsolicited SYN: resend it with this cookie (see `cookie`).
+    Retry {
+        cookie: Cookie,
+    },
+}
+
+/// Largest encoded handshake message (a SYN carrying a cookie).
+pub const MAX_HANDSHAKE_LEN: usize = 4 + 32 + 4 + 1 + 1 + COOKIE_LEN;
+
+const TAG_SYN: u32 = 0;
+const TAG_SYN_ACK: u32 = 1;
+const TAG_BYE: u32 = 2;
+const TAG_RETRY: u32 = 3;
+
+impl HandshakeMsg {
+    /// Encodes the message for the wire.
+    ///
+    /// The layout is the one bincode uses for this enum: a little-endian `u32`
+    /// variant tag followed by fixed-size fields. It is written out by hand so
+    /// decoding unauthenticated packets never trusts a length read from them.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(MAX_HANDSHAKE_LEN);
+        match self {
+            HandshakeMsg::Syn {
+                public_key,
+                cipher_mode,
+                capabilities,
+                cookie,
+            } => {
+                out.extend_from_slice(&TAG_SYN.to_le_bytes());
+                out.extend_from_slice(public_key);
+                let mode: u32 = match cipher_mode {
+                    EncryptionMode::ChaCha20Poly1305 => 0,
+                    EncryptionMode::Aes256Gcm => 1,
+                };
+                out.extend_from_slice(&mode.to_le_bytes());
+                out.push(u8::from(*capabilities));
+                match cookie {
+                    Some(cookie) => {
+                        out.push(1);
+                        out.extend_from_slice(cookie);
+                    }
+                    None => out.push(0),
+                }
+            }
+            HandshakeMsg::SynAck {
+                public_key,
+                capabilities,
+            } => {
+                out.extend_from_slice(&TAG_SYN_ACK.to_le_bytes());
+                out.extend_from_slice(public_key);
+                out.push(u8::from(*capabilities));
+            }
+            HandshakeMsg::Bye => out.extend_from_slice(&TAG_BYE.to_le_bytes()),
+            HandshakeMsg::Retry { cookie } => {
+                out.extend_from_slice(&TAG_RETRY.to_le_bytes());
+                out.extend_from_slice(cookie);
+            }
+        }
+        out
+    }
+
+    /// Decodes a datagram produced by `encode`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(HandshakeMsg)` - The datagram is exactly one well-formed message.
+    /// * `Err` - It is too long, truncated, has trailing bytes or an unknown tag or value.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() > MAX_HANDSHAKE_LEN {
+            bail!("Handshake message too long ({} bytes)", bytes.len());
+        }
+        let mut reader = Reader(bytes);
+        let msg = match reader.u32()? {
+            TAG_SYN => HandshakeMsg::Syn {
+                public_key: reader.array()?,
+                cipher_mode: match reader.u32()? {
+                    0 => EncryptionMode::ChaCha20Poly1305,
+                    1 => EncryptionMode::Aes256Gcm,
+                    other => bail!("Unknown encryption mode {}", other),
+                },
+                capabilities: Capabilities::from(reader.u8()?),
+                cookie: match reader.bool()? {
+                    true => Some(reader.array()?),
+                    false => None,
+                },
+            },
+            TAG_SYN_ACK => HandshakeMsg::SynAck {
+                public_key: reader.array()?,
+                capabilities: Capabilities::from(reader.u8()?),
+            },
+            TAG_BYE => HandshakeMsg::Bye,
+            TAG_RETRY => HandshakeMsg::Retry {
+                cookie: reader.array()?,
+            },
+            other => bail!("Unknown handshake message {}", other),
+        };
+        if !reader.0.is_empty() {
+            bail!("{} trailing bytes after handshake message", reader.0.len());
+        }
+        Ok(msg)
+    }
+}
+
+/// Reads fixed-size fields from the front of a datagram.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let Some((head, rest)) = self.0.split_first_chunk::<N>() else {
+            bail!("Truncated handshake message");
+        };
+        self.0 = rest;
+        Ok(*head)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool> {
+        match self.array::<1>()? {
+            [0] => Ok(false),
+            [1] => Ok(true),
+            [other] => bail!("Invalid flag {}", other),
+        }
+    }
+}
+
+/// Result of a successful handshake.
+#[derive(Debug)]
+pub struct HandshakeOutcome {
+    /// Derived session keys.
+    pub session: SessionData,
+    /// Features both peers agreed to use.
+    pub capabilities: Capabilities,
+    /// Everything the peer advertised.
+    pub peer_capabilities: Capabilities,
 }
 
 /// Performs UDP hole punching and secure key exchange handshake with remote peer.
 ///
 /// Establishes bidirectional connection by sending SYN packets (containing local public key)
-/// while listening for responses. Handles "Punching" state updates and transitions to
-/// "Connected" upon success.
+/// while listening for responses. Reports the phase and progress of the attempt in
+/// "Punching" state updates, ending in the KCP upgrade phase on success; the caller
+/// moves to "Connected" once the stream is up.
 ///
 /// # Arguments
 ///
@@ -40,10 +312,12 @@ pub enum HandshakeMsg {
 /// * `state` - Shared application state for status and UI event updates.
 /// * `timeout_secs` - Maximum duration (in seconds) to attempt handshake.
 /// * `my_mode` - Preferred encryption mode for session.
+/// * `my_caps` - Optional features offered to the peer.
 ///
 /// # Returns
 ///
-/// * `Ok(SessionData)` - Handshake succeeded, returns derived session keys.
+/// * `Ok(HandshakeOutcome)` - Handshake succeeded, returns derived session keys
+///   and negotiated capabilities.
 /// * `Err` - Operation timed out, was rejected, mode mismatch, or socket error occurred.
 pub async fn handshake(
     client_socket: Arc<UdpSocket>,
@@ -51,8 +325,10 @@ pub async fn handshake(
     state: SharedState,
     timeout_secs: u64,
     my_mode: EncryptionMode,
-) -> Result<SessionData> {
+    my_caps: Capabilities,
+) -> Result<HandshakeOutcome> {
     let mut buf = [0u8; 2048];
+    let local_addr = client_socket.local_addr()?;
     let timeout = Duration::from_secs(timeout_secs);
     let start_time = Instant::now();
 
@@ -65,10 +341,23 @@ pub async fn handshake(
     send_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
 
     let mut peer_pub_key: Option<[u8; 32]> = None;
+    let mut peer_caps = Capabilities::default();
+    // Cookie a listening peer asked us to echo, if any
+    let mut cookie: Option<Cookie> = None;
 
     // Track handshake progress
     let mut received_syn_ack = false;
     let mut sent_syn_ack = false;
+    let mut phase = PunchPhase::Spraying;
+    let mut attempts: u32 = 0;
+    let progress = |phase, attempts| {
+        Some(PunchProgress::new(
+            phase,
+            attempts,
+            start_time.elapsed(),
+            timeout,
+        ))
+    };
 
     // Linger state: Used to keep the connection alive briefly after completion
     // to ensure the peer receives the final ACK.
@@ -77,26 +366,30 @@ pub async fn handshake(
     debug!("Starting handshake with {}", peer_addr);
 
     // Update initial state
-    {
+    let transcript = {
         let mut guard = state.write().await;
         guard.set_status(
             Status::Punching,
-            Some("Handshaking (Keys Generated)...".to_string()),
-            Some(timeout_secs),
+            Some(StatusMessage::KeysGenerated),
+            progress(phase, attempts),
         );
-    }
+        guard.transcript.clone()
+    };
 
     loop {
         // 1. Check Timeout
         let elapsed = start_time.elapsed();
         if elapsed > timeout {
-            let msg = format!("Handshake timed out with {}", peer_addr);
+            let msg = StatusMessage::HandshakeTimedOut {
+                peer: Some(peer_addr),
+            };
             // Notify UI of timeout
-            state
-                .write()
-                .await
-                .set_status(Status::Punching, Some(msg.clone()), Some(0));
-            bail!(msg);
+            state.write().await.set_status(
+                Status::Punching,
+                Some(msg.clone()),
+                progress(phase, attempts),
+            );
+            bail!(msg.to_string());
         }
 
         // 2. Check Linger Phase Completion
@@ -114,21 +407,32 @@ pub async fn handshake(
             continue;
         }
 
-        let secs_left = timeout.as_secs().saturating_sub(elapsed.as_secs());
-
         tokio::select! {
             // 1. Listen to incoming packets
             result = client_socket.recv_from(&mut buf) => {
                 let (len, sender) = result.context("Socket read error")?;
 
+                let decoded = HandshakeMsg::decode(&buf[..len]);
+                transcript.record(
+                    Direction::Received,
+                    Protocol::Handshake,
+                    local_addr,
+                    sender,
+                    || match &decoded {
+                        Ok(msg) => format!("{:?}", msg),
+                        Err(_) => "<undecodable>".into(),
+                    },
+                    &buf[..len],
+                );
+
                 if sender != peer_addr {
                     debug!("Ignored packet from unknown sender: {}", sender);
                     continue;
                 }
 
-                match bincode::deserialize::<HandshakeMsg>(&buf[..len]) {
+                match decoded {
                     Ok(msg) => match msg {
-                        HandshakeMsg::Syn { public_key, cipher_mode } => {
+                        HandshakeMsg::Syn { public_key, cipher_mode, capabilities, .. } => {
                             // do not update the key to prevent MITM
                             if let Some(existing) = peer_pub_key {
                                 if existing != public_key {
@@ -147,23 +451,28 @@ pub async fn handshake(
                             }
 
                             debug!("Received SYN from {}, mode: {:?}", sender, cipher_mode);
+                            peer_caps = capabilities;
 
                             // Send SYN-ACK
-                            let reply = bincode::serialize(&HandshakeMsg::SynAck {
+                            let reply = HandshakeMsg::SynAck {
                                 public_key: my_pub_bytes,
-                            })?;
-                            client_socket.send_to(&reply, peer_addr).await?;
+                                capabilities: my_caps,
+                            };
+                            send_msg(&client_socket, peer_addr, &reply, &transcript).await?;
 
                             // Notify UI
+                            if !received_syn_ack {
+                                phase = PunchPhase::AwaitingSynAck;
+                            }
                             state.write().await.set_status(
                                 Status::Punching,
-                                Some(format!("Received SYN (Key: {:?})...", &public_key[0..4])),
-                                Some(secs_left),
+                                Some(StatusMessage::SynReceived { key_prefix: public_key[0..4].to_vec() }),
+                                progress(phase, attempts),
                             );
 
                             sent_syn_ack = true;
                         }
-                        HandshakeMsg::SynAck { public_key } => {
+                        HandshakeMsg::SynAck { public_key, capabilities } => {
                             if let Some(existing) = peer_pub_key {
                                 if existing != public_key {
                                     warn!("Security Warning: Peer key changed mid-handshake! Ignoring.");
@@ -175,22 +484,30 @@ pub async fn handshake(
 
                             debug!("Received SYN-ACK from {}", sender);
                             received_syn_ack = true;
+                            peer_caps = capabilities;
 
                             // Notify UI
+                            phase = PunchPhase::KeyExchange;
                             state.write().await.set_status(
                                 Status::Punching,
-                                Some(format!("Received SYN-ACK (Key: {:?})...", &public_key[0..4])),
-                                Some(secs_left),
+                                Some(StatusMessage::SynAckReceived { key_prefix: public_key[0..4].to_vec() }),
+                                progress(phase, attempts),
                             );
                         }
                         HandshakeMsg::Bye => {
                             state.write().await.set_status(
                                 Status::Punching,
-                                Some("Connection rejected by peer".into()),
-                                Some(secs_left)
+                                Some(StatusMessage::RejectedByPeer),
+                                progress(phase, attempts)
                             );
                             bail!("Connection rejected by peer");
                         }
+                        HandshakeMsg::Retry { cookie: issued } => {
+                            // Echoed from the next SYN on; not answered right away, so a
+                            // forged Retry can't make us send more than we already do
+                            debug!("Received cookie from {}", sender);
+                            cookie = Some(issued);
+                        }
                     },
                     Err(_) => {
                         debug!("Ignored invalid packet during handshake");
@@ -204,26 +521,30 @@ pub async fn handshake(
                 // client will send one final redundant SynAck.
                 if linger_until.is_some() {
                     if sent_syn_ack {
-                        let reply = bincode::serialize(&HandshakeMsg::SynAck {
+                        let reply = HandshakeMsg::SynAck {
                              public_key: my_pub_bytes,
-                        })?;
-                        client_socket.send_to(&reply, peer_addr).await.ok();
+                             capabilities: my_caps,
+                        };
+                        send_msg(&client_socket, peer_addr, &reply, &transcript).await.ok();
                     }
                     continue;
                 }
 
                 // Send SYN until we receive a SYN-ACK
                 if !received_syn_ack {
-                    let msg = bincode::serialize(&HandshakeMsg::Syn {
+                    let msg = HandshakeMsg::Syn {
                         public_key: my_pub_bytes,
                         cipher_mode: my_mode,
-                    })?;
-                    client_socket.send_to(&msg, peer_addr).await.context("Failed to send packet")?;
+                        capabilities: my_caps,
+                        cookie,
+                    };
+                    send_msg(&client_socket, peer_addr, &msg, &transcript).await.context("Failed to send packet")?;
+                    attempts += 1;
 
                     state.write().await.set_status(
                         Status::Punching,
-                        Some("Exchanging Keys...".into()),
-                        Some(secs_left),
+                        Some(StatusMessage::ExchangingKeys),
+                        progress(phase, attempts),
                     );
                 }
             }
@@ -245,25 +566,78 @@ pub async fn handshake(
             .await
             .set_security_info(session.fingerprint.clone(), algo_name.to_string());
 
-        // Transition to Connected state
+        // The session is up once the caller has started the KCP stream
         state.write().await.set_status(
-            Status::Connected,
-            Some(format!("Secure Channel Established ({})", algo_name)),
-            None,
+            Status::Punching,
+            Some(StatusMessage::SecureChannelEstablished {
+                algorithm: algo_name.to_string(),
+            }),
+            progress(PunchPhase::UpgradingKcp, attempts),
         );
 
-        Ok(session)
+        Ok(HandshakeOutcome {
+            session,
+            capabilities: my_caps.intersect(peer_caps),
+            peer_capabilities: peer_caps,
+        })
     } else {
         bail!("Handshake failed: No public key received");
     }
 }
 
+/// Turns away a peer that is trying to connect.
+///
+/// The peer's handshake ends with "Connection rejected by peer".
+pub async fn send_bye(
+    socket: &UdpSocket,
+    peer_addr: SocketAddr,
+    transcript: &Transcript,
+) -> Result<()> {
+    send_msg(socket, peer_addr, &HandshakeMsg::Bye, transcript).await
+}
+
+/// Asks a peer whose SYN carried no valid cookie to resend it with `cookie`.
+pub async fn send_retry(
+    socket: &UdpSocket,
+    peer_addr: SocketAddr,
+    cookie: Cookie,
+    transcript: &Transcript,
+) -> Result<()> {
+    send_msg(
+        socket,
+        peer_addr,
+        &HandshakeMsg::Retry { cookie },
+        transcript,
+    )
+    .await
+}
+
+/// Serializes and sends a handshake message, recording it to the transcript.
+async fn send_msg(
+    socket: &UdpSocket,
+    peer_addr: SocketAddr,
+    msg: &HandshakeMsg,
+    transcript: &Transcript,
+) -> Result<()> {
+    let bytes = msg.encode();
+    socket.send_to(&bytes, peer_addr).await?;
+    transcript.record(
+        Direction::Sent,
+        Protocol::Handshake,
+        socket.local_addr()?,
+        peer_addr,
+        || format!("{:?}", msg),
+        &bytes,
+    );
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::{
         super::super::{
             config::EncryptionMode,
-            web::shared_state::{AppEvent, AppState, Command, Status},
+            web::shared_state::{AppEvent, AppState, Command, PunchPhase, Status},
         },
         *,
     };
@@ -290,7 +664,8 @@ mod tests {
         Arc::new(socket)
     }
 
-    #[tokio::test]
+    #[tokio::test(start_paused = true)]
+    #[allow(clippy::collapsible_if, clippy::collapsible_match)]
     async fn test_handshake_success() {
         let socket_a = bind_local().await;
         let socket_b = bind_local().await;
@@ -308,6 +683,8 @@ mod tests {
             let syn_msg = bincode::serialize(&HandshakeMsg::Syn {
                 public_key: fake_pub_key,
                 cipher_mode: EncryptionMode::ChaCha20Poly1305,
+                capabilities: Capabilities::default(),
+                cookie: None,
             })
             .unwrap();
             socket_b.send_to(&syn_msg, addr_a).await.unwrap();
@@ -321,6 +698,7 @@ mod tests {
                             // Send SYN-ACK back so A can fulfill `received_syn_ack`
                             let reply = bincode::serialize(&HandshakeMsg::SynAck {
                                 public_key: fake_pub_key,
+                                capabilities: Capabilities::default(),
                             })
                             .unwrap();
                             socket_b.send_to(&reply, addr_a).await.unwrap();
@@ -339,6 +717,7 @@ mod tests {
             state_a.clone(),
             5,
             EncryptionMode::ChaCha20Poly1305,
+            Capabilities::default(),
         )
         .await;
 
@@ -347,32 +726,36 @@ mod tests {
         } else {
             assert!(result.is_ok());
             let locked = state_a.read().await;
-            assert_eq!(locked.status, Status::Connected);
+            assert_eq!(locked.status, Status::Punching);
         }
     }
 
-    #[tokio::test]
+    #[tokio::test(start_paused = true)]
     async fn test_handshake_timeout() {
         let socket_a = bind_local().await;
         let socket_b = bind_local().await;
         let state_a = create_dummy_state();
         let addr_b = socket_b.local_addr().unwrap();
 
-        // Very short timeout to force failure
+        // The peer never answers; on the paused clock the wait is instant
+        let started = Instant::now();
         let result = handshake(
             socket_a,
             addr_b,
             state_a,
-            1,
+            30,
             EncryptionMode::ChaCha20Poly1305,
+            Capabilities::default(),
         )
         .await;
 
         assert!(result.is_err());
         assert!(result.unwrap_err().to_string().contains("timed out"));
+        assert!(started.elapsed() >= Duration::from_secs(30));
+        assert!(started.elapsed() < Duration::from_secs(31));
     }
 
-    #[tokio::test]
+    #[tokio::test(start_paused = true)]
     async fn test_handshake_mode_mismatch() {
         let socket_a = bind_local().await;
         let socket_b = bind_local().await;
@@ -387,6 +770,8 @@ mod tests {
                 public_key: fake_pub_key,
                 // Sending AES when A expects ChaCha
                 cipher_mode: EncryptionMode::Aes256Gcm,
+                capabilities: Capabilities::default(),
+                cookie: None,
             })
             .unwrap();
             socket_b.send_to(&syn_msg, addr_a).await.unwrap();
@@ -398,6 +783,7 @@ mod tests {
             state_a,
             2,
             EncryptionMode::ChaCha20Poly1305, // Expecting ChaCha
+            Capabilities::default(),
         )
         .await;
 
@@ -407,7 +793,8 @@ mod tests {
         assert!(err.contains("mode mismatch"));
     }
 
-    #[tokio::test]
+    #[tokio::test(start_paused = true)]
+    #[allow(clippy::unnecessary_unwrap)]
     async fn test_handshake_ignores_wrong_sender() {
         let socket_a = bind_local().await;
         let socket_b = bind_local().await; // Real Peer
@@ -430,6 +817,8 @@ mod tests {
             let syn = bincode::serialize(&HandshakeMsg::Syn {
                 public_key: fake_key,
                 cipher_mode: EncryptionMode::ChaCha20Poly1305,
+                capabilities: Capabilities::default(),
+                cookie: None,
             })
             .unwrap();
             socket_b.send_to(&syn, addr_a).await.unwrap();
@@ -437,6 +826,7 @@ mod tests {
             // Peer sends SYN-ACK
             let reply = bincode::serialize(&HandshakeMsg::SynAck {
                 public_key: fake_key,
+                capabilities: Capabilities::default(),
             })
             .unwrap();
             socket_b.send_to(&reply, addr_a).await.unwrap();
@@ -448,6 +838,7 @@ mod tests {
             state_a,
             5,
             EncryptionMode::ChaCha20Poly1305,
+            Capabilities::default(),
         )
         .await;
 
@@ -459,7 +850,7 @@ mod tests {
         }
     }
 
-    #[tokio::test]
+    #[tokio::test(start_paused = true)]
     async fn test_handshake_rejects_bye_packet() {
         let socket_a = bind_local().await;
         let socket_b = bind_local().await;
@@ -480,6 +871,7 @@ mod tests {
             state_a,
             2,
             EncryptionMode::ChaCha20Poly1305,
+            Capabilities::default(),
         )
         .await;
 
@@ -490,7 +882,152 @@ mod tests {
         );
     }
 
-    #[tokio::test]
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_echoes_retry_cookie() {
+        let socket_a = bind_local().await;
+        let socket_b = bind_local().await;
+        let state_a = create_dummy_state();
+
+        let addr_a = socket_a.local_addr().unwrap();
+        let addr_b = socket_b.local_addr().unwrap();
+
+        // Peer B listens: the first SYN gets a cookie, the handshake is then
+        // abandoned with a Bye once a SYN echoes it
+        let echoed = tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let cookie = [5u8; 16];
+            loop {
+                let (len, _) = socket_b.recv_from(&mut buf).await.unwrap();
+                let Ok(HandshakeMsg::Syn { cookie: echo, .. }) = bincode::deserialize(&buf[..len])
+                else {
+                    continue;
+                };
+                let reply = match echo {
+                    None => HandshakeMsg::Retry { cookie },
+                    Some(echo) => {
+                        assert_eq!(echo, cookie);
+                        HandshakeMsg::Bye
+                    }
+                };
+                let reply = bincode::serialize(&reply).unwrap();
+                socket_b.send_to(&reply, addr_a).await.unwrap();
+                if echo.is_some() {
+                    return true;
+                }
+            }
+        });
+
+        let result = handshake(
+            socket_a,
+            addr_b,
+            state_a,
+            3,
+            EncryptionMode::ChaCha20Poly1305,
+            Capabilities::default(),
+        )
+        .await;
+
+        assert!(echoed.await.unwrap());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_retry_is_smaller_than_syn() {
+        let syn = bincode::serialize(&HandshakeMsg::Syn {
+            public_key: [0; 32],
+            cipher_mode: EncryptionMode::ChaCha20Poly1305,
+            capabilities: Capabilities::default(),
+            cookie: None,
+        })
+        .unwrap();
+        let retry = bincode::serialize(&HandshakeMsg::Retry { cookie: [0; 16] }).unwrap();
+        assert!(retry.len() < syn.len());
+    }
+
+    fn sample_messages() -> Vec<HandshakeMsg> {
+        vec![
+            HandshakeMsg::Syn {
+                public_key: [1; 32],
+                cipher_mode: EncryptionMode::Aes256Gcm,
+                capabilities: Capabilities {
+                    padding: true,
+                    shares: true,
+                    ..Default::default()
+                },
+                cookie: Some([2; 16]),
+            },
+            HandshakeMsg::Syn {
+                public_key: [3; 32],
+                cipher_mode: EncryptionMode::ChaCha20Poly1305,
+                capabilities: Capabilities::default(),
+                cookie: None,
+            },
+            HandshakeMsg::SynAck {
+                public_key: [4; 32],
+                capabilities: Capabilities {
+                    padding: true,
+                    shares: true,
+                    ..Default::default()
+                },
+            },
+            HandshakeMsg::Bye,
+            HandshakeMsg::Retry { cookie: [5; 16] },
+        ]
+    }
+
+    #[test]
+    fn test_codec_matches_bincode_layout() {
+        for msg in sample_messages() {
+            let bytes = msg.encode();
+            assert!(bytes.len() <= MAX_HANDSHAKE_LEN);
+            assert_eq!(bytes, bincode::serialize(&msg).unwrap());
+            assert_eq!(HandshakeMsg::decode(&bytes).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn test_decode_rejects_malformed_input() {
+        for msg in sample_messages() {
+            let bytes = msg.encode();
+            for len in 0..bytes.len() {
+                assert!(HandshakeMsg::decode(&bytes[..len]).is_err());
+            }
+            let mut long = bytes.clone();
+            long.push(0);
+            assert!(HandshakeMsg::decode(&long).is_err());
+        }
+        assert!(HandshakeMsg::decode(&[0; 4096]).is_err());
+
+        // Random and bit-flipped packets never panic, and whatever decodes
+        // re-encodes to the same bytes, except for unknown capability flags
+        let mut seed: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        let valid: Vec<Vec<u8>> = sample_messages().iter().map(HandshakeMsg::encode).collect();
+        for _ in 0..20_000 {
+            let mut bytes = valid[next() as usize % valid.len()].clone();
+            match next() % 3 {
+                0 => bytes = (0..next() % 80).map(|_| next() as u8).collect(),
+                1 => {
+                    let i = next() as usize % bytes.len();
+                    bytes[i] ^= 1 << (next() % 8);
+                }
+                _ => bytes.truncate(next() as usize % (bytes.len() + 1)),
+            }
+            if let Ok(msg) = HandshakeMsg::decode(&bytes) {
+                let encoded = msg.encode();
+                assert_eq!(encoded.len(), bytes.len());
+                assert_eq!(HandshakeMsg::decode(&encoded).unwrap(), msg);
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[allow(clippy::collapsible_if)]
     async fn test_handshake_handles_simultaneous_syn() {
         let socket_a = bind_local().await;
         let socket_b = bind_local().await;
@@ -509,6 +1046,8 @@ mod tests {
             let syn = bincode::serialize(&HandshakeMsg::Syn {
                 public_key: fake_key,
                 cipher_mode: EncryptionMode::ChaCha20Poly1305,
+                capabilities: Capabilities::default(),
+                cookie: None,
             })
             .unwrap();
             socket_b_clone.send_to(&syn, addr_a).await.unwrap();
@@ -521,6 +1060,7 @@ mod tests {
                         // Send SYN-ACK back
                         let reply = bincode::serialize(&HandshakeMsg::SynAck {
                             public_key: fake_key,
+                            capabilities: Capabilities::default(),
                         })
                         .unwrap();
                         socket_b_clone.send_to(&reply, addr_a).await.unwrap();
@@ -536,16 +1076,17 @@ mod tests {
             state_a.clone(),
             5,
             EncryptionMode::ChaCha20Poly1305,
+            Capabilities::default(),
         )
         .await;
 
         if result.is_ok() {
-            assert_eq!(state_a.read().await.status, Status::Connected);
+            assert_eq!(state_a.read().await.status, Status::Punching);
         }
     }
 
     /// Test that both peers complete handshake when initiating simultaneously
-    #[tokio::test]
+    #[tokio::test(start_paused = true)]
     async fn test_both_peers_complete_handshake_when_initiating_simultaneously() {
         let socket_a = bind_local().await;
         let socket_b = bind_local().await;
@@ -565,6 +1106,7 @@ mod tests {
                 state_a_clone,
                 5,
                 EncryptionMode::ChaCha20Poly1305,
+                Capabilities::default(),
             )
             .await
         });
@@ -578,6 +1120,7 @@ mod tests {
                 state_b_clone,
                 5,
                 EncryptionMode::ChaCha20Poly1305,
+                Capabilities::default(),
             )
             .await
         });
@@ -589,11 +1132,11 @@ mod tests {
         assert!(result_a.is_ok(), "Peer A should complete handshake");
         assert!(result_b.is_ok(), "Peer B should complete handshake");
 
-        assert_eq!(state_a.read().await.status, Status::Connected);
-        assert_eq!(state_b.read().await.status, Status::Connected);
+        assert_eq!(state_a.read().await.status, Status::Punching);
+        assert_eq!(state_b.read().await.status, Status::Punching);
     }
 
-    #[tokio::test]
+    #[tokio::test(start_paused = true)]
     async fn test_handshake_with_aes256_mode() {
         let socket_a = bind_local().await;
         let socket_b = bind_local().await;
@@ -613,6 +1156,7 @@ mod tests {
                 state_a_clone,
                 5,
                 EncryptionMode::Aes256Gcm,
+                Capabilities::default(),
             )
             .await
         });
@@ -626,6 +1170,7 @@ mod tests {
                 state_b_clone,
                 5,
                 EncryptionMode::Aes256Gcm,
+                Capabilities::default(),
             )
             .await
         });
@@ -643,7 +1188,7 @@ mod tests {
         );
     }
 
-    #[tokio::test]
+    #[tokio::test(start_paused = true)]
     async fn test_handshake_sets_fingerprint() {
         let socket_a = bind_local().await;
         let socket_b = bind_local().await;
@@ -662,6 +1207,7 @@ mod tests {
                 state_a_clone,
                 5,
                 EncryptionMode::ChaCha20Poly1305,
+                Capabilities::default(),
             )
             .await
         });
@@ -675,6 +1221,7 @@ mod tests {
                 state_b_clone,
                 5,
                 EncryptionMode::ChaCha20Poly1305,
+                Capabilities::default(),
             )
             .await
         });
@@ -691,6 +1238,72 @@ mod tests {
         assert_eq!(fp_a, fp_b, "Fingerprints should match");
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_negotiates_capabilities() {
+        let socket_a = bind_local().await;
+        let socket_b = bind_local().await;
+        let addr_a = socket_a.local_addr().unwrap();
+        let addr_b = socket_b.local_addr().unwrap();
+
+        let offer = Capabilities {
+            padding: true,
+            shares: true,
+            ..Default::default()
+        };
+        let state_a = create_dummy_state();
+        let handle_a = tokio::spawn(async move {
+            handshake(
+                socket_a,
+                addr_b,
+                state_a,
+                5,
+                EncryptionMode::ChaCha20Poly1305,
+                offer,
+            )
+            .await
+        });
+        let state_b = create_dummy_state();
+        let handle_b = tokio::spawn(async move {
+            handshake(
+                socket_b,
+                addr_a,
+                state_b,
+                5,
+                EncryptionMode::ChaCha20Poly1305,
+                Capabilities::default(),
+            )
+            .await
+        });
+
+        let outcome_a = handle_a.await.unwrap().unwrap();
+        let outcome_b = handle_b.await.unwrap().unwrap();
+
+        // Padding is only used when both sides offer it.
+        assert!(!outcome_a.capabilities.padding);
+        assert!(!outcome_b.capabilities.padding);
+        assert_eq!(offer.intersect(offer), offer);
+
+        // Each side learns what the other offers, and what it lacks
+        assert_eq!(outcome_b.peer_capabilities, offer);
+        assert_eq!(
+            outcome_a.peer_capabilities.missing(offer),
+            vec![
+                Feature::Padding,
+                Feature::Shares,
+                Feature::Assist,
+                Feature::RemoteWipe,
+                Feature::WakeRelay
+            ]
+        );
+        assert_eq!(
+            offer.missing(Capabilities::default()),
+            vec![Feature::Assist, Feature::RemoteWipe, Feature::WakeRelay]
+        );
+        assert!(HandshakeMsg::decode(&[0, 0, 0, 0]).is_err());
+        // Flags of a newer version are ignored
+        assert_eq!(Capabilities::from(1 << 7 | 1), Capabilities::from(1));
+    }
+
     #[tokio::test]
     async fn test_handshake_with_very_short_timeout() {
         let socket_a = bind_local().await;
@@ -705,6 +1318,7 @@ mod tests {
             state_a,
             1, // 1 second timeout
             EncryptionMode::ChaCha20Poly1305,
+            Capabilities::default(),
         )
         .await;
 
@@ -712,7 +1326,7 @@ mod tests {
         assert!(result.is_err());
     }
 
-    #[tokio::test]
+    #[tokio::test(start_paused = true)]
     async fn test_handshake_status_transitions() {
         let socket_a = bind_local().await;
         let socket_b = bind_local().await;
@@ -724,6 +1338,7 @@ mod tests {
 
         // Check initial status
         assert_eq!(state_a.read().await.status, Status::Disconnected);
+        let mut events = state_a.read().await.subscribe_events();
 
         let socket_a_clone = socket_a.clone();
         let state_a_clone = state_a.clone();
@@ -734,6 +1349,7 @@ mod tests {
                 state_a_clone,
                 5,
                 EncryptionMode::ChaCha20Poly1305,
+                Capabilities::default(),
             )
             .await
         });
@@ -747,6 +1363,7 @@ mod tests {
                 state_b_clone,
                 5,
                 EncryptionMode::ChaCha20Poly1305,
+                Capabilities::default(),
             )
             .await
         });
@@ -754,8 +1371,28 @@ mod tests {
         let _ = handle_a.await.unwrap();
         let _ = handle_b.await.unwrap();
 
-        // Status should transition to Connected
-        assert_eq!(state_a.read().await.status, Status::Connected);
-        assert_eq!(state_b.read().await.status, Status::Connected);
+        // Both stay in Punching until the caller has upgraded to KCP
+        assert_eq!(state_a.read().await.status, Status::Punching);
+        assert_eq!(state_b.read().await.status, Status::Punching);
+
+        // Phases only move forward, and so does the progress bar
+        let mut seen = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let AppEvent::Punching {
+                progress: Some(progress),
+                ..
+            } = event
+            {
+                seen.push(progress);
+            }
+        }
+        assert_eq!(seen.first().unwrap().phase, PunchPhase::Spraying);
+        let last = seen.last().unwrap();
+        assert_eq!(last.phase, PunchPhase::UpgradingKcp);
+        assert!(last.attempts >= 1);
+        assert!(
+            seen.windows(2)
+                .all(|pair| pair[0].phase <= pair[1].phase && pair[0].percent <= pair[1].percent)
+        );
     }
 }
@@ -0,0 +1,88 @@
+//! Redelivery of texts lost when a session drops.
+//!
+//! Texts are numbered per side without gaps (see `reactions`), so how many
+//! of the peer's texts one side received sums up everything it has. When a
+//! session ends without a confirmed Bye, each side keeps its sent texts and
+//! that count. When the same peers connect again, each sends a
+//! `SyncSummary` naming the dropped session by its SAS fingerprint, which
+//! both derived, and the count it received; the other side re-sends the
+//! texts past that count.
+
+use std::collections::VecDeque;
+
+/// Sent texts kept for redelivery; older ones are not re-sent.
+pub const MAX_BACKFILL: usize = 100;
+
+/// Texts we sent in the open conversation, by sequence number.
+#[derive(Debug, Clone, Default)]
+pub struct SentTexts {
+    entries: VecDeque<(u64, String)>,
+}
+
+impl SentTexts {
+    /// Records our text number `seq`.
+    pub fn push(&mut self, seq: u64, text: String) {
+        if self.entries.len() == MAX_BACKFILL {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((seq, text));
+    }
+
+    /// Returns the kept texts numbered `seq` or higher, oldest first.
+    pub fn since(&self, seq: u64) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter(|(s, _)| *s >= seq)
+            .map(|(_, text)| text.clone())
+            .collect()
+    }
+}
+
+/// Our side of a conversation that ended without a confirmed Bye.
+#[derive(Debug, Clone)]
+pub struct DroppedConversation {
+    /// SAS fingerprint of the session, the same on both sides.
+    pub fingerprint: String,
+    pub sent: SentTexts,
+    /// Peer texts we received.
+    pub received: u64,
+}
+
+impl DroppedConversation {
+    /// Returns the texts to re-send if the peer reports receiving
+    /// `peer_received` of ours in the session with `fingerprint`.
+    pub fn backfill(&self, fingerprint: &str, peer_received: u64) -> Vec<String> {
+        if fingerprint != self.fingerprint {
+            return Vec::new();
+        }
+        self.sent.since(peer_received)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backfills_only_what_the_peer_missed() {
+        let mut sent = SentTexts::default();
+        for seq in 0..MAX_BACKFILL as u64 + 2 {
+            sent.push(seq, format!("text {}", seq));
+        }
+        let dropped = DroppedConversation {
+            fingerprint: "AB CD EF".into(),
+            sent,
+            received: 3,
+        };
+
+        let last = MAX_BACKFILL as u64 + 1;
+        assert_eq!(
+            dropped.backfill("AB CD EF", last),
+            [format!("text {}", last)]
+        );
+        assert!(dropped.backfill("AB CD EF", last + 1).is_empty());
+        assert!(dropped.backfill("00 00 00", 0).is_empty());
+        // The oldest texts were not kept
+        assert_eq!(dropped.backfill("AB CD EF", 0).len(), MAX_BACKFILL);
+    }
+}
@@ -0,0 +1,228 @@
+//! Connection requests from peers we did not dial.
+//!
+//! A peer that starts punching first shows up as a stream of SYNs from an
+//! unknown address while we are idle. Each distinct address becomes one
+//! prompt; prompts the user does not answer in time are rejected with a Bye.
+//! Rejected addresses are ignored for a while so their remaining SYN
+//! retransmissions do not re-open the prompt.
+//!
+//! SYNs only reach the queue once they echo a source validation cookie
+//! (see `cookie`), so spoofed addresses can't fill it.
+
+use super::{
+    super::{config::EncryptionMode, storage::unix_timestamp},
+    cookie::Cookie,
+    handshake::HandshakeMsg,
+};
+use serde::Serialize;
+use std::{collections::HashMap, net::SocketAddr};
+use tokio::time::{Duration, Instant};
+
+/// A peer waiting for the user to accept or reject it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IncomingRequest {
+    pub addr: SocketAddr,
+    /// Encryption mode the peer asked for.
+    pub cipher_mode: EncryptionMode,
+    /// Unix timestamp (seconds) after which the request is rejected automatically.
+    pub expires_at: u64,
+    #[serde(skip)]
+    deadline: Instant,
+}
+
+/// Pending connection requests, deduplicated by address.
+#[derive(Debug)]
+pub struct IncomingQueue {
+    requests: Vec<IncomingRequest>,
+    /// Recently rejected addresses and when they may prompt again.
+    cooldown: HashMap<SocketAddr, Instant>,
+    prompt_timeout: Duration,
+    max_pending: usize,
+}
+
+impl IncomingQueue {
+    /// Creates an empty queue.
+    ///
+    /// # Arguments
+    ///
+    /// * `prompt_timeout` - How long a request waits before being rejected.
+    ///   Rejected addresses are ignored for the same duration.
+    /// * `max_pending` - Requests beyond this many are dropped without a prompt.
+    pub fn new(prompt_timeout: Duration, max_pending: usize) -> Self {
+        Self {
+            requests: Vec::new(),
+            cooldown: HashMap::new(),
+            prompt_timeout,
+            max_pending,
+        }
+    }
+
+    /// Requests waiting for an answer, oldest first.
+    pub fn requests(&self) -> &[IncomingRequest] {
+        &self.requests
+    }
+
+    /// Records a SYN from `addr`.
+    ///
+    /// # Returns
+    ///
+    /// True if a new request was queued, i.e., the list shown to the user changed.
+    pub fn on_syn(&mut self, addr: SocketAddr, cipher_mode: EncryptionMode, now: Instant) -> bool {
+        self.cooldown.retain(|_, until| *until > now);
+
+        if self.cooldown.contains_key(&addr)
+            || self.requests.iter().any(|r| r.addr == addr)
+            || self.requests.len() >= self.max_pending
+        {
+            return false;
+        }
+
+        self.requests.push(IncomingRequest {
+            addr,
+            cipher_mode,
+            expires_at: unix_timestamp() + self.prompt_timeout.as_secs(),
+            deadline: now + self.prompt_timeout,
+        });
+        true
+    }
+
+    /// Removes an accepted request.
+    pub fn take(&mut self, addr: SocketAddr) -> Option<IncomingRequest> {
+        let index = self.requests.iter().position(|r| r.addr == addr)?;
+        Some(self.requests.remove(index))
+    }
+
+    /// Removes a request and ignores further SYNs from it for a while.
+    ///
+    /// # Returns
+    ///
+    /// True if the address had a pending request.
+    pub fn reject(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        match self.take(addr) {
+            Some(_) => {
+                self.cooldown.insert(addr, now + self.prompt_timeout);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Rejects every pending request.
+    ///
+    /// # Returns
+    ///
+    /// The rejected addresses.
+    pub fn reject_all(&mut self, now: Instant) -> Vec<SocketAddr> {
+        let rejected: Vec<_> = self.requests.drain(..).map(|r| r.addr).collect();
+        for addr in &rejected {
+            self.cooldown.insert(*addr, now + self.prompt_timeout);
+        }
+        rejected
+    }
+
+    /// Rejects requests whose prompt has timed out.
+    ///
+    /// # Returns
+    ///
+    /// The expired addresses.
+    pub fn expire(&mut self, now: Instant) -> Vec<SocketAddr> {
+        let expired: Vec<_> = self
+            .requests
+            .iter()
+            .filter(|r| r.deadline <= now)
+            .map(|r| r.addr)
+            .collect();
+        for addr in &expired {
+            self.reject(*addr, now);
+        }
+        expired
+    }
+
+    /// Instant the oldest pending request expires.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.requests.iter().map(|r| r.deadline).min()
+    }
+}
+
+/// Returns the requested encryption mode and echoed cookie if `datagram` is a handshake SYN.
+pub fn parse_syn(datagram: &[u8]) -> Option<(EncryptionMode, Option<Cookie>)> {
+    match HandshakeMsg::decode(datagram) {
+        Ok(HandshakeMsg::Syn {
+            cipher_mode,
+            cookie,
+            ..
+        }) => Some((cipher_mode, cookie)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messaging::handshake::Capabilities;
+
+    const MODE: EncryptionMode = EncryptionMode::ChaCha20Poly1305;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([203, 0, 113, 7], port))
+    }
+
+    #[test]
+    fn test_deduplicates_and_caps_requests() {
+        let mut queue = IncomingQueue::new(Duration::from_secs(30), 2);
+        let now = Instant::now();
+
+        assert!(queue.on_syn(addr(1), MODE, now));
+        assert!(!queue.on_syn(addr(1), MODE, now)); // retransmission
+        assert!(queue.on_syn(addr(2), MODE, now));
+        assert!(!queue.on_syn(addr(3), MODE, now)); // full
+
+        assert_eq!(queue.requests().len(), 2);
+    }
+
+    #[test]
+    fn test_rejected_address_is_ignored_until_cooldown_ends() {
+        let mut queue = IncomingQueue::new(Duration::from_secs(30), 4);
+        let now = Instant::now();
+
+        queue.on_syn(addr(1), MODE, now);
+        assert!(queue.reject(addr(1), now));
+        assert!(!queue.reject(addr(1), now));
+
+        assert!(!queue.on_syn(addr(1), MODE, now + Duration::from_secs(10)));
+        assert!(queue.on_syn(addr(1), MODE, now + Duration::from_secs(31)));
+    }
+
+    #[test]
+    fn test_expire_removes_only_timed_out_requests() {
+        let mut queue = IncomingQueue::new(Duration::from_secs(30), 4);
+        let now = Instant::now();
+
+        queue.on_syn(addr(1), MODE, now);
+        queue.on_syn(addr(2), MODE, now + Duration::from_secs(10));
+        assert_eq!(queue.next_deadline(), Some(now + Duration::from_secs(30)));
+
+        let expired = queue.expire(now + Duration::from_secs(30));
+        assert_eq!(expired, vec![addr(1)]);
+        assert_eq!(queue.requests()[0].addr, addr(2));
+    }
+
+    #[test]
+    fn test_parse_syn() {
+        let syn = bincode::serialize(&HandshakeMsg::Syn {
+            public_key: [1; 32],
+            cipher_mode: EncryptionMode::Aes256Gcm,
+            capabilities: Capabilities::default(),
+            cookie: Some([3; 16]),
+        })
+        .unwrap();
+        let bye = bincode::serialize(&HandshakeMsg::Bye).unwrap();
+
+        assert_eq!(
+            parse_syn(&syn),
+            Some((EncryptionMode::Aes256Gcm, Some([3; 16])))
+        );
+        assert_eq!(parse_syn(&bye), None);
+        assert_eq!(parse_syn(b"\x00\x01"), None);
+    }
+}
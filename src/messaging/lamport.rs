@@ -0,0 +1,100 @@
+//! Conversation order both peers agree on.
+//!
+//! Each side shows its own texts when sent and the peer's when they arrive,
+//! so two texts written at the same time appear in a different order on each
+//! end. Every text therefore carries a Lamport clock value, and both sides
+//! sort by it, breaking ties by which side wrote the text. The side is fixed
+//! by comparing the two public keys of the handshake, which both peers see.
+
+use serde::{Deserialize, Serialize};
+
+/// Position of a text in the conversation, identical on both sides.
+///
+/// Sorts by clock, then by side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct MessageOrder {
+    /// Lamport clock value the writer stamped on the text.
+    pub clock: u64,
+    /// 0 for the side whose public key sorts first, 1 for the other.
+    pub side: u8,
+}
+
+/// Lamport clock of the open conversation.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LamportClock {
+    /// Highest clock value sent or seen.
+    time: u64,
+    /// Our side, as in `MessageOrder::side`.
+    side: u8,
+}
+
+impl LamportClock {
+    /// Creates a clock for the side whose public key sorts first if `first` is true.
+    pub fn new(first: bool) -> Self {
+        Self {
+            time: 0,
+            side: u8::from(!first),
+        }
+    }
+
+    /// Restarts the clock for a new conversation, keeping the side.
+    pub fn reset(&mut self) {
+        self.time = 0;
+    }
+
+    /// Returns the value to stamp on the next text we send.
+    ///
+    /// Does not advance the clock; `local` does once the text is sent.
+    pub fn next(&self) -> u64 {
+        self.time + 1
+    }
+
+    /// Records a text we sent stamped with `clock`.
+    pub fn local(&mut self, clock: u64) -> MessageOrder {
+        self.time = self.time.max(clock);
+        MessageOrder {
+            clock,
+            side: self.side,
+        }
+    }
+
+    /// Records a text the peer stamped with `clock`.
+    pub fn remote(&mut self, clock: u64) -> MessageOrder {
+        self.time = self.time.max(clock);
+        MessageOrder {
+            clock,
+            side: 1 - self.side,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_both_sides_agree_on_order() {
+        let mut alice = LamportClock::new(true);
+        let mut bob = LamportClock::new(false);
+
+        // Both write at once, then Bob answers having seen Alice's text
+        let a1 = alice.next();
+        let b1 = bob.next();
+        let alice_view = [alice.local(a1), alice.remote(b1)];
+        let bob_view = [bob.local(b1), bob.remote(a1)];
+        let b2 = bob.next();
+        let bob_reply = bob.local(b2);
+        let alice_reply = alice.remote(b2);
+
+        let mut alice_order = vec![alice_view[0], alice_view[1], alice_reply];
+        let mut bob_order = vec![bob_view[1], bob_view[0], bob_reply];
+        alice_order.sort();
+        bob_order.sort();
+        assert_eq!(alice_order, bob_order);
+        assert_eq!(alice_order[0], alice_view[0]);
+        assert!(alice_reply.clock > a1);
+
+        alice.reset();
+        assert_eq!(alice.next(), 1);
+    }
+}
@@ -1,20 +1,41 @@
 use super::{
     super::{
+        assist::AssistOutcome,
+        audit::DisconnectReason,
         config::EncryptionMode,
-        web::shared_state::{SharedState, Status},
+        mirror::MirrorItem,
+        share::{ShareRequest, ShareResponse},
+        stats_history::LinkCounters,
+        storage::{unix_timestamp, unix_timestamp_ms},
+        traffic::TrafficClass,
+        web::{
+            shared_state::{SharedState, Status},
+            status_message::StatusMessage,
+        },
+        wipe::WipeReport,
     },
-    crypto::CipherAlgo,
-    handshake::{self, HandshakeMsg},
+    crypto::{self, CipherAlgo},
+    expiry::MAX_TTL_SECS,
+    handshake::{self, Capabilities, HandshakeMsg, HandshakeOutcome},
+    outbox::{Outbox, Priority},
+    padding,
+    ping::HEARTBEAT_SEQ,
+    reactions::MessageId,
+    session_digest::{self, SessionDigest, TranscriptCheck},
+    text_limit::{self, TAG_LEN},
 };
-use anyhow::{Result, bail};
+use anyhow::{Result, anyhow, bail};
+use bincode::Options;
+use futures::{FutureExt, future};
 use serde::{Deserialize, Serialize};
-use std::{net::SocketAddr, sync::Arc};
+use std::{future::Future, net::SocketAddr, pin::Pin, sync::Arc};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::UdpSocket,
+    time::{Duration, Instant},
 };
 use tokio_kcp::{KcpConfig, KcpNoDelayConfig, KcpStream};
-use tracing::{debug, error, info, warn};
+use tracing::{Span, debug, error, info, info_span, warn};
 
 /// Manages P2P connection lifecycle from raw UDP to reliable KCP.
 ///
@@ -26,10 +47,15 @@ use tracing::{debug, error, info, warn};
 pub struct MessageManager {
     /// Shared UDP socket for discovery and KCP stream.
     client_socket: Arc<UdpSocket>,
+    /// Every bound socket (primary first). Raced during handshake; the ones not
+    /// carrying the session stay warm as failover paths.
+    paths: Vec<Arc<UdpSocket>>,
     /// Shared application state for UI updates.
     state: SharedState,
     /// Connected peer address. Set after successful handshake.
     peer_addr: Option<SocketAddr>,
+    /// ID of the connection attempt or session in progress; tags its log lines.
+    connection_id: Option<String>,
     /// Active reliable stream. None until `upgrade_to_kcp` is called.
     kcp_stream: Option<KcpStream>,
 
@@ -39,15 +65,194 @@ pub struct MessageManager {
     tx_nonce: u64,
     /// Receive nonce counter (strictly increasing).
     rx_nonce: u64,
+    /// Hash of the frames sent this session.
+    tx_digest: SessionDigest,
+    /// Hash of the frames received this session.
+    rx_digest: SessionDigest,
+    /// `rx_digest` before the last frame; a `TranscriptCheck` in that frame
+    /// covers what came before it.
+    rx_digest_before_last: SessionDigest,
+    /// When we last sent a `TranscriptCheck`.
+    transcript_checked_at: Instant,
+
+    /// Optional features this side offers during the handshake.
+    local_caps: Capabilities,
+    /// Features both sides agreed on for the current session.
+    capabilities: Capabilities,
+    /// Secret shared by the user's own nodes, proven to the peer after the
+    /// KCP upgrade.
+    own_node_secret: Option<String>,
+    /// `SessionData::binding` and `lower_key` of the current session.
+    binding: Option<([u8; 32], bool)>,
+    /// The peer proved it knows `own_node_secret` this session.
+    peer_own_node: bool,
+
+    /// Encrypted bytes written to the KCP stream this session.
+    bytes_sent: u64,
+    /// When the peer was last heard from on the KCP stream.
+    last_heard: Instant,
+    /// When the oldest unanswered heartbeat was sent.
+    heartbeat_sent_at: Option<Instant>,
+    /// Encrypted bytes read from the KCP stream this session.
+    bytes_received: u64,
+    /// Heartbeats sent this session.
+    heartbeats_sent: u64,
+    /// Heartbeats the peer answered this session.
+    heartbeats_answered: u64,
+
+    /// Messages waiting for room in the KCP send window.
+    outbox: Outbox<(TrafficClass, Vec<u8>)>,
+    /// Encrypted message KCP had no room for; written before anything else.
+    stalled: Option<(TrafficClass, Vec<u8>)>,
 }
 
+/// Largest sealed frame either side can receive; the size of the buffer
+/// session messages are read into.
+pub const MAX_FRAME_LEN: usize = 4096;
+
+/// Bye sends before a disconnect gives up on the peer's acknowledgment.
+const BYE_ATTEMPTS: u32 = 3;
+
+/// Wait for a `ByeAck` after each Bye.
+const BYE_ACK_WAIT: Duration = Duration::from_millis(700);
+
+/// Segments KCP may hold before further writes wait. Kept small so a backlog
+/// queues in the `Outbox`, where control frames can overtake it, rather than
+/// in KCP's own first-in-first-out queue.
+const KCP_SEND_WINDOW: u16 = 128;
+
+/// Longest the KCP stream may take to be set up once the handshake succeeded.
+const KCP_UPGRADE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest the KCP stream may take to deliver queued data before it is closed.
+const KCP_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Index of the winning path and the handshake outcome, or the last error.
+pub type HandshakeResult = Result<(usize, HandshakeOutcome)>;
+
+/// A handshake race started by `MessageManager::start_handshake`.
+pub type PendingHandshake = Pin<Box<dyn Future<Output = HandshakeResult> + Send>>;
+
 /// Represents a message sent/received to/from a peer.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum StreamMessage {
-    /// Regular chat content.
-    Text(String),
+    /// Regular chat content, stamped with the sender's Lamport clock.
+    Text { text: String, clock: u64 },
     /// Signal to close connection.
     Bye,
+    /// Latency probe; answered with a `Pong` carrying the same sequence number.
+    Ping(u32),
+    /// Reply to a `Ping`.
+    Pong(u32),
+    /// Adds or removes a reaction. `message_id` is from the sender's point of view.
+    Reaction {
+        message_id: MessageId,
+        emoji: String,
+        add: bool,
+    },
+    /// Asks the peer to run a command it granted in assist mode.
+    AssistRequest { id: u32, name: String },
+    /// Result of an `AssistRequest`.
+    AssistResponse {
+        id: u32,
+        name: String,
+        outcome: AssistOutcome,
+    },
+    /// Browses or reads from one of the peer's shared folders.
+    ShareQuery { id: u32, request: ShareRequest },
+    /// Answer to a `ShareQuery`.
+    ShareReply {
+        id: u32,
+        result: Result<ShareResponse, String>,
+    },
+    /// Confirms a `Bye`; everything sent before the Bye was received.
+    ByeAck,
+    /// Digest of every frame the sender sent before this one.
+    TranscriptCheck(SessionDigest),
+    /// Asks the peer, one of our own nodes, to delete its history.
+    WipeRequest { id: u32 },
+    /// Result of a `WipeRequest`.
+    WipeResult {
+        id: u32,
+        result: Result<WipeReport, String>,
+    },
+    /// A contact or session record from a paired node of the same user.
+    Mirror(MirrorItem),
+    /// Asks the peer, one of our own nodes, to send a Wake-on-LAN packet on
+    /// its network.
+    WakeRequest {
+        id: u32,
+        mac: String,
+        broadcast: SocketAddr,
+    },
+    /// Result of a `WakeRequest`.
+    WakeResult { id: u32, result: Result<(), String> },
+    /// Chat content both sides delete `ttl_secs` after it was sent.
+    ExpiringText {
+        text: String,
+        clock: u64,
+        ttl_secs: u32,
+    },
+    /// Sets the message TTL of the conversation, or turns it off.
+    MessageTtl { ttl_secs: Option<u32> },
+    /// Sent at the start of a conversation if the last session with the
+    /// peer dropped: how many of the peer's texts we received in the session
+    /// with `fingerprint`. The peer sends the rest again.
+    SyncSummary { fingerprint: String, received: u64 },
+    /// Proves the sender knows the own-node secret; see
+    /// `crypto::own_node_proof`.
+    OwnNodeProof([u8; 32]),
+}
+
+impl StreamMessage {
+    /// Decodes a message received from the peer.
+    ///
+    /// Nothing decoded may claim more bytes than `bytes` holds, so a forged
+    /// length prefix fails instead of allocating, and trailing bytes are rejected.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        Ok(bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .reject_trailing_bytes()
+            .with_limit(bytes.len() as u64)
+            .deserialize(bytes)?)
+    }
+
+    /// Traffic class used to order this message against others waiting to be sent.
+    pub fn priority(&self) -> Priority {
+        match self {
+            StreamMessage::Bye
+            | StreamMessage::ByeAck
+            | StreamMessage::TranscriptCheck(_)
+            | StreamMessage::Ping(_)
+            | StreamMessage::Pong(_)
+            | StreamMessage::OwnNodeProof(_) => Priority::Control,
+            StreamMessage::Text { .. }
+            | StreamMessage::ExpiringText { .. }
+            | StreamMessage::MessageTtl { .. }
+            | StreamMessage::SyncSummary { .. }
+            | StreamMessage::Reaction { .. }
+            | StreamMessage::AssistRequest { .. }
+            | StreamMessage::ShareQuery { .. }
+            | StreamMessage::WipeRequest { .. }
+            | StreamMessage::WipeResult { .. }
+            | StreamMessage::WakeRequest { .. }
+            | StreamMessage::WakeResult { .. } => Priority::Chat,
+            StreamMessage::AssistResponse { .. }
+            | StreamMessage::ShareReply { .. }
+            | StreamMessage::Mirror(_) => Priority::Bulk,
+        }
+    }
+
+    /// Traffic class this message is counted under in the traffic stats.
+    pub fn traffic_class(&self) -> TrafficClass {
+        match self {
+            StreamMessage::ShareQuery { .. } | StreamMessage::ShareReply { .. } => {
+                TrafficClass::File
+            }
+            _ if self.priority() == Priority::Control => TrafficClass::Control,
+            _ => TrafficClass::Chat,
+        }
+    }
 }
 
 impl MessageManager {
@@ -59,18 +264,82 @@ impl MessageManager {
     /// * `state` - Reference to shared application state.
     pub fn new(client_socket: Arc<UdpSocket>, state: SharedState) -> Self {
         Self {
+            paths: vec![client_socket.clone()],
             client_socket,
             state,
             peer_addr: None,
+            connection_id: None,
             kcp_stream: None,
             cipher: None, // Init
             tx_nonce: 0,  // Init
             rx_nonce: 0,  // Init
+            tx_digest: SessionDigest::default(),
+            rx_digest: SessionDigest::default(),
+            rx_digest_before_last: SessionDigest::default(),
+            transcript_checked_at: Instant::now(),
+            local_caps: Capabilities::default(),
+            capabilities: Capabilities::default(),
+            own_node_secret: None,
+            binding: None,
+            peer_own_node: false,
+            bytes_sent: 0,
+            bytes_received: 0,
+            heartbeats_sent: 0,
+            heartbeats_answered: 0,
+            last_heard: Instant::now(),
+            heartbeat_sent_at: None,
+            outbox: Outbox::default(),
+            stalled: None,
         }
     }
 
+    /// Sets the optional features offered to peers in future handshakes.
+    pub fn set_local_capabilities(&mut self, caps: Capabilities) {
+        self.local_caps = caps;
+    }
+
+    /// Sets the secret proven to peers that are the user's own nodes.
+    pub fn set_own_node_secret(&mut self, secret: Option<String>) {
+        self.own_node_secret = secret;
+    }
+
+    /// Checks the peer's `OwnNodeProof` against our own-node secret.
+    ///
+    /// # Returns
+    ///
+    /// True if the proof is valid for this session; the peer then counts as
+    /// one of our own nodes until the session ends.
+    pub fn accept_own_node_proof(&mut self, proof: &[u8; 32]) -> bool {
+        let (Some(secret), Some((binding, lower_key))) = (&self.own_node_secret, &self.binding)
+        else {
+            return false;
+        };
+        self.peer_own_node = crypto::verify_own_node_proof(secret, binding, !lower_key, proof);
+        self.peer_own_node
+    }
+
+    /// True if the peer proved this session that it is one of our own nodes.
+    pub fn peer_is_own_node(&self) -> bool {
+        self.peer_own_node
+    }
+
+    /// Registers an additional socket (e.g., bound on a second interface) as a path.
+    pub fn add_path(&mut self, socket: Arc<UdpSocket>) {
+        self.paths.push(socket);
+    }
+
+    /// Returns the bound sockets that are not carrying the current session.
+    pub fn standby_paths(&self) -> impl Iterator<Item = &Arc<UdpSocket>> {
+        self.paths
+            .iter()
+            .filter(|socket| !Arc::ptr_eq(socket, &self.client_socket))
+    }
+
     /// Initiates connection handshake with target peer.
     ///
+    /// When several paths are bound, the handshake is raced across all of them
+    /// and the first path to complete carries the session.
+    ///
     /// Blocks until handshake succeeds or times out.
     /// Handles `Punching` -> `Connected` state transitions.
     ///
@@ -90,42 +359,187 @@ impl MessageManager {
         timeout_secs: u64,
         mode: EncryptionMode,
     ) -> Result<()> {
-        debug!("Initiating handshake with peer {}", peer_addr);
+        let pending = self.start_handshake(peer_addr, timeout_secs, mode).await;
+        let result = pending.await;
+        self.finish_handshake(peer_addr, result).await
+    }
 
-        // Call the standalone handshake function with 5 arguments
-        match handshake::handshake(
-            self.client_socket.clone(),
-            peer_addr,
-            self.state.clone(),
-            timeout_secs,
-            mode,
-        )
-        .await
+    /// Records a new connection attempt and returns the handshake race.
+    ///
+    /// The returned future owns everything it needs, so the controller can
+    /// run it on its own task and keep serving other commands meanwhile.
+    /// Pass its result to `finish_handshake`.
+    ///
+    /// The attempt gets a new connection ID; see `span`.
+    pub async fn start_handshake(
+        &mut self,
+        peer_addr: SocketAddr,
+        timeout_secs: u64,
+        mode: EncryptionMode,
+    ) -> PendingHandshake {
         {
-            Ok(session) => {
+            let mut guard = self.state.write().await;
+            let connection_id = guard.begin_connection();
+            let nat_type = guard.nat_type;
+            let peer_label = guard.peer_label.clone();
+            // Guest sessions leave no record
+            if !guard.guest {
+                guard
+                    .session_log
+                    .begin(connection_id.clone(), peer_addr, peer_label, nat_type);
+            }
+            self.connection_id = Some(connection_id);
+        }
+        let _span = self.span().entered();
+        debug!("Initiating handshake with peer {}", peer_addr);
+
+        self.race_paths(peer_addr, timeout_secs, mode)
+    }
+
+    /// Returns a span tagging log lines with the current connection ID.
+    ///
+    /// Disabled between connections.
+    pub fn span(&self) -> Span {
+        match &self.connection_id {
+            Some(id) => info_span!("conn", id = %id),
+            None => Span::none(),
+        }
+    }
+
+    /// Applies the result of a handshake started with `start_handshake`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - Handshake succeeded; `self.peer_addr` is set.
+    /// * `Err` - Handshake failed; state reset to `Disconnected`.
+    pub async fn finish_handshake(
+        &mut self,
+        peer_addr: SocketAddr,
+        result: HandshakeResult,
+    ) -> Result<()> {
+        match result {
+            Ok((path, outcome)) => {
+                let session = outcome.session;
                 info!("Handshake complete, fingerprint: {}", session.fingerprint);
                 self.peer_addr = Some(peer_addr);
+                self.client_socket = self.paths[path].clone();
+                self.publish_paths().await;
+
+                self.state.write().await.start_clock(session.lower_key);
 
                 // Store the Cipher and Reset Nonces
                 self.cipher = Some(session.cipher);
+                self.binding = Some((session.binding, session.lower_key));
+                self.peer_own_node = false;
                 self.tx_nonce = 0;
                 self.rx_nonce = 0;
+                self.reset_digests();
+                self.state.write().await.set_transcript_check(None);
+                self.bytes_sent = 0;
+                self.bytes_received = 0;
+                self.heartbeats_sent = 0;
+                self.heartbeats_answered = 0;
+                self.capabilities = outcome.capabilities;
+                if self.capabilities.padding {
+                    debug!("Traffic padding negotiated");
+                }
+                let peer = outcome.peer_capabilities;
+                let mut guard = self.state.write().await;
+                guard.max_text_len = text_limit::max_text_len(self.capabilities.padding);
+                guard.set_peer_features(Some(peer.features()), peer.missing(self.local_caps));
+                drop(guard);
 
                 Ok(())
             }
             Err(e) => {
                 error!("Handshake failed: {}", e);
 
-                self.state.write().await.set_status(
+                let mut guard = self.state.write().await;
+                guard.session_log.finish(
+                    DisconnectReason::HandshakeFailed,
+                    Some(e.to_string()),
+                    0,
+                    0,
+                );
+                guard.set_status(
                     Status::Disconnected,
-                    Some(format!("Connection failed: {}", e)),
+                    Some(StatusMessage::ConnectionFailed {
+                        error: e.to_string(),
+                    }),
                     None,
                 );
+                self.connection_id = None;
                 bail!(e);
             }
         }
     }
 
+    /// Records that a handshake started with `start_handshake` was abandoned.
+    ///
+    /// # Arguments
+    ///
+    /// * `reason` - `LocalRequest` if the user cancelled, `PeerTimeout` if it overran.
+    /// * `detail` - Shown to the user and stored in the session record.
+    pub async fn cancel_handshake(&mut self, reason: DisconnectReason, detail: StatusMessage) {
+        info!("Handshake abandoned: {}", detail);
+
+        let mut guard = self.state.write().await;
+        guard
+            .session_log
+            .finish(reason, Some(detail.to_string()), 0, 0);
+        guard.set_status(Status::Disconnected, Some(detail), None);
+        self.connection_id = None;
+    }
+
+    /// Runs the handshake on every bound path concurrently.
+    ///
+    /// # Returns
+    ///
+    /// A future resolving to the index of the winning path and its outcome,
+    /// or the last error if every path failed.
+    fn race_paths(
+        &self,
+        peer_addr: SocketAddr,
+        timeout_secs: u64,
+        mode: EncryptionMode,
+    ) -> PendingHandshake {
+        let attempts: Vec<_> = self
+            .paths
+            .iter()
+            .enumerate()
+            .map(|(index, socket)| {
+                let attempt = handshake::handshake(
+                    socket.clone(),
+                    peer_addr,
+                    self.state.clone(),
+                    timeout_secs,
+                    mode,
+                    self.local_caps,
+                );
+                Box::pin(async move { attempt.await.map(|outcome| (index, outcome)) })
+            })
+            .collect();
+        let multiple = attempts.len() > 1;
+
+        Box::pin(async move {
+            let (winner, _losers) = future::select_ok(attempts).await?;
+            if multiple {
+                debug!("Path {} won the handshake race", winner.0);
+            }
+            Ok(winner)
+        })
+    }
+
+    /// Publishes the active and standby local path addresses to shared state.
+    async fn publish_paths(&self) {
+        let active = self.client_socket.local_addr().ok();
+        let standby = self
+            .standby_paths()
+            .filter_map(|socket| socket.local_addr().ok())
+            .collect();
+        self.state.write().await.set_paths(active, standby);
+    }
+
     /// Upgrades existing raw UDP connection to reliable KCP stream.
     ///
     /// Uses "Turbo Mode" configuration for low latency:
@@ -152,7 +566,7 @@ impl MessageManager {
                     resend: 2,
                     nc: true,
                 },
-                wnd_size: (1024, 1024),
+                wnd_size: (KCP_SEND_WINDOW, 1024),
                 mtu: 1400,
                 ..Default::default()
             };
@@ -161,8 +575,73 @@ impl MessageManager {
             let socket = self.clone_socket()?;
 
             // Connect the KCP stream wrapper.
-            self.kcp_stream =
-                Some(KcpStream::connect_with_socket(&config, socket, peer_addr).await?);
+            let connect = KcpStream::connect_with_socket(&config, socket, peer_addr);
+            let result = match tokio::time::timeout(KCP_UPGRADE_TIMEOUT, connect).await {
+                Ok(result) => result.map_err(anyhow::Error::from),
+                Err(_) => Err(anyhow!(
+                    "KCP upgrade did not complete within {} s",
+                    KCP_UPGRADE_TIMEOUT.as_secs()
+                )),
+            };
+            match result {
+                Ok(stream) => {
+                    self.kcp_stream = Some(stream);
+                    // The session starts out alive; the liveness clock runs from here
+                    self.last_heard = Instant::now();
+                }
+                Err(e) => {
+                    self.state.write().await.session_log.finish(
+                        DisconnectReason::UpgradeFailed,
+                        Some(e.to_string()),
+                        0,
+                        0,
+                    );
+                    self.connection_id = None;
+                    bail!(e);
+                }
+            }
+
+            let summary = {
+                let mut guard = self.state.write().await;
+                let algo = guard.encryption_algo.clone();
+                guard.session_log.established("KCP", algo);
+                guard.stats_history.begin(
+                    self.connection_id.clone(),
+                    peer_addr,
+                    unix_timestamp_ms(),
+                );
+                guard.open_conversation(peer_addr);
+                guard.sync_summary()
+            };
+            if let Some((fingerprint, received)) = summary {
+                debug!(
+                    "Asking peer to resend texts past {} of the dropped session",
+                    received
+                );
+                if let Err(e) = self
+                    .send_stream_message(&StreamMessage::SyncSummary {
+                        fingerprint,
+                        received,
+                    })
+                    .await
+                {
+                    warn!("Failed to send history summary: {}", e);
+                }
+            }
+
+            let proof = match (&self.own_node_secret, &self.binding) {
+                (Some(secret), Some((binding, lower_key))) => {
+                    Some(crypto::own_node_proof(secret, binding, *lower_key))
+                }
+                _ => None,
+            };
+            if let Some(proof) = proof
+                && let Err(e) = self
+                    .send_stream_message(&StreamMessage::OwnNodeProof(proof))
+                    .await
+            {
+                warn!("Failed to send own-node proof: {}", e);
+            }
 
             info!("KCP upgrade complete");
             Ok(())
@@ -173,36 +652,402 @@ impl MessageManager {
 
     /// Sends a text message wrapped in the StreamMessage protocol
     ///
+    /// The text is stamped with the conversation clock; `add_message` must
+    /// follow before anything else is received. While the conversation has a
+    /// message TTL, the text is sent as an `ExpiringText` carrying it.
+    ///
     /// # Arguments
     ///
     /// * `text` - Message to send.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the text does not fit in one message.
     pub async fn send_text(&mut self, text: String) -> Result<()> {
-        let payload = bincode::serialize(&StreamMessage::Text(text))?;
-        self.send_secure(&payload).await
+        let max_len = text_limit::max_text_len(self.capabilities.padding);
+        if text.len() > max_len {
+            bail!("Message is {} bytes; the limit is {}", text.len(), max_len);
+        }
+        let (clock, message_ttl) = {
+            let guard = self.state.read().await;
+            (guard.next_clock(), guard.message_ttl)
+        };
+        let msg = match message_ttl {
+            Some(ttl_secs) => StreamMessage::ExpiringText {
+                text,
+                clock,
+                ttl_secs,
+            },
+            None => StreamMessage::Text { text, clock },
+        };
+        self.send_stream_message(&msg).await
+    }
+
+    /// Tells the peer the conversation's new message TTL.
+    ///
+    /// # Arguments
+    ///
+    /// * `ttl_secs` - TTL in seconds, or `None` to keep messages.
+    pub async fn send_message_ttl(&mut self, ttl_secs: Option<u32>) -> Result<()> {
+        self.send_stream_message(&StreamMessage::MessageTtl { ttl_secs })
+            .await
+    }
+
+    /// Sends a latency probe.
+    ///
+    /// # Arguments
+    ///
+    /// * `seq` - Sequence number echoed back in the `Pong`.
+    pub async fn send_ping(&mut self, seq: u32) -> Result<()> {
+        self.send_stream_message(&StreamMessage::Ping(seq)).await
+    }
+
+    /// Sends a liveness heartbeat, a ping with `HEARTBEAT_SEQ`.
+    pub async fn send_heartbeat(&mut self) -> Result<()> {
+        self.send_ping(HEARTBEAT_SEQ).await?;
+        self.heartbeat_sent_at.get_or_insert_with(Instant::now);
+        self.heartbeats_sent += 1;
+        Ok(())
+    }
+
+    /// Handles the pong to a heartbeat.
+    ///
+    /// # Returns
+    ///
+    /// The round-trip time since the oldest unanswered heartbeat, if one was sent.
+    pub fn on_heartbeat_pong(&mut self) -> Option<Duration> {
+        let rtt = self
+            .heartbeat_sent_at
+            .take()
+            .map(|sent_at| sent_at.elapsed());
+        if rtt.is_some() {
+            self.heartbeats_answered += 1;
+        }
+        rtt
     }
 
-    /// Encrypts and sends a binary message over the established KCP stream.
+    /// Answers a latency probe.
+    ///
+    /// # Arguments
+    ///
+    /// * `seq` - Sequence number from the received `Ping`.
+    pub async fn send_pong(&mut self, seq: u32) -> Result<()> {
+        self.send_stream_message(&StreamMessage::Pong(seq)).await
+    }
+
+    /// Sends a reaction on a message.
+    ///
+    /// # Arguments
+    ///
+    /// * `message_id` - Target message, from our point of view.
+    /// * `emoji` - The reaction.
+    /// * `add` - False to withdraw an earlier reaction.
+    pub async fn send_reaction(
+        &mut self,
+        message_id: MessageId,
+        emoji: String,
+        add: bool,
+    ) -> Result<()> {
+        self.send_stream_message(&StreamMessage::Reaction {
+            message_id,
+            emoji,
+            add,
+        })
+        .await
+    }
+
+    /// Asks the peer to run a granted command.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Invocation ID echoed in the response.
+    /// * `name` - Name of the grant.
+    pub async fn send_assist_request(&mut self, id: u32, name: String) -> Result<()> {
+        self.send_stream_message(&StreamMessage::AssistRequest { id, name })
+            .await
+    }
+
+    /// Returns the result of a command the peer asked us to run.
+    pub async fn send_assist_response(
+        &mut self,
+        id: u32,
+        name: String,
+        outcome: AssistOutcome,
+    ) -> Result<()> {
+        self.send_stream_message(&StreamMessage::AssistResponse { id, name, outcome })
+            .await
+    }
+
+    /// Sends a request for the peer's shared folders.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Request ID echoed in the reply.
+    /// * `request` - Listing or ranged read.
+    pub async fn send_share_query(&mut self, id: u32, request: ShareRequest) -> Result<()> {
+        self.send_stream_message(&StreamMessage::ShareQuery { id, request })
+            .await
+    }
+
+    /// Answers a request for our shared folders.
+    pub async fn send_share_reply(
+        &mut self,
+        id: u32,
+        result: Result<ShareResponse, String>,
+    ) -> Result<()> {
+        self.send_stream_message(&StreamMessage::ShareReply { id, result })
+            .await
+    }
+
+    /// Asks the peer to wipe its history.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Request ID echoed in the result.
+    pub async fn send_wipe_request(&mut self, id: u32) -> Result<()> {
+        self.send_stream_message(&StreamMessage::WipeRequest { id })
+            .await
+    }
+
+    /// Asks the peer to send a Wake-on-LAN packet on its network.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Request ID echoed in the result.
+    /// * `mac` - MAC address of the machine to wake.
+    /// * `broadcast` - Where the peer sends the packet.
+    pub async fn send_wake_request(
+        &mut self,
+        id: u32,
+        mac: String,
+        broadcast: SocketAddr,
+    ) -> Result<()> {
+        self.send_stream_message(&StreamMessage::WakeRequest { id, mac, broadcast })
+            .await
+    }
+
+    /// Answers the peer's `WakeRequest`.
+    pub async fn send_wake_result(&mut self, id: u32, result: Result<(), String>) -> Result<()> {
+        self.send_stream_message(&StreamMessage::WakeResult { id, result })
+            .await
+    }
+
+    /// Sends mirrored contacts and session records to a paired node.
+    ///
+    /// # Arguments
+    ///
+    /// * `items` - Entries to send, one message each.
+    pub async fn send_mirror(&mut self, items: Vec<MirrorItem>) -> Result<()> {
+        for item in items {
+            self.send_stream_message(&StreamMessage::Mirror(item))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Answers the peer's `WipeRequest`.
+    pub async fn send_wipe_result(
+        &mut self,
+        id: u32,
+        result: Result<WipeReport, String>,
+    ) -> Result<()> {
+        self.send_stream_message(&StreamMessage::WipeResult { id, result })
+            .await
+    }
+
+    /// Serializes a message and queues it for sending at its priority.
+    async fn send_stream_message(&mut self, msg: &StreamMessage) -> Result<()> {
+        let payload = bincode::serialize(msg)?;
+        self.send_secure(payload, msg.priority(), msg.traffic_class())
+            .await
+    }
+
+    /// Queues a binary message and writes as much of the queue as KCP accepts.
     ///
     /// # Arguments
     ///
     /// * `payload` - The bytes to send.
-    async fn send_secure(&mut self, payload: &[u8]) -> Result<()> {
-        if let Some(stream) = &mut self.kcp_stream {
-            if let Some(cipher) = &self.cipher {
-                // Encrypt payload
-                let ciphertext = cipher.encrypt(self.tx_nonce, payload)?;
-                self.tx_nonce += 1;
+    /// * `priority` - More urgent messages overtake queued ones.
+    /// * `class` - Traffic class the message is counted under once sent.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the session is not up, or if the sealed message
+    /// would not fit in the peer's `MAX_FRAME_LEN` receive buffer.
+    async fn send_secure(
+        &mut self,
+        payload: Vec<u8>,
+        priority: Priority,
+        class: TrafficClass,
+    ) -> Result<()> {
+        if self.kcp_stream.is_none() {
+            bail!("KCP stream not established");
+        }
+        if self.cipher.is_none() {
+            bail!("Encryption not initialized");
+        }
+        let max_len = text_limit::max_payload_len(self.capabilities.padding);
+        if payload.len() > max_len {
+            bail!(
+                "Message is {} bytes; at most {} fit in a frame",
+                payload.len(),
+                max_len
+            );
+        }
+        self.outbox.push(priority, (class, payload));
+        self.pump_outbox().await
+    }
 
-                // Send ciphertext
-                stream.write_all(&ciphertext).await?;
-                stream.flush().await?;
-                Ok(())
-            } else {
-                bail!("Encryption not initialized");
+    /// Returns true if messages are waiting for room in the KCP send window.
+    pub fn has_backlog(&self) -> bool {
+        self.stalled.is_some() || !self.outbox.is_empty()
+    }
+
+    /// Returns the number of messages waiting for room in the KCP send window.
+    pub fn backlog_len(&self) -> usize {
+        self.outbox.len() + usize::from(self.stalled.is_some())
+    }
+
+    /// Returns true if the periodic `TranscriptCheck` is due.
+    pub fn transcript_check_due(&self) -> bool {
+        self.transcript_checked_at.elapsed() >= session_digest::CHECK_INTERVAL
+    }
+
+    /// Sends the digest of every frame sent so far.
+    ///
+    /// Skipped while messages are queued: they would be sealed ahead of the
+    /// check without being covered by it.
+    ///
+    /// # Returns
+    ///
+    /// True if the check was sent.
+    pub async fn send_transcript_check(&mut self) -> Result<bool> {
+        if self.has_backlog() {
+            return Ok(false);
+        }
+        let digest = self.tx_digest;
+        self.send_stream_message(&StreamMessage::TranscriptCheck(digest))
+            .await?;
+        self.transcript_checked_at = Instant::now();
+        Ok(true)
+    }
+
+    /// Compares the peer's digest of what it sent with what we received
+    /// before its `TranscriptCheck`, and reports the result.
+    ///
+    /// Must be called right after the frame carrying the check was received.
+    ///
+    /// # Returns
+    ///
+    /// True if both sides saw the same frames.
+    pub async fn verify_transcript(&mut self, sent: SessionDigest) -> bool {
+        let check = TranscriptCheck::compare(&sent, &self.rx_digest_before_last, unix_timestamp());
+        if check.matched {
+            debug!("Session transcript matches after {} frames", check.sent);
+        } else {
+            warn!(
+                "Session transcript diverged: peer sent {} frames, {} received",
+                check.sent, check.received
+            );
+        }
+        let matched = check.matched;
+        self.state.write().await.set_transcript_check(Some(check));
+        matched
+    }
+
+    fn reset_digests(&mut self) {
+        self.tx_digest = SessionDigest::default();
+        self.rx_digest = SessionDigest::default();
+        self.rx_digest_before_last = SessionDigest::default();
+        self.transcript_checked_at = Instant::now();
+    }
+
+    /// Encrypts queued messages in priority order and writes them to the KCP
+    /// stream until its send window is full.
+    ///
+    /// Never waits for the window; call again once the peer has acknowledged data.
+    pub async fn pump_outbox(&mut self) -> Result<()> {
+        if !self.has_backlog() {
+            return Ok(());
+        }
+        let traffic = self.state.read().await.traffic.clone();
+        let mut wrote = false;
+        loop {
+            let (class, ciphertext) = match self.stalled.take() {
+                Some(stalled) => stalled,
+                None => match self.outbox.pop() {
+                    Some((_, (class, payload))) => (class, self.seal(&payload)?),
+                    None => break,
+                },
+            };
+            let Some(stream) = &mut self.kcp_stream else {
+                bail!("KCP stream not established");
+            };
+            // KCP takes a whole message or, with a full window, nothing
+            match stream.write(&ciphertext).now_or_never() {
+                Some(result) => {
+                    result?;
+                    self.bytes_sent += ciphertext.len() as u64;
+                    traffic.record(class, ciphertext.len());
+                    wrote = true;
+                }
+                None => {
+                    self.stalled = Some((class, ciphertext));
+                    break;
+                }
+            }
+        }
+        if wrote && let Some(stream) = &mut self.kcp_stream {
+            stream.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Waits, up to `KCP_DRAIN_TIMEOUT`, until every queued message has been
+    /// handed to KCP.
+    async fn flush_outbox(&mut self) -> Result<()> {
+        let deadline = Instant::now() + KCP_DRAIN_TIMEOUT;
+        loop {
+            self.pump_outbox().await?;
+            if !self.has_backlog() {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                bail!("Peer stopped accepting data");
             }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Pads (if negotiated) and encrypts a payload with the next transmit nonce.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, without using up the nonce, if the sealed frame
+    /// would exceed `MAX_FRAME_LEN`: the peer could not read it, and every
+    /// later frame would fail to decrypt.
+    fn seal(&mut self, payload: &[u8]) -> Result<Vec<u8>> {
+        let Some(cipher) = &self.cipher else {
+            bail!("Encryption not initialized");
+        };
+        let padded;
+        let plaintext = if self.capabilities.padding {
+            padded = padding::pad(payload);
+            &padded[..]
         } else {
-            bail!("KCP stream not established");
+            payload
+        };
+        if plaintext.len() + TAG_LEN > MAX_FRAME_LEN {
+            bail!(
+                "Sealed frame would be {} bytes; the limit is {}",
+                plaintext.len() + TAG_LEN,
+                MAX_FRAME_LEN
+            );
         }
+        let ciphertext = cipher.encrypt(self.tx_nonce, plaintext)?;
+        self.tx_nonce += 1;
+        self.tx_digest.update(&ciphertext);
+        Ok(ciphertext)
     }
 
     /// Reads a message from the KCP stream, decrypts it, and writes to buffer.
@@ -221,18 +1066,27 @@ impl MessageManager {
             if n == 0 {
                 return Ok(0);
             }
+            self.bytes_received += n as u64;
+            self.last_heard = Instant::now();
 
             if let Some(cipher) = &self.cipher {
                 // Decrypt
                 let ciphertext = &buf[..n];
-                let plaintext = cipher.decrypt(self.rx_nonce, ciphertext)?;
+                let decrypted = cipher.decrypt(self.rx_nonce, ciphertext)?;
                 self.rx_nonce += 1;
+                self.rx_digest_before_last = self.rx_digest;
+                self.rx_digest.update(ciphertext);
+                let plaintext = if self.capabilities.padding {
+                    padding::unpad(&decrypted)?
+                } else {
+                    &decrypted[..]
+                };
 
                 // Copy plaintext back to buf
                 if plaintext.len() > buf.len() {
                     bail!("Buffer too small for plaintext");
                 }
-                buf[..plaintext.len()].copy_from_slice(&plaintext);
+                buf[..plaintext.len()].copy_from_slice(plaintext);
 
                 Ok(plaintext.len())
             } else {
@@ -243,6 +1097,26 @@ impl MessageManager {
         }
     }
 
+    /// Encrypted bytes sent and received this session.
+    pub fn session_bytes(&self) -> u64 {
+        self.bytes_sent + self.bytes_received
+    }
+
+    /// Traffic and heartbeat totals of the session so far.
+    pub fn link_counters(&self) -> LinkCounters {
+        LinkCounters {
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            heartbeats_sent: self.heartbeats_sent,
+            heartbeats_answered: self.heartbeats_answered,
+        }
+    }
+
+    /// Time since the peer was last heard from on the KCP stream.
+    pub fn idle_for(&self) -> Duration {
+        self.last_heard.elapsed()
+    }
+
     /// Returns true if the KCP stream is currently active.
     pub fn is_connected(&self) -> bool {
         self.kcp_stream.is_some()
@@ -293,28 +1167,30 @@ impl MessageManager {
     ///
     /// This method:
     /// 1. Sends a Bye message to the peer (over KCP if connected, UDP as fallback)
-    /// 2. Closes the KCP stream if active
-    /// 3. Resets the connection state
-    /// 4. Updates shared state to Disconnected
+    /// 2. Over KCP, waits a bounded time for the peer's `ByeAck`, resending the Bye
+    /// 3. Closes the KCP stream if active
+    /// 4. Resets the connection state
+    /// 5. Updates shared state to Disconnected
     ///
     /// # Returns
     ///
-    /// * `Ok(())` - Disconnection successful
+    /// * `Ok(true)` - The peer acknowledged the Bye
+    /// * `Ok(false)` - No acknowledgment; the peer is assumed gone
     /// * `Err` - If sending the Bye message fails (cleanup still proceeds)
-    pub async fn disconnect(&mut self) -> Result<()> {
+    pub async fn disconnect(&mut self) -> Result<bool> {
         self.disconnect_internal(true).await
     }
 
-    /// Disconnects from peer without sending Bye (used when receiving Bye from peer).
+    /// Acknowledges the peer's Bye and disconnects without sending one.
     ///
-    /// This method performs cleanup without notifying the peer, since they already
-    /// initiated the disconnect.
+    /// The acknowledgment is given time to reach the peer before the KCP
+    /// stream is closed.
     ///
     /// # Returns
     ///
     /// * `Ok(())` - Disconnection successful
     pub async fn disconnect_on_bye_received(&mut self) -> Result<()> {
-        self.disconnect_internal(false).await
+        self.disconnect_internal(false).await.map(|_| ())
     }
 
     /// Internal disconnect implementation with option to send Bye message.
@@ -322,10 +1198,18 @@ impl MessageManager {
     /// # Arguments
     ///
     /// * `send_bye` - If true, sends Bye message to peer before cleanup.
+    ///   Otherwise the peer sent one, and it is acknowledged.
+    ///
+    /// # Returns
+    ///
+    /// True if the peer confirmed the disconnect or initiated it.
     #[allow(clippy::collapsible_if)]
-    async fn disconnect_internal(&mut self, send_bye: bool) -> Result<()> {
+    async fn disconnect_internal(&mut self, send_bye: bool) -> Result<bool> {
         debug!("Initiating disconnect (send_bye: {})", send_bye);
 
+        // A peer that sent Bye has nothing left to confirm
+        let mut confirmed = !send_bye;
+
         // Send Bye message to peer only if requested
         if send_bye {
             if let Some(peer_addr) = self.peer_addr {
@@ -333,25 +1217,86 @@ impl MessageManager {
 
                 // 1. Try KCP (Encrypted)
                 if self.kcp_stream.is_some() && self.cipher.is_some() {
-                    if let Ok(bye_packet) = bincode::serialize(&StreamMessage::Bye) {
-                        if self.send_secure(&bye_packet).await.is_ok() {
-                            debug!("Sent encrypted Bye via KCP");
+                    match self.exchange_bye().await {
+                        Ok(acked) => {
                             sent_via_kcp = true;
+                            confirmed = acked;
+                            if !acked {
+                                warn!("Peer did not acknowledge Bye; assuming it is gone");
+                            }
                         }
+                        Err(e) => debug!("Bye over KCP failed: {}", e),
                     }
                 }
 
                 // 2. Fallback: UDP Raw (HandshakeMsg::Bye)
                 if !sent_via_kcp {
-                    let udp_bye = bincode::serialize(&HandshakeMsg::Bye)?;
+                    let udp_bye = HandshakeMsg::Bye.encode();
                     match self.client_socket.send_to(&udp_bye, peer_addr).await {
-                        Ok(_) => debug!("Sent HandshakeMsg::Bye via UDP"),
+                        Ok(n) => {
+                            self.state
+                                .read()
+                                .await
+                                .traffic
+                                .record(TrafficClass::Handshake, n);
+                            debug!("Sent HandshakeMsg::Bye via UDP");
+                        }
                         Err(e) => warn!("Failed to send Bye via UDP: {}", e),
                     }
                 }
             }
+        } else if self.kcp_stream.is_some() && self.cipher.is_some() {
+            // Our queued messages go out ahead of the ByeAck, followed by the
+            // digest of everything we sent
+            let acked = match self.flush_outbox().await {
+                Ok(()) => match self.send_transcript_check().await {
+                    Ok(_) => self.send_stream_message(&StreamMessage::ByeAck).await,
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            };
+            if let Err(e) = acked {
+                debug!("Failed to acknowledge Bye: {}", e);
+            }
         }
 
+        // Let queued messages (and our ByeAck) reach the peer before closing
+        self.drain_kcp().await;
+
+        let reason = if send_bye {
+            DisconnectReason::LocalRequest
+        } else {
+            DisconnectReason::PeerRequest
+        };
+        let message = match (send_bye, confirmed) {
+            (false, _) => StatusMessage::PeerDisconnected,
+            (true, true) => StatusMessage::DisconnectConfirmed,
+            (true, false) => StatusMessage::DisconnectUnconfirmed,
+        };
+        self.teardown(reason, confirmed, message, None).await;
+        Ok(confirmed)
+    }
+
+    /// Drops a session whose peer stopped responding, without a Bye.
+    ///
+    /// # Arguments
+    ///
+    /// * `detail` - Why the peer is considered gone; shown to the user and logged.
+    pub async fn abandon(&mut self, detail: StatusMessage) {
+        warn!("Dropping session: {}", detail);
+        let error = detail.to_string();
+        self.teardown(DisconnectReason::PeerTimeout, false, detail, Some(error))
+            .await;
+    }
+
+    /// Closes the stream, resets session state and reports the end of the session.
+    async fn teardown(
+        &mut self,
+        reason: DisconnectReason,
+        confirmed: bool,
+        message: StatusMessage,
+        error: Option<String>,
+    ) {
         // Close KCP stream if active
         if let Err(e) = self.close_kcp().await {
             warn!("Error closing KCP stream during disconnect: {}", e);
@@ -359,23 +1304,125 @@ impl MessageManager {
 
         // Reset connection state
         self.peer_addr = None;
+        self.client_socket = self.paths[0].clone();
         // Reset Cipher
         self.cipher = None;
+        self.binding = None;
+        self.peer_own_node = false;
         self.tx_nonce = 0;
         self.rx_nonce = 0;
+        self.reset_digests();
+        self.outbox.clear();
+        self.stalled = None;
+        self.heartbeat_sent_at = None;
 
         // Clear chat history
         self.state.read().await.clear_chat();
 
         // Update shared state
-        self.state.write().await.set_status(
-            Status::Disconnected,
-            Some("Disconnected from peer".into()),
-            None,
-        );
+        let mut guard = self.state.write().await;
+        let keep_stats = guard.session_log.keeps_current();
+        guard.stats_history.finish(keep_stats);
+        guard
+            .session_log
+            .finish(reason, error, self.bytes_sent, self.bytes_received);
+        guard.end_data_usage(self.session_bytes());
+        guard.set_outbox_depth(0);
+        guard.close_conversation(reason, confirmed);
+        guard.set_peer_features(None, Vec::new());
+        guard.set_status(Status::Disconnected, Some(message), None);
+        drop(guard);
+        self.publish_paths().await;
+        self.bytes_sent = 0;
+        self.bytes_received = 0;
+        self.heartbeats_sent = 0;
+        self.heartbeats_answered = 0;
+        self.capabilities = Capabilities::default();
+        self.connection_id = None;
 
         info!("Disconnect complete");
-        Ok(())
+    }
+
+    /// Sends Bye over the KCP stream and waits for the peer's `ByeAck`.
+    ///
+    /// The Bye is resent up to `BYE_ATTEMPTS` times. Texts that arrive while
+    /// waiting were sent before the peer saw our Bye and are still delivered.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(true)` - The peer acknowledged, or sent its own Bye at the same time.
+    /// * `Ok(false)` - No acknowledgment arrived.
+    /// * `Err` - The Bye could not be sent or the stream failed.
+    async fn exchange_bye(&mut self) -> Result<bool> {
+        let mut buf = [0u8; 4096];
+        // The Bye promises that everything before it was sent, so it must not
+        // overtake queued messages
+        self.flush_outbox().await?;
+        self.send_transcript_check().await?;
+
+        for attempt in 1..=BYE_ATTEMPTS {
+            self.send_stream_message(&StreamMessage::Bye).await?;
+            self.flush_outbox().await?;
+            debug!("Sent encrypted Bye via KCP (attempt {})", attempt);
+
+            let deadline = Instant::now() + BYE_ACK_WAIT;
+            while let Ok(result) =
+                tokio::time::timeout_at(deadline, self.receive_message(&mut buf)).await
+            {
+                let n = result?;
+                if n == 0 {
+                    bail!("KCP stream closed");
+                }
+                match StreamMessage::decode(&buf[..n]) {
+                    Ok(StreamMessage::ByeAck) => return Ok(true),
+                    // Both sides hung up at once; each Bye confirms the other
+                    Ok(StreamMessage::Bye) => {
+                        let _ = self.send_stream_message(&StreamMessage::ByeAck).await;
+                        return Ok(true);
+                    }
+                    Ok(StreamMessage::Text { text, clock }) => {
+                        self.state.write().await.add_peer_message(text, clock, None);
+                    }
+                    Ok(StreamMessage::ExpiringText {
+                        text,
+                        clock,
+                        ttl_secs,
+                    }) => {
+                        let ttl_secs = ttl_secs.clamp(1, MAX_TTL_SECS);
+                        self.state
+                            .write()
+                            .await
+                            .add_peer_message(text, clock, Some(ttl_secs));
+                    }
+                    Ok(StreamMessage::TranscriptCheck(sent)) => {
+                        self.verify_transcript(sent).await;
+                    }
+                    Ok(_) => {}
+                    Err(e) => debug!("Ignoring undecodable message during Bye: {}", e),
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Waits, up to `KCP_DRAIN_TIMEOUT`, until everything written to the KCP
+    /// stream has been acknowledged by the peer.
+    async fn drain_kcp(&mut self) {
+        let Some(stream) = &mut self.kcp_stream else {
+            return;
+        };
+        // Also sends pending acks, so the peer's own drain is not left waiting
+        if let Err(e) = stream.flush().await {
+            debug!("KCP flush before close failed: {}", e);
+        }
+        let deadline = Instant::now() + KCP_DRAIN_TIMEOUT;
+        while !stream.session().kcp_socket().lock().can_close() {
+            if Instant::now() >= deadline {
+                debug!("KCP send queue not drained before close");
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
     }
 
     /// Closes active KCP stream gracefully.
@@ -431,6 +1478,56 @@ mod tests {
         MessageManager::new(Arc::new(socket), state)
     }
 
+    #[test]
+    fn test_stream_decode_bounds_lengths() {
+        let text = bincode::serialize(&StreamMessage::Text {
+            text: "hi".into(),
+            clock: 1,
+        })
+        .unwrap();
+        assert!(matches!(
+            StreamMessage::decode(&text),
+            Ok(StreamMessage::Text { text: t, clock: 1 }) if t == "hi"
+        ));
+
+        // A Text claiming an enormous length is refused before allocating
+        let mut bomb = 0u32.to_le_bytes().to_vec();
+        bomb.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert!(StreamMessage::decode(&bomb).is_err());
+
+        let mut trailing = text.clone();
+        trailing.push(0);
+        assert!(StreamMessage::decode(&trailing).is_err());
+    }
+
+    #[test]
+    fn test_variant_tags_are_stable() {
+        // Older peers decode by tag; new variants go at the end of the enum
+        let tag = |msg: &StreamMessage| {
+            u32::from_le_bytes(bincode::serialize(msg).unwrap()[..4].try_into().unwrap())
+        };
+        let last = tag(&StreamMessage::WakeResult {
+            id: 0,
+            result: Ok(()),
+        });
+        let expiring = StreamMessage::ExpiringText {
+            text: String::new(),
+            clock: 0,
+            ttl_secs: 0,
+        };
+        assert_eq!(tag(&expiring), last + 1);
+        assert_eq!(tag(&StreamMessage::MessageTtl { ttl_secs: None }), last + 2);
+        let summary = StreamMessage::SyncSummary {
+            fingerprint: String::new(),
+            received: 0,
+        };
+        assert_eq!(tag(&summary), last + 3);
+        assert_eq!(tag(&StreamMessage::OwnNodeProof([0; 32])), last + 4);
+        // As before any of them were added
+        assert_eq!(tag(&StreamMessage::Bye), 1);
+        assert_eq!(tag(&StreamMessage::ByeAck), 9);
+    }
+
     #[tokio::test]
     async fn test_initialization() {
         let manager = create_test_manager().await;
@@ -603,6 +1700,316 @@ mod tests {
         assert!(manager.peer_addr.is_none());
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_races_paths() {
+        let mut manager = create_test_manager().await;
+        let standby = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let standby_addr = standby.local_addr().unwrap();
+        manager.add_path(standby.clone());
+
+        // The peer only knows the standby address, so only that path can win.
+        let peer_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let peer_addr = peer_socket.local_addr().unwrap();
+        let peer_state = create_test_state();
+        let peer = tokio::spawn(async move {
+            handshake::handshake(
+                peer_socket,
+                standby_addr,
+                peer_state,
+                5,
+                EncryptionMode::ChaCha20Poly1305,
+                Capabilities::default(),
+            )
+            .await
+        });
+
+        manager
+            .handshake(peer_addr, 5, EncryptionMode::ChaCha20Poly1305)
+            .await
+            .unwrap();
+        assert!(peer.await.unwrap().is_ok());
+
+        assert_eq!(manager.client_socket.local_addr().unwrap(), standby_addr);
+        assert_eq!(manager.standby_paths().count(), 1);
+        assert_eq!(manager.state.read().await.active_path, Some(standby_addr));
+
+        // Disconnecting falls back to the primary path.
+        manager.disconnect().await.unwrap();
+        assert!(Arc::ptr_eq(&manager.client_socket, &manager.paths[0]));
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_records_session() {
+        let mut manager = create_test_manager().await;
+        let peer: SocketAddr = "127.0.0.1:9999".parse().unwrap();
+
+        manager
+            .state
+            .write()
+            .await
+            .session_log
+            .begin("c1".into(), peer, None, Default::default());
+        manager.peer_addr = Some(peer);
+        manager.bytes_sent = 42;
+
+        manager.disconnect().await.unwrap();
+
+        let guard = manager.state.read().await;
+        let record = guard.session_log.records().last().unwrap();
+        assert_eq!(record.peer, peer);
+        assert_eq!(record.bytes_sent, 42);
+        assert_eq!(
+            record.disconnect_reason,
+            Some(DisconnectReason::LocalRequest)
+        );
+        drop(guard);
+        assert_eq!(manager.bytes_sent, 0);
+    }
+
+    /// Two managers with an established KCP session between them.
+    ///
+    /// The handshake runs on the paused clock, so its linger costs nothing.
+    /// KCP keeps time by the wall clock, so the clock is resumed before the
+    /// upgrade.
+    async fn connected_pair() -> (MessageManager, MessageManager) {
+        let mut alice = create_test_manager().await;
+        let mut bob = create_test_manager().await;
+        let alice_addr = alice.client_socket.local_addr().unwrap();
+        let bob_addr = bob.client_socket.local_addr().unwrap();
+
+        let (a, b) = tokio::join!(
+            alice.handshake(bob_addr, 5, EncryptionMode::ChaCha20Poly1305),
+            bob.handshake(alice_addr, 5, EncryptionMode::ChaCha20Poly1305)
+        );
+        a.unwrap();
+        b.unwrap();
+        tokio::time::resume();
+        alice.upgrade_to_kcp().await.unwrap();
+        bob.upgrade_to_kcp().await.unwrap();
+        (alice, bob)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bye_is_acknowledged_after_last_messages() {
+        let (mut alice, mut bob) = connected_pair().await;
+
+        // Bob's last words are still in flight when Alice hangs up
+        bob.send_text("last words".into()).await.unwrap();
+        let bob_side = async {
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = bob.receive_message(&mut buf).await.unwrap();
+                if let Ok(StreamMessage::Bye) = bincode::deserialize(&buf[..n]) {
+                    break;
+                }
+            }
+            bob.disconnect_on_bye_received().await.unwrap();
+        };
+
+        let (confirmed, ()) = tokio::join!(alice.disconnect(), bob_side);
+        assert!(confirmed.unwrap());
+        assert!(alice.state.read().await.has_message(MessageId {
+            from_me: false,
+            seq: 0,
+        }));
+        assert!(!bob.is_connected());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_transcript_check_detects_divergence() {
+        let (mut alice, mut bob) = connected_pair().await;
+        let mut buf = [0u8; 4096];
+
+        alice.send_text("hello".into()).await.unwrap();
+        assert!(alice.send_transcript_check().await.unwrap());
+        // A frame that never reaches Bob
+        alice.tx_digest.update(b"lost");
+        assert!(alice.send_transcript_check().await.unwrap());
+
+        let mut results = Vec::new();
+        while results.len() < 2 {
+            let n = bob.receive_message(&mut buf).await.unwrap();
+            if let Ok(StreamMessage::TranscriptCheck(sent)) = StreamMessage::decode(&buf[..n]) {
+                results.push(bob.verify_transcript(sent).await);
+            }
+        }
+        assert_eq!(results, vec![true, false]);
+        let check = bob.state.read().await.transcript_check.clone().unwrap();
+        assert_eq!((check.sent, check.received, check.matched), (3, 2, false));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_control_frames_overtake_backlog() {
+        let (mut alice, mut bob) = connected_pair().await;
+
+        // More file reads than the KCP send window holds
+        let filler = "x".repeat(1000);
+        for id in 0..200 {
+            alice
+                .send_share_reply(id, Err(filler.clone()))
+                .await
+                .unwrap();
+        }
+        assert!(alice.has_backlog());
+        alice.send_ping(7).await.unwrap();
+
+        // Alice keeps feeding KCP while Bob reads, as the main loop would
+        let mut buf = [0u8; 4096];
+        let mut replies_before_ping = 0;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            assert!(Instant::now() < deadline, "Ping never arrived");
+            let read = Duration::from_millis(10);
+            if let Ok(result) = tokio::time::timeout(read, bob.receive_message(&mut buf)).await {
+                let n = result.unwrap();
+                match bincode::deserialize(&buf[..n]).unwrap() {
+                    StreamMessage::Ping(7) => break,
+                    StreamMessage::ShareReply { id, .. } => {
+                        assert_eq!(id, replies_before_ping);
+                        replies_before_ping += 1;
+                    }
+                    other => panic!("Unexpected message: {:?}", other),
+                }
+            }
+            alice.pump_outbox().await.unwrap();
+        }
+        assert!(replies_before_ping < 200);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_oversized_frames_are_refused() {
+        let (mut alice, mut bob) = connected_pair().await;
+
+        // Would not fit in Bob's receive buffer
+        let huge = "x".repeat(MAX_FRAME_LEN);
+        assert!(alice.send_share_reply(1, Err(huge)).await.is_err());
+        assert_eq!(alice.tx_nonce, 0);
+        assert!(alice.seal(&[0u8; MAX_FRAME_LEN]).is_err());
+        assert_eq!(alice.tx_nonce, 0);
+
+        // The session is still in step
+        alice.send_ping(3).await.unwrap();
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        let n = bob.receive_message(&mut buf).await.unwrap();
+        assert!(matches!(
+            bincode::deserialize(&buf[..n]).unwrap(),
+            StreamMessage::Ping(3)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_own_node_proof_is_exchanged() {
+        let mut alice = create_test_manager().await;
+        let mut bob = create_test_manager().await;
+        alice.set_own_node_secret(Some("shared".into()));
+        bob.set_own_node_secret(Some("shared".into()));
+        let alice_addr = alice.client_socket.local_addr().unwrap();
+        let bob_addr = bob.client_socket.local_addr().unwrap();
+        let (a, b) = tokio::join!(
+            alice.handshake(bob_addr, 5, EncryptionMode::ChaCha20Poly1305),
+            bob.handshake(alice_addr, 5, EncryptionMode::ChaCha20Poly1305)
+        );
+        a.unwrap();
+        b.unwrap();
+        tokio::time::resume();
+        alice.upgrade_to_kcp().await.unwrap();
+        bob.upgrade_to_kcp().await.unwrap();
+
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        let n = bob.receive_message(&mut buf).await.unwrap();
+        let StreamMessage::OwnNodeProof(proof) = bincode::deserialize(&buf[..n]).unwrap() else {
+            panic!("expected an own-node proof");
+        };
+        assert!(!bob.peer_is_own_node());
+        assert!(bob.accept_own_node_proof(&proof));
+        assert!(bob.peer_is_own_node());
+        // Alice's proof says nothing about Bob, nor does it match another secret
+        assert!(!alice.accept_own_node_proof(&proof));
+        bob.set_own_node_secret(Some("other".into()));
+        assert!(!bob.accept_own_node_proof(&proof));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bye_without_ack_is_assumed() {
+        let (mut alice, _bob) = connected_pair().await;
+
+        // Bob never reads, so never acknowledges
+        assert!(!alice.disconnect().await.unwrap());
+        assert!(!alice.is_connected());
+        assert_eq!(alice.state.read().await.status, Status::Disconnected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_silent_peer_is_abandoned() {
+        let (mut alice, mut bob) = connected_pair().await;
+        assert!(alice.idle_for() < Duration::from_secs(1));
+
+        // Anything from the peer counts as a sign of life
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        bob.send_ping(7).await.unwrap();
+        let mut buf = [0u8; 4096];
+        alice.receive_message(&mut buf).await.unwrap();
+        assert!(alice.idle_for() < Duration::from_millis(50));
+
+        alice
+            .abandon(StatusMessage::PeerUnresponsive { idle_secs: 30 })
+            .await;
+
+        assert!(!alice.is_connected());
+        let guard = alice.state.read().await;
+        assert_eq!(guard.status, Status::Disconnected);
+        let record = guard.session_log.records().last().unwrap();
+        assert_eq!(
+            record.disconnect_reason,
+            Some(DisconnectReason::PeerTimeout)
+        );
+        assert_eq!(
+            record.error.as_deref(),
+            Some("Peer stopped responding for 30 s")
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_time_follows_the_clock() {
+        let manager = create_test_manager().await;
+        assert_eq!(manager.idle_for(), Duration::ZERO);
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(manager.idle_for(), Duration::from_secs(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancelled_handshake_is_recorded() {
+        let mut manager = create_test_manager().await;
+        // Nobody answers on this port, so the handshake would run until its timeout
+        let peer: SocketAddr = "127.0.0.1:9".parse().unwrap();
+
+        let task = tokio::spawn(
+            manager
+                .start_handshake(peer, 30, EncryptionMode::ChaCha20Poly1305)
+                .await,
+        );
+        tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+        task.abort();
+        manager
+            .cancel_handshake(
+                DisconnectReason::LocalRequest,
+                StatusMessage::HandshakeCancelled,
+            )
+            .await;
+
+        let guard = manager.state.read().await;
+        assert_eq!(guard.status, Status::Disconnected);
+        let record = guard.session_log.records().last().unwrap();
+        assert_eq!(record.peer, peer);
+        assert_eq!(
+            record.disconnect_reason,
+            Some(DisconnectReason::LocalRequest)
+        );
+        drop(guard);
+        assert!(manager.peer_addr.is_none());
+    }
+
     #[tokio::test]
     async fn test_close_kcp_with_none_stream() {
         let mut manager = create_test_manager().await;
@@ -1,3 +1,17 @@
+pub mod broadcast;
+pub mod chat_command;
+pub mod connect;
+pub mod cookie;
 pub mod crypto;
+pub mod expiry;
 pub mod handshake;
+pub mod history_sync;
+pub mod incoming;
+pub mod lamport;
 pub mod message_manager;
+pub mod outbox;
+pub mod padding;
+pub mod ping;
+pub mod reactions;
+pub mod session_digest;
+pub mod text_limit;
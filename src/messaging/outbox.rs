@@ -0,0 +1,142 @@
+//! Priority queue for outgoing stream messages.
+//!
+//! Messages wait here while the KCP send window is full. When room frees up,
+//! control frames go first, then chat, then bulk data, so a ping or a Bye is
+//! never stuck behind a backlog of file reads. A lower class that keeps being
+//! passed over is served anyway after `MAX_PASSED_OVER` sends.
+
+use std::collections::VecDeque;
+
+/// Times a waiting message class may be passed over before it is served.
+const MAX_PASSED_OVER: u32 = 8;
+
+/// Traffic class of an outgoing message, most urgent first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Pings, pongs and disconnect frames.
+    Control,
+    /// Text, reactions and requests to the peer.
+    Chat,
+    /// Large replies such as shared file reads and command output.
+    Bulk,
+}
+
+impl Priority {
+    const ALL: [Priority; 3] = [Priority::Control, Priority::Chat, Priority::Bulk];
+}
+
+/// Messages waiting to be encrypted and written to the stream.
+#[derive(Debug)]
+pub struct Outbox<T> {
+    queues: [VecDeque<T>; 3],
+    /// Sends that went to a more urgent class while this one was waiting.
+    passed_over: [u32; 3],
+}
+
+impl<T> Default for Outbox<T> {
+    fn default() -> Self {
+        Self {
+            queues: Default::default(),
+            passed_over: [0; 3],
+        }
+    }
+}
+
+impl<T> Outbox<T> {
+    /// Queues a message behind others of the same class.
+    pub fn push(&mut self, priority: Priority, payload: T) {
+        self.queues[priority as usize].push_back(payload);
+    }
+
+    /// Takes the next message to send.
+    ///
+    /// # Returns
+    ///
+    /// The oldest message of the most urgent class, unless a lower class has
+    /// been passed over `MAX_PASSED_OVER` times; then that class goes first.
+    pub fn pop(&mut self) -> Option<(Priority, T)> {
+        let waiting = |p: &Priority| !self.queues[*p as usize].is_empty();
+        let starved = Priority::ALL
+            .into_iter()
+            .rev()
+            .filter(waiting)
+            .find(|p| self.passed_over[*p as usize] >= MAX_PASSED_OVER);
+        let next = starved.or_else(|| Priority::ALL.into_iter().find(waiting))?;
+
+        for lower in &Priority::ALL[next as usize + 1..] {
+            if waiting(lower) {
+                self.passed_over[*lower as usize] += 1;
+            }
+        }
+        self.passed_over[next as usize] = 0;
+        let payload = self.queues[next as usize].pop_front()?;
+        Some((next, payload))
+    }
+
+    /// Number of queued messages.
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+
+    /// Returns true if nothing is queued.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops everything queued.
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(outbox: &mut Outbox<u8>) -> Vec<(Priority, u8)> {
+        std::iter::from_fn(|| outbox.pop()).collect()
+    }
+
+    #[test]
+    fn test_urgent_classes_go_first() {
+        let mut outbox = Outbox::default();
+        outbox.push(Priority::Bulk, 1);
+        outbox.push(Priority::Chat, 2);
+        outbox.push(Priority::Bulk, 3);
+        outbox.push(Priority::Control, 4);
+        outbox.push(Priority::Chat, 5);
+        assert_eq!(outbox.len(), 5);
+
+        assert_eq!(
+            drain(&mut outbox),
+            vec![
+                (Priority::Control, 4),
+                (Priority::Chat, 2),
+                (Priority::Chat, 5),
+                (Priority::Bulk, 1),
+                (Priority::Bulk, 3),
+            ]
+        );
+        assert!(outbox.is_empty());
+    }
+
+    #[test]
+    fn test_starved_class_is_served() {
+        let mut outbox = Outbox::default();
+        outbox.push(Priority::Bulk, 0);
+        for i in 1..=20 {
+            outbox.push(Priority::Chat, i);
+        }
+
+        let order = drain(&mut outbox);
+        let bulk_at = order.iter().position(|(p, _)| *p == Priority::Bulk);
+        assert_eq!(bulk_at, Some(MAX_PASSED_OVER as usize));
+        // Chat keeps its own order around the bulk message
+        let chat: Vec<u8> = order
+            .iter()
+            .filter(|(p, _)| *p == Priority::Chat)
+            .map(|(_, i)| *i)
+            .collect();
+        assert_eq!(chat, (1..=20).collect::<Vec<u8>>());
+    }
+}
@@ -0,0 +1,102 @@
+//! Length hiding for GhostLink.
+//!
+//! Pads application frames to randomised bucket sizes before encryption so
+//! packet lengths on the wire don't reveal what kind of message is being sent.
+//!
+//! This hides lengths only. It does not disguise the protocol: the padding
+//! sits inside the encrypted payload, and KCP's segment header (conversation
+//! id, command, sequence numbers) still travels in the clear beneath it.
+//!
+//! Disguising the wire image itself (header scrambling, or a WireGuard-like
+//! datagram layout) would have to happen beneath KCP, and this tree has no
+//! place for that yet. `tokio_kcp` reads and writes the UDP socket it is
+//! given directly, so every datagram would need to pass through a custom
+//! transport or a local relay socket first. Until one exists, a DPI
+//! middlebox can still recognise the traffic as KCP.
+
+use anyhow::{Result, bail};
+use rand_core::{OsRng, RngCore};
+
+/// Frames are padded to a multiple of this many bytes.
+const BUCKET_SIZE: usize = 128;
+
+/// Up to this many extra buckets are appended at random.
+const MAX_EXTRA_BUCKETS: u32 = 2;
+
+/// Length prefix carrying the real payload size.
+const HEADER_LEN: usize = 4;
+
+/// Wraps `payload` in a length-prefixed frame padded to a random bucket size.
+///
+/// The padding is zeroes; it is only ever sent encrypted.
+pub fn pad(payload: &[u8]) -> Vec<u8> {
+    let min_len = HEADER_LEN + payload.len();
+    let extra = (OsRng.next_u32() % (MAX_EXTRA_BUCKETS + 1)) as usize;
+    let target_len = (min_len.div_ceil(BUCKET_SIZE) + extra) * BUCKET_SIZE;
+
+    let mut frame = Vec::with_capacity(target_len);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame.resize(target_len, 0);
+    frame
+}
+
+/// Returns the largest payload whose padded frame never exceeds `frame_len`.
+pub const fn max_payload(frame_len: usize) -> usize {
+    (frame_len / BUCKET_SIZE - MAX_EXTRA_BUCKETS as usize) * BUCKET_SIZE - HEADER_LEN
+}
+
+/// Extracts the payload from a frame produced by [`pad`].
+///
+/// # Returns
+///
+/// * `Ok(&[u8])` - The original payload.
+/// * `Err` - The frame is truncated or its length prefix is inconsistent.
+pub fn unpad(frame: &[u8]) -> Result<&[u8]> {
+    if frame.len() < HEADER_LEN {
+        bail!("Padded frame too short");
+    }
+
+    let mut header = [0u8; HEADER_LEN];
+    header.copy_from_slice(&frame[..HEADER_LEN]);
+    let len = u32::from_be_bytes(header) as usize;
+
+    // The prefix comes from the peer; on 32-bit targets it can overflow
+    let Some(end) = HEADER_LEN.checked_add(len) else {
+        bail!("Padded frame length prefix exceeds frame size");
+    };
+    match frame.get(HEADER_LEN..end) {
+        Some(payload) => Ok(payload),
+        None => bail!("Padded frame length prefix exceeds frame size"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pad_roundtrip() {
+        for len in [0, 1, 123, 124, 125, 1000] {
+            let payload = vec![0xAB; len];
+            let frame = pad(&payload);
+            assert_eq!(unpad(&frame).unwrap(), payload.as_slice());
+        }
+    }
+
+    #[test]
+    fn test_pad_uses_bucket_sizes() {
+        let frame = pad(b"hello");
+        assert_eq!(frame.len() % BUCKET_SIZE, 0);
+        assert!(frame.len() <= (1 + MAX_EXTRA_BUCKETS as usize) * BUCKET_SIZE);
+    }
+
+    #[test]
+    fn test_unpad_rejects_bad_frames() {
+        assert!(unpad(&[0, 0]).is_err());
+
+        let mut frame = pad(b"hello");
+        frame[..HEADER_LEN].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(unpad(&frame).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+//! Application-level ping over an established session.
+//!
+//! Pings travel inside the encrypted KCP stream, so the measured RTT includes
+//! KCP retransmissions and is what chat traffic actually experiences.
+
+use serde::Serialize;
+use tokio::{
+    sync::oneshot,
+    time::{Duration, Instant},
+};
+
+/// Pings sent when a run does not say how many.
+pub const DEFAULT_PING_COUNT: u32 = 5;
+
+/// Largest ping run accepted.
+pub const MAX_PING_COUNT: u32 = 100;
+
+/// Maximum time a whole ping run may take before it is abandoned.
+pub const PING_RUN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Sequence number of liveness heartbeats. Ping runs count up from 0 and
+/// never get this far, so a heartbeat's pong is ignored by any active run.
+pub const HEARTBEAT_SEQ: u32 = u32::MAX;
+
+/// Round-trip statistics for a ping run, in milliseconds.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PingStats {
+    pub sent: u32,
+    pub received: u32,
+    pub min_ms: f64,
+    pub avg_ms: f64,
+    pub max_ms: f64,
+    /// Mean absolute difference between consecutive RTTs.
+    pub jitter_ms: f64,
+}
+
+impl PingStats {
+    /// Computes statistics from the RTTs of answered pings.
+    ///
+    /// # Arguments
+    ///
+    /// * `sent` - Number of pings sent.
+    /// * `samples` - RTT of each answered ping, in send order.
+    pub fn from_samples(sent: u32, samples: &[Duration]) -> Self {
+        let ms: Vec<f64> = samples.iter().map(|d| d.as_secs_f64() * 1000.0).collect();
+
+        let (min_ms, max_ms, avg_ms) = if ms.is_empty() {
+            (0.0, 0.0, 0.0)
+        } else {
+            (
+                ms.iter().copied().fold(f64::INFINITY, f64::min),
+                ms.iter().copied().fold(0.0, f64::max),
+                ms.iter().sum::<f64>() / ms.len() as f64,
+            )
+        };
+
+        let jitter_ms = if ms.len() < 2 {
+            0.0
+        } else {
+            ms.windows(2).map(|w| (w[1] - w[0]).abs()).sum::<f64>() / (ms.len() - 1) as f64
+        };
+
+        Self {
+            sent,
+            received: samples.len() as u32,
+            min_ms,
+            avg_ms,
+            max_ms,
+            jitter_ms,
+        }
+    }
+}
+
+/// Reply channel for a ping run.
+pub type PingReply = oneshot::Sender<Result<PingStats, String>>;
+
+/// An in-progress ping run. One ping is outstanding at a time.
+#[derive(Debug)]
+pub struct PingProbe {
+    count: u32,
+    /// Sequence number of the outstanding ping.
+    seq: u32,
+    sent_at: Instant,
+    samples: Vec<Duration>,
+    /// The run is abandoned after this instant.
+    pub deadline: Instant,
+    /// Operation tracking this run.
+    operation: u64,
+    reply: PingReply,
+}
+
+impl PingProbe {
+    /// Starts a run of `count` pings. The caller sends ping 0 right away.
+    pub fn new(count: u32, operation: u64, reply: PingReply) -> Self {
+        let now = Instant::now();
+        Self {
+            count,
+            seq: 0,
+            sent_at: now,
+            samples: Vec::with_capacity(count as usize),
+            deadline: now + PING_RUN_TIMEOUT,
+            operation,
+            reply,
+        }
+    }
+
+    /// Sequence number of the outstanding ping.
+    pub fn seq(&self) -> u32 {
+        self.seq
+    }
+
+    /// Records a pong.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(seq)` - Next ping to send.
+    /// * `None` - The pong was stale, or the run is complete (see `is_complete`).
+    pub fn on_pong(&mut self, seq: u32) -> Option<u32> {
+        if seq != self.seq {
+            return None;
+        }
+
+        self.samples.push(self.sent_at.elapsed());
+        if self.is_complete() {
+            return None;
+        }
+
+        self.seq += 1;
+        self.sent_at = Instant::now();
+        Some(self.seq)
+    }
+
+    /// Round-trip time of the latest answered ping.
+    pub fn last_rtt(&self) -> Option<Duration> {
+        self.samples.last().copied()
+    }
+
+    /// Operation tracking this run.
+    pub fn operation(&self) -> u64 {
+        self.operation
+    }
+
+    /// Pings answered so far, and pings in the run.
+    pub fn progress(&self) -> (u32, u32) {
+        (self.samples.len() as u32, self.count)
+    }
+
+    /// Returns true once every ping has been answered.
+    pub fn is_complete(&self) -> bool {
+        self.samples.len() as u32 >= self.count
+    }
+
+    /// Sends the statistics to the requester.
+    pub fn finish(self) {
+        let stats = PingStats::from_samples(self.seq + 1, &self.samples);
+        let _ = self.reply.send(Ok(stats));
+    }
+
+    /// Aborts the run with an error.
+    pub fn fail(self, reason: &str) {
+        let _ = self.reply.send(Err(reason.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_from_samples() {
+        let samples = [10, 20, 15].map(Duration::from_millis);
+        let stats = PingStats::from_samples(3, &samples);
+
+        assert_eq!(stats.received, 3);
+        assert_eq!(stats.min_ms, 10.0);
+        assert_eq!(stats.max_ms, 20.0);
+        assert_eq!(stats.avg_ms, 15.0);
+        // |20-10| and |15-20| average to 7.5
+        assert_eq!(stats.jitter_ms, 7.5);
+    }
+
+    #[test]
+    fn test_stats_empty() {
+        let stats = PingStats::from_samples(4, &[]);
+        assert_eq!(stats.sent, 4);
+        assert_eq!(stats.received, 0);
+        assert_eq!(stats.avg_ms, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_probe_sequence() {
+        let (tx, rx) = oneshot::channel();
+        let mut probe = PingProbe::new(2, 1, tx);
+
+        assert_eq!(probe.on_pong(5), None); // stale
+        assert_eq!(probe.on_pong(0), Some(1));
+        assert!(!probe.is_complete());
+        assert_eq!(probe.progress(), (1, 2));
+        assert_eq!(probe.on_pong(1), None);
+        assert!(probe.is_complete());
+
+        probe.finish();
+        let stats = rx.await.unwrap().unwrap();
+        assert_eq!(stats.sent, 2);
+        assert_eq!(stats.received, 2);
+    }
+}
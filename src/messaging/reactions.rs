@@ -0,0 +1,165 @@
+//! Emoji reactions on chat messages.
+//!
+//! Messages have no IDs on the wire. Both sides number the texts of a
+//! conversation in the order they were sent, which the KCP stream preserves,
+//! so "the peer's third message" means the same message on both ends. A
+//! reaction names its target from the reacting side's point of view; the
+//! receiver flips it.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Longest reaction accepted, in bytes. Enough for ZWJ sequences and flags.
+pub const MAX_EMOJI_LEN: usize = 32;
+
+/// Reactions one side may keep on a single message.
+pub const MAX_REACTIONS_PER_SIDE: usize = 8;
+
+/// Identifies a message within the current conversation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct MessageId {
+    /// True if the local user wrote the message.
+    pub from_me: bool,
+    /// Position among the messages written by that side, starting at 0.
+    pub seq: u64,
+}
+
+impl MessageId {
+    /// Returns the same message as seen from the other peer.
+    pub fn flipped(self) -> Self {
+        Self {
+            from_me: !self.from_me,
+            seq: self.seq,
+        }
+    }
+}
+
+/// A reaction left on a message.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Reaction {
+    pub emoji: String,
+    /// True if the local user reacted.
+    pub from_me: bool,
+}
+
+/// Reactions in the current conversation.
+#[derive(Debug, Clone, Default)]
+pub struct Reactions {
+    entries: HashMap<MessageId, Vec<Reaction>>,
+}
+
+impl Reactions {
+    /// Drops every reaction on a message.
+    pub fn remove(&mut self, message_id: MessageId) {
+        self.entries.remove(&message_id);
+    }
+
+    /// Adds or removes a reaction.
+    ///
+    /// # Returns
+    ///
+    /// True if the reactions on the message changed.
+    pub fn apply(&mut self, message_id: MessageId, emoji: &str, from_me: bool, add: bool) -> bool {
+        let list = self.entries.entry(message_id).or_default();
+        let existing = list
+            .iter()
+            .position(|r| r.emoji == emoji && r.from_me == from_me);
+
+        match (add, existing) {
+            (true, None) => {
+                if list.iter().filter(|r| r.from_me == from_me).count() >= MAX_REACTIONS_PER_SIDE {
+                    return false;
+                }
+                list.push(Reaction {
+                    emoji: emoji.to_string(),
+                    from_me,
+                });
+                true
+            }
+            (false, Some(index)) => {
+                list.remove(index);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Reactions on `message_id`, oldest first.
+    pub fn on(&self, message_id: MessageId) -> &[Reaction] {
+        self.entries.get(&message_id).map_or(&[], Vec::as_slice)
+    }
+
+    /// Forgets all reactions.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// Normalises a user- or peer-supplied reaction.
+///
+/// # Returns
+///
+/// * `Ok(String)` - The trimmed emoji.
+/// * `Err(String)` - The reaction is empty, too long, or contains text.
+pub fn validate_emoji(emoji: &str) -> Result<String, String> {
+    let emoji = emoji.trim();
+    if emoji.is_empty() {
+        return Err("Reaction must not be empty".into());
+    }
+    if emoji.len() > MAX_EMOJI_LEN {
+        return Err(format!("Reaction must be at most {} bytes", MAX_EMOJI_LEN));
+    }
+    if emoji
+        .chars()
+        .any(|c| c.is_ascii_alphabetic() || c.is_whitespace() || c.is_control())
+    {
+        return Err("Reaction must be an emoji".into());
+    }
+    Ok(emoji.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSG: MessageId = MessageId {
+        from_me: false,
+        seq: 2,
+    };
+
+    #[test]
+    fn test_apply_toggles_per_side() {
+        let mut reactions = Reactions::default();
+
+        assert!(reactions.apply(MSG, "👍", true, true));
+        assert!(!reactions.apply(MSG, "👍", true, true)); // already there
+        assert!(reactions.apply(MSG, "👍", false, true)); // peer's own reaction
+        assert_eq!(reactions.on(MSG).len(), 2);
+
+        assert!(reactions.apply(MSG, "👍", true, false));
+        assert!(!reactions.apply(MSG, "👍", true, false));
+        assert!(!reactions.on(MSG)[0].from_me);
+        assert!(reactions.on(MSG.flipped()).is_empty());
+    }
+
+    #[test]
+    fn test_apply_caps_reactions_per_side() {
+        let mut reactions = Reactions::default();
+        for i in 0..MAX_REACTIONS_PER_SIDE {
+            assert!(reactions.apply(MSG, &i.to_string(), false, true));
+        }
+
+        assert!(!reactions.apply(MSG, "🎉", false, true));
+        assert!(reactions.apply(MSG, "🎉", true, true));
+    }
+
+    #[test]
+    fn test_validate_emoji() {
+        assert_eq!(validate_emoji(" 👍 "), Ok("👍".to_string()));
+        assert!(validate_emoji("👨‍👩‍👧").is_ok());
+        assert!(validate_emoji("").is_err());
+        assert!(validate_emoji("lol").is_err());
+        assert!(validate_emoji("👍 👍").is_err());
+        assert!(validate_emoji(&"👍".repeat(9)).is_err());
+    }
+}
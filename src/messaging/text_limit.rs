@@ -0,0 +1,110 @@
+//! How much text fits in one message, and splitting longer text.
+//!
+//! Both sides read session messages into a `MAX_FRAME_LEN` buffer, so a
+//! sealed frame must not exceed it. What remains for text depends on
+//! whether traffic padding was negotiated: padding rounds frames up to a
+//! bucket and may add more.
+
+use super::{message_manager::MAX_FRAME_LEN, padding};
+
+/// Authentication tag added by the session cipher.
+pub const TAG_LEN: usize = 16;
+
+/// Bytes bincode adds around the text of an `ExpiringText`, the larger
+/// text message: variant tag, text length, clock and TTL.
+const TEXT_OVERHEAD: usize = 4 + 8 + 8 + 4;
+
+/// Most text bytes one message can carry, whatever was negotiated.
+pub const MAX_TEXT_LEN: usize = max_text_len(true);
+
+/// Returns the largest serialized message that fits in a sealed frame.
+///
+/// # Arguments
+///
+/// * `padding` - True if traffic padding was negotiated.
+pub const fn max_payload_len(padding: bool) -> usize {
+    let payload = MAX_FRAME_LEN - TAG_LEN;
+    if padding {
+        padding::max_payload(payload)
+    } else {
+        payload
+    }
+}
+
+/// Returns the most text bytes one message can carry in a session.
+///
+/// # Arguments
+///
+/// * `padding` - True if traffic padding was negotiated.
+pub const fn max_text_len(padding: bool) -> usize {
+    max_payload_len(padding) - TEXT_OVERHEAD
+}
+
+/// Splits `text` into pieces of at most `max_len` bytes.
+///
+/// Pieces end after a line break or space where there is one in the second
+/// half of the piece, so words stay whole; otherwise at a character
+/// boundary. Nothing is dropped: the pieces joined give back `text`.
+pub fn split(text: &str, max_len: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while rest.len() > max_len {
+        let mut end = max_len;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let window = &rest[..end];
+        let cut = window
+            .rfind('\n')
+            .filter(|&i| i >= end / 2)
+            .or_else(|| window.rfind(' ').filter(|&i| i >= end / 2))
+            .map_or(end, |i| i + 1);
+        pieces.push(&rest[..cut]);
+        rest = &rest[cut..];
+    }
+    if !rest.is_empty() || pieces.is_empty() {
+        pieces.push(rest);
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messaging::message_manager::StreamMessage;
+
+    #[test]
+    fn test_longest_text_fits_a_frame() {
+        let msg = StreamMessage::ExpiringText {
+            text: "x".repeat(MAX_TEXT_LEN),
+            clock: u64::MAX,
+            ttl_secs: u32::MAX,
+        };
+        let payload = bincode::serialize(&msg).unwrap();
+        for _ in 0..50 {
+            assert!(padding::pad(&payload).len() + TAG_LEN <= MAX_FRAME_LEN);
+        }
+        assert_eq!(payload.len() - MAX_TEXT_LEN, TEXT_OVERHEAD);
+        assert_eq!(max_text_len(false) + TEXT_OVERHEAD + TAG_LEN, MAX_FRAME_LEN);
+    }
+
+    #[test]
+    fn test_split_keeps_words_and_characters_whole() {
+        assert_eq!(split("short", 10), vec!["short"]);
+        assert_eq!(split("", 10), vec![""]);
+        assert_eq!(
+            split("hello world again", 12),
+            vec!["hello world ", "again"]
+        );
+        assert_eq!(
+            split("line one\nline two", 12),
+            vec!["line one\n", "line two"]
+        );
+        // No break in the second half: cut at the limit
+        assert_eq!(split("a abcdefghij", 6), vec!["a abcd", "efghij"]);
+        // Multi-byte characters are not cut
+        let pieces = split("ééééé", 3);
+        assert_eq!(pieces, vec!["é", "é", "é", "é", "é"]);
+        assert_eq!(pieces.concat(), "ééééé");
+    }
+}
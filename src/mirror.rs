@@ -0,0 +1,143 @@
+//! Mirroring between your own nodes.
+//!
+//! A peer whose saved contact label is listed in `mirror.peers` is another
+//! node of the same user; pairing two nodes means listing each other there
+//! and configuring the same `own_node_secret`. Once a paired node has proven
+//! in the session that it knows the secret, each side sends what its
+//! settings select, one item per message over the encrypted session: saved
+//! contacts, finished session records, or both. The receiver merges what it
+//! gets, but only if it has paired the sender too. Chat text is not kept past
+//! a session on either node, so there is none to mirror.
+
+use crate::{audit::SessionRecord, contacts::Contact};
+use serde::{Deserialize, Serialize};
+
+/// Which of your own nodes this node mirrors with, and what it sends them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MirrorSettings {
+    /// Contact labels of your own other nodes. Empty disables mirroring.
+    pub peers: Vec<String>,
+    /// Send saved contacts.
+    pub contacts: bool,
+    /// Send finished session records.
+    pub sessions: bool,
+}
+
+impl Default for MirrorSettings {
+    fn default() -> Self {
+        Self {
+            peers: Vec::new(),
+            contacts: true,
+            sessions: true,
+        }
+    }
+}
+
+impl MirrorSettings {
+    /// Returns true if the peer saved as `peer_label` is a paired node.
+    ///
+    /// Peers without a saved contact never are.
+    pub fn paired(&self, peer_label: Option<&str>) -> bool {
+        peer_label.is_some_and(|label| self.peers.iter().any(|p| p == label))
+    }
+
+    /// Returns the items to send a paired node, contacts first.
+    ///
+    /// Retention overrides stay behind: the paired node keeps mirrored
+    /// records as its own policy says.
+    pub fn outgoing<'a>(
+        &self,
+        contacts: &[Contact],
+        sessions: impl Iterator<Item = &'a SessionRecord>,
+    ) -> Vec<MirrorItem> {
+        let mut items = Vec::new();
+        if self.contacts {
+            items.extend(contacts.iter().cloned().map(MirrorItem::Contact));
+        }
+        if self.sessions {
+            items.extend(sessions.map(|record| {
+                MirrorItem::Session(SessionRecord {
+                    retention: None,
+                    ..record.clone()
+                })
+            }));
+        }
+        items
+    }
+}
+
+/// One mirrored entry, small enough for a single stream message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum MirrorItem {
+    Contact(Contact),
+    Session(SessionRecord),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        audit::{DisconnectReason, SessionLog},
+        messaging::message_manager::StreamMessage,
+        retention::Retention,
+        web::shared_state::NatType,
+    };
+    use bincode::Options;
+
+    #[test]
+    fn test_sends_only_selected_items_to_paired_nodes() {
+        let contact = Contact {
+            label: "Bob".into(),
+            addr: "203.0.113.7:41234".parse().unwrap(),
+            added_at: 100,
+            endpoints: Vec::new(),
+            hostname: None,
+            wake: None,
+        };
+        let mut settings = MirrorSettings {
+            peers: vec!["Laptop".into()],
+            contacts: true,
+            sessions: false,
+        };
+        assert!(settings.paired(Some("Laptop")));
+        assert!(!settings.paired(Some("Bob")));
+        assert!(!settings.paired(None));
+
+        let items = settings.outgoing(std::slice::from_ref(&contact), std::iter::empty());
+        assert_eq!(items, [MirrorItem::Contact(contact)]);
+
+        settings.contacts = false;
+        assert!(settings.outgoing(&[], std::iter::empty()).is_empty());
+    }
+
+    #[test]
+    fn test_session_records_cross_the_stream() {
+        let mut log = SessionLog::default();
+        log.begin(
+            "c1".into(),
+            "203.0.113.7:41234".parse().unwrap(),
+            None,
+            NatType::Cone,
+        );
+        log.set_retention(Some(Retention::Days(3)));
+        log.finish(DisconnectReason::PeerRequest, None, 1, 2);
+
+        let settings = MirrorSettings::default();
+        let items = settings.outgoing(&[], log.records());
+        let MirrorItem::Session(record) = &items[0] else {
+            panic!("expected a session record");
+        };
+        assert_eq!(record.retention, None);
+        assert_eq!(record.bytes_received, 2);
+
+        let item = items[0].clone();
+        let bytes = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .serialize(&StreamMessage::Mirror(item.clone()))
+            .unwrap();
+        match StreamMessage::decode(&bytes).unwrap() {
+            StreamMessage::Mirror(decoded) => assert_eq!(decoded, item),
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Cache of the last STUN results.
+//!
+//! A quick restart on the same network shows the previous public address and
+//! NAT type immediately instead of waiting for two STUN round trips. The
+//! values are re-checked by the first keep-alive once the controller runs.
+
+use crate::{
+    storage::{read_json, unix_timestamp, write_json},
+    web::shared_state::NatType,
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, path::Path};
+use tracing::warn;
+
+/// Last observed NAT mapping.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NatCache {
+    pub public_ip: SocketAddr,
+    pub nat_type: NatType,
+    /// Local port the mapping belongs to. A different port means a different mapping.
+    pub local_port: u16,
+    /// Unix timestamp (seconds) the mapping was last confirmed.
+    pub observed_at: u64,
+    /// Seconds after `observed_at` the mapping is assumed to have expired.
+    pub ttl_secs: u64,
+}
+
+impl NatCache {
+    /// Creates an entry observed now.
+    pub fn new(public_ip: SocketAddr, nat_type: NatType, local_port: u16, ttl_secs: u64) -> Self {
+        Self {
+            public_ip,
+            nat_type,
+            local_port,
+            observed_at: unix_timestamp(),
+            ttl_secs,
+        }
+    }
+
+    /// Loads the cache file.
+    ///
+    /// A missing or unreadable file yields `None`; the cache is only an optimisation.
+    pub fn load(path: &Path) -> Option<Self> {
+        match read_json(path) {
+            Ok(cache) => cache,
+            Err(e) => {
+                warn!("Ignoring NAT cache: {:#}", e);
+                None
+            }
+        }
+    }
+
+    /// Writes the cache file.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        write_json(path, self)
+    }
+
+    /// Returns true if the entry can stand in for a fresh STUN query.
+    ///
+    /// # Arguments
+    ///
+    /// * `local_port` - Port the socket is bound to now.
+    /// * `now` - Current Unix timestamp in seconds.
+    pub fn is_usable(&self, local_port: u16, now: u64) -> bool {
+        self.local_port == local_port && now < self.observed_at.saturating_add(self.ttl_secs)
+    }
+
+    /// Returns true if `next` is worth writing to disk.
+    ///
+    /// Changed values always are; unchanged ones only once half the TTL has
+    /// passed, so keep-alives don't rewrite the file every few seconds.
+    pub fn should_replace_with(&self, next: &NatCache) -> bool {
+        self.public_ip != next.public_ip
+            || self.nat_type != next.nat_type
+            || self.local_port != next.local_port
+            || next.observed_at >= self.observed_at.saturating_add(self.ttl_secs / 2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::TempDir;
+
+    fn entry(observed_at: u64) -> NatCache {
+        NatCache {
+            public_ip: "203.0.113.7:40000".parse().unwrap(),
+            nat_type: NatType::Cone,
+            local_port: 5000,
+            observed_at,
+            ttl_secs: 600,
+        }
+    }
+
+    #[test]
+    fn test_usable_within_ttl_on_same_port() {
+        let cache = entry(1_000);
+
+        assert!(cache.is_usable(5000, 1_000));
+        assert!(cache.is_usable(5000, 1_599));
+        assert!(!cache.is_usable(5000, 1_600));
+        assert!(!cache.is_usable(5001, 1_000));
+    }
+
+    #[test]
+    fn test_should_replace_with() {
+        let cache = entry(1_000);
+
+        assert!(!cache.should_replace_with(&entry(1_100)));
+        assert!(cache.should_replace_with(&entry(1_300)));
+
+        let mut moved = entry(1_100);
+        moved.nat_type = NatType::Symmetric;
+        assert!(cache.should_replace_with(&moved));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = TempDir::new("nat-cache");
+        let path = dir.join("nat_cache.json");
+        let cache = entry(1_000);
+
+        cache.save(&path).unwrap();
+        assert_eq!(NatCache::load(&path), Some(cache));
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(NatCache::load(&path), None);
+    }
+}
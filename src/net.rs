@@ -2,23 +2,107 @@
 //!
 //! Provides NAT traversal and public IP discovery using STUN.
 
-use super::web::shared_state::NatType;
+use super::{
+    config::{ApiSocket, Dscp, PortRetry},
+    transcript::{Direction, Protocol, Transcript},
+    web::shared_state::NatType,
+};
 use anyhow::{Context, Result, bail};
-use std::net::{IpAddr, SocketAddr};
+use futures::future::join_all;
+use rand_core::{OsRng, RngCore};
+use serde::Serialize;
+use std::{
+    fmt,
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+};
 use stun::{
     agent::TransactionId,
-    message::{BINDING_REQUEST, Getter, Message},
+    error_code::ErrorCodeAttribute,
+    message::{
+        BINDING_REQUEST, CLASS_ERROR_RESPONSE, CLASS_INDICATION, Getter, METHOD_BINDING, Message,
+        MessageType,
+    },
     xoraddr::XorMappedAddress,
 };
+#[cfg(unix)]
+use tokio::net::UnixListener;
 use tokio::{
-    net::UdpSocket,
-    time::{Duration, timeout},
+    net::{TcpListener, UdpSocket},
+    time::{Duration, Instant, timeout, timeout_at},
 };
-use tracing::debug;
+use tracing::{debug, warn};
 
 /// Duration to wait for STUN response before timing out.
 const STUN_TIMEOUT: Duration = Duration::from_secs(3);
 
+/// Why a STUN query failed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", content = "detail")]
+pub enum StunError {
+    /// The server name did not resolve to a usable address.
+    DnsFailure(String),
+    /// No response arrived in time.
+    Timeout,
+    /// The OS refused to send or receive UDP.
+    UdpBlocked(String),
+    /// The response could not be parsed or lacked a mapped address.
+    MalformedResponse(String),
+    /// The response answered a different request.
+    TransactionMismatch,
+    /// The server answered with a STUN error response.
+    ServerError(String),
+    /// UDP is dropped and HTTP intercepted, pointing to this login page.
+    CaptivePortal(String),
+}
+
+impl StunError {
+    /// Returns a suggestion the UI can show next to the error.
+    pub fn advice(&self) -> &'static str {
+        match self {
+            StunError::DnsFailure(_) => {
+                "Check your internet connection and DNS settings, or configure a different STUN server."
+            }
+            StunError::Timeout => {
+                "Your network appears to block outbound UDP. Try another network or ask the administrator to allow UDP."
+            }
+            StunError::UdpBlocked(_) => {
+                "The operating system or a local firewall is blocking UDP for GhostLink."
+            }
+            StunError::MalformedResponse(_) | StunError::ServerError(_) => {
+                "The STUN server misbehaved. Configure a different STUN server."
+            }
+            StunError::TransactionMismatch => {
+                "A stray or spoofed response was received. Retry; if it persists, something on the path is tampering with UDP."
+            }
+            StunError::CaptivePortal(_) => {
+                "This network wants you to sign in first. Open any web page in a browser, complete the login, and GhostLink will retry on its own."
+            }
+        }
+    }
+}
+
+impl fmt::Display for StunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StunError::DnsFailure(detail) => write!(f, "STUN DNS lookup failed: {}", detail),
+            StunError::Timeout => write!(f, "STUN request timed out"),
+            StunError::UdpBlocked(detail) => write!(f, "UDP blocked: {}", detail),
+            StunError::MalformedResponse(detail) => {
+                write!(f, "Malformed STUN response: {}", detail)
+            }
+            StunError::TransactionMismatch => write!(f, "STUN transaction ID mismatch"),
+            StunError::ServerError(detail) => write!(f, "STUN server error: {}", detail),
+            StunError::CaptivePortal(portal) => {
+                write!(f, "Captive portal intercepting traffic: {}", portal)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StunError {}
+
 /// Resolves local IP address using DNS server.
 ///
 /// Connecting to remote address causes OS to select appropriate local interface and IP.
@@ -42,6 +126,252 @@ pub async fn get_local_ip(local_port: u16) -> Result<SocketAddr> {
     Ok(local_ip)
 }
 
+/// Outcome of querying one STUN server during a parallel probe.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StunProbe {
+    /// Server as configured (host:port).
+    pub server: String,
+    /// Our address as seen by the server.
+    pub public_addr: Option<SocketAddr>,
+    /// Time from sending the request to receiving the answer.
+    pub rtt_ms: Option<u64>,
+    /// Why the query failed.
+    pub error: Option<StunError>,
+}
+
+impl StunProbe {
+    fn new(server: &str) -> Self {
+        Self {
+            server: server.to_string(),
+            public_addr: None,
+            rtt_ms: None,
+            error: None,
+        }
+    }
+
+    /// Returns the mapped address, or why it is missing.
+    pub fn result(&self) -> Result<SocketAddr, StunError> {
+        match (self.public_addr, &self.error) {
+            (Some(addr), _) => Ok(addr),
+            (None, Some(e)) => Err(e.clone()),
+            (None, None) => Err(StunError::Timeout),
+        }
+    }
+}
+
+/// Result of NAT detection against the primary and verifier servers.
+#[derive(Debug, Clone)]
+pub struct NatDetection {
+    /// Public address from the primary server, or the verifier if the primary failed.
+    pub public_addr: Result<SocketAddr, StunError>,
+    /// Cone if both servers saw the same mapping, Symmetric if they differ.
+    pub nat_type: NatType,
+    /// Per-server outcome, primary first.
+    pub probes: Vec<StunProbe>,
+}
+
+/// Binds the client UDP socket, falling back to other ports if it is taken.
+///
+/// # Arguments
+///
+/// * `ip` - Local address to bind.
+/// * `port` - Preferred port. Port 0 lets the OS choose and never retries.
+/// * `retry` - How fallback ports are chosen.
+/// * `range` - Ports fallbacks are drawn from.
+/// * `attempts` - Number of fallback ports tried.
+///
+/// # Returns
+///
+/// * `Ok(UdpSocket)` - Bound socket; check `local_addr` for the port actually used.
+/// * `Err` - The preferred port failed for another reason, or every fallback was taken.
+pub async fn bind_udp(
+    ip: IpAddr,
+    port: u16,
+    retry: PortRetry,
+    range: RangeInclusive<u16>,
+    attempts: u32,
+) -> Result<UdpSocket> {
+    let err = match UdpSocket::bind((ip, port)).await {
+        Ok(socket) => return Ok(socket),
+        Err(e) => e,
+    };
+    if err.kind() != ErrorKind::AddrInUse || port == 0 || retry == PortRetry::Disabled {
+        return Err(err).context(format!("Failed to bind UDP port {}", port));
+    }
+
+    warn!("UDP port {} is in use, trying {:?} fallback", port, retry);
+    for candidate in fallback_ports(port, retry, &range, attempts) {
+        match UdpSocket::bind((ip, candidate)).await {
+            Ok(socket) => return Ok(socket),
+            Err(e) if e.kind() == ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e).context(format!("Failed to bind UDP port {}", candidate)),
+        }
+    }
+    bail!(
+        "UDP port {} is in use and no free port was found in {}-{} after {} attempts",
+        port,
+        range.start(),
+        range.end(),
+        attempts
+    )
+}
+
+/// Binds the web server's TCP listener, trying the ports in `fallback` in
+/// order if `port` is taken.
+///
+/// # Arguments
+///
+/// * `ip` - Local address to bind.
+/// * `port` - Preferred port. Port 0 lets the OS choose and never retries.
+/// * `fallback` - Ports tried next. An empty range disables the fallback.
+///
+/// # Returns
+///
+/// * `Ok(TcpListener)` - Bound listener; check `local_addr` for the port actually used.
+/// * `Err` - The preferred port failed for another reason, or every fallback was taken.
+pub async fn bind_tcp(ip: IpAddr, port: u16, fallback: RangeInclusive<u16>) -> Result<TcpListener> {
+    let err = match TcpListener::bind((ip, port)).await {
+        Ok(listener) => return Ok(listener),
+        Err(e) => e,
+    };
+    if err.kind() != ErrorKind::AddrInUse || port == 0 || fallback.is_empty() {
+        return Err(err).context(format!("Failed to bind TCP port {}", port));
+    }
+
+    warn!(
+        "TCP port {} is in use, trying {}-{}",
+        port,
+        fallback.start(),
+        fallback.end()
+    );
+    for candidate in fallback_ports(port, PortRetry::Sequential, &fallback, u32::MAX) {
+        match TcpListener::bind((ip, candidate)).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) if e.kind() == ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e).context(format!("Failed to bind TCP port {}", candidate)),
+        }
+    }
+    bail!(
+        "TCP port {} is in use and so is every port in {}-{}",
+        port,
+        fallback.start(),
+        fallback.end()
+    )
+}
+
+/// Binds the Unix domain socket the web server listens on.
+///
+/// A socket file at the path that nobody listens on any more, left by an
+/// earlier run, is replaced. The new one is accessible to its owner only:
+/// it is bound inside a private directory, restricted, and only then linked
+/// to the path, so it is never reachable with the default permissions.
+///
+/// # Errors
+///
+/// Returns an error if another process is listening on the path, something
+/// other than a socket is there, or the socket cannot be created.
+#[cfg(unix)]
+pub fn bind_unix(socket: &ApiSocket) -> Result<UnixListener> {
+    match socket {
+        ApiSocket::Path(path) => {
+            use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+            if let Ok(meta) = std::fs::symlink_metadata(path) {
+                if !meta.file_type().is_socket() {
+                    bail!("{} exists and is not a socket", socket);
+                }
+                if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                    bail!("{} is in use by another process", socket);
+                }
+                std::fs::remove_file(path)?;
+            }
+            let staging =
+                create_private_dir(path).with_context(|| format!("Failed to bind {}", socket))?;
+            let staged = staging.join("s");
+            let bound = UnixListener::bind(&staged)
+                .with_context(|| format!("Failed to bind {}", socket))
+                .and_then(|listener| {
+                    std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+                    // Unlike a rename, a link never replaces whatever appeared at the path meanwhile
+                    std::fs::hard_link(&staged, path)
+                        .with_context(|| format!("Failed to bind {}", socket))?;
+                    Ok(listener)
+                });
+            let _ = std::fs::remove_file(&staged);
+            let _ = std::fs::remove_dir(&staging);
+            bound
+        }
+        #[cfg(target_os = "linux")]
+        ApiSocket::Abstract(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net};
+
+            let addr = net::SocketAddr::from_abstract_name(name.as_bytes())?;
+            let listener = net::UnixListener::bind_addr(&addr)
+                .with_context(|| format!("Failed to bind {}", socket))?;
+            listener.set_nonblocking(true)?;
+            Ok(UnixListener::from_std(listener)?)
+        }
+        #[cfg(not(target_os = "linux"))]
+        ApiSocket::Abstract(_) => bail!("Abstract sockets are only available on Linux"),
+    }
+}
+
+/// Creates a directory only the owner can enter, next to `path`.
+///
+/// The name is random and the directory must not exist yet, so nothing
+/// already there is reused or removed.
+#[cfg(unix)]
+fn create_private_dir(path: &std::path::Path) -> std::io::Result<std::path::PathBuf> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    let mut attempts = 0;
+    loop {
+        let dir = path.with_file_name(format!(".ghostlink-{:016x}", OsRng.next_u64()));
+        match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists && attempts < 8 => attempts += 1,
+            result => return result.map(|()| dir),
+        }
+    }
+}
+
+/// Lists the ports to try after `port`, never including `port` itself.
+fn fallback_ports(
+    port: u16,
+    retry: PortRetry,
+    range: &RangeInclusive<u16>,
+    attempts: u32,
+) -> Vec<u16> {
+    let (start, end) = (u32::from(*range.start()), u32::from(*range.end()));
+    if start > end {
+        return Vec::new();
+    }
+    let span = end - start + 1;
+    let in_range = (start..=end).contains(&u32::from(port));
+    // Excluding `port` itself keeps the random draw from spinning on a one-port range
+    let take = attempts.min(span - u32::from(in_range)) as usize;
+
+    match retry {
+        PortRetry::Disabled => Vec::new(),
+        PortRetry::Sequential => {
+            // Continue after `port` if it lies in the range, else start at the bottom
+            let first = if in_range {
+                u32::from(port) - start + 1
+            } else {
+                0
+            };
+            (0..span)
+                .map(|i| (start + (first + i) % span) as u16)
+                .filter(|&p| p != port)
+                .take(take)
+                .collect()
+        }
+        PortRetry::Random => std::iter::repeat_with(|| (start + OsRng.next_u32() % span) as u16)
+            .filter(|&p| p != port)
+            .take(take)
+            .collect(),
+    }
+}
+
 /// Discovers public IP and port using STUN.
 ///
 /// # Workflow
@@ -56,128 +386,388 @@ pub async fn get_local_ip(local_port: u16) -> Result<SocketAddr> {
 ///
 /// * `socket` - Bound UDP socket.
 /// * `stun_server` - STUN server address (e.g., "stun.l.google.com:19302").
+/// * `transcript` - Debug trace the request and response are recorded to.
 ///
 /// # Returns
 ///
 /// * `Ok(SocketAddr)` - Public IP and port.
-/// * `Err` - DNS, network, or STUN validation failed.
+/// * `Err(StunError)` - Classified DNS, network, or STUN validation failure.
 pub async fn resolve_public_ip(
     socket: &UdpSocket,
     stun_server: impl AsRef<str>,
-) -> Result<SocketAddr> {
+    transcript: &Transcript,
+) -> Result<SocketAddr, StunError> {
     let stun_server = stun_server.as_ref();
     debug!("Querying STUN server: {}", stun_server);
 
-    // 1. Determine socket type (IPv4 or IPv6)
-    let local_addr = socket
-        .local_addr()
-        .context("Could not get local socket address")?;
-    let is_ipv4_socket = local_addr.is_ipv4();
-
-    // 2. Resolve DNS for STUN server
-    let mut addrs = tokio::net::lookup_host(stun_server)
-        .await
-        .context(format!("Failed to resolve DNS for {}", stun_server))?;
-
-    // 3. Filter addresses compatible with socket type
-    let target_addr = addrs
-        .find(|addr| {
-            if is_ipv4_socket {
-                addr.is_ipv4()
-            } else {
-                addr.is_ipv6()
-            }
-        })
-        .context(format!(
-            "STUN server {} has no addresses compatible with socket (Protocol Mismatch)",
-            stun_server
-        ))?;
-
-    // Build STUN binding request
-    let mut msg = Message::new();
-    msg.build(&[Box::<TransactionId>::default(), Box::new(BINDING_REQUEST)])?;
+    // 1. Determine socket type and resolve DNS for a compatible address
+    let local_addr = local_addr(socket)?;
+    let target_addr = lookup_stun_server(stun_server, local_addr.is_ipv4()).await?;
 
-    let expected_tx_id = msg.transaction_id;
+    // 2. Build and send request
+    let request = binding_request()?;
+    send_request(socket, local_addr, target_addr, &request, transcript).await?;
 
-    // 4. Send request
-    socket
-        .send_to(&msg.raw, target_addr)
-        .await
-        .context("Failed to send STUN request")?;
-
-    // 5. Wait for response with timeout (UDP packets can be lost)
+    // 3. Wait for response with timeout (UDP packets can be lost)
     let mut buf = [0u8; 1024];
 
     let (len, sender_addr) = timeout(STUN_TIMEOUT, socket.recv_from(&mut buf))
         .await
-        .context("STUN request timed out")?
-        .context("Failed to receive STUN response")?;
+        .map_err(|_| StunError::Timeout)?
+        .map_err(|e| StunError::UdpBlocked(format!("Failed to receive STUN response: {}", e)))?;
 
     debug!("Received {} bytes from {}", len, sender_addr);
 
-    // 6. Parse and validate response
-    let mut response = Message::new();
-    response.unmarshal_binary(&buf[..len])?;
+    // 4. Parse and validate response
+    let response = parse_response(&buf[..len], local_addr, sender_addr, transcript)?;
 
-    if response.transaction_id != expected_tx_id {
-        bail!(
+    if response.transaction_id != request.transaction_id {
+        debug!(
             "Security Mismatch: Expected Transaction ID {:?}, but got {:?}",
-            expected_tx_id,
-            response.transaction_id
+            request.transaction_id, response.transaction_id
         );
+        return Err(StunError::TransactionMismatch);
     }
 
-    // 7. Extract public IP
-    let mut xor_addr = XorMappedAddress::default();
-    xor_addr
-        .get_from(&response)
-        .context("STUN response did not contain XOR-MAPPED-ADDRESS")?;
-
-    let public_addr = SocketAddr::new(xor_addr.ip, xor_addr.port);
+    // 5. Extract public IP
+    let public_addr = mapped_address(&response)?;
     debug!("Public IP resolved: {}", public_addr);
 
     Ok(public_addr)
 }
 
-/// Detects NAT type by querying second STUN server.
+/// Keeps the NAT mapping towards a STUN server open without querying it.
+///
+/// Sends a Binding Indication, which the server accepts without replying
+/// (RFC 5389, section 10).
+///
+/// # Arguments
+///
+/// * `socket` - Bound UDP socket whose mapping to keep.
+/// * `stun_server` - STUN server address.
+/// * `transcript` - Debug trace the indication is recorded to.
+///
+/// # Errors
+///
+/// DNS resolution or sending failed.
+pub async fn send_binding_indication(
+    socket: &UdpSocket,
+    stun_server: impl AsRef<str>,
+    transcript: &Transcript,
+) -> Result<(), StunError> {
+    let local_addr = local_addr(socket)?;
+    let target_addr = lookup_stun_server(stun_server.as_ref(), local_addr.is_ipv4()).await?;
+
+    let mut indication = Message::new();
+    indication
+        .build(&[
+            Box::new(TransactionId::new()),
+            Box::new(MessageType::new(METHOD_BINDING, CLASS_INDICATION)),
+        ])
+        .map_err(|e| StunError::MalformedResponse(format!("Failed to build indication: {}", e)))?;
+    send_request(socket, local_addr, target_addr, &indication, transcript).await
+}
+
+/// Queries several STUN servers concurrently from one socket.
+///
+/// All requests are sent up front and responses are matched back to their
+/// server by transaction ID, so the total wait is the slowest server's RTT
+/// rather than the sum.
+///
+/// # Arguments
+///
+/// * `socket` - Bound UDP socket.
+/// * `servers` - STUN server addresses.
+/// * `transcript` - Debug trace the exchanges are recorded to.
+///
+/// # Returns
 ///
-/// Compares public port from two different STUN servers:
+/// One probe per server, in the order given.
+pub async fn probe_stun_servers(
+    socket: &UdpSocket,
+    servers: &[&str],
+    transcript: &Transcript,
+) -> Vec<StunProbe> {
+    let mut probes: Vec<StunProbe> = servers.iter().map(|s| StunProbe::new(s)).collect();
+
+    let local_addr = match local_addr(socket) {
+        Ok(addr) => addr,
+        Err(e) => {
+            for probe in &mut probes {
+                probe.error = Some(e.clone());
+            }
+            return probes;
+        }
+    };
+
+    // 1. Resolve every server concurrently
+    let targets = join_all(
+        servers
+            .iter()
+            .map(|server| lookup_stun_server(server, local_addr.is_ipv4())),
+    )
+    .await;
+
+    // 2. Send all requests before waiting for any response
+    let mut pending = Vec::new();
+    for (index, target) in targets.into_iter().enumerate() {
+        let sent = match target {
+            Ok(target) => match binding_request() {
+                Ok(request) => send_request(socket, local_addr, target, &request, transcript)
+                    .await
+                    .map(|()| (target, request.transaction_id)),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        match sent {
+            Ok((target, tx_id)) => pending.push((index, target, tx_id, Instant::now())),
+            Err(e) => probes[index].error = Some(e),
+        }
+    }
+
+    // 3. Collect responses until every server answered or the timeout hits
+    let deadline = Instant::now() + STUN_TIMEOUT;
+    let mut buf = [0u8; 1024];
+    while !pending.is_empty() {
+        let (len, sender_addr) = match timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            Ok(Ok(received)) => received,
+            Ok(Err(e)) => {
+                let error =
+                    StunError::UdpBlocked(format!("Failed to receive STUN response: {}", e));
+                for (index, ..) in pending.drain(..) {
+                    probes[index].error = Some(error.clone());
+                }
+                break;
+            }
+            Err(_) => break,
+        };
+
+        let response = parse_response(&buf[..len], local_addr, sender_addr, transcript);
+        let matched = response.as_ref().ok().and_then(|response| {
+            pending
+                .iter()
+                .position(|(.., tx_id, _)| *tx_id == response.transaction_id)
+        });
+
+        match (matched, response) {
+            (Some(slot), Ok(response)) => {
+                let (index, _, _, sent_at) = pending.swap_remove(slot);
+                let probe = &mut probes[index];
+                probe.rtt_ms = Some(sent_at.elapsed().as_millis() as u64);
+                match mapped_address(&response) {
+                    Ok(addr) => probe.public_addr = Some(addr),
+                    Err(e) => probe.error = Some(e),
+                }
+            }
+            (_, response) => {
+                // Unmatched: blame the server it came from, as a single query would
+                let Some(slot) = pending
+                    .iter()
+                    .position(|(_, target, ..)| *target == sender_addr)
+                else {
+                    debug!("Ignoring stray packet from {}", sender_addr);
+                    continue;
+                };
+                let (index, ..) = pending.swap_remove(slot);
+                probes[index].error =
+                    Some(response.err().unwrap_or(StunError::TransactionMismatch));
+            }
+        }
+    }
+
+    for (index, ..) in pending {
+        probes[index].error = Some(StunError::Timeout);
+    }
+
+    for probe in &probes {
+        debug!(
+            "STUN {}: {:?} in {:?} ms",
+            probe.server,
+            probe.result(),
+            probe.rtt_ms
+        );
+    }
+    probes
+}
+
+/// Resolves our public address and detects the NAT type in one round trip.
+///
+/// Queries both servers in parallel and compares the mapped ports:
 /// - Same port → Cone NAT (P2P-friendly).
 /// - Different port → Symmetric NAT (P2P-difficult).
 ///
 /// # Arguments
 ///
 /// * `socket` - Bound UDP socket.
-/// * `stun_server` - Second STUN server address.
-/// * `prev_addr` - Address from first STUN query.
+/// * `stun_server` - Primary STUN server address.
+/// * `stun_verifier` - Second STUN server address.
+/// * `transcript` - Debug trace the exchanges are recorded to.
 ///
 /// # Returns
 ///
-/// NAT type: Cone, Symmetric, or Unknown.
-pub async fn get_nat_type(
+/// Public address, NAT type (Unknown unless both servers answered), and
+/// per-server probes.
+pub async fn detect_nat(
     socket: &UdpSocket,
-    stun_server: impl AsRef<str>,
-    prev_addr: SocketAddr,
-) -> NatType {
-    // Resolve public IP using new STUN server
-    resolve_public_ip(socket, stun_server).await.map_or_else(
-        // Return Unknown if any error
-        |_| NatType::Unknown,
-        |public_ip| {
-            // Return NAT type based on response
-            if prev_addr == public_ip {
-                NatType::Cone
-            } else {
-                NatType::Symmetric
-            }
+    stun_server: &str,
+    stun_verifier: &str,
+    transcript: &Transcript,
+) -> NatDetection {
+    let probes = probe_stun_servers(socket, &[stun_server, stun_verifier], transcript).await;
+    let primary = probes[0].result();
+    let verifier = probes[1].result();
+
+    let nat_type = match (&primary, &verifier) {
+        (Ok(a), Ok(b)) if a == b => NatType::Cone,
+        (Ok(_), Ok(_)) => NatType::Symmetric,
+        _ => NatType::Unknown,
+    };
+
+    NatDetection {
+        public_addr: primary.or(verifier),
+        nat_type,
+        probes,
+    }
+}
+
+fn local_addr(socket: &UdpSocket) -> Result<SocketAddr, StunError> {
+    socket
+        .local_addr()
+        .map_err(|e| StunError::UdpBlocked(format!("Could not get local socket address: {}", e)))
+}
+
+/// Resolves `stun_server` to an address of the socket's family.
+async fn lookup_stun_server(stun_server: &str, is_ipv4: bool) -> Result<SocketAddr, StunError> {
+    let mut addrs = tokio::net::lookup_host(stun_server)
+        .await
+        .map_err(|e| StunError::DnsFailure(format!("{}: {}", stun_server, e)))?;
+
+    addrs.find(|addr| addr.is_ipv4() == is_ipv4).ok_or_else(|| {
+        StunError::DnsFailure(format!(
+            "{} has no addresses compatible with socket (Protocol Mismatch)",
+            stun_server
+        ))
+    })
+}
+
+/// Builds a BINDING_REQUEST with a fresh transaction ID.
+fn binding_request() -> Result<Message, StunError> {
+    let mut msg = Message::new();
+    msg.build(&[Box::new(TransactionId::new()), Box::new(BINDING_REQUEST)])
+        .map_err(|e| StunError::MalformedResponse(format!("Failed to build request: {}", e)))?;
+    Ok(msg)
+}
+
+async fn send_request(
+    socket: &UdpSocket,
+    local_addr: SocketAddr,
+    target_addr: SocketAddr,
+    request: &Message,
+    transcript: &Transcript,
+) -> Result<(), StunError> {
+    socket
+        .send_to(&request.raw, target_addr)
+        .await
+        .map_err(|e| StunError::UdpBlocked(format!("Failed to send STUN request: {}", e)))?;
+    transcript.record(
+        Direction::Sent,
+        Protocol::Stun,
+        local_addr,
+        target_addr,
+        || request.to_string(),
+        &request.raw,
+    );
+    Ok(())
+}
+
+/// Parses a received datagram and records it to the transcript.
+fn parse_response(
+    datagram: &[u8],
+    local_addr: SocketAddr,
+    sender_addr: SocketAddr,
+    transcript: &Transcript,
+) -> Result<Message, StunError> {
+    let mut response = Message::new();
+    let parsed = response.unmarshal_binary(datagram);
+    transcript.record(
+        Direction::Received,
+        Protocol::Stun,
+        local_addr,
+        sender_addr,
+        || match &parsed {
+            Ok(()) => response.to_string(),
+            Err(e) => format!("<malformed: {}>", e),
         },
-    )
+        datagram,
+    );
+    parsed.map_err(|e| StunError::MalformedResponse(e.to_string()))?;
+    Ok(response)
+}
+
+/// Extracts the mapped address, classifying error responses.
+fn mapped_address(response: &Message) -> Result<SocketAddr, StunError> {
+    if response.typ.class == CLASS_ERROR_RESPONSE {
+        let mut error_code = ErrorCodeAttribute::default();
+        let detail = match error_code.get_from(response) {
+            Ok(()) => error_code.to_string(),
+            Err(_) => "error response without ERROR-CODE".to_string(),
+        };
+        return Err(StunError::ServerError(detail));
+    }
+
+    let mut xor_addr = XorMappedAddress::default();
+    xor_addr
+        .get_from(response)
+        .map_err(|_| StunError::MalformedResponse("missing XOR-MAPPED-ADDRESS".to_string()))?;
+
+    Ok(SocketAddr::new(xor_addr.ip, xor_addr.port))
+}
+
+/// Applies QoS marking and TTL to a socket.
+///
+/// DSCP is written to the upper six bits of the IPv4 TOS byte.
+///
+/// # Arguments
+///
+/// * `socket` - Bound UDP socket.
+/// * `dscp` - Code point to mark outgoing packets with, if any.
+/// * `ttl` - IP time-to-live for outgoing packets, if any.
+///
+/// # Returns
+///
+/// * `Ok(())` - Options applied.
+/// * `Err` - The OS rejected an option (e.g., TOS on an IPv6 socket).
+pub fn apply_socket_options(
+    socket: &UdpSocket,
+    dscp: Option<Dscp>,
+    ttl: Option<u32>,
+) -> Result<()> {
+    if let Some(dscp) = dscp {
+        socket
+            .set_tos(u32::from(dscp.code_point()) << 2)
+            .context(format!("Failed to set DSCP {:?}", dscp))?;
+        debug!("DSCP set to {:?}", dscp);
+    }
+
+    if let Some(ttl) = ttl {
+        socket
+            .set_ttl(ttl)
+            .context(format!("Failed to set TTL {}", ttl))?;
+        debug!("TTL set to {}", ttl);
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use stun::message::BINDING_SUCCESS;
+    use crate::storage::TempDir;
+    use stun::{
+        error_code::CODE_BAD_REQUEST,
+        message::{BINDING_ERROR, BINDING_SUCCESS},
+    };
 
     /// Verifies that the resolve_public_ip function correctly handles a valid STUN response.
     #[tokio::test]
@@ -212,12 +802,21 @@ mod test {
 
         // Run client
         let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
-        let result = resolve_public_ip(&socket, server_addr.to_string()).await;
+        let transcript = Transcript::default();
+        transcript.set_enabled(true);
+        let result = resolve_public_ip(&socket, server_addr.to_string(), &transcript).await;
 
         // Verify
         assert!(result.is_ok());
         let ip = result.unwrap();
         assert_eq!(ip.port(), 9999);
+
+        // Both the request and the response are traced
+        let entries = transcript.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].direction, Direction::Sent);
+        assert_eq!(entries[1].direction, Direction::Received);
+        assert_eq!(entries[1].remote, server_addr);
     }
 
     /// Verifies that resolve_public_ip fails gracefully when DNS resolution fails.
@@ -226,15 +825,18 @@ mod test {
         let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
 
         // Use an invalid hostname that will fail DNS resolution
-        let result = resolve_public_ip(&socket, "invalid.hostname.that.does.not.exist:19302").await;
+        let result = resolve_public_ip(
+            &socket,
+            "invalid.hostname.that.does.not.exist:19302",
+            &Transcript::default(),
+        )
+        .await;
 
-        assert!(result.is_err());
-        let err_msg = result.unwrap_err().to_string();
-        assert!(err_msg.contains("Failed to resolve DNS") || err_msg.contains("failed to lookup"));
+        assert!(matches!(result, Err(StunError::DnsFailure(_))));
     }
 
     /// Verifies that resolve_public_ip times out if no response is received.
-    #[tokio::test]
+    #[tokio::test(start_paused = true)]
     async fn test_resolve_public_ip_timeout() {
         let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
         // Bind a "server" that never replies
@@ -242,10 +844,10 @@ mod test {
         let server_addr = mock_server.local_addr().unwrap();
 
         // Expect a timeout error roughly after STUN_TIMEOUT
-        let result = resolve_public_ip(&socket, server_addr.to_string()).await;
+        let result =
+            resolve_public_ip(&socket, server_addr.to_string(), &Transcript::default()).await;
 
-        assert!(result.is_err());
-        assert_eq!(result.unwrap_err().to_string(), "STUN request timed out");
+        assert_eq!(result.unwrap_err(), StunError::Timeout);
     }
 
     /// Verifies that resolve_public_ip rejects responses with mismatched transaction IDs.
@@ -280,18 +882,15 @@ mod test {
         });
 
         let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
-        let result = resolve_public_ip(&socket, server_addr.to_string()).await;
+        let result =
+            resolve_public_ip(&socket, server_addr.to_string(), &Transcript::default()).await;
 
-        assert!(result.is_err());
-        let err_msg = result.unwrap_err().to_string();
-        assert!(err_msg.contains("Security Mismatch"));
+        assert_eq!(result.unwrap_err(), StunError::TransactionMismatch);
     }
 
-    /// Simulates a scenario where the second STUN server sees a DIFFERENT port than the first one.
-    /// This indicates the router is assigning new external ports for each destination (Symmetric).
+    /// A STUN error response is classified as a server error with its code.
     #[tokio::test]
-    async fn test_get_nat_type_symmetric() {
-        // 1. Setup Mock Server 2 (Simulating a second STUN server)
+    async fn test_resolve_public_ip_server_error() {
         let mock_server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
         let server_addr = mock_server.local_addr().unwrap();
 
@@ -302,14 +901,13 @@ mod test {
             let mut req = Message::new();
             req.unmarshal_binary(&buf[..len]).unwrap();
 
-            // Reply with a DIFFERENT port than what the client expects from the first server
             let mut resp = Message::new();
             resp.transaction_id = req.transaction_id;
             resp.build(&[
-                Box::new(BINDING_SUCCESS),
-                Box::new(XorMappedAddress {
-                    ip: "127.0.0.1".parse().unwrap(),
-                    port: 8888, // Different port
+                Box::new(BINDING_ERROR),
+                Box::new(ErrorCodeAttribute {
+                    code: CODE_BAD_REQUEST,
+                    reason: b"Bad Request".to_vec(),
                 }),
             ])
             .unwrap();
@@ -317,24 +915,37 @@ mod test {
             mock_server.send_to(&resp.raw, client_addr).await.unwrap();
         });
 
-        // 2. Setup Client
         let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let result =
+            resolve_public_ip(&socket, server_addr.to_string(), &Transcript::default()).await;
+
+        match result {
+            Err(StunError::ServerError(detail)) => assert!(detail.contains("400")),
+            other => panic!("expected ServerError, got {:?}", other),
+        }
+    }
+
+    /// Garbage instead of a STUN message is reported as a malformed response.
+    #[tokio::test]
+    async fn test_resolve_public_ip_malformed_response() {
+        let mock_server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = mock_server.local_addr().unwrap();
 
-        // 3. Define "Previous Address" (Result from STUN 1)
-        // Pretend STUN 1 said we are on port 9999.
-        let prev_addr: SocketAddr = "127.0.0.1:9999".parse().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let (_, client_addr) = mock_server.recv_from(&mut buf).await.unwrap();
+            mock_server.send_to(b"not stun", client_addr).await.unwrap();
+        });
 
-        // 4. Run Detection
-        // Since STUN 2 returns port 8888, and 8888 != 9999, it should be Symmetric.
-        let nat_type = get_nat_type(&socket, server_addr.to_string(), prev_addr).await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let result =
+            resolve_public_ip(&socket, server_addr.to_string(), &Transcript::default()).await;
 
-        assert_eq!(nat_type, NatType::Symmetric);
+        assert!(matches!(result, Err(StunError::MalformedResponse(_))));
     }
 
-    /// Simulates a scenario where the second STUN server sees the SAME port as the first one.
-    /// This indicates the router reuses the mapping (Cone).
-    #[tokio::test]
-    async fn test_get_nat_type_cone() {
+    /// Spawns a STUN server that answers one request with `port` after `delay`.
+    async fn spawn_mock_stun(port: u16, delay: Duration) -> SocketAddr {
         let mock_server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
         let server_addr = mock_server.local_addr().unwrap();
 
@@ -345,38 +956,236 @@ mod test {
             let mut req = Message::new();
             req.unmarshal_binary(&buf[..len]).unwrap();
 
-            // Reply with the SAME port as prev_addr
             let mut resp = Message::new();
             resp.transaction_id = req.transaction_id;
             resp.build(&[
                 Box::new(BINDING_SUCCESS),
                 Box::new(XorMappedAddress {
                     ip: "127.0.0.1".parse().unwrap(),
-                    port: 9999, // Same port
+                    port,
                 }),
             ])
             .unwrap();
 
+            tokio::time::sleep(delay).await;
             mock_server.send_to(&resp.raw, client_addr).await.unwrap();
         });
 
+        server_addr
+    }
+
+    /// Simulates a scenario where the second STUN server sees a DIFFERENT port than the first one.
+    /// This indicates the router is assigning new external ports for each destination (Symmetric).
+    #[tokio::test]
+    async fn test_detect_nat_symmetric() {
+        let primary = spawn_mock_stun(9999, Duration::ZERO).await;
+        let verifier = spawn_mock_stun(8888, Duration::ZERO).await;
         let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
-        let prev_addr: SocketAddr = "127.0.0.1:9999".parse().unwrap();
 
-        let nat_type = get_nat_type(&socket, server_addr.to_string(), prev_addr).await;
+        let detection = detect_nat(
+            &socket,
+            &primary.to_string(),
+            &verifier.to_string(),
+            &Transcript::default(),
+        )
+        .await;
 
-        assert_eq!(nat_type, NatType::Cone);
+        assert_eq!(detection.nat_type, NatType::Symmetric);
+        assert_eq!(detection.public_addr.unwrap().port(), 9999);
+    }
+
+    /// Simulates a scenario where the second STUN server sees the SAME port as the first one.
+    /// This indicates the router reuses the mapping (Cone).
+    #[tokio::test]
+    async fn test_detect_nat_cone() {
+        let primary = spawn_mock_stun(9999, Duration::ZERO).await;
+        let verifier = spawn_mock_stun(9999, Duration::ZERO).await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let detection = detect_nat(
+            &socket,
+            &primary.to_string(),
+            &verifier.to_string(),
+            &Transcript::default(),
+        )
+        .await;
+
+        assert_eq!(detection.nat_type, NatType::Cone);
     }
 
     /// If the second STUN query fails (timeout/DNS), it should default to `Unknown` rather than crashing.
     #[tokio::test]
-    async fn test_get_nat_type_unknown_on_failure() {
+    async fn test_detect_nat_unknown_on_failure() {
+        let primary = spawn_mock_stun(9999, Duration::ZERO).await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // Point the verifier to a non-existent server to force an error
+        let detection = detect_nat(
+            &socket,
+            &primary.to_string(),
+            "127.0.0.1:0",
+            &Transcript::default(),
+        )
+        .await;
+
+        assert_eq!(detection.nat_type, NatType::Unknown);
+        assert_eq!(detection.public_addr.unwrap().port(), 9999);
+        assert!(detection.probes[1].error.is_some());
+    }
+
+    /// Both servers are queried at once, and out-of-order answers reach the right probe.
+    #[tokio::test]
+    async fn test_probe_stun_servers_runs_in_parallel() {
+        let slow = spawn_mock_stun(1111, Duration::from_millis(400)).await;
+        let fast = spawn_mock_stun(2222, Duration::from_millis(300)).await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let started = Instant::now();
+        let probes = probe_stun_servers(
+            &socket,
+            &[&slow.to_string(), &fast.to_string()],
+            &Transcript::default(),
+        )
+        .await;
+
+        // Sequential queries would take at least 700 ms
+        assert!(started.elapsed() < Duration::from_millis(650));
+        assert_eq!(probes[0].public_addr.unwrap().port(), 1111);
+        assert_eq!(probes[1].public_addr.unwrap().port(), 2222);
+        assert!(probes[0].rtt_ms.unwrap() >= 400);
+        assert!(probes[1].rtt_ms.unwrap() >= 300);
+    }
+
+    /// Sequential fallback continues after the taken port and wraps within the range.
+    #[test]
+    fn test_fallback_ports_sequential() {
+        assert_eq!(
+            fallback_ports(5002, PortRetry::Sequential, &(5000..=5003), 10),
+            vec![5003, 5000, 5001]
+        );
+        assert_eq!(
+            fallback_ports(80, PortRetry::Sequential, &(5000..=5003), 2),
+            vec![5000, 5001]
+        );
+        assert!(fallback_ports(5002, PortRetry::Disabled, &(5000..=5003), 10).is_empty());
+    }
+
+    #[test]
+    fn test_fallback_ports_random_stays_in_range() {
+        let ports = fallback_ports(5002, PortRetry::Random, &(5000..=5010), 8);
+        assert_eq!(ports.len(), 8);
+        assert!(
+            ports
+                .iter()
+                .all(|p| (5000..=5010).contains(p) && *p != 5002)
+        );
+    }
+
+    /// A taken port falls back to another one instead of failing.
+    #[tokio::test]
+    async fn test_bind_udp_falls_back_when_taken() {
+        let taken = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+
+        let socket = bind_udp(ip, port, PortRetry::Random, 20000..=60000, 10)
+            .await
+            .unwrap();
+        assert_ne!(socket.local_addr().unwrap().port(), port);
+
+        let err = bind_udp(ip, port, PortRetry::Disabled, 20000..=60000, 10).await;
+        assert!(err.is_err());
+    }
+
+    /// A taken web port moves to the first free port of the fallback range.
+    #[tokio::test]
+    async fn test_bind_tcp_falls_back_when_taken() {
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let taken = TcpListener::bind((ip, 0)).await.unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let next = TcpListener::bind((ip, 0)).await.unwrap();
+        let free = next.local_addr().unwrap().port();
+        drop(next);
+
+        let listener = bind_tcp(ip, port, free..=free).await.unwrap();
+        assert_eq!(listener.local_addr().unwrap().port(), free);
+
+        // Both are taken now, and an empty range does not retry at all
+        assert!(bind_tcp(ip, port, free..=free).await.is_err());
+        assert!(bind_tcp(ip, port, RangeInclusive::new(1, 0)).await.is_err());
+    }
+
+    /// The web API can be served on a Unix socket that only its owner may use.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_bind_unix_serves_http() {
+        use std::os::unix::fs::PermissionsExt;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = TempDir::new("api-socket");
+        let socket = ApiSocket::Path(dir.join("api.sock"));
+
+        // A file left behind by an earlier run is replaced, a live socket is not
+        drop(bind_unix(&socket).unwrap());
+        let listener = bind_unix(&socket).unwrap();
+        assert!(bind_unix(&socket).is_err());
+        let mode = std::fs::metadata(dir.join("api.sock"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // Nothing is left of the private directory it was bound in
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        // Anything else at the path is left alone
+        std::fs::write(dir.join("notes.txt"), "keep me").unwrap();
+        std::os::unix::fs::symlink(dir.join("notes.txt"), dir.join("link.sock")).unwrap();
+        for name in ["notes.txt", "link.sock"] {
+            assert!(bind_unix(&ApiSocket::Path(dir.join(name))).is_err());
+            assert_eq!(std::fs::read_to_string(dir.join(name)).unwrap(), "keep me");
+        }
+
+        let app = axum::Router::new().route("/", axum::routing::get(|| async { "hello" }));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let mut stream = tokio::net::UnixStream::connect(dir.join("api.sock"))
+            .await
+            .unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("hello"));
+
+        #[cfg(target_os = "linux")]
+        {
+            let name = format!("ghostlink-test-{}", std::process::id());
+            let _listener = bind_unix(&ApiSocket::Abstract(name.clone())).unwrap();
+            assert!(bind_unix(&ApiSocket::Abstract(name)).is_err());
+        }
+    }
+
+    /// DSCP and TTL should be readable back from the socket after applying.
+    #[tokio::test]
+    async fn test_apply_socket_options() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        apply_socket_options(&socket, Some(Dscp::Ef), Some(7)).unwrap();
+
+        assert_eq!(socket.tos().unwrap(), 46 << 2);
+        assert_eq!(socket.ttl().unwrap(), 7);
+    }
+
+    /// Leaving both options unset must not touch the socket.
+    #[tokio::test]
+    async fn test_apply_socket_options_none() {
         let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
-        let prev_addr: SocketAddr = "127.0.0.1:9999".parse().unwrap();
+        let ttl = socket.ttl().unwrap();
 
-        // Point to a non-existent server to force a timeout/error
-        let nat_type = get_nat_type(&socket, "127.0.0.1:0", prev_addr).await;
+        apply_socket_options(&socket, None, None).unwrap();
 
-        assert_eq!(nat_type, NatType::Unknown);
+        assert_eq!(socket.ttl().unwrap(), ttl);
     }
 }
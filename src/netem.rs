@@ -0,0 +1,269 @@
+//! Network condition simulator.
+//!
+//! Injects latency, jitter, loss and reordering into datagrams, so handshake
+//! and KCP behaviour can be exercised reproducibly without a real bad link.
+//! `ghostlink dev --pair` applies it between its two nodes; tests relay UDP
+//! between two local sockets through it. Only built with the `netem` feature.
+
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::{sync::RwLock, time::Duration};
+
+/// Impairments applied to every relayed datagram.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct NetemConfig {
+    /// Base one-way delay in milliseconds.
+    pub latency_ms: u64,
+    /// Delay varies uniformly by up to this many milliseconds either way.
+    pub jitter_ms: u64,
+    /// Probability (0.0-1.0) that a datagram is dropped.
+    pub loss: f64,
+    /// Probability (0.0-1.0) that a datagram skips the delay and overtakes
+    /// earlier ones.
+    pub reorder: f64,
+}
+
+/// Live, shared impairment settings. Changes apply to the next datagram.
+pub type NetemHandle = Arc<RwLock<NetemConfig>>;
+
+/// Decides what happens to one datagram under `config`.
+///
+/// # Returns
+///
+/// * `Some(delay)` - Deliver it after `delay`.
+/// * `None` - Drop it.
+pub fn impair(config: &NetemConfig) -> Option<Duration> {
+    if chance(config.loss) {
+        return None;
+    }
+    Some(if chance(config.reorder) {
+        Duration::ZERO
+    } else {
+        sample_delay(config)
+    })
+}
+
+/// Returns true with probability `p`.
+fn chance(p: f64) -> bool {
+    p > 0.0 && (OsRng.next_u32() as f64 / u32::MAX as f64) < p
+}
+
+/// Draws a delay uniformly from `latency ± jitter`, floored at zero.
+fn sample_delay(config: &NetemConfig) -> Duration {
+    let jitter = if config.jitter_ms == 0 {
+        0
+    } else {
+        (OsRng.next_u64() % (2 * config.jitter_ms + 1)) as i64 - config.jitter_ms as i64
+    };
+    let millis = (config.latency_ms as i64 + jitter).max(0) as u64;
+    Duration::from_millis(millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::EncryptionMode,
+        messaging::message_manager::{MessageManager, StreamMessage},
+        web::shared_state::{AppEvent, AppState, Command, SharedState},
+    };
+    use anyhow::{Context, Result};
+    use std::net::SocketAddr;
+    use tokio::{
+        net::UdpSocket,
+        sync::{broadcast, mpsc},
+        task::JoinHandle,
+        time::sleep,
+    };
+    use tracing::debug;
+
+    /// A lossy relay between two endpoints.
+    ///
+    /// Endpoint A must send to `a_side` and endpoint B to `b_side`; each sees the
+    /// other as that address. The relay stops when the link is dropped.
+    #[derive(Debug)]
+    pub struct NetemLink {
+        /// Address endpoint A talks to.
+        pub a_side: SocketAddr,
+        /// Address endpoint B talks to.
+        pub b_side: SocketAddr,
+        tasks: [JoinHandle<()>; 2],
+    }
+
+    impl NetemLink {
+        /// Binds the relay sockets and starts forwarding.
+        ///
+        /// # Arguments
+        ///
+        /// * `a` - Address of endpoint A.
+        /// * `b` - Address of endpoint B.
+        /// * `conditions` - Impairments to apply in both directions.
+        pub async fn spawn(a: SocketAddr, b: SocketAddr, conditions: NetemHandle) -> Result<Self> {
+            let toward_a = Arc::new(
+                UdpSocket::bind((a.ip(), 0))
+                    .await
+                    .context("Failed to bind netem socket for A")?,
+            );
+            let toward_b = Arc::new(
+                UdpSocket::bind((b.ip(), 0))
+                    .await
+                    .context("Failed to bind netem socket for B")?,
+            );
+
+            let a_side = toward_a.local_addr()?;
+            let b_side = toward_b.local_addr()?;
+            debug!("Netem link {} <-> {} via {} / {}", a, b, a_side, b_side);
+
+            let tasks = [
+                tokio::spawn(relay(
+                    toward_a.clone(),
+                    a,
+                    toward_b.clone(),
+                    b,
+                    conditions.clone(),
+                )),
+                tokio::spawn(relay(toward_b, b, toward_a, a, conditions)),
+            ];
+
+            Ok(Self {
+                a_side,
+                b_side,
+                tasks,
+            })
+        }
+    }
+
+    impl Drop for NetemLink {
+        fn drop(&mut self) {
+            for task in &self.tasks {
+                task.abort();
+            }
+        }
+    }
+
+    /// Forwards datagrams from `src` (received on `inbound`) to `dst` (sent from `outbound`).
+    async fn relay(
+        inbound: Arc<UdpSocket>,
+        src: SocketAddr,
+        outbound: Arc<UdpSocket>,
+        dst: SocketAddr,
+        conditions: NetemHandle,
+    ) {
+        let mut buf = [0u8; 65_535];
+        loop {
+            let (len, sender) = match inbound.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    debug!("Netem relay read failed: {}", e);
+                    continue;
+                }
+            };
+            if sender != src {
+                continue;
+            }
+
+            let Some(delay) = impair(&*conditions.read().await) else {
+                continue;
+            };
+
+            let datagram = buf[..len].to_vec();
+            let outbound = outbound.clone();
+            tokio::spawn(async move {
+                if !delay.is_zero() {
+                    sleep(delay).await;
+                }
+                let _ = outbound.send_to(&datagram, dst).await;
+            });
+        }
+    }
+
+    fn create_test_state() -> SharedState {
+        let (cmd_tx, mut cmd_rx) = mpsc::channel::<Command>(32);
+        let (event_tx, _) = broadcast::channel::<AppEvent>(32);
+        tokio::spawn(async move { while cmd_rx.recv().await.is_some() {} });
+        Arc::new(RwLock::new(AppState::new(cmd_tx, event_tx)))
+    }
+
+    async fn bind_manager() -> (MessageManager, SocketAddr) {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr = socket.local_addr().unwrap();
+        (MessageManager::new(socket, create_test_state()), addr)
+    }
+
+    #[test]
+    fn test_sample_delay_within_bounds() {
+        let config = NetemConfig {
+            latency_ms: 50,
+            jitter_ms: 10,
+            ..Default::default()
+        };
+        for _ in 0..100 {
+            let delay = sample_delay(&config).as_millis();
+            assert!((40..=60).contains(&delay));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_full_loss_drops_everything() {
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let conditions: NetemHandle = Arc::new(RwLock::new(NetemConfig {
+            loss: 1.0,
+            ..Default::default()
+        }));
+        let link = NetemLink::spawn(
+            a.local_addr().unwrap(),
+            b.local_addr().unwrap(),
+            conditions.clone(),
+        )
+        .await
+        .unwrap();
+
+        a.send_to(b"lost", link.a_side).await.unwrap();
+        let mut buf = [0u8; 16];
+        let result = tokio::time::timeout(Duration::from_millis(200), b.recv_from(&mut buf)).await;
+        assert!(result.is_err());
+
+        // Lifting the loss lets traffic through again
+        conditions.write().await.loss = 0.0;
+        a.send_to(b"found", link.a_side).await.unwrap();
+        let (len, from) = b.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"found");
+        assert_eq!(from, link.b_side);
+    }
+
+    #[tokio::test]
+    async fn test_session_over_impaired_link() {
+        let (mut alice, alice_addr) = bind_manager().await;
+        let (mut bob, bob_addr) = bind_manager().await;
+        let conditions: NetemHandle = Arc::new(RwLock::new(NetemConfig {
+            latency_ms: 20,
+            jitter_ms: 5,
+            loss: 0.05,
+            reorder: 0.05,
+        }));
+        let link = NetemLink::spawn(alice_addr, bob_addr, conditions)
+            .await
+            .unwrap();
+
+        let mode = EncryptionMode::ChaCha20Poly1305;
+        let (a, b) = tokio::join!(
+            alice.handshake(link.a_side, 10, mode),
+            bob.handshake(link.b_side, 10, mode)
+        );
+        a.unwrap();
+        b.unwrap();
+        alice.upgrade_to_kcp().await.unwrap();
+        bob.upgrade_to_kcp().await.unwrap();
+
+        alice.send_text("hello over netem".into()).await.unwrap();
+        let mut buf = [0u8; 4096];
+        let n = tokio::time::timeout(Duration::from_secs(10), bob.receive_message(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let message: StreamMessage = bincode::deserialize(&buf[..n]).unwrap();
+        assert!(matches!(message, StreamMessage::Text { text, .. } if text == "hello over netem"));
+    }
+}
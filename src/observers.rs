@@ -0,0 +1,165 @@
+//! Read-only observers of the web API.
+//!
+//! An observer is a local process, such as a permanent logger, or a second
+//! UI that should only watch. It is issued a token and presents it as a
+//! bearer token, or as the `observer_token` query parameter where headers
+//! cannot be set (`EventSource`). A request carrying a token may only read
+//! the event stream and state; it can never send, connect or change
+//! settings. Requests without a token keep full access only from this
+//! machine (loopback or the API socket) once any observer exists, and
+//! observers are only managed from this machine.
+//!
+//! Only a SHA-256 of each token is stored, as a JSON file in the data
+//! directory, so observers keep working across restarts.
+
+use crate::storage::{read_json, unix_timestamp, write_json};
+use anyhow::Result;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use tracing::warn;
+
+/// Routes an observer may `GET`.
+pub const OBSERVER_ROUTES: &[&str] = &[
+    "/api/events",
+    "/api/state",
+    "/api/summary",
+    "/api/stats",
+    "/api/sessions",
+    "/api/version",
+    "/api/capabilities",
+];
+
+/// A registered observer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Observer {
+    pub name: String,
+    /// Hex SHA-256 of the token.
+    token_sha256: String,
+    /// Unix timestamp (seconds) the token was issued.
+    pub created_at: u64,
+}
+
+/// Registered observers, backed by a JSON file.
+#[derive(Debug, Clone, Default)]
+pub struct Observers {
+    /// File the observers are saved to. `None` keeps them in memory only.
+    path: Option<PathBuf>,
+    entries: Vec<Observer>,
+}
+
+impl Observers {
+    /// Opens the observers stored at `path`.
+    pub fn open(path: PathBuf) -> Self {
+        let entries = read_json(&path).unwrap_or_else(|e| {
+            warn!("Failed to load observers: {:#}", e);
+            None
+        });
+
+        Self {
+            path: Some(path),
+            entries: entries.unwrap_or_default(),
+        }
+    }
+
+    /// Returns all observers in the order they were added.
+    pub fn all(&self) -> &[Observer] {
+        &self.entries
+    }
+
+    /// Issues a token for a new observer, replacing any with the same name.
+    ///
+    /// # Returns
+    ///
+    /// The token. It is not stored and cannot be shown again.
+    pub fn add(&mut self, name: String) -> Result<String> {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let token = hex(&bytes);
+
+        self.entries.retain(|o| o.name != name);
+        self.entries.push(Observer {
+            name,
+            token_sha256: hex(&Sha256::digest(token.as_bytes())),
+            created_at: unix_timestamp(),
+        });
+        self.save()?;
+        Ok(token)
+    }
+
+    /// Revokes the observer named `name`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(true)` - The observer existed and was removed.
+    /// * `Ok(false)` - No observer has that name.
+    pub fn remove(&mut self, name: &str) -> Result<bool> {
+        let before = self.entries.len();
+        self.entries.retain(|o| o.name != name);
+        if self.entries.len() == before {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Returns the name of the observer `token` was issued to, if any.
+    pub fn authenticate(&self, token: &str) -> Option<&str> {
+        let digest = hex(&Sha256::digest(token.as_bytes()));
+        self.entries
+            .iter()
+            .find(|o| o.token_sha256 == digest)
+            .map(|o| o.name.as_str())
+    }
+
+    fn save(&self) -> Result<()> {
+        match &self.path {
+            Some(path) => write_json(path, &self.entries),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Returns true if an observer may make a request with `method` to `path`.
+pub fn allowed(method: &axum::http::Method, path: &str) -> bool {
+    method == axum::http::Method::GET && OBSERVER_ROUTES.contains(&path)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::TempDir;
+
+    #[test]
+    fn test_tokens_authenticate_until_revoked() {
+        let dir = TempDir::new("observers");
+        let path = dir.join("observers.json");
+        let mut observers = Observers::open(path.clone());
+
+        let token = observers.add("logger".into()).unwrap();
+        assert_eq!(token.len(), 64);
+        assert_eq!(observers.authenticate(&token), Some("logger"));
+        assert_eq!(observers.authenticate("guess"), None);
+        // The token itself is never stored
+        assert!(!std::fs::read_to_string(&path).unwrap().contains(&token));
+
+        // Reissuing under the same name invalidates the old token
+        let reissued = observers.add("logger".into()).unwrap();
+        assert_eq!(observers.authenticate(&token), None);
+        let reopened = Observers::open(path.clone());
+        assert_eq!(reopened.authenticate(&reissued), Some("logger"));
+
+        assert!(observers.remove("logger").unwrap());
+        assert!(!observers.remove("logger").unwrap());
+        assert_eq!(observers.authenticate(&reissued), None);
+
+        assert!(allowed(&axum::http::Method::GET, "/api/events"));
+        assert!(!allowed(&axum::http::Method::POST, "/api/message"));
+        assert!(!allowed(&axum::http::Method::GET, "/api/observers"));
+    }
+}
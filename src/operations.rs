@@ -0,0 +1,172 @@
+//! Registry of long-running actions started through the API.
+//!
+//! Each connect, ping run, probe or self-test gets an ID when it starts. Scripts
+//! can then poll `/api/operations/{id}` for its status, progress and result
+//! instead of following the event stream.
+
+use crate::storage::unix_timestamp;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::VecDeque;
+
+/// Operations kept in memory, finished or not. The oldest are dropped first.
+const MAX_OPERATIONS: usize = 100;
+
+/// What an operation does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationKind {
+    Connect,
+    Ping,
+    Probe,
+    SelfTest,
+}
+
+/// Where an operation is in its lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationStatus {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// A tracked action.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Operation {
+    pub id: u64,
+    pub kind: OperationKind,
+    pub status: OperationStatus,
+    /// Fraction completed, from 0 to 1, for operations that can tell.
+    pub progress: Option<f64>,
+    /// Unix timestamp (seconds) the operation started.
+    pub started_at: u64,
+    /// Unix timestamp (seconds) the operation finished. `None` while running.
+    pub finished_at: Option<u64>,
+    /// Operation-specific result, once succeeded.
+    pub result: Option<Value>,
+    /// Failure detail, once failed.
+    pub error: Option<String>,
+}
+
+/// Recent operations, oldest first.
+#[derive(Debug, Clone, Default)]
+pub struct Operations {
+    last_id: u64,
+    entries: VecDeque<Operation>,
+}
+
+impl Operations {
+    /// Registers a new running operation.
+    ///
+    /// # Returns
+    ///
+    /// The ID to report to the caller and to pass to `finish`.
+    pub fn start(&mut self, kind: OperationKind) -> u64 {
+        self.last_id += 1;
+        if self.entries.len() == MAX_OPERATIONS {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Operation {
+            id: self.last_id,
+            kind,
+            status: OperationStatus::Running,
+            progress: None,
+            started_at: unix_timestamp(),
+            finished_at: None,
+            result: None,
+            error: None,
+        });
+        self.last_id
+    }
+
+    /// Records that `done` of `total` steps have completed.
+    pub fn set_progress(&mut self, id: u64, done: u32, total: u32) {
+        if let Some(op) = self.running_mut(id)
+            && total > 0
+        {
+            op.progress = Some(f64::from(done.min(total)) / f64::from(total));
+        }
+    }
+
+    /// Marks an operation as finished.
+    ///
+    /// Does nothing if the operation already finished or was dropped.
+    pub fn finish(&mut self, id: u64, outcome: Result<Value, String>) {
+        let Some(op) = self.running_mut(id) else {
+            return;
+        };
+        op.finished_at = Some(unix_timestamp());
+        match outcome {
+            Ok(result) => {
+                op.status = OperationStatus::Succeeded;
+                op.progress = Some(1.0);
+                op.result = Some(result);
+            }
+            Err(error) => {
+                op.status = OperationStatus::Failed;
+                op.error = Some(error);
+            }
+        }
+    }
+
+    /// Returns the operation with `id`, if it is still remembered.
+    pub fn get(&self, id: u64) -> Option<&Operation> {
+        self.entries.iter().find(|op| op.id == id)
+    }
+
+    /// Returns remembered operations, oldest first.
+    pub fn all(&self) -> impl DoubleEndedIterator<Item = &Operation> {
+        self.entries.iter()
+    }
+
+    fn running_mut(&mut self, id: u64) -> Option<&mut Operation> {
+        self.entries
+            .iter_mut()
+            .find(|op| op.id == id && op.status == OperationStatus::Running)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_lifecycle() {
+        let mut ops = Operations::default();
+        let ping = ops.start(OperationKind::Ping);
+        let connect = ops.start(OperationKind::Connect);
+        assert_ne!(ping, connect);
+
+        ops.set_progress(ping, 2, 4);
+        assert_eq!(ops.get(ping).unwrap().progress, Some(0.5));
+
+        ops.finish(ping, Ok(json!({ "received": 4 })));
+        ops.finish(connect, Err("Handshake failed".into()));
+        // Finishing twice keeps the first outcome
+        ops.finish(connect, Ok(json!({})));
+
+        let ping = ops.get(ping).unwrap();
+        assert_eq!(ping.status, OperationStatus::Succeeded);
+        assert_eq!(ping.progress, Some(1.0));
+        assert_eq!(ping.result, Some(json!({ "received": 4 })));
+
+        let connect = ops.get(connect).unwrap();
+        assert_eq!(connect.status, OperationStatus::Failed);
+        assert_eq!(connect.error.as_deref(), Some("Handshake failed"));
+        assert!(connect.finished_at.is_some());
+    }
+
+    #[test]
+    fn test_drops_oldest() {
+        let mut ops = Operations::default();
+        let first = ops.start(OperationKind::SelfTest);
+        for _ in 0..MAX_OPERATIONS {
+            ops.start(OperationKind::SelfTest);
+        }
+
+        assert!(ops.get(first).is_none());
+        assert_eq!(ops.all().count(), MAX_OPERATIONS);
+    }
+}
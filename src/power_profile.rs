@@ -0,0 +1,167 @@
+//! Power and traffic profile of the node.
+//!
+//! On battery or a metered link, every background packet costs more than a
+//! slightly slower reconnect does. Either puts the node in low-traffic mode,
+//! in which NAT keep-alives are stretched toward the longest gap the mapping
+//! has been seen to survive (see `keep_alive`).
+//!
+//! Hints from the config or `PUT /api/power-profile` win over detection.
+//! Without one, battery power is read from `/sys/class/power_supply` (Linux
+//! only; elsewhere the node is taken to be on mains power), and a configured
+//! monthly data cap counts as a metered link.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Where Linux lists batteries and power adapters.
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+/// What the user says about the link and power source. A `None` field is
+/// left to detection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PowerHints {
+    #[serde(default)]
+    pub metered: Option<bool>,
+    #[serde(default)]
+    pub on_battery: Option<bool>,
+}
+
+/// The profile in force, as reported in `/api/state`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PowerProfile {
+    pub metered: bool,
+    pub on_battery: bool,
+    /// Background traffic is reduced: metered or on battery.
+    pub low_traffic: bool,
+    pub hints: PowerHints,
+    /// Longest gap between NAT keep-alives at the moment.
+    pub keep_alive_secs: Option<u64>,
+    /// A monthly data cap is configured.
+    #[serde(skip)]
+    data_capped: bool,
+    #[serde(skip)]
+    power_supply_dir: PathBuf,
+}
+
+impl Default for PowerProfile {
+    fn default() -> Self {
+        Self::new(PowerHints::default(), false)
+    }
+}
+
+impl PowerProfile {
+    /// Creates the profile from the configured hints and detects the rest.
+    ///
+    /// # Arguments
+    ///
+    /// * `hints` - Hints from the config.
+    /// * `data_capped` - Whether a monthly data cap is configured.
+    pub fn new(hints: PowerHints, data_capped: bool) -> Self {
+        let mut profile = Self {
+            metered: false,
+            on_battery: false,
+            low_traffic: false,
+            hints,
+            keep_alive_secs: None,
+            data_capped,
+            power_supply_dir: PathBuf::from(POWER_SUPPLY_DIR),
+        };
+        profile.refresh();
+        profile
+    }
+
+    /// Replaces the hints and re-evaluates the profile.
+    ///
+    /// # Returns
+    ///
+    /// True if the profile changed.
+    pub fn set_hints(&mut self, hints: PowerHints) -> bool {
+        let changed = self.hints != hints;
+        self.hints = hints;
+        self.refresh() || changed
+    }
+
+    /// Detects the power source again, for what the hints leave open.
+    ///
+    /// # Returns
+    ///
+    /// True if the profile changed.
+    pub fn refresh(&mut self) -> bool {
+        let metered = self.hints.metered.unwrap_or(self.data_capped);
+        let on_battery = self
+            .hints
+            .on_battery
+            .unwrap_or_else(|| on_battery(&self.power_supply_dir).unwrap_or(false));
+        let changed = (metered, on_battery) != (self.metered, self.on_battery);
+        self.metered = metered;
+        self.on_battery = on_battery;
+        self.low_traffic = metered || on_battery;
+        changed
+    }
+}
+
+/// Reads whether the machine runs on battery from a sysfs `power_supply`
+/// directory.
+///
+/// # Returns
+///
+/// `Some(true)` if a battery is discharging, `Some(false)` if there is a
+/// battery and none is, and `None` if there is no battery or no such
+/// directory.
+fn on_battery(dir: &Path) -> Option<bool> {
+    let read = |path: PathBuf| fs::read_to_string(path).ok();
+    let mut found = false;
+    for entry in fs::read_dir(dir).ok()?.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if read(path.join("type")).as_deref().map(str::trim) != Some("Battery") {
+            continue;
+        }
+        found = true;
+        if read(path.join("status")).as_deref().map(str::trim) == Some("Discharging") {
+            return Some(true);
+        }
+    }
+    found.then_some(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::TempDir;
+
+    #[test]
+    fn test_hints_override_detection() {
+        let dir = TempDir::new("power");
+        let battery = dir.join("BAT0");
+        fs::create_dir_all(&battery).unwrap();
+        fs::create_dir_all(dir.join("AC")).unwrap();
+        fs::write(dir.join("AC/type"), "Mains\n").unwrap();
+        fs::write(battery.join("type"), "Battery\n").unwrap();
+        fs::write(battery.join("status"), "Charging\n").unwrap();
+
+        let mut profile = PowerProfile {
+            power_supply_dir: dir.path().to_path_buf(),
+            ..PowerProfile::new(PowerHints::default(), true)
+        };
+        profile.refresh();
+        // A data cap alone makes the link count as metered
+        assert!(profile.metered && !profile.on_battery && profile.low_traffic);
+
+        fs::write(battery.join("status"), "Discharging\n").unwrap();
+        assert!(profile.refresh());
+        assert!(profile.on_battery);
+
+        assert!(profile.set_hints(PowerHints {
+            metered: Some(false),
+            on_battery: Some(false),
+        }));
+        assert!(!profile.low_traffic);
+        assert!(!profile.set_hints(profile.hints));
+
+        assert_eq!(on_battery(&dir.join("missing")), None);
+    }
+}
@@ -0,0 +1,227 @@
+//! Dry-run reachability probe.
+//!
+//! Before committing to a full handshake, a user can check that an address
+//! is worth dialling. A few SYNs without a cookie go out from a throwaway
+//! socket; an idle GhostLink node answers those with a `Retry` and keeps no
+//! state, so the probe raises no connection request on the other side.
+//!
+//! Silence is not a verdict. A peer behind NAT drops packets until it
+//! punches towards us itself, and a node that is busy with a session or a
+//! handshake ignores strangers. A refusal or an answer is conclusive.
+
+use crate::{
+    config::EncryptionMode,
+    messaging::{
+        crypto::KeyPair,
+        handshake::{Capabilities, HandshakeMsg},
+    },
+    transcript::{Direction, Protocol, Transcript},
+};
+use anyhow::Result;
+use serde::Serialize;
+use std::{
+    io::ErrorKind,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use tokio::{
+    net::UdpSocket,
+    time::{Duration, Instant, timeout_at},
+};
+
+/// Probe packets sent, as UDP may drop one.
+const PROBE_COUNT: u32 = 3;
+
+/// Time between probe packets.
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long to wait for anything to come back.
+const PROBE_WINDOW: Duration = Duration::from_secs(2);
+
+/// What came back from the probed address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeOutcome {
+    /// An idle GhostLink node answered.
+    Listening,
+    /// Something answered, but not with a handshake message.
+    Response,
+    /// The host refused the packets (ICMP unreachable).
+    Unreachable,
+    /// Nothing came back within the window.
+    Silence,
+}
+
+/// Result of a probe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ProbeReport {
+    pub target: SocketAddr,
+    pub outcome: ProbeOutcome,
+    /// Probe packets sent before the outcome was known.
+    pub sent: u32,
+    /// Time from the first probe to the answer, if one came.
+    pub rtt_ms: Option<u64>,
+}
+
+/// Probes `target` and reports what came back.
+///
+/// # Arguments
+///
+/// * `target` - Candidate peer address.
+/// * `transcript` - Records the probe packets with the handshake log.
+///
+/// # Errors
+///
+/// Returns an error if no socket can be opened towards `target`.
+pub async fn probe(target: SocketAddr, transcript: &Transcript) -> Result<ProbeReport> {
+    let bind = if target.is_ipv4() {
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+    } else {
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    // A connected socket reports ICMP errors for its peer on the next call
+    socket.connect(target).await?;
+    let local = socket.local_addr()?;
+
+    let packet = HandshakeMsg::Syn {
+        public_key: KeyPair::generate().public.to_bytes(),
+        cipher_mode: EncryptionMode::ChaCha20Poly1305,
+        capabilities: Capabilities::default(),
+        cookie: None,
+    }
+    .encode();
+
+    let started = Instant::now();
+    let deadline = started + PROBE_WINDOW;
+    let mut next_send = started;
+    let mut sent = 0;
+    let mut buf = [0u8; 2048];
+    let report = |outcome, sent| ProbeReport {
+        target,
+        outcome,
+        sent,
+        rtt_ms: matches!(outcome, ProbeOutcome::Listening | ProbeOutcome::Response)
+            .then(|| started.elapsed().as_millis() as u64),
+    };
+
+    loop {
+        if sent < PROBE_COUNT && Instant::now() >= next_send {
+            if let Err(e) = socket.send(&packet).await {
+                if is_unreachable(&e) {
+                    return Ok(report(ProbeOutcome::Unreachable, sent));
+                }
+                return Err(e.into());
+            }
+            transcript.record(
+                Direction::Sent,
+                Protocol::Handshake,
+                local,
+                target,
+                || "Syn (reachability probe)".into(),
+                &packet,
+            );
+            sent += 1;
+            next_send += PROBE_INTERVAL;
+        }
+
+        let wake = if sent < PROBE_COUNT {
+            next_send.min(deadline)
+        } else {
+            deadline
+        };
+        match timeout_at(wake, socket.recv(&mut buf)).await {
+            Ok(Ok(len)) => {
+                let decoded = HandshakeMsg::decode(&buf[..len]);
+                transcript.record(
+                    Direction::Received,
+                    Protocol::Handshake,
+                    local,
+                    target,
+                    || match &decoded {
+                        Ok(msg) => format!("{:?}", msg),
+                        Err(_) => "<undecodable>".into(),
+                    },
+                    &buf[..len],
+                );
+                let outcome = match decoded {
+                    Ok(_) => ProbeOutcome::Listening,
+                    Err(_) => ProbeOutcome::Response,
+                };
+                return Ok(report(outcome, sent));
+            }
+            Ok(Err(e)) if is_unreachable(&e) => {
+                return Ok(report(ProbeOutcome::Unreachable, sent));
+            }
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) if Instant::now() >= deadline => {
+                return Ok(report(ProbeOutcome::Silence, sent));
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// Returns true for the errors an ICMP unreachable surfaces as.
+fn is_unreachable(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::ConnectionRefused | ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tells_listeners_refusals_and_silence_apart() {
+        let transcript = Transcript::default();
+
+        // An idle node answers a cookie-less SYN with a Retry
+        let node = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let node_addr = node.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 256];
+            let (len, sender) = node.recv_from(&mut buf).await.unwrap();
+            assert!(matches!(
+                HandshakeMsg::decode(&buf[..len]),
+                Ok(HandshakeMsg::Syn { cookie: None, .. })
+            ));
+            let retry = HandshakeMsg::Retry {
+                cookie: Default::default(),
+            };
+            node.send_to(&retry.encode(), sender).await.unwrap();
+        });
+        let report = probe(node_addr, &transcript).await.unwrap();
+        assert_eq!(report.outcome, ProbeOutcome::Listening);
+        assert_eq!(report.sent, 1);
+        assert!(report.rtt_ms.is_some());
+
+        // Something that is not GhostLink
+        let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let other_addr = other.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 256];
+            let (_, sender) = other.recv_from(&mut buf).await.unwrap();
+            other.send_to(b"hello?", sender).await.unwrap();
+        });
+        let report = probe(other_addr, &transcript).await.unwrap();
+        assert_eq!(report.outcome, ProbeOutcome::Response);
+
+        // Nothing bound to the port: the host refuses
+        let closed = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+        let report = probe(closed_addr, &transcript).await.unwrap();
+        assert_eq!(report.outcome, ProbeOutcome::Unreachable);
+        assert_eq!(report.rtt_ms, None);
+
+        // Bound but never answering, like a peer behind NAT
+        let quiet = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let report = probe(quiet.local_addr().unwrap(), &transcript)
+            .await
+            .unwrap();
+        assert_eq!(report.outcome, ProbeOutcome::Silence);
+        assert_eq!(report.sent, PROBE_COUNT);
+    }
+}
@@ -0,0 +1,82 @@
+//! How long past sessions are kept.
+//!
+//! Session history can be kept forever, for a number of days after a session
+//! ends, or only while the session lasts. The global default can be
+//! overridden per contact label, and the open conversation can override both.
+//! A janitor task in `main` prunes expired records every `JANITOR_INTERVAL`;
+//! session-only records are never written to disk at all.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::time::Duration;
+
+/// Time between janitor runs.
+pub const JANITOR_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// How long a finished session is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(tag = "mode", content = "days", rename_all = "snake_case")]
+pub enum Retention {
+    #[default]
+    Forever,
+    /// Kept for this many days after the session ended.
+    Days(u32),
+    /// Forgotten as soon as the session ends.
+    SessionOnly,
+}
+
+impl Retention {
+    /// Returns true if a session that ended at `ended_at` is still kept at `now`.
+    ///
+    /// Both are unix timestamps in seconds.
+    pub fn keeps(self, ended_at: u64, now: u64) -> bool {
+        match self {
+            Retention::Forever => true,
+            Retention::Days(days) => now < ended_at.saturating_add(days as u64 * SECS_PER_DAY),
+            Retention::SessionOnly => false,
+        }
+    }
+}
+
+/// Global retention with per-contact overrides.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Applies to sessions with peers that have no override.
+    pub default: Retention,
+    /// Overrides keyed by contact label.
+    pub contacts: HashMap<String, Retention>,
+}
+
+impl RetentionPolicy {
+    /// Returns the retention for a peer saved as `peer_label`.
+    pub fn for_label(&self, peer_label: Option<&str>) -> Retention {
+        peer_label
+            .and_then(|label| self.contacts.get(label))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contact_override_and_expiry() {
+        let policy = RetentionPolicy {
+            default: Retention::Days(7),
+            contacts: HashMap::from([("Bob".to_string(), Retention::SessionOnly)]),
+        };
+        assert_eq!(policy.for_label(Some("Bob")), Retention::SessionOnly);
+        assert_eq!(policy.for_label(Some("Alice")), Retention::Days(7));
+        assert_eq!(policy.for_label(None), Retention::Days(7));
+
+        let ended = 1_700_000_000;
+        assert!(Retention::Days(7).keeps(ended, ended + 7 * SECS_PER_DAY - 1));
+        assert!(!Retention::Days(7).keeps(ended, ended + 7 * SECS_PER_DAY));
+        assert!(Retention::Forever.keeps(ended, u64::MAX));
+        assert!(!Retention::SessionOnly.keeps(ended, ended));
+    }
+}
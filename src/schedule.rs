@@ -0,0 +1,201 @@
+//! Messages composed now and sent later.
+//!
+//! A scheduled message is addressed to a peer and kept in a JSON file in the
+//! data directory until it is sent or cancelled. The controller sends it once
+//! its time has come and a session with that peer is up; if the peer is not
+//! connected then, it waits for the next session.
+
+use crate::storage::{read_json, unix_timestamp, write_json};
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, path::PathBuf};
+use tracing::warn;
+
+/// Messages that may wait at once.
+pub const MAX_SCHEDULED: usize = 100;
+
+/// A message waiting for its send time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduledMessage {
+    pub id: u64,
+    /// Peer the message goes to.
+    pub peer: SocketAddr,
+    pub text: String,
+    /// Unix timestamp (seconds) the message is due.
+    pub send_at: u64,
+    /// Unix timestamp (seconds) the message was scheduled.
+    pub created_at: u64,
+}
+
+/// Pending scheduled messages, backed by a JSON file.
+///
+/// Serializes as the list of messages.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(transparent)]
+pub struct Schedule {
+    /// File the messages are saved to. `None` keeps them in memory only.
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    /// Ordered by send time, then by ID.
+    entries: Vec<ScheduledMessage>,
+}
+
+impl Schedule {
+    /// Opens the scheduled messages stored at `path`.
+    pub fn open(path: PathBuf) -> Self {
+        let entries: Option<Vec<ScheduledMessage>> = read_json(&path).unwrap_or_else(|e| {
+            warn!("Failed to load scheduled messages: {:#}", e);
+            None
+        });
+
+        let mut schedule = Self {
+            path: Some(path),
+            entries: entries.unwrap_or_default(),
+        };
+        schedule.sort();
+        schedule
+    }
+
+    /// Returns the pending messages, soonest first.
+    pub fn all(&self) -> &[ScheduledMessage] {
+        &self.entries
+    }
+
+    /// Schedules `text` to be sent to `peer` at `send_at`.
+    ///
+    /// # Errors
+    ///
+    /// `MAX_SCHEDULED` messages are already waiting, or saving failed.
+    pub fn add(
+        &mut self,
+        peer: SocketAddr,
+        text: String,
+        send_at: u64,
+    ) -> Result<ScheduledMessage> {
+        if self.entries.len() >= MAX_SCHEDULED {
+            bail!("At most {} messages can be scheduled", MAX_SCHEDULED);
+        }
+        let message = ScheduledMessage {
+            id: self.entries.iter().map(|m| m.id + 1).max().unwrap_or(1),
+            peer,
+            text,
+            send_at,
+            created_at: unix_timestamp(),
+        };
+        self.entries.push(message.clone());
+        self.sort();
+        self.save()?;
+        Ok(message)
+    }
+
+    /// Changes the text or send time of a pending message.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(ScheduledMessage))` - The updated message.
+    /// * `Ok(None)` - No message has that ID; it may have been sent already.
+    pub fn edit(
+        &mut self,
+        id: u64,
+        text: Option<String>,
+        send_at: Option<u64>,
+    ) -> Result<Option<ScheduledMessage>> {
+        let Some(message) = self.entries.iter_mut().find(|m| m.id == id) else {
+            return Ok(None);
+        };
+        if let Some(text) = text {
+            message.text = text;
+        }
+        if let Some(send_at) = send_at {
+            message.send_at = send_at;
+        }
+        let message = message.clone();
+        self.sort();
+        self.save()?;
+        Ok(Some(message))
+    }
+
+    /// Removes a pending message, once sent or when cancelled.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(true)` - The message was pending and is removed.
+    /// * `Ok(false)` - No message has that ID.
+    pub fn remove(&mut self, id: u64) -> Result<bool> {
+        let before = self.entries.len();
+        self.entries.retain(|m| m.id != id);
+        if self.entries.len() == before {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Returns the messages for `peer` due at unix time `now`, soonest first.
+    pub fn due(&self, peer: SocketAddr, now: u64) -> Vec<ScheduledMessage> {
+        self.entries
+            .iter()
+            .filter(|m| m.peer == peer && m.send_at <= now)
+            .cloned()
+            .collect()
+    }
+
+    fn sort(&mut self) {
+        self.entries.sort_by_key(|m| (m.send_at, m.id));
+    }
+
+    fn save(&self) -> Result<()> {
+        match &self.path {
+            Some(path) => write_json(path, &self.entries),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::TempDir;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([203, 0, 113, 7], port))
+    }
+
+    #[test]
+    fn test_due_edit_and_persist() {
+        let dir = TempDir::new("schedule");
+        let path = dir.join("scheduled.json");
+
+        let mut schedule = Schedule::open(path.clone());
+        let later = schedule.add(addr(1), "later".into(), 200).unwrap();
+        let sooner = schedule.add(addr(1), "sooner".into(), 100).unwrap();
+        schedule.add(addr(2), "other peer".into(), 100).unwrap();
+        assert_ne!(later.id, sooner.id);
+
+        let due: Vec<_> = schedule
+            .due(addr(1), 150)
+            .into_iter()
+            .map(|m| m.text)
+            .collect();
+        assert_eq!(due, ["sooner"]);
+
+        // Moving a message earlier puts it first
+        schedule
+            .edit(later.id, Some("edited".into()), Some(50))
+            .unwrap();
+        let due: Vec<_> = schedule
+            .due(addr(1), 150)
+            .into_iter()
+            .map(|m| m.text)
+            .collect();
+        assert_eq!(due, ["edited", "sooner"]);
+        assert!(schedule.edit(99, None, Some(1)).unwrap().is_none());
+
+        assert!(schedule.remove(sooner.id).unwrap());
+        assert!(!schedule.remove(sooner.id).unwrap());
+
+        let reopened = Schedule::open(path.clone());
+        assert_eq!(reopened.all().len(), 2);
+        assert_eq!(reopened.all()[0].text, "edited");
+    }
+}
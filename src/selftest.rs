@@ -0,0 +1,262 @@
+//! Loopback self-test for GhostLink.
+//!
+//! Runs a complete session between two in-process endpoints on localhost so
+//! users can rule out local problems (firewall, crypto, KCP) before blaming
+//! the network.
+
+use crate::{
+    config::EncryptionMode,
+    messaging::message_manager::{MessageManager, StreamMessage},
+    web::shared_state::{AppEvent, AppState, Command, SharedState},
+};
+use anyhow::{Result, bail};
+use serde::Serialize;
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{
+    net::UdpSocket,
+    sync::{RwLock, broadcast, mpsc},
+    time::{Duration, Instant, timeout},
+};
+use tracing::{debug, info};
+
+/// Seconds allowed for the loopback handshake.
+const HANDSHAKE_TIMEOUT_SECS: u64 = 5;
+
+/// Time allowed for each leg of the message round-trip.
+const ROUND_TRIP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Payload exchanged during the round-trip stage.
+const PROBE_TEXT: &str = "ghostlink-selftest";
+
+/// Steps of the self-test, in the order they run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Stage {
+    /// Binding two local UDP sockets.
+    Bind,
+    /// Hole punching and key exchange.
+    Handshake,
+    /// Both sides derived the same session keys.
+    Encryption,
+    /// Wrapping the sockets in KCP streams.
+    KcpUpgrade,
+    /// Sending a message and receiving the echo.
+    RoundTrip,
+}
+
+/// Outcome of a single stage.
+#[derive(Debug, Clone, Serialize)]
+pub struct StageResult {
+    pub stage: Stage,
+    pub passed: bool,
+    pub elapsed_ms: u64,
+    /// Failure detail.
+    pub error: Option<String>,
+}
+
+/// Result of a full self-test run.
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfTestReport {
+    /// True if every stage passed.
+    pub passed: bool,
+    /// First stage that failed, if any. Later stages are not run.
+    pub failed_stage: Option<Stage>,
+    pub stages: Vec<StageResult>,
+}
+
+impl SelfTestReport {
+    fn record<T>(&mut self, stage: Stage, started: Instant, result: &Result<T>) {
+        let error = result.as_ref().err().map(|e| e.to_string());
+        if error.is_some() {
+            self.passed = false;
+            self.failed_stage = Some(stage);
+        }
+        self.stages.push(StageResult {
+            stage,
+            passed: error.is_none(),
+            elapsed_ms: started.elapsed().as_millis() as u64,
+            error,
+        });
+    }
+}
+
+/// Runs the loopback self-test.
+///
+/// Stops at the first failing stage.
+///
+/// # Arguments
+///
+/// * `mode` - Encryption mode both endpoints use.
+pub async fn run(mode: EncryptionMode) -> SelfTestReport {
+    let mut report = SelfTestReport {
+        passed: true,
+        failed_stage: None,
+        stages: Vec::new(),
+    };
+
+    // 1. Bind
+    let started = Instant::now();
+    let endpoints = bind_endpoints().await;
+    report.record(Stage::Bind, started, &endpoints);
+    let Ok((mut alice, mut bob)) = endpoints else {
+        return report;
+    };
+
+    // 2. Handshake
+    let started = Instant::now();
+    let (a, b) = tokio::join!(
+        alice
+            .manager
+            .handshake(bob.addr, HANDSHAKE_TIMEOUT_SECS, mode),
+        bob.manager
+            .handshake(alice.addr, HANDSHAKE_TIMEOUT_SECS, mode)
+    );
+    let result = a.and(b);
+    report.record(Stage::Handshake, started, &result);
+    if result.is_err() {
+        return report;
+    }
+
+    // 3. Encryption
+    let started = Instant::now();
+    let result = check_fingerprints(&alice, &bob).await;
+    report.record(Stage::Encryption, started, &result);
+    if result.is_err() {
+        return report;
+    }
+
+    // 4. KCP upgrade
+    let started = Instant::now();
+    let result = match alice.manager.upgrade_to_kcp().await {
+        Ok(()) => bob.manager.upgrade_to_kcp().await,
+        Err(e) => Err(e),
+    };
+    report.record(Stage::KcpUpgrade, started, &result);
+    if result.is_err() {
+        return report;
+    }
+
+    // 5. Round-trip
+    let started = Instant::now();
+    let result = round_trip(&mut alice.manager, &mut bob.manager).await;
+    report.record(Stage::RoundTrip, started, &result);
+
+    // Bob acknowledges right away so Alice's disconnect is confirmed
+    let _ = tokio::join!(
+        alice.manager.disconnect(),
+        bob.manager.disconnect_on_bye_received()
+    );
+
+    info!("Self-test finished: passed={}", report.passed);
+    report
+}
+
+/// One side of the loopback session.
+struct Endpoint {
+    manager: MessageManager,
+    addr: SocketAddr,
+    /// Private state, not connected to the UI.
+    state: SharedState,
+}
+
+/// Binds two endpoints on localhost.
+async fn bind_endpoints() -> Result<(Endpoint, Endpoint)> {
+    Ok((bind_endpoint().await?, bind_endpoint().await?))
+}
+
+async fn bind_endpoint() -> Result<Endpoint> {
+    let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let addr = socket.local_addr()?;
+
+    let (cmd_tx, mut cmd_rx) = mpsc::channel::<Command>(8);
+    let (event_tx, _) = broadcast::channel::<AppEvent>(8);
+    tokio::spawn(async move { while cmd_rx.recv().await.is_some() {} });
+    let state = Arc::new(RwLock::new(AppState::new(cmd_tx, event_tx)));
+
+    Ok(Endpoint {
+        manager: MessageManager::new(socket, state.clone()),
+        addr,
+        state,
+    })
+}
+
+/// Both sides must have derived the same SAS fingerprint.
+async fn check_fingerprints(alice: &Endpoint, bob: &Endpoint) -> Result<()> {
+    let a = alice.state.read().await.fingerprint.clone();
+    let b = bob.state.read().await.fingerprint.clone();
+    match (a, b) {
+        (Some(a), Some(b)) if a == b => Ok(()),
+        (Some(a), Some(b)) => bail!("Fingerprint mismatch: {} vs {}", a, b),
+        _ => bail!("Session keys were not derived"),
+    }
+}
+
+/// Sends a probe from `alice` to `bob` and echoes it back.
+async fn round_trip(alice: &mut MessageManager, bob: &mut MessageManager) -> Result<()> {
+    alice.send_text(PROBE_TEXT.into()).await?;
+    let received = receive_text(bob).await?;
+    if received != PROBE_TEXT {
+        bail!("Peer received corrupted message");
+    }
+    debug!("Self-test probe delivered, echoing");
+
+    bob.send_text(received).await?;
+    let echoed = receive_text(alice).await?;
+    if echoed != PROBE_TEXT {
+        bail!("Echo corrupted");
+    }
+    Ok(())
+}
+
+async fn receive_text(manager: &mut MessageManager) -> Result<String> {
+    let mut buf = [0u8; 4096];
+    let n = timeout(ROUND_TRIP_TIMEOUT, manager.receive_message(&mut buf))
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out waiting for message"))??;
+    match StreamMessage::decode(&buf[..n])? {
+        StreamMessage::Text { text, .. } => Ok(text),
+        other => bail!("Unexpected message: {:?}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_selftest_passes_on_loopback() {
+        let report = run(EncryptionMode::Aes256Gcm).await;
+
+        assert!(report.passed, "{:?}", report);
+        assert_eq!(report.failed_stage, None);
+        let stages: Vec<_> = report.stages.iter().map(|s| s.stage).collect();
+        assert_eq!(
+            stages,
+            vec![
+                Stage::Bind,
+                Stage::Handshake,
+                Stage::Encryption,
+                Stage::KcpUpgrade,
+                Stage::RoundTrip
+            ]
+        );
+    }
+
+    #[test]
+    fn test_report_records_first_failure() {
+        let mut report = SelfTestReport {
+            passed: true,
+            failed_stage: None,
+            stages: Vec::new(),
+        };
+        report.record(Stage::Bind, Instant::now(), &Ok(()));
+        report.record(
+            Stage::Handshake,
+            Instant::now(),
+            &Err::<(), _>(anyhow::anyhow!("timeout")),
+        );
+
+        assert!(!report.passed);
+        assert_eq!(report.failed_stage, Some(Stage::Handshake));
+        assert_eq!(report.stages[1].error.as_deref(), Some("timeout"));
+    }
+}
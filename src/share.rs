@@ -0,0 +1,330 @@
+//! Read-only folder sharing with the connected peer.
+//!
+//! Each share in the config names a local directory and the contacts allowed
+//! to browse it. The peer can list directories and read byte ranges of
+//! files; nothing can be written, and paths cannot leave the share root,
+//! symlinks included. Every access is logged and shown in the UI.
+
+use crate::config::SharedFolder;
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path, PathBuf};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::oneshot,
+};
+
+/// Largest range returned by one read. Responses must fit in a single
+/// stream message together with framing, encryption and padding.
+pub const MAX_READ_LEN: u32 = 2048;
+
+/// Encoded bytes of the entries returned by one listing; the rest is marked
+/// truncated. Leaves room in the frame for the reply around them.
+const MAX_LISTING_BYTES: u64 = 2048;
+
+/// A request from the peer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ShareRequest {
+    /// Lists a directory. An empty `share` lists the shares we may browse.
+    List { share: String, path: String },
+    /// Reads up to `len` bytes of a file starting at `offset`.
+    Read {
+        share: String,
+        path: String,
+        offset: u64,
+        len: u32,
+    },
+}
+
+/// An entry in a directory listing.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShareEntry {
+    pub name: String,
+    pub is_dir: bool,
+    /// File size in bytes; 0 for directories.
+    pub size: u64,
+}
+
+/// Successful answer to a `ShareRequest`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ShareResponse {
+    Listing {
+        entries: Vec<ShareEntry>,
+        /// True if some entries did not fit in the response.
+        truncated: bool,
+    },
+    Data {
+        offset: u64,
+        bytes: Vec<u8>,
+        /// Size of the whole file.
+        total_size: u64,
+    },
+}
+
+/// Reply channel for a request we sent to the peer's shares.
+pub type ShareReply = oneshot::Sender<Result<ShareResponse, String>>;
+
+/// Answers a peer's request against our shares.
+///
+/// # Arguments
+///
+/// * `shares` - Shares from the config.
+/// * `peer_label` - Contact label of the peer; only labelled peers can be granted access.
+/// * `request` - What the peer asked for.
+pub async fn handle(
+    shares: &[SharedFolder],
+    peer_label: Option<&str>,
+    request: &ShareRequest,
+) -> Result<ShareResponse, String> {
+    let granted = |share: &&SharedFolder| {
+        peer_label.is_some_and(|label| share.allowed_contacts.iter().any(|c| c == label))
+    };
+
+    let (share, path) = match request {
+        ShareRequest::List { share, path } | ShareRequest::Read { share, path, .. } => {
+            (share, path)
+        }
+    };
+
+    if share.is_empty() {
+        let entries = shares
+            .iter()
+            .filter(granted)
+            .map(|s| ShareEntry {
+                name: s.name.clone(),
+                is_dir: true,
+                size: 0,
+            })
+            .collect();
+        return Ok(listing(entries));
+    }
+
+    let folder = shares
+        .iter()
+        .filter(granted)
+        .find(|s| &s.name == share)
+        .ok_or("No such share")?;
+    let target = resolve(&folder.path, path).await?;
+
+    match request {
+        ShareRequest::List { .. } => list(&target).await,
+        ShareRequest::Read { offset, len, .. } => read(&target, *offset, *len).await,
+    }
+}
+
+/// Maps a peer-supplied relative path onto a file inside `root`.
+async fn resolve(root: &Path, relative: &str) -> Result<PathBuf, String> {
+    let relative = Path::new(relative);
+    if relative
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err("Invalid path".into());
+    }
+
+    let root = fs::canonicalize(root)
+        .await
+        .map_err(|_| "Share is unavailable")?;
+    let target = fs::canonicalize(root.join(relative))
+        .await
+        .map_err(|_| "No such file or directory")?;
+
+    // A symlink inside the share must not lead outside it
+    if !target.starts_with(&root) {
+        return Err("Invalid path".into());
+    }
+    Ok(target)
+}
+
+async fn list(dir: &Path) -> Result<ShareResponse, String> {
+    let mut reader = fs::read_dir(dir).await.map_err(|_| "Not a directory")?;
+
+    let mut entries = Vec::new();
+    while let Ok(Some(entry)) = reader.next_entry().await {
+        let Ok(meta) = entry.metadata().await else {
+            continue;
+        };
+        entries.push(ShareEntry {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            is_dir: meta.is_dir(),
+            size: if meta.is_dir() { 0 } else { meta.len() },
+        });
+    }
+    Ok(listing(entries))
+}
+
+/// Sorts `entries`, directories first, and keeps those that fit in
+/// `MAX_LISTING_BYTES` once encoded.
+///
+/// Sorting first means the same entries are dropped on every request.
+fn listing(mut entries: Vec<ShareEntry>) -> ShareResponse {
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    let mut used: u64 = 0;
+    let fits = entries
+        .iter()
+        .take_while(|entry| {
+            let size = bincode::serialized_size(entry).unwrap_or(u64::MAX);
+            used = used.saturating_add(size);
+            used <= MAX_LISTING_BYTES
+        })
+        .count();
+    let truncated = fits < entries.len();
+    entries.truncate(fits);
+    ShareResponse::Listing { entries, truncated }
+}
+
+async fn read(path: &Path, offset: u64, len: u32) -> Result<ShareResponse, String> {
+    let mut file = fs::File::open(path).await.map_err(|_| "Cannot open file")?;
+    let meta = file.metadata().await.map_err(|_| "Cannot open file")?;
+    if !meta.is_file() {
+        return Err("Not a file".into());
+    }
+
+    let len = len.min(MAX_READ_LEN) as usize;
+    let mut bytes = vec![0u8; len];
+    file.seek(std::io::SeekFrom::Start(offset))
+        .await
+        .map_err(|_| "Read failed")?;
+
+    let mut filled = 0;
+    while filled < len {
+        match file.read(&mut bytes[filled..]).await {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(_) => return Err("Read failed".into()),
+        }
+    }
+    bytes.truncate(filled);
+
+    Ok(ShareResponse::Data {
+        offset,
+        bytes,
+        total_size: meta.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::TempDir;
+
+    fn setup() -> (TempDir, Vec<SharedFolder>) {
+        let base = TempDir::new("share");
+        let root = base.join("shared");
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(root.join("hello.txt"), b"hello world").unwrap();
+        std::fs::write(base.join("secret.txt"), b"secret").unwrap();
+
+        let shares = vec![SharedFolder {
+            name: "stuff".into(),
+            path: root,
+            allowed_contacts: vec!["Bob".into()],
+        }];
+        (base, shares)
+    }
+
+    fn read_req(path: &str, offset: u64, len: u32) -> ShareRequest {
+        ShareRequest::Read {
+            share: "stuff".into(),
+            path: path.into(),
+            offset,
+            len,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_and_read_for_granted_peer() {
+        let (_base, shares) = setup();
+
+        let roots = ShareRequest::List {
+            share: String::new(),
+            path: String::new(),
+        };
+        match handle(&shares, Some("Bob"), &roots).await.unwrap() {
+            ShareResponse::Listing { entries, .. } => assert_eq!(entries[0].name, "stuff"),
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        let listing = ShareRequest::List {
+            share: "stuff".into(),
+            path: ".".into(),
+        };
+        match handle(&shares, Some("Bob"), &listing).await.unwrap() {
+            ShareResponse::Listing { entries, truncated } => {
+                assert!(!truncated);
+                assert_eq!(entries[0].name, "docs");
+                assert_eq!(entries[1].size, 11);
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        assert_eq!(
+            handle(&shares, Some("Bob"), &read_req("hello.txt", 6, 100)).await,
+            Ok(ShareResponse::Data {
+                offset: 6,
+                bytes: b"world".to_vec(),
+                total_size: 11,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_large_listing_is_truncated_to_fit() {
+        let (_base, shares) = setup();
+        let many = shares[0].path.join("many");
+        std::fs::create_dir_all(&many).unwrap();
+        for i in 0..600 {
+            std::fs::write(many.join(format!("{:03}", i)), b"").unwrap();
+        }
+
+        let request = ShareRequest::List {
+            share: "stuff".into(),
+            path: "many".into(),
+        };
+        let first = handle(&shares, Some("Bob"), &request).await.unwrap();
+        let ShareResponse::Listing { entries, truncated } = &first else {
+            panic!("unexpected response: {:?}", first);
+        };
+        assert!(truncated);
+        assert!(bincode::serialized_size(entries).unwrap() <= MAX_LISTING_BYTES + 8);
+        // Sorted before truncating, so the same entries come back every time
+        assert_eq!(entries[0].name, "000");
+        assert_eq!(handle(&shares, Some("Bob"), &request).await.unwrap(), first);
+    }
+
+    #[tokio::test]
+    async fn test_refuses_ungranted_peers_and_escapes() {
+        let (base, shares) = setup();
+
+        assert!(
+            handle(&shares, Some("Eve"), &read_req("hello.txt", 0, 10))
+                .await
+                .is_err()
+        );
+        assert!(
+            handle(&shares, None, &read_req("hello.txt", 0, 10))
+                .await
+                .is_err()
+        );
+        assert!(
+            handle(&shares, Some("Bob"), &read_req("../secret.txt", 0, 10))
+                .await
+                .is_err()
+        );
+        assert!(
+            handle(&shares, Some("Bob"), &read_req("/etc/passwd", 0, 10))
+                .await
+                .is_err()
+        );
+
+        #[cfg(unix)]
+        {
+            let link = shares[0].path.join("escape.txt");
+            std::os::unix::fs::symlink(base.join("secret.txt"), &link).unwrap();
+            assert_eq!(
+                handle(&shares, Some("Bob"), &read_req("escape.txt", 0, 10)).await,
+                Err("Invalid path".to_string())
+            );
+        }
+    }
+}
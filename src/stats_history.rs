@@ -0,0 +1,342 @@
+//! Link quality over the course of a session.
+//!
+//! While a session is up, the controller takes a sample every
+//! `SAMPLE_INTERVAL`: the latest round-trip time, the share of heartbeats
+//! that went unanswered, and throughput in each direction. Transfers are
+//! marked on the same timeline, so a UI can draw sparklines and show
+//! whether the link degraded when a download started. Finished sessions
+//! are kept in a JSON Lines file next to the session history, and pruned
+//! with it.
+
+use crate::{
+    share::ShareRequest,
+    storage::{read_jsonl_tail, remove_file, write_jsonl},
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, net::SocketAddr, path::PathBuf, time::Duration};
+use tracing::warn;
+
+/// Time between samples.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Samples kept per session; older ones are dropped. One hour at `SAMPLE_INTERVAL`.
+const MAX_SAMPLES: usize = 720;
+
+/// Marks kept per session; older ones are dropped.
+const MAX_MARKS: usize = 200;
+
+/// Finished sessions kept.
+const MAX_SESSIONS: usize = 20;
+
+/// Link counters of the session, as totals since it started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkCounters {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub heartbeats_sent: u64,
+    pub heartbeats_answered: u64,
+}
+
+/// Link quality over one `SAMPLE_INTERVAL`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatsSample {
+    /// Unix timestamp (milliseconds) the sample was taken at.
+    pub at_ms: u64,
+    /// Latest round-trip time measured in the interval, if any was.
+    pub rtt_ms: Option<u64>,
+    /// Heartbeats sent in the interval that were not answered in it.
+    /// `None` if none were sent, as on a busy link.
+    pub loss_percent: Option<u8>,
+    /// Encrypted bytes per second written to the peer.
+    pub tx_bps: u64,
+    /// Encrypted bytes per second read from the peer.
+    pub rx_bps: u64,
+}
+
+/// Something that happened during the session, to line samples up against.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum MarkEvent {
+    /// A file read from the start, by us or by the peer.
+    TransferStarted {
+        share: String,
+        path: String,
+        by_me: bool,
+    },
+}
+
+impl MarkEvent {
+    /// Returns a `TransferStarted` if `request` reads a file from the start.
+    pub fn transfer_started(request: &ShareRequest, by_me: bool) -> Option<Self> {
+        match request {
+            ShareRequest::Read {
+                share,
+                path,
+                offset: 0,
+                ..
+            } => Some(MarkEvent::TransferStarted {
+                share: share.clone(),
+                path: path.clone(),
+                by_me,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// A `MarkEvent` and when it happened.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatsMark {
+    /// Unix timestamp (milliseconds).
+    pub at_ms: u64,
+    #[serde(flatten)]
+    pub event: MarkEvent,
+}
+
+/// Samples and marks of one session.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionStats {
+    /// Connection ID of the session, as in the session history.
+    pub connection_id: Option<String>,
+    pub peer: SocketAddr,
+    /// Unix timestamp (milliseconds) the session was established at.
+    pub started_at_ms: u64,
+    pub samples: VecDeque<StatsSample>,
+    pub marks: VecDeque<StatsMark>,
+}
+
+/// The session being sampled and the finished ones, backed by a JSON Lines file.
+#[derive(Debug, Clone, Default)]
+pub struct StatsHistory {
+    /// File finished sessions are written to. `None` keeps them in memory only.
+    path: Option<PathBuf>,
+    current: Option<SessionStats>,
+    /// Finished sessions, oldest first.
+    past: VecDeque<SessionStats>,
+    /// Counters and time of the previous sample.
+    last: (LinkCounters, u64),
+    /// Latest round-trip time since the previous sample.
+    rtt: Option<Duration>,
+}
+
+impl StatsHistory {
+    /// Opens the history stored at `path`, loading the most recent sessions.
+    pub fn open(path: PathBuf) -> Self {
+        let past = read_jsonl_tail(&path, MAX_SESSIONS).unwrap_or_else(|e| {
+            warn!("Failed to load link statistics: {}", e);
+            Vec::new()
+        });
+        Self {
+            path: Some(path),
+            past: past.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Starts sampling a session established at `now_ms`.
+    pub fn begin(&mut self, connection_id: Option<String>, peer: SocketAddr, now_ms: u64) {
+        self.current = Some(SessionStats {
+            connection_id,
+            peer,
+            started_at_ms: now_ms,
+            samples: VecDeque::new(),
+            marks: VecDeque::new(),
+        });
+        self.last = (LinkCounters::default(), now_ms);
+        self.rtt = None;
+    }
+
+    /// Notes a round-trip time for the next sample.
+    pub fn observe_rtt(&mut self, rtt: Duration) {
+        self.rtt = Some(rtt);
+    }
+
+    /// Takes a sample if `SAMPLE_INTERVAL` passed since the previous one.
+    ///
+    /// # Arguments
+    ///
+    /// * `now_ms` - Current unix timestamp (milliseconds).
+    /// * `counters` - Session totals so far.
+    pub fn sample(&mut self, now_ms: u64, counters: LinkCounters) {
+        let Some(current) = &mut self.current else {
+            return;
+        };
+        let (last, last_ms) = self.last;
+        let elapsed_ms = now_ms.saturating_sub(last_ms);
+        if elapsed_ms < SAMPLE_INTERVAL.as_millis() as u64 {
+            return;
+        }
+
+        let per_sec = |now: u64, before: u64| now.saturating_sub(before) * 1000 / elapsed_ms;
+        let sent = counters
+            .heartbeats_sent
+            .saturating_sub(last.heartbeats_sent);
+        let answered = counters
+            .heartbeats_answered
+            .saturating_sub(last.heartbeats_answered)
+            .min(sent);
+        current.samples.push_back(StatsSample {
+            at_ms: now_ms,
+            rtt_ms: self.rtt.take().map(|rtt| rtt.as_millis() as u64),
+            loss_percent: (sent > 0).then(|| ((sent - answered) * 100 / sent) as u8),
+            tx_bps: per_sec(counters.bytes_sent, last.bytes_sent),
+            rx_bps: per_sec(counters.bytes_received, last.bytes_received),
+        });
+        if current.samples.len() > MAX_SAMPLES {
+            current.samples.pop_front();
+        }
+        self.last = (counters, now_ms);
+    }
+
+    /// Marks `event` on the timeline of the session being sampled.
+    pub fn mark(&mut self, now_ms: u64, event: MarkEvent) {
+        if let Some(current) = &mut self.current {
+            current.marks.push_back(StatsMark {
+                at_ms: now_ms,
+                event,
+            });
+            if current.marks.len() > MAX_MARKS {
+                current.marks.pop_front();
+            }
+        }
+    }
+
+    /// Stops sampling the session, keeping it if `keep` is set.
+    ///
+    /// Guest and session-only sessions are not kept, like their history.
+    pub fn finish(&mut self, keep: bool) {
+        let Some(current) = self.current.take() else {
+            return;
+        };
+        if !keep {
+            return;
+        }
+        self.past.push_back(current);
+        if self.past.len() > MAX_SESSIONS {
+            self.past.pop_front();
+        }
+        self.save();
+    }
+
+    /// Returns the session being sampled, if any.
+    pub fn current(&self) -> Option<&SessionStats> {
+        self.current.as_ref()
+    }
+
+    /// Returns the finished sessions, oldest first.
+    pub fn past(&self) -> impl DoubleEndedIterator<Item = &SessionStats> {
+        self.past.iter()
+    }
+
+    /// Returns the session with `connection_id`, open or finished.
+    pub fn find(&self, connection_id: &str) -> Option<&SessionStats> {
+        self.current
+            .iter()
+            .chain(self.past.iter().rev())
+            .find(|stats| stats.connection_id.as_deref() == Some(connection_id))
+    }
+
+    /// Forgets finished sessions whose connection ID `keeps` rejects.
+    ///
+    /// # Returns
+    ///
+    /// The number of sessions forgotten.
+    pub fn retain(&mut self, keeps: impl Fn(&str) -> bool) -> usize {
+        let before = self.past.len();
+        self.past
+            .retain(|stats| stats.connection_id.as_deref().is_some_and(&keeps));
+        let removed = before - self.past.len();
+        if removed > 0 {
+            self.save();
+        }
+        removed
+    }
+
+    /// Forgets every session, including the one being sampled, and deletes the file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file could not be deleted.
+    pub fn clear(&mut self) -> Result<()> {
+        self.current = None;
+        self.past.clear();
+        if let Some(path) = &self.path {
+            remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn save(&self) {
+        let past: Vec<_> = self.past.iter().collect();
+        if let Some(path) = &self.path
+            && let Err(e) = write_jsonl(path, &past)
+        {
+            warn!("Failed to save link statistics: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_samples_rates_loss_and_marks() {
+        let mut history = StatsHistory::default();
+        let peer: SocketAddr = "203.0.113.7:41234".parse().unwrap();
+        let start = 1_700_000_000_000;
+        history.begin(Some("c1".into()), peer, start);
+
+        let mut counters = LinkCounters {
+            bytes_sent: 5_000,
+            bytes_received: 50_000,
+            heartbeats_sent: 2,
+            heartbeats_answered: 1,
+        };
+        // Too soon for a sample
+        history.sample(start + 1000, counters);
+        assert!(history.current().unwrap().samples.is_empty());
+
+        history.observe_rtt(Duration::from_millis(42));
+        history.sample(start + 5000, counters);
+        history.mark(
+            start + 6000,
+            MarkEvent::TransferStarted {
+                share: "photos".into(),
+                path: "a.jpg".into(),
+                by_me: true,
+            },
+        );
+        counters.bytes_received += 500_000;
+        history.sample(start + 10_000, counters);
+
+        let stats = history.current().unwrap();
+        assert_eq!(
+            stats.samples[0],
+            StatsSample {
+                at_ms: start + 5000,
+                rtt_ms: Some(42),
+                loss_percent: Some(50),
+                tx_bps: 1000,
+                rx_bps: 10_000,
+            }
+        );
+        // No heartbeats and no new RTT in the second interval
+        assert_eq!(stats.samples[1].rx_bps, 100_000);
+        assert_eq!(stats.samples[1].loss_percent, None);
+        assert_eq!(stats.samples[1].rtt_ms, None);
+        assert_eq!(stats.marks.len(), 1);
+
+        history.finish(true);
+        assert!(history.current().is_none());
+        assert_eq!(history.find("c1").unwrap().samples.len(), 2);
+
+        history.begin(Some("c2".into()), peer, start + 20_000);
+        history.finish(false);
+        assert!(history.find("c2").is_none());
+
+        assert_eq!(history.retain(|id| id != "c1"), 1);
+        assert_eq!(history.past().count(), 0);
+    }
+}
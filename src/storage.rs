@@ -0,0 +1,292 @@
+//! On-disk persistence helpers for GhostLink.
+//!
+//! Everything GhostLink remembers between runs lives as small JSON or
+//! JSON Lines files inside the configured data directory.
+
+use anyhow::{Context, Result};
+use serde::{Serialize, de::DeserializeOwned};
+use std::{
+    fs::{self, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+/// Returns the current wall-clock time as seconds since the Unix epoch.
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Returns the current wall-clock time as milliseconds since the Unix epoch.
+pub fn unix_timestamp_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Converts a Unix timestamp to a (year, month, day) triple in UTC.
+pub fn utc_date(unix_secs: u64) -> (i64, u32, u32) {
+    // Civil-from-days, counting from 0000-03-01 so leap days fall at year end
+    let days = (unix_secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Formats a Unix timestamp as `YYYY-MM-DD HH:MM UTC`.
+pub fn format_utc(unix_secs: u64) -> String {
+    let (year, month, day) = utc_date(unix_secs);
+    let secs_of_day = unix_secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60
+    )
+}
+
+/// Appends a single record as one line of JSON to `path`.
+///
+/// Parent directories are created on demand.
+pub fn append_jsonl<T: Serialize>(path: &Path, record: &T) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+    }
+
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// Replaces `path` with one line of JSON per record.
+///
+/// Written to a temporary file and renamed into place, like `write_json`.
+pub fn write_jsonl<T: Serialize>(path: &Path, records: &[T]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+    }
+
+    let mut text = String::new();
+    for record in records {
+        text.push_str(&serde_json::to_string(record)?);
+        text.push('\n');
+    }
+
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, text).with_context(|| format!("Failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
+
+/// Reads at most the last `limit` records from a JSON Lines file.
+///
+/// A missing file yields an empty list. Lines that fail to parse are
+/// skipped with a warning so one corrupt entry cannot hide the rest.
+pub fn read_jsonl_tail<T: DeserializeOwned>(path: &Path, limit: usize) -> Result<Vec<T>> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to open {}", path.display())),
+    };
+
+    let mut records = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(record) => records.push(record),
+            Err(e) => warn!("Skipping malformed line in {}: {}", path.display(), e),
+        }
+    }
+
+    let skip = records.len().saturating_sub(limit);
+    Ok(records.split_off(skip))
+}
+
+/// Writes `value` as pretty JSON to `path`, replacing any existing file.
+///
+/// The data is written to a temporary file and renamed into place so a crash
+/// never leaves a truncated file behind.
+pub fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+    }
+
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(value)?)
+        .with_context(|| format!("Failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
+
+/// Deletes a file, if it exists.
+///
+/// # Returns
+///
+/// True if there was a file to delete.
+pub fn remove_file(path: &Path) -> Result<bool> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("Failed to delete {}", path.display())),
+    }
+}
+
+/// Reads a JSON file written by `write_json`.
+///
+/// # Returns
+///
+/// * `Ok(None)` - The file does not exist.
+/// * `Ok(Some(T))` - The parsed value.
+/// * `Err` - The file could not be read or parsed.
+pub fn read_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let value = serde_json::from_slice(&bytes)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    Ok(Some(value))
+}
+
+/// Scratch directory for tests, unique to the test and removed when dropped.
+#[cfg(test)]
+pub struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    /// Creates an empty directory named after `name`, the process and a
+    /// counter, so tests running in parallel never share one.
+    pub fn new(name: &str) -> Self {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "ghostlink-{}-{}-{}",
+            name,
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).expect("Failed to create test directory");
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Returns the path of `name` inside the directory.
+    pub fn join(&self, name: impl AsRef<Path>) -> std::path::PathBuf {
+        self.0.join(name)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Entry {
+        n: u32,
+    }
+
+    #[test]
+    fn test_format_utc() {
+        assert_eq!(format_utc(0), "1970-01-01 00:00 UTC");
+        assert_eq!(format_utc(1_709_251_199), "2024-02-29 23:59 UTC");
+        assert_eq!(format_utc(1_798_718_400), "2026-12-31 12:00 UTC");
+    }
+
+    #[test]
+    fn test_append_and_read_roundtrip() {
+        let dir = TempDir::new("storage");
+        // Missing parent directories are created
+        let path = dir.join("nested/data.jsonl");
+
+        for n in 0..5 {
+            append_jsonl(&path, &Entry { n }).unwrap();
+        }
+
+        let all: Vec<Entry> = read_jsonl_tail(&path, 10).unwrap();
+        assert_eq!(all.len(), 5);
+
+        let tail: Vec<Entry> = read_jsonl_tail(&path, 2).unwrap();
+        assert_eq!(tail, vec![Entry { n: 3 }, Entry { n: 4 }]);
+    }
+
+    #[test]
+    fn test_read_missing_file_is_empty() {
+        let dir = TempDir::new("storage");
+        let records: Vec<Entry> = read_jsonl_tail(&dir.join("data.jsonl"), 10).unwrap();
+        assert!(records.is_empty());
+    }
+
+    #[test]
+    fn test_read_skips_malformed_lines() {
+        let dir = TempDir::new("storage");
+        let path = dir.join("data.jsonl");
+
+        append_jsonl(&path, &Entry { n: 1 }).unwrap();
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"not json\n")
+            .unwrap();
+        append_jsonl(&path, &Entry { n: 2 }).unwrap();
+
+        let records: Vec<Entry> = read_jsonl_tail(&path, 10).unwrap();
+        assert_eq!(records, vec![Entry { n: 1 }, Entry { n: 2 }]);
+    }
+
+    #[test]
+    fn test_write_and_read_json() {
+        let dir = TempDir::new("storage");
+        let path = dir.join("value.json");
+
+        assert_eq!(read_json::<Entry>(&path).unwrap(), None);
+
+        write_json(&path, &Entry { n: 1 }).unwrap();
+        write_json(&path, &Entry { n: 2 }).unwrap();
+        assert_eq!(read_json::<Entry>(&path).unwrap(), Some(Entry { n: 2 }));
+
+        fs::write(&path, b"{").unwrap();
+        assert!(read_json::<Entry>(&path).is_err());
+    }
+}
@@ -0,0 +1,142 @@
+//! Counters of outgoing traffic by class.
+//!
+//! Users on metered connections can see what GhostLink sends: STUN probes
+//! and handshake packets are counted as they hit the socket, session messages
+//! as they are handed to KCP. KCP headers, acknowledgments and retransmissions
+//! are not visible from here and are not included.
+
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+
+/// What an outgoing packet or message is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrafficClass {
+    /// Hole punching, handshake and out-of-band Bye packets.
+    Handshake,
+    /// Public address and NAT type probes.
+    Stun,
+    /// Text, reactions and assist mode.
+    Chat,
+    /// Shared folder listings and reads.
+    File,
+    /// Pings, pongs and disconnect frames.
+    Control,
+}
+
+impl TrafficClass {
+    pub const ALL: [TrafficClass; 5] = [
+        TrafficClass::Handshake,
+        TrafficClass::Stun,
+        TrafficClass::Chat,
+        TrafficClass::File,
+        TrafficClass::Control,
+    ];
+
+    /// Name used in API output and metric labels.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TrafficClass::Handshake => "handshake",
+            TrafficClass::Stun => "stun",
+            TrafficClass::Chat => "chat",
+            TrafficClass::File => "file",
+            TrafficClass::Control => "control",
+        }
+    }
+}
+
+/// Bytes and packets sent in one class.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct TrafficCounter {
+    pub bytes: u64,
+    pub packets: u64,
+}
+
+/// Shared handle to the traffic counters.
+///
+/// Cloning is cheap; all clones count into the same totals.
+#[derive(Debug, Clone, Default)]
+pub struct Traffic {
+    counters: Arc<Mutex<[TrafficCounter; TrafficClass::ALL.len()]>>,
+}
+
+impl Traffic {
+    /// Counts one packet or message of `bytes` sent in `class`.
+    pub fn record(&self, class: TrafficClass, bytes: usize) {
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        let counter = &mut counters[class as usize];
+        counter.bytes += bytes as u64;
+        counter.packets += 1;
+    }
+
+    /// Returns the totals since startup, one entry per class.
+    pub fn snapshot(&self) -> Vec<(TrafficClass, TrafficCounter)> {
+        let counters = *self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        TrafficClass::ALL.into_iter().zip(counters).collect()
+    }
+
+    /// Renders the totals in the Prometheus text exposition format.
+    pub fn prometheus(&self) -> String {
+        let snapshot = self.snapshot();
+        let mut out = String::new();
+        let mut metric = |name: &str, help: &str, value: fn(&TrafficCounter) -> u64| {
+            out.push_str(&format!("# HELP {name} {help}\n# TYPE {name} counter\n"));
+            for (class, counter) in &snapshot {
+                out.push_str(&format!(
+                    "{name}{{class=\"{}\"}} {}\n",
+                    class.as_str(),
+                    value(counter)
+                ));
+            }
+        };
+        metric(
+            "ghostlink_sent_bytes_total",
+            "Bytes sent, by traffic class.",
+            |c| c.bytes,
+        );
+        metric(
+            "ghostlink_sent_packets_total",
+            "Packets or messages sent, by traffic class.",
+            |c| c.packets,
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts_per_class() {
+        let traffic = Traffic::default();
+        let clone = traffic.clone();
+        traffic.record(TrafficClass::Chat, 100);
+        clone.record(TrafficClass::Chat, 50);
+        traffic.record(TrafficClass::Stun, 20);
+
+        let snapshot = traffic.snapshot();
+        assert_eq!(snapshot.len(), TrafficClass::ALL.len());
+        let get = |class| snapshot.iter().find(|(c, _)| *c == class).unwrap().1;
+        assert_eq!(
+            get(TrafficClass::Chat),
+            TrafficCounter {
+                bytes: 150,
+                packets: 2
+            }
+        );
+        assert_eq!(get(TrafficClass::Stun).packets, 1);
+        assert_eq!(get(TrafficClass::File), TrafficCounter::default());
+    }
+
+    #[test]
+    fn test_prometheus_output() {
+        let traffic = Traffic::default();
+        traffic.record(TrafficClass::Handshake, 64);
+
+        let text = traffic.prometheus();
+        assert!(text.contains("# TYPE ghostlink_sent_bytes_total counter\n"));
+        assert!(text.contains("ghostlink_sent_bytes_total{class=\"handshake\"} 64\n"));
+        assert!(text.contains("ghostlink_sent_packets_total{class=\"handshake\"} 1\n"));
+        assert!(text.contains("ghostlink_sent_packets_total{class=\"file\"} 0\n"));
+    }
+}
@@ -0,0 +1,296 @@
+//! Packet transcript for debugging connection setup.
+//!
+//! When enabled, every STUN and handshake packet sent or received is kept in a
+//! bounded ring buffer so a trace can be attached to "punching never completes"
+//! reports. The same packets can also be written to a pcap file on demand.
+
+use crate::{
+    capture::PcapWriter,
+    storage::unix_timestamp_ms,
+    traffic::{Traffic, TrafficClass},
+};
+use anyhow::{Result, bail};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tracing::{info, warn};
+
+/// Number of packets kept before the oldest are dropped.
+const MAX_ENTRIES: usize = 512;
+
+/// Whether a packet left or arrived at this host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// Protocol a recorded packet belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Protocol {
+    Stun,
+    Handshake,
+}
+
+/// A single captured packet.
+#[derive(Debug, Clone, Serialize)]
+pub struct PacketEntry {
+    /// Unix timestamp in milliseconds.
+    pub timestamp_ms: u64,
+    pub direction: Direction,
+    pub protocol: Protocol,
+    /// Remote address the packet was sent to or received from.
+    pub remote: SocketAddr,
+    /// Human-readable decoding of the packet.
+    pub summary: String,
+    /// Raw bytes as lowercase hex.
+    pub raw_hex: String,
+}
+
+/// Result of a finished pcap capture.
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureSummary {
+    pub path: PathBuf,
+    pub packets: u64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    enabled: bool,
+    entries: VecDeque<PacketEntry>,
+    /// Directory new capture files are created in.
+    capture_dir: Option<PathBuf>,
+    /// Active pcap capture, if any.
+    capture: Option<PcapWriter>,
+}
+
+/// Shared handle to the packet ring buffer.
+///
+/// Cloning is cheap; all clones record into the same buffer. Recording is a
+/// no-op until the transcript is enabled or a capture is started, but sent
+/// packets are always counted in the traffic stats.
+#[derive(Debug, Clone, Default)]
+pub struct Transcript {
+    inner: Arc<Mutex<Inner>>,
+    traffic: Traffic,
+}
+
+impl Transcript {
+    /// Creates a transcript that counts sent packets into `traffic`.
+    pub fn new(traffic: Traffic) -> Self {
+        Self {
+            inner: Arc::default(),
+            traffic,
+        }
+    }
+
+    /// Turns recording on or off. Existing entries are kept.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.lock().enabled = enabled;
+    }
+
+    /// Returns true if packets are being recorded.
+    pub fn is_enabled(&self) -> bool {
+        self.lock().enabled
+    }
+
+    /// Sets the directory capture files are written to.
+    pub fn set_capture_dir(&self, dir: PathBuf) {
+        self.lock().capture_dir = Some(dir);
+    }
+
+    /// Starts writing recorded packets to a new pcap file.
+    ///
+    /// # Arguments
+    ///
+    /// * `redact` - Zero out payload bytes, keeping only headers and lengths.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(PathBuf)` - Path of the new capture file.
+    /// * `Err` - A capture is already running or the file could not be created.
+    pub fn start_capture(&self, redact: bool) -> Result<PathBuf> {
+        let mut inner = self.lock();
+        if inner.capture.is_some() {
+            bail!("A capture is already running");
+        }
+
+        let dir = inner
+            .capture_dir
+            .clone()
+            .unwrap_or_else(|| std::env::temp_dir().join("ghostlink-captures"));
+        let path = dir.join(format!("capture-{}.pcap", unix_timestamp_ms()));
+
+        inner.capture = Some(PcapWriter::create(&path, redact)?);
+        info!("Packet capture started: {}", path.display());
+        Ok(path)
+    }
+
+    /// Stops the active capture.
+    ///
+    /// # Returns
+    ///
+    /// The capture file and packet count, or `None` if no capture was running.
+    pub fn stop_capture(&self) -> Option<CaptureSummary> {
+        let writer = self.lock().capture.take()?;
+        info!("Packet capture stopped: {}", writer.path().display());
+        Some(CaptureSummary {
+            path: writer.path().to_path_buf(),
+            packets: writer.packets(),
+        })
+    }
+
+    /// Records a packet to the transcript and any active capture.
+    ///
+    /// # Arguments
+    ///
+    /// * `direction` - Whether the packet was sent or received.
+    /// * `protocol` - Protocol the packet belongs to.
+    /// * `local` - Address of the local socket.
+    /// * `remote` - Peer or server address.
+    /// * `summary` - Parsed content, built only when the transcript is enabled.
+    /// * `raw` - Packet bytes as seen on the socket.
+    pub fn record(
+        &self,
+        direction: Direction,
+        protocol: Protocol,
+        local: SocketAddr,
+        remote: SocketAddr,
+        summary: impl FnOnce() -> String,
+        raw: &[u8],
+    ) {
+        if direction == Direction::Sent {
+            let class = match protocol {
+                Protocol::Stun => TrafficClass::Stun,
+                Protocol::Handshake => TrafficClass::Handshake,
+            };
+            self.traffic.record(class, raw.len());
+        }
+
+        let mut inner = self.lock();
+
+        if let Some(writer) = &mut inner.capture {
+            let (src, dst) = match direction {
+                Direction::Sent => (local, remote),
+                Direction::Received => (remote, local),
+            };
+            if let Err(e) = writer.write_packet(src, dst, raw) {
+                warn!("Packet capture failed, stopping: {}", e);
+                inner.capture = None;
+            }
+        }
+
+        if !inner.enabled {
+            return;
+        }
+
+        if inner.entries.len() == MAX_ENTRIES {
+            inner.entries.pop_front();
+        }
+        inner.entries.push_back(PacketEntry {
+            timestamp_ms: unix_timestamp_ms(),
+            direction,
+            protocol,
+            remote,
+            summary: summary(),
+            raw_hex: raw.iter().map(|b| format!("{:02x}", b)).collect(),
+        });
+    }
+
+    /// Drops the recorded packets exchanged with `remote`.
+    ///
+    /// Packets already written to a capture file are not affected.
+    pub fn forget(&self, remote: SocketAddr) {
+        self.lock().entries.retain(|entry| entry.remote != remote);
+    }
+
+    /// Returns a copy of the recorded packets, oldest first.
+    pub fn entries(&self) -> Vec<PacketEntry> {
+        self.lock().entries.iter().cloned().collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        // A panic while holding the lock leaves the buffer usable; keep going.
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::TempDir;
+
+    fn local() -> SocketAddr {
+        "192.0.2.10:40000".parse().unwrap()
+    }
+
+    fn remote() -> SocketAddr {
+        "198.51.100.1:3478".parse().unwrap()
+    }
+
+    #[test]
+    fn test_disabled_records_nothing() {
+        let transcript = Transcript::default();
+        transcript.record(
+            Direction::Sent,
+            Protocol::Stun,
+            local(),
+            remote(),
+            || "req".into(),
+            &[1],
+        );
+        assert!(transcript.entries().is_empty());
+    }
+
+    #[test]
+    fn test_records_hex_and_is_bounded() {
+        let transcript = Transcript::default();
+        transcript.set_enabled(true);
+
+        let clone = transcript.clone();
+        for _ in 0..MAX_ENTRIES + 3 {
+            clone.record(
+                Direction::Received,
+                Protocol::Handshake,
+                local(),
+                remote(),
+                || "Syn".into(),
+                &[0xde, 0xad],
+            );
+        }
+
+        let entries = transcript.entries();
+        assert_eq!(entries.len(), MAX_ENTRIES);
+        assert_eq!(entries[0].raw_hex, "dead");
+        assert_eq!(entries[0].direction, Direction::Received);
+    }
+
+    #[test]
+    fn test_capture_counts_packets_without_transcript() {
+        let dir = TempDir::new("transcript");
+        let transcript = Transcript::default();
+        transcript.set_capture_dir(dir.path().to_path_buf());
+
+        let path = transcript.start_capture(true).unwrap();
+        assert!(transcript.start_capture(true).is_err());
+
+        transcript.record(
+            Direction::Sent,
+            Protocol::Handshake,
+            local(),
+            remote(),
+            || "Syn".into(),
+            &[1, 2, 3],
+        );
+
+        let summary = transcript.stop_capture().unwrap();
+        assert_eq!(summary.path, path);
+        assert_eq!(summary.packets, 1);
+        assert!(transcript.entries().is_empty());
+        assert!(transcript.stop_capture().is_none());
+    }
+}
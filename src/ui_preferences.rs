@@ -0,0 +1,133 @@
+//! Web UI preferences kept on the node.
+//!
+//! Theme, notification sound and timestamp format are stored as a JSON file
+//! in the data directory, so every browser opening this node's UI gets the
+//! same settings.
+
+use crate::storage::{read_json, write_json};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tracing::warn;
+
+/// Colour scheme of the web UI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Theme {
+    /// Dark, neon accents and CRT effects.
+    #[default]
+    Neon,
+    Light,
+    /// Black and white with strong borders, no effects.
+    HighContrast,
+}
+
+/// How message and log times are shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum TimestampFormat {
+    #[default]
+    #[serde(rename = "24h")]
+    Hours24,
+    #[serde(rename = "12h")]
+    Hours12,
+}
+
+/// Settings applied by the web UI.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UiPreferences {
+    pub theme: Theme,
+    /// Play a sound when a peer message arrives.
+    pub notification_sound: bool,
+    pub timestamp_format: TimestampFormat,
+}
+
+/// The current preferences, backed by a JSON file.
+///
+/// Serializes as the preferences alone.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(transparent)]
+pub struct UiPreferencesStore {
+    /// File the preferences are saved to. `None` keeps them in memory only.
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    current: UiPreferences,
+}
+
+impl UiPreferencesStore {
+    /// Opens the preferences stored at `path`, or the defaults if there are none.
+    pub fn open(path: PathBuf) -> Self {
+        let current = read_json(&path).unwrap_or_else(|e| {
+            warn!("Failed to load UI preferences: {:#}", e);
+            None
+        });
+
+        Self {
+            path: Some(path),
+            current: current.unwrap_or_default(),
+        }
+    }
+
+    /// Returns the current preferences.
+    pub fn get(&self) -> &UiPreferences {
+        &self.current
+    }
+
+    /// Replaces the preferences and saves them.
+    ///
+    /// The new preferences apply even if saving fails.
+    pub fn set(&mut self, preferences: UiPreferences) -> Result<()> {
+        self.current = preferences;
+        if let Some(path) = &self.path {
+            write_json(path, &self.current)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::TempDir;
+    use serde_json::json;
+
+    #[test]
+    fn test_wire_format() {
+        let prefs = UiPreferences {
+            theme: Theme::HighContrast,
+            notification_sound: true,
+            timestamp_format: TimestampFormat::Hours12,
+        };
+        assert_eq!(
+            serde_json::to_value(&prefs).unwrap(),
+            json!({
+                "theme": "high-contrast",
+                "notification_sound": true,
+                "timestamp_format": "12h",
+            })
+        );
+
+        // Missing fields take their defaults; unknown ones are rejected
+        let partial: UiPreferences = serde_json::from_value(json!({ "theme": "light" })).unwrap();
+        assert_eq!(partial.theme, Theme::Light);
+        assert_eq!(partial.timestamp_format, TimestampFormat::Hours24);
+        assert!(serde_json::from_value::<UiPreferences>(json!({ "colour": "red" })).is_err());
+    }
+
+    #[test]
+    fn test_persists() {
+        let dir = TempDir::new("ui-prefs");
+        let path = dir.join("ui_preferences.json");
+
+        let mut store = UiPreferencesStore::open(path.clone());
+        assert_eq!(store.get(), &UiPreferences::default());
+        store
+            .set(UiPreferences {
+                theme: Theme::Light,
+                ..UiPreferences::default()
+            })
+            .unwrap();
+
+        assert_eq!(UiPreferencesStore::open(path).get().theme, Theme::Light);
+    }
+}
@@ -0,0 +1,272 @@
+//! Build information and the update check.
+//!
+//! Self-hosted nodes are easy to forget about. With `update_check`
+//! configured, the node fetches a release manifest every so often and
+//! raises an event when it names a newer version, flagging security fixes.
+//! The check is off by default, as it contacts the configured URL.
+//!
+//! The URL serves `{"manifest": "...", "signature": "..."}`, where
+//! `manifest` is the release as a JSON string and `signature` the hex
+//! Ed25519 signature of that string. A manifest not signed with the
+//! configured key is rejected, so a compromised download page or mirror
+//! cannot announce a release of its own.
+
+use anyhow::{Context, Result, anyhow};
+use ed25519_dalek::{Signature, VerifyingKey};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::time::Duration;
+
+/// Maximum time spent fetching the manifest.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Where and how often to look for new releases.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpdateCheck {
+    /// URL of the signed release manifest.
+    pub url: String,
+    /// Hex Ed25519 public key the manifest must be signed with.
+    pub public_key: String,
+    /// Seconds between checks.
+    pub interval_secs: u64,
+}
+
+/// What this binary was built from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// Short commit hash, or `unknown` if built outside a git checkout.
+    pub git_hash: &'static str,
+    /// Unix timestamp (seconds) of the build.
+    pub build_date: u64,
+    /// Cargo features compiled in.
+    pub features: Vec<&'static str>,
+    pub os: &'static str,
+    pub arch: &'static str,
+}
+
+/// Returns the build information of this binary.
+pub fn build_info() -> BuildInfo {
+    let mut features = Vec::new();
+    if cfg!(feature = "netem") {
+        features.push("netem");
+    }
+
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: env!("GHOSTLINK_GIT_HASH"),
+        build_date: env!("GHOSTLINK_BUILD_DATE").parse().unwrap_or(0),
+        features,
+        os: std::env::consts::OS,
+        arch: std::env::consts::ARCH,
+    }
+}
+
+/// A release announced by the manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Release {
+    /// Version number, e.g. `0.2.0`.
+    pub version: String,
+    /// True if the release fixes a security issue.
+    #[serde(default)]
+    pub security: bool,
+    /// Release notes or a summary of them.
+    #[serde(default)]
+    pub notes: String,
+    /// Where to download it.
+    #[serde(default)]
+    pub url: Option<String>,
+}
+
+/// The manifest as served: the release JSON and its signature.
+#[derive(Debug, Deserialize)]
+struct SignedManifest {
+    manifest: String,
+    signature: String,
+}
+
+/// Fetches and verifies release manifests.
+#[derive(Debug, Clone)]
+pub struct UpdateChecker {
+    client: Client,
+    url: String,
+    key: VerifyingKey,
+}
+
+impl UpdateChecker {
+    /// Creates a checker for `settings`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the public key is invalid or the HTTP client
+    /// cannot be built.
+    pub fn new(settings: &UpdateCheck) -> Result<Self> {
+        let key: [u8; 32] = decode_hex(&settings.public_key)
+            .and_then(|bytes| bytes.try_into().ok())
+            .context("Public key must be 32 bytes of hex")?;
+        let client = Client::builder()
+            .timeout(FETCH_TIMEOUT)
+            .user_agent(concat!("GhostLink/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+
+        Ok(Self {
+            client,
+            url: settings.url.clone(),
+            key: VerifyingKey::from_bytes(&key)?,
+        })
+    }
+
+    /// Fetches the manifest once.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(release))` - The manifest names a newer version.
+    /// * `Ok(None)` - This build is current.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the manifest cannot be fetched, its signature
+    /// does not verify, or its version cannot be read.
+    pub async fn check(&self) -> Result<Option<Release>> {
+        let body = self
+            .client
+            .get(&self.url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| anyhow!("{}", e.without_url()))?
+            .bytes()
+            .await
+            .map_err(|e| anyhow!("{}", e.without_url()))?;
+        let signed: SignedManifest = serde_json::from_slice(&body).context("Malformed manifest")?;
+        let release = self.verify(&signed)?;
+        Ok(is_newer(&release.version, env!("CARGO_PKG_VERSION"))?.then_some(release))
+    }
+
+    /// Checks the signature of a manifest and parses it.
+    fn verify(&self, signed: &SignedManifest) -> Result<Release> {
+        let signature: [u8; 64] = decode_hex(&signed.signature)
+            .and_then(|bytes| bytes.try_into().ok())
+            .context("Signature must be 64 bytes of hex")?;
+        self.key
+            .verify_strict(
+                signed.manifest.as_bytes(),
+                &Signature::from_bytes(&signature),
+            )
+            .map_err(|_| anyhow!("Manifest signature does not verify"))?;
+        Ok(serde_json::from_str(&signed.manifest)?)
+    }
+}
+
+/// Returns true if `candidate` is a later version than `current`.
+///
+/// # Errors
+///
+/// Returns an error unless both are dotted numbers such as `1.2.3`.
+fn is_newer(candidate: &str, current: &str) -> Result<bool> {
+    let parse = |version: &str| -> Result<Vec<u64>> {
+        let mut parts = version
+            .trim_start_matches('v')
+            .split('.')
+            .map(|part| part.parse())
+            .collect::<Result<Vec<u64>, _>>()
+            .map_err(|_| anyhow!("Invalid version {:?}", version))?;
+        // 1.2 and 1.2.0 are the same release
+        while parts.last() == Some(&0) {
+            parts.pop();
+        }
+        Ok(parts)
+    };
+    Ok(parse(candidate)? > parse(current)?)
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim();
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Json, Router, routing::get};
+    use ed25519_dalek::{Signer, SigningKey};
+    use serde_json::json;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[tokio::test]
+    async fn test_accepts_only_signed_newer_releases() {
+        let signing = SigningKey::from_bytes(&[7; 32]);
+        let sign = |release: &serde_json::Value| {
+            let manifest = release.to_string();
+            json!({
+                "manifest": manifest,
+                "signature": hex(&signing.sign(manifest.as_bytes()).to_bytes()),
+            })
+        };
+        let newer = sign(&json!({ "version": "99.0.0", "security": true, "notes": "Fixes" }));
+        let current = sign(&json!({ "version": env!("CARGO_PKG_VERSION") }));
+        let mut forged = newer.clone();
+        forged["manifest"] = json!({ "version": "99.0.1" }).to_string().into();
+
+        let app = Router::new()
+            .route(
+                "/newer",
+                get(move || std::future::ready(Json(newer.clone()))),
+            )
+            .route(
+                "/current",
+                get(move || std::future::ready(Json(current.clone()))),
+            )
+            .route(
+                "/forged",
+                get(move || std::future::ready(Json(forged.clone()))),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let checker = |path: &str, key: &SigningKey| {
+            UpdateChecker::new(&UpdateCheck {
+                url: format!("{}{}", base, path),
+                public_key: hex(key.verifying_key().as_bytes()),
+                interval_secs: 3600,
+            })
+            .unwrap()
+        };
+        let release = checker("/newer", &signing).check().await.unwrap().unwrap();
+        assert_eq!(release.version, "99.0.0");
+        assert!(release.security);
+        assert_eq!(checker("/current", &signing).check().await.unwrap(), None);
+        assert!(checker("/forged", &signing).check().await.is_err());
+        let other = SigningKey::from_bytes(&[8; 32]);
+        assert!(checker("/newer", &other).check().await.is_err());
+
+        assert!(
+            UpdateChecker::new(&UpdateCheck {
+                url: base.clone(),
+                public_key: "abcd".into(),
+                interval_secs: 3600,
+            })
+            .is_err()
+        );
+        assert!(is_newer("0.10.0", "0.9.1").unwrap());
+        assert!(is_newer("v1.0", "0.9.1").unwrap());
+        assert!(!is_newer("0.1.0", "0.1.0").unwrap());
+        assert!(!is_newer("0.1", "0.1.0").unwrap());
+        assert!(is_newer("0.2.0-rc1", "0.1.0").is_err());
+
+        let info = build_info();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(!info.git_hash.is_empty());
+        assert_eq!(info.features.contains(&"netem"), cfg!(feature = "netem"));
+    }
+}
@@ -0,0 +1,109 @@
+//! The connection state machine.
+//!
+//! A connection is `Disconnected`, `Punching` or `Connected`, and the
+//! handshake, the session and the controller all move it along. Every move
+//! goes through `AppState::set_status`, which checks it against the table
+//! below. A move the table does not allow, such as a late handshake update
+//! landing after the session is up, is logged and dropped instead of
+//! leaving the UI in the wrong state.
+//!
+//! ```text
+//! Disconnected --Attempt--> Punching --Establish--> Connected
+//!      ^                       |                        |
+//!      +--------Abort----------+                        |
+//!      +--------------------Close-----------------------+
+//! ```
+//!
+//! Staying in a state is allowed, and only updates the status message.
+
+use super::shared_state::Status;
+use std::fmt;
+
+/// An allowed change of connection status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    /// Disconnected to Punching: a handshake started.
+    Attempt,
+    /// Punching to Connected: the handshake succeeded.
+    Establish,
+    /// Punching to Disconnected: the handshake failed or was cancelled.
+    Abort,
+    /// Connected to Disconnected: the session ended.
+    Close,
+    /// The status is unchanged; only the message is new.
+    Stay,
+}
+
+impl Transition {
+    /// Looks up the transition from `from` to `to`.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(transition)` - The change is allowed.
+    /// * `None` - The change is invalid, e.g. Connected back to Punching.
+    pub fn between(from: Status, to: Status) -> Option<Self> {
+        match (from, to) {
+            (Status::Disconnected, Status::Punching) => Some(Self::Attempt),
+            (Status::Punching, Status::Connected) => Some(Self::Establish),
+            (Status::Punching, Status::Disconnected) => Some(Self::Abort),
+            (Status::Connected, Status::Disconnected) => Some(Self::Close),
+            (from, to) if from == to => Some(Self::Stay),
+            _ => None,
+        }
+    }
+}
+
+/// A status change the state machine refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidTransition {
+    pub from: Status,
+    pub to: Status,
+}
+
+impl fmt::Display for InvalidTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cannot go from {:?} to {:?}", self.from, self.to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_table_transitions_are_allowed() {
+        use Status::*;
+
+        assert_eq!(
+            Transition::between(Disconnected, Punching),
+            Some(Transition::Attempt)
+        );
+        assert_eq!(
+            Transition::between(Punching, Connected),
+            Some(Transition::Establish)
+        );
+        assert_eq!(
+            Transition::between(Punching, Disconnected),
+            Some(Transition::Abort)
+        );
+        assert_eq!(
+            Transition::between(Connected, Disconnected),
+            Some(Transition::Close)
+        );
+        for status in [Disconnected, Punching, Connected] {
+            assert_eq!(Transition::between(status, status), Some(Transition::Stay));
+        }
+
+        // A session is only reached through a handshake, and never goes back to one
+        assert_eq!(Transition::between(Disconnected, Connected), None);
+        assert_eq!(Transition::between(Connected, Punching), None);
+        assert_eq!(
+            InvalidTransition {
+                from: Connected,
+                to: Punching
+            }
+            .to_string(),
+            "cannot go from Connected to Punching"
+        );
+    }
+}
@@ -0,0 +1,157 @@
+//! Running behind a reverse proxy.
+//!
+//! A proxy such as nginx or Caddy may serve the UI under a subpath, and
+//! reports the real client in `X-Forwarded-For` and `X-Forwarded-Proto`.
+//! Those headers are only believed from the proxies listed in
+//! `trusted_proxies`, or from the API socket, whose file permissions
+//! already limit who can connect. Anyone else could set them to anything.
+
+use anyhow::{Result, bail};
+use axum::{extract::connect_info::Connected, http::HeaderMap, serve::IncomingStream};
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+};
+use tokio::net::TcpListener;
+
+/// The connection a request arrived on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Peer(
+    /// Remote address, or `None` for the Unix domain socket.
+    pub Option<SocketAddr>,
+);
+
+impl Connected<IncomingStream<'_, TcpListener>> for Peer {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        Peer(Some(*stream.remote_addr()))
+    }
+}
+
+#[cfg(unix)]
+impl Connected<IncomingStream<'_, tokio::net::UnixListener>> for Peer {
+    fn connect_info(_stream: IncomingStream<'_, tokio::net::UnixListener>) -> Self {
+        Peer(None)
+    }
+}
+
+/// Who a request came from, once trusted proxies are looked through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Client {
+    /// Client address. `None` if a local tool used the API socket directly.
+    pub ip: Option<IpAddr>,
+    /// True if the client reached the proxy over HTTPS.
+    pub https: bool,
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ip {
+            Some(ip) => write!(f, "{}", ip),
+            None => write!(f, "API socket"),
+        }
+    }
+}
+
+/// Works out the client of a request that arrived from `peer`.
+///
+/// Behind trusted proxies, the client is the last address in
+/// `X-Forwarded-For` that is not a trusted proxy itself; addresses left of
+/// it were supplied by the client and prove nothing.
+///
+/// # Arguments
+///
+/// * `peer` - Address the connection came from, `None` for the API socket.
+/// * `headers` - Request headers.
+/// * `trusted` - Proxies whose forwarded headers are believed.
+pub fn resolve(peer: Option<IpAddr>, headers: &HeaderMap, trusted: &[IpAddr]) -> Client {
+    if peer.is_some_and(|ip| !trusted.contains(&ip)) {
+        return Client {
+            ip: peer,
+            https: false,
+        };
+    }
+
+    let hops: Vec<IpAddr> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|hop| hop.trim().parse().ok())
+        .collect();
+    let ip = hops
+        .iter()
+        .rev()
+        .find(|ip| !trusted.contains(ip))
+        .or(hops.first())
+        .copied()
+        .or(peer);
+    let https = headers
+        .get("x-forwarded-proto")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"));
+
+    Client { ip, https }
+}
+
+/// Normalizes the configured base path to `/name` form, or `""` for the root.
+///
+/// # Errors
+///
+/// Returns an error if the path does not start with `/` or contains
+/// characters that do not belong in a path.
+pub fn normalize_base_path(path: &str) -> Result<String> {
+    let path = path.trim().trim_end_matches('/');
+    if path.is_empty() {
+        return Ok(String::new());
+    }
+    if !path.starts_with('/')
+        || path.contains("//")
+        || path.contains(['?', '#', '{', '}', '*', ' '])
+    {
+        bail!("Invalid base path {:?}; use a form like /ghostlink", path);
+    }
+    Ok(path.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_trusts_forwarded_headers_only_from_proxies() {
+        let proxy: IpAddr = "127.0.0.1".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("10.9.9.9, 203.0.113.5"),
+        );
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("https"));
+
+        // The proxy appended the real client; what the client sent is ignored
+        let client = resolve(Some(proxy), &headers, &[proxy]);
+        assert_eq!(client.ip, Some("203.0.113.5".parse().unwrap()));
+        assert!(client.https);
+
+        // Without trust, the headers are the client's word only
+        let client = resolve(Some(proxy), &headers, &[]);
+        assert_eq!(client.ip, Some(proxy));
+        assert!(!client.https);
+
+        // A proxy on the API socket is trusted by file permissions
+        assert_eq!(
+            resolve(None, &headers, &[]).ip,
+            Some("203.0.113.5".parse().unwrap())
+        );
+        assert_eq!(
+            resolve(None, &HeaderMap::new(), &[]).to_string(),
+            "API socket"
+        );
+
+        assert_eq!(normalize_base_path("/ghostlink/").unwrap(), "/ghostlink");
+        assert_eq!(normalize_base_path("/").unwrap(), "");
+        assert!(normalize_base_path("ghostlink").is_err());
+        assert!(normalize_base_path("/{id}").is_err());
+    }
+}
@@ -1,3 +1,8 @@
+pub mod connection_state;
+pub mod forwarded;
 pub mod shared_state;
+pub mod status_message;
+pub mod strict_json;
+pub mod system_notice;
 pub mod web_server;
-pub use web_server::start_web_server;
+pub use web_server::{open_browser, start_web_server};
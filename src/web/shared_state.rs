@@ -1,6 +1,68 @@
+use super::{
+    connection_state::{InvalidTransition, Transition},
+    status_message::{EventMessage, StatusMessage},
+    system_notice::SystemNotice,
+};
+use crate::{
+    assist::AssistOutcome,
+    audit::{DisconnectReason, SessionLog},
+    contacts::Contacts,
+    crash_report::{ConfigSummary, CrashNotice},
+    data_budget::{BudgetScope, BudgetWarning, DataBudget},
+    echo_bot::EchoBot,
+    event_log::EventLog,
+    history_export::HistoryExport,
+    link_preview::{self, LinkPreview},
+    messaging::{
+        expiry::Expiring,
+        handshake::Feature,
+        history_sync::{DroppedConversation, SentTexts},
+        incoming::IncomingRequest,
+        lamport::{LamportClock, MessageOrder},
+        reactions::{MessageId, Reaction, Reactions},
+        session_digest::TranscriptCheck,
+        text_limit::MAX_TEXT_LEN,
+    },
+    net::{StunError, StunProbe},
+    observers::Observers,
+    operations::Operations,
+    power_profile::{PowerHints, PowerProfile},
+    schedule::{Schedule, ScheduledMessage},
+    share::ShareRequest,
+    stats_history::StatsHistory,
+    storage,
+    traffic::Traffic,
+    transcript::Transcript,
+    ui_preferences::{UiPreferences, UiPreferencesStore},
+    update::Release,
+    wipe::WipeReport,
+};
+use rand_core::{OsRng, RngCore};
 use serde::{Deserialize, Serialize};
-use std::{net::SocketAddr, sync::Arc};
-use tokio::sync::{RwLock, broadcast, mpsc};
+use std::{
+    hash::{Hash, Hasher},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tokio::{
+    sync::{RwLock, broadcast, mpsc},
+    time::Duration,
+};
+use tracing::warn;
+
+/// Time between SSE heartbeats.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Commands that can be queued for the controller.
+pub const COMMAND_QUEUE_CAPACITY: usize = 32;
+
+/// How long an API handler waits for room in a full command queue before
+/// giving up with 503.
+pub const COMMAND_SEND_TIMEOUT: Duration = Duration::from_secs(2);
 
 /// Thread-safe wrapper for application state.
 ///
@@ -34,12 +96,196 @@ pub struct AppState {
     /// Peer's IP address.
     pub peer_ip: Option<SocketAddr>,
 
+    /// Display label for the peer (e.g., "Bob"), from the connect request or contacts.
+    pub peer_label: Option<String>,
+
+    /// Identifier of the open conversation, carried by its Message events.
+    pub conversation_id: Option<String>,
+
+    /// Short ID of the current or most recent connection attempt, carried by
+    /// status events and log lines so reconnects can be told apart.
+    pub connection_id: Option<String>,
+
+    /// True while the current attempt or session is a guest session: it is
+    /// not written to the session or event log, and its peer details are
+    /// scrubbed when it ends.
+    pub guest: bool,
+
+    /// Seconds after which messages of the open conversation disappear, as
+    /// agreed with the peer. `None` keeps them.
+    pub message_ttl: Option<u32>,
+
+    /// Most text bytes one message can carry, as of the last handshake.
+    /// Until then, the limit whatever the peer negotiates.
+    pub max_text_len: usize,
+
+    /// Features and services the peer advertised in the last handshake.
+    /// `None` until a session is established, and after it ends.
+    pub peer_features: Option<Vec<Feature>>,
+
     // --- ENCRYPTION STATE ---
     /// The Short Authentication String (SAS) fingerprint for manual verification.
     pub fingerprint: Option<String>,
     /// The name of the negotiated encryption algorithm (e.g., "ChaCha20-Poly1305").
     pub encryption_algo: Option<String>,
+    /// Latest comparison of the session transcript with the peer's, kept
+    /// after the session ends until the next one is established.
+    pub transcript_check: Option<TranscriptCheck>,
     // ------------------------
+    /// Most recent STUN failure. Cleared once a query succeeds.
+    pub last_network_error: Option<NetworkError>,
+
+    /// Whether the STUN server can be reached, as far as keep-alives tell.
+    pub network_status: NetworkStatus,
+
+    /// Battery and metered-link state, and how far keep-alives are stretched.
+    pub power_profile: PowerProfile,
+
+    /// Per-server outcome of the startup STUN race.
+    pub stun_probes: Vec<StunProbe>,
+
+    /// Peers asking to connect, waiting for the user to accept or reject.
+    pub incoming_requests: Vec<IncomingRequest>,
+
+    /// UDP port the client socket is actually bound to.
+    pub bound_port: Option<u16>,
+
+    /// Set when the configured port was taken and another one had to be used.
+    pub port_warning: Option<String>,
+
+    /// Address the web UI is served at, e.g. `http://localhost:8080/`.
+    pub web_url: Option<String>,
+
+    /// Reverse proxies whose `X-Forwarded-*` headers are believed.
+    #[serde(skip)]
+    pub trusted_proxies: Vec<IpAddr>,
+
+    /// Whether `/api/connect` accepts loopback peers other than the echo bot.
+    #[serde(skip)]
+    pub allow_loopback_peers: bool,
+
+    /// Set when an earlier run crashed and left a report that was not dismissed.
+    pub crash_report: Option<CrashNotice>,
+
+    /// File the crash report is written to.
+    #[serde(skip)]
+    pub crash_report_path: Option<PathBuf>,
+
+    /// Names of the commands the peer may run here in assist mode.
+    pub assist_grants: Vec<String>,
+
+    /// Names of the folders shared with the peer.
+    pub shares: Vec<String>,
+
+    /// Local address of the socket carrying the current session.
+    pub active_path: Option<SocketAddr>,
+
+    /// Local addresses of bound sockets kept warm as failover paths.
+    pub standby_paths: Vec<SocketAddr>,
+
+    /// The built-in echo peer, while it runs.
+    pub echo_bot: Option<EchoBot>,
+
+    /// Web UI settings shared by every browser using this node.
+    pub ui_preferences: UiPreferencesStore,
+
+    /// Messages waiting for their send time.
+    pub scheduled: Schedule,
+
+    /// Audit log of past and current sessions.
+    #[serde(skip)]
+    pub session_log: SessionLog,
+
+    /// Saved peer labels.
+    #[serde(skip)]
+    pub contacts: Contacts,
+
+    /// Tokens of read-only API observers.
+    #[serde(skip)]
+    pub observers: Observers,
+
+    /// Configuration details safe to share, for diagnostics bundles.
+    #[serde(skip)]
+    pub config_summary: Option<ConfigSummary>,
+
+    /// Newer release found by the update check, if any.
+    #[serde(skip)]
+    pub available_update: Option<Release>,
+
+    /// Debug trace of STUN and handshake packets.
+    #[serde(skip)]
+    pub transcript: Transcript,
+
+    /// Recent events and warnings, kept on disk across runs.
+    #[serde(skip)]
+    pub event_log: EventLog,
+
+    /// Long-running actions started through the API.
+    #[serde(skip)]
+    pub operations: Operations,
+
+    /// Bytes and packets sent, by traffic class.
+    #[serde(skip)]
+    pub traffic: Traffic,
+
+    /// Session and monthly data usage against the configured caps.
+    #[serde(skip)]
+    pub data_budget: DataBudget,
+
+    /// Periodic export of session history, run by the janitor.
+    #[serde(skip)]
+    pub history_export: HistoryExport,
+
+    /// Link quality samples of the current and recent sessions.
+    #[serde(skip)]
+    pub stats_history: StatsHistory,
+
+    /// Messages written by each side in this conversation: (mine, peer's).
+    #[serde(skip)]
+    message_counts: (u64, u64),
+
+    /// Peer messages in this conversation the user has seen.
+    #[serde(skip)]
+    read_count: u64,
+
+    /// Last measured round-trip time to the peer.
+    #[serde(skip)]
+    rtt: Option<Duration>,
+
+    /// Reactions in this conversation.
+    #[serde(skip)]
+    reactions: Reactions,
+
+    /// Messages in this conversation that will disappear.
+    #[serde(skip)]
+    expiring: Expiring,
+
+    /// Orders the texts of this conversation the same way on both sides.
+    #[serde(skip)]
+    clock: LamportClock,
+
+    /// Texts we sent in this conversation, for redelivery if it drops.
+    #[serde(skip)]
+    sent_texts: SentTexts,
+
+    /// The last conversation, if it ended without a confirmed Bye.
+    #[serde(skip)]
+    dropped: Option<DroppedConversation>,
+
+    /// Peak depth and rejections of the command queue.
+    #[serde(skip)]
+    command_queue: CommandQueueStats,
+
+    /// Session messages waiting for room in the KCP send window, as of the
+    /// last liveness check.
+    #[serde(skip)]
+    outbox_depth: usize,
+
+    /// Impairments applied by in-process netem links.
+    #[cfg(feature = "netem")]
+    #[serde(skip)]
+    pub netem: crate::netem::NetemHandle,
+
     /// Channel for sending commands to the controller.
     #[serde(skip)]
     cmd_tx: mpsc::Sender<Command>,
@@ -47,6 +293,11 @@ pub struct AppState {
     /// Channel for broadcasting state changes to the UI.
     #[serde(skip)]
     event_tx: broadcast::Sender<AppEvent>,
+
+    /// Events broadcast so far, heartbeats aside. Shared by clones so
+    /// snapshots report the count they were taken at.
+    #[serde(skip)]
+    event_seq: Arc<AtomicU64>,
 }
 
 impl AppState {
@@ -57,16 +308,69 @@ impl AppState {
     /// * `cmd_tx` - Channel for sending commands to controller
     /// * `event_tx` - Channel for broadcasting events to UI
     pub fn new(cmd_tx: mpsc::Sender<Command>, event_tx: broadcast::Sender<AppEvent>) -> Self {
+        let traffic = Traffic::default();
         Self {
             local_ip: None,
             public_ip: None,
             nat_type: NatType::default(),
             status: Status::default(),
             peer_ip: None,
+            peer_label: None,
+            conversation_id: None,
+            connection_id: None,
+            guest: false,
+            message_ttl: None,
+            max_text_len: MAX_TEXT_LEN,
+            peer_features: None,
             fingerprint: None,
             encryption_algo: None,
+            transcript_check: None,
+            last_network_error: None,
+            network_status: NetworkStatus::Online,
+            power_profile: PowerProfile::default(),
+            stun_probes: Vec::new(),
+            incoming_requests: Vec::new(),
+            bound_port: None,
+            port_warning: None,
+            web_url: None,
+            trusted_proxies: Vec::new(),
+            allow_loopback_peers: false,
+            crash_report: None,
+            crash_report_path: None,
+            assist_grants: Vec::new(),
+            shares: Vec::new(),
+            active_path: None,
+            standby_paths: Vec::new(),
+            echo_bot: None,
+            ui_preferences: UiPreferencesStore::default(),
+            scheduled: Schedule::default(),
+            session_log: SessionLog::default(),
+            contacts: Contacts::default(),
+            observers: Observers::default(),
+            config_summary: None,
+            available_update: None,
+            transcript: Transcript::new(traffic.clone()),
+            event_log: EventLog::default(),
+            operations: Operations::default(),
+            traffic,
+            data_budget: DataBudget::default(),
+            history_export: HistoryExport::default(),
+            stats_history: StatsHistory::default(),
+            message_counts: (0, 0),
+            read_count: 0,
+            rtt: None,
+            reactions: Reactions::default(),
+            expiring: Expiring::default(),
+            clock: LamportClock::default(),
+            sent_texts: SentTexts::default(),
+            dropped: None,
+            command_queue: CommandQueueStats::default(),
+            outbox_depth: 0,
+            #[cfg(feature = "netem")]
+            netem: Default::default(),
             cmd_tx,
             event_tx,
+            event_seq: Arc::default(),
         }
     }
 
@@ -75,6 +379,45 @@ impl AppState {
         &self.cmd_tx
     }
 
+    /// Returns current depth and counters of the command queue.
+    pub fn command_queue_stats(&self) -> CommandQueueStats {
+        let capacity = self.cmd_tx.max_capacity();
+        CommandQueueStats {
+            capacity,
+            depth: capacity - self.cmd_tx.capacity(),
+            ..self.command_queue
+        }
+    }
+
+    /// Returns how many events are buffered for SSE subscribers and how many there are.
+    pub fn event_queue_stats(&self) -> EventQueueStats {
+        EventQueueStats {
+            queued: self.event_tx.len(),
+            subscribers: self.event_tx.receiver_count(),
+        }
+    }
+
+    /// Returns the number of session messages waiting to be sent.
+    pub fn outbox_depth(&self) -> usize {
+        self.outbox_depth
+    }
+
+    /// Records the number of session messages waiting to be sent.
+    pub fn set_outbox_depth(&mut self, depth: usize) {
+        self.outbox_depth = depth;
+    }
+
+    /// Records the queue depth after a command was queued.
+    pub fn record_command_queued(&mut self) {
+        let depth = self.cmd_tx.max_capacity() - self.cmd_tx.capacity();
+        self.command_queue.peak_depth = self.command_queue.peak_depth.max(depth);
+    }
+
+    /// Records a command turned away because the queue stayed full.
+    pub fn record_command_rejected(&mut self) {
+        self.command_queue.rejected += 1;
+    }
+
     /// Creates a new event subscriber.
     pub fn subscribe_events(&self) -> broadcast::Receiver<AppEvent> {
         self.event_tx.subscribe()
@@ -87,22 +430,22 @@ impl AppState {
     pub fn set_local_ip(
         &mut self,
         addr: SocketAddr,
-        message: Option<String>,
-        timeout: Option<u64>,
+        message: Option<StatusMessage>,
+        progress: Option<PunchProgress>,
     ) {
         self.local_ip = Some(addr);
-        self.broadcast_status_change(message, timeout);
+        self.broadcast_status_change(message, progress);
     }
     #[allow(dead_code)]
     /// Updates public IP and notifies listeners.
     pub fn set_public_ip(
         &mut self,
         addr: SocketAddr,
-        message: Option<String>,
-        timeout: Option<u64>,
+        message: Option<StatusMessage>,
+        progress: Option<PunchProgress>,
     ) {
         self.public_ip = Some(addr);
-        self.broadcast_status_change(message, timeout);
+        self.broadcast_status_change(message, progress);
     }
 
     /// Updates NAT type and notifies listeners.
@@ -110,78 +453,877 @@ impl AppState {
     pub fn set_nat_type(
         &mut self,
         nat_type: NatType,
-        message: Option<String>,
-        timeout: Option<u64>,
+        message: Option<StatusMessage>,
+        progress: Option<PunchProgress>,
     ) {
         self.nat_type = nat_type;
-        self.broadcast_status_change(message, timeout);
+        self.broadcast_status_change(message, progress);
+    }
+
+    /// Updates connection status and notifies listeners.
+    ///
+    /// The change must be allowed by the connection state machine; one that
+    /// is not, such as Connected back to Punching, is logged and ignored.
+    /// Ending a session forgets its round trip time, and ending a guest
+    /// session scrubs its details once the final event is out.
+    pub fn set_status(
+        &mut self,
+        status: Status,
+        message: Option<StatusMessage>,
+        progress: Option<PunchProgress>,
+    ) {
+        let Some(transition) = Transition::between(self.status, status) else {
+            warn!(
+                "Ignoring status change: {}",
+                InvalidTransition {
+                    from: self.status,
+                    to: status,
+                }
+            );
+            return;
+        };
+        self.status = status;
+        if transition == Transition::Close {
+            self.rtt = None;
+        }
+        // The guest flag stays up until the final event is out, so it is not logged
+        let guest_ended = status == Status::Disconnected && self.guest;
+        if guest_ended {
+            self.scrub_guest_session();
+        }
+        self.broadcast_status_change(message, progress);
+        if guest_ended {
+            self.guest = false;
+        }
+    }
+
+    /// Forgets the peer and session details of a guest session that ended.
+    fn scrub_guest_session(&mut self) {
+        if let Some(peer) = self.peer_ip.take() {
+            self.transcript.forget(peer);
+        }
+        self.peer_label = None;
+        self.connection_id = None;
+        self.fingerprint = None;
+        self.encryption_algo = None;
+        self.transcript_check = None;
+        self.rtt = None;
+        self.reactions = Reactions::default();
+        self.message_ttl = None;
+        self.expiring.clear();
+    }
+
+    /// Updates peer IP and its display label and notifies listeners.
+    ///
+    /// Without an explicit `label`, the contact saved for `addr` (if any) is used.
+    pub fn set_peer_ip(
+        &mut self,
+        addr: SocketAddr,
+        label: Option<String>,
+        message: Option<StatusMessage>,
+        progress: Option<PunchProgress>,
+    ) {
+        self.peer_ip = Some(addr);
+        self.peer_label = label.or_else(|| self.contacts.label_for(addr));
+        self.broadcast_status_change(message, progress);
+    }
+
+    /// Updates security details for current session.
+    /// Called by handshake module upon successful key exchange.
+    pub fn set_security_info(&mut self, fingerprint: String, algorithm: String) {
+        self.fingerprint = Some(fingerprint);
+        self.encryption_algo = Some(algorithm);
+        // Note: Does not broadcast immediately.
+        // Handshake typically calls set_status(Connected) right after,
+        // which triggers broadcast with this new data included.
+    }
+
+    /// Records the latest transcript comparison, or clears it for a new session.
+    ///
+    /// A comparison is broadcast to the UI.
+    pub fn set_transcript_check(&mut self, check: Option<TranscriptCheck>) {
+        self.transcript_check = check.clone();
+        if let Some(check) = check {
+            self.broadcast_event(AppEvent::TranscriptCheck {
+                check,
+                connection_id: self.connection_id.clone(),
+            });
+        }
+    }
+
+    /// Records what the peer of a new session offers, or clears it.
+    ///
+    /// Broadcasts a `CapabilityMismatch` naming `missing` if it is not empty.
+    pub fn set_peer_features(&mut self, features: Option<Vec<Feature>>, missing: Vec<Feature>) {
+        self.peer_features = features;
+        if !missing.is_empty() {
+            self.broadcast_event(AppEvent::CapabilityMismatch {
+                connection_id: self.connection_id.clone(),
+                missing,
+            });
+        }
+    }
+
+    /// Returns an error naming `feature` if the connected peer lacks it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a session is up and its peer did not advertise
+    /// `feature`. Without a session, the caller's own checks apply.
+    pub fn require_peer_feature(&self, feature: Feature) -> Result<(), String> {
+        match &self.peer_features {
+            Some(features) if !features.contains(&feature) => {
+                Err(format!("The peer does not offer {}", feature))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Exports the finished sessions if the history export is enabled and a
+    /// session ended since its last run.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the export file could not be written or rotated.
+    pub fn export_history(&mut self, now: u64) -> anyhow::Result<Option<PathBuf>> {
+        self.history_export.run(self.session_log.records(), now)
+    }
+
+    /// Forgets the link statistics of sessions no longer in the session history.
+    pub fn prune_link_stats(&mut self) {
+        let log = &self.session_log;
+        self.stats_history.retain(|id| {
+            log.records()
+                .any(|record| record.connection_id.as_deref() == Some(id))
+        });
+    }
+
+    /// Records or clears the latest STUN failure.
+    ///
+    /// Broadcasts only when the error actually changes.
+    pub fn set_network_error(&mut self, error: Option<&StunError>) {
+        let next = error.map(NetworkError::from);
+        if self.last_network_error == next {
+            return;
+        }
+        self.last_network_error = next;
+        self.broadcast_status_change(None, None);
+    }
+
+    /// Replaces the power hints and notifies listeners if the profile changed.
+    pub fn set_power_hints(&mut self, hints: PowerHints) {
+        if self.power_profile.set_hints(hints) {
+            self.broadcast_status_change(None, None);
+        }
+    }
+
+    /// Detects the power source again and notifies listeners on change.
+    ///
+    /// # Returns
+    ///
+    /// True if background traffic should be reduced.
+    pub fn refresh_power_profile(&mut self) -> bool {
+        if self.power_profile.refresh() {
+            self.broadcast_status_change(None, None);
+        }
+        self.power_profile.low_traffic
+    }
+
+    /// Records the current gap between NAT keep-alives.
+    ///
+    /// Does not broadcast; the next status change carries the new value.
+    pub fn set_keep_alive_interval(&mut self, interval: Duration) {
+        self.power_profile.keep_alive_secs = Some(interval.as_secs());
+    }
+
+    /// Updates whether the network is usable and notifies listeners on change.
+    pub fn set_network_status(&mut self, network_status: NetworkStatus) {
+        if self.network_status == network_status {
+            return;
+        }
+        self.network_status = network_status;
+        let message = match network_status {
+            NetworkStatus::Online => StatusMessage::NetworkRestored,
+            NetworkStatus::Offline => StatusMessage::NetworkOffline,
+        };
+        self.broadcast_status_change(Some(message), None);
+    }
+
+    /// Stores the per-server STUN results.
+    ///
+    /// Does not broadcast; the following status change carries the new values.
+    pub fn set_stun_probes(&mut self, probes: Vec<StunProbe>) {
+        self.stun_probes = probes;
+    }
+
+    /// Replaces the pending incoming requests and notifies the UI.
+    pub fn set_incoming_requests(&mut self, requests: Vec<IncomingRequest>) {
+        self.incoming_requests = requests;
+        self.broadcast_event(AppEvent::IncomingRequests {
+            requests: self.incoming_requests.clone(),
+        });
+    }
+
+    /// Updates the local path addresses.
+    ///
+    /// Does not broadcast; the following status change carries the new values.
+    pub fn set_paths(&mut self, active: Option<SocketAddr>, standby: Vec<SocketAddr>) {
+        self.active_path = active;
+        self.standby_paths = standby;
+    }
+
+    /// Broadcasts current state to all active listeners.
+    ///
+    /// Constructs an event based on the current status and sends it
+    /// via the event channel.
+    fn broadcast_status_change(
+        &self,
+        message: Option<StatusMessage>,
+        progress: Option<PunchProgress>,
+    ) {
+        let message = message.map(EventMessage::from);
+        let event = match self.status {
+            // When disconnected, sends the full state.
+            Status::Disconnected => AppEvent::Disconnected {
+                state: Box::new(self.clone()),
+                message,
+                connection_id: self.connection_id.clone(),
+            },
+            // During punching, sends the phase and how far along it is.
+            Status::Punching => AppEvent::Punching {
+                progress,
+                message,
+                connection_id: self.connection_id.clone(),
+            },
+            // When connected, sends status messages AND security info.
+            Status::Connected => AppEvent::Connected {
+                message,
+                connection_id: self.connection_id.clone(),
+                peer_label: self.peer_label.clone(),
+                fingerprint: self.fingerprint.clone(),
+                encryption_algo: self.encryption_algo.clone(),
+            },
+        };
+        self.broadcast_event(event);
+    }
+
+    /// Assigns a new ID to a connection attempt that is about to start.
+    ///
+    /// # Returns
+    ///
+    /// The new connection ID.
+    pub fn begin_connection(&mut self) -> String {
+        let mut id = [0u8; 4];
+        OsRng.fill_bytes(&mut id);
+        let connection_id: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+
+        self.connection_id = Some(connection_id.clone());
+        connection_id
+    }
+
+    /// Starts a conversation with the current peer and announces it to the UI.
+    ///
+    /// # Returns
+    ///
+    /// The new conversation ID.
+    pub fn open_conversation(&mut self, peer: SocketAddr) -> String {
+        let mut id = [0u8; 8];
+        OsRng.fill_bytes(&mut id);
+        let conversation_id: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+
+        self.conversation_id = Some(conversation_id.clone());
+        self.message_counts = (0, 0);
+        self.read_count = 0;
+        self.rtt = None;
+        self.reactions.clear();
+        self.message_ttl = None;
+        self.clock.reset();
+        self.sent_texts = SentTexts::default();
+        self.broadcast_event(AppEvent::ConversationOpened {
+            conversation_id: conversation_id.clone(),
+            connection_id: self.connection_id.clone(),
+            peer,
+            peer_label: self.peer_label.clone(),
+        });
+        self.notify(SystemNotice::Connected {
+            peer,
+            peer_label: self.peer_label.clone(),
+        });
+        conversation_id
+    }
+
+    /// Ends the open conversation, if any, and announces it to the UI.
+    ///
+    /// Unless `confirmed` or a guest session, what is needed to redeliver
+    /// lost texts in the next session with the same peer is kept.
+    pub fn close_conversation(&mut self, reason: DisconnectReason, confirmed: bool) {
+        if let Some(conversation_id) = self.conversation_id.take() {
+            let sent = std::mem::take(&mut self.sent_texts);
+            self.dropped = match &self.fingerprint {
+                Some(fingerprint) if !confirmed && !self.guest => Some(DroppedConversation {
+                    fingerprint: fingerprint.clone(),
+                    sent,
+                    received: self.message_counts.1,
+                }),
+                _ => None,
+            };
+            self.broadcast_event(AppEvent::System {
+                conversation_id: Some(conversation_id.clone()),
+                notice: SystemNotice::Disconnected { reason },
+                text: SystemNotice::Disconnected { reason }.to_string(),
+            });
+            self.broadcast_event(AppEvent::ConversationClosed {
+                conversation_id,
+                connection_id: self.connection_id.clone(),
+                reason,
+                confirmed,
+            });
+        }
+    }
+
+    /// Sets which side this node is in the conversation order of a new session.
+    ///
+    /// # Arguments
+    ///
+    /// * `lower_key` - True if our handshake public key sorts before the peer's.
+    pub fn start_clock(&mut self, lower_key: bool) {
+        self.clock = LamportClock::new(lower_key);
+    }
+
+    /// Returns what to send the peer in a `SyncSummary` at the start of a
+    /// conversation: the fingerprint of the session that dropped and how
+    /// many of the peer's texts we received in it.
+    pub fn sync_summary(&self) -> Option<(String, u64)> {
+        self.dropped
+            .as_ref()
+            .map(|dropped| (dropped.fingerprint.clone(), dropped.received))
+    }
+
+    /// Answers the peer's `SyncSummary`.
+    ///
+    /// # Arguments
+    ///
+    /// * `fingerprint` - Session the peer's summary is for.
+    /// * `received` - How many of our texts the peer received in it.
+    ///
+    /// # Returns
+    ///
+    /// The texts the peer missed, to send again. Empty if the peer's
+    /// dropped session is not ours.
+    pub fn take_backfill(&mut self, fingerprint: &str, received: u64) -> Vec<String> {
+        self.dropped
+            .take_if(|dropped| dropped.fingerprint == fingerprint)
+            .map(|dropped| dropped.backfill(fingerprint, received))
+            .unwrap_or_default()
+    }
+
+    /// Tells the UI how many lost texts were sent again.
+    pub fn announce_backfill(&self, resent: usize) {
+        self.broadcast_event(AppEvent::HistorySynced {
+            conversation_id: self.conversation_id.clone(),
+            resent,
+        });
+    }
+
+    /// Returns the clock value to stamp on the next text we send.
+    pub fn next_clock(&self) -> u64 {
+        self.clock.next()
+    }
+
+    /// Numbers a chat message and broadcasts it to the UI.
+    ///
+    /// Must be called for every text in the order it was sent or received;
+    /// the numbering is what reactions refer to. A text of ours takes the
+    /// clock value `send_text` stamped it with and disappears after
+    /// `message_ttl`, if set. A peer text added here is ordered as it arrived.
+    ///
+    /// # Returns
+    ///
+    /// The ID of the message.
+    pub fn add_message(&mut self, content: String, from_me: bool) -> MessageId {
+        let clock = self.clock.next();
+        if from_me {
+            let order = self.clock.local(clock);
+            self.push_message(content, from_me, order, self.message_ttl)
+        } else {
+            let order = self.clock.remote(clock);
+            self.push_message(content, from_me, order, None)
+        }
+    }
+
+    /// Like `add_message`, for a peer text stamped with `clock` that
+    /// disappears after `ttl_secs`, if given.
+    pub fn add_peer_message(
+        &mut self,
+        content: String,
+        clock: u64,
+        ttl_secs: Option<u32>,
+    ) -> MessageId {
+        let order = self.clock.remote(clock);
+        self.push_message(content, false, order, ttl_secs)
+    }
+
+    fn push_message(
+        &mut self,
+        content: String,
+        from_me: bool,
+        order: MessageOrder,
+        ttl_secs: Option<u32>,
+    ) -> MessageId {
+        let count = if from_me {
+            &mut self.message_counts.0
+        } else {
+            &mut self.message_counts.1
+        };
+        let message_id = MessageId {
+            from_me,
+            seq: *count,
+        };
+        *count += 1;
+        if from_me && ttl_secs.is_none() {
+            self.sent_texts.push(message_id.seq, content.clone());
+        }
+
+        let expires_at = ttl_secs.map(|ttl| storage::unix_timestamp() + ttl as u64);
+        if let Some(expires_at) = expires_at {
+            self.expiring
+                .track(self.conversation_id.clone(), message_id, expires_at);
+        }
+
+        self.send_event(AppEvent::Message {
+            links: link_preview::find_urls(&content),
+            content,
+            from_me,
+            message_id,
+            order,
+            expires_at,
+            conversation_id: self.conversation_id.clone(),
+            peer: self.peer_ip,
+            peer_label: self.peer_label.clone(),
+        });
+        message_id
+    }
+
+    /// Replaces the web UI preferences and announces them to open UIs.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if the preferences could not be saved. They still apply
+    /// until the next restart.
+    pub fn set_ui_preferences(&mut self, preferences: UiPreferences) -> anyhow::Result<()> {
+        let saved = self.ui_preferences.set(preferences.clone());
+        self.broadcast_event(AppEvent::UiPreferences { preferences });
+        saved
+    }
+
+    /// Announces the pending scheduled messages to open UIs.
+    ///
+    /// Call after every change to `scheduled`.
+    pub fn announce_scheduled(&self) {
+        self.broadcast_event(AppEvent::Scheduled {
+            scheduled: self.scheduled.all().to_vec(),
+        });
+    }
+
+    /// Marks every peer message received so far as seen.
+    pub fn mark_read(&mut self) {
+        self.read_count = self.message_counts.1;
+    }
+
+    /// Records a round-trip time measured by a ping or heartbeat.
+    pub fn set_rtt(&mut self, rtt: Duration) {
+        self.rtt = Some(rtt);
+        self.stats_history.observe_rtt(rtt);
+    }
+
+    /// Returns the compact status shown by status bar widgets.
+    pub fn summary(&self) -> Summary {
+        let peer = self
+            .peer_label
+            .clone()
+            .or_else(|| self.peer_ip.map(|addr| addr.to_string()));
+        let unread = self.message_counts.1 - self.read_count;
+        let rtt_ms = match self.status {
+            Status::Connected => self.rtt.map(|rtt| rtt.as_millis() as u64),
+            _ => None,
+        };
+
+        let mut text = match (self.status, &peer) {
+            (Status::Connected, Some(peer)) => format!("Connected to {}", peer),
+            (Status::Punching, Some(peer)) => format!("Connecting to {}", peer),
+            (status, _) => format!("{:?}", status),
+        };
+        if self.network_status == NetworkStatus::Offline {
+            text.push_str(" · offline");
+        }
+        if unread > 0 {
+            text.push_str(&format!(" · {} unread", unread));
+        }
+        if let Some(rtt_ms) = rtt_ms {
+            text.push_str(&format!(" · {} ms", rtt_ms));
+        }
+
+        Summary {
+            status: self.status,
+            network_status: self.network_status,
+            peer,
+            unread,
+            rtt_ms,
+            text,
+        }
+    }
+
+    /// Broadcasts previews fetched for a message's links.
+    ///
+    /// Dropped if the conversation has ended since the fetch started.
+    pub fn attach_link_previews(
+        &self,
+        conversation_id: &str,
+        message_id: MessageId,
+        previews: Vec<LinkPreview>,
+    ) {
+        if self.conversation_id.as_deref() != Some(conversation_id) || previews.is_empty() {
+            return;
+        }
+        self.broadcast_event(AppEvent::LinkPreviews {
+            conversation_id: conversation_id.to_string(),
+            message_id,
+            previews,
+        });
+    }
+
+    /// Shows an assist-mode invocation in the UI.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Invocation ID chosen by the requesting side.
+    /// * `name` - Name of the granted command.
+    /// * `requested_by_me` - True if we asked the peer to run it.
+    /// * `outcome` - `None` when the request was just sent or received.
+    pub fn report_assist(
+        &self,
+        id: u32,
+        name: String,
+        requested_by_me: bool,
+        outcome: Option<AssistOutcome>,
+    ) {
+        self.broadcast_event(AppEvent::Assist {
+            id,
+            name,
+            requested_by_me,
+            outcome,
+        });
+    }
+
+    /// Shows the peer's access to our shared folders in the UI.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - What the peer asked for.
+    /// * `error` - Why it was refused or failed, if it was.
+    pub fn report_share_access(&self, request: ShareRequest, error: Option<String>) {
+        self.broadcast_event(AppEvent::ShareAccess { request, error });
+    }
+
+    /// Updates data usage from the running total of the current session and
+    /// warns about caps that crossed a threshold.
+    ///
+    /// # Arguments
+    ///
+    /// * `session_total` - Bytes sent and received this session so far.
+    pub fn record_data_usage(&mut self, session_total: u64) {
+        let warnings = self.data_budget.observe_session(session_total);
+        self.report_budget_warnings(warnings);
+    }
+
+    /// Records the final data usage of a session that ended.
+    pub fn end_data_usage(&mut self, session_total: u64) {
+        let warnings = self.data_budget.end_session(session_total);
+        self.report_budget_warnings(warnings);
+    }
+
+    fn report_budget_warnings(&self, warnings: Vec<BudgetWarning>) {
+        let bulk_blocked = !self.data_budget.bulk_allowed();
+        for warning in warnings {
+            self.broadcast_event(AppEvent::DataBudget {
+                scope: warning.scope,
+                percent: warning.percent,
+                used: warning.used,
+                cap: warning.cap,
+                bulk_blocked,
+            });
+        }
+    }
+
+    /// Records a newer release found by the update check, telling the UI
+    /// the first time it is seen.
+    pub fn set_available_update(&mut self, release: Release) {
+        if self.available_update.as_ref() == Some(&release) {
+            return;
+        }
+        self.available_update = Some(release.clone());
+        self.broadcast_event(AppEvent::UpdateAvailable { release });
+    }
+
+    /// Returns true if `message_id` names a message in this conversation.
+    pub fn has_message(&self, message_id: MessageId) -> bool {
+        let count = if message_id.from_me {
+            self.message_counts.0
+        } else {
+            self.message_counts.1
+        };
+        message_id.seq < count
+    }
+
+    /// Adds or removes a reaction and broadcasts the message's reactions to the UI.
+    ///
+    /// # Arguments
+    ///
+    /// * `message_id` - Target message, from the local point of view.
+    /// * `emoji` - A reaction already checked with `validate_emoji`.
+    /// * `from_me` - True if the local user reacted.
+    /// * `add` - False to remove the reaction.
+    ///
+    /// # Returns
+    ///
+    /// True if the message exists and its reactions changed.
+    pub fn apply_reaction(
+        &mut self,
+        message_id: MessageId,
+        emoji: &str,
+        from_me: bool,
+        add: bool,
+    ) -> bool {
+        if !self.has_message(message_id) || !self.reactions.apply(message_id, emoji, from_me, add) {
+            return false;
+        }
+
+        self.broadcast_event(AppEvent::Reaction {
+            conversation_id: self.conversation_id.clone(),
+            message_id,
+            reactions: self.reactions.on(message_id).to_vec(),
+        });
+        true
+    }
+
+    /// Sets how long messages of the open conversation are kept and tells the UI.
+    ///
+    /// # Arguments
+    ///
+    /// * `ttl_secs` - TTL already checked with `validate_ttl`, or `None` to keep messages.
+    /// * `from_me` - True if the local user changed it, false if the peer did.
+    pub fn set_message_ttl(&mut self, ttl_secs: Option<u32>, from_me: bool) {
+        self.message_ttl = ttl_secs;
+        self.broadcast_event(AppEvent::MessageTtl {
+            conversation_id: self.conversation_id.clone(),
+            ttl_secs,
+            from_me,
+        });
+    }
+
+    /// Deletes the messages whose TTL has passed, with their reactions,
+    /// whether or not their conversation is still open.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - Current unix timestamp (seconds).
+    pub fn expire_messages(&mut self, now: u64) {
+        for (conversation_id, message_id) in self.expiring.take_expired(now) {
+            // Reactions are only kept for the open conversation
+            if conversation_id == self.conversation_id {
+                self.reactions.remove(message_id);
+            }
+            self.broadcast_event(AppEvent::MessageExpired {
+                conversation_id,
+                message_id,
+            });
+        }
+    }
+
+    /// Clears the chat history in the UI.
+    pub fn clear_chat(&self) {
+        self.broadcast_event(AppEvent::ClearChat);
     }
 
-    /// Updates connection status and notifies listeners.
-    pub fn set_status(&mut self, status: Status, message: Option<String>, timeout: Option<u64>) {
-        self.status = status;
-        self.broadcast_status_change(message, timeout);
+    /// Returns the connection and data usage figures shown by `/stats`.
+    pub fn stats_text(&self) -> String {
+        let (sent, received) = self.message_counts;
+        let usage = self.data_budget.report();
+        let cap = |cap: Option<u64>| cap.map_or_else(String::new, |cap| format!(" of {}", cap));
+        format!(
+            "{} · {} messages sent, {} received · {} bytes this session{}, {} bytes in {}{}",
+            self.summary().text,
+            sent,
+            received,
+            usage.session.used,
+            cap(usage.session.cap),
+            usage.monthly.used,
+            usage.month,
+            cap(usage.monthly.cap),
+        )
     }
 
-    /// Updates peer IP and notifies listeners.
-    pub fn set_peer_ip(&mut self, addr: SocketAddr, message: Option<String>, timeout: Option<u64>) {
-        self.peer_ip = Some(addr);
-        self.broadcast_status_change(message, timeout);
+    /// Shows the outcome of a chat command in the UI.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command as typed, e.g. `/ping 3`.
+    /// * `outcome` - Its output, or why it failed.
+    pub fn command_result(&self, command: String, outcome: Result<String, String>) {
+        let (output, error) = match outcome {
+            Ok(output) => (Some(output), None),
+            Err(e) => (None, Some(e)),
+        };
+        self.notify(SystemNotice::CommandResult {
+            command,
+            output,
+            error,
+        });
     }
 
-    /// Updates security details for current session.
-    /// Called by handshake module upon successful key exchange.
-    pub fn set_security_info(&mut self, fingerprint: String, algorithm: String) {
-        self.fingerprint = Some(fingerprint);
-        self.encryption_algo = Some(algorithm);
-        // Note: Does not broadcast immediately.
-        // Handshake typically calls set_status(Connected) right after,
-        // which triggers broadcast with this new data included.
+    /// Shows a system notice in the open conversation and records it.
+    pub fn notify(&self, notice: SystemNotice) {
+        self.broadcast_event(AppEvent::System {
+            conversation_id: self.conversation_id.clone(),
+            text: notice.to_string(),
+            notice,
+        });
     }
 
-    /// Broadcasts current state to all active listeners.
+    /// Deletes past sessions, the event logs, any crash report and the open
+    /// conversation, then tells the UI.
     ///
-    /// Constructs an event based on the current status and sends it
-    /// via the event channel.
-    fn broadcast_status_change(&self, message: Option<String>, timeout: Option<u64>) {
-        let event = match self.status {
-            // When disconnected, sends the full state.
-            Status::Disconnected => AppEvent::Disconnected {
-                state: self.clone(),
-                message,
-            },
-            // During punching, sends progress updates and timeouts.
-            Status::Punching => AppEvent::Punching { timeout, message },
-            // When connected, sends status messages AND security info.
-            Status::Connected => AppEvent::Connected {
-                message,
-                fingerprint: self.fingerprint.clone(),
-                encryption_algo: self.encryption_algo.clone(),
-            },
+    /// Stops at the first store that cannot be cleared.
+    pub fn wipe_history(&mut self) -> anyhow::Result<WipeReport> {
+        let sessions = self.session_log.clear()?;
+        self.stats_history.clear()?;
+        let events = self.event_log.clear()?;
+        let crash_report = match &self.crash_report_path {
+            Some(path) => storage::remove_file(path)?,
+            None => false,
         };
-        self.broadcast_event(event);
+        self.crash_report = None;
+        self.reactions = Reactions::default();
+        self.expiring.clear();
+        self.sent_texts = SentTexts::default();
+        self.dropped = None;
+        self.clear_chat();
+
+        let report = WipeReport {
+            sessions,
+            events,
+            crash_report,
+        };
+        self.broadcast_event(AppEvent::HistoryWiped {
+            report: report.clone(),
+        });
+        Ok(report)
     }
 
-    /// Broadcasts a chat message to the UI.
-    pub fn add_message(&self, content: String, from_me: bool) {
-        let _ = self.event_tx.send(AppEvent::Message { content, from_me });
+    /// Returns how many events have been broadcast, heartbeats aside.
+    pub fn event_seq(&self) -> u64 {
+        self.event_seq.load(Ordering::SeqCst)
     }
 
-    /// Clears the chat history in the UI.
-    pub fn clear_chat(&self) {
-        let _ = self.event_tx.send(AppEvent::ClearChat);
+    /// Returns a checksum of the state as `/api/state` serves it.
+    pub fn checksum(&self) -> String {
+        let mut hasher = std::hash::DefaultHasher::new();
+        serde_json::to_string(self)
+            .unwrap_or_default()
+            .hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+
+    /// Sends a heartbeat carrying the event count and state checksum, so
+    /// clients can tell when they missed events and should fetch the
+    /// state again.
+    ///
+    /// Call it with the state locked for writing, so no event that is
+    /// already counted can still be on its way.
+    pub fn send_heartbeat(&self) {
+        let _ = self.event_tx.send(AppEvent::Heartbeat {
+            seq: self.event_seq(),
+            checksum: self.checksum(),
+        });
     }
 
     /// Broadcasts an event to the UI.
     fn broadcast_event(&self, event: AppEvent) {
+        if !self.guest {
+            self.event_log.record_event(&event);
+        }
+        self.send_event(event);
+    }
+
+    /// Sends an event to the UI, counting it.
+    fn send_event(&self, event: AppEvent) {
+        self.event_seq.fetch_add(1, Ordering::SeqCst);
         let _ = self.event_tx.send(event);
     }
 }
 
+/// A classified network failure, as shown to the user.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NetworkError {
+    /// Failure classification.
+    pub error: StunError,
+    /// Human-readable description.
+    pub message: String,
+    /// Suggested next step.
+    pub advice: String,
+}
+
+impl From<&StunError> for NetworkError {
+    fn from(error: &StunError) -> Self {
+        Self {
+            error: error.clone(),
+            message: error.to_string(),
+            advice: error.advice().to_string(),
+        }
+    }
+}
+
+/// Compact status for status bars and menu-bar widgets.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Summary {
+    pub status: Status,
+    pub network_status: NetworkStatus,
+    /// Label of the peer, or its address if it has none.
+    pub peer: Option<String>,
+    /// Peer messages in this conversation not yet marked read.
+    pub unread: u64,
+    /// Last measured round-trip time, while connected.
+    pub rtt_ms: Option<u64>,
+    /// The fields above as one line of text.
+    pub text: String,
+}
+
+/// Load on the command queue between the API and the controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct CommandQueueStats {
+    /// Commands the queue can hold.
+    pub capacity: usize,
+    /// Commands waiting for the controller right now.
+    pub depth: usize,
+    /// Highest depth seen after queueing a command.
+    pub peak_depth: usize,
+    /// Commands rejected because the queue stayed full.
+    pub rejected: u64,
+}
+
+/// Load on the broadcast channel feeding SSE subscribers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct EventQueueStats {
+    /// Events not yet received by the slowest subscriber.
+    pub queued: usize,
+    /// Connected subscribers (open UIs and long polls).
+    pub subscribers: usize,
+}
+
 /// NAT (Network Address Translation) type.
 ///
 /// Determines if direct P2P connections are possible.
-#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
 #[allow(dead_code)]
 pub enum NatType {
     /// NAT type not yet determined.
@@ -203,23 +1345,31 @@ pub enum AppEvent {
     ///
     Disconnected {
         /// Full state for UI synchronization.
-        state: AppState,
+        state: Box<AppState>,
         /// Messages.
-        message: Option<String>,
+        message: Option<EventMessage>,
+        /// Connection the status change belongs to.
+        connection_id: Option<String>,
     },
 
     /// Attempting NAT hole punching.
     Punching {
-        /// Time remaining for handshake attempt (seconds).
-        timeout: Option<u64>,
+        /// Phase of the attempt and how far along it is.
+        progress: Option<PunchProgress>,
         /// Log messages.
-        message: Option<String>,
+        message: Option<EventMessage>,
+        /// Connection attempt in progress.
+        connection_id: Option<String>,
     },
 
     /// P2P connection established.
     Connected {
         /// System or peer message.
-        message: Option<String>,
+        message: Option<EventMessage>,
+        /// Connection carrying the session.
+        connection_id: Option<String>,
+        /// Display label of the peer, if known.
+        peer_label: Option<String>,
         /// SAS Fingerprint for UI verification
         fingerprint: Option<String>,
         /// Algorithm used
@@ -229,10 +1379,216 @@ pub enum AppEvent {
     Message {
         content: String,
         from_me: bool,
+        /// Position of the message in the conversation; target of reactions.
+        message_id: MessageId,
+        /// Where the message sorts in the conversation, the same on both sides.
+        order: MessageOrder,
+        /// Unix timestamp (seconds) the message disappears at, if it does.
+        expires_at: Option<u64>,
+        /// http(s) URLs found in `content`, for the UI to render as links.
+        links: Vec<String>,
+        /// Conversation the message belongs to.
+        conversation_id: Option<String>,
+        /// Address of the peer in this conversation.
+        peer: Option<SocketAddr>,
+        /// Display label of the peer in this conversation, if known.
+        peer_label: Option<String>,
+    },
+
+    /// Metadata was fetched for links in a received message.
+    LinkPreviews {
+        conversation_id: String,
+        message_id: MessageId,
+        previews: Vec<LinkPreview>,
+    },
+
+    /// A command was requested or completed in assist mode.
+    Assist {
+        id: u32,
+        name: String,
+        requested_by_me: bool,
+        outcome: Option<AssistOutcome>,
+    },
+
+    /// The peer listed or read something in our shared folders.
+    ShareAccess {
+        request: ShareRequest,
+        error: Option<String>,
+    },
+
+    /// The reactions on a message changed.
+    Reaction {
+        conversation_id: Option<String>,
+        message_id: MessageId,
+        /// All reactions now on the message.
+        reactions: Vec<Reaction>,
+    },
+
+    /// A session was established; following messages carry its ID.
+    ConversationOpened {
+        conversation_id: String,
+        connection_id: Option<String>,
+        peer: SocketAddr,
+        peer_label: Option<String>,
+    },
+
+    /// A session ended; no further messages will carry its ID.
+    ConversationClosed {
+        conversation_id: String,
+        connection_id: Option<String>,
+        reason: DisconnectReason,
+        /// False if we hung up and the peer never acknowledged it.
+        confirmed: bool,
     },
 
     /// Clear chat history.
     ClearChat,
+
+    /// Something the node reports in the conversation, such as the peer
+    /// connecting or a chat command's result. Not chat content.
+    System {
+        conversation_id: Option<String>,
+        #[serde(flatten)]
+        notice: SystemNotice,
+        /// English text of the notice.
+        text: String,
+    },
+
+    /// The list of peers asking to connect changed.
+    IncomingRequests { requests: Vec<IncomingRequest> },
+
+    /// The web UI preferences changed.
+    UiPreferences { preferences: UiPreferences },
+
+    /// The message TTL of the open conversation was set or turned off.
+    MessageTtl {
+        conversation_id: Option<String>,
+        ttl_secs: Option<u32>,
+        /// True if the local user changed it.
+        from_me: bool,
+    },
+
+    /// A message's TTL passed; it is deleted.
+    MessageExpired {
+        conversation_id: Option<String>,
+        message_id: MessageId,
+    },
+
+    /// The pending scheduled messages changed.
+    Scheduled { scheduled: Vec<ScheduledMessage> },
+
+    /// A peer allowed to do so wiped this node's history.
+    HistoryWiped { report: WipeReport },
+
+    /// Texts the peer missed when the last session dropped were sent again.
+    HistorySynced {
+        conversation_id: Option<String>,
+        resent: usize,
+    },
+
+    /// The peer's transcript digest was compared with ours.
+    TranscriptCheck {
+        check: TranscriptCheck,
+        connection_id: Option<String>,
+    },
+
+    /// The peer lacks features or services this node offers; requests for
+    /// them are refused for the rest of the session.
+    CapabilityMismatch {
+        connection_id: Option<String>,
+        missing: Vec<Feature>,
+    },
+
+    /// Data usage reached a warning threshold of a cap.
+    DataBudget {
+        scope: BudgetScope,
+        percent: u8,
+        used: u64,
+        cap: u64,
+        /// True if shared file reads are now refused.
+        bulk_blocked: bool,
+    },
+
+    /// The update check found a newer release.
+    UpdateAvailable { release: Release },
+
+    /// Sent every `HEARTBEAT_INTERVAL`. `seq` counts the events broadcast
+    /// before it; a client that saw fewer missed some.
+    Heartbeat { seq: u64, checksum: String },
+}
+
+/// Broad kinds of events, for clients that only want some of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventCategory {
+    /// Connection state and incoming requests.
+    Status,
+    /// Chat content and the conversation around it.
+    Messages,
+    /// Shared folder access and data usage.
+    Transfers,
+    /// Everything else: system notices, assist mode, preferences, maintenance notices.
+    System,
+    /// SSE heartbeats.
+    Heartbeat,
+}
+
+impl EventCategory {
+    /// Parses a comma-separated list such as `status,messages`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a message naming the first unknown category.
+    pub fn parse_list(list: &str) -> Result<Vec<EventCategory>, String> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                serde_json::from_value(serde_json::Value::String(name.to_string()))
+                    .map_err(|_| format!("Unknown event category {:?}", name))
+            })
+            .collect()
+    }
+}
+
+impl AppEvent {
+    /// Returns the category the event belongs to.
+    pub fn category(&self) -> EventCategory {
+        match self {
+            AppEvent::Disconnected { .. }
+            | AppEvent::Punching { .. }
+            | AppEvent::Connected { .. }
+            | AppEvent::IncomingRequests { .. }
+            | AppEvent::TranscriptCheck { .. }
+            | AppEvent::CapabilityMismatch { .. } => EventCategory::Status,
+            AppEvent::Message { .. }
+            | AppEvent::LinkPreviews { .. }
+            | AppEvent::Reaction { .. }
+            | AppEvent::ConversationOpened { .. }
+            | AppEvent::ConversationClosed { .. }
+            | AppEvent::ClearChat
+            | AppEvent::MessageTtl { .. }
+            | AppEvent::MessageExpired { .. }
+            | AppEvent::Scheduled { .. }
+            | AppEvent::HistorySynced { .. } => EventCategory::Messages,
+            AppEvent::ShareAccess { .. } | AppEvent::DataBudget { .. } => EventCategory::Transfers,
+            AppEvent::Assist { .. }
+            | AppEvent::UiPreferences { .. }
+            | AppEvent::HistoryWiped { .. }
+            | AppEvent::UpdateAvailable { .. }
+            | AppEvent::System { .. } => EventCategory::System,
+            AppEvent::Heartbeat { .. } => EventCategory::Heartbeat,
+        }
+    }
+}
+
+/// Reachability of the network, judged by NAT keep-alives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub enum NetworkStatus {
+    #[default]
+    Online,
+    /// Keep-alives keep failing; they are retried with backoff.
+    Offline,
 }
 
 /// Connection state of the P2P node.
@@ -249,22 +1605,150 @@ pub enum Status {
     Connected,
 }
 
+/// Phase of a connection attempt, in the order they are passed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PunchPhase {
+    /// Looking up the hostname saved with the contact.
+    Resolving,
+    /// Sending SYNs; nothing heard from the peer yet.
+    Spraying,
+    /// The peer's SYN was answered; waiting for its SYN-ACK.
+    AwaitingSynAck,
+    /// Both sides heard each other; agreeing on session keys.
+    KeyExchange,
+    /// Keys agreed; starting the KCP stream.
+    UpgradingKcp,
+}
+
+impl PunchPhase {
+    /// Percentages at which the phase starts and ends on the progress bar.
+    fn span(self) -> (u8, u8) {
+        match self {
+            PunchPhase::Resolving => (0, 5),
+            PunchPhase::Spraying => (5, 60),
+            PunchPhase::AwaitingSynAck => (60, 75),
+            PunchPhase::KeyExchange => (75, 90),
+            PunchPhase::UpgradingKcp => (90, 99),
+        }
+    }
+}
+
+/// How far a connection attempt has got, for the UI's progress bar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PunchProgress {
+    pub phase: PunchPhase,
+    /// SYNs sent so far.
+    pub attempts: u32,
+    /// Rough completion, 0 to 99; 100 is the Connected event.
+    pub percent: u8,
+    /// Seconds left before the attempt times out.
+    pub secs_left: u64,
+}
+
+impl PunchProgress {
+    /// Places an attempt within its phase by how much of the timeout is used.
+    ///
+    /// # Arguments
+    ///
+    /// * `phase` - Current phase.
+    /// * `attempts` - SYNs sent so far.
+    /// * `elapsed` - Time since the attempt started.
+    /// * `timeout` - Time the attempt is given.
+    pub fn new(phase: PunchPhase, attempts: u32, elapsed: Duration, timeout: Duration) -> Self {
+        let (start, end) = phase.span();
+        let used = if timeout.is_zero() {
+            1.0
+        } else {
+            (elapsed.as_secs_f64() / timeout.as_secs_f64()).min(1.0)
+        };
+        Self {
+            phase,
+            attempts,
+            percent: start + (f64::from(end - start) * used) as u8,
+            secs_left: timeout.saturating_sub(elapsed).as_secs(),
+        }
+    }
+}
+
 /// Commands from Web UI to Controller.
 #[derive(Debug)]
 pub enum Command {
     /// Initiate connection to configured peer.
-    ConnectPeer,
+    ConnectPeer {
+        /// Answered once the session is up or the attempt failed.
+        reply: Option<crate::messaging::connect::ConnectReply>,
+    },
 
     /// Sends a message
     SendMessage(String),
+    /// Sets the message TTL of the conversation and tells the peer
+    SetMessageTtl(Option<u32>),
 
     /// Disconnect from current peer
     Disconnect,
+
+    /// Connect to a peer that asked to connect.
+    AcceptIncoming(SocketAddr),
+
+    /// Turn away a peer that asked to connect.
+    RejectIncoming(SocketAddr),
+
+    /// Send `text` to every active session and report per-peer delivery.
+    Broadcast {
+        text: String,
+        reply: crate::messaging::broadcast::BroadcastReply,
+    },
+
+    /// Add or remove a reaction on a message in the current conversation.
+    React {
+        message_id: MessageId,
+        emoji: String,
+        add: bool,
+    },
+
+    /// Ask the peer to run one of the commands it granted us.
+    AssistRun { name: String },
+
+    /// A command the peer asked for has finished; send it the result.
+    AssistFinished {
+        id: u32,
+        name: String,
+        outcome: AssistOutcome,
+    },
+
+    /// List or read from the peer's shared folders.
+    ShareQuery {
+        request: ShareRequest,
+        reply: crate::share::ShareReply,
+    },
+
+    /// Ask the peer, one of our own nodes, to wipe its history.
+    RemoteWipe { reply: crate::wipe::WipeReply },
+
+    /// Ask the peer, one of our own nodes, to send a Wake-on-LAN packet.
+    RelayWake {
+        mac: String,
+        broadcast: SocketAddr,
+        reply: crate::wol::WakeReply,
+    },
+
+    /// The power hints changed; reschedule NAT keep-alives.
+    PowerProfileChanged,
+
+    /// Measure round-trip time with `count` application-level pings.
+    Ping {
+        count: u32,
+        /// Operation tracking the run, for progress updates.
+        operation: u64,
+        reply: crate::messaging::ping::PingReply,
+    },
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{event_log::EntryKind, storage::TempDir};
     use std::net::{IpAddr, Ipv4Addr};
 
     fn create_test_state() -> AppState {
@@ -291,7 +1775,7 @@ mod tests {
         let mut state = create_test_state();
         let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100)), 8080);
 
-        state.set_local_ip(addr, Some("Local IP set".into()), None);
+        state.set_local_ip(addr, None, None);
 
         assert_eq!(state.local_ip, Some(addr));
     }
@@ -301,7 +1785,7 @@ mod tests {
         let mut state = create_test_state();
         let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)), 5678);
 
-        state.set_public_ip(addr, Some("Public IP resolved".into()), None);
+        state.set_public_ip(addr, Some(StatusMessage::PublicIpResolved), None);
 
         assert_eq!(state.public_ip, Some(addr));
     }
@@ -310,22 +1794,311 @@ mod tests {
     fn test_set_nat_type() {
         let mut state = create_test_state();
 
-        state.set_nat_type(NatType::Cone, Some("NAT detected".into()), None);
+        state.set_nat_type(NatType::Cone, Some(StatusMessage::NatTypeDetected), None);
         assert_eq!(state.nat_type, NatType::Cone);
 
         state.set_nat_type(NatType::Symmetric, None, None);
         assert_eq!(state.nat_type, NatType::Symmetric);
     }
 
+    #[test]
+    fn test_set_network_error_broadcasts_changes_only() {
+        let (cmd_tx, _cmd_rx) = mpsc::channel(32);
+        let (event_tx, mut event_rx) = broadcast::channel(32);
+        let mut state = AppState::new(cmd_tx, event_tx);
+
+        state.set_network_error(Some(&StunError::Timeout));
+        state.set_network_error(Some(&StunError::Timeout));
+
+        let error = state.last_network_error.clone().unwrap();
+        assert_eq!(error.error, StunError::Timeout);
+        assert!(error.advice.contains("UDP"));
+        assert!(event_rx.try_recv().is_ok());
+        assert!(event_rx.try_recv().is_err());
+
+        state.set_network_error(None);
+        assert_eq!(state.last_network_error, None);
+        assert!(event_rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_heartbeat_counts_events_and_checksums_state() {
+        let (cmd_tx, _cmd_rx) = mpsc::channel(32);
+        let (event_tx, mut event_rx) = broadcast::channel(32);
+        let mut state = AppState::new(cmd_tx, event_tx);
+        let before = state.checksum();
+
+        state.add_message("hello".into(), true);
+        state.set_network_error(Some(&StunError::Timeout));
+        state.send_heartbeat();
+
+        assert!(matches!(event_rx.try_recv(), Ok(AppEvent::Message { .. })));
+        assert!(event_rx.try_recv().is_ok());
+        match event_rx.try_recv() {
+            Ok(AppEvent::Heartbeat { seq, checksum }) => {
+                assert_eq!(seq, 2);
+                assert_eq!(checksum, state.checksum());
+                assert_ne!(checksum, before);
+            }
+            other => panic!("expected a heartbeat, got {:?}", other),
+        }
+        // Heartbeats are not counted themselves
+        assert_eq!(state.event_seq(), 2);
+        assert_eq!(state.clone().event_seq(), 2);
+    }
+
+    #[test]
+    fn test_event_categories() {
+        assert_eq!(
+            EventCategory::parse_list("status, messages,"),
+            Ok(vec![EventCategory::Status, EventCategory::Messages])
+        );
+        assert!(EventCategory::parse_list("status,voice").is_err());
+        assert_eq!(AppEvent::ClearChat.category(), EventCategory::Messages);
+        assert_eq!(
+            AppEvent::Punching {
+                progress: None,
+                message: None,
+                connection_id: None,
+            }
+            .category(),
+            EventCategory::Status
+        );
+    }
+
+    #[test]
+    fn test_system_notices_are_logged_with_params() {
+        let state = create_test_state();
+        state.command_result("/stats".into(), Ok("Disconnected".into()));
+
+        let entries = state.event_log.entries();
+        let EntryKind::Event { event } = &entries[0].kind else {
+            panic!("expected an event, got {:?}", entries[0]);
+        };
+        assert_eq!(
+            *event,
+            serde_json::json!({
+                "status": "SYSTEM",
+                "conversation_id": null,
+                "kind": "command_result",
+                "params": { "command": "/stats", "output": "Disconnected", "error": null },
+                "text": "/stats · Disconnected",
+            })
+        );
+    }
+
+    #[test]
+    fn test_network_status_announces_offline_and_recovery() {
+        let (cmd_tx, _cmd_rx) = mpsc::channel(32);
+        let (event_tx, mut event_rx) = broadcast::channel(32);
+        let mut state = AppState::new(cmd_tx, event_tx);
+
+        state.set_network_status(NetworkStatus::Online);
+        assert!(event_rx.try_recv().is_err());
+
+        state.set_network_status(NetworkStatus::Offline);
+        state.set_network_status(NetworkStatus::Offline);
+        match event_rx.try_recv().unwrap() {
+            AppEvent::Disconnected { state, message, .. } => {
+                assert_eq!(state.network_status, NetworkStatus::Offline);
+                assert_eq!(message.unwrap().message, StatusMessage::NetworkOffline);
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+        assert!(event_rx.try_recv().is_err());
+
+        assert_eq!(state.summary().text, "Disconnected · offline");
+
+        state.set_network_status(NetworkStatus::Online);
+        assert!(event_rx.try_recv().is_ok());
+        assert_eq!(state.summary().text, "Disconnected");
+    }
+
     #[test]
     fn test_set_status() {
         let mut state = create_test_state();
 
-        state.set_status(Status::Punching, Some("Connecting...".into()), Some(30));
+        let mut rx = state.subscribe_events();
+
+        let progress = PunchProgress::new(
+            PunchPhase::Spraying,
+            4,
+            Duration::from_secs(15),
+            Duration::from_secs(30),
+        );
+        state.set_status(
+            Status::Punching,
+            Some(StatusMessage::KeysGenerated),
+            Some(progress),
+        );
         assert_eq!(state.status, Status::Punching);
+        match rx.try_recv().unwrap() {
+            AppEvent::Punching { progress, .. } => assert_eq!(
+                progress,
+                Some(PunchProgress {
+                    phase: PunchPhase::Spraying,
+                    attempts: 4,
+                    percent: 32,
+                    secs_left: 15,
+                })
+            ),
+            other => panic!("Unexpected event: {:?}", other),
+        }
+
+        state.set_status(
+            Status::Connected,
+            Some(StatusMessage::ConnectedViaKcp),
+            None,
+        );
+        assert_eq!(state.status, Status::Connected);
+    }
+
+    #[test]
+    fn test_punch_progress_only_moves_forward() {
+        let phases = [
+            PunchPhase::Resolving,
+            PunchPhase::Spraying,
+            PunchPhase::AwaitingSynAck,
+            PunchPhase::KeyExchange,
+            PunchPhase::UpgradingKcp,
+        ];
+        let timeout = Duration::from_secs(30);
+        let mut last = 0;
+        for phase in phases {
+            let (start, end) = phase.span();
+            // Past the timeout too, as a phase can overrun it
+            for secs in 0..=40 {
+                let progress = PunchProgress::new(phase, 0, Duration::from_secs(secs), timeout);
+                assert!(progress.percent >= last, "{:?} at {} s", phase, secs);
+                assert!((start..=end).contains(&progress.percent));
+                last = progress.percent;
+            }
+            assert_eq!(last, end);
+            // Without a timeout, the phase counts as done
+            let done = PunchProgress::new(phase, 0, Duration::from_secs(5), Duration::ZERO);
+            assert_eq!(done.percent, end);
+        }
+        assert!(last < 100);
+    }
+
+    #[test]
+    fn test_status_events_carry_connection_id() {
+        let mut state = create_test_state();
+        let mut rx = state.subscribe_events();
+
+        let first = state.begin_connection();
+        assert_eq!(first.len(), 8);
+        state.set_status(Status::Punching, None, None);
+        match rx.try_recv().unwrap() {
+            AppEvent::Punching { connection_id, .. } => {
+                assert_eq!(connection_id.as_deref(), Some(first.as_str()))
+            }
+            other => panic!("Unexpected event: {:?}", other),
+        }
+
+        // A reconnect is told apart by its new ID
+        let second = state.begin_connection();
+        assert_ne!(first, second);
+        state.set_status(Status::Disconnected, None, None);
+        match rx.try_recv().unwrap() {
+            AppEvent::Disconnected {
+                state,
+                connection_id,
+                ..
+            } => {
+                assert_eq!(connection_id.as_deref(), Some(second.as_str()));
+                assert_eq!(state.connection_id, connection_id);
+            }
+            other => panic!("Unexpected event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_invalid_status_change_is_ignored() {
+        let mut state = create_test_state();
+        let mut rx = state.subscribe_events();
+
+        // No session without a handshake
+        state.set_status(Status::Connected, None, None);
+        assert_eq!(state.status, Status::Disconnected);
+        assert!(rx.try_recv().is_err());
 
-        state.set_status(Status::Connected, Some("Connected".into()), None);
+        state.set_status(Status::Punching, None, None);
+        state.set_status(Status::Connected, None, None);
+        state.set_rtt(Duration::from_millis(20));
+        while rx.try_recv().is_ok() {}
+
+        // A late handshake update does not drag the session back
+        state.set_status(Status::Punching, Some(StatusMessage::ExchangingKeys), None);
         assert_eq!(state.status, Status::Connected);
+        assert!(rx.try_recv().is_err());
+
+        state.set_status(Status::Disconnected, None, None);
+        assert_eq!(state.status, Status::Disconnected);
+        assert_eq!(state.rtt, None);
+    }
+
+    #[test]
+    fn test_guest_session_is_scrubbed_and_not_logged() {
+        let mut state = create_test_state();
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 9999);
+
+        state.guest = true;
+        state.set_peer_ip(addr, Some("Stranger".into()), None, None);
+        state.begin_connection();
+        state.set_security_info("fp".into(), "ChaCha20-Poly1305".into());
+        state.set_status(Status::Punching, None, None);
+        state.set_status(Status::Connected, None, None);
+        state.set_status(Status::Disconnected, None, None);
+
+        assert!(!state.guest);
+        assert_eq!(state.peer_ip, None);
+        assert_eq!(state.peer_label, None);
+        assert_eq!(state.connection_id, None);
+        assert_eq!(state.fingerprint, None);
+        assert!(state.event_log.entries().is_empty());
+
+        // The next session is logged as usual
+        state.set_peer_ip(addr, None, None, None);
+        assert_eq!(state.event_log.entries().len(), 1);
+    }
+
+    #[test]
+    fn test_wipe_history() {
+        let dir = TempDir::new("wipe");
+        let mut state = create_test_state();
+        state.session_log = SessionLog::open(dir.join("sessions.jsonl"));
+        state.event_log.open(dir.join("event_log.jsonl"));
+        state.crash_report_path = Some(dir.join("crash_report.json"));
+        storage::write_json(&dir.join("crash_report.json"), &"report").unwrap();
+
+        let peer = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 9999);
+        state
+            .session_log
+            .begin("c1".into(), peer, None, NatType::Unknown);
+        state
+            .session_log
+            .finish(DisconnectReason::LocalRequest, None, 0, 0);
+        state.set_peer_ip(peer, None, None, None);
+        let mut rx = state.subscribe_events();
+
+        let report = state.wipe_history().unwrap();
+        assert_eq!(
+            report,
+            WipeReport {
+                sessions: 1,
+                events: 1,
+                crash_report: true,
+            }
+        );
+        assert_eq!(state.session_log.records().count(), 0);
+        assert!(!dir.join("sessions.jsonl").exists());
+        assert!(!dir.join("crash_report.json").exists());
+        assert!(matches!(rx.try_recv().unwrap(), AppEvent::ClearChat));
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            AppEvent::HistoryWiped { .. }
+        ));
     }
 
     #[test]
@@ -333,11 +2106,25 @@ mod tests {
         let mut state = create_test_state();
         let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 9999);
 
-        state.set_peer_ip(addr, Some("Peer set".into()), None);
+        state.set_peer_ip(addr, None, Some(StatusMessage::TargetSet), None);
 
         assert_eq!(state.peer_ip, Some(addr));
     }
 
+    #[test]
+    fn test_set_peer_ip_uses_contact_label() {
+        let mut state = create_test_state();
+        let addr: SocketAddr = "203.0.113.7:41234".parse().unwrap();
+        state.contacts.upsert("Bob".into(), addr).unwrap();
+
+        state.set_peer_ip(addr, None, None, None);
+        assert_eq!(state.peer_label.as_deref(), Some("Bob"));
+
+        // An explicit label wins over the contact
+        state.set_peer_ip(addr, Some("Robert".into()), None, None);
+        assert_eq!(state.peer_label.as_deref(), Some("Robert"));
+    }
+
     #[test]
     fn test_set_security_info() {
         let mut state = create_test_state();
@@ -352,7 +2139,7 @@ mod tests {
     async fn test_event_subscription() {
         let (cmd_tx, _cmd_rx) = mpsc::channel(32);
         let (event_tx, _event_rx) = broadcast::channel(32);
-        let state = AppState::new(cmd_tx, event_tx);
+        let mut state = AppState::new(cmd_tx, event_tx);
 
         let mut rx = state.subscribe_events();
 
@@ -372,7 +2159,11 @@ mod tests {
         let state = AppState::new(cmd_tx.clone(), event_tx);
 
         // Send a command
-        state.cmd_tx().send(Command::ConnectPeer).await.unwrap();
+        state
+            .cmd_tx()
+            .send(Command::ConnectPeer { reply: None })
+            .await
+            .unwrap();
 
         // Should receive the command
         let cmd = cmd_rx.recv().await;
@@ -409,10 +2200,200 @@ mod tests {
 
     #[test]
     fn test_add_message() {
-        let state = create_test_state();
+        let mut state = create_test_state();
 
-        // Should not panic
-        state.add_message("Hello".to_string(), true);
-        state.add_message("World".to_string(), false);
+        // Each side is numbered separately
+        let first = state.add_message("Hello".to_string(), true);
+        let reply = state.add_message("World".to_string(), false);
+        let second = state.add_message("Again".to_string(), true);
+
+        assert_eq!((first.from_me, first.seq), (true, 0));
+        assert_eq!((reply.from_me, reply.seq), (false, 0));
+        assert_eq!(second.seq, 1);
+        assert!(state.has_message(second));
+        assert!(!state.has_message(MessageId {
+            from_me: false,
+            seq: 1
+        }));
+    }
+
+    #[test]
+    fn test_apply_reaction_requires_known_message() {
+        let mut state = create_test_state();
+        let mut rx = state.subscribe_events();
+        let unknown = MessageId {
+            from_me: false,
+            seq: 0,
+        };
+
+        assert!(!state.apply_reaction(unknown, "👍", true, true));
+
+        let message_id = state.add_message("Hi".to_string(), false);
+        assert!(state.apply_reaction(message_id, "👍", true, true));
+
+        rx.try_recv().unwrap(); // the message
+        match rx.try_recv().unwrap() {
+            AppEvent::Reaction { reactions, .. } => {
+                assert_eq!(reactions.len(), 1);
+                assert!(reactions[0].from_me);
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        // A new conversation starts numbering again
+        state.open_conversation("203.0.113.7:41234".parse().unwrap());
+        assert!(!state.has_message(message_id));
+    }
+
+    #[test]
+    fn test_expiring_messages() {
+        let mut state = create_test_state();
+        let mut rx = state.subscribe_events();
+        let now = storage::unix_timestamp();
+
+        state.set_message_ttl(Some(60), true);
+        let mine = state.add_message("gone soon".to_string(), true);
+        let theirs = state.add_peer_message("me too".to_string(), 2, Some(3600));
+        state.set_message_ttl(None, false);
+        let kept = state.add_message("kept".to_string(), true);
+        assert!(state.apply_reaction(mine, "👍", false, true));
+
+        let mut expiries = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            if let AppEvent::Message {
+                message_id,
+                expires_at,
+                ..
+            } = event
+            {
+                expiries.push((message_id, expires_at.map(|at| at - now)));
+            }
+        }
+        assert!(matches!(expiries[0], (id, Some(60 | 61)) if id == mine));
+        assert!(matches!(expiries[1], (id, Some(3600 | 3601)) if id == theirs));
+        assert_eq!(expiries[2], (kept, None));
+
+        state.expire_messages(now + 120);
+        match rx.try_recv().unwrap() {
+            AppEvent::MessageExpired { message_id, .. } => assert_eq!(message_id, mine),
+            other => panic!("unexpected event: {:?}", other),
+        }
+        assert!(rx.try_recv().is_err());
+        assert!(state.reactions.on(mine).is_empty());
+    }
+
+    #[test]
+    fn test_messages_expire_while_disconnected() {
+        let mut state = create_test_state();
+        let peer = "203.0.113.7:41234".parse().unwrap();
+        let now = storage::unix_timestamp();
+
+        let first = state.open_conversation(peer);
+        state.set_message_ttl(Some(60), true);
+        let message_id = state.add_message("gone soon".to_string(), true);
+        state.close_conversation(DisconnectReason::PeerTimeout, false);
+
+        // Neither the disconnect nor the next conversation cancels it
+        let mut rx = state.subscribe_events();
+        state.expire_messages(now + 30);
+        assert!(rx.try_recv().is_err());
+        state.open_conversation(peer);
+        state.close_conversation(DisconnectReason::PeerTimeout, false);
+        while rx.try_recv().is_ok() {}
+
+        state.expire_messages(now + 120);
+        match rx.try_recv().unwrap() {
+            AppEvent::MessageExpired {
+                conversation_id,
+                message_id: expired,
+            } => {
+                assert_eq!(conversation_id, Some(first));
+                assert_eq!(expired, message_id);
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_dropped_conversation_is_backfilled() {
+        let mut state = create_test_state();
+        let peer = "203.0.113.7:41234".parse().unwrap();
+        state.fingerprint = Some("AB CD EF".to_string());
+
+        state.open_conversation(peer);
+        state.add_message("one".to_string(), true);
+        state.add_peer_message("hi".to_string(), 1, None);
+        state.add_message("two".to_string(), true);
+        state.set_message_ttl(Some(60), true);
+        state.add_message("not kept".to_string(), true);
+        state.close_conversation(DisconnectReason::PeerTimeout, false);
+
+        state.fingerprint = Some("12 34 56".to_string());
+        state.open_conversation(peer);
+        assert_eq!(state.sync_summary(), Some(("AB CD EF".to_string(), 1)));
+        assert!(state.take_backfill("12 34 56", 0).is_empty());
+        assert_eq!(state.take_backfill("AB CD EF", 1), ["two"]);
+        // Answered once
+        assert!(state.take_backfill("AB CD EF", 0).is_empty());
+
+        // Nothing is kept after a confirmed Bye
+        state.add_message("three".to_string(), true);
+        state.close_conversation(DisconnectReason::LocalRequest, true);
+        assert_eq!(state.sync_summary(), None);
+    }
+
+    #[test]
+    fn test_conversation_lifecycle_tags_messages() {
+        let (cmd_tx, _cmd_rx) = mpsc::channel(32);
+        let (event_tx, mut event_rx) = broadcast::channel(32);
+        let mut state = AppState::new(cmd_tx, event_tx);
+        let peer: SocketAddr = "203.0.113.7:41234".parse().unwrap();
+        state.peer_ip = Some(peer);
+
+        let id = state.open_conversation(peer);
+        assert_eq!(id.len(), 16);
+        state.add_message("Hello".to_string(), false);
+        state.close_conversation(DisconnectReason::PeerRequest, true);
+        state.close_conversation(DisconnectReason::PeerRequest, true); // already closed
+
+        match event_rx.try_recv().unwrap() {
+            AppEvent::ConversationOpened {
+                conversation_id, ..
+            } => assert_eq!(conversation_id, id),
+            other => panic!("unexpected event: {:?}", other),
+        }
+        assert!(matches!(
+            event_rx.try_recv().unwrap(),
+            AppEvent::System {
+                notice: SystemNotice::Connected { .. },
+                ..
+            }
+        ));
+        match event_rx.try_recv().unwrap() {
+            AppEvent::Message {
+                conversation_id,
+                peer: from,
+                ..
+            } => {
+                assert_eq!(conversation_id.as_deref(), Some(id.as_str()));
+                assert_eq!(from, Some(peer));
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+        assert!(matches!(
+            event_rx.try_recv().unwrap(),
+            AppEvent::System {
+                notice: SystemNotice::Disconnected {
+                    reason: DisconnectReason::PeerRequest
+                },
+                ..
+            }
+        ));
+        assert!(matches!(
+            event_rx.try_recv().unwrap(),
+            AppEvent::ConversationClosed { .. }
+        ));
+        assert!(event_rx.try_recv().is_err());
+        assert_eq!(state.conversation_id, None);
     }
 }
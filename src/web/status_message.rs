@@ -0,0 +1,164 @@
+//! Status messages shown to the user.
+//!
+//! Progress and outcome messages travel as a code plus parameters so a UI
+//! can render them in its own language. Each event also carries the English
+//! text, which UIs without a translation for a code show as is.
+
+use serde::Serialize;
+use std::{fmt, net::SocketAddr};
+
+/// A status message, identified by its code.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "code", content = "params", rename_all = "snake_case")]
+pub enum StatusMessage {
+    PublicIpCached,
+    NatTypeCached,
+    PublicIpResolved,
+    PublicIpUpdated,
+    NatTypeDetected,
+    /// The peer address was set through the API.
+    TargetSet,
+    IncomingAccepted,
+    HandshakeStarted {
+        peer: SocketAddr,
+    },
+    KeysGenerated,
+    /// A handshake packet arrived; `key_prefix` is the start of the peer's key.
+    SynReceived {
+        key_prefix: Vec<u8>,
+    },
+    SynAckReceived {
+        key_prefix: Vec<u8>,
+    },
+    ExchangingKeys,
+    RejectedByPeer,
+    HandshakeTimedOut {
+        peer: Option<SocketAddr>,
+    },
+    HandshakeCancelled,
+    ConnectionFailed {
+        error: String,
+    },
+    SecureChannelEstablished {
+        algorithm: String,
+    },
+    KcpUpgradeFailed {
+        error: String,
+    },
+    ConnectedViaKcp,
+    PeerDisconnected,
+    DisconnectConfirmed,
+    DisconnectUnconfirmed,
+    PeerUnresponsive {
+        idle_secs: u64,
+    },
+    /// NAT keep-alives keep failing.
+    NetworkOffline,
+    NetworkRestored,
+}
+
+/// Renders the English text.
+impl fmt::Display for StatusMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatusMessage::PublicIpCached => write!(f, "Using cached public IP"),
+            StatusMessage::NatTypeCached => write!(f, "Using cached NAT type"),
+            StatusMessage::PublicIpResolved => write!(f, "Public IP resolved"),
+            StatusMessage::PublicIpUpdated => write!(f, "Public IP updated"),
+            StatusMessage::NatTypeDetected => write!(f, "NAT type detected"),
+            StatusMessage::TargetSet => write!(f, "Target set via API"),
+            StatusMessage::IncomingAccepted => write!(f, "Accepted incoming request"),
+            StatusMessage::HandshakeStarted { peer } => {
+                write!(f, "Initiating handshake with {}...", peer)
+            }
+            StatusMessage::KeysGenerated => write!(f, "Handshaking (Keys Generated)..."),
+            StatusMessage::SynReceived { key_prefix } => {
+                write!(f, "Received SYN (Key: {:?})...", key_prefix)
+            }
+            StatusMessage::SynAckReceived { key_prefix } => {
+                write!(f, "Received SYN-ACK (Key: {:?})...", key_prefix)
+            }
+            StatusMessage::ExchangingKeys => write!(f, "Exchanging Keys..."),
+            StatusMessage::RejectedByPeer => write!(f, "Connection rejected by peer"),
+            StatusMessage::HandshakeTimedOut { peer: Some(peer) } => {
+                write!(f, "Handshake timed out with {}", peer)
+            }
+            StatusMessage::HandshakeTimedOut { peer: None } => write!(f, "Handshake timed out"),
+            StatusMessage::HandshakeCancelled => write!(f, "Cancelled during handshake"),
+            StatusMessage::ConnectionFailed { error } => write!(f, "Connection failed: {}", error),
+            StatusMessage::SecureChannelEstablished { algorithm } => {
+                write!(f, "Secure Channel Established ({})", algorithm)
+            }
+            StatusMessage::KcpUpgradeFailed { error } => write!(f, "KCP Upgrade failed: {}", error),
+            StatusMessage::ConnectedViaKcp => write!(f, "Connected securely via KCP"),
+            StatusMessage::PeerDisconnected => write!(f, "Disconnected from peer"),
+            StatusMessage::DisconnectConfirmed => write!(f, "Peer confirmed disconnect"),
+            StatusMessage::DisconnectUnconfirmed => {
+                write!(f, "Disconnected; peer did not confirm and is assumed gone")
+            }
+            StatusMessage::PeerUnresponsive { idle_secs } => {
+                write!(f, "Peer stopped responding for {} s", idle_secs)
+            }
+            StatusMessage::NetworkOffline => {
+                write!(f, "Network unreachable; retrying in the background")
+            }
+            StatusMessage::NetworkRestored => write!(f, "Network reachable again"),
+        }
+    }
+}
+
+/// A status message as sent in events: code, parameters and English text.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EventMessage {
+    #[serde(flatten)]
+    pub message: StatusMessage,
+    pub text: String,
+}
+
+impl From<StatusMessage> for EventMessage {
+    fn from(message: StatusMessage) -> Self {
+        Self {
+            text: message.to_string(),
+            message,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_wire_format() {
+        let peer: SocketAddr = "203.0.113.5:4000".parse().unwrap();
+        let event = EventMessage::from(StatusMessage::HandshakeStarted { peer });
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            json!({
+                "code": "handshake_started",
+                "params": { "peer": "203.0.113.5:4000" },
+                "text": "Initiating handshake with 203.0.113.5:4000...",
+            })
+        );
+
+        // Messages without parameters carry only the code and text
+        let event = EventMessage::from(StatusMessage::ExchangingKeys);
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            json!({ "code": "exchanging_keys", "text": "Exchanging Keys..." })
+        );
+    }
+
+    #[test]
+    fn test_english_fallback() {
+        let message = StatusMessage::SynReceived {
+            key_prefix: vec![1, 2, 3, 4],
+        };
+        assert_eq!(message.to_string(), "Received SYN (Key: [1, 2, 3, 4])...");
+        assert_eq!(
+            StatusMessage::HandshakeTimedOut { peer: None }.to_string(),
+            "Handshake timed out"
+        );
+    }
+}
@@ -0,0 +1,149 @@
+//! JSON request bodies that are checked field by field.
+//!
+//! Axum's `Json` answers a body it cannot read with a plain-text 422 and
+//! lets a typoed field through as if it were absent. `StrictJson` is meant
+//! for request types with `#[serde(deny_unknown_fields)]`, and answers with
+//! an `ApiError` that names the field it tripped over.
+
+use axum::{
+    Json,
+    body::Bytes,
+    extract::{FromRequest, Request},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+
+/// An API error answered as `{"error": ..., "field": ...}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiError {
+    pub status: StatusCode,
+    /// Request field at fault, if the error is about one.
+    pub field: Option<String>,
+    pub message: String,
+}
+
+impl ApiError {
+    /// A 400 for a field that was read but is not acceptable.
+    pub fn invalid(field: &str, message: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            field: Some(field.to_string()),
+            message: message.into(),
+        }
+    }
+}
+
+impl From<(StatusCode, String)> for ApiError {
+    fn from((status, message): (StatusCode, String)) -> Self {
+        Self {
+            status,
+            field: None,
+            message,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = json!({ "error": self.message, "field": self.field });
+        (self.status, Json(body)).into_response()
+    }
+}
+
+/// A JSON body that must match `T` exactly.
+pub struct StrictJson<T>(pub T);
+
+impl<T: DeserializeOwned, S: Send + Sync> FromRequest<S> for StrictJson<T> {
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let is_json = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("application/json"));
+        if !is_json {
+            return Err((
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Expected a body with Content-Type: application/json".to_string(),
+            )
+                .into());
+        }
+        let body = Bytes::from_request(req, state)
+            .await
+            .map_err(|e| ApiError::from((e.status(), e.body_text())))?;
+        parse(&body).map(StrictJson)
+    }
+}
+
+/// Reads `body` as `T`, naming the offending field on failure.
+///
+/// # Errors
+///
+/// Returns 400 if the body is not JSON, and 422 if it does not match `T`.
+fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, ApiError> {
+    let mut deserializer = serde_json::Deserializer::from_slice(body);
+    let value: T = serde_path_to_error::deserialize(&mut deserializer).map_err(|e| {
+        let path = e.path().to_string();
+        let inner = e.into_inner();
+        if inner.is_syntax() || inner.is_eof() {
+            return ApiError::from((StatusCode::BAD_REQUEST, format!("Invalid JSON: {}", inner)));
+        }
+        let message = inner.to_string();
+        // A missing field is reported at the enclosing object
+        let missing = message
+            .strip_prefix("missing field `")
+            .and_then(|rest| rest.split('`').next());
+        let field = match (path.as_str(), missing) {
+            (".", Some(name)) => name.to_string(),
+            (".", None) => path,
+            (_, Some(name)) => format!("{}.{}", path, name),
+            (_, None) => path,
+        };
+        ApiError {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            field: Some(field),
+            message,
+        }
+    })?;
+    deserializer
+        .end()
+        .map_err(|e| ApiError::from((StatusCode::BAD_REQUEST, format!("Invalid JSON: {}", e))))?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Sample {
+        ip: String,
+        port: u16,
+    }
+
+    #[test]
+    fn test_errors_name_the_field() {
+        let field = |body: &str| parse::<Sample>(body.as_bytes()).unwrap_err().field;
+
+        assert_eq!(field(r#"{"ip": "1.2.3.4"}"#).as_deref(), Some("port"));
+        assert_eq!(
+            field(r#"{"ip": "1.2.3.4", "port": 1, "prot": 2}"#).as_deref(),
+            Some("prot")
+        );
+        assert_eq!(
+            field(r#"{"ip": "1.2.3.4", "port": 70000}"#).as_deref(),
+            Some("port")
+        );
+
+        let error = parse::<Sample>(b"{\"ip\": ").unwrap_err();
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+        assert_eq!(error.field, None);
+        let sample: Sample = parse(br#"{"ip": "1.2.3.4", "port": 1}"#).unwrap();
+        assert_eq!((sample.ip.as_str(), sample.port), ("1.2.3.4", 1));
+    }
+}
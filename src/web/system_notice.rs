@@ -0,0 +1,120 @@
+//! Notices generated by the node, as opposed to chat content.
+//!
+//! A notice travels as a kind plus parameters, like a status message, with
+//! the English text alongside. UIs and exports can then style, translate or
+//! filter notices instead of reading them out of message text. They are
+//! stored in the event log with their parameters.
+
+use crate::audit::DisconnectReason;
+use serde::Serialize;
+use std::{fmt, net::SocketAddr};
+
+/// A system notice, identified by its kind.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", content = "params", rename_all = "snake_case")]
+pub enum SystemNotice {
+    /// A session with the peer was established.
+    Connected {
+        peer: SocketAddr,
+        peer_label: Option<String>,
+    },
+    Disconnected {
+        reason: DisconnectReason,
+    },
+    /// The peer was given a new label.
+    PeerRenamed {
+        label: String,
+        /// True if the label was saved as a contact.
+        saved: bool,
+    },
+    /// The peer read the last chunk of a file in our shares.
+    TransferFinished {
+        share: String,
+        path: String,
+        size: u64,
+    },
+    /// A chat command ran, or could not.
+    CommandResult {
+        command: String,
+        output: Option<String>,
+        error: Option<String>,
+    },
+}
+
+/// Renders the English text.
+impl fmt::Display for SystemNotice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SystemNotice::Connected {
+                peer_label: Some(label),
+                ..
+            } => write!(f, "Connected to {}", label),
+            SystemNotice::Connected { peer, .. } => write!(f, "Connected to {}", peer),
+            SystemNotice::Disconnected { reason } => match reason {
+                DisconnectReason::LocalRequest => write!(f, "You disconnected"),
+                DisconnectReason::PeerRequest => write!(f, "The peer disconnected"),
+                DisconnectReason::PeerTimeout => write!(f, "The peer stopped responding"),
+                DisconnectReason::HandshakeFailed | DisconnectReason::UpgradeFailed => {
+                    write!(f, "The connection failed")
+                }
+            },
+            SystemNotice::PeerRenamed { label, saved: true } => {
+                write!(f, "Peer is now {}, saved to contacts", label)
+            }
+            SystemNotice::PeerRenamed {
+                label,
+                saved: false,
+            } => {
+                write!(f, "Peer is now {} for this session", label)
+            }
+            SystemNotice::TransferFinished { share, path, size } => {
+                write!(
+                    f,
+                    "Peer finished reading {}/{} ({} bytes)",
+                    share, path, size
+                )
+            }
+            SystemNotice::CommandResult {
+                command,
+                error: Some(error),
+                ..
+            } => write!(f, "{} failed: {}", command, error),
+            SystemNotice::CommandResult {
+                command, output, ..
+            } => write!(f, "{} · {}", command, output.as_deref().unwrap_or("done")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_wire_format() {
+        let notice = SystemNotice::TransferFinished {
+            share: "docs".into(),
+            path: "a/b.txt".into(),
+            size: 42,
+        };
+        assert_eq!(
+            serde_json::to_value(&notice).unwrap(),
+            json!({
+                "kind": "transfer_finished",
+                "params": { "share": "docs", "path": "a/b.txt", "size": 42 },
+            })
+        );
+        assert_eq!(
+            notice.to_string(),
+            "Peer finished reading docs/a/b.txt (42 bytes)"
+        );
+
+        let notice = SystemNotice::CommandResult {
+            command: "/ping".into(),
+            output: None,
+            error: Some("Not connected to a peer".into()),
+        };
+        assert_eq!(notice.to_string(), "/ping failed: Not connected to a peer");
+    }
+}
@@ -5,262 +5,3612 @@
 //! 2. REST API endpoints
 //! 3. Server-Sent Events (SSE) for real-time updates
 
-use super::shared_state::{Command, SharedState, Status};
-use crate::config::EncryptionMode;
+use super::forwarded::{self, Client, Peer};
+use super::shared_state::{
+    AppState, COMMAND_SEND_TIMEOUT, Command, EventCategory, SharedState, Status, Summary,
+};
+use super::status_message::StatusMessage;
+use super::strict_json::{ApiError, StrictJson};
+use crate::{
+    bundle::Bundle,
+    config::EncryptionMode,
+    contacts::{Endpoint, validate_endpoints, validate_hostname, validate_label},
+    crash_report::{self, ConfigSummary},
+    ddns,
+    echo_bot::EchoBot,
+    history_export::ExportFormat,
+    messaging::{
+        chat_command::{self, ChatInput},
+        expiry::validate_ttl,
+        handshake::Feature,
+        ping::{DEFAULT_PING_COUNT, MAX_PING_COUNT},
+        reactions::{MessageId, validate_emoji},
+        text_limit,
+    },
+    observers,
+    operations::OperationKind,
+    power_profile::PowerHints,
+    reachability,
+    retention::Retention,
+    selftest,
+    share::{MAX_READ_LEN, ShareRequest, ShareResponse},
+    stats_history::SAMPLE_INTERVAL,
+    storage::unix_timestamp,
+    ui_preferences::UiPreferences,
+    update::build_info,
+    wol::{self, WakeTarget},
+};
 use anyhow::Result;
 use axum::{
     Json, Router,
-    extract::State,
-    http::StatusCode,
+    extract::{ConnectInfo, Path, Query, Request, State, connect_info::Connected},
+    http::{HeaderName, HeaderValue, StatusCode, header},
+    middleware::{self, Next},
     response::{
-        IntoResponse,
+        IntoResponse, Redirect, Response,
         sse::{Event, KeepAlive, Sse},
     },
-    routing::{get, post},
+    routing::{delete, get, post, put},
+    serve::{IncomingStream, Listener},
 };
 use futures::stream::Stream;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::{
     convert::Infallible,
+    hash::{Hash, Hasher},
     net::{IpAddr, Ipv4Addr, SocketAddr},
     str::FromStr,
     time::Duration,
 };
+use tokio::sync::{mpsc::error::SendTimeoutError, oneshot};
 use tokio_stream::{StreamExt, wrappers::BroadcastStream};
 use tower_http::{cors::CorsLayer, services::ServeDir};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 /// Starts the HTTP server.
 ///
 /// # Arguments
 ///
 /// * `shared_state` - Thread-safe application state
-/// * `port` - Port to listen on
-pub async fn start_web_server(shared_state: SharedState, port: u16) -> Result<()> {
-    let app = router(shared_state);
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+/// * `listener` - Bound listener to serve on
+/// * `base_path` - Path prefix the UI and API are served under
+pub async fn start_web_server<L>(
+    shared_state: SharedState,
+    listener: L,
+    base_path: &str,
+) -> Result<()>
+where
+    L: Listener,
+    L::Addr: std::fmt::Debug,
+    Peer: for<'a> Connected<IncomingStream<'a, L>>,
+{
+    let app = app(shared_state, base_path);
+    axum::serve(listener, app.into_make_service_with_connect_info::<Peer>()).await?;
 
-    info!("Web UI available at http://{}", addr);
+    Ok(())
+}
 
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+/// Creates the router served under `base_path`, e.g. `/ghostlink` behind a
+/// reverse proxy, or `""` for the root.
+///
+/// The bare base path redirects to itself with a trailing slash, so the
+/// UI's relative URLs resolve under it.
+pub fn app(shared_state: SharedState, base_path: &str) -> Router {
+    if base_path.is_empty() {
+        return router(shared_state);
+    }
+    let base = base_path.to_string();
+    Router::new()
+        .nest_service(base_path, router(shared_state))
+        .layer(middleware::from_fn(move |request: Request, next: Next| {
+            let index = (request.uri().path() == base).then(|| format!("{}/", base));
+            async move {
+                match index {
+                    Some(index) => Redirect::permanent(&index).into_response(),
+                    None => next.run(request).await,
+                }
+            }
+        }))
+}
 
+/// Opens `url` in the default browser.
+///
+/// # Errors
+///
+/// Returns an error if the platform's opener cannot be started.
+pub fn open_browser(url: &str) -> Result<()> {
+    let mut command = if cfg!(target_os = "windows") {
+        let mut command = std::process::Command::new("cmd");
+        command.args(["/C", "start", ""]);
+        command
+    } else if cfg!(target_os = "macos") {
+        std::process::Command::new("open")
+    } else {
+        std::process::Command::new("xdg-open")
+    };
+    command
+        .arg(url)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()?;
     Ok(())
 }
 
 /// Creates the Axum router with all routes and middleware.
 pub fn router(shared_state: SharedState) -> Router {
-    Router::new()
+    let app = Router::new()
         // API Routes
         .route("/api/state", get(get_state))
         .route("/api/connect", post(connect_peer))
+        .route("/api/probe", post(probe_peer))
         .route("/api/disconnect", post(disconnect_peer))
+        .route("/api/incoming/accept", post(accept_incoming))
+        .route("/api/incoming/reject", post(reject_incoming))
         .route("/api/message", post(send_message))
+        .route("/api/message-ttl", post(set_message_ttl))
+        .route("/api/scheduled", get(get_scheduled).post(schedule_message))
+        .route(
+            "/api/scheduled/{id}",
+            put(edit_scheduled).delete(cancel_scheduled),
+        )
+        .route("/api/broadcast", post(broadcast_message))
+        .route("/api/reactions", post(react_to_message))
+        .route("/api/assist", post(request_assist))
+        .route("/api/share", get(list_peer_share))
+        .route("/api/share/read", get(read_peer_share))
+        .route("/api/remote-wipe", post(remote_wipe))
         .route("/api/events", get(sse_handler))
+        .route("/api/sessions", get(get_sessions))
+        .route(
+            "/api/history-export",
+            get(get_history_export).put(set_history_export),
+        )
+        .route("/api/stats/history", get(get_stats_history))
+        .route("/api/power-profile", put(set_power_profile))
+        .route("/api/config", get(get_config))
+        .route("/api/version", get(get_version))
+        .route("/api/capabilities", get(get_capabilities))
+        .route(
+            "/api/conversation/retention",
+            post(set_conversation_retention),
+        )
+        .route("/api/contacts", get(get_contacts).post(save_contact))
+        .route("/api/contacts/{label}", delete(delete_contact))
+        .route("/api/contacts/{label}/wake", post(wake_contact))
+        .route("/api/observers", get(get_observers).post(add_observer))
+        .route("/api/observers/{name}", delete(delete_observer))
+        .route("/api/diagnostics", get(get_diagnostics))
+        .route("/api/debug/handshake-log", get(get_handshake_log))
+        .route("/api/debug/last-run", get(get_last_run))
+        .route("/api/debug/runtime", get(get_runtime))
+        .route("/api/debug/bundle", post(export_bundle))
+        .route(
+            "/api/crash-report",
+            get(get_crash_report).delete(dismiss_crash_report),
+        )
+        .route("/api/debug/capture/start", post(start_capture))
+        .route("/api/debug/capture/stop", post(stop_capture))
+        .route("/api/selftest", post(run_selftest))
+        .route("/api/echo-bot", post(set_echo_bot))
+        .route("/api/ping", post(ping_peer))
+        .route("/api/operations", get(get_operations))
+        .route("/api/operations/{id}", get(get_operation))
+        .route("/api/stats", get(get_stats))
+        .route("/api/summary", get(get_summary))
+        .route("/api/read", post(mark_read))
+        .route(
+            "/api/ui-preferences",
+            get(get_ui_preferences).put(put_ui_preferences),
+        )
+        .route("/metrics", get(get_metrics));
+
+    #[cfg(feature = "netem")]
+    let app = app.route("/api/debug/netem", get(get_netem).post(set_netem));
+
+    app
         // Static File Serving (Fallback)
         .fallback_service(ServeDir::new("static").append_index_html_on_directories(true))
         // Middleware
         .layer(CorsLayer::permissive())
+        .layer(middleware::map_response(add_retry_after))
+        .layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            observer_scope,
+        ))
+        .layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            identify_client,
+        ))
         .with_state(shared_state)
 }
 
+/// Records who sent a request as a [`Client`] extension, looking through
+/// trusted reverse proxies.
+async fn identify_client(
+    State(state): State<SharedState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<Peer>>()
+        .and_then(|ConnectInfo(Peer(addr))| addr.map(|addr| addr.ip()));
+    let trusted = state.read().await.trusted_proxies.clone();
+    let client = forwarded::resolve(peer, request.headers(), &trusted);
+    debug!(
+        "{} {} from {}{}",
+        request.method(),
+        request.uri().path(),
+        client,
+        if client.https { " (https)" } else { "" }
+    );
+    request.extensions_mut().insert(client);
+    next.run(request).await
+}
+
+/// Limits requests carrying an observer token to reading state and events.
+///
+/// The token comes from a bearer `Authorization` header or, for
+/// `EventSource`, the `observer_token` query parameter.
+///
+/// A request without a token has full access only from this machine
+/// (loopback, or the API socket) once any observer exists, as the web UI
+/// listens on all interfaces. Observers themselves are only ever managed
+/// from this machine, so nobody on the LAN can issue a token.
+async fn observer_scope(
+    State(state): State<SharedState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let query = request.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("observer_token="))
+    });
+    let Some(token) = bearer.or(query) else {
+        let local = request
+            .extensions()
+            .get::<Client>()
+            .is_none_or(|client| client.ip.is_none_or(|ip| ip.is_loopback()));
+        let managing = request.uri().path().starts_with("/api/observers");
+        if !local && (managing || !state.read().await.observers.all().is_empty()) {
+            return (
+                StatusCode::UNAUTHORIZED,
+                "Only local clients may use the API without a token",
+            )
+                .into_response();
+        }
+        return next.run(request).await;
+    };
+
+    let name = state
+        .read()
+        .await
+        .observers
+        .authenticate(token)
+        .map(str::to_string);
+    match name {
+        None => {
+            if let Some(client) = request.extensions().get::<Client>() {
+                warn!("Rejected an unknown observer token from {}", client);
+            }
+            (StatusCode::UNAUTHORIZED, "Unknown observer token").into_response()
+        }
+        Some(name) if observers::allowed(request.method(), request.uri().path()) => {
+            if request.uri().path() == "/api/events" {
+                info!("Observer {} attached to the event stream", name);
+            }
+            next.run(request).await
+        }
+        Some(_) => (StatusCode::FORBIDDEN, "Observers have read-only access").into_response(),
+    }
+}
+
+/// Seconds a client should wait before retrying after a 503.
+const RETRY_AFTER_SECS: u64 = 1;
+
+/// Tells clients when to retry requests rejected because the controller is busy.
+async fn add_retry_after(mut response: Response) -> Response {
+    if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+        response
+            .headers_mut()
+            .entry(header::RETRY_AFTER)
+            .or_insert_with(|| HeaderValue::from(RETRY_AFTER_SECS));
+    }
+    response
+}
+
+/// Queues a command for the controller.
+///
+/// Waits at most `COMMAND_SEND_TIMEOUT` for room in the queue so a stalled
+/// controller cannot hold API requests open indefinitely.
+///
+/// # Returns
+///
+/// * `Ok(())` - The command was queued.
+/// * `Err` - 503 if the queue stayed full, 500 if the controller has stopped.
+async fn send_command(state: &SharedState, command: Command) -> Result<(), (StatusCode, String)> {
+    let cmd_tx = state.read().await.cmd_tx().clone();
+    match cmd_tx.send_timeout(command, COMMAND_SEND_TIMEOUT).await {
+        Ok(()) => {
+            state.write().await.record_command_queued();
+            Ok(())
+        }
+        Err(SendTimeoutError::Timeout(_)) => {
+            warn!("Command queue full; rejecting request");
+            state.write().await.record_command_rejected();
+            Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Controller is busy, try again shortly".to_string(),
+            ))
+        }
+        Err(SendTimeoutError::Closed(_)) => {
+            error!("Failed to send command: controller has stopped");
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal Controller Error".to_string(),
+            ))
+        }
+    }
+}
+
 // --- API Handlers ---
 
 /// Handler for `GET /api/state`.
-/// Returns current application state including IPs, NAT type, and status.
+/// Returns current application state including IPs, NAT type, and status,
+/// with the event count and checksum SSE heartbeats are compared against.
 async fn get_state(State(state): State<SharedState>) -> impl IntoResponse {
     let data = state.read().await;
-    Json(json!({ "state": data.clone() }))
+    Json(json!({
+        "state": data.clone(),
+        "seq": data.event_seq(),
+        "checksum": data.checksum(),
+    }))
+}
+
+/// Handler for `GET /api/sessions`.
+/// Returns the audit log of past sessions (newest first) and the active one, if any.
+async fn get_sessions(State(state): State<SharedState>) -> impl IntoResponse {
+    let data = state.read().await;
+    let sessions: Vec<_> = data.session_log.records().rev().cloned().collect();
+    Json(json!({
+        "current": data.session_log.current(),
+        "sessions": sessions,
+    }))
+}
+
+/// Handler for `GET /api/history-export`.
+/// Returns the history export settings and the last file written.
+async fn get_history_export(State(state): State<SharedState>) -> impl IntoResponse {
+    Json(json!({ "history_export": state.read().await.history_export }))
 }
 
 #[derive(Debug, Deserialize)]
-struct ConnectionRequest {
-    ip: String,
-    port: u16,
-    #[serde(default = "default_encryption_mode")]
-    mode: EncryptionMode,
+struct HistoryExportRequest {
+    enabled: bool,
+    /// New file format; omitted keeps the current one.
+    #[serde(default)]
+    format: Option<ExportFormat>,
 }
 
-fn default_encryption_mode() -> EncryptionMode {
-    EncryptionMode::ChaCha20Poly1305
+/// Handler for `PUT /api/history-export`.
+/// Turns the periodic history export on or off until the next restart, and
+/// changes its format if given. Exports right away if there is anything new.
+async fn set_history_export(
+    State(state): State<SharedState>,
+    Json(input): Json<HistoryExportRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let mut guard = state.write().await;
+    guard.history_export.set(input.enabled, input.format);
+    guard.export_history(unix_timestamp()).map_err(|e| {
+        error!("Failed to export session history: {:#}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))
+    })?;
+    Ok(Json(json!({ "history_export": guard.history_export })))
 }
 
-/// Handler for `POST /api/connect`.
-/// Validates peer IP and triggers connection process.
-async fn connect_peer(
+/// Handler for `GET /api/config`.
+/// Returns the retention policy in force and the one applying to the open conversation.
+async fn get_config(State(state): State<SharedState>) -> impl IntoResponse {
+    let data = state.read().await;
+    let log = &data.session_log;
+    Json(json!({
+        "retention": log.policy(),
+        "conversation_retention": log.current().map(|record| log.retention_of(record)),
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct ConversationRetentionRequest {
+    /// Retention for this conversation; `null` returns it to the policy.
+    retention: Option<Retention>,
+}
+
+/// Handler for `POST /api/conversation/retention`.
+/// Overrides how long the session in progress is kept once it ends.
+async fn set_conversation_retention(
     State(state): State<SharedState>,
-    Json(input): Json<ConnectionRequest>,
+    Json(input): Json<ConversationRetentionRequest>,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
-    debug!(
-        "Received connection request: {}:{} (Mode: {:?})",
-        input.ip, input.port, input.mode
-    );
+    let mut guard = state.write().await;
+    if !guard.session_log.set_retention(input.retention) {
+        return Err((StatusCode::CONFLICT, "No session in progress".to_string()));
+    }
+    let log = &guard.session_log;
+    let retention = log.current().map(|record| log.retention_of(record));
+    Ok(Json(json!({ "conversation_retention": retention })))
+}
 
-    // 1. Validate Input IP
-    let ip_v4 = Ipv4Addr::from_str(&input.ip).map_err(|e| {
-        (
-            StatusCode::BAD_REQUEST,
-            format!("Invalid IP address: {}", e),
-        )
-    })?;
+/// Handler for `GET /api/version`.
+/// Returns what this binary was built from and, with the update check
+/// enabled, any newer release it found.
+async fn get_version(State(state): State<SharedState>) -> impl IntoResponse {
+    let data = state.read().await;
+    Json(json!({
+        "build": build_info(),
+        "update_check": data.config_summary.as_ref().is_some_and(|c| c.update_check),
+        "available_update": data.available_update,
+    }))
+}
 
-    let peer_addr = SocketAddr::new(IpAddr::V4(ip_v4), input.port);
+/// Handler for `GET /api/capabilities`.
+/// Returns which optional subsystems this binary was built with and which
+/// the configuration turns on, so clients only offer what works.
+async fn get_capabilities(State(state): State<SharedState>) -> impl IntoResponse {
+    let data = state.read().await;
+    let config = data.config_summary.as_ref();
+    let enabled = |on: fn(&ConfigSummary) -> bool| config.is_some_and(on);
+    Json(json!({
+        "compiled": build_info().features,
+        "enabled": {
+            "assist": enabled(|c| c.assist_grants > 0),
+            "shares": enabled(|c| c.shares > 0),
+            "remote_wipe": enabled(|c| c.own_node_secret && c.wipe_contacts > 0),
+            "wake_relay": enabled(|c| c.own_node_secret && c.wake_relay_contacts > 0),
+            "mirror": enabled(|c| c.own_node_secret && c.mirror_peers > 0),
+            "ddns": enabled(|c| c.ddns),
+            "update_check": enabled(|c| c.update_check),
+            "link_previews": enabled(|c| c.link_previews),
+            "traffic_padding": enabled(|c| c.traffic_padding),
+            "handshake_log": enabled(|c| c.debug_transcript),
+            "netem": cfg!(feature = "netem"),
+        },
+    }))
+}
 
-    // 2. Validate State & Update
-    {
+/// Handler for `GET /api/diagnostics`.
+/// Returns per-server STUN results (mapped address, RTT, error), the last network error
+/// and command queue load.
+async fn get_diagnostics(State(state): State<SharedState>) -> impl IntoResponse {
+    Json(diagnostics_report(&*state.read().await))
+}
+
+fn diagnostics_report(data: &AppState) -> serde_json::Value {
+    json!({
+        "nat_type": data.nat_type,
+        "stun": data.stun_probes,
+        "last_network_error": data.last_network_error,
+        "command_queue": data.command_queue_stats(),
+    })
+}
+
+/// Handler for `GET /api/stats`.
+/// Returns bytes and packets sent since startup, by traffic class, and data
+/// usage against the configured caps.
+async fn get_stats(State(state): State<SharedState>) -> impl IntoResponse {
+    let data = state.read().await;
+    let sent: serde_json::Map<_, _> = data
+        .traffic
+        .snapshot()
+        .into_iter()
+        .map(|(class, counter)| (class.as_str().to_string(), json!(counter)))
+        .collect();
+    Json(json!({
+        "sent": sent,
+        "data_usage": data.data_budget.report(),
+    }))
+}
+
+/// Longest a `GET /api/summary` long-poll may wait for a change.
+const MAX_SUMMARY_WAIT_SECS: u64 = 300;
+
+/// How often a long-poll re-checks values that change without an event (RTT).
+#[derive(Debug, Deserialize)]
+struct StatsHistoryQuery {
+    /// Session to return. Defaults to the current one, or the last that ended.
+    connection_id: Option<String>,
+}
+
+/// Handler for `GET /api/stats/history`.
+/// Returns the link quality samples and transfer marks of one session, for
+/// sparklines, and a list of the sessions that have them (newest first).
+///
+/// # Errors
+///
+/// Returns 404 if no statistics are kept for `connection_id`.
+async fn get_stats_history(
+    State(state): State<SharedState>,
+    Query(query): Query<StatsHistoryQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let data = state.read().await;
+    let history = &data.stats_history;
+    let session = match &query.connection_id {
+        Some(id) => Some(history.find(id).ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                format!("No link statistics for connection {}", id),
+            )
+        })?),
+        None => history.current().or_else(|| history.past().next_back()),
+    };
+    let sessions: Vec<_> = history
+        .current()
+        .into_iter()
+        .chain(history.past().rev())
+        .map(|stats| {
+            json!({
+                "connection_id": stats.connection_id,
+                "peer": stats.peer,
+                "started_at_ms": stats.started_at_ms,
+                "samples": stats.samples.len(),
+            })
+        })
+        .collect();
+    Ok(Json(json!({
+        "interval_ms": SAMPLE_INTERVAL.as_millis() as u64,
+        "session": session,
+        "sessions": sessions,
+    })))
+}
+
+/// Handler for `PUT /api/power-profile`.
+/// Replaces the metered and battery hints until the next restart. A field
+/// left out or `null` goes back to detection. Returns the resulting profile.
+async fn set_power_profile(
+    State(state): State<SharedState>,
+    StrictJson(hints): StrictJson<PowerHints>,
+) -> Result<impl IntoResponse, ApiError> {
+    let profile = {
         let mut guard = state.write().await;
-        if guard.status != Status::Disconnected {
+        guard.set_power_hints(hints);
+        guard.power_profile.clone()
+    };
+    send_command(&state, Command::PowerProfileChanged).await?;
+    Ok(Json(json!({ "power_profile": profile })))
+}
+
+const SUMMARY_RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Deserialize)]
+struct SummaryQuery {
+    /// Long-poll: hold the request until the summary changes, up to this long.
+    wait_secs: Option<u64>,
+    /// Version the caller already has. Defaults to the current one.
+    since: Option<String>,
+    /// `text` returns just the one-line status as plain text.
+    format: Option<String>,
+}
+
+/// Handler for `GET /api/summary`.
+/// Returns a compact status (state, peer, unread count, RTT) for status bar
+/// scripts. With `wait_secs`, the response is held until the summary differs
+/// from `since`. The version is returned in the `ETag` header and, for JSON,
+/// in the body.
+async fn get_summary(
+    State(state): State<SharedState>,
+    Query(query): Query<SummaryQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    if query.wait_secs.is_some_and(|w| w > MAX_SUMMARY_WAIT_SECS) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("wait_secs must be at most {}", MAX_SUMMARY_WAIT_SECS),
+        ));
+    }
+    let text_format = match query.format.as_deref() {
+        None | Some("json") => false,
+        Some("text") => true,
+        Some(other) => {
             return Err((
                 StatusCode::BAD_REQUEST,
-                "Cannot connect: Node is already busy (connected or punching).".to_string(),
+                format!("Unknown format '{}'; use json or text", other),
             ));
         }
+    };
 
-        // Set the peer IP
-        guard.set_peer_ip(peer_addr, Some("Target set via API".into()), None);
-    }
+    let (mut summary, mut events) = {
+        let data = state.read().await;
+        (data.summary(), data.subscribe_events())
+    };
+    let mut version = summary_version(&summary);
 
-    // 3. Send command to controller
-    // Controller reads peer_addr from SharedState
-    let cmd_tx = state.read().await.cmd_tx().clone();
-    if let Err(e) = cmd_tx.send(Command::ConnectPeer).await {
-        error!("Failed to send ConnectPeer command: {}", e);
-        return Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Internal Controller Error".to_string(),
-        ));
+    if let Some(wait_secs) = query.wait_secs {
+        let since = query.since.unwrap_or_else(|| version.clone());
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(wait_secs);
+        while version == since {
+            let now = tokio::time::Instant::now();
+            if now >= deadline {
+                break;
+            }
+            let recheck = deadline.min(now + SUMMARY_RECHECK_INTERVAL);
+            // Lagging behind on events only means re-checking sooner
+            let _ = tokio::time::timeout_at(recheck, events.recv()).await;
+            summary = state.read().await.summary();
+            version = summary_version(&summary);
+        }
     }
 
-    Ok(StatusCode::OK)
+    let etag = HeaderValue::from_str(&format!("\"{}\"", version))
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let body = if text_format {
+        (
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            format!("{}\n", summary.text),
+        )
+            .into_response()
+    } else {
+        Json(json!({ "summary": summary, "version": version })).into_response()
+    };
+    Ok(([(header::ETAG, etag)], body))
 }
 
-/// Handler for `POST /api/disconnect`.
-/// Triggers graceful disconnection from the current peer.
-async fn disconnect_peer(
+/// Hashes a summary into the version string long-polls compare against.
+fn summary_version(summary: &Summary) -> String {
+    let mut hasher = std::hash::DefaultHasher::new();
+    serde_json::to_string(summary)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Handler for `POST /api/read`.
+/// Marks the peer's messages received so far as read.
+async fn mark_read(State(state): State<SharedState>) -> impl IntoResponse {
+    state.write().await.mark_read();
+    StatusCode::NO_CONTENT
+}
+
+/// Handler for `GET /metrics`.
+/// Returns the traffic counters for Prometheus to scrape.
+async fn get_metrics(State(state): State<SharedState>) -> impl IntoResponse {
+    let traffic = state.read().await.traffic.clone();
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        traffic.prometheus(),
+    )
+}
+
+/// Handler for `GET /api/ui-preferences`.
+/// Returns the web UI settings stored on this node.
+async fn get_ui_preferences(State(state): State<SharedState>) -> impl IntoResponse {
+    Json(state.read().await.ui_preferences.get().clone())
+}
+
+/// Handler for `PUT /api/ui-preferences`.
+/// Replaces the web UI settings; fields left out take their defaults.
+async fn put_ui_preferences(
     State(state): State<SharedState>,
+    Json(preferences): Json<UiPreferences>,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
-    debug!("Received disconnect request");
+    state
+        .write()
+        .await
+        .set_ui_preferences(preferences.clone())
+        .map_err(|e| {
+            error!("Failed to save UI preferences: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        })?;
+    Ok(Json(preferences))
+}
 
-    // Check if connected or punching
-    let status = state.read().await.status;
-    if status == Status::Disconnected {
-        return Err((StatusCode::BAD_REQUEST, "Already disconnected".to_string()));
-    }
+/// Handler for `GET /api/contacts`.
+/// Returns saved peer labels.
+async fn get_contacts(State(state): State<SharedState>) -> impl IntoResponse {
+    let data = state.read().await;
+    Json(json!({ "contacts": data.contacts.all() }))
+}
 
-    // Send command to controller
-    let cmd_tx = state.read().await.cmd_tx().clone();
-    if let Err(e) = cmd_tx.send(Command::Disconnect).await {
-        error!("Failed to send Disconnect command: {}", e);
-        return Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Internal Controller Error".to_string(),
-        ));
+#[derive(Debug, Deserialize)]
+struct ContactRequest {
+    label: String,
+    /// Looked up from `hostname` when omitted.
+    #[serde(default)]
+    addr: Option<SocketAddr>,
+    /// Other endpoints of the peer. Omitted keeps the saved ones.
+    #[serde(default)]
+    endpoints: Option<Vec<Endpoint>>,
+    /// `host:port` the address is looked up from when dialling. Omitted
+    /// keeps the saved one; empty clears it.
+    #[serde(default)]
+    hostname: Option<String>,
+    /// Wake-on-LAN target of the peer. Omitted keeps the saved one; an
+    /// empty `mac` clears it.
+    #[serde(default)]
+    wake: Option<WakeTarget>,
+}
+
+/// Handler for `POST /api/contacts`.
+/// Saves a label for a peer address, replacing any contact with the same label or address.
+async fn save_contact(
+    State(state): State<SharedState>,
+    Json(input): Json<ContactRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let label = validate_label(&input.label).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    if let Some(endpoints) = &input.endpoints {
+        validate_endpoints(endpoints).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
     }
+    let hostname = match input.hostname.as_deref().map(str::trim) {
+        None => None,
+        Some("") => Some(None),
+        Some(hostname) => Some(Some(
+            validate_hostname(hostname).map_err(|e| (StatusCode::BAD_REQUEST, e))?,
+        )),
+    };
+    let wake = match input.wake {
+        None => None,
+        Some(wake) if wake.mac.trim().is_empty() => Some(None),
+        Some(wake) => {
+            wol::validate(&wake).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+            Some(Some(wake))
+        }
+    };
+    let addr = match (input.addr, &hostname) {
+        (Some(addr), _) => addr,
+        (None, Some(Some(hostname))) => ddns::lookup(hostname)
+            .await
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("{:#}", e)))?,
+        (None, _) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Either addr or hostname is required".into(),
+            ));
+        }
+    };
 
-    Ok(StatusCode::OK)
+    let mut guard = state.write().await;
+    let saved = guard.contacts.upsert(label, addr).and_then(|mut contact| {
+        if let Some(endpoints) = input.endpoints {
+            contact = guard
+                .contacts
+                .set_endpoints(&contact.label, endpoints)?
+                .unwrap_or(contact);
+        }
+        if let Some(hostname) = hostname {
+            contact = guard
+                .contacts
+                .set_hostname(&contact.label, hostname)?
+                .unwrap_or(contact);
+        }
+        if let Some(wake) = wake {
+            contact = guard
+                .contacts
+                .set_wake(&contact.label, wake)?
+                .unwrap_or(contact);
+        }
+        Ok(contact)
+    });
+    let contact = saved.map_err(|e| {
+        error!("Failed to save contact: {}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    })?;
+    Ok(Json(contact))
 }
 
 #[derive(Debug, Deserialize)]
-struct SendMessageRequest {
-    message: String,
+struct ObserverRequest {
+    name: String,
 }
 
-/// Handler for `POST /api/message`.
-async fn send_message(
+/// Handler for `GET /api/observers`.
+/// Lists read-only observers by name; their tokens are never shown again.
+async fn get_observers(State(state): State<SharedState>) -> impl IntoResponse {
+    let guard = state.read().await;
+    let observers: Vec<_> = guard
+        .observers
+        .all()
+        .iter()
+        .map(|o| json!({ "name": o.name, "created_at": o.created_at }))
+        .collect();
+    Json(observers)
+}
+
+/// Handler for `POST /api/observers`.
+/// Issues a token for a read-only observer, replacing any with the same name.
+///
+/// The token is only returned here.
+async fn add_observer(
     State(state): State<SharedState>,
-    Json(input): Json<SendMessageRequest>,
+    Json(input): Json<ObserverRequest>,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
-    if input.message.trim().is_empty() {
-        return Err((StatusCode::BAD_REQUEST, "Message cannot be empty".into()));
-    }
+    let name = validate_label(&input.name).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let token = state
+        .write()
+        .await
+        .observers
+        .add(name.clone())
+        .map_err(|e| {
+            error!("Failed to save observer: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        })?;
+    info!("Issued observer token for {}", name);
+    Ok(Json(json!({ "name": name, "token": token })))
+}
 
-    // Check if connected
-    if state.read().await.status != Status::Connected {
-        return Err((StatusCode::BAD_REQUEST, "Not connected to a peer".into()));
+/// Handler for `DELETE /api/observers/{name}`.
+/// Revokes an observer's token. Streams it already opened stay open until
+/// they disconnect.
+async fn delete_observer(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    match state.write().await.observers.remove(&name) {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err((StatusCode::NOT_FOUND, format!("No observer named {}", name))),
+        Err(e) => {
+            error!("Failed to delete observer: {}", e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        }
     }
+}
 
-    // Send command to controller
-    let cmd_tx = state.read().await.cmd_tx().clone();
-    if let Err(e) = cmd_tx.send(Command::SendMessage(input.message)).await {
-        error!("Failed to send Message command: {}", e);
-        return Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Internal Controller Error".to_string(),
-        ));
+/// Handler for `DELETE /api/contacts/{label}`.
+/// Removes a saved contact.
+async fn delete_contact(
+    State(state): State<SharedState>,
+    Path(label): Path<String>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let mut guard = state.write().await;
+    match guard.contacts.remove(&label) {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err((StatusCode::NOT_FOUND, format!("No contact named {}", label))),
+        Err(e) => {
+            error!("Failed to delete contact: {}", e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        }
     }
+}
 
-    Ok(StatusCode::OK)
+#[derive(Debug, Deserialize)]
+struct WakeRequest {
+    /// Dial the contact right after the wake packet is sent.
+    #[serde(default)]
+    connect: bool,
+    /// Seconds to wait for the session, as for `/api/connect`.
+    #[serde(default)]
+    wait_secs: Option<u64>,
 }
 
-/// Handler for `GET /api/events`.
-/// Establishes SSE stream for real-time state updates.
-async fn sse_handler(
+/// Handler for `POST /api/contacts/{label}/wake`.
+/// Sends a Wake-on-LAN packet to a contact's machine and, with `connect`, dials it.
+///
+/// A contact woken through a relay needs a session with that relay, which
+/// sends the packet on its network; `connect` is refused then, as the relay
+/// session has to end before the contact can be dialled.
+async fn wake_contact(
     State(state): State<SharedState>,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    debug!("New SSE client connected");
-
-    // Create a broadcast receiver from the state
-    let rx = state.read().await.subscribe_events();
-    let stream = BroadcastStream::new(rx);
+    Path(label): Path<String>,
+    Json(input): Json<WakeRequest>,
+) -> Result<Response, (StatusCode, String)> {
+    let guard = state.read().await;
+    let contact = guard
+        .contacts
+        .all()
+        .iter()
+        .find(|c| c.label == label)
+        .cloned()
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("No contact named {}", label)))?;
+    let wake = contact.wake.ok_or_else(|| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("No wake target saved for {}", label),
+        )
+    })?;
+    let relay_connected = guard.status == Status::Connected
+        && guard
+            .peer_ip
+            .and_then(|addr| guard.contacts.label_for(addr))
+            == wake.relay;
+    let relay_feature = guard.require_peer_feature(Feature::WakeRelay);
+    drop(guard);
 
-    // Map broadcast messages to SSE events
-    let stream = stream.map(|msg| match msg {
-        Ok(app_event) => {
-            // Serialize the event to JSON
-            let json_data = serde_json::to_string(&app_event).unwrap_or_else(|_| "{}".into());
-            Ok(Event::default().data(json_data))
+    match &wake.relay {
+        None => wol::send(&wake.mac, wake.broadcast).await.map_err(|e| {
+            error!("Failed to send wake packet: {:#}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))
+        })?,
+        Some(_) if input.connect => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Cannot connect while the relay session is open; wake first, then connect".into(),
+            ));
         }
-        Err(_lagged) => {
-            // Handle lagged receivers (slow clients) gracefully
-            Ok(Event::default().comment("keep-alive-sync"))
+        Some(relay) if !relay_connected => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Connect to {} to wake through it", relay),
+            ));
         }
-    });
+        Some(_) => {
+            relay_feature.map_err(|e| (StatusCode::CONFLICT, e))?;
+            let (reply_tx, reply_rx) = oneshot::channel();
+            send_command(
+                &state,
+                Command::RelayWake {
+                    mac: wake.mac.clone(),
+                    broadcast: wake.broadcast,
+                    reply: reply_tx,
+                },
+            )
+            .await?;
+            match tokio::time::timeout(SHARE_TIMEOUT, reply_rx).await {
+                Ok(Ok(Ok(()))) => {}
+                Ok(Ok(Err(e))) => return Err((StatusCode::BAD_GATEWAY, e)),
+                Ok(Err(_)) => {
+                    return Err((
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "Controller dropped the wake request".to_string(),
+                    ));
+                }
+                Err(_) => {
+                    return Err((
+                        StatusCode::GATEWAY_TIMEOUT,
+                        "Relay did not answer".to_string(),
+                    ));
+                }
+            }
+        }
+    }
+    info!("Sent wake packet for {}", label);
 
-    Sse::new(stream).keep_alive(
-        KeepAlive::new()
-            .interval(Duration::from_secs(5))
-            .text("keep-alive"),
+    if !input.connect {
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    }
+    connect_peer(
+        State(state),
+        StrictJson(ConnectionRequest {
+            ip: contact.addr.ip().to_string(),
+            port: contact.addr.port(),
+            mode: default_encryption_mode(),
+            label: Some(label),
+            wait_secs: input.wait_secs,
+            guest: false,
+        }),
     )
+    .await
+    .map_err(|e| (e.status, e.message))
 }
 
-#[cfg(test)]
-mod tests {
-    use super::super::shared_state::{AppEvent, AppState, NatType, Status};
-    use super::*;
-    use axum::{
-        body::Body,
-        http::{Request, StatusCode},
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConnectionRequest {
+    ip: String,
+    port: u16,
+    #[serde(default = "default_encryption_mode")]
+    mode: EncryptionMode,
+    /// Display label for the peer. Defaults to the saved contact, if any.
+    #[serde(default)]
+    label: Option<String>,
+    /// Seconds to wait for the session before answering. Answers at once if omitted.
+    #[serde(default)]
+    wait_secs: Option<u64>,
+    /// Keep the session out of the session and event logs and forget the peer
+    /// once it ends.
+    #[serde(default)]
+    guest: bool,
+}
+
+/// Longest `/api/connect` will hold a request open waiting for the session.
+const MAX_CONNECT_WAIT_SECS: u64 = 120;
+
+fn default_encryption_mode() -> EncryptionMode {
+    EncryptionMode::ChaCha20Poly1305
+}
+
+/// Handler for `GET /api/debug/handshake-log`.
+/// Returns the recorded STUN and handshake packets (oldest first).
+async fn get_handshake_log(State(state): State<SharedState>) -> impl IntoResponse {
+    let transcript = state.read().await.transcript.clone();
+    Json(json!({
+        "enabled": transcript.is_enabled(),
+        "entries": transcript.entries(),
+    }))
+}
+
+/// Handler for `GET /api/debug/last-run`.
+/// Returns the events and warnings recorded by the previous run and this one.
+async fn get_last_run(State(state): State<SharedState>) -> impl IntoResponse {
+    let event_log = state.read().await.event_log.clone();
+    Json(json!({
+        "last_run": event_log.last_run(),
+        "this_run": event_log.entries(),
+    }))
+}
+
+/// Handler for `POST /api/debug/bundle`.
+/// Returns a tar archive to attach to bug reports: version, configuration
+/// summary, diagnostics report, event logs of this and the last run,
+/// handshake log, session history and any crash report.
+///
+/// The configuration summary leaves out commands, paths, addresses and
+/// URLs, like the one in crash reports. Session history and the handshake
+/// log do include peer addresses.
+async fn export_bundle(State(state): State<SharedState>) -> Result<Response, (StatusCode, String)> {
+    let now = unix_timestamp();
+    let mut bundle = Bundle::new(now);
+    let data = state.read().await;
+    let event_log = data.event_log.clone();
+    let sessions: Vec<_> = data.session_log.records().cloned().collect();
+    let crash = data
+        .crash_report_path
+        .as_deref()
+        .and_then(crash_report::load);
+
+    let added = (|| -> Result<()> {
+        let mut version = serde_json::to_value(build_info())?;
+        version["generated_at"] = json!(now);
+        bundle.add_json("version.json", &version)?;
+        bundle.add_json("config.json", &data.config_summary)?;
+        bundle.add_json("diagnostics.json", &diagnostics_report(&data))?;
+        bundle.add_json(
+            "event_log.json",
+            &json!({
+                "last_run": event_log.last_run(),
+                "this_run": event_log.entries(),
+            }),
+        )?;
+        bundle.add_json("handshake_log.json", &data.transcript.entries())?;
+        bundle.add_json(
+            "sessions.json",
+            &json!({
+                "current": data.session_log.current(),
+                "sessions": sessions,
+            }),
+        )?;
+        if let Some(crash) = &crash {
+            bundle.add_json("crash_report.json", crash)?;
+        }
+        Ok(())
+    })();
+    drop(data);
+    added.map_err(|e| {
+        error!("Failed to build diagnostics bundle: {:#}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    })?;
+
+    let disposition = format!("attachment; filename=\"ghostlink-diagnostics-{}.tar\"", now);
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-tar".to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        bundle.finish(),
+    )
+        .into_response())
+}
+
+/// Entry count and approximate size of an in-memory store.
+fn store_usage<T: Serialize>(items: &[T]) -> serde_json::Value {
+    let approx_bytes = serde_json::to_vec(items).map_or(0, |bytes| bytes.len());
+    json!({ "entries": items.len(), "approx_bytes": approx_bytes })
+}
+
+/// Handler for `GET /api/debug/runtime`.
+/// Returns tokio task counts, queue depths and the approximate size of
+/// in-memory stores, to diagnose slow UIs and leaks on long-running nodes.
+///
+/// Sizes are measured as serialized JSON, which tracks growth rather than
+/// exact heap usage.
+async fn get_runtime(State(state): State<SharedState>) -> impl IntoResponse {
+    let metrics = tokio::runtime::Handle::current().metrics();
+    let data = state.read().await;
+
+    let sessions: Vec<_> = data.session_log.records().collect();
+    let operations: Vec<_> = data.operations.all().collect();
+    let state_bytes = serde_json::to_vec(&*data).map_or(0, |bytes| bytes.len());
+
+    Json(json!({
+        "tasks": {
+            "workers": metrics.num_workers(),
+            "alive": metrics.num_alive_tasks(),
+            "global_queue_depth": metrics.global_queue_depth(),
+        },
+        "queues": {
+            "commands": data.command_queue_stats(),
+            "events": data.event_queue_stats(),
+            "outbox": data.outbox_depth(),
+        },
+        "stores": {
+            "event_log": store_usage(&data.event_log.entries()),
+            "handshake_log": store_usage(&data.transcript.entries()),
+            "sessions": store_usage(&sessions),
+            "operations": store_usage(&operations),
+            "contacts": store_usage(data.contacts.all()),
+            "incoming_requests": store_usage(&data.incoming_requests),
+            "state": { "approx_bytes": state_bytes },
+        },
+    }))
+}
+
+/// Handler for `GET /api/crash-report`.
+/// Returns the report left by a crashed earlier run.
+async fn get_crash_report(
+    State(state): State<SharedState>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let path = state.read().await.crash_report_path.clone();
+    match path.as_deref().and_then(crash_report::load) {
+        Some(report) => Ok(Json(report)),
+        None => Err((StatusCode::NOT_FOUND, "No crash report".to_string())),
+    }
+}
+
+/// Handler for `DELETE /api/crash-report`.
+/// Deletes the crash report and stops announcing it.
+async fn dismiss_crash_report(
+    State(state): State<SharedState>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let mut guard = state.write().await;
+    if guard.crash_report.take().is_none() {
+        return Err((StatusCode::NOT_FOUND, "No crash report".to_string()));
+    }
+    if let Some(path) = &guard.crash_report_path
+        && let Err(e) = std::fs::remove_file(path)
+    {
+        error!("Failed to delete crash report: {}", e);
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct CaptureRequest {
+    /// Zero out packet payloads in the capture file.
+    #[serde(default)]
+    redact: bool,
+}
+
+/// Handler for `POST /api/debug/capture/start`.
+/// Starts writing GhostLink's STUN and handshake packets to a pcap file.
+async fn start_capture(
+    State(state): State<SharedState>,
+    input: Option<Json<CaptureRequest>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let Json(input) = input.unwrap_or_default();
+    let transcript = state.read().await.transcript.clone();
+
+    match transcript.start_capture(input.redact) {
+        Ok(path) => Ok(Json(json!({ "path": path }))),
+        Err(e) => Err((StatusCode::CONFLICT, e.to_string())),
+    }
+}
+
+/// Handler for `POST /api/debug/capture/stop`.
+/// Stops the running capture and reports the file and packet count.
+async fn stop_capture(
+    State(state): State<SharedState>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let transcript = state.read().await.transcript.clone();
+
+    match transcript.stop_capture() {
+        Some(summary) => Ok(Json(summary)),
+        None => Err((StatusCode::CONFLICT, "No capture is running".to_string())),
+    }
+}
+
+/// Handler for `GET /api/debug/netem`.
+/// Returns the impairments applied by in-process netem links.
+#[cfg(feature = "netem")]
+async fn get_netem(State(state): State<SharedState>) -> impl IntoResponse {
+    let netem = state.read().await.netem.clone();
+    let config = *netem.read().await;
+    Json(config)
+}
+
+/// Handler for `POST /api/debug/netem`.
+/// Replaces the impairments; takes effect on the next relayed datagram.
+#[cfg(feature = "netem")]
+async fn set_netem(
+    State(state): State<SharedState>,
+    Json(input): Json<crate::netem::NetemConfig>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    if !(0.0..=1.0).contains(&input.loss) || !(0.0..=1.0).contains(&input.reorder) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "loss and reorder must be between 0.0 and 1.0".to_string(),
+        ));
+    }
+
+    let netem = state.read().await.netem.clone();
+    *netem.write().await = input;
+    Ok(StatusCode::OK)
+}
+
+#[derive(Debug, Deserialize)]
+struct SelfTestRequest {
+    /// Encryption mode to exercise.
+    #[serde(default = "default_encryption_mode")]
+    mode: EncryptionMode,
+}
+
+/// Resolves a requested peer address to a UDP target.
+///
+/// # Errors
+///
+/// Returns 400 for an invalid address.
+fn udp_target(ip: &str, port: u16) -> Result<SocketAddr, (StatusCode, String)> {
+    let ip_v4 = Ipv4Addr::from_str(ip).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid IP address: {}", e),
+        )
+    })?;
+    Ok(SocketAddr::new(IpAddr::V4(ip_v4), port))
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeRequest {
+    ip: String,
+    port: u16,
+}
+
+/// Handler for `POST /api/probe`.
+/// Sends a few probe packets to a candidate peer and reports whether a
+/// node answered, the host refused, or nothing came back. Does not start a
+/// connection or touch the live one.
+async fn probe_peer(
+    State(state): State<SharedState>,
+    Json(input): Json<ProbeRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let target = udp_target(&input.ip, input.port)?;
+
+    let (operation, transcript) = {
+        let mut guard = state.write().await;
+        (
+            guard.operations.start(OperationKind::Probe),
+            guard.transcript.clone(),
+        )
     };
-    use serde_json::{Value, json};
-    use std::sync::Arc;
-    use tokio::sync::{RwLock, broadcast, mpsc};
-    use tower::ServiceExt;
+    let outcome = reachability::probe(target, &transcript)
+        .await
+        .map_err(|e| format!("{:#}", e));
+    finish_operation(&state, operation, &outcome).await;
+    match outcome {
+        Ok(report) => Ok((operation_header(operation), Json(report))),
+        Err(e) => {
+            error!("Probe of {} failed: {}", target, e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, e))
+        }
+    }
+}
 
-    /// Helper to create a fresh state for each test.
-    /// This mimics the real application startup.
-    fn create_test_state() -> SharedState {
-        let (cmd_tx, mut cmd_rx) = mpsc::channel::<Command>(32);
-        let (event_tx, _) = broadcast::channel::<AppEvent>(32);
+/// Handler for `POST /api/selftest`.
+/// Runs a full session against an internal loopback endpoint and reports
+/// which stage failed, if any. Does not touch the live connection.
+async fn run_selftest(
+    State(state): State<SharedState>,
+    input: Option<Json<SelfTestRequest>>,
+) -> impl IntoResponse {
+    let mode = input.map_or_else(default_encryption_mode, |Json(input)| input.mode);
+    let operation = state
+        .write()
+        .await
+        .operations
+        .start(OperationKind::SelfTest);
+    let report = selftest::run(mode).await;
+    // The run completed; whether every stage passed is part of the report
+    finish_operation(&state, operation, &Ok::<_, String>(&report)).await;
+    (operation_header(operation), Json(report))
+}
 
-        // Drain the command channel to prevent it from filling up during tests
-        tokio::spawn(async move { while cmd_rx.recv().await.is_some() {} });
+#[derive(Debug, Deserialize)]
+struct EchoBotRequest {
+    enabled: bool,
+}
+
+/// Handler for `POST /api/echo-bot`.
+/// Starts or stops the built-in echo peer, and returns its loopback address
+/// while it runs. Connecting to the bot goes through `/api/connect` like
+/// any other peer.
+async fn set_echo_bot(
+    State(state): State<SharedState>,
+    Json(input): Json<EchoBotRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let mut guard = state.write().await;
+    if input.enabled && guard.echo_bot.is_none() {
+        let bot = EchoBot::start().await.map_err(|e| {
+            error!("Failed to start the echo bot: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to start the echo bot: {}", e),
+            )
+        })?;
+        guard.echo_bot = Some(bot);
+    } else if !input.enabled
+        && let Some(bot) = guard.echo_bot.take()
+    {
+        bot.stop();
+    }
+    Ok(Json(json!({ "echo_bot": guard.echo_bot })))
+}
+
+/// Header naming the operation that tracks a request.
+const OPERATION_ID_HEADER: &str = "x-operation-id";
+
+fn operation_header(id: u64) -> [(HeaderName, String); 1] {
+    [(HeaderName::from_static(OPERATION_ID_HEADER), id.to_string())]
+}
+
+/// Records the outcome of an operation.
+async fn finish_operation<T: Serialize>(state: &SharedState, id: u64, outcome: &Result<T, String>) {
+    let outcome = match outcome {
+        Ok(result) => serde_json::to_value(result).map_err(|e| e.to_string()),
+        Err(e) => Err(e.clone()),
+    };
+    state.write().await.operations.finish(id, outcome);
+}
+
+/// Handler for `GET /api/operations`.
+/// Returns recent operations, newest first.
+async fn get_operations(State(state): State<SharedState>) -> impl IntoResponse {
+    let data = state.read().await;
+    let operations: Vec<_> = data.operations.all().rev().cloned().collect();
+    Json(json!({ "operations": operations }))
+}
+
+/// Handler for `GET /api/operations/{id}`.
+/// Returns the status, progress and result of one operation.
+async fn get_operation(
+    State(state): State<SharedState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    state
+        .read()
+        .await
+        .operations
+        .get(id)
+        .cloned()
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, format!("No operation {}", id)))
+}
+
+/// Handler for `POST /api/connect`.
+/// Validates peer IP and triggers connection process.
+///
+/// With `wait_secs`, answers with the session details once connected, 502 if
+/// the attempt failed, or 202 if it is still running when the wait ends.
+/// Errors are JSON and name the request field at fault, if any.
+async fn connect_peer(
+    State(state): State<SharedState>,
+    StrictJson(input): StrictJson<ConnectionRequest>,
+) -> Result<Response, ApiError> {
+    debug!(
+        "Received connection request: {}:{} (Mode: {:?})",
+        input.ip, input.port, input.mode
+    );
+
+    // 1. Validate Input Address
+    if input.port == 0 {
+        return Err(ApiError::invalid(
+            "port",
+            "port must be between 1 and 65535",
+        ));
+    }
+    let peer_addr = udp_target(&input.ip, input.port).map_err(|(status, message)| ApiError {
+        status,
+        field: Some("ip".into()),
+        message,
+    })?;
+
+    let label = input
+        .label
+        .as_deref()
+        .map(validate_label)
+        .transpose()
+        .map_err(|e| ApiError::invalid("label", e))?;
+
+    if input.wait_secs.is_some_and(|w| w > MAX_CONNECT_WAIT_SECS) {
+        return Err(ApiError::invalid(
+            "wait_secs",
+            format!("wait_secs must be at most {}", MAX_CONNECT_WAIT_SECS),
+        ));
+    }
+
+    // 2. Validate State & Update
+    {
+        let mut guard = state.write().await;
+        let is_echo_bot = guard
+            .echo_bot
+            .as_ref()
+            .is_some_and(|bot| bot.addr == peer_addr);
+        if peer_addr.ip().is_loopback() && !guard.allow_loopback_peers && !is_echo_bot {
+            return Err(ApiError::invalid(
+                "ip",
+                "Loopback peers are refused unless allow_loopback_peers is set",
+            ));
+        }
+        if guard.status != Status::Disconnected {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Cannot connect: Node is already busy (connected or punching).".to_string(),
+            )
+                .into());
+        }
+
+        // Set the peer IP
+        guard.guest = input.guest;
+        guard.set_peer_ip(peer_addr, label, Some(StatusMessage::TargetSet), None);
+    }
+
+    // 3. Send command to controller
+    // Controller reads peer_addr from SharedState
+    let (reply_tx, mut reply_rx) = oneshot::channel();
+    send_command(
+        &state,
+        Command::ConnectPeer {
+            reply: Some(reply_tx),
+        },
+    )
+    .await?;
+    let operation = state.write().await.operations.start(OperationKind::Connect);
+
+    if let Some(wait_secs) = input.wait_secs
+        && let Ok(reply) = tokio::time::timeout(Duration::from_secs(wait_secs), &mut reply_rx).await
+    {
+        let outcome =
+            reply.unwrap_or_else(|_| Err("Controller dropped the connect request".into()));
+        finish_operation(&state, operation, &outcome).await;
+        return match outcome {
+            Ok(outcome) => Ok((operation_header(operation), Json(outcome)).into_response()),
+            Err(e) => Err((StatusCode::BAD_GATEWAY, e).into()),
+        };
+    }
+
+    // Still punching; record the outcome in the operation whenever it arrives
+    let tracker = state.clone();
+    tokio::spawn(async move {
+        let outcome = reply_rx
+            .await
+            .unwrap_or_else(|_| Err("Controller dropped the connect request".into()));
+        finish_operation(&tracker, operation, &outcome).await;
+    });
+
+    let status = if input.wait_secs.is_some() {
+        StatusCode::ACCEPTED
+    } else {
+        StatusCode::OK
+    };
+    Ok((
+        status,
+        operation_header(operation),
+        Json(json!({
+            "operation_id": operation,
+            "status": Status::Punching,
+            "peer": peer_addr,
+        })),
+    )
+        .into_response())
+}
+
+#[derive(Debug, Deserialize)]
+struct IncomingDecision {
+    /// Address of the requesting peer, as listed in `incoming_requests`.
+    addr: SocketAddr,
+}
+
+/// Handler for `POST /api/incoming/accept`.
+/// Connects to a peer that asked to connect; other pending requests are rejected.
+async fn accept_incoming(
+    State(state): State<SharedState>,
+    Json(input): Json<IncomingDecision>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    {
+        let guard = state.read().await;
+        if guard.status != Status::Disconnected {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Cannot accept: Node is already busy (connected or punching).".to_string(),
+            ));
+        }
+    }
+    send_incoming_decision(&state, input.addr, Command::AcceptIncoming(input.addr)).await
+}
+
+/// Handler for `POST /api/incoming/reject`.
+/// Turns away a peer that asked to connect.
+async fn reject_incoming(
+    State(state): State<SharedState>,
+    Json(input): Json<IncomingDecision>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    send_incoming_decision(&state, input.addr, Command::RejectIncoming(input.addr)).await
+}
+
+/// Forwards an accept/reject decision if `addr` has a pending request.
+async fn send_incoming_decision(
+    state: &SharedState,
+    addr: SocketAddr,
+    command: Command,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if !state
+        .read()
+        .await
+        .incoming_requests
+        .iter()
+        .any(|r| r.addr == addr)
+    {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("No pending connection request from {}", addr),
+        ));
+    }
+
+    send_command(state, command).await?;
+    Ok(StatusCode::OK)
+}
+
+/// Handler for `POST /api/disconnect`.
+/// Triggers graceful disconnection from the current peer.
+async fn disconnect_peer(
+    State(state): State<SharedState>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    debug!("Received disconnect request");
+
+    // Check if connected or punching
+    let status = state.read().await.status;
+    if status == Status::Disconnected {
+        return Err((StatusCode::BAD_REQUEST, "Already disconnected".to_string()));
+    }
+
+    // Send command to controller
+    send_command(&state, Command::Disconnect).await?;
+
+    Ok(StatusCode::OK)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SendMessageRequest {
+    message: String,
+    /// Sends text too long for one message as several instead of refusing it.
+    #[serde(default)]
+    split: bool,
+}
+
+/// Handler for `POST /api/message`.
+///
+/// Text longer than one message can carry is refused with 413, naming the
+/// limit, unless `split` is set. Returns the number of messages queued.
+/// Errors are JSON and name the request field at fault, if any.
+async fn send_message(
+    State(state): State<SharedState>,
+    StrictJson(input): StrictJson<SendMessageRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    if input.message.trim().is_empty() {
+        return Err(ApiError::invalid("message", "Message cannot be empty"));
+    }
+
+    // Chat commands report their own errors, and /stats works offline
+    let ChatInput::Text(text) = chat_command::parse(&input.message) else {
+        send_command(&state, Command::SendMessage(input.message)).await?;
+        return Ok(Json(json!({ "messages": 1 })));
+    };
+
+    let max_len = {
+        let data = state.read().await;
+        if data.status != Status::Connected {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Not connected to a peer".to_string(),
+            )
+                .into());
+        }
+        data.max_text_len
+    };
+    if text.len() <= max_len {
+        send_command(&state, Command::SendMessage(input.message)).await?;
+        return Ok(Json(json!({ "messages": 1 })));
+    }
+    if !input.split {
+        return Err(ApiError {
+            status: StatusCode::PAYLOAD_TOO_LARGE,
+            field: Some("message".into()),
+            message: format!(
+                "Message is {} bytes; the limit is {} bytes. Set split to send it as several messages",
+                text.len(),
+                max_len
+            ),
+        });
+    }
+
+    // Pieces are escaped like typed text, so one starting with a slash is not run
+    // as a command; one byte is kept free for that
+    let pieces = text_limit::split(&text, max_len - 1);
+    for piece in &pieces {
+        let message = if piece.starts_with('/') {
+            format!("/{}", piece)
+        } else {
+            piece.to_string()
+        };
+        send_command(&state, Command::SendMessage(message)).await?;
+    }
+
+    Ok(Json(json!({ "messages": pieces.len() })))
+}
+
+#[derive(Debug, Deserialize)]
+struct MessageTtlRequest {
+    /// Seconds until messages disappear; `null` keeps them.
+    ttl_secs: Option<u32>,
+}
+
+/// Handler for `POST /api/message-ttl`.
+/// Sets how long messages of the open conversation are kept, on both sides.
+async fn set_message_ttl(
+    State(state): State<SharedState>,
+    Json(input): Json<MessageTtlRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let ttl_secs = input
+        .ttl_secs
+        .map(validate_ttl)
+        .transpose()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+    if state.read().await.status != Status::Connected {
+        return Err((StatusCode::BAD_REQUEST, "Not connected to a peer".into()));
+    }
+
+    send_command(&state, Command::SetMessageTtl(ttl_secs)).await?;
+    Ok(StatusCode::OK)
+}
+
+#[derive(Debug, Deserialize)]
+struct ScheduleRequest {
+    message: String,
+    /// Unix timestamp (seconds) to send at. Must be in the future.
+    send_at: u64,
+    /// Saved contact to send to. Defaults to the connected peer.
+    #[serde(default)]
+    contact: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EditScheduledRequest {
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(default)]
+    send_at: Option<u64>,
+}
+
+/// Rejects empty scheduled messages and send times that have passed.
+fn validate_schedule(
+    message: Option<&str>,
+    send_at: Option<u64>,
+) -> Result<(), (StatusCode, String)> {
+    if message.is_some_and(|m| m.trim().is_empty()) {
+        return Err((StatusCode::BAD_REQUEST, "Message cannot be empty".into()));
+    }
+    if send_at.is_some_and(|at| at <= unix_timestamp()) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Send time must be in the future".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Handler for `GET /api/scheduled`.
+/// Returns the messages waiting for their send time, soonest first.
+async fn get_scheduled(State(state): State<SharedState>) -> impl IntoResponse {
+    Json(state.read().await.scheduled.all().to_vec())
+}
+
+/// Handler for `POST /api/scheduled`.
+/// Schedules a message for the connected peer or a saved contact. It is sent
+/// at its time if that peer is connected, or in the next session with it.
+async fn schedule_message(
+    State(state): State<SharedState>,
+    Json(input): Json<ScheduleRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    validate_schedule(Some(&input.message), Some(input.send_at))?;
+
+    let mut guard = state.write().await;
+    let peer = match &input.contact {
+        Some(label) => guard
+            .contacts
+            .all()
+            .iter()
+            .find(|c| &c.label == label)
+            .map(|c| c.addr)
+            .ok_or((StatusCode::NOT_FOUND, format!("No contact named {}", label)))?,
+        None => guard.peer_ip.ok_or((
+            StatusCode::BAD_REQUEST,
+            "Not connected to a peer; name a contact".to_string(),
+        ))?,
+    };
+    let message = guard
+        .scheduled
+        .add(peer, input.message, input.send_at)
+        .map_err(|e| (StatusCode::CONFLICT, e.to_string()))?;
+    guard.announce_scheduled();
+    Ok(Json(message))
+}
+
+/// Handler for `PUT /api/scheduled/{id}`.
+/// Changes the text or send time of a pending message.
+async fn edit_scheduled(
+    State(state): State<SharedState>,
+    Path(id): Path<u64>,
+    Json(input): Json<EditScheduledRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    validate_schedule(input.message.as_deref(), input.send_at)?;
+
+    let mut guard = state.write().await;
+    match guard.scheduled.edit(id, input.message, input.send_at) {
+        Ok(Some(message)) => {
+            guard.announce_scheduled();
+            Ok(Json(message))
+        }
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            format!("No scheduled message {}", id),
+        )),
+        Err(e) => {
+            error!("Failed to save scheduled messages: {}", e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        }
+    }
+}
+
+/// Handler for `DELETE /api/scheduled/{id}`.
+/// Cancels a pending message.
+async fn cancel_scheduled(
+    State(state): State<SharedState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let mut guard = state.write().await;
+    match guard.scheduled.remove(id) {
+        Ok(true) => {
+            guard.announce_scheduled();
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(false) => Err((
+            StatusCode::NOT_FOUND,
+            format!("No scheduled message {}", id),
+        )),
+        Err(e) => {
+            error!("Failed to save scheduled messages: {}", e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ReactionRequest {
+    message_id: MessageId,
+    emoji: String,
+    #[serde(default = "default_reaction_add")]
+    add: bool,
+}
+
+fn default_reaction_add() -> bool {
+    true
+}
+
+/// Handler for `POST /api/reactions`.
+/// Adds or removes a reaction on a message in the current conversation.
+async fn react_to_message(
+    State(state): State<SharedState>,
+    Json(input): Json<ReactionRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let emoji = validate_emoji(&input.emoji).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+    {
+        let guard = state.read().await;
+        if guard.status != Status::Connected {
+            return Err((StatusCode::BAD_REQUEST, "Not connected to a peer".into()));
+        }
+        if !guard.has_message(input.message_id) {
+            return Err((StatusCode::NOT_FOUND, "No such message".into()));
+        }
+    }
+
+    send_command(
+        &state,
+        Command::React {
+            message_id: input.message_id,
+            emoji,
+            add: input.add,
+        },
+    )
+    .await?;
+
+    Ok(StatusCode::OK)
+}
+
+#[derive(Debug, Deserialize)]
+struct AssistRequest {
+    name: String,
+}
+
+/// Handler for `POST /api/assist`.
+/// Asks the peer to run one of the commands it granted us; the result arrives as an SSE event.
+async fn request_assist(
+    State(state): State<SharedState>,
+    Json(input): Json<AssistRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let name = input.name.trim().to_string();
+    if name.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Command name cannot be empty".into(),
+        ));
+    }
+
+    let guard = state.read().await;
+    if guard.status != Status::Connected {
+        return Err((StatusCode::BAD_REQUEST, "Not connected to a peer".into()));
+    }
+    guard
+        .require_peer_feature(Feature::Assist)
+        .map_err(|e| (StatusCode::CONFLICT, e))?;
+    drop(guard);
+
+    send_command(&state, Command::AssistRun { name }).await?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Maximum time to wait for the peer to answer a share request.
+const SHARE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Deserialize)]
+struct ShareListQuery {
+    #[serde(default)]
+    share: String,
+    #[serde(default)]
+    path: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ShareReadQuery {
+    share: String,
+    path: String,
+    #[serde(default)]
+    offset: u64,
+    #[serde(default = "default_share_read_len")]
+    len: u32,
+}
+
+fn default_share_read_len() -> u32 {
+    MAX_READ_LEN
+}
+
+/// Sends a request to the peer's shared folders and waits for the answer.
+async fn query_peer_share(
+    state: &SharedState,
+    request: ShareRequest,
+) -> Result<ShareResponse, (StatusCode, String)> {
+    let guard = state.read().await;
+    if guard.status != Status::Connected {
+        return Err((StatusCode::BAD_REQUEST, "Not connected to a peer".into()));
+    }
+    guard
+        .require_peer_feature(Feature::Shares)
+        .map_err(|e| (StatusCode::CONFLICT, e))?;
+    drop(guard);
+
+    let (reply_tx, reply_rx) = oneshot::channel();
+    send_command(
+        state,
+        Command::ShareQuery {
+            request,
+            reply: reply_tx,
+        },
+    )
+    .await?;
+
+    match tokio::time::timeout(SHARE_TIMEOUT, reply_rx).await {
+        Ok(Ok(Ok(response))) => Ok(response),
+        Ok(Ok(Err(e))) => Err((StatusCode::NOT_FOUND, e)),
+        Ok(Err(_)) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Controller dropped the share request".to_string(),
+        )),
+        Err(_) => Err((
+            StatusCode::GATEWAY_TIMEOUT,
+            "Peer did not answer".to_string(),
+        )),
+    }
+}
+
+/// Handler for `GET /api/share`.
+/// Lists a directory in one of the peer's shared folders; no `share` lists the shares.
+async fn list_peer_share(
+    State(state): State<SharedState>,
+    Query(query): Query<ShareListQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let request = ShareRequest::List {
+        share: query.share,
+        path: query.path,
+    };
+    match query_peer_share(&state, request).await? {
+        ShareResponse::Listing { entries, truncated } => Ok(Json(json!({
+            "entries": entries,
+            "truncated": truncated,
+        }))),
+        ShareResponse::Data { .. } => Err((
+            StatusCode::BAD_GATEWAY,
+            "Unexpected reply from peer".to_string(),
+        )),
+    }
+}
+
+/// Handler for `GET /api/share/read`.
+/// Reads a byte range of a file in one of the peer's shared folders.
+async fn read_peer_share(
+    State(state): State<SharedState>,
+    Query(query): Query<ShareReadQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    if query.len == 0 || query.len > MAX_READ_LEN {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("len must be between 1 and {}", MAX_READ_LEN),
+        ));
+    }
+
+    let request = ShareRequest::Read {
+        share: query.share,
+        path: query.path,
+        offset: query.offset,
+        len: query.len,
+    };
+    match query_peer_share(&state, request).await? {
+        ShareResponse::Data {
+            bytes, total_size, ..
+        } => Ok((
+            [
+                (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+                (
+                    header::HeaderName::from_static("x-total-size"),
+                    total_size.to_string(),
+                ),
+            ],
+            bytes,
+        )),
+        ShareResponse::Listing { .. } => Err((
+            StatusCode::BAD_GATEWAY,
+            "Unexpected reply from peer".to_string(),
+        )),
+    }
+}
+
+/// Handler for `POST /api/remote-wipe`.
+/// Asks the connected peer, one of our own nodes, to delete its history.
+///
+/// The peer only complies if our saved contact label there is in its
+/// `wipe_contacts` and we proved the shared `own_node_secret` in this
+/// session; a refusal or failure is answered with 502 and its reason.
+async fn remote_wipe(
+    State(state): State<SharedState>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let guard = state.read().await;
+    if guard.status != Status::Connected {
+        return Err((StatusCode::BAD_REQUEST, "Not connected to a peer".into()));
+    }
+    guard
+        .require_peer_feature(Feature::RemoteWipe)
+        .map_err(|e| (StatusCode::CONFLICT, e))?;
+    drop(guard);
+
+    let (reply_tx, reply_rx) = oneshot::channel();
+    send_command(&state, Command::RemoteWipe { reply: reply_tx }).await?;
+
+    match tokio::time::timeout(SHARE_TIMEOUT, reply_rx).await {
+        Ok(Ok(Ok(report))) => Ok(Json(report)),
+        Ok(Ok(Err(e))) => Err((StatusCode::BAD_GATEWAY, e)),
+        Ok(Err(_)) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Controller dropped the wipe request".to_string(),
+        )),
+        Err(_) => Err((
+            StatusCode::GATEWAY_TIMEOUT,
+            "Peer did not answer".to_string(),
+        )),
+    }
+}
+
+/// Handler for `POST /api/broadcast`.
+/// Sends a message to every active session and returns per-peer delivery results.
+async fn broadcast_message(
+    State(state): State<SharedState>,
+    StrictJson(input): StrictJson<SendMessageRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    if input.message.trim().is_empty() {
+        return Err(ApiError::invalid("message", "Message cannot be empty"));
+    }
+
+    let (reply_tx, reply_rx) = oneshot::channel();
+    send_command(
+        &state,
+        Command::Broadcast {
+            text: input.message,
+            reply: reply_tx,
+        },
+    )
+    .await?;
+
+    match reply_rx.await {
+        Ok(report) => Ok(Json(report)),
+        Err(_) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Controller dropped the broadcast request".to_string(),
+        )
+            .into()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PingRequest {
+    #[serde(default = "default_ping_count")]
+    count: u32,
+}
+
+fn default_ping_count() -> u32 {
+    DEFAULT_PING_COUNT
+}
+
+/// Handler for `POST /api/ping`.
+/// Sends application-level pings over the session and returns RTT statistics.
+async fn ping_peer(
+    State(state): State<SharedState>,
+    input: Option<Json<PingRequest>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let count = input.map_or_else(default_ping_count, |Json(input)| input.count);
+    if count == 0 || count > MAX_PING_COUNT {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("count must be between 1 and {}", MAX_PING_COUNT),
+        ));
+    }
+
+    if state.read().await.status != Status::Connected {
+        return Err((StatusCode::BAD_REQUEST, "Not connected to a peer".into()));
+    }
+
+    let operation = state.write().await.operations.start(OperationKind::Ping);
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let sent = send_command(
+        &state,
+        Command::Ping {
+            count,
+            operation,
+            reply: reply_tx,
+        },
+    )
+    .await;
+    if let Err((status, e)) = sent {
+        finish_operation::<()>(&state, operation, &Err(e.clone())).await;
+        return Err((status, e));
+    }
+
+    let Ok(outcome) = reply_rx.await else {
+        let e = "Controller dropped the ping request".to_string();
+        finish_operation::<()>(&state, operation, &Err(e.clone())).await;
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, e));
+    };
+    finish_operation(&state, operation, &outcome).await;
+    match outcome {
+        Ok(stats) => Ok((operation_header(operation), Json(stats))),
+        Err(e) => Err((StatusCode::CONFLICT, e)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct EventsQuery {
+    /// Comma-separated event categories to receive, e.g. `status`. All by default.
+    categories: Option<String>,
+}
+
+/// Handler for `GET /api/events`.
+/// Establishes SSE stream for real-time state updates. `categories` limits
+/// it to some kinds of events (`status`, `messages`, `transfers`, `system`,
+/// `heartbeat`), e.g. for a status bar widget.
+async fn sse_handler(
+    State(state): State<SharedState>,
+    Query(query): Query<EventsQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    let categories = query
+        .categories
+        .as_deref()
+        .map(EventCategory::parse_list)
+        .transpose()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    debug!("New SSE client connected");
+
+    // Create a broadcast receiver from the state
+    let rx = state.read().await.subscribe_events();
+    let stream = BroadcastStream::new(rx).filter(move |msg| match (msg, &categories) {
+        (Ok(app_event), Some(categories)) => categories.contains(&app_event.category()),
+        _ => true,
+    });
+
+    // Map broadcast messages to SSE events
+    let stream = stream.map(|msg| match msg {
+        Ok(app_event) => {
+            // Serialize the event to JSON
+            let json_data = serde_json::to_string(&app_event).unwrap_or_else(|_| "{}".into());
+            Ok(Event::default().data(json_data))
+        }
+        Err(_lagged) => {
+            // Handle lagged receivers (slow clients) gracefully
+            Ok(Event::default().comment("keep-alive-sync"))
+        }
+    });
+
+    Ok(Sse::new(stream).keep_alive(
+        KeepAlive::new()
+            .interval(Duration::from_secs(5))
+            .text("keep-alive"),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::shared_state::{AppEvent, AppState, NatType, Status};
+    use super::*;
+    use crate::{
+        audit::DisconnectReason,
+        config::Config,
+        crash_report::{ConfigSummary, CrashNotice, CrashReport},
+        history_export::{ExportSettings, HistoryExport},
+        messaging::{
+            broadcast::{BroadcastReport, Delivery},
+            connect::ConnectOutcome,
+            incoming::IncomingQueue,
+            ping::PingStats,
+        },
+        net::{StunError, StunProbe},
+        stats_history::LinkCounters,
+        storage::{TempDir, write_json},
+        traffic::TrafficClass,
+        transcript::{Direction, Protocol},
+        update::Release,
+    };
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use serde_json::{Value, json};
+    use std::{path::PathBuf, sync::Arc};
+    use tokio::{
+        sync::{RwLock, broadcast, mpsc},
+        time::{Duration, Instant},
+    };
+    use tower::ServiceExt;
+
+    /// Helper to create a fresh state for each test.
+    /// This mimics the real application startup.
+    fn create_test_state() -> SharedState {
+        let (cmd_tx, mut cmd_rx) = mpsc::channel::<Command>(32);
+        let (event_tx, _) = broadcast::channel::<AppEvent>(32);
+
+        // Drain the command channel to prevent it from filling up during tests
+        tokio::spawn(async move { while cmd_rx.recv().await.is_some() {} });
+
+        Arc::new(RwLock::new(AppState::new(cmd_tx, event_tx)))
+    }
+
+    /// Checks that `/api/state` returns the correct default JSON structure
+    /// when the application first boots (all nulls/defaults).
+    #[tokio::test]
+    async fn test_get_state_initial_structure() {
+        let state = create_test_state();
+        let app = router(state);
+
+        let request = Request::builder()
+            .uri("/api/state")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_json: Value = serde_json::from_slice(&body_bytes).unwrap();
+
+        // The API returns { "state": { ... } }
+        let state_obj = &body_json["state"];
+
+        // Verify defaults
+        assert_eq!(state_obj["public_ip"], Value::Null);
+        assert_eq!(state_obj["peer_ip"], Value::Null);
+        assert_eq!(state_obj["status"], "Disconnected");
+        assert_eq!(state_obj["nat_type"], "Unknown");
+    }
+
+    /// Manually modifies the `SharedState` and verifies that `/api/state`
+    /// reflects these changes (IPs, Status, NAT Type) in the JSON response.
+    #[tokio::test]
+    async fn test_get_state_reflects_updates() {
+        let state = create_test_state();
+
+        // 1. Manually update internal state
+        {
+            let mut guard = state.write().await;
+            guard.public_ip = Some("203.0.113.10:8080".parse().unwrap());
+            guard.peer_ip = Some("198.51.100.20:9000".parse().unwrap());
+            guard.status = Status::Punching;
+            guard.nat_type = NatType::Symmetric;
+        }
+
+        let app = router(state);
+
+        let request = Request::builder()
+            .uri("/api/state")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_json: Value = serde_json::from_slice(&body_bytes).unwrap();
+        let state_obj = &body_json["state"];
+
+        // 2. Verify JSON matches updates
+        assert_eq!(state_obj["public_ip"], "203.0.113.10:8080");
+        assert_eq!(state_obj["peer_ip"], "198.51.100.20:9000");
+        assert_eq!(state_obj["status"], "Punching");
+        assert_eq!(state_obj["nat_type"], "Symmetric");
+    }
+
+    #[tokio::test]
+    async fn test_connect_valid_payload() {
+        let state = create_test_state();
+        let app = router(state.clone());
+
+        // Updated: Added optional "mode" field (implicit test of default logic)
+        let payload = json!({ "ip": "192.168.1.50", "port": 9000 });
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/api/connect")
+            .header("content-type", "application/json")
+            .body(Body::from(payload.to_string()))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Verify state update
+        let peer_ip = state.read().await.peer_ip;
+        assert_eq!(peer_ip.unwrap().to_string(), "192.168.1.50:9000");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_connect_wait_returns_outcome() {
+        let (cmd_tx, mut cmd_rx) = mpsc::channel::<Command>(32);
+        let (event_tx, _) = broadcast::channel::<AppEvent>(32);
+        let state = Arc::new(RwLock::new(AppState::new(cmd_tx, event_tx)));
+
+        // Stub controller: port 9000 connects, 9001 fails, 9002 never finishes
+        let controller_state = state.clone();
+        tokio::spawn(async move {
+            let mut stalled = Vec::new();
+            while let Some(cmd) = cmd_rx.recv().await {
+                let Command::ConnectPeer { reply: Some(reply) } = cmd else {
+                    continue;
+                };
+                let peer = controller_state.read().await.peer_ip.unwrap();
+                match peer.port() {
+                    9000 => {
+                        let _ = reply.send(Ok(ConnectOutcome {
+                            peer,
+                            peer_label: None,
+                            fingerprint: Some("AB CD EF".into()),
+                            encryption_algo: Some("ChaCha20-Poly1305".into()),
+                        }));
+                    }
+                    9001 => {
+                        let _ = reply.send(Err("Handshake failed: timed out".into()));
+                    }
+                    _ => stalled.push(reply),
+                }
+            }
+        });
+
+        let connect = |port: u16, wait_secs: u64| {
+            let app = router(state.clone());
+            let payload = json!({ "ip": "203.0.113.9", "port": port, "wait_secs": wait_secs });
+            let request = Request::builder()
+                .method("POST")
+                .uri("/api/connect")
+                .header("content-type", "application/json")
+                .body(Body::from(payload.to_string()))
+                .unwrap();
+            app.oneshot(request)
+        };
+
+        let response = connect(9000, 5).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_json: Value = serde_json::from_slice(&body_bytes).unwrap();
+        assert_eq!(body_json["peer"], "203.0.113.9:9000");
+        assert_eq!(body_json["fingerprint"], "AB CD EF");
+
+        let response = connect(9001, 5).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+        let response = connect(9002, 1).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        let response = connect(9000, MAX_CONNECT_WAIT_SECS + 1).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_operations_track_connect() {
+        let (cmd_tx, mut cmd_rx) = mpsc::channel::<Command>(32);
+        let (event_tx, _) = broadcast::channel::<AppEvent>(32);
+        let state = Arc::new(RwLock::new(AppState::new(cmd_tx, event_tx)));
+
+        // Stub controller: the handshake finishes once the test releases it
+        let (release_tx, release_rx) = oneshot::channel::<()>();
+        tokio::spawn(async move {
+            if let Some(Command::ConnectPeer { reply: Some(reply) }) = cmd_rx.recv().await {
+                let _ = release_rx.await;
+                let _ = reply.send(Err("Handshake failed: timed out".into()));
+            }
+        });
+        let app = router(state);
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/api/connect")
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"ip": "203.0.113.9", "port": 9000}"#))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_json: Value = serde_json::from_slice(&body_bytes).unwrap();
+        let id = body_json["operation_id"].as_u64().unwrap();
+
+        let get_operation = |id: u64| {
+            let request = Request::builder()
+                .uri(format!("/api/operations/{}", id))
+                .body(Body::empty())
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                (status, serde_json::from_slice::<Value>(&body_bytes).ok())
+            }
+        };
+
+        let (status, operation) = get_operation(id).await;
+        assert_eq!(status, StatusCode::OK);
+        let operation = operation.unwrap();
+        assert_eq!(operation["kind"], "connect");
+        assert_eq!(operation["status"], "running");
+
+        release_tx.send(()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        loop {
+            let operation = get_operation(id).await.1.unwrap();
+            if operation["status"] != "running" {
+                assert_eq!(operation["status"], "failed");
+                assert_eq!(operation["error"], "Handshake failed: timed out");
+                break;
+            }
+            assert!(Instant::now() < deadline, "operation never finished");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(get_operation(id + 1).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_export_bundle() {
+        let state = create_test_state();
+        state.write().await.config_summary = Some(ConfigSummary::from(&Config::load()));
+        let request = Request::builder()
+            .method("POST")
+            .uri("/api/debug/bundle")
+            .body(Body::empty())
+            .unwrap();
+        let response = router(state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/x-tar"
+        );
+        assert!(
+            response.headers()[header::CONTENT_DISPOSITION]
+                .to_str()
+                .unwrap()
+                .starts_with("attachment; filename=\"ghostlink-diagnostics-")
+        );
+
+        let tar = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        // File names sit at the start of each 512-byte header
+        let text = String::from_utf8_lossy(&tar);
+        for name in [
+            "version.json",
+            "config.json",
+            "diagnostics.json",
+            "event_log.json",
+            "sessions.json",
+        ] {
+            assert!(text.contains(&format!("{}\0", name)), "{} missing", name);
+        }
+        assert!(!text.contains("crash_report.json"));
+    }
+
+    #[tokio::test]
+    async fn test_get_version() {
+        let state = create_test_state();
+        let request = || {
+            Request::builder()
+                .uri("/api/version")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = router(state.clone()).oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["build"]["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(body["build"]["git_hash"], env!("GHOSTLINK_GIT_HASH"));
+        assert_eq!(body["update_check"], false);
+        assert!(body["available_update"].is_null());
+
+        let mut events = state.read().await.subscribe_events();
+        let release = Release {
+            version: "99.0.0".into(),
+            security: true,
+            notes: "Fixes a crash".into(),
+            url: None,
+        };
+        state.write().await.set_available_update(release.clone());
+        // Seeing the same release again is not news
+        state.write().await.set_available_update(release);
+        assert!(matches!(
+            events.try_recv(),
+            Ok(AppEvent::UpdateAvailable { release }) if release.security
+        ));
+        assert!(events.try_recv().is_err());
+
+        let response = router(state).oneshot(request()).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["available_update"]["version"], "99.0.0");
+    }
+
+    /// Behind a reverse proxy, everything moves under the base path.
+    #[tokio::test]
+    async fn test_serves_under_base_path() {
+        let state = create_test_state();
+        let get = |uri: &str| {
+            app(state.clone(), "/ghostlink")
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        };
+
+        let response = get("/ghostlink").await.unwrap();
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers()[header::LOCATION], "/ghostlink/");
+        assert_eq!(get("/ghostlink/").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(
+            get("/ghostlink/api/version").await.unwrap().status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            get("/api/version").await.unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_capabilities() {
+        let state = create_test_state();
+        let mut config = Config::load();
+        config.link_previews = true;
+        config.wipe_contacts = vec!["Laptop".into()];
+        config.own_node_secret = Some("secret".into());
+        state.write().await.config_summary = Some(ConfigSummary::from(&config));
+
+        let request = Request::builder()
+            .uri("/api/capabilities")
+            .body(Body::empty())
+            .unwrap();
+        let response = router(state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["enabled"]["link_previews"], true);
+        assert_eq!(body["enabled"]["remote_wipe"], true);
+        assert_eq!(body["enabled"]["assist"], false);
+        assert_eq!(body["enabled"]["netem"], cfg!(feature = "netem"));
+        assert_eq!(
+            body["compiled"].as_array().unwrap().len(),
+            usize::from(cfg!(feature = "netem"))
+        );
+    }
+
+    /// Observer tokens only read state and events; without one, only local clients get in.
+    #[tokio::test]
+    async fn test_observers_are_read_only() {
+        let state = create_test_state();
+        let request = |method: &str, uri: &str, token: Option<&str>| {
+            let mut builder = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("content-type", "application/json");
+            if let Some(token) = token {
+                builder = builder.header("authorization", format!("Bearer {}", token));
+            }
+            builder
+                .body(Body::from(json!({ "name": "logger" }).to_string()))
+                .unwrap()
+        };
+
+        let remote = |mut request: Request<Body>| {
+            let addr = SocketAddr::from(([192, 168, 1, 20], 50000));
+            request
+                .extensions_mut()
+                .insert(ConnectInfo(Peer(Some(addr))));
+            request
+        };
+
+        // Nobody on the LAN may issue tokens, even before the first one
+        let response = router(state.clone())
+            .oneshot(remote(request("POST", "/api/observers", None)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = router(state.clone())
+            .oneshot(request("POST", "/api/observers", None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let issued: Value = serde_json::from_slice(&body).unwrap();
+        let token = issued["token"].as_str().unwrap().to_string();
+
+        let response = router(state.clone())
+            .oneshot(request("GET", "/api/state", Some(&token)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = router(state.clone())
+            .oneshot(request(
+                "GET",
+                &format!("/api/summary?observer_token={}", token),
+                None,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // From elsewhere on the LAN, only with the token
+        let response = router(state.clone())
+            .oneshot(remote(request("GET", "/api/state", None)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = router(state.clone())
+            .oneshot(remote(request("GET", "/api/state", Some(&token))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // No sending, and no issuing more tokens
+        for (method, uri) in [
+            ("POST", "/api/message"),
+            ("POST", "/api/observers"),
+            ("GET", "/api/config"),
+        ] {
+            let response = router(state.clone())
+                .oneshot(request(method, uri, Some(&token)))
+                .await
+                .unwrap();
+            assert_eq!(
+                response.status(),
+                StatusCode::FORBIDDEN,
+                "{} {}",
+                method,
+                uri
+            );
+        }
+        let response = router(state.clone())
+            .oneshot(request("GET", "/api/state", Some("guess")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = router(state.clone())
+            .oneshot(request("DELETE", "/api/observers/logger", None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = router(state.clone())
+            .oneshot(request("GET", "/api/state", Some(&token)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    /// A saved contact labels the peer on connect; an explicit label must be valid.
+    #[tokio::test]
+    async fn test_contacts_label_connect() {
+        let state = create_test_state();
+        let post = |uri: &str, payload: Value| {
+            Request::builder()
+                .method("POST")
+                .uri(uri)
+                .header("content-type", "application/json")
+                .body(Body::from(payload.to_string()))
+                .unwrap()
+        };
+
+        let response = router(state.clone())
+            .oneshot(post(
+                "/api/contacts",
+                json!({ "label": " Bob ", "addr": "192.168.1.50:9000" }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = router(state.clone())
+            .oneshot(post(
+                "/api/connect",
+                json!({ "ip": "192.168.1.50", "port": 9000, "label": "" }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = router(state.clone())
+            .oneshot(post(
+                "/api/connect",
+                json!({ "ip": "192.168.1.50", "port": 9000 }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(state.read().await.peer_label.as_deref(), Some("Bob"));
+
+        let response = router(state.clone())
+            .oneshot(post(
+                "/api/contacts",
+                json!({
+                    "label": "Bob",
+                    "addr": "192.168.1.50:9000",
+                    "endpoints": [{ "onion": { "host": "example.com", "port": 9000 } }],
+                }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = router(state.clone())
+            .oneshot(post(
+                "/api/contacts",
+                json!({
+                    "label": "Bob",
+                    "addr": "192.168.1.50:9000",
+                    "endpoints": [{ "udp": "203.0.113.7:9000" }, { "relay": "relay.example:7000" }],
+                }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let guard = state.read().await;
+        assert_eq!(guard.contacts.all()[0].endpoints.len(), 2);
+        assert_eq!(guard.contacts.all()[0].hostname, None);
+        assert_eq!(
+            guard
+                .contacts
+                .label_for("203.0.113.7:9000".parse().unwrap())
+                .as_deref(),
+            Some("Bob")
+        );
+        drop(guard);
+
+        // Saved by hostname alone, the address is looked up
+        let response = router(state.clone())
+            .oneshot(post(
+                "/api/contacts",
+                json!({ "label": "Carol", "hostname": "127.0.0.1:9100" }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            state
+                .read()
+                .await
+                .contacts
+                .hostname_for("127.0.0.1:9100".parse().unwrap()),
+            Some(("Carol".into(), "127.0.0.1:9100".into()))
+        );
+        let response = router(state.clone())
+            .oneshot(post("/api/contacts", json!({ "label": "Dave" })))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let request = Request::builder()
+            .method("DELETE")
+            .uri("/api/contacts/Bob")
+            .body(Body::empty())
+            .unwrap();
+        let response = router(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(state.read().await.contacts.all().len(), 1); // Carol
+    }
+
+    #[tokio::test]
+    async fn test_wake_contact() {
+        let state = create_test_state();
+        let post = |uri: &str, payload: Value| {
+            Request::builder()
+                .method("POST")
+                .uri(uri)
+                .header("content-type", "application/json")
+                .body(Body::from(payload.to_string()))
+                .unwrap()
+        };
+        let receiver = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let broadcast = receiver.local_addr().unwrap().to_string();
+
+        let response = router(state.clone())
+            .oneshot(post(
+                "/api/contacts",
+                json!({ "label": "Server", "addr": "192.168.1.50:9000" }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = router(state.clone())
+            .oneshot(post("/api/contacts/Server/wake", json!({})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Only local broadcast addresses are accepted
+        let response = router(state.clone())
+            .oneshot(post(
+                "/api/contacts",
+                json!({
+                    "label": "Server",
+                    "addr": "192.168.1.50:9000",
+                    "wake": { "mac": "aa:bb:cc:dd:ee:01", "broadcast": "203.0.113.7:9" },
+                }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = router(state.clone())
+            .oneshot(post(
+                "/api/contacts",
+                json!({
+                    "label": "Server",
+                    "addr": "192.168.1.50:9000",
+                    "wake": { "mac": "aa:bb:cc:dd:ee:01", "broadcast": broadcast },
+                }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = router(state.clone())
+            .oneshot(post("/api/contacts/Server/wake", json!({})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let mut buf = [0u8; 256];
+        let (len, _) = receiver.recv_from(&mut buf).await.unwrap();
+        assert_eq!(len, 102);
+
+        // Through a relay, the relay session must be up
+        let response = router(state.clone())
+            .oneshot(post(
+                "/api/contacts",
+                json!({
+                    "label": "Server",
+                    "addr": "192.168.1.50:9000",
+                    "wake": { "mac": "aa:bb:cc:dd:ee:01", "relay": "Laptop" },
+                }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        for payload in [json!({}), json!({ "connect": true })] {
+            let response = router(state.clone())
+                .oneshot(post("/api/contacts/Server/wake", payload))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+
+        let response = router(state.clone())
+            .oneshot(post(
+                "/api/contacts",
+                json!({ "label": "Server", "addr": "192.168.1.50:9000", "wake": { "mac": "" } }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(state.read().await.contacts.all()[0].wake, None);
+    }
+
+    #[tokio::test]
+    async fn test_connect_invalid_payload_fails() {
+        let state = create_test_state();
+        let connect = |payload: Value| {
+            let request = Request::builder()
+                .method("POST")
+                .uri("/api/connect")
+                .header("content-type", "application/json")
+                .body(Body::from(payload.to_string()))
+                .unwrap();
+            let app = router(state.clone());
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                let body: Value = serde_json::from_slice(&body).unwrap();
+                (status, body["field"].clone())
+            }
+        };
+
+        // Missing port
+        let (status, field) = connect(json!({ "ip": "192.168.1.50" })).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(field, "port");
+
+        // Typoed field
+        let (status, field) =
+            connect(json!({ "ip": "192.168.1.50", "port": 9000, "gust": true })).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(field, "gust");
+
+        let (status, field) = connect(json!({ "ip": "192.168.1.50", "port": 0 })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(field, "port");
+
+        let (status, field) = connect(json!({ "ip": "127.0.0.1", "port": 9000 })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(field, "ip");
+
+        // Loopback is fine when allowed
+        state.write().await.allow_loopback_peers = true;
+        let (status, _) = connect(json!({ "ip": "127.0.0.1", "port": 9000 })).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    /// Accepting is only possible for a peer that actually asked to connect.
+    #[tokio::test]
+    async fn test_accept_incoming_requires_pending_request() {
+        let (cmd_tx, mut cmd_rx) = mpsc::channel::<Command>(32);
+        let (event_tx, _) = broadcast::channel::<AppEvent>(32);
+        let state = Arc::new(RwLock::new(AppState::new(cmd_tx, event_tx)));
+
+        let requester: SocketAddr = "203.0.113.7:41234".parse().unwrap();
+        let mut queue = IncomingQueue::new(Duration::from_secs(30), 4);
+        queue.on_syn(requester, EncryptionMode::ChaCha20Poly1305, Instant::now());
+        state
+            .write()
+            .await
+            .set_incoming_requests(queue.requests().to_vec());
+
+        let accept = |addr: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/api/incoming/accept")
+                .header("content-type", "application/json")
+                .body(Body::from(json!({ "addr": addr }).to_string()))
+                .unwrap()
+        };
+
+        let response = router(state.clone())
+            .oneshot(accept("198.51.100.1:5000"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = router(state)
+            .oneshot(accept("203.0.113.7:41234"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(matches!(
+            cmd_rx.recv().await,
+            Some(Command::AcceptIncoming(addr)) if addr == requester
+        ));
+    }
+
+    #[test]
+    fn test_udp_target() {
+        assert_eq!(
+            udp_target("192.168.1.50", 9000),
+            Ok("192.168.1.50:9000".parse().unwrap())
+        );
+        assert!(udp_target("not-an-ip", 80).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_fails_when_busy() {
+        let state = create_test_state();
+        {
+            state.write().await.status = Status::Connected;
+        }
+        let app = router(state);
+
+        let payload = json!({ "ip": "192.168.1.55", "port": 9000 });
+        let request = Request::builder()
+            .method("POST")
+            .uri("/api/connect")
+            .header("content-type", "application/json")
+            .body(Body::from(payload.to_string()))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_get_sessions_lists_newest_first() {
+        let state = create_test_state();
+        {
+            let mut guard = state.write().await;
+            for port in [1000, 2000] {
+                guard.session_log.begin(
+                    format!("c{}", port),
+                    SocketAddr::from(([198, 51, 100, 20], port)),
+                    None,
+                    NatType::Cone,
+                );
+                guard
+                    .session_log
+                    .finish(DisconnectReason::LocalRequest, None, 10, 20);
+            }
+        }
+        let app = router(state);
+
+        let request = Request::builder()
+            .uri("/api/sessions")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_json: Value = serde_json::from_slice(&body_bytes).unwrap();
+
+        assert_eq!(body_json["current"], Value::Null);
+        let sessions = body_json["sessions"].as_array().unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0]["peer"], "198.51.100.20:2000");
+        assert_eq!(sessions[0]["disconnect_reason"], "LocalRequest");
+        assert_eq!(sessions[0]["bytes_received"], 20);
+    }
+
+    #[tokio::test]
+    async fn test_history_export_toggle_exports_at_once() {
+        let state = create_test_state();
+        let dir = TempDir::new("api-export");
+        {
+            let mut guard = state.write().await;
+            guard.history_export = HistoryExport::new(
+                &ExportSettings {
+                    dir: Some(dir.path().to_path_buf()),
+                    ..Default::default()
+                },
+                PathBuf::new(),
+            );
+            guard.session_log.begin(
+                "c1".into(),
+                SocketAddr::from(([198, 51, 100, 20], 1000)),
+                None,
+                NatType::Cone,
+            );
+            guard
+                .session_log
+                .finish(DisconnectReason::LocalRequest, None, 10, 20);
+        }
+
+        let request = Request::builder()
+            .method("PUT")
+            .uri("/api/history-export")
+            .header("content-type", "application/json")
+            .body(Body::from(
+                json!({ "enabled": true, "format": "markdown" }).to_string(),
+            ))
+            .unwrap();
+        let response = router(state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let export = &body["history_export"];
+        assert_eq!(export["format"], "markdown");
+        assert_eq!(export["last_export"]["sessions"], 1);
+        let path = export["last_export"]["path"].as_str().unwrap();
+        assert!(path.ends_with(".md"));
+        assert!(
+            std::fs::read_to_string(path)
+                .unwrap()
+                .contains("198.51.100.20:1000")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stats_history_returns_samples() {
+        let state = create_test_state();
+        {
+            let mut guard = state.write().await;
+            let peer = SocketAddr::from(([198, 51, 100, 20], 1000));
+            guard.stats_history.begin(Some("c1".into()), peer, 1_000);
+            guard.set_rtt(Duration::from_millis(30));
+            guard.stats_history.sample(
+                6_000,
+                LinkCounters {
+                    bytes_sent: 5_000,
+                    ..Default::default()
+                },
+            );
+        }
+        let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        let response = router(state.clone())
+            .oneshot(get("/api/stats/history"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let sample = &body["session"]["samples"][0];
+        assert_eq!(sample["rtt_ms"], 30);
+        assert_eq!(sample["tx_bps"], 1000);
+        assert_eq!(body["sessions"][0]["connection_id"], "c1");
+
+        let response = router(state)
+            .oneshot(get("/api/stats/history?connection_id=nope"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_power_hints_set_low_traffic() {
+        let state = create_test_state();
+        let put = |body: &str| {
+            Request::builder()
+                .method("PUT")
+                .uri("/api/power-profile")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let response = router(state.clone())
+            .oneshot(put(r#"{"metered": true}"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["power_profile"]["metered"], true);
+        assert_eq!(body["power_profile"]["low_traffic"], true);
+        assert_eq!(body["power_profile"]["hints"]["on_battery"], Value::Null);
+
+        let response = router(state.clone())
+            .oneshot(put(r#"{"meterd": true}"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(state.read().await.power_profile.metered);
+    }
+
+    #[tokio::test]
+    async fn test_conversation_retention_override() {
+        let state = create_test_state();
+        let post = |retention: Value| {
+            Request::builder()
+                .method("POST")
+                .uri("/api/conversation/retention")
+                .header("content-type", "application/json")
+                .body(Body::from(json!({ "retention": retention }).to_string()))
+                .unwrap()
+        };
+
+        let app = router(state.clone());
+        let response = app.oneshot(post(Value::Null)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        state.write().await.session_log.begin(
+            "c1".into(),
+            SocketAddr::from(([198, 51, 100, 20], 1000)),
+            None,
+            NatType::Cone,
+        );
+        let app = router(state.clone());
+        let response = app
+            .oneshot(post(json!({ "mode": "days", "days": 3 })))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let app = router(state);
+        let request = Request::builder()
+            .uri("/api/config")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_json: Value = serde_json::from_slice(&body_bytes).unwrap();
+        assert_eq!(body_json["retention"]["default"]["mode"], "forever");
+        assert_eq!(
+            body_json["conversation_retention"],
+            json!({ "mode": "days", "days": 3 })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_handshake_log() {
+        let state = create_test_state();
+        {
+            let transcript = state.read().await.transcript.clone();
+            transcript.set_enabled(true);
+            transcript.record(
+                Direction::Sent,
+                Protocol::Handshake,
+                SocketAddr::from(([192, 0, 2, 30], 5000)),
+                SocketAddr::from(([198, 51, 100, 30], 4000)),
+                || "Bye".into(),
+                &[2, 0, 0, 0],
+            );
+        }
+        let app = router(state);
+
+        let request = Request::builder()
+            .uri("/api/debug/handshake-log")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_json: Value = serde_json::from_slice(&body_bytes).unwrap();
+
+        assert_eq!(body_json["enabled"], true);
+        let entries = body_json["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["direction"], "Sent");
+        assert_eq!(entries[0]["raw_hex"], "02000000");
+    }
+
+    #[tokio::test]
+    async fn test_crash_report_announced_until_dismissed() {
+        let dir = TempDir::new("web-crash");
+        let path = dir.join("crash_report.json");
+        let report = CrashReport {
+            at: 1_700_000_000,
+            version: "0.1.0".into(),
+            message: "boom".into(),
+            location: None,
+            thread: None,
+            backtrace: String::new(),
+            config: ConfigSummary::from(&Config::load()),
+            last_events: Vec::new(),
+        };
+        write_json(&path, &report).unwrap();
+
+        let state = create_test_state();
+        {
+            let mut guard = state.write().await;
+            guard.crash_report = Some(CrashNotice::from(&report));
+            guard.crash_report_path = Some(path.clone());
+        }
+        let snapshot = serde_json::to_value(&*state.read().await).unwrap();
+        assert_eq!(snapshot["crash_report"]["message"], "boom");
+
+        let app = router(state.clone());
+        let request = |method: &str| {
+            Request::builder()
+                .method(method)
+                .uri("/api/crash-report")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = app.clone().oneshot(request("GET")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_json: Value = serde_json::from_slice(&body_bytes).unwrap();
+        assert_eq!(body_json["message"], "boom");
+
+        let response = app.clone().oneshot(request("DELETE")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(!path.exists());
+        assert!(state.read().await.crash_report.is_none());
+
+        let response = app.oneshot(request("GET")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_get_runtime() {
+        let state = create_test_state();
+        {
+            let mut guard = state.write().await;
+            guard.clear_chat();
+            guard.set_outbox_depth(3);
+        }
+        let _events = state.read().await.subscribe_events();
+        let app = router(state);
+
+        let request = Request::builder()
+            .uri("/api/debug/runtime")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_json: Value = serde_json::from_slice(&body_bytes).unwrap();
+
+        assert!(body_json["tasks"]["workers"].as_u64().unwrap() >= 1);
+        assert_eq!(body_json["queues"]["outbox"], 3);
+        assert_eq!(body_json["queues"]["events"]["subscribers"], 1);
+        assert_eq!(body_json["queues"]["commands"]["depth"], 0);
+        assert_eq!(body_json["stores"]["event_log"]["entries"], 1);
+        assert!(
+            body_json["stores"]["event_log"]["approx_bytes"]
+                .as_u64()
+                .unwrap()
+                > 0
+        );
+        assert_eq!(body_json["stores"]["contacts"]["entries"], 0);
+        assert!(
+            body_json["stores"]["state"]["approx_bytes"]
+                .as_u64()
+                .unwrap()
+                > 0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_last_run() {
+        let state = create_test_state();
+        state.read().await.clear_chat();
+        let app = router(state);
+
+        let request = Request::builder()
+            .uri("/api/debug/last-run")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_json: Value = serde_json::from_slice(&body_bytes).unwrap();
+
+        // The test state's log was never opened, so there is no previous run
+        assert_eq!(body_json["last_run"], json!([]));
+        let entries = body_json["this_run"].as_array().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["kind"], "event");
+        assert_eq!(entries[0]["event"]["status"], "CLEAR_CHAT");
+    }
+
+    /// `/api/diagnostics` exposes each STUN server's RTT and failure.
+    #[tokio::test]
+    async fn test_diagnostics_reports_stun_probes() {
+        let state = create_test_state();
+        state.write().await.set_stun_probes(vec![
+            StunProbe {
+                server: "stun.example.org:3478".into(),
+                public_addr: Some("203.0.113.5:40000".parse().unwrap()),
+                rtt_ms: Some(42),
+                error: None,
+            },
+            StunProbe {
+                server: "stun.example.net:3478".into(),
+                public_addr: None,
+                rtt_ms: None,
+                error: Some(StunError::Timeout),
+            },
+        ]);
+        let app = router(state);
+
+        let request = Request::builder()
+            .uri("/api/diagnostics")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_json: Value = serde_json::from_slice(&body_bytes).unwrap();
+
+        let stun = body_json["stun"].as_array().unwrap();
+        assert_eq!(stun.len(), 2);
+        assert_eq!(stun[0]["rtt_ms"], 42);
+        assert_eq!(stun[0]["public_addr"], "203.0.113.5:40000");
+        assert_eq!(stun[1]["error"]["kind"], "Timeout");
+    }
+
+    #[tokio::test]
+    async fn test_summary_counts_unread_and_long_polls() {
+        let state = create_test_state();
+        {
+            let mut guard = state.write().await;
+            guard.peer_label = Some("Bob".into());
+            guard.status = Status::Connected;
+            guard.set_rtt(Duration::from_millis(34));
+            guard.add_message("hi".into(), false);
+            guard.add_message("hello".into(), true);
+            guard.add_message("there?".into(), false);
+        }
+        let app = router(state.clone());
+
+        let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(get("/api/summary")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().contains_key(header::ETAG));
+        let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_json: Value = serde_json::from_slice(&body_bytes).unwrap();
+        assert_eq!(body_json["summary"]["unread"], 2);
+        assert_eq!(body_json["summary"]["rtt_ms"], 34);
+        assert_eq!(
+            body_json["summary"]["text"],
+            "Connected to Bob · 2 unread · 34 ms"
+        );
+        let version = body_json["version"].as_str().unwrap().to_string();
+
+        let read = Request::builder()
+            .method("POST")
+            .uri("/api/read")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(read).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        // Marking read already changed the summary, so the long-poll returns at once
+        let uri = format!("/api/summary?wait_secs=5&since={}&format=text", version);
+        let response = app.clone().oneshot(get(&uri)).await.unwrap();
+        let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body_bytes[..], "Connected to Bob · 34 ms\n".as_bytes());
+
+        // Otherwise it waits for the next change
+        let writer = state.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            writer
+                .write()
+                .await
+                .add_message("still there?".into(), false);
+        });
+        let started = Instant::now();
+        let response = app.oneshot(get("/api/summary?wait_secs=5")).await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_json: Value = serde_json::from_slice(&body_bytes).unwrap();
+        assert_eq!(body_json["summary"]["unread"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_ui_preferences_round_trip() {
+        let state = create_test_state();
+        let mut events = state.read().await.subscribe_events();
+        let app = router(state.clone());
+
+        let put = |body: Value| {
+            Request::builder()
+                .method("PUT")
+                .uri("/api/ui-preferences")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let response = app
+            .clone()
+            .oneshot(put(json!({ "theme": "light", "notification_sound": true })))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        match events.try_recv().unwrap() {
+            AppEvent::UiPreferences { preferences } => assert!(preferences.notification_sound),
+            other => panic!("Unexpected event: {:?}", other),
+        }
+
+        let response = app
+            .clone()
+            .oneshot(put(json!({ "theme": "sepia" })))
+            .await
+            .unwrap();
+        assert!(response.status().is_client_error());
+
+        let request = Request::builder()
+            .uri("/api/ui-preferences")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_json: Value = serde_json::from_slice(&body_bytes).unwrap();
+        assert_eq!(
+            body_json,
+            json!({ "theme": "light", "notification_sound": true, "timestamp_format": "24h" })
+        );
+
+        // Part of the state snapshot sent to new UIs
+        let snapshot = serde_json::to_value(&*state.read().await).unwrap();
+        assert_eq!(snapshot["ui_preferences"]["theme"], "light");
+    }
+
+    #[tokio::test]
+    async fn test_stats_count_sent_traffic() {
+        let state = create_test_state();
+        {
+            let guard = state.read().await;
+            let local = SocketAddr::from(([192, 0, 2, 10], 40000));
+            let server = SocketAddr::from(([198, 51, 100, 1], 3478));
+            guard.transcript.record(
+                Direction::Sent,
+                Protocol::Stun,
+                local,
+                server,
+                String::new,
+                &[0; 20],
+            );
+            guard.transcript.record(
+                Direction::Received,
+                Protocol::Stun,
+                local,
+                server,
+                String::new,
+                &[0; 32],
+            );
+            guard.traffic.record(TrafficClass::Chat, 100);
+        }
+        let app = router(state);
+
+        let request = Request::builder()
+            .uri("/api/stats")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_json: Value = serde_json::from_slice(&body_bytes).unwrap();
+        // Received packets are not counted
+        assert_eq!(
+            body_json["sent"]["stun"],
+            json!({ "bytes": 20, "packets": 1 })
+        );
+        assert_eq!(body_json["sent"]["chat"]["bytes"], 100);
+        assert_eq!(body_json["sent"]["file"]["packets"], 0);
+        assert_eq!(body_json["data_usage"]["monthly"]["used"], 0);
+        assert_eq!(body_json["data_usage"]["bulk_blocked"], false);
 
-        Arc::new(RwLock::new(AppState::new(cmd_tx, event_tx)))
+        let request = Request::builder()
+            .uri("/metrics")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(
+            response.headers()[header::CONTENT_TYPE]
+                .to_str()
+                .unwrap()
+                .starts_with("text/plain")
+        );
+        let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let text = String::from_utf8(body_bytes.to_vec()).unwrap();
+        assert!(text.contains("ghostlink_sent_bytes_total{class=\"stun\"} 20\n"));
     }
 
-    /// Checks that `/api/state` returns the correct default JSON structure
-    /// when the application first boots (all nulls/defaults).
     #[tokio::test]
-    async fn test_get_state_initial_structure() {
+    async fn test_capture_start_stop() {
         let state = create_test_state();
+        let capture_dir = TempDir::new("web-capture");
+        state
+            .read()
+            .await
+            .transcript
+            .set_capture_dir(capture_dir.path().to_path_buf());
         let app = router(state);
 
+        // Stopping before starting is a conflict
         let request = Request::builder()
-            .uri("/api/state")
+            .method("POST")
+            .uri("/api/debug/capture/stop")
             .body(Body::empty())
             .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
 
+        let request = Request::builder()
+            .method("POST")
+            .uri("/api/debug/capture/start")
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"redact": true}"#))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/api/debug/capture/stop")
+            .body(Body::empty())
+            .unwrap();
         let response = app.oneshot(request).await.unwrap();
         assert_eq!(response.status(), StatusCode::OK);
 
@@ -268,36 +3618,49 @@ mod tests {
             .await
             .unwrap();
         let body_json: Value = serde_json::from_slice(&body_bytes).unwrap();
-
-        // The API returns { "state": { ... } }
-        let state_obj = &body_json["state"];
-
-        // Verify defaults
-        assert_eq!(state_obj["public_ip"], Value::Null);
-        assert_eq!(state_obj["peer_ip"], Value::Null);
-        assert_eq!(state_obj["status"], "Disconnected");
-        assert_eq!(state_obj["nat_type"], "Unknown");
+        assert_eq!(body_json["packets"], 0);
     }
 
-    /// Manually modifies the `SharedState` and verifies that `/api/state`
-    /// reflects these changes (IPs, Status, NAT Type) in the JSON response.
+    #[cfg(feature = "netem")]
     #[tokio::test]
-    async fn test_get_state_reflects_updates() {
+    async fn test_set_netem() {
         let state = create_test_state();
+        let app = router(state.clone());
 
-        // 1. Manually update internal state
-        {
-            let mut guard = state.write().await;
-            guard.public_ip = Some("203.0.113.10:8080".parse().unwrap());
-            guard.peer_ip = Some("198.51.100.20:9000".parse().unwrap());
-            guard.status = Status::Punching;
-            guard.nat_type = NatType::Symmetric;
-        }
+        let request = Request::builder()
+            .method("POST")
+            .uri("/api/debug/netem")
+            .header("content-type", "application/json")
+            .body(Body::from(
+                r#"{"latency_ms": 80, "jitter_ms": 10, "loss": 0.02, "reorder": 0.0}"#,
+            ))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
 
-        let app = router(state);
+        let netem = state.read().await.netem.clone();
+        assert_eq!(netem.read().await.latency_ms, 80);
 
         let request = Request::builder()
-            .uri("/api/state")
+            .method("POST")
+            .uri("/api/debug/netem")
+            .header("content-type", "application/json")
+            .body(Body::from(
+                r#"{"latency_ms": 0, "jitter_ms": 0, "loss": 1.5, "reorder": 0.0}"#,
+            ))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_selftest_endpoint() {
+        let state = create_test_state();
+        let app = router(state.clone());
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/api/selftest")
             .body(Body::empty())
             .unwrap();
 
@@ -308,73 +3671,353 @@ mod tests {
             .await
             .unwrap();
         let body_json: Value = serde_json::from_slice(&body_bytes).unwrap();
-        let state_obj = &body_json["state"];
+        assert_eq!(body_json["passed"], true);
+        assert_eq!(body_json["stages"].as_array().unwrap().len(), 5);
 
-        // 2. Verify JSON matches updates
-        assert_eq!(state_obj["public_ip"], "203.0.113.10:8080");
-        assert_eq!(state_obj["peer_ip"], "198.51.100.20:9000");
-        assert_eq!(state_obj["status"], "Punching");
-        assert_eq!(state_obj["nat_type"], "Symmetric");
+        // The live connection state is untouched
+        assert_eq!(state.read().await.status, Status::Disconnected);
     }
 
     #[tokio::test]
-    async fn test_connect_valid_payload() {
+    async fn test_probe_reports_without_connecting() {
         let state = create_test_state();
-        let app = router(state.clone());
-
-        // Updated: Added optional "mode" field (implicit test of default logic)
-        let payload = json!({ "ip": "192.168.1.50", "port": 9000 });
+        let probe = |ip: String, port: u16| {
+            let request = Request::builder()
+                .method("POST")
+                .uri("/api/probe")
+                .header("content-type", "application/json")
+                .body(Body::from(json!({ "ip": ip, "port": port }).to_string()))
+                .unwrap();
+            router(state.clone()).oneshot(request)
+        };
 
-        let request = Request::builder()
-            .method("POST")
-            .uri("/api/connect")
-            .header("content-type", "application/json")
-            .body(Body::from(payload.to_string()))
+        // Nothing listens on a port just released
+        let closed = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = closed.local_addr().unwrap().port();
+        drop(closed);
+        let response = probe("127.0.0.1".into(), port).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().contains_key(OPERATION_ID_HEADER));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
             .unwrap();
+        let report: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(report["outcome"], "unreachable");
+        assert_eq!(report["target"], format!("127.0.0.1:{}", port));
 
-        let response = app.oneshot(request).await.unwrap();
-        assert_eq!(response.status(), StatusCode::OK);
+        let response = probe("not an address".into(), 80).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
 
-        // Verify state update
-        let peer_ip = state.read().await.peer_ip;
-        assert_eq!(peer_ip.unwrap().to_string(), "192.168.1.50:9000");
+        let guard = state.read().await;
+        assert_eq!(guard.status, Status::Disconnected);
+        assert_eq!(guard.peer_ip, None);
     }
 
     #[tokio::test]
-    async fn test_connect_invalid_payload_fails() {
+    async fn test_echo_bot_toggle() {
         let state = create_test_state();
-        let app = router(state);
+        let toggle = |enabled: bool| {
+            let request = Request::builder()
+                .method("POST")
+                .uri("/api/echo-bot")
+                .header("content-type", "application/json")
+                .body(Body::from(json!({ "enabled": enabled }).to_string()))
+                .unwrap();
+            let app = router(state.clone());
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<Value>(&body).unwrap()
+            }
+        };
+
+        let started = toggle(true).await;
+        let addr = started["echo_bot"]["addr"].as_str().unwrap().to_string();
+        assert!(addr.starts_with("127.0.0.1:"));
+        // Enabling a running bot keeps it
+        assert_eq!(toggle(true).await["echo_bot"]["addr"], addr);
+
+        assert_eq!(toggle(false).await["echo_bot"], Value::Null);
+        assert!(state.read().await.echo_bot.is_none());
+    }
 
-        let payload = json!({ "ip": "192.168.1.50" }); // Missing port
+    #[tokio::test]
+    async fn test_ping_returns_controller_stats() {
+        let (cmd_tx, mut cmd_rx) = mpsc::channel::<Command>(32);
+        let (event_tx, _) = broadcast::channel::<AppEvent>(32);
+        let state = Arc::new(RwLock::new(AppState::new(cmd_tx, event_tx)));
+        state.write().await.status = Status::Connected;
+
+        // Stub controller answering the ping run
+        tokio::spawn(async move {
+            while let Some(cmd) = cmd_rx.recv().await {
+                if let Command::Ping { count, reply, .. } = cmd {
+                    let samples = vec![Duration::from_millis(12); count as usize];
+                    let _ = reply.send(Ok(PingStats::from_samples(count, &samples)));
+                }
+            }
+        });
+        let app = router(state.clone());
 
         let request = Request::builder()
             .method("POST")
-            .uri("/api/connect")
+            .uri("/api/ping")
             .header("content-type", "application/json")
-            .body(Body::from(payload.to_string()))
+            .body(Body::from(r#"{"count": 3}"#))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let operation: u64 = response.headers()[OPERATION_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_json: Value = serde_json::from_slice(&body_bytes).unwrap();
+        assert_eq!(body_json["received"], 3);
+        assert_eq!(body_json["avg_ms"], 12.0);
+
+        let recorded = state
+            .read()
+            .await
+            .operations
+            .get(operation)
+            .cloned()
             .unwrap();
+        assert_eq!(recorded.result.unwrap()["received"], 3);
 
+        let request = Request::builder()
+            .method("POST")
+            .uri("/api/ping")
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"count": 0}"#))
+            .unwrap();
         let response = app.oneshot(request).await.unwrap();
-        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
     }
 
     #[tokio::test]
-    async fn test_connect_fails_when_busy() {
+    async fn test_reaction_requires_known_message() {
         let state = create_test_state();
+        let react = |payload: Value| {
+            Request::builder()
+                .method("POST")
+                .uri("/api/reactions")
+                .header("content-type", "application/json")
+                .body(Body::from(payload.to_string()))
+                .unwrap()
+        };
+        let payload = json!({ "message_id": { "from_me": false, "seq": 0 }, "emoji": "👍" });
+
+        let response = router(state.clone())
+            .oneshot(react(payload.clone()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        state.write().await.status = Status::Connected;
+        let response = router(state.clone())
+            .oneshot(react(payload.clone()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        state.write().await.add_message("Hi".into(), false);
+        let response = router(state.clone()).oneshot(react(payload)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = router(state)
+            .oneshot(react(
+                json!({ "message_id": { "from_me": false, "seq": 0 }, "emoji": "ok" }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_assist_request_forwards_name() {
+        let (cmd_tx, mut cmd_rx) = mpsc::channel::<Command>(32);
+        let (event_tx, _) = broadcast::channel::<AppEvent>(32);
+        let state = Arc::new(RwLock::new(AppState::new(cmd_tx, event_tx)));
+        let assist = |name: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/api/assist")
+                .header("content-type", "application/json")
+                .body(Body::from(json!({ "name": name }).to_string()))
+                .unwrap()
+        };
+
+        let response = router(state.clone())
+            .oneshot(assist("uptime"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        state.write().await.status = Status::Connected;
+        let response = router(state.clone()).oneshot(assist(" ")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = router(state).oneshot(assist(" uptime ")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        match cmd_rx.recv().await {
+            Some(Command::AssistRun { name }) => assert_eq!(name, "uptime"),
+            other => panic!("unexpected command: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_share_read_returns_peer_bytes() {
+        let (cmd_tx, mut cmd_rx) = mpsc::channel::<Command>(32);
+        let (event_tx, _) = broadcast::channel::<AppEvent>(32);
+        let state = Arc::new(RwLock::new(AppState::new(cmd_tx, event_tx)));
+        state.write().await.status = Status::Connected;
+
+        // Stub controller standing in for the peer
+        tokio::spawn(async move {
+            while let Some(cmd) = cmd_rx.recv().await {
+                if let Command::ShareQuery { request, reply } = cmd {
+                    let response = match request {
+                        ShareRequest::Read { offset, .. } => Ok(ShareResponse::Data {
+                            offset,
+                            bytes: b"world".to_vec(),
+                            total_size: 11,
+                        }),
+                        ShareRequest::List { .. } => Err("No such share".to_string()),
+                    };
+                    let _ = reply.send(response);
+                }
+            }
+        });
+        let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        let response = router(state.clone())
+            .oneshot(get("/api/share/read?share=stuff&path=hello.txt&offset=6"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-total-size"], "11");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"world");
+
+        let response = router(state.clone())
+            .oneshot(get("/api/share?share=missing"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = router(state)
+            .oneshot(get("/api/share/read?share=stuff&path=hello.txt&len=0"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_missing_peer_features_are_refused() {
+        let (cmd_tx, _cmd_rx) = mpsc::channel::<Command>(32);
+        let (event_tx, mut event_rx) = broadcast::channel::<AppEvent>(32);
+        let state = Arc::new(RwLock::new(AppState::new(cmd_tx, event_tx)));
         {
-            state.write().await.status = Status::Connected;
+            let mut guard = state.write().await;
+            guard.status = Status::Connected;
+            guard.set_peer_features(
+                Some(vec![Feature::Shares]),
+                vec![Feature::Assist, Feature::RemoteWipe],
+            );
         }
+        assert!(matches!(
+            event_rx.recv().await.unwrap(),
+            AppEvent::CapabilityMismatch { missing, .. } if missing == [Feature::Assist, Feature::RemoteWipe]
+        ));
+
+        let response = router(state.clone())
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/remote-wipe")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"The peer does not offer remote wipe");
+
+        let response = router(state)
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/assist")
+                    .header("content-type", "application/json")
+                    .body(Body::from(json!({ "name": "uptime" }).to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_aggregates_deliveries() {
+        let (cmd_tx, mut cmd_rx) = mpsc::channel::<Command>(32);
+        let (event_tx, _) = broadcast::channel::<AppEvent>(32);
+        let state = Arc::new(RwLock::new(AppState::new(cmd_tx, event_tx)));
+
+        // Stub controller with one session
+        tokio::spawn(async move {
+            while let Some(cmd) = cmd_rx.recv().await {
+                if let Command::Broadcast { reply, .. } = cmd {
+                    let _ = reply.send(BroadcastReport::new(vec![Delivery {
+                        peer: "203.0.113.7:41234".parse().unwrap(),
+                        peer_label: Some("Bob".into()),
+                        delivered: true,
+                        error: None,
+                    }]));
+                }
+            }
+        });
         let app = router(state);
+        let post = |uri: &str, payload: Value| {
+            Request::builder()
+                .method("POST")
+                .uri(uri)
+                .header("content-type", "application/json")
+                .body(Body::from(payload.to_string()))
+                .unwrap()
+        };
 
-        let payload = json!({ "ip": "192.168.1.55", "port": 9000 });
-        let request = Request::builder()
-            .method("POST")
-            .uri("/api/connect")
-            .header("content-type", "application/json")
-            .body(Body::from(payload.to_string()))
+        let response = app
+            .clone()
+            .oneshot(post("/api/broadcast", json!({ "message": "Hello all" })))
+            .await
             .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
 
-        let response = app.oneshot(request).await.unwrap();
+        let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_json: Value = serde_json::from_slice(&body_bytes).unwrap();
+        assert_eq!(body_json["delivered"], 1);
+        assert_eq!(body_json["failed"], 0);
+        assert_eq!(body_json["deliveries"][0]["peer_label"], "Bob");
+
+        let response = app
+            .oneshot(post("/api/broadcast", json!({ "message": "  " })))
+            .await
+            .unwrap();
         assert_eq!(response.status(), StatusCode::BAD_REQUEST);
     }
 
@@ -396,6 +4039,38 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_sse_filters_categories() {
+        let state = create_test_state();
+        let request = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        let response = router(state.clone())
+            .oneshot(request("/api/events?categories=status,voice"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = router(state.clone())
+            .oneshot(request("/api/events?categories=status"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut body = response.into_body().into_data_stream();
+        {
+            let mut guard = state.write().await;
+            guard.add_message("hello".into(), false);
+            guard.set_public_ip(
+                "203.0.113.10:8080".parse().unwrap(),
+                Some(StatusMessage::PublicIpResolved),
+                None,
+            );
+        }
+        let chunk = body.next().await.unwrap().unwrap();
+        let chunk = String::from_utf8_lossy(&chunk);
+        assert!(chunk.contains("\"status\":\"DISCONNECTED\""), "{}", chunk);
+        assert!(!chunk.contains("hello"));
+    }
+
     /// Verifies that updating public IP triggers a broadcast event.
     #[tokio::test]
     async fn test_public_ip_update_broadcasts_event() {
@@ -409,7 +4084,7 @@ mod tests {
             let mut guard = state.write().await;
             guard.set_public_ip(
                 "203.0.113.10:8080".parse().unwrap(),
-                Some("Public IP resolved".into()),
+                Some(StatusMessage::PublicIpResolved),
                 None,
             );
         }
@@ -420,6 +4095,7 @@ mod tests {
             AppEvent::Disconnected {
                 state: app_state,
                 message: Some(_),
+                ..
             } => {
                 assert_eq!(
                     app_state.public_ip.unwrap().to_string(),
@@ -440,7 +4116,7 @@ mod tests {
             let mut guard = state.write().await;
             guard.set_public_ip(
                 "203.0.113.10:8080".parse().unwrap(),
-                Some("Initial IP".into()),
+                Some(StatusMessage::PublicIpResolved),
                 None,
             );
         }
@@ -456,7 +4132,7 @@ mod tests {
 
             assert_ne!(old_ip, Some(new_ip));
 
-            guard.set_public_ip(new_ip, Some("Public IP updated".into()), None);
+            guard.set_public_ip(new_ip, Some(StatusMessage::PublicIpUpdated), None);
         }
 
         // Verify event contains new IP
@@ -466,6 +4142,7 @@ mod tests {
             AppEvent::Disconnected {
                 state: app_state,
                 message: Some(_),
+                ..
             } => {
                 assert_eq!(
                     app_state.public_ip.unwrap().to_string(),
@@ -485,7 +4162,7 @@ mod tests {
         // Update NAT type
         {
             let mut guard = state.write().await;
-            guard.set_nat_type(NatType::Cone, Some("NAT type detected".into()), None);
+            guard.set_nat_type(NatType::Cone, Some(StatusMessage::NatTypeDetected), None);
         }
 
         // Verify event
@@ -494,6 +4171,7 @@ mod tests {
             AppEvent::Disconnected {
                 state: app_state,
                 message: Some(_),
+                ..
             } => {
                 assert_eq!(app_state.nat_type, NatType::Cone);
             }
@@ -516,6 +4194,120 @@ mod tests {
         assert_eq!(response.status(), StatusCode::BAD_REQUEST);
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_full_command_queue_returns_503() {
+        // Nobody drains the queue, as if the controller had stalled
+        let (cmd_tx, _cmd_rx) = mpsc::channel::<Command>(1);
+        let (event_tx, _) = broadcast::channel(16);
+        let state = Arc::new(RwLock::new(AppState::new(cmd_tx, event_tx)));
+        state.write().await.status = Status::Connected;
+
+        let send = |message: &str| {
+            let app = router(state.clone());
+            let request = Request::builder()
+                .method("POST")
+                .uri("/api/message")
+                .header("content-type", "application/json")
+                .body(Body::from(json!({ "message": message }).to_string()))
+                .unwrap();
+            app.oneshot(request)
+        };
+
+        assert_eq!(send("first").await.unwrap().status(), StatusCode::OK);
+
+        let response = send("second").await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+
+        let stats = state.read().await.command_queue_stats();
+        assert_eq!(stats.capacity, 1);
+        assert_eq!(stats.depth, 1);
+        assert_eq!(stats.peak_depth, 1);
+        assert_eq!(stats.rejected, 1);
+    }
+
+    #[tokio::test]
+    async fn test_long_messages_are_refused_or_split() {
+        let (cmd_tx, mut cmd_rx) = mpsc::channel::<Command>(8);
+        let (event_tx, _) = broadcast::channel(16);
+        let state = Arc::new(RwLock::new(AppState::new(cmd_tx, event_tx)));
+        {
+            let mut data = state.write().await;
+            data.status = Status::Connected;
+            data.max_text_len = 10;
+        }
+
+        let send = |body: Value| {
+            let app = router(state.clone());
+            let request = Request::builder()
+                .method("POST")
+                .uri("/api/message")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            app.oneshot(request)
+        };
+
+        let response = send(json!({ "message": "a".repeat(11) })).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("the limit is 10 bytes"));
+        assert!(cmd_rx.try_recv().is_err());
+
+        // A message at the limit goes as is
+        let response = send(json!({ "message": "a".repeat(10), "split": true }))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(matches!(cmd_rx.try_recv(), Ok(Command::SendMessage(text)) if text.len() == 10));
+
+        // Pieces that start with a slash are escaped so they are not run
+        let response = send(json!({ "message": "see path /usr/local/bin", "split": true }))
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let mut pieces = Vec::new();
+        while let Ok(Command::SendMessage(text)) = cmd_rx.try_recv() {
+            pieces.push(text);
+        }
+        assert_eq!(body["messages"], pieces.len());
+        assert_eq!(pieces, vec!["see path ", "//usr/loca", "l/bin"]);
+    }
+
+    #[tokio::test]
+    async fn test_chat_commands_work_while_disconnected() {
+        let (cmd_tx, mut cmd_rx) = mpsc::channel::<Command>(4);
+        let (event_tx, _) = broadcast::channel(16);
+        let state = Arc::new(RwLock::new(AppState::new(cmd_tx, event_tx)));
+
+        let send = |message: &str| {
+            let app = router(state.clone());
+            let request = Request::builder()
+                .method("POST")
+                .uri("/api/message")
+                .header("content-type", "application/json")
+                .body(Body::from(json!({ "message": message }).to_string()))
+                .unwrap();
+            app.oneshot(request)
+        };
+
+        // The controller runs commands and reports whether they could run
+        assert_eq!(send("/stats").await.unwrap().status(), StatusCode::OK);
+        assert!(matches!(cmd_rx.try_recv(), Ok(Command::SendMessage(text)) if text == "/stats"));
+
+        // Text, escaped slashes included, still needs a peer
+        for text in ["hello", "//stats"] {
+            let response = send(text).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+        assert!(cmd_rx.try_recv().is_err());
+    }
+
     #[tokio::test]
     async fn test_disconnect_when_connected_succeeds() {
         let state = create_test_state();
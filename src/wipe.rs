@@ -0,0 +1,46 @@
+//! Remote wipe between your own nodes.
+//!
+//! A peer whose saved contact label is listed in `wipe_contacts`, and which
+//! proved in the session that it knows `own_node_secret`, may ask this node
+//! to delete its history: past sessions, the event logs of this and the
+//! previous run, any crash report and the open conversation. Contacts,
+//! preferences and configuration stay. Meant for a lost device: wipe it from
+//! the node you still have the next time it comes online.
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+/// What a wipe removed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WipeReport {
+    /// Session records deleted, including one in progress.
+    pub sessions: usize,
+    /// Event log entries of this run deleted.
+    pub events: usize,
+    /// True if a crash report was deleted.
+    pub crash_report: bool,
+}
+
+/// Reply channel for a wipe we asked the peer to perform.
+pub type WipeReply = oneshot::Sender<Result<WipeReport, String>>;
+
+/// Returns true if the peer saved as `peer_label` may wipe this node.
+///
+/// Peers without a saved contact never may.
+pub fn authorized(wipe_contacts: &[String], peer_label: Option<&str>) -> bool {
+    peer_label.is_some_and(|label| wipe_contacts.iter().any(|c| c == label))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_listed_contacts_may_wipe() {
+        let allowed = vec!["Home server".to_string()];
+        assert!(authorized(&allowed, Some("Home server")));
+        assert!(!authorized(&allowed, Some("Bob")));
+        assert!(!authorized(&allowed, None));
+        assert!(!authorized(&[], Some("Home server")));
+    }
+}
@@ -0,0 +1,168 @@
+//! Wake-on-LAN for peers that sleep.
+//!
+//! A contact may carry the MAC address of the machine its node runs on, and
+//! where to broadcast the magic packet that wakes it. Waking sends the packet
+//! from this machine, which only reaches a sleeping machine on the same LAN,
+//! or asks another of your own nodes on that LAN to send it: the relay, by
+//! saved contact label. A relay only complies if our saved contact label
+//! there is in its `wake_relay_contacts` and we proved in the session that
+//! we know its `own_node_secret`.
+//!
+//! A handshake keeps punching for `handshake_timeout_secs`, long enough for
+//! most machines to wake, so wake-and-connect dials right after the packet.
+
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::{net::UdpSocket, sync::oneshot};
+
+/// Magic packets sent per wake, as UDP may drop one.
+const REPEAT: usize = 3;
+
+/// Length of a magic packet: 6 bytes of 0xFF, then the MAC 16 times.
+const PACKET_LEN: usize = 6 + 16 * 6;
+
+/// How to wake the machine a contact's node runs on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WakeTarget {
+    /// MAC address, e.g. `aa:bb:cc:dd:ee:ff`.
+    pub mac: String,
+    /// Where the magic packet goes; the LAN's broadcast address, port 9.
+    #[serde(default = "default_broadcast")]
+    pub broadcast: SocketAddr,
+    /// Contact label of your own node that sends the packet on the
+    /// sleeping machine's LAN. `None` sends it from this machine.
+    #[serde(default)]
+    pub relay: Option<String>,
+}
+
+fn default_broadcast() -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::BROADCAST, 9))
+}
+
+/// Reply channel for a wake we asked a relay to send.
+pub type WakeReply = oneshot::Sender<Result<(), String>>;
+
+/// Parses a MAC address written with `:` or `-` separators.
+///
+/// # Errors
+///
+/// Returns a message if `mac` is not six hex bytes.
+pub fn parse_mac(mac: &str) -> Result<[u8; 6], String> {
+    let invalid = || format!("{} is not a MAC address", mac);
+    let mut bytes = [0u8; 6];
+    let mut parts = mac.trim().split([':', '-']);
+    for byte in &mut bytes {
+        let part = parts
+            .next()
+            .filter(|part| part.len() == 2)
+            .ok_or_else(invalid)?;
+        *byte = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+    }
+    match parts.next() {
+        Some(_) => Err(invalid()),
+        None => Ok(bytes),
+    }
+}
+
+/// Checks a wake target before it is saved or sent.
+///
+/// The packet may only go to a broadcast, private, link-local or loopback
+/// IPv4 address, so a relay cannot be made to send it across the Internet.
+///
+/// # Errors
+///
+/// Returns a message if the MAC address or broadcast address is invalid.
+pub fn validate(target: &WakeTarget) -> Result<(), String> {
+    parse_mac(&target.mac)?;
+    let local = match target.broadcast.ip() {
+        IpAddr::V4(ip) => {
+            ip.is_broadcast() || ip.is_private() || ip.is_link_local() || ip.is_loopback()
+        }
+        IpAddr::V6(_) => false,
+    };
+    if !local || target.broadcast.port() == 0 {
+        return Err(format!(
+            "{} is not a local IPv4 broadcast address",
+            target.broadcast
+        ));
+    }
+    Ok(())
+}
+
+/// Builds the magic packet that wakes `mac`.
+pub fn magic_packet(mac: [u8; 6]) -> [u8; PACKET_LEN] {
+    let mut packet = [0xFF; PACKET_LEN];
+    for chunk in packet[6..].chunks_exact_mut(6) {
+        chunk.copy_from_slice(&mac);
+    }
+    packet
+}
+
+/// Sends the magic packet for `mac` to `broadcast` from this machine.
+///
+/// # Errors
+///
+/// Returns an error if the target is invalid or the packet cannot be sent.
+pub async fn send(mac: &str, broadcast: SocketAddr) -> Result<()> {
+    let target = WakeTarget {
+        mac: mac.to_string(),
+        broadcast,
+        relay: None,
+    };
+    validate(&target).map_err(|e| anyhow!(e))?;
+    let packet = magic_packet(parse_mac(mac).map_err(|e| anyhow!(e))?);
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    for _ in 0..REPEAT {
+        socket.send_to(&packet, broadcast).await?;
+    }
+    Ok(())
+}
+
+/// Returns true if the peer saved as `peer_label` may have this node send
+/// wake packets.
+///
+/// Peers without a saved contact never may.
+pub fn authorized(wake_relay_contacts: &[String], peer_label: Option<&str>) -> bool {
+    peer_label.is_some_and(|label| wake_relay_contacts.iter().any(|c| c == label))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sends_magic_packets_to_local_targets() {
+        let mac = parse_mac("AA-bb-cc-dd-ee-01").unwrap();
+        assert_eq!(mac, [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0x01]);
+        assert_eq!(parse_mac(" aa:bb:cc:dd:ee:01 "), Ok(mac));
+        assert!(parse_mac("aa:bb:cc:dd:ee").is_err());
+        assert!(parse_mac("aa:bb:cc:dd:ee:01:02").is_err());
+        assert!(parse_mac("aa:bb:cc:dd:ee:zz").is_err());
+
+        let target = |broadcast: &str| WakeTarget {
+            mac: "aa:bb:cc:dd:ee:01".into(),
+            broadcast: broadcast.parse().unwrap(),
+            relay: None,
+        };
+        assert!(validate(&target("192.168.1.255:9")).is_ok());
+        assert!(validate(&target("255.255.255.255:9")).is_ok());
+        assert!(validate(&target("203.0.113.7:9")).is_err());
+        assert!(validate(&target("[ff02::1]:9")).is_err());
+
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        send("aa:bb:cc:dd:ee:01", receiver.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut buf = [0u8; 256];
+        let (len, _) = receiver.recv_from(&mut buf).await.unwrap();
+        assert_eq!(len, PACKET_LEN);
+        assert_eq!(buf[..6], [0xFF; 6]);
+        assert!(buf[6..len].chunks(6).all(|chunk| chunk == mac));
+
+        assert!(authorized(&["Home server".into()], Some("Home server")));
+        assert!(!authorized(&["Home server".into()], None));
+    }
+}
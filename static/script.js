@@ -3,7 +3,22 @@ const state = {
     fullAddress: null,
     localAddress: null,
     peerAddress: null,
+    peerLabel: null, // Display name for the peer (connect request or contacts)
+    guest: false, // Current session is a guest session: not logged, forgotten on disconnect
     natType: 'Unknown',
+    networkError: null, // Last classified STUN failure, if any
+    networkStatus: 'Online', // 'Offline' while keep-alives keep failing
+    portWarningShown: false, // Configured UDP port was taken; warned once
+    crashNoticeShown: false, // An earlier run left a crash report; announced once
+    incomingRequests: [], // Peers asking to connect: { addr, cipher_mode, expires_at }
+    messageTtl: null, // Seconds until messages of this conversation disappear, agreed with the peer
+    scheduled: [], // Messages waiting for their send time: { id, peer, text, send_at, created_at }
+    conversationId: null, // Open conversation; messages tagged with another ID are stale
+    reactions: {}, // Message key -> [{ emoji, from_me }] for the open conversation
+    eventSeq: null, // Events the server has broadcast, as far as this page knows
+    checkpoint: null, // { seq, checksum } of the server state at the last heartbeat or fetch
+    splitOffered: null, // Message refused as too long; sending it again splits it
+    preferences: { theme: 'neon', notification_sound: false, timestamp_format: '24h' }, // Stored on the node
     connectionStatus: 'disconnected', // disconnected, punching, connected
     isIpValid: false,
     isPortValid: false,
@@ -21,6 +36,9 @@ const els = {
     statusText: document.getElementById('statusText'),
     statusBadge: document.getElementById('statusBadge'),
     statusDot: document.querySelector('#statusBadge .status-dot'),
+    themeBtn: document.getElementById('themeBtn'),
+    clockBtn: document.getElementById('clockBtn'),
+    soundBtn: document.getElementById('soundBtn'),
 
     // Home
     myIpDisplay: document.getElementById('myIpDisplay'),
@@ -35,12 +53,21 @@ const els = {
     ipError: document.getElementById('ipError'),
     portError: document.getElementById('portError'),
     submitBtn: document.querySelector('#connectForm button'),
+    probeBtn: document.getElementById('probeBtn'),
+    echoBotBtn: document.getElementById('echoBotBtn'),
+    probeResult: document.getElementById('probeResult'),
+    guestInput: document.getElementById('guestMode'),
+    incomingPanel: document.getElementById('incomingPanel'),
+    incomingList: document.getElementById('incomingList'),
 
     // Punching / Visualization
     vizClientIp: document.getElementById('vizClientIp'),
     vizPeerIp: document.getElementById('vizPeerIp'),
     punchLogs: document.getElementById('punchLogs'),
-    punchTimeout: document.getElementById('punchTimeout'),
+    punchPhase: document.getElementById('punchPhase'),
+    punchPercent: document.getElementById('punchPercent'),
+    punchProgressBar: document.getElementById('punchProgressBar'),
+    punchDetail: document.getElementById('punchDetail'),
     cancelPunchBtn: document.getElementById('cancelPunchBtn'), // New Cancel Button
 
     // Connected / Chat
@@ -49,6 +76,7 @@ const els = {
     chatForm: document.getElementById('chatForm'),
     chatInput: document.getElementById('chatInput'),
     sendBtn: document.getElementById('sendBtn'),
+    scheduledList: document.getElementById('scheduledList'),
     disconnectBtn: document.getElementById('disconnectBtn'), // New Disconnect Button
 
     // Toast
@@ -79,10 +107,12 @@ async function fetchState() {
     els.myIpDisplay.style.opacity = '0.5';
 
     try {
-        const res = await fetch('/api/state');
+        const res = await fetch('api/state');
         if (!res.ok) throw new Error(`Server error`);
         
         const jsonResponse = await res.json();
+        state.eventSeq = jsonResponse.seq ?? null;
+        state.checkpoint = { seq: jsonResponse.seq, checksum: jsonResponse.checksum };
         
         // The server returns: { "state": { public_ip: "...", ... } }
         // We must unwrap the "state" key.
@@ -122,14 +152,54 @@ function syncState(data) {
     // 3. Peer IP
     if (data.peer_ip) state.peerAddress = data.peer_ip;
     else if (data.peer_ip === null) state.peerAddress = null; // Explicit reset
+    if (data.peer_label !== undefined) state.peerLabel = data.peer_label;
+    if (data.guest !== undefined) state.guest = data.guest;
+    if (data.message_ttl !== undefined) state.messageTtl = data.message_ttl;
 
-    // 4. NAT Type (New)
+    // 4. Network error (STUN failure classification)
+    if (data.last_network_error !== undefined) {
+        state.networkError = data.last_network_error;
+        renderMyInfo(true);
+    }
+    if (data.network_status !== undefined) {
+        state.networkStatus = data.network_status;
+        renderMyInfo(true);
+    }
+
+    // 4b. Configured UDP port had to be abandoned
+    if (data.port_warning && !state.portWarningShown) {
+        state.portWarningShown = true;
+        showToast(data.port_warning);
+    }
+
+    // 4c. Peers asking to connect
+    if (data.incoming_requests) {
+        state.incomingRequests = data.incoming_requests;
+        renderIncomingRequests();
+    }
+
+    // 4c2. Messages waiting for their send time
+    if (data.scheduled) {
+        state.scheduled = data.scheduled;
+        renderScheduled();
+    }
+
+    // 4d. UI preferences stored on the node
+    if (data.ui_preferences) applyPreferences(data.ui_preferences);
+
+    // 4e. An earlier run crashed and left a report
+    if (data.crash_report && !state.crashNoticeShown) {
+        state.crashNoticeShown = true;
+        showToast("Previous run crashed; report available at /api/crash-report");
+    }
+
+    // 5. NAT Type (New)
     if (data.nat_type) {
         state.natType = data.nat_type;
         renderNatType();
     }
 
-    // 5. Status
+    // 6. Status
     if (data.status) {
         // reuse handleStatusChange to trigger UI transitions if needed,
         // but strictly speaking, fetchState is usually for init/refresh.
@@ -156,7 +226,7 @@ function handleStatusChange(statusStr, data = {}) {
     // Simplified: Directly display message if present in the event payload
     if (normStatus === 'DISCONNECTED') {
         if (data.message) {
-            showToast(data.message);
+            showToast(describeMessage(data.message));
         }
     }
 
@@ -180,7 +250,7 @@ function handleStatusChange(statusStr, data = {}) {
     } else {
         // DISCONNECTED
         els.viewHome.classList.add('active');
-        els.submitBtn.disabled = !(state.isIpValid && state.isPortValid);
+        toggleSubmitButton();
         els.submitBtn.innerText = "INITIATE LINK SEQUENCE";
     }
 }
@@ -201,30 +271,94 @@ async function enterPunchingState(data) {
     // It should have been synced via fetchState() or previous input.
     
     els.vizClientIp.innerText = state.fullAddress || "Unknown";
-    els.vizPeerIp.innerText = state.peerAddress || "Target";
+    els.vizPeerIp.innerText = peerDisplayName() || "Target";
 
-    // Handle Timeout Display (from AppEvent::Punching { timeout })
-    if (data.timeout !== undefined && data.timeout !== null) {
-        els.punchTimeout.innerText = `${data.timeout}s`;
+    // Handle Progress Display (from AppEvent::Punching { progress })
+    if (data.progress) {
+        renderPunchProgress(data.progress);
     }
 
     // Handle Logs (from AppEvent::Punching { message })
     if (data.message) {
-        addLog(data.message);
+        addLog(describeMessage(data.message));
     }
 }
 
+const PUNCH_PHASE_LABELS = {
+    resolving: 'RESOLVING HOSTNAME...',
+    spraying: 'SPRAYING SYN PACKETS...',
+    awaiting_syn_ack: 'AWAITING SYN-ACK...',
+    key_exchange: 'EXCHANGING KEYS...',
+    upgrading_kcp: 'UPGRADING TO KCP...',
+};
+
+/**
+ * Shows the phase of a connection attempt and how far along it is
+ */
+function renderPunchProgress(progress) {
+    els.punchPhase.innerText = PUNCH_PHASE_LABELS[progress.phase] || 'ESTABLISHING TUNNEL...';
+    els.punchPercent.innerText = `${progress.percent}%`;
+    els.punchProgressBar.style.width = `${progress.percent}%`;
+    els.punchDetail.innerText = `${progress.attempts} SYN${progress.attempts === 1 ? '' : 'S'} SENT / ${progress.secs_left}s LEFT`;
+}
+
 async function enterConnectedState(data) {
     els.viewConnected.classList.add('active');
 
     // Update chat header with peer info
-    els.chatPeerIp.innerText = state.peerAddress || "Connected Peer";
+    if (data.peer_label !== undefined) state.peerLabel = data.peer_label;
+    renderChatHeader();
+    renderScheduled();
 
     if (data.message) {
-        console.log("Connected:", data.message);
+        console.log("Connected:", describeMessage(data.message));
     }
 }
 
+/**
+ * Shows the peer and the session's modes in the chat header
+ */
+function renderChatHeader() {
+    els.chatPeerIp.innerText = (peerDisplayName() || "Connected Peer")
+        + (state.guest ? ' [GUEST]' : '')
+        + (state.messageTtl ? ` [DISAPPEARING ${formatDuration(state.messageTtl)}]` : '');
+}
+
+/**
+ * Formats seconds as the largest whole unit, e.g. 90 -> "1m", 7200 -> "2h"
+ */
+function formatDuration(secs) {
+    if (secs >= 86400) return `${Math.floor(secs / 86400)}d`;
+    if (secs >= 3600) return `${Math.floor(secs / 3600)}h`;
+    if (secs >= 60) return `${Math.floor(secs / 60)}m`;
+    return `${secs}s`;
+}
+
+/**
+ * Translated status message templates by code, e.g. { exchanging_keys: "Schlüsseltausch..." }.
+ * `{name}` is replaced with the parameter of that name. Codes without a
+ * template fall back to the English text sent by the node.
+ */
+const MESSAGE_TEMPLATES = {};
+
+/**
+ * Renders a status message from an event.
+ */
+function describeMessage(message) {
+    const template = MESSAGE_TEMPLATES[message.code];
+    if (!template) return message.text;
+    const params = message.params || {};
+    return template.replace(/\{(\w+)\}/g, (match, name) => name in params ? String(params[name]) : match);
+}
+
+/**
+ * "Bob (203.0.113.7:41234)" when the peer has a label, otherwise just the address.
+ */
+function peerDisplayName() {
+    if (state.peerLabel && state.peerAddress) return `${state.peerLabel} (${state.peerAddress})`;
+    return state.peerLabel || state.peerAddress;
+}
+
 // --- SSE (Real-time Events) ---
 function connectSSE() {
     if (state.sseSource && state.sseSource.readyState !== EventSource.CLOSED) {
@@ -232,26 +366,142 @@ function connectSSE() {
     }
 
     // Endpoint: /api/events
-    state.sseSource = new EventSource('/api/events');
+    // Relative, like every API URL here, so the UI also works under a proxy's base path
+    state.sseSource = new EventSource('api/events');
 
     state.sseSource.onmessage = (event) => {
         try {
             const data = JSON.parse(event.data);
             
             // AppEvent Structure: 
-            // { status: "DISCONNECTED", state: { ... } }
-            // { status: "PUNCHING", timeout: 10, message: "..." }
-            // { status: "CONNECTED", message: "..." }
-            // { status: "MESSAGE", content: "...", from_me: true/false }
+            // { status: "DISCONNECTED", state: { ... }, message, connection_id }
+            // { status: "PUNCHING", progress: { phase, attempts, percent, secs_left }, message, connection_id }
+            // { status: "CONNECTED", message, connection_id }
+            //   where message = { code: "exchanging_keys", params: { ... }, text: "Exchanging Keys..." }
+            // { status: "MESSAGE", content: "...", from_me: true/false, conversation_id, peer, peer_label: "Bob" | null }
+            // { status: "LINK_PREVIEWS", conversation_id, message_id, previews: [{ url, title, description }] }
+            // { status: "ASSIST", id, name, requested_by_me, outcome: { exit_code, output, error } | null }
+            // { status: "SHARE_ACCESS", request: { List: {...} } | { Read: {...} }, error: "..." | null }
+            // { status: "REACTION", conversation_id, message_id: { from_me, seq }, reactions: [...] }
+            // { status: "CONVERSATION_OPENED", conversation_id, connection_id, peer, peer_label }
+            // { status: "CONVERSATION_CLOSED", conversation_id, connection_id, reason, confirmed }
             // { status: "CLEAR_CHAT" }
+            // { status: "SYSTEM", conversation_id, kind: "connected" | "disconnected" | "peer_renamed"
+            //   | "transfer_finished" | "command_result", params: { ... }, text: "Connected to Bob" }
+            // { status: "INCOMING_REQUESTS", requests: [...] }
+            // { status: "DATA_BUDGET", scope: "session" | "month", percent, used, cap, bulk_blocked }
+            // { status: "UI_PREFERENCES", preferences: { theme, notification_sound, timestamp_format } }
+            // { status: "MESSAGE_TTL", conversation_id, ttl_secs, from_me }
+            // { status: "MESSAGE_EXPIRED", conversation_id, message_id }
+            // { status: "SCHEDULED", scheduled: [{ id, peer, text, send_at, created_at }] }
+            // { status: "TRANSCRIPT_CHECK", check: { sent, received, matched, at }, connection_id }
+            // { status: "CAPABILITY_MISMATCH", connection_id, missing: ["shares" | "assist" | ...] }
+            // { status: "HISTORY_WIPED", report: { sessions, events, crash_report } }
+            // { status: "HISTORY_SYNCED", conversation_id, resent }
+            // { status: "UPDATE_AVAILABLE", release: { version, security, notes, url } }
+            // { status: "HEARTBEAT", seq, checksum }
+
+            if (data.status === 'HEARTBEAT') {
+                checkHeartbeat(data);
+                return;
+            }
+            if (state.eventSeq !== null) state.eventSeq += 1;
 
             if (data.status) {
                 if (data.status === 'MESSAGE') {
                     // Handle chat message
-                    addChatMessage(data.content, data.from_me);
+                    if (data.conversation_id && state.conversationId
+                        && data.conversation_id !== state.conversationId) {
+                        return;
+                    }
+                    addChatMessage(data.content, data.from_me, data.peer_label, data.message_id, data.links, data.expires_at, data.order, data.conversation_id);
+                    if (!data.from_me) {
+                        markRead();
+                        playNotificationSound();
+                    }
+                } else if (data.status === 'LINK_PREVIEWS') {
+                    if (data.conversation_id === state.conversationId) {
+                        renderLinkPreviews(messageKey(data.message_id), data.previews);
+                    }
+                } else if (data.status === 'SHARE_ACCESS') {
+                    // The peer browsed our shared folders; make it visible
+                    const [kind, req] = Object.entries(data.request)[0];
+                    const where = req.share ? `${req.share}/${req.path}` : 'shares';
+                    showToast(`PEER ${kind === 'Read' ? 'READ' : 'LISTED'} ${where}${data.error ? ' (DENIED)' : ''}`);
+                } else if (data.status === 'ASSIST') {
+                    addAssistEntry(data);
+                } else if (data.status === 'REACTION') {
+                    const key = messageKey(data.message_id);
+                    state.reactions[key] = data.reactions;
+                    renderReactions(key);
+                } else if (data.status === 'CONVERSATION_OPENED') {
+                    state.conversationId = data.conversation_id;
+                    state.reactions = {};
+                    state.messageTtl = null;
+                } else if (data.status === 'CONVERSATION_CLOSED') {
+                    if (state.conversationId === data.conversation_id) {
+                        state.conversationId = null;
+                    }
                 } else if (data.status === 'CLEAR_CHAT') {
                     // Handle clear chat event
                     clearChatUI();
+                } else if (data.status === 'SYSTEM') {
+                    if (data.conversation_id && state.conversationId
+                        && data.conversation_id !== state.conversationId) {
+                        return;
+                    }
+                    if (data.kind === 'peer_renamed') {
+                        state.peerLabel = data.params.label;
+                        renderChatHeader();
+                    }
+                    addSystemNotice(data);
+                } else if (data.status === 'INCOMING_REQUESTS') {
+                    state.incomingRequests = data.requests || [];
+                    renderIncomingRequests();
+                } else if (data.status === 'MESSAGE_TTL') {
+                    state.messageTtl = data.ttl_secs;
+                    renderChatHeader();
+                    const who = data.from_me ? 'YOU' : 'PEER';
+                    showToast(data.ttl_secs
+                        ? `${who} SET MESSAGES TO DISAPPEAR AFTER ${formatDuration(data.ttl_secs)}`
+                        : `${who} TURNED OFF DISAPPEARING MESSAGES`);
+                } else if (data.status === 'MESSAGE_EXPIRED') {
+                    // Also sent after the conversation has closed; its messages are still shown
+                    const key = messageKey(data.message_id);
+                    if (data.conversation_id === state.conversationId) {
+                        delete state.reactions[key];
+                    }
+                    els.chatMessages
+                        .querySelector(`[data-message-key="${key}"][data-conversation-id="${data.conversation_id}"]`)
+                        ?.remove();
+                } else if (data.status === 'SCHEDULED') {
+                    state.scheduled = data.scheduled || [];
+                    renderScheduled();
+                } else if (data.status === 'DATA_BUDGET') {
+                    const scope = data.scope === 'month' ? 'MONTHLY' : 'SESSION';
+                    const mb = (bytes) => (bytes / 1048576).toFixed(1);
+                    showToast(`${scope} DATA ${data.percent}% USED (${mb(data.used)} / ${mb(data.cap)} MB)`
+                        + (data.bulk_blocked ? ' - FILE TRANSFERS PAUSED' : ''));
+                } else if (data.status === 'UI_PREFERENCES') {
+                    applyPreferences(data.preferences);
+                } else if (data.status === 'HISTORY_WIPED') {
+                    showToast(`HISTORY WIPED BY PEER (${data.report.sessions} SESSIONS)`);
+                } else if (data.status === 'HISTORY_SYNCED') {
+                    showToast(`RESENT ${data.resent} MESSAGES LOST WHEN THE LAST SESSION DROPPED`);
+                } else if (data.status === 'UPDATE_AVAILABLE') {
+                    const { version, security, notes, url } = data.release;
+                    showToast(`GHOSTLINK ${version} AVAILABLE${security ? ' - SECURITY FIX' : ''}`);
+                    addLog(`Version ${version} is available${url ? ` at ${url}` : ''}${notes ? `: ${notes}` : ''}`);
+                } else if (data.status === 'TRANSCRIPT_CHECK') {
+                    // Only divergence is worth interrupting the user for
+                    if (!data.check.matched) {
+                        const { sent, received } = data.check;
+                        showToast('SESSION TRANSCRIPT MISMATCH');
+                        addLog(`Transcript mismatch: peer sent ${sent} frames, ${received} received`);
+                    }
+                } else if (data.status === 'CAPABILITY_MISMATCH') {
+                    const missing = data.missing.map((feature) => feature.replace(/_/g, ' '));
+                    addLog(`Peer does not offer: ${missing.join(', ')}`);
                 } else {
                     handleStatusChange(data.status, data);
                 }
@@ -266,6 +516,23 @@ function connectSSE() {
     };
 }
 
+/**
+ * Fetches the state again if events were missed (e.g. a proxy buffering
+ * the stream dropped some), or the state changed without any
+ */
+function checkHeartbeat(heartbeat) {
+    const missed = state.eventSeq !== null && heartbeat.seq !== state.eventSeq;
+    const silent = state.checkpoint !== null
+        && state.checkpoint.seq === heartbeat.seq
+        && state.checkpoint.checksum !== heartbeat.checksum;
+    state.eventSeq = heartbeat.seq;
+    state.checkpoint = { seq: heartbeat.seq, checksum: heartbeat.checksum };
+    if (missed || silent) {
+        console.warn('Out of sync with the server, fetching state again');
+        fetchState();
+    }
+}
+
 // --- UI Rendering ---
 
 function renderNatType() {
@@ -285,6 +552,117 @@ function renderNatType() {
     }
 }
 
+function renderIncomingRequests() {
+    if (!els.incomingList) return;
+
+    const requests = state.incomingRequests;
+    els.incomingPanel.hidden = requests.length === 0;
+    els.incomingList.innerHTML = '';
+
+    const now = Date.now() / 1000;
+    requests.forEach((req) => {
+        const item = document.createElement('li');
+        item.className = 'incoming-item';
+
+        const info = document.createElement('div');
+        info.textContent = req.addr;
+        const meta = document.createElement('div');
+        meta.className = 'incoming-meta';
+        meta.textContent = `${req.cipher_mode} · expires in ${Math.max(0, Math.round(req.expires_at - now))}s`;
+        info.appendChild(meta);
+
+        const actions = document.createElement('div');
+        actions.className = 'incoming-actions';
+        [['ACCEPT', 'accept'], ['REJECT', 'reject']].forEach(([label, action]) => {
+            const btn = document.createElement('button');
+            btn.className = 'icon-btn';
+            btn.textContent = label;
+            btn.addEventListener('click', () => answerIncoming(req.addr, action));
+            actions.appendChild(btn);
+        });
+
+        item.append(info, actions);
+        els.incomingList.appendChild(item);
+    });
+}
+
+/**
+ * Lists the scheduled messages for the connected peer above the chat input
+ */
+function renderScheduled() {
+    if (!els.scheduledList) return;
+
+    const pending = state.scheduled.filter((m) => m.peer === state.peerAddress);
+    els.scheduledList.hidden = pending.length === 0;
+    els.scheduledList.innerHTML = '';
+
+    pending.forEach((message) => {
+        const item = document.createElement('li');
+        item.className = 'scheduled-item';
+
+        const info = document.createElement('span');
+        info.textContent = `PENDING ${formatTime(new Date(message.send_at * 1000))} · ${message.text}`;
+
+        const cancel = document.createElement('button');
+        cancel.className = 'icon-btn';
+        cancel.textContent = 'CANCEL';
+        cancel.addEventListener('click', () => cancelScheduled(message.id));
+
+        item.append(info, cancel);
+        els.scheduledList.appendChild(item);
+    });
+}
+
+async function setMessageTtl(ttlSecs) {
+    try {
+        const res = await fetch('api/message-ttl', {
+            method: 'POST',
+            headers: { 'Content-Type': 'application/json' },
+            body: JSON.stringify({ ttl_secs: ttlSecs })
+        });
+        if (!res.ok) throw new Error(await res.text());
+        els.chatInput.value = '';
+    } catch (err) {
+        showToast(`COULD NOT SET MESSAGE TTL: ${err.message}`);
+    }
+}
+
+async function scheduleMessage(minutes, message) {
+    try {
+        const res = await fetch('api/scheduled', {
+            method: 'POST',
+            headers: { 'Content-Type': 'application/json' },
+            body: JSON.stringify({ message, send_at: Math.floor(Date.now() / 1000) + minutes * 60 })
+        });
+        if (!res.ok) throw new Error(await res.text());
+        els.chatInput.value = '';
+    } catch (err) {
+        showToast(`SCHEDULING FAILED: ${err.message}`);
+    }
+}
+
+async function cancelScheduled(id) {
+    try {
+        const res = await fetch(`api/scheduled/${id}`, { method: 'DELETE' });
+        if (!res.ok) throw new Error(await res.text());
+    } catch (err) {
+        showToast('Could not cancel scheduled message');
+    }
+}
+
+async function answerIncoming(addr, action) {
+    try {
+        const res = await fetch(`api/incoming/${action}`, {
+            method: 'POST',
+            headers: { 'Content-Type': 'application/json' },
+            body: JSON.stringify({ addr })
+        });
+        if (!res.ok) throw new Error(await res.text());
+    } catch (err) {
+        showToast(`Could not ${action} request`);
+    }
+}
+
 function renderStatusBadge() {
     const s = state.connectionStatus;
     els.statusText.innerText = s.toUpperCase();
@@ -306,11 +684,24 @@ function renderStatusBadge() {
 }
 
 function renderMyInfo(success) {
-    if (success && state.fullAddress) {
+    // A captive portal only needs a browser login, so it gets its own label
+    const portal = state.networkError && state.networkError.error.kind === 'CaptivePortal';
+    if (success && state.networkStatus === 'Offline') {
+        // The last known address may be stale; peers cannot reach it now
+        els.myIpDisplay.innerText = portal ? "LOGIN_REQUIRED" : "OFFLINE";
+        els.myIpDisplay.classList.add('error');
+        els.apiErrorMsg.innerText = "NETWORK UNREACHABLE. RETRYING IN THE BACKGROUND";
+        els.apiErrorMsg.style.display = 'block';
+        els.copyBtn.style.display = 'none';
+    } else if (success && state.fullAddress) {
         els.myIpDisplay.innerText = state.fullAddress;
         els.myIpDisplay.classList.remove('error');
         els.apiErrorMsg.style.display = 'none';
         els.copyBtn.style.display = 'flex';
+    } else if (success && state.networkError) {
+        els.myIpDisplay.innerText = portal ? "LOGIN_REQUIRED" : "STUN_FAIL";
+        els.myIpDisplay.classList.add('error');
+        els.copyBtn.style.display = 'none';
     } else {
         els.myIpDisplay.innerText = "CONN_FAIL";
         els.myIpDisplay.classList.add('error');
@@ -319,6 +710,12 @@ function renderMyInfo(success) {
         els.copyBtn.style.display = 'none';
     }
 
+    // A STUN failure comes with advice on what to try
+    if (success && state.networkError) {
+        els.apiErrorMsg.innerText = `${state.networkError.message}. ${state.networkError.advice}`;
+        els.apiErrorMsg.style.display = 'block';
+    }
+
     // Render local IP
     if (success && state.localAddress) {
         els.myLocalIpDisplay.innerText = state.localAddress;
@@ -334,7 +731,7 @@ function renderMyInfo(success) {
 function addLog(message) {
     const row = document.createElement('div');
     row.className = `log-line system`; 
-    const timeStr = new Date().toLocaleTimeString('en-US', {hour12: false, hour:"2-digit", minute:"2-digit", second:"2-digit"});
+    const timeStr = formatTime(new Date(), true);
     row.innerHTML = `<span class="log-timestamp">[${timeStr}]</span> ${message.toUpperCase()}`;
     els.punchLogs.appendChild(row);
     els.punchLogs.scrollTop = els.punchLogs.scrollHeight;
@@ -360,7 +757,7 @@ function clearChatUI() {
  * @param {string} content - Message content
  * @param {boolean} fromMe - True if message was sent by the user, false if received from peer
  */
-function addChatMessage(content, fromMe) {
+function addChatMessage(content, fromMe, peerLabel = null, messageId = null, links = [], expiresAt = null, order = null, conversationId = null) {
     // Remove welcome message if it exists
     const welcome = els.chatMessages.querySelector('.chat-welcome');
     if (welcome) {
@@ -375,23 +772,245 @@ function addChatMessage(content, fromMe) {
     
     const contentDiv = document.createElement('div');
     contentDiv.className = 'message-content';
-    contentDiv.textContent = content;
+    renderMessageContent(contentDiv, content, links || []);
     
     const timeDiv = document.createElement('span');
     timeDiv.className = 'message-time';
     const now = new Date();
-    timeDiv.textContent = now.toLocaleTimeString(undefined, {hour: '2-digit', minute: '2-digit', hour12: false});
+    const time = formatTime(now);
+    timeDiv.textContent = !fromMe && peerLabel ? `${peerLabel} · ${time}` : time;
     
     bubbleDiv.appendChild(contentDiv);
     bubbleDiv.appendChild(timeDiv);
     messageDiv.appendChild(bubbleDiv);
+
+    // Countdown only; the node deletes the message and sends MESSAGE_EXPIRED
+    if (expiresAt) {
+        const expiryDiv = document.createElement('span');
+        expiryDiv.className = 'message-expiry';
+        expiryDiv.dataset.expiresAt = expiresAt;
+        timeDiv.appendChild(expiryDiv);
+        updateExpiryCountdowns();
+    }
+
+    if (messageId) {
+        messageDiv.dataset.messageKey = messageKey(messageId);
+        messageDiv.dataset.conversationId = conversationId;
+
+        const reactionsDiv = document.createElement('div');
+        reactionsDiv.className = 'message-reactions';
+        bubbleDiv.appendChild(reactionsDiv);
+
+        const picker = document.createElement('div');
+        picker.className = 'reaction-picker';
+        QUICK_REACTIONS.forEach(emoji => {
+            const btn = document.createElement('button');
+            btn.type = 'button';
+            btn.textContent = emoji;
+            btn.addEventListener('click', () => toggleReaction(messageId, emoji));
+            picker.appendChild(btn);
+        });
+        messageDiv.appendChild(picker);
+    }
     
-    els.chatMessages.appendChild(messageDiv);
+    // Both peers sort texts by (clock, side), so concurrent ones end up in the same order
+    const later = order && Array.from(els.chatMessages.querySelectorAll('[data-order-clock]'))
+        .find(el => compareOrder(el.dataset, order) > 0);
+    if (order) {
+        messageDiv.dataset.orderClock = order.clock;
+        messageDiv.dataset.orderSide = order.side;
+    }
+    els.chatMessages.insertBefore(messageDiv, later || null);
     
     // Auto-scroll to bottom
     els.chatMessages.scrollTop = els.chatMessages.scrollHeight;
 }
 
+/**
+ * Fills a message element with its text, turning the server-detected URLs into links.
+ * Everything else stays plain text; no markup from the message is interpreted.
+ */
+function renderMessageContent(container, content, links) {
+    let pos = 0;
+    links.forEach(url => {
+        const at = content.indexOf(url, pos);
+        if (at < 0) return;
+        container.appendChild(document.createTextNode(content.slice(pos, at)));
+
+        const a = document.createElement('a');
+        a.href = url;
+        a.textContent = url;
+        a.target = '_blank';
+        a.rel = 'noopener noreferrer';
+        a.referrerPolicy = 'no-referrer';
+        container.appendChild(a);
+        pos = at + url.length;
+    });
+    container.appendChild(document.createTextNode(content.slice(pos)));
+}
+
+/**
+ * Shows fetched link metadata under a message
+ */
+function renderLinkPreviews(key, previews) {
+    const messageDiv = els.chatMessages.querySelector(`[data-message-key="${key}"]`);
+    if (!messageDiv) return;
+    const bubble = messageDiv.querySelector('.message-bubble');
+
+    previews.forEach(p => {
+        const card = document.createElement('div');
+        card.className = 'link-preview';
+
+        const title = document.createElement('div');
+        title.className = 'link-preview-title';
+        title.textContent = p.title || p.url;
+        card.appendChild(title);
+
+        if (p.description) {
+            const desc = document.createElement('div');
+            desc.className = 'link-preview-description';
+            desc.textContent = p.description;
+            card.appendChild(desc);
+        }
+        bubble.insertBefore(card, bubble.querySelector('.message-time'));
+    });
+}
+
+const QUICK_REACTIONS = ['👍', '❤️', '😂', '😮', '😢'];
+
+function messageKey(messageId) {
+    return `${messageId.from_me ? 'me' : 'peer'}-${messageId.seq}`;
+}
+
+/**
+ * Compares a rendered message's order with another order, like a sort callback
+ */
+function compareOrder(dataset, order) {
+    return (Number(dataset.orderClock) - order.clock) || (Number(dataset.orderSide) - order.side);
+}
+
+/**
+ * Updates the time left on disappearing messages
+ */
+function updateExpiryCountdowns() {
+    const now = Date.now() / 1000;
+    els.chatMessages.querySelectorAll('.message-expiry').forEach(span => {
+        const left = Math.max(0, Math.ceil(span.dataset.expiresAt - now));
+        span.textContent = `⏱ ${formatDuration(left)}`;
+    });
+}
+
+setInterval(updateExpiryCountdowns, 1000);
+
+/**
+ * Redraws the reaction chips under a message
+ */
+function renderReactions(key) {
+    const messageDiv = els.chatMessages.querySelector(`[data-message-key="${key}"]`);
+    if (!messageDiv) return;
+    const container = messageDiv.querySelector('.message-reactions');
+
+    // Group identical emoji into one chip with a count
+    const counts = new Map();
+    (state.reactions[key] || []).forEach(r => {
+        const chip = counts.get(r.emoji) || { count: 0, mine: false };
+        chip.count += 1;
+        chip.mine = chip.mine || r.from_me;
+        counts.set(r.emoji, chip);
+    });
+
+    container.innerHTML = '';
+    counts.forEach((chip, emoji) => {
+        const span = document.createElement('span');
+        span.className = `reaction-chip${chip.mine ? ' mine' : ''}`;
+        span.textContent = chip.count > 1 ? `${emoji} ${chip.count}` : emoji;
+        container.appendChild(span);
+    });
+}
+
+/**
+ * Adds our reaction to a message, or removes it if already present
+ */
+async function toggleReaction(messageId, emoji) {
+    const existing = state.reactions[messageKey(messageId)] || [];
+    const add = !existing.some(r => r.from_me && r.emoji === emoji);
+    try {
+        const res = await fetch('api/reactions', {
+            method: 'POST',
+            headers: { 'Content-Type': 'application/json' },
+            body: JSON.stringify({ message_id: messageId, emoji, add })
+        });
+        if (!res.ok) throw new Error(await res.text());
+    } catch (err) {
+        console.error('Reaction failed:', err);
+        showToast('REACTION FAILED');
+    }
+}
+
+/**
+ * Asks the peer to run a granted command; the result arrives as an ASSIST event
+ */
+async function requestAssist(name) {
+    try {
+        const res = await fetch('api/assist', {
+            method: 'POST',
+            headers: { 'Content-Type': 'application/json' },
+            body: JSON.stringify({ name })
+        });
+        if (!res.ok) throw new Error(await res.text());
+    } catch (err) {
+        console.error('Assist request failed:', err);
+        showToast('ASSIST REQUEST FAILED');
+    }
+}
+
+/**
+ * Logs an assist-mode invocation in the chat so both sides see what ran
+ */
+function addAssistEntry(data) {
+    const welcome = els.chatMessages.querySelector('.chat-welcome');
+    if (welcome) welcome.remove();
+
+    const who = data.requested_by_me ? 'You asked the peer' : `${peerDisplayName()} asked you`;
+    const entry = document.createElement('div');
+    entry.className = 'assist-entry';
+
+    const header = document.createElement('div');
+    if (!data.outcome) {
+        header.textContent = `ASSIST · ${who} to run "${data.name}"`;
+    } else if (data.outcome.error) {
+        header.textContent = `ASSIST · "${data.name}" failed: ${data.outcome.error}`;
+    } else {
+        header.textContent = `ASSIST · "${data.name}" exited with ${data.outcome.exit_code}`;
+    }
+    entry.appendChild(header);
+
+    if (data.outcome && data.outcome.output) {
+        const pre = document.createElement('pre');
+        pre.textContent = data.outcome.output;
+        entry.appendChild(pre);
+    }
+
+    els.chatMessages.appendChild(entry);
+    els.chatMessages.scrollTop = els.chatMessages.scrollHeight;
+}
+
+/**
+ * Shows a notice from the node, such as a command result, as a system line
+ */
+function addSystemNotice(data) {
+    const welcome = els.chatMessages.querySelector('.chat-welcome');
+    if (welcome) welcome.remove();
+
+    const entry = document.createElement('div');
+    entry.className = `system-notice notice-${data.kind.replace(/_/g, '-')}`;
+    entry.textContent = data.text;
+    if (data.kind === 'command_result' && data.params.error) entry.classList.add('failed');
+
+    els.chatMessages.appendChild(entry);
+    els.chatMessages.scrollTop = els.chatMessages.scrollHeight;
+}
+
 /**
  * Handles chat form submission
  */
@@ -401,19 +1020,51 @@ async function handleChatSubmit(e) {
     const message = els.chatInput.value.trim();
     if (!message) return;
     
-    // Disable send button temporarily
+    // "/assist <name>" asks the peer to run a command it granted us
+    const assistMatch = message.match(/^\/assist\s+(\S+)$/);
+    if (assistMatch) {
+        els.chatInput.value = '';
+        await requestAssist(assistMatch[1]);
+        return;
+    }
+
+    // "/ttl <seconds>|off" makes messages of this conversation disappear on both sides
+    const ttlMatch = message.match(/^\/ttl\s+(\d+|off)$/);
+    if (ttlMatch) {
+        await setMessageTtl(ttlMatch[1] === 'off' ? null : parseInt(ttlMatch[1], 10));
+        return;
+    }
+
+    // "/later <minutes> <text>" sends the text after a delay
+    const laterMatch = message.match(/^\/later\s+(\d+)\s+(.+)$/);
+    if (laterMatch) {
+        await scheduleMessage(parseInt(laterMatch[1], 10), laterMatch[2]);
+        return;
+    }
+
+    // Anything else, including /ping, /stats, /nick and other commands, goes
+    // to the node; commands come back as SYSTEM events
     els.sendBtn.disabled = true;
     
     try {
-        const res = await fetch('/api/message', {
+        // Sending a refused message again unchanged opts in to splitting it
+        const split = state.splitOffered === message;
+        const res = await fetch('api/message', {
             method: 'POST',
             headers: { 'Content-Type': 'application/json' },
-            body: JSON.stringify({ message })
+            body: JSON.stringify({ message, split })
         });
-        
+
+        if (res.status === 413) {
+            state.splitOffered = message;
+            addLog((await res.json()).error);
+            showToast('MESSAGE TOO LONG - SEND AGAIN TO SPLIT IT');
+            return;
+        }
         if (!res.ok) {
             throw new Error('Failed to send message');
         }
+        state.splitOffered = null;
         
         // Clear input and refocus
         els.chatInput.value = '';
@@ -436,29 +1087,99 @@ async function handleConnect(e) {
     const ip = els.peerIpInput.value.trim();
     const port = parseInt(els.peerPortInput.value.trim(), 10);
     state.peerAddress = `${ip}:${port}`;
+    state.peerLabel = null; // The backend fills this in from contacts
+    state.guest = els.guestInput.checked;
 
     const btn = els.submitBtn;
     btn.innerText = "INITIATING...";
     btn.disabled = true;
 
     try {
-        const res = await fetch('/api/connect', {
+        const res = await fetch('api/connect', {
             method: 'POST',
             headers: { 'Content-Type': 'application/json' },
-            body: JSON.stringify({ ip, port })
+            body: JSON.stringify({ ip, port, guest: state.guest })
         });
-        if (!res.ok) throw new Error();
+        // Errors name the field at fault, e.g. { error: "...", field: "ip" }
+        if (!res.ok) throw new Error((await res.json()).error);
         
         els.punchLogs.innerHTML = '';
         // No longer using lastSavedMessage
 
     } catch (err) {
+        if (err.message) addLog(err.message);
         showToast("CONNECTION FAILED TO START");
         btn.innerText = "INITIATE LINK SEQUENCE";
         btn.disabled = false;
     } 
 }
 
+const PROBE_OUTCOMES = {
+    listening: ['GHOSTLINK NODE ANSWERED', false],
+    response: ['SOMETHING ANSWERED, BUT NOT GHOSTLINK', true],
+    unreachable: ['HOST REFUSED THE PACKETS', true],
+    silence: ['NO REPLY; NORMAL IF THE PEER IS BEHIND NAT', false],
+};
+
+/**
+ * Probes the target address without starting a connection
+ */
+async function handleProbe() {
+    if (!state.isIpValid || !state.isPortValid) return;
+
+    const ip = els.peerIpInput.value.trim();
+    const port = parseInt(els.peerPortInput.value.trim(), 10);
+    els.probeBtn.disabled = true;
+    els.probeResult.classList.remove('failed');
+    els.probeResult.innerText = 'PROBING...';
+
+    try {
+        const res = await fetch('api/probe', {
+            method: 'POST',
+            headers: { 'Content-Type': 'application/json' },
+            body: JSON.stringify({ ip, port })
+        });
+        if (!res.ok) throw new Error(await res.text());
+        const report = await res.json();
+        const [text, failed] = PROBE_OUTCOMES[report.outcome] || [report.outcome, false];
+        els.probeResult.innerText = report.rtt_ms !== null ? `${text} (${report.rtt_ms} ms)` : text;
+        els.probeResult.classList.toggle('failed', failed);
+    } catch (err) {
+        els.probeResult.innerText = 'PROBE FAILED';
+        els.probeResult.classList.add('failed');
+    } finally {
+        toggleSubmitButton();
+    }
+}
+
+/**
+ * Starts the built-in echo peer and connects to it, for trying GhostLink
+ * without a second machine
+ */
+async function handleEchoBot() {
+    els.echoBotBtn.disabled = true;
+    try {
+        const res = await fetch('api/echo-bot', {
+            method: 'POST',
+            headers: { 'Content-Type': 'application/json' },
+            body: JSON.stringify({ enabled: true })
+        });
+        if (!res.ok) throw new Error(await res.text());
+        const { echo_bot } = await res.json();
+        const sep = echo_bot.addr.lastIndexOf(':');
+        els.peerIpInput.value = echo_bot.addr.slice(0, sep);
+        els.peerPortInput.value = echo_bot.addr.slice(sep + 1);
+        handleIpValidation('blur');
+        handlePortValidation();
+        els.connectForm.requestSubmit();
+    } catch (err) {
+        console.error('Echo bot failed to start:', err);
+        showToast("ECHO BOT FAILED TO START");
+    } finally {
+        els.echoBotBtn.disabled = false;
+    }
+}
+
 // --- Disconnect Logic ---
 
 async function handleDisconnect(e) {
@@ -472,7 +1193,7 @@ async function handleDisconnect(e) {
     }
 
     try {
-        const res = await fetch('/api/disconnect', { method: 'POST' });
+        const res = await fetch('api/disconnect', { method: 'POST' });
         if (!res.ok) throw new Error("Disconnect failed");
         
         // Success: We do nothing here. The backend will process the request,
@@ -492,9 +1213,99 @@ async function handleDisconnect(e) {
     }
 }
 
+// --- UI Preferences ---
+
+const THEMES = ['neon', 'light', 'high-contrast'];
+
+/**
+ * Applies preferences received from the node and updates the header controls.
+ */
+function applyPreferences(prefs) {
+    state.preferences = { ...state.preferences, ...prefs };
+    const { theme, notification_sound, timestamp_format } = state.preferences;
+
+    if (theme === 'neon') delete document.documentElement.dataset.theme;
+    else document.documentElement.dataset.theme = theme;
+
+    if (els.themeBtn) els.themeBtn.textContent = theme.replace('-', ' ').toUpperCase();
+    if (els.clockBtn) els.clockBtn.textContent = timestamp_format.toUpperCase();
+    if (els.soundBtn) {
+        els.soundBtn.textContent = notification_sound ? 'SOUND ON' : 'SOUND OFF';
+        els.soundBtn.classList.toggle('active', notification_sound);
+    }
+}
+
+/**
+ * Saves changed preferences on the node. Every open UI, this one included,
+ * applies them from the UI_PREFERENCES event.
+ */
+async function updatePreferences(changes) {
+    try {
+        const res = await fetch('api/ui-preferences', {
+            method: 'PUT',
+            headers: { 'Content-Type': 'application/json' },
+            body: JSON.stringify({ ...state.preferences, ...changes })
+        });
+        if (!res.ok) throw new Error(await res.text());
+    } catch (err) {
+        console.warn("Saving preferences failed", err);
+        showToast("COULD NOT SAVE PREFERENCES");
+    }
+}
+
+function cycleTheme() {
+    const next = THEMES[(THEMES.indexOf(state.preferences.theme) + 1) % THEMES.length];
+    updatePreferences({ theme: next });
+}
+
+/**
+ * Formats a time of day in the preferred 12 or 24 hour format.
+ */
+function formatTime(date, withSeconds = false) {
+    const options = { hour: '2-digit', minute: '2-digit', hour12: state.preferences.timestamp_format === '12h' };
+    if (withSeconds) options.second = '2-digit';
+    return date.toLocaleTimeString('en-US', options);
+}
+
+let audioContext = null;
+
+/**
+ * Plays a short beep for an incoming message, if enabled.
+ */
+function playNotificationSound() {
+    if (!state.preferences.notification_sound) return;
+    try {
+        audioContext = audioContext || new AudioContext();
+        const osc = audioContext.createOscillator();
+        const gain = audioContext.createGain();
+        osc.frequency.value = 880;
+        gain.gain.setValueAtTime(0.1, audioContext.currentTime);
+        gain.gain.exponentialRampToValueAtTime(0.001, audioContext.currentTime + 0.2);
+        osc.connect(gain).connect(audioContext.destination);
+        osc.start();
+        osc.stop(audioContext.currentTime + 0.2);
+    } catch (err) {
+        console.warn("Notification sound failed", err);
+    }
+}
+
 // --- Validation & Utilities ---
+/**
+ * Tells the backend the peer's messages have been seen, so status bar
+ * widgets stop counting them as unread. Skipped while the tab is hidden.
+ */
+async function markRead() {
+    if (document.visibilityState !== 'visible') return;
+    try {
+        await fetch('api/read', { method: 'POST' });
+    } catch (err) {
+        console.warn("Mark read failed", err);
+    }
+}
+
 function toggleSubmitButton() {
     els.submitBtn.disabled = !(state.isIpValid && state.isPortValid);
+    if (els.probeBtn) els.probeBtn.disabled = els.submitBtn.disabled;
 }
 
 function showToast(message) {
@@ -615,6 +1426,8 @@ function setupEventListeners() {
     if(els.copyLocalBtn) els.copyLocalBtn.addEventListener('click', copyLocalToClipboard);
     
     if(els.connectForm) els.connectForm.addEventListener('submit', handleConnect);
+    if(els.probeBtn) els.probeBtn.addEventListener('click', handleProbe);
+    if(els.echoBotBtn) els.echoBotBtn.addEventListener('click', handleEchoBot);
     
     if(els.peerIpInput) {
         els.peerIpInput.addEventListener('input', () => handleIpValidation('input'));
@@ -627,6 +1440,17 @@ function setupEventListeners() {
     // New Disconnect Listeners
     if(els.disconnectBtn) els.disconnectBtn.addEventListener('click', handleDisconnect);
     if(els.cancelPunchBtn) els.cancelPunchBtn.addEventListener('click', handleDisconnect);
+
+    if(els.themeBtn) els.themeBtn.addEventListener('click', cycleTheme);
+    if(els.clockBtn) els.clockBtn.addEventListener('click', () => updatePreferences({
+        timestamp_format: state.preferences.timestamp_format === '24h' ? '12h' : '24h'
+    }));
+    if(els.soundBtn) els.soundBtn.addEventListener('click', () => updatePreferences({
+        notification_sound: !state.preferences.notification_sound
+    }));
+
+    // Messages that arrived while the tab was hidden are read once it is shown
+    document.addEventListener('visibilitychange', markRead);
 }
 
 init();